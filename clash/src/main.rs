@@ -24,9 +24,11 @@ struct Cli {
         value_parser,
         value_name = "FILE",
         default_value = "config.yaml",
-        help = "Specify configuration file"
+        action = clap::ArgAction::Append,
+        help = "Specify configuration file, or an http(s) url to fetch it from, \
+                given more than once the files are merged in order"
     )]
-    config: PathBuf,
+    config: Vec<PathBuf>,
     #[clap(
         short = 't',
         long,
@@ -46,6 +48,18 @@ struct Cli {
     version: bool,
     #[clap(short, long, help = "Additinally log to file")]
     log_file: Option<String>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Worker threads of the runtime, overrides runtime.worker-threads"
+    )]
+    worker_threads: Option<usize>,
+    #[clap(
+        long,
+        value_parser = ["multi", "current"],
+        help = "Runtime flavor, overrides runtime.flavor"
+    )]
+    runtime_flavor: Option<String>,
 }
 
 fn main() {
@@ -63,24 +77,94 @@ fn main() {
         exit(0)
     }
 
-    let file = cli
-        .directory
-        .as_ref()
-        .unwrap_or(&std::env::current_dir().unwrap())
-        .join(cli.config)
-        .to_string_lossy()
-        .to_string();
+    // either flag replaces the whole runtime section of the config
+    let rt = match (cli.runtime_flavor.as_deref(), cli.worker_threads) {
+        (_, Some(0)) => {
+            eprintln!("--worker-threads must be at least 1");
+            exit(1);
+        }
+        (Some("current"), Some(_)) => {
+            eprintln!("--worker-threads is only for the multi runtime flavor");
+            exit(1);
+        }
+        (Some("current"), None) => Some(TokioRuntime::SingleThread),
+        (_, Some(n)) => Some(TokioRuntime::Workers(n)),
+        (Some(_), None) => Some(TokioRuntime::MultiThread),
+        (None, None) => None,
+    };
 
-    if !Path::new(&file).exists() {
-        // TODO: offer a internal default config, to compatible with clash
-        // behavior
-        panic!("config file not found: {}", file);
+    let is_url = |f: &&PathBuf| {
+        f.to_str()
+            .is_some_and(|f| f.starts_with("https://") || f.starts_with("http://"))
+    };
+    let url = cli
+        .config
+        .iter()
+        .find(is_url)
+        .map(|f| f.to_string_lossy().to_string());
+    if let Some(url) = url {
+        if cli.config.len() > 1 {
+            eprintln!("a config url can't be merged with other config files");
+            exit(1);
+        }
+        run(cli, clash::Config::Url(url), "from url".to_owned());
+    }
+
+    let files: Vec<String> = cli
+        .config
+        .iter()
+        .map(|f| {
+            cli.directory
+                .as_ref()
+                .unwrap_or(&std::env::current_dir().unwrap())
+                .join(f)
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+
+    for file in &files {
+        if !Path::new(file).exists() {
+            if cli.test_config {
+                eprintln!("configuration file {} not found", file);
+                exit(1);
+            }
+            // TODO: offer a internal default config, to compatible with clash
+            // behavior
+            panic!("config file not found: {}", file);
+        }
     }
+    let file = files.join(", ");
+    let config = if files.len() == 1 {
+        clash::Config::File(files[0].clone())
+    } else {
+        clash::Config::Files(files)
+    };
+    run(cli, config, file);
+}
+
+/// tests or starts `config`, `file` is what it is called in messages
+fn run(cli: Cli, config: clash::Config, file: String) -> ! {
     if cli.test_config {
-        match clash::Config::File(file.clone()).try_parse() {
-            Ok(_) => {
-                println!("configuration file {} test is successful", file);
-                exit(0);
+        let cwd = cli.directory.clone().unwrap_or_else(|| PathBuf::from("."));
+        match config.test(&cwd) {
+            Ok(d) => {
+                for w in &d.warnings {
+                    println!("warning: {}", w);
+                }
+                for e in &d.errors {
+                    println!("error: {}", e);
+                }
+                if d.errors.is_empty() {
+                    println!("configuration file {} test is successful", file);
+                    exit(0);
+                }
+                eprintln!(
+                    "configuration file {} test failed with {} error(s)",
+                    file,
+                    d.errors.len()
+                );
+                exit(1);
             }
             Err(e) => {
                 eprintln!("configuration file {} test failed: {}", file, e);
@@ -90,12 +174,12 @@ fn main() {
     }
 
     match clash::start(clash::Options {
-        config: clash::Config::File(file),
+        config,
         cwd: cli.directory.map(|x| x.to_string_lossy().to_string()),
-        rt: Some(TokioRuntime::MultiThread),
+        rt,
         log_file: cli.log_file,
     }) {
-        Ok(_) => {}
+        Ok(_) => exit(0),
         Err(_) => {
             exit(1);
         }
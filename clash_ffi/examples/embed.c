@@ -0,0 +1,77 @@
+/*
+ * Runs clash-rs from C for a few seconds, printing its logs and traffic.
+ *
+ *   cbindgen --config clash_ffi/cbindgen.toml --crate clash_ffi \
+ *       --output target/clashrs.h
+ *   cargo build -p clash_ffi --release
+ *   cc clash_ffi/examples/embed.c -Itarget -Ltarget/release -lclashrs \
+ *       -o target/embed
+ *   LD_LIBRARY_PATH=target/release target/embed config.yaml .
+ */
+#include <stdio.h>
+#include <unistd.h>
+
+#include "clashrs.h"
+
+static void on_log(void *user_data, ClashLogLevel level, const char *msg) {
+    static const char *levels[] = {"TRACE", "DEBUG", "INFO", "WARN", "ERROR"};
+    (void)user_data;
+    printf("[%s] %s\n", levels[level], msg);
+}
+
+static void on_traffic(void *user_data, int64_t up, int64_t down,
+                       int64_t up_total, int64_t down_total) {
+    int *ticks = user_data;
+    (*ticks)++;
+    printf("up %lld B/s down %lld B/s, %lld / %lld B in total\n",
+           (long long)up, (long long)down, (long long)up_total,
+           (long long)down_total);
+}
+
+int main(int argc, char **argv) {
+    /* a path, or the config itself as yaml or json */
+    const char *config = argc > 1 ? argv[1] : "{\"mixed-port\": 7890}";
+    const char *home = argc > 2 ? argv[2] : ".";
+
+    int ticks = 0;
+    ClashCallbacks callbacks = {
+        .user_data = &ticks,
+        .on_log = on_log,
+        .on_traffic = on_traffic,
+    };
+
+    if (clash_stop() != ClashResult_NotRunning) {
+        fprintf(stderr, "stop before start should say it's not running\n");
+        return 1;
+    }
+
+    if (clash_start(config, home, &callbacks) != ClashResult_Ok) {
+        fprintf(stderr, "start failed: %s\n", clash_last_error());
+        return 1;
+    }
+    if (clash_start(config, home, &callbacks) != ClashResult_AlreadyRunning) {
+        fprintf(stderr, "second start should be refused\n");
+    }
+
+    if (clash_set_mode("global") != ClashResult_Ok) {
+        fprintf(stderr, "set mode failed: %s\n", clash_last_error());
+    }
+    if (clash_set_mode("fast") != ClashResult_InvalidArgument) {
+        fprintf(stderr, "unknown mode should be refused\n");
+    }
+
+    sleep(3);
+
+    if (clash_reload(config) != ClashResult_Ok) {
+        fprintf(stderr, "reload failed: %s\n", clash_last_error());
+    }
+
+    sleep(2);
+
+    clash_stop();
+    while (clash_is_running()) {
+        usleep(100 * 1000);
+    }
+    printf("stopped after %d traffic reports\n", ticks);
+    return 0;
+}
@@ -1,63 +1,391 @@
-use clash_lib::{shutdown, start, Config, Options, TokioRuntime};
+//! The C interface for embedding clash-rs, e.g. in a mobile app. The header
+//! is generated with `cbindgen --config clash_ffi/cbindgen.toml --crate
+//! clash_ffi`, `examples/embed.c` shows how it's used.
+//!
+//! Every function returns a [`ClashResult`], the message of the last error
+//! on the calling thread is then given by [`clash_last_error`]. A panic is
+//! caught and reported as [`ClashResult::Panic`], it never unwinds into the
+//! caller.
+//!
+//! The functions of the first version are still there, deprecated:
+//! `clash_shutdown` and `clash_free_string` as they were, and the old
+//! `clash_start`, which ran on the calling thread until shut down, as
+//! [`clash_start_blocking`].
+
+use clash_lib::{Config, LogLevel, Options, RunMode, TokioRuntime};
 use std::{
-    ffi::{CStr, CString},
+    cell::RefCell,
+    ffi::{c_void, CStr, CString},
     os::raw::{c_char, c_int},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, Once,
+    },
+    time::Duration,
 };
+use tokio::sync::broadcast::error::RecvError;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClashResult {
+    Ok              = 0,
+    /// a null or non utf-8 string, or an unknown mode
+    InvalidArgument = 1,
+    AlreadyRunning  = 2,
+    NotRunning      = 3,
+    /// the config was rejected, or it failed to start
+    Failed          = 4,
+    Panic           = 5,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClashLogLevel {
+    Trace   = 0,
+    Debug   = 1,
+    Info    = 2,
+    Warning = 3,
+    Error   = 4,
+}
+
+/// Called on a thread of clash's, with the message only valid for the call.
+pub type ClashLogCallback = Option<
+    extern "C" fn(user_data: *mut c_void, level: ClashLogLevel, msg: *const c_char),
+>;
+
+/// Called once a second while running, the speeds are in bytes per second,
+/// the totals in bytes since the last reload.
+pub type ClashTrafficCallback = Option<
+    extern "C" fn(
+        user_data: *mut c_void,
+        up: i64,
+        down: i64,
+        up_total: i64,
+        down_total: i64,
+    ),
+>;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ClashCallbacks {
+    /// passed back to the callbacks as is
+    pub user_data: *mut c_void,
+    pub on_log: ClashLogCallback,
+    pub on_traffic: ClashTrafficCallback,
+}
+
+// the user data is the caller's to make safe to use from another thread
+unsafe impl Send for ClashCallbacks {}
+
+/// Called with each socket clash opens to the outside, before it's used.
+/// Returning false fails whatever the socket was for.
+pub type ClashProtectCallback =
+    Option<extern "C" fn(user_data: *mut c_void, fd: c_int) -> bool>;
+
+/// the callbacks of the running instance, cleared by `clash_stop`
+static CALLBACKS: Mutex<Option<ClashCallbacks>> = Mutex::new(None);
+
+/// bumped on each start, the traffic thread of an earlier one then ends
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(code: ClashResult, msg: impl ToString) -> ClashResult {
+    let msg = CString::new(msg.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+    code
+}
 
+fn guard(f: impl FnOnce() -> ClashResult) -> ClashResult {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(rv) => rv,
+        Err(e) => {
+            let msg = e
+                .downcast_ref::<&str>()
+                .map(|x| x.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or("unknown panic".to_owned());
+            fail(ClashResult::Panic, msg)
+        }
+    }
+}
+
+fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, ClashResult> {
+    if s.is_null() {
+        return Err(fail(
+            ClashResult::InvalidArgument,
+            format!("{} is null", name),
+        ));
+    }
+    unsafe { CStr::from_ptr(s) }.to_str().map_err(|_| {
+        fail(
+            ClashResult::InvalidArgument,
+            format!("{} is not utf-8", name),
+        )
+    })
+}
+
+/// a path to a file, or else the config itself, json being yaml too
+fn config_arg(s: &str) -> Config {
+    if Path::new(s).is_file() {
+        Config::File(s.to_owned())
+    } else {
+        Config::Str(s.to_owned())
+    }
+}
+
+/// The log events go to whatever callback is set when they come, the one
+/// thread forwarding them lives as long as the process.
+fn forward_logs() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        let mut logs = clash_lib::subscribe_logs();
+        std::thread::spawn(move || loop {
+            let event = match logs.blocking_recv() {
+                Ok(e) => e,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            let Some(cb) = *CALLBACKS.lock().unwrap() else {
+                continue;
+            };
+            let Some(on_log) = cb.on_log else {
+                continue;
+            };
+            let level = match event.level {
+                LogLevel::Trace => ClashLogLevel::Trace,
+                LogLevel::Debug => ClashLogLevel::Debug,
+                LogLevel::Info => ClashLogLevel::Info,
+                LogLevel::Warning => ClashLogLevel::Warning,
+                LogLevel::Error | LogLevel::Silent => ClashLogLevel::Error,
+            };
+            let msg = CString::new(event.msg.replace('\0', " ")).unwrap_or_default();
+            on_log(cb.user_data, level, msg.as_ptr());
+        });
+    });
+}
+
+fn forward_traffic(generation: u64) {
+    std::thread::spawn(move || {
+        while GENERATION.load(Ordering::SeqCst) == generation {
+            std::thread::sleep(Duration::from_secs(1));
+            let Some(cb) = *CALLBACKS.lock().unwrap() else {
+                continue;
+            };
+            let (Some(on_traffic), Some(t)) = (cb.on_traffic, clash_lib::traffic())
+            else {
+                continue;
+            };
+            on_traffic(cb.user_data, t.up, t.down, t.up_total, t.down_total);
+        }
+    });
+}
+
+/// Starts clash on a thread of its own and returns once it's running.
+///
+/// `config` is the path to a config file, or the config itself, as yaml or
+/// json. The paths in it are relative to `home_dir`, where the cache and
+/// the downloaded databases are kept too. `callbacks` may be null.
 #[no_mangle]
 pub extern "C" fn clash_start(
+    config: *const c_char,
+    home_dir: *const c_char,
+    callbacks: *const ClashCallbacks,
+) -> ClashResult {
+    guard(|| {
+        let config = match str_arg(config, "config") {
+            Ok(s) => config_arg(s),
+            Err(e) => return e,
+        };
+        let home_dir = match str_arg(home_dir, "home_dir") {
+            Ok(s) => s.to_owned(),
+            Err(e) => return e,
+        };
+        if clash_lib::is_running() {
+            return fail(ClashResult::AlreadyRunning, "already running");
+        }
+
+        *CALLBACKS.lock().unwrap() = unsafe { callbacks.as_ref() }.copied();
+        forward_logs();
+
+        let rv = clash_lib::spawn(Options {
+            config,
+            cwd: Some(home_dir),
+            rt: None,
+            log_file: None,
+        });
+        match rv {
+            Ok(_) => {
+                let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+                forward_traffic(generation);
+                ClashResult::Ok
+            }
+            Err(e) => {
+                CALLBACKS.lock().unwrap().take();
+                fail(ClashResult::Failed, e)
+            }
+        }
+    })
+}
+
+/// Asks clash to stop, the open connections are given the config's
+/// `shutdown-grace` to finish. No callback is made once it returns, short
+/// of one already under way on another thread.
+#[no_mangle]
+pub extern "C" fn clash_stop() -> ClashResult {
+    guard(|| {
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+        CALLBACKS.lock().unwrap().take();
+        if clash_lib::shutdown() {
+            ClashResult::Ok
+        } else {
+            fail(ClashResult::NotRunning, "not running")
+        }
+    })
+}
+
+/// Switches to another config, the same way as `clash_start` takes it.
+/// Returns once it's in use, or rejected, the running one is kept then.
+#[no_mangle]
+pub extern "C" fn clash_reload(config: *const c_char) -> ClashResult {
+    guard(|| {
+        let config = match str_arg(config, "config") {
+            Ok(s) => config_arg(s),
+            Err(e) => return e,
+        };
+        if !clash_lib::is_running() {
+            return fail(ClashResult::NotRunning, "not running");
+        }
+        match clash_lib::reload(config) {
+            Ok(()) => ClashResult::Ok,
+            Err(e) => fail(ClashResult::Failed, e),
+        }
+    })
+}
+
+/// `rule`, `global` or `direct`, until the next reload
+#[no_mangle]
+pub extern "C" fn clash_set_mode(mode: *const c_char) -> ClashResult {
+    guard(|| {
+        let mode = match str_arg(mode, "mode") {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let mode = match mode.to_ascii_lowercase().as_str() {
+            "rule" => RunMode::Rule,
+            "global" => RunMode::Global,
+            "direct" => RunMode::Direct,
+            _ => {
+                return fail(
+                    ClashResult::InvalidArgument,
+                    format!("unknown mode: {}", mode),
+                );
+            }
+        };
+        match clash_lib::set_mode(mode) {
+            Ok(()) => ClashResult::Ok,
+            Err(e) => fail(ClashResult::NotRunning, e),
+        }
+    })
+}
+
+/// Sets the protector of the sockets clash opens, null to clear it. On
+/// Android it should call `VpnService.protect`, or the traffic loops back
+/// into the VPN. It may be called from any thread, and can be set before
+/// `clash_start`.
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn clash_set_socket_protector(
+    protect: ClashProtectCallback,
+    user_data: *mut c_void,
+) -> ClashResult {
+    guard(|| {
+        // the user data is the caller's to make safe to use from another thread
+        let user_data = user_data as usize;
+        clash_lib::set_socket_protector(protect.map(|protect| {
+            std::sync::Arc::new(move |fd| protect(user_data as *mut c_void, fd))
+                as clash_lib::SocketProtector
+        }));
+        ClashResult::Ok
+    })
+}
+
+/// 1 if running, 0 if not
+#[no_mangle]
+pub extern "C" fn clash_is_running() -> c_int {
+    clash_lib::is_running() as c_int
+}
+
+/// The message of the last error on this thread, empty if none. Valid until
+/// the next call on this thread.
+#[no_mangle]
+pub extern "C" fn clash_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// The `clash_start` of the first version: runs on the calling thread until
+/// shut down, logging to the file `log`. Returns an empty string, or the
+/// error, either to be freed with `clash_free_string`.
+#[deprecated(note = "use clash_start, which returns once it's running")]
+#[no_mangle]
+pub extern "C" fn clash_start_blocking(
     config: *const c_char,
     log: *const c_char,
     cwd: *const c_char,
     multithread: c_int,
 ) -> *mut c_char {
-    let config_str = unsafe { CStr::from_ptr(config) }
-        .to_str()
-        .unwrap_or_default()
-        .to_string();
-    let log_str = unsafe { CStr::from_ptr(log) }
-        .to_str()
-        .unwrap_or_default()
-        .to_string();
-    let cwd_str = unsafe { CStr::from_ptr(cwd) }
-        .to_str()
-        .unwrap_or_default()
-        .to_string();
-    let rt = if multithread != 0 {
-        Some(TokioRuntime::MultiThread)
-    } else {
-        Some(TokioRuntime::SingleThread)
+    let rv = guard(|| {
+        let args = (
+            str_arg(config, "config"),
+            str_arg(log, "log"),
+            str_arg(cwd, "cwd"),
+        );
+        let (config, log, cwd) = match args {
+            (Ok(config), Ok(log), Ok(cwd)) => (config, log, cwd),
+            (Err(e), ..) | (_, Err(e), _) | (.., Err(e)) => return e,
+        };
+        let rt = if multithread != 0 {
+            TokioRuntime::MultiThread
+        } else {
+            TokioRuntime::SingleThread
+        };
+        let rv = clash_lib::start(Options {
+            config: Config::Str(config.to_owned()),
+            cwd: Some(cwd.to_owned()),
+            rt: Some(rt),
+            log_file: Some(log.to_owned()),
+        });
+        match rv {
+            Ok(()) => ClashResult::Ok,
+            Err(e) => fail(ClashResult::Failed, e),
+        }
+    });
+    let msg = match rv {
+        ClashResult::Ok => CString::default(),
+        _ => LAST_ERROR.with(|e| {
+            CString::new(format!("Error: {}", e.borrow().to_string_lossy()))
+                .unwrap_or_default()
+        }),
     };
-
-    let options = Options {
-        config: Config::Str(config_str),
-        cwd: Some(cwd_str),
-        rt,
-        log_file: Some(log_str),
-    };
-
-    match start(options) {
-        Ok(_) => CString::new("").unwrap().into_raw(),
-        Err(e) => CString::new(format!("Error: {}", e)).unwrap().into_raw(),
-    }
+    msg.into_raw()
 }
 
+/// 1 if it was asked to stop, 0 if it isn't running
+#[deprecated(note = "use clash_stop")]
 #[no_mangle]
 pub extern "C" fn clash_shutdown() -> c_int {
-    if shutdown() {
-        1 // Success
-    } else {
-        0 // Failure
-    }
+    catch_unwind(clash_lib::shutdown).unwrap_or(false) as c_int
 }
 
+/// Frees a string returned by `clash_start_blocking`.
+#[deprecated(note = "only needed with clash_start_blocking")]
 #[no_mangle]
-#[allow(unused_must_use)]
 pub extern "C" fn clash_free_string(s: *mut c_char) {
-    if s.is_null() {
-        return;
-    }
-    unsafe {
-        CString::from_raw(s);
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
     }
 }
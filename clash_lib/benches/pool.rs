@@ -0,0 +1,158 @@
+//! Many short connections relayed with the copy loop, its buffers taken from
+//! the pool, against tokio's copy allocating them for every connection.
+//!
+//! Before the timed runs it prints the allocations per connection of each,
+//! counted by the global allocator below.
+//!
+//! cargo bench -p clash_lib --features bench --bench pool
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use clash_lib::bench::{
+    copy_buf_bidirectional_with_timeout, STREAM_BUFFER_SIZE, STREAM_POOL,
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// bytes sent through each connection
+const SIZE: usize = 256 * 1024;
+const CONNECTIONS: usize = 64;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+enum Relay {
+    Pooled,
+    Unpooled,
+}
+
+async fn pair(l: &TcpListener) -> (TcpStream, TcpStream) {
+    let c = TcpStream::connect(l.local_addr().unwrap()).await.unwrap();
+    let (s, _) = l.accept().await.unwrap();
+    (c, s)
+}
+
+/// sends SIZE bytes from client to server through the relay
+async fn connection(l: &TcpListener, relay: Relay) {
+    let (mut client, mut a) = pair(l).await;
+    let (mut b, mut server) = pair(l).await;
+
+    let relayed = tokio::spawn(async move {
+        match relay {
+            Relay::Pooled => copy_buf_bidirectional_with_timeout(
+                &mut a,
+                &mut b,
+                STREAM_BUFFER_SIZE,
+                TIMEOUT,
+                TIMEOUT,
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+            Relay::Unpooled => tokio::io::copy_bidirectional_with_sizes(
+                &mut a,
+                &mut b,
+                STREAM_BUFFER_SIZE,
+                STREAM_BUFFER_SIZE,
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        }
+    });
+    let received = tokio::spawn(async move {
+        let mut buf = [0; 16 * 1024];
+        let mut total = 0;
+        loop {
+            match server.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => total += n,
+            }
+        }
+        server.shutdown().await.unwrap();
+        total
+    });
+
+    let data = [0x42; 16 * 1024];
+    for _ in 0..SIZE / data.len() {
+        client.write_all(&data).await.unwrap();
+    }
+    client.shutdown().await.unwrap();
+
+    assert_eq!(received.await.unwrap(), SIZE);
+    relayed.await.unwrap().unwrap();
+}
+
+async fn connections(l: &TcpListener, relay: Relay) {
+    for _ in 0..CONNECTIONS {
+        connection(l, relay).await;
+    }
+}
+
+fn report(rt: &tokio::runtime::Runtime, l: &TcpListener, relay: Relay, name: &str) {
+    // the first round fills the pool
+    rt.block_on(connections(l, relay));
+
+    let count = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    rt.block_on(connections(l, relay));
+    println!(
+        "{}: {:.1} allocations, {:.1} KiB allocated per connection",
+        name,
+        (ALLOCATIONS.load(Ordering::Relaxed) - count) as f64 / CONNECTIONS as f64,
+        (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes) as f64
+            / CONNECTIONS as f64
+            / 1024.0,
+    );
+}
+
+fn pool(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let l = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+
+    report(&rt, &l, Relay::Pooled, "pooled");
+    report(&rt, &l, Relay::Unpooled, "unpooled");
+    println!("pool buffers allocated: {}", STREAM_POOL.allocated());
+
+    let mut group = c.benchmark_group("pool");
+    group.throughput(Throughput::Bytes((SIZE * CONNECTIONS) as u64));
+    group.sample_size(20);
+
+    group.bench_function("pooled", |b| {
+        b.to_async(&rt).iter(|| connections(&l, Relay::Pooled))
+    });
+    group.bench_function("unpooled", |b| {
+        b.to_async(&rt).iter(|| connections(&l, Relay::Unpooled))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, pool);
+criterion_main!(benches);
@@ -0,0 +1,92 @@
+//! Relaying between two tcp connections over loopback, with the copy loop
+//! and with splice(2).
+//!
+//! cargo bench -p clash_lib --features bench --bench relay
+
+use std::time::Duration;
+
+use clash_lib::bench::copy_buf_bidirectional_with_timeout;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use clash_lib::bench::splice_bidirectional;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const SIZE: usize = 64 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+enum Relay {
+    Copy,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Splice,
+}
+
+async fn pair() -> (TcpStream, TcpStream) {
+    let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let c = TcpStream::connect(l.local_addr().unwrap()).await.unwrap();
+    let (s, _) = l.accept().await.unwrap();
+    (c, s)
+}
+
+/// sends SIZE bytes from client to server through the relay
+async fn transfer(relay: Relay) {
+    let (mut client, mut a) = pair().await;
+    let (mut b, mut server) = pair().await;
+
+    let relayed = tokio::spawn(async move {
+        match relay {
+            Relay::Copy => {
+                copy_buf_bidirectional_with_timeout(
+                    &mut a, &mut b, 4096, TIMEOUT, TIMEOUT,
+                )
+                .await
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Relay::Splice => {
+                splice_bidirectional(&a, &b, TIMEOUT, TIMEOUT, |_| {}, |_| {}).await
+            }
+        }
+    });
+    let received = tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        let mut total = 0;
+        loop {
+            match server.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => total += n,
+            }
+        }
+        server.shutdown().await.unwrap();
+        total
+    });
+
+    let data = vec![0x42; 1024 * 1024];
+    for _ in 0..SIZE / data.len() {
+        client.write_all(&data).await.unwrap();
+    }
+    client.shutdown().await.unwrap();
+
+    assert_eq!(received.await.unwrap(), SIZE);
+    relayed.await.unwrap().unwrap();
+}
+
+fn relay(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("relay");
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.sample_size(20);
+
+    group.bench_function("copy", |b| b.to_async(&rt).iter(|| transfer(Relay::Copy)));
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    group.bench_function("splice", |b| {
+        b.to_async(&rt).iter(|| transfer(Relay::Splice))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, relay);
+criterion_main!(benches);
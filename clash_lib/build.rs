@@ -1,3 +1,9 @@
+use std::{
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 fn main() -> std::io::Result<()> {
     println!("cargo::rustc-check-cfg=cfg(docker_test)");
     println!("cargo:rerun-if-env-changed=CLASH_DOCKER_TEST");
@@ -5,9 +11,107 @@ fn main() -> std::io::Result<()> {
         println!("cargo::rustc-cfg=docker_test");
     }
 
+    build_info();
+
     println!("cargo:rerun-if-changed=src/common/geodata/geodata.proto");
     prost_build::compile_protos(
         &["src/common/geodata/geodata.proto"],
         &["src/common/geodata"],
     )
 }
+
+/// what `GET /version` and the startup line tell about this build
+fn build_info() {
+    println!("cargo:rerun-if-env-changed=CLASH_GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // HEAD and the branch it's on, not the index, which changes with every
+    // `git add`
+    let mut watched = vec!["../.git/HEAD".to_owned()];
+    if let Some(r) = std::fs::read_to_string("../.git/HEAD")
+        .ok()
+        .and_then(|x| x.strip_prefix("ref:").map(|x| x.trim().to_owned()))
+    {
+        watched.push(format!("../.git/{r}"));
+        // where the ref is once git packs it
+        watched.push("../.git/packed-refs".to_owned());
+    }
+    for f in watched {
+        if Path::new(&f).exists() {
+            println!("cargo:rerun-if-changed={f}");
+        }
+    }
+
+    let commit = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--tags"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .or_else(|| std::env::var("CLASH_GIT_SHA").ok())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=CLASH_BUILD_COMMIT={commit}");
+
+    // reproducible builds pin the date
+    let now = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default()
+        });
+    println!("cargo:rustc-env=CLASH_BUILD_DATE={}", format_date(now));
+
+    // the names docker and go use, e.g. linux/amd64
+    let os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let arch = match std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default() {
+        x if x == "x86_64" => "amd64".to_owned(),
+        x if x == "aarch64" => "arm64".to_owned(),
+        x if x == "x86" => "386".to_owned(),
+        x => x,
+    };
+    println!("cargo:rustc-env=CLASH_BUILD_PLATFORM={os}/{arch}");
+
+    let mut features = std::env::vars()
+        .filter_map(|(k, _)| {
+            k.strip_prefix("CARGO_FEATURE_")
+                .map(|x| x.to_lowercase().replace('_', "-"))
+        })
+        .filter(|x| x != "default")
+        .collect::<Vec<_>>();
+    features.sort();
+    println!(
+        "cargo:rustc-env=CLASH_BUILD_FEATURES={}",
+        features.join(",")
+    );
+}
+
+/// `secs` since the epoch as RFC 3339 in UTC, without pulling in chrono
+fn format_date(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // days to civil date, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,24 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse, routing::post, Router};
+use http::StatusCode;
+
+use crate::app::{api::AppState, dns::ThreadSafeDNSResolver};
+
+#[derive(Clone)]
+struct CacheState {
+    resolver: ThreadSafeDNSResolver,
+}
+
+pub fn routes(resolver: ThreadSafeDNSResolver) -> Router<Arc<AppState>> {
+    let state = CacheState { resolver };
+    Router::new()
+        .route("/fakeip/flush", post(flush_fake_ip))
+        .with_state(state)
+}
+
+/// connections already set up keep going, only new lookups are affected
+async fn flush_fake_ip(State(state): State<CacheState>) -> impl IntoResponse {
+    state.resolver.flush_fake_ip().await;
+    StatusCode::NO_CONTENT
+}
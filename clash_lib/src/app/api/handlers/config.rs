@@ -1,4 +1,4 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{net::Ipv4Addr, path::PathBuf, sync::Arc};
 
 use axum::{
     extract::{Query, State},
@@ -8,9 +8,8 @@ use axum::{
 };
 
 use http::StatusCode;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::sync::Mutex;
-use tracing::warn;
 
 use crate::{
     app::{
@@ -20,7 +19,8 @@ use crate::{
         inbound::manager::{Ports, ThreadSafeInboundManager},
     },
     config::{def, internal::config::BindAddress},
-    GlobalState,
+    proxy::utils::Interface,
+    GlobalState, ReloadRequest,
 };
 
 #[derive(Clone)]
@@ -50,33 +50,92 @@ pub fn routes(
         })
 }
 
-async fn get_configs(State(state): State<ConfigState>) -> impl IntoResponse {
+#[derive(Deserialize)]
+struct GetConfigsQuery {
+    /// the whole config as loaded, with the files merged, instead of the
+    /// settings dashboards show
+    #[serde(default)]
+    effective: bool,
+}
+
+async fn get_configs(
+    State(state): State<ConfigState>,
+    Query(q): Query<GetConfigsQuery>,
+) -> impl IntoResponse {
+    if q.effective {
+        let g = state.global_state.lock().await;
+        return Json(g.effective_config.clone()).into_response();
+    }
+
     let inbound_manager = state.inbound_manager.lock().await;
     let run_mode = state.dispatcher.get_mode().await;
     let global_state = state.global_state.lock().await;
     let dns_resolver = state.dns_resolver;
 
     let ports = inbound_manager.get_ports();
+    let bind_address = inbound_manager.get_bind_address();
 
-    axum::response::Json(PatchConfigRequest {
-        port: ports.port,
-        socks_port: ports.socks_port,
-        redir_port: ports.redir_port,
-        tproxy_port: ports.tproxy_port,
-        mixed_port: ports.mixed_port,
-        bind_address: Some(inbound_manager.get_bind_address().to_string()),
-
-        mode: Some(run_mode),
-        log_level: Some(global_state.log_level),
-        ipv6: Some(dns_resolver.ipv6()),
-        allow_lan: Some(match inbound_manager.get_bind_address() {
-            BindAddress::Any => true,
-            BindAddress::One(one) => match one {
-                crate::proxy::utils::Interface::IpAddr(ip) => !ip.is_loopback(),
-                crate::proxy::utils::Interface::Name(iface) => iface != "lo",
-            },
-        }),
+    axum::response::Json(GetConfigsResponse {
+        ports,
+        bind_address: bind_address.to_string(),
+        allow_lan: allow_lan(bind_address),
+        mode: run_mode,
+        log_level: global_state.log_level,
+        ipv6: dns_resolver.ipv6(),
+        global_client_fingerprint: global_state
+            .effective_config
+            .get("global-client-fingerprint")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_owned(),
+        unified_delay: global_state
+            .effective_config
+            .get("unified-delay")
+            .and_then(|x| x.as_bool())
+            .unwrap_or_default(),
+        ..Default::default()
     })
+    .into_response()
+}
+
+fn allow_lan(bind_address: &BindAddress) -> bool {
+    match bind_address {
+        BindAddress::Any => true,
+        BindAddress::One(one) => match one {
+            Interface::IpAddr(ip) => !ip.is_loopback(),
+            Interface::Name(iface) => iface != "lo",
+        },
+    }
+}
+
+/// the shape of mihomo's `GET /configs`, the fields clash-rs has no
+/// equivalent for are kept at their mihomo defaults so dashboards can render
+/// the settings page
+#[derive(Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct GetConfigsResponse {
+    #[serde(flatten)]
+    ports: Ports,
+    bind_address: String,
+    allow_lan: bool,
+    mode: def::RunMode,
+    log_level: def::LogLevel,
+    ipv6: bool,
+
+    authentication: Vec<String>,
+    skip_auth_prefixes: Vec<String>,
+    lan_allowed_ips: Vec<String>,
+    lan_disallowed_ips: Vec<String>,
+    inbound_tfo: bool,
+    inbound_mptcp: bool,
+    interface_name: String,
+    #[serde(rename = "unified-delay")]
+    unified_delay: bool,
+    tcp_concurrent: bool,
+    sniffing: bool,
+    geodata_mode: bool,
+    find_process_mode: &'static str,
+    global_client_fingerprint: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -91,74 +150,81 @@ struct UploadConfigQuery {
     force: Option<bool>,
 }
 
+/// the running config is only replaced once the new one is fully built, a
+/// config that fails to load is reported back and leaves everything as is.
+/// With neither a path nor a payload the running config is reloaded from
+/// where it came from.
 async fn update_configs(
     _q: Query<UploadConfigQuery>,
     State(state): State<ConfigState>,
     Json(req): Json<UpdateConfigRequest>,
 ) -> impl IntoResponse {
-    let (done, wait) = tokio::sync::oneshot::channel();
-    let g = state.global_state.lock().await;
-    match (req.path, req.payload) {
-        (_, Some(payload)) => {
-            let msg = "config reloading from payload".to_string();
-            let cfg = crate::Config::Str(payload);
-            match g.reload_tx.send((cfg, done)).await {
-                Ok(_) => {
-                    wait.await.unwrap();
-                    (StatusCode::NO_CONTENT, msg).into_response()
+    let (cfg, msg) = {
+        let g = state.global_state.lock().await;
+        match (req.path, req.payload) {
+            (_, Some(payload)) => (
+                Some(crate::Config::Str(payload)),
+                "config reloaded from payload".to_string(),
+            ),
+            (Some(mut path), None) => {
+                if !PathBuf::from(&path).is_absolute() {
+                    path = PathBuf::from(g.cwd.clone())
+                        .join(path)
+                        .to_string_lossy()
+                        .to_string();
                 }
-                Err(_) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "could not signal config reload",
-                )
-                    .into_response(),
-            }
-        }
-        (Some(mut path), None) => {
-            if !PathBuf::from(&path).is_absolute() {
-                path = PathBuf::from(g.cwd.clone())
-                    .join(path)
-                    .to_string_lossy()
-                    .to_string();
-            }
-            if !PathBuf::from(&path).exists() {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    format!("config file {} not found", path),
-                )
-                    .into_response();
-            }
-
-            let msg = format!("config reloading from file {}", path);
-            let cfg: crate::Config = crate::Config::File(path);
-            match g.reload_tx.send((cfg, done)).await {
-                Ok(_) => {
-                    wait.await.unwrap();
-                    (StatusCode::NO_CONTENT, msg).into_response()
+                if !PathBuf::from(&path).exists() {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        format!("config file {} not found", path),
+                    )
+                        .into_response();
                 }
 
-                Err(_) => (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "could not signal config reload",
-                )
-                    .into_response(),
+                let msg = format!("config reloaded from file {}", path);
+                (Some(crate::Config::File(path)), msg)
             }
+            // the running config again, a config from a url is fetched again
+            (None, None) => (None, "config reloaded from its source".to_string()),
         }
-        (None, None) => {
-            (StatusCode::BAD_REQUEST, "no path or payload provided").into_response()
-        }
+    };
+
+    // the reload swaps the global state, so the lock can't be held while
+    // waiting for it
+    let reload_tx = state.global_state.lock().await.reload_tx.clone();
+    let (done, wait) = tokio::sync::oneshot::channel();
+    let req = match cfg {
+        Some(cfg) => ReloadRequest::Reload(cfg, done),
+        None => ReloadRequest::Refresh(done),
+    };
+    if reload_tx.send(req).await.is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not signal config reload",
+        )
+            .into_response();
+    }
+
+    match wait.await {
+        Ok(Ok(())) => (StatusCode::NO_CONTENT, msg).into_response(),
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, e).into_response(),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "config reload was aborted",
+        )
+            .into_response(),
     }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "kebab-case")]
+/// all fields are optional, a port of 0 disables that listener
+#[derive(Default)]
 struct PatchConfigRequest {
     port: Option<u16>,
     socks_port: Option<u16>,
     redir_port: Option<u16>,
     tproxy_port: Option<u16>,
     mixed_port: Option<u16>,
-    bind_address: Option<String>,
+    bind_address: Option<BindAddress>,
     mode: Option<def::RunMode>,
     log_level: Option<def::LogLevel>,
     ipv6: Option<bool>,
@@ -166,6 +232,46 @@ struct PatchConfigRequest {
 }
 
 impl PatchConfigRequest {
+    /// unknown fields are ignored, a known field with a bad value is an error
+    /// naming that field
+    fn from_json(body: &serde_json::Value) -> Result<Self, String> {
+        let Some(body) = body.as_object() else {
+            return Err("body must be a JSON object".to_owned());
+        };
+
+        fn field<T: DeserializeOwned>(
+            body: &serde_json::Map<String, serde_json::Value>,
+            name: &str,
+        ) -> Result<Option<T>, String> {
+            match body.get(name) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(v) => T::deserialize(v)
+                    .map(Some)
+                    .map_err(|e| format!("invalid value for {}: {}", name, e)),
+            }
+        }
+
+        let bind_address = field::<String>(body, "bind-address")?
+            .map(|x| {
+                x.parse::<BindAddress>()
+                    .map_err(|_| format!("invalid value for bind-address: {}", x))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            port: field(body, "port")?,
+            socks_port: field(body, "socks-port")?,
+            redir_port: field(body, "redir-port")?,
+            tproxy_port: field(body, "tproxy-port")?,
+            mixed_port: field(body, "mixed-port")?,
+            bind_address,
+            mode: field(body, "mode")?,
+            log_level: field(body, "log-level")?,
+            ipv6: field(body, "ipv6")?,
+            allow_lan: field(body, "allow-lan")?,
+        })
+    }
+
     fn rebuild_listeners(&self) -> bool {
         self.port.is_some()
             || self.socks_port.is_some()
@@ -173,66 +279,83 @@ impl PatchConfigRequest {
             || self.tproxy_port.is_some()
             || self.mixed_port.is_some()
             || self.bind_address.is_some()
+            || self.allow_lan.is_some()
+    }
+}
+
+fn patch_port(new: Option<u16>, current: Option<u16>) -> Option<u16> {
+    match new {
+        Some(0) => None,
+        Some(port) => Some(port),
+        None => current,
     }
 }
 
 async fn patch_configs(
     State(state): State<ConfigState>,
-    Json(payload): Json<PatchConfigRequest>,
+    Json(body): Json<serde_json::Value>,
 ) -> impl IntoResponse {
-    if payload.allow_lan.is_some() {
-        warn!(
-            "setting allow_lan doesn't do anything. please set bind_address to a \
-             LAN address instead."
-        );
-    }
+    let payload = match PatchConfigRequest::from_json(&body) {
+        Ok(payload) => payload,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
 
     let mut inbound_manager = state.inbound_manager.lock().await;
 
-    if let Some(bind_address) = payload.bind_address.clone() {
-        match bind_address.parse::<BindAddress>() {
-            Ok(bind_address) => {
-                inbound_manager.set_bind_address(bind_address);
-            }
-            Err(_) => {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    format!("invalid bind address: {}", bind_address),
-                )
-                    .into_response();
+    match (payload.bind_address.clone(), payload.allow_lan) {
+        (Some(bind_address), _) => inbound_manager.set_bind_address(bind_address),
+        // allow-lan only flips between all interfaces and loopback, keeping an
+        // explicitly configured address that already matches
+        (None, Some(allow)) => {
+            if allow != allow_lan(inbound_manager.get_bind_address()) {
+                inbound_manager.set_bind_address(if allow {
+                    BindAddress::Any
+                } else {
+                    BindAddress::One(Interface::IpAddr(Ipv4Addr::LOCALHOST.into()))
+                });
             }
         }
+        (None, None) => {}
     }
 
     let mut global_state = state.global_state.lock().await;
 
     if payload.rebuild_listeners() {
-        // TODO: maybe buggy
         let current_ports = inbound_manager.get_ports();
 
         let ports = Ports {
-            port: payload.port.or(current_ports.port),
-            socks_port: payload.socks_port.or(current_ports.socks_port),
-            redir_port: payload.redir_port.or(current_ports.redir_port),
-            tproxy_port: payload.tproxy_port.or(current_ports.tproxy_port),
-            mixed_port: payload.mixed_port.or(current_ports.mixed_port),
+            port: patch_port(payload.port, current_ports.port),
+            socks_port: patch_port(payload.socks_port, current_ports.socks_port),
+            redir_port: patch_port(payload.redir_port, current_ports.redir_port),
+            tproxy_port: patch_port(payload.tproxy_port, current_ports.tproxy_port),
+            mixed_port: patch_port(payload.mixed_port, current_ports.mixed_port),
         };
 
         inbound_manager.rebuild_listeners(ports);
 
+        // wait for the old listeners to be dropped so their ports are free to
+        // bind again
         global_state.inbound_listener_handle.abort();
+        let _ = (&mut global_state.inbound_listener_handle).await;
 
-        let r = inbound_manager.get_runner().unwrap();
+        let r = match inbound_manager.get_runner() {
+            Ok(r) => r,
+            Err(e) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                    .into_response()
+            }
+        };
 
         global_state.inbound_listener_handle = tokio::spawn(r);
     }
 
+    // new connections pick the mode up as they are dispatched
     if let Some(mode) = payload.mode {
-        state.dispatcher.set_mode(mode).await;
+        state.dispatcher.set_mode(mode);
     }
 
     if let Some(log_level) = payload.log_level {
-        global_state.log_level = log_level;
+        crate::set_log_level(&mut global_state, log_level);
     }
 
     if let Some(ipv6) = payload.ipv6 {
@@ -241,3 +364,46 @@ async fn patch_configs(
 
     StatusCode::ACCEPTED.into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{patch_port, PatchConfigRequest};
+    use crate::config::def::RunMode;
+
+    #[test]
+    fn test_patch_config_request() {
+        let req = PatchConfigRequest::from_json(&json!({
+            "mode": "global",
+            "mixed-port": 7891,
+            "unknown-field": 1,
+        }))
+        .unwrap();
+        assert!(matches!(req.mode, Some(RunMode::Global)));
+        assert_eq!(req.mixed_port, Some(7891));
+        assert!(req.rebuild_listeners());
+
+        let err = PatchConfigRequest::from_json(&json!({"mode": "fast"}))
+            .err()
+            .unwrap();
+        assert!(err.contains("mode"));
+
+        let err = PatchConfigRequest::from_json(&json!({"port": 70000}))
+            .err()
+            .unwrap();
+        assert!(err.contains("port"));
+
+        let err = PatchConfigRequest::from_json(&json!({"allow-lan": "yes"}))
+            .err()
+            .unwrap();
+        assert!(err.contains("allow-lan"));
+    }
+
+    #[test]
+    fn test_patch_port() {
+        assert_eq!(patch_port(Some(0), Some(7890)), None);
+        assert_eq!(patch_port(Some(7891), Some(7890)), Some(7891));
+        assert_eq!(patch_port(None, Some(7890)), Some(7890));
+    }
+}
@@ -16,18 +16,28 @@ use tracing::{debug, warn};
 use crate::app::{
     api::{handlers::utils::is_request_websocket, AppState},
     dispatcher::StatisticsManager,
+    inbound::manager::ThreadSafeInboundManager,
 };
 
 #[derive(Clone)]
 struct ConnectionState {
     statistics_manager: Arc<StatisticsManager>,
+    inbound_manager: ThreadSafeInboundManager,
 }
 
-pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+pub fn routes(
+    statistics_manager: Arc<StatisticsManager>,
+    inbound_manager: ThreadSafeInboundManager,
+) -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(get_connections).delete(close_all_connection))
+        .route("/stats", get(get_connection_stats))
+        .route("/sources", get(get_sources))
         .route("/{id}", delete(close_connection))
-        .with_state(ConnectionState { statistics_manager })
+        .with_state(ConnectionState {
+            statistics_manager,
+            inbound_manager,
+        })
 }
 
 #[derive(Deserialize)]
@@ -82,6 +92,18 @@ async fn get_connections(
     })
 }
 
+async fn get_connection_stats(
+    State(state): State<ConnectionState>,
+) -> impl IntoResponse {
+    let limiter = state.inbound_manager.lock().await.connection_limiter();
+    Json(limiter.stats())
+}
+
+/// the clients by source IP, with what they moved since start
+async fn get_sources(State(state): State<ConnectionState>) -> impl IntoResponse {
+    Json(state.statistics_manager.totals().sources().list())
+}
+
 async fn close_connection(
     State(state): State<ConnectionState>,
     Path(id): Path<uuid::Uuid>,
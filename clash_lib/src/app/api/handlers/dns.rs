@@ -3,7 +3,7 @@ use std::sync::Arc;
 use axum::{
     extract::{Query, State},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
     Json, Router,
 };
 use hickory_proto::{op::Message, rr::RecordType};
@@ -23,9 +23,15 @@ pub fn routes(resolver: ThreadSafeDNSResolver) -> Router<Arc<AppState>> {
     let state = DNSState { resolver };
     Router::new()
         .route("/query", get(query_dns))
+        .route("/flush", post(flush_dns))
         .with_state(state)
 }
 
+async fn flush_dns(State(state): State<DNSState>) -> impl IntoResponse {
+    state.resolver.flush_cache().await;
+    StatusCode::NO_CONTENT
+}
+
 #[derive(Deserialize)]
 struct DnsQUery {
     name: String,
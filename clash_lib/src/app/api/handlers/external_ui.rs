@@ -0,0 +1,187 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use axum::{extract::State, response::IntoResponse, routing::post, Json, Router};
+use http::StatusCode;
+use serde_json::Map;
+use tokio::sync::Mutex;
+use tower_http::services::{ServeDir, ServeFile};
+use tracing::{error, info};
+
+use crate::{
+    app::api::AppState,
+    common::{http::HttpClient, utils::download},
+};
+
+/// The dashboard folder served at `/ui/`, optionally kept up to date from a
+/// zip archive at `external-ui-url`.
+#[derive(Clone)]
+pub struct ExternalUi {
+    dir: PathBuf,
+    url: Option<String>,
+    http_client: HttpClient,
+    // one download at a time
+    updating: Arc<Mutex<()>>,
+}
+
+impl ExternalUi {
+    pub fn new(dir: PathBuf, url: Option<String>, http_client: HttpClient) -> Self {
+        Self {
+            dir,
+            url,
+            http_client,
+            updating: Default::default(),
+        }
+    }
+
+    /// Paths leaving the folder are rejected by `ServeDir`, anything that is
+    /// not a file goes to index.html so dashboards can route on the client.
+    pub fn service(&self) -> ServeDir<ServeFile> {
+        ServeDir::new(&self.dir)
+            .fallback(ServeFile::new(self.dir.join("index.html")))
+    }
+
+    /// Downloads the dashboard if there is an url for it and the folder is
+    /// missing.
+    pub async fn ensure_downloaded(&self) {
+        if self.url.is_none() || self.dir.exists() {
+            return;
+        }
+        if let Err(e) = self.update().await {
+            error!("failed to download external ui: {}", e);
+        }
+    }
+
+    /// The folder is only replaced once the new archive is fully extracted.
+    pub async fn update(&self) -> anyhow::Result<()> {
+        let url = self
+            .url
+            .as_ref()
+            .ok_or_else(|| anyhow!("external-ui-url is not set"))?;
+        let _g = self.updating.lock().await;
+
+        info!("downloading external ui from {}", url);
+        let archive = sibling(&self.dir, ".zip");
+        let staging = sibling(&self.dir, ".new");
+        download(url, &archive, &self.http_client).await?;
+
+        let (a, s) = (archive.clone(), staging.clone());
+        let extracted = tokio::task::spawn_blocking(move || extract_zip(&a, &s))
+            .await
+            .map_err(|e| anyhow!("extract task failed: {}", e));
+        let _ = tokio::fs::remove_file(&archive).await;
+        extracted??;
+
+        if self.dir.exists() {
+            tokio::fs::remove_dir_all(&self.dir).await?;
+        }
+        tokio::fs::rename(&staging, &self.dir).await?;
+        info!("external ui updated at {}", self.dir.display());
+        Ok(())
+    }
+}
+
+pub fn routes(ui: ExternalUi) -> Router<Arc<AppState>> {
+    Router::new().route("/ui", post(upgrade_ui)).with_state(ui)
+}
+
+async fn upgrade_ui(State(ui): State<ExternalUi>) -> impl IntoResponse {
+    match ui.update().await {
+        Ok(_) => {
+            let mut map = Map::new();
+            map.insert("status".to_owned(), "ok".into());
+            Json(map).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// `dir` with `suffix` appended to its name, e.g. `ui` -> `ui.new`
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = dir.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+fn extract_zip(archive: &Path, dest: &Path) -> anyhow::Result<()> {
+    if dest.exists() {
+        fs::remove_dir_all(dest)?;
+    }
+    fs::create_dir_all(dest)?;
+
+    let mut zip = zip::ZipArchive::new(fs::File::open(archive)?)?;
+    // releases usually wrap everything in one folder, e.g. `yacd-gh-pages/`
+    let root = common_root(zip.file_names()).unwrap_or_default();
+
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        // None for absolute paths and paths with `..`
+        let name = entry
+            .enclosed_name()
+            .ok_or_else(|| anyhow!("invalid path in archive: {}", entry.name()))?;
+        let Ok(rel) = name.strip_prefix(&root) else {
+            continue;
+        };
+        if rel.as_os_str().is_empty() {
+            continue;
+        }
+
+        let out = dest.join(rel);
+        if entry.is_dir() {
+            fs::create_dir_all(&out)?;
+        } else {
+            if let Some(parent) = out.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            std::io::copy(&mut entry, &mut fs::File::create(&out)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// The folder every entry of the archive is in, if there is one.
+fn common_root<'a>(names: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut root = None;
+    for name in names {
+        // a file at the top means there is no wrapping folder
+        let (top, _) = name.split_once('/')?;
+        match root {
+            None => root = Some(top),
+            Some(r) if r == top => {}
+            Some(_) => return None,
+        }
+    }
+    root.map(ToOwned::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{common_root, sibling};
+
+    #[test]
+    fn test_common_root() {
+        let names = ["dist/", "dist/index.html", "dist/assets/app.js"];
+        assert_eq!(common_root(names.into_iter()), Some("dist".to_owned()));
+
+        let names = ["index.html", "assets/app.js"];
+        assert_eq!(common_root(names.into_iter()), None);
+
+        let names = ["a/index.html", "b/app.js"];
+        assert_eq!(common_root(names.into_iter()), None);
+    }
+
+    #[test]
+    fn test_sibling() {
+        assert_eq!(
+            sibling(Path::new("/etc/clash/ui"), ".new"),
+            Path::new("/etc/clash/ui.new")
+        );
+    }
+}
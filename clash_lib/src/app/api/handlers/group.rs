@@ -0,0 +1,163 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, patch},
+    Json, Router,
+};
+use futures::{stream, StreamExt};
+use http::StatusCode;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    app::{
+        api::{
+            middlewares::rate_limit::{limit_delay_tests, ApiLimits},
+            AppState,
+        },
+        outbound::manager::ThreadSafeOutboundManager,
+    },
+    proxy::OutboundType,
+};
+
+/// how many members of a group are tested at the same time
+const GROUP_DELAY_CONCURRENCY: usize = 10;
+
+#[derive(Clone)]
+struct GroupState {
+    outbound_manager: ThreadSafeOutboundManager,
+}
+
+pub fn routes(
+    outbound_manager: ThreadSafeOutboundManager,
+    limits: Arc<ApiLimits>,
+) -> Router<Arc<AppState>> {
+    let state = GroupState { outbound_manager };
+    Router::new()
+        .route("/{name}", patch(patch_group))
+        .route(
+            "/{name}/delay",
+            get(get_group_delay).route_layer(middleware::from_fn_with_state(
+                limits,
+                limit_delay_tests,
+            )),
+        )
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct DelayRequest {
+    url: String,
+    #[serde(default = "default_delay_timeout")]
+    timeout: u64,
+}
+
+fn default_delay_timeout() -> u64 {
+    5000
+}
+
+/// Tests every member of the group and returns the delays of those that
+/// answered, failed members are left out. The results go into each member's
+/// history the same way the single proxy delay test does.
+async fn get_group_delay(
+    State(state): State<GroupState>,
+    Path(name): Path<String>,
+    Query(q): Query<DelayRequest>,
+) -> Response {
+    let outbound_manager = state.outbound_manager.clone();
+    let Some(group) = outbound_manager.get_outbound(&name) else {
+        return (StatusCode::NOT_FOUND, format!("group {} not found", name))
+            .into_response();
+    };
+    let Some(members) = outbound_manager.group_members(&group).await else {
+        return (StatusCode::NOT_FOUND, format!("{} is not a group", name))
+            .into_response();
+    };
+
+    let timeout = Duration::from_millis(q.timeout);
+    let delays: HashMap<String, u16> = stream::iter(members)
+        .filter(|p| {
+            futures::future::ready(!matches!(p.proto(), OutboundType::Reject))
+        })
+        .map(|p| {
+            let outbound_manager = outbound_manager.clone();
+            let url = q.url.clone();
+            async move {
+                let n = p.name().to_owned();
+                outbound_manager
+                    .url_test(p, &url, timeout)
+                    .await
+                    .map(|(delay, _)| (n.clone(), delay))
+                    .map_err(|e| debug!("group delay test for {} failed: {}", n, e))
+                    .ok()
+            }
+        })
+        .buffer_unordered(GROUP_DELAY_CONCURRENCY)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await;
+
+    // a url-test group picks its fastest member when asked for it, do it now
+    // so the selection reflects the results right away
+    if matches!(group.proto(), OutboundType::UrlTest) {
+        let now = outbound_manager.selected_member(&group).await;
+        debug!("{} now uses {} after delay test", name, now.name());
+    }
+
+    Json(delays).into_response()
+}
+
+#[derive(Deserialize)]
+struct PatchGroupRequest {
+    weights: HashMap<String, u32>,
+}
+
+/// Sets the weights of some members of a `strategy: weighted` load-balance
+/// group, the others keep theirs. New connections use them right away, and
+/// they're back to the config's on reload. Nothing is set if any of the
+/// names isn't a member.
+async fn patch_group(
+    State(state): State<GroupState>,
+    Path(name): Path<String>,
+    Json(payload): Json<PatchGroupRequest>,
+) -> Response {
+    let outbound_manager = state.outbound_manager.clone();
+    let Some(group) = outbound_manager.get_outbound(&name) else {
+        return (StatusCode::NOT_FOUND, format!("group {} not found", name))
+            .into_response();
+    };
+    let Some(weights) = outbound_manager.get_load_balance_weights(&name) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("{} is not a weighted load-balance group", name),
+        )
+            .into_response();
+    };
+
+    let members = outbound_manager
+        .group_members(&group)
+        .await
+        .unwrap_or_default();
+    let mut unknown = payload
+        .weights
+        .keys()
+        .filter(|x| !members.iter().any(|m| m.name() == x.as_str()))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        unknown.sort_unstable();
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("not members of {}: {}", name, unknown.join(", ")),
+        )
+            .into_response();
+    }
+
+    let mut weights = weights.write().unwrap();
+    weights.extend(payload.weights);
+    debug!("{} weights now {:?}", name, weights);
+    Json(weights.clone()).into_response()
+}
@@ -1,34 +1,106 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
-    extract::{ws::Message, ConnectInfo, State, WebSocketUpgrade},
+    extract::{ws::Message, ConnectInfo, Query, State, WebSocketUpgrade},
     response::IntoResponse,
 };
 
-use tracing::warn;
+use futures::{SinkExt, StreamExt};
+use http::StatusCode;
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
 
-use crate::app::api::AppState;
+use crate::{
+    app::{api::AppState, logging::LogEvent},
+    config::def::LogLevel,
+};
+
+#[derive(Deserialize)]
+pub struct GetLogsQuery {
+    level: Option<LogLevel>,
+}
 
+/// a bad `level` is rejected with 400 by the Query extractor before the
+/// websocket upgrade
 pub async fn handle(
     ws: WebSocketUpgrade,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    addr: Option<ConnectInfo<SocketAddr>>,
     State(state): State<Arc<AppState>>,
+    Query(q): Query<GetLogsQuery>,
 ) -> impl IntoResponse {
+    let level = q.level.unwrap_or(LogLevel::Info);
+
+    // there is no peer address over the unix socket
+    let addr = addr.map_or_else(|| "unix".to_owned(), |x| x.0.to_string());
+    let peer = addr.clone();
     ws.on_failed_upgrade(move |e| {
-        warn!("ws upgrade error: {} with {}", e, addr);
+        warn!("ws upgrade error: {} with {}", e, peer);
     })
-    .on_upgrade(move |mut socket| async move {
-        let mut rx = state.log_source_tx.subscribe();
-        while let Ok(evt) = rx.recv().await {
-            let res = serde_json::to_vec(&evt).unwrap();
-
-            if let Err(e) = socket
-                .send(Message::Text(String::from_utf8(res).unwrap().into()))
-                .await
-            {
-                warn!("ws send error: {}", e);
-                break;
+    .on_upgrade(move |socket| async move {
+        // nothing falls in between or is sent twice
+        let (recent, mut logs) = state.recent_logs.subscribe(&state.log_source_tx);
+        let (mut tx, mut rx) = socket.split();
+
+        for evt in recent.iter().filter(|x| x.level >= level) {
+            if tx.send(to_message(evt, true)).await.is_err() {
+                return;
+            }
+        }
+
+        // the broadcast channel is the bounded per client queue, a slow client
+        // lags behind and misses events instead of blocking the logging
+        let mut dropped = 0;
+        loop {
+            tokio::select! {
+                evt = logs.recv() => {
+                    let evt = match evt {
+                        Ok(evt) => evt,
+                        Err(RecvError::Lagged(n)) => {
+                            dropped += n;
+                            LogEvent {
+                                level: LogLevel::Warning,
+                                msg: format!(
+                                    "{} log events dropped for slow consumer",
+                                    n
+                                ),
+                                fields: Default::default(),
+                            }
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    if evt.level < level {
+                        continue;
+                    }
+                    if let Err(e) = tx.send(to_message(&evt, false)).await {
+                        debug!("ws send error: {}", e);
+                        break;
+                    }
+                }
+                msg = rx.next() => match msg {
+                    // client gone
+                    None | Some(Ok(Message::Close(_))) | Some(Err(_)) => break,
+                    Some(Ok(_)) => {}
+                },
             }
         }
+
+        if dropped > 0 {
+            debug!("{} log events dropped for /logs client {}", dropped, addr);
+        }
     })
 }
+
+/// replayed events from before the client connected are marked as such
+fn to_message(evt: &LogEvent, replayed: bool) -> Message {
+    let mut v = serde_json::to_value(evt).unwrap();
+    if replayed {
+        v["replayed"] = true.into();
+    }
+    Message::Text(v.to_string().into())
+}
+
+pub async fn clear(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    state.recent_logs.clear();
+    StatusCode::NO_CONTENT
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{
     body::Body,
@@ -6,8 +6,10 @@ use axum::{
     response::IntoResponse,
     Json,
 };
+use futures::{SinkExt, StreamExt};
 use http::HeaderMap;
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 use tracing::{debug, warn};
 
 use crate::app::api::AppState;
@@ -50,28 +52,38 @@ pub async fn handle(
     ws.on_failed_upgrade(|e| {
         warn!("ws upgrade error: {}", e);
     })
-    .on_upgrade(move |mut socket| async move {
-        let interval = q.interval;
-
-        let mgr = state.statistics_manager.clone();
+    .on_upgrade(move |socket| async move {
+        // the shared sampler ticks every second, a longer interval skips ticks
+        let interval = Duration::from_secs(q.interval.unwrap_or(1));
+        let mut memory = state.statistics_manager.subscribe_memory();
+        let (mut tx, mut rx) = socket.split();
+        let mut last_sent: Option<Instant> = None;
 
         loop {
-            let snapshot = GetMemoryResponse {
-                inuse: mgr.memory_usage(),
-                oslimit: 0,
-            };
-            let j = serde_json::to_vec(&snapshot).unwrap();
-            let body = String::from_utf8(j).unwrap();
+            tokio::select! {
+                changed = memory.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let inuse = *memory.borrow_and_update();
+                    if last_sent.is_some_and(|x| x.elapsed() < interval) {
+                        continue;
+                    }
+                    last_sent = Some(Instant::now());
 
-            if let Err(e) = socket.send(Message::Text(body.into())).await {
-                debug!("send memory snapshot failed: {}", e);
-                break;
+                    let snapshot = GetMemoryResponse { inuse, oslimit: 0 };
+                    let j = serde_json::to_string(&snapshot).unwrap();
+                    if let Err(e) = tx.send(Message::Text(j.into())).await {
+                        debug!("send memory snapshot failed: {}", e);
+                        break;
+                    }
+                }
+                msg = rx.next() => match msg {
+                    // client gone
+                    None | Some(Ok(Message::Close(_))) | Some(Err(_)) => break,
+                    Some(Ok(_)) => {}
+                },
             }
-
-            tokio::time::sleep(tokio::time::Duration::from_secs(
-                interval.unwrap_or(1),
-            ))
-            .await;
         }
     })
 }
@@ -1,13 +1,20 @@
+pub mod cache;
 pub mod config;
 pub mod connection;
 pub mod dns;
+pub mod external_ui;
+pub mod group;
 pub mod hello;
 pub mod log;
 pub mod memory;
+pub mod network;
 pub mod provider;
 pub mod proxy;
 pub mod restart;
 pub mod rule;
+pub mod schedule;
+pub mod shutdown;
+pub mod statistics;
 pub mod traffic;
 pub mod version;
 
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{ws::Message, FromRequest, Request, State, WebSocketUpgrade},
+    response::IntoResponse,
+    Json,
+};
+use futures::{SinkExt, StreamExt};
+use http::HeaderMap;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+
+use crate::app::{
+    api::AppState,
+    net_monitor::{self, DefaultInterface},
+};
+
+use super::utils::is_request_websocket;
+
+#[derive(Serialize)]
+struct GetNetworkResponse {
+    interface: Option<DefaultInterface>,
+}
+
+/// The default interface, or over a websocket, each time it changes.
+pub async fn handle(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    if !is_request_websocket(headers) {
+        return Json(GetNetworkResponse {
+            interface: net_monitor::default_interface(),
+        })
+        .into_response();
+    }
+
+    let ws = match WebSocketUpgrade::from_request(req, &state).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!("ws upgrade error: {}", e);
+            return e.into_response();
+        }
+    };
+
+    ws.on_failed_upgrade(|e| {
+        warn!("ws upgrade error: {}", e);
+    })
+    .on_upgrade(move |socket| async move {
+        let mut changes = net_monitor::subscribe();
+        let (mut tx, mut rx) = socket.split();
+
+        loop {
+            tokio::select! {
+                change = changes.recv() => {
+                    let change = match change {
+                        Ok(c) => c,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    };
+                    let j = serde_json::to_string(&change).unwrap();
+                    if let Err(e) = tx.send(Message::Text(j.into())).await {
+                        debug!("send network change failed: {}", e);
+                        break;
+                    }
+                }
+                msg = rx.next() => match msg {
+                    // client gone
+                    None | Some(Ok(Message::Close(_))) | Some(Err(_)) => break,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    })
+}
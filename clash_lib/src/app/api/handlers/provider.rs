@@ -12,7 +12,13 @@ use serde::Deserialize;
 
 use crate::{
     app::{
-        api::AppState, outbound::manager::ThreadSafeOutboundManager,
+        api::{
+            middlewares::rate_limit::{
+                limit_delay_tests, limit_provider_updates, ApiLimits,
+            },
+            AppState,
+        },
+        outbound::manager::ThreadSafeOutboundManager,
         remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
     },
     proxy::AnyOutboundHandler,
@@ -22,20 +28,39 @@ struct ProviderState {
     outbound_manager: ThreadSafeOutboundManager,
 }
 
-pub fn routes(outbound_manager: ThreadSafeOutboundManager) -> Router<Arc<AppState>> {
+pub fn routes(
+    outbound_manager: ThreadSafeOutboundManager,
+    limits: Arc<ApiLimits>,
+) -> Router<Arc<AppState>> {
     let state = ProviderState { outbound_manager };
     Router::new()
         .route("/", get(get_providers))
         .nest(
             "/{provider_name}",
             Router::new()
-                .route("/", get(get_provider).put(update_provider))
+                .route(
+                    "/",
+                    get(get_provider).put(update_provider).route_layer(
+                        middleware::from_fn_with_state(
+                            limits.clone(),
+                            limit_provider_updates,
+                        ),
+                    ),
+                )
                 .route("/healthcheck", get(provider_healthcheck))
                 .nest(
                     "/{proxy_name}",
                     Router::new()
                         .route("/", get(get_proxy))
-                        .route("/healthcheck", get(get_proxy_delay))
+                        .route(
+                            "/healthcheck",
+                            get(get_proxy_delay).route_layer(
+                                middleware::from_fn_with_state(
+                                    limits,
+                                    limit_delay_tests,
+                                ),
+                            ),
+                        )
                         .layer(middleware::from_fn_with_state(
                             state.clone(),
                             find_provider_proxy_by_name,
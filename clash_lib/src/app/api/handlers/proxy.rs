@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
 
 use axum::{
     extract::{Extension, Path, Query, State},
@@ -10,29 +10,45 @@ use axum::{
 };
 
 use http::{header, HeaderMap, StatusCode};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::sync::Semaphore;
 
 use crate::{
     app::{
-        api::AppState, outbound::manager::ThreadSafeOutboundManager,
+        api::{
+            middlewares::rate_limit::{limit_delay_tests, ApiLimits},
+            AppState,
+        },
+        dispatcher::StatisticsManager,
+        outbound::manager::ThreadSafeOutboundManager,
         profile::ThreadSafeCacheFile,
     },
-    proxy::AnyOutboundHandler,
+    common::rate_limit::Bandwidth,
+    proxy::{AnyOutboundHandler, OutboundType},
 };
 
+/// how many delay tests can run at the same time, the rest wait for a slot
+const MAX_CONCURRENT_DELAY_TESTS: usize = 16;
+
 #[derive(Clone)]
 pub struct ProxyState {
     outbound_manager: ThreadSafeOutboundManager,
     cache_store: ThreadSafeCacheFile,
+    statistics_manager: Arc<StatisticsManager>,
+    delay_test_limit: Arc<Semaphore>,
 }
 
 pub fn routes(
     outbound_manager: ThreadSafeOutboundManager,
     cache_store: ThreadSafeCacheFile,
+    statistics_manager: Arc<StatisticsManager>,
+    limits: Arc<ApiLimits>,
 ) -> Router<Arc<AppState>> {
     let state = ProxyState {
         outbound_manager,
         cache_store,
+        statistics_manager,
+        delay_test_limit: Arc::new(Semaphore::new(MAX_CONCURRENT_DELAY_TESTS)),
     };
     Router::new()
         .route("/", get(get_proxies))
@@ -40,7 +56,22 @@ pub fn routes(
             "/{name}",
             Router::new()
                 .route("/", get(get_proxy).put(update_proxy))
-                .route("/delay", get(get_proxy_delay))
+                .route(
+                    "/delay",
+                    get(get_proxy_delay).route_layer(
+                        middleware::from_fn_with_state(
+                            limits.clone(),
+                            limit_delay_tests,
+                        ),
+                    ),
+                )
+                .route(
+                    "/{member}/delay",
+                    get(get_member_delay).route_layer(
+                        middleware::from_fn_with_state(limits, limit_delay_tests),
+                    ),
+                )
+                .route("/limits", get(get_limits).patch(update_limits))
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
                     find_proxy_by_name,
@@ -60,10 +91,11 @@ async fn get_proxies(State(state): State<ProxyState>) -> impl IntoResponse {
 
 async fn find_proxy_by_name(
     State(state): State<ProxyState>,
-    Path(name): Path<String>,
+    Path(params): Path<HashMap<String, String>>,
     mut req: Request<axum::body::Body>,
     next: Next,
 ) -> Response {
+    let name = params.get("name").cloned().unwrap_or_default();
     let outbound_manager = state.outbound_manager.clone();
     if let Some(proxy) = outbound_manager.get_outbound(&name) {
         req.extensions_mut().insert(proxy);
@@ -78,7 +110,12 @@ async fn get_proxy(
     State(state): State<ProxyState>,
 ) -> impl IntoResponse {
     let outbound_manager = state.outbound_manager.clone();
-    axum::response::Json(outbound_manager.get_proxy(&proxy).await)
+    let mut r = outbound_manager.get_proxy(&proxy).await;
+    r.insert(
+        "totals".to_owned(),
+        Box::new(state.statistics_manager.totals().proxy(proxy.name())),
+    );
+    axum::response::Json(r)
 }
 
 #[derive(Deserialize)]
@@ -124,30 +161,181 @@ async fn update_proxy(
 #[derive(Deserialize)]
 struct DelayRequest {
     url: String,
-    timeout: u16,
+    #[serde(default = "default_delay_timeout")]
+    timeout: u64,
+}
+
+fn default_delay_timeout() -> u64 {
+    5000
 }
+
+/// the delay of a group is the delay of its currently selected proxy, the
+/// result is recorded in that proxy's history
 async fn get_proxy_delay(
     State(state): State<ProxyState>,
     Extension(proxy): Extension<AnyOutboundHandler>,
     Query(q): Query<DelayRequest>,
 ) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONNECTION, "close".parse().unwrap());
+
     let outbound_manager = state.outbound_manager.clone();
-    let timeout = Duration::from_millis(q.timeout.into());
+    let proxy = outbound_manager.selected_member(&proxy).await;
     let n = proxy.name().to_owned();
+
+    if matches!(proxy.proto(), OutboundType::Reject) {
+        return delay_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            headers,
+            format!("proxy {} rejects all connections", n),
+        );
+    }
+
+    // "test all" from a dashboard fires one request per proxy at once
+    let Ok(_permit) = state.delay_test_limit.acquire().await else {
+        return delay_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            headers,
+            "delay test is shutting down".to_owned(),
+        );
+    };
+
+    let timeout = Duration::from_millis(q.timeout);
+    delay_response(
+        headers,
+        &n,
+        outbound_manager.url_test(proxy, &q.url, timeout).await,
+    )
+}
+
+/// The delay of `member` as `group` uses it, e.g. through the hops before
+/// it in a relay. The result is recorded in the member's history, tagged
+/// with the group, and the group's selection is left alone.
+async fn get_member_delay(
+    State(state): State<ProxyState>,
+    Extension(group): Extension<AnyOutboundHandler>,
+    Path(params): Path<HashMap<String, String>>,
+    Query(q): Query<DelayRequest>,
+) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     headers.insert(header::CONNECTION, "close".parse().unwrap());
-    match outbound_manager.url_test(proxy, &q.url, timeout).await {
+
+    let member = params.get("member").cloned().unwrap_or_default();
+    let outbound_manager = state.outbound_manager.clone();
+    let Some(proxy) = outbound_manager.member_via_group(&group, &member).await
+    else {
+        return delay_error(
+            StatusCode::NOT_FOUND,
+            headers,
+            format!("proxy {} is not in group {}", member, group.name()),
+        );
+    };
+
+    let Ok(_permit) = state.delay_test_limit.acquire().await else {
+        return delay_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            headers,
+            "delay test is shutting down".to_owned(),
+        );
+    };
+
+    let timeout = Duration::from_millis(q.timeout);
+    delay_response(
+        headers,
+        &member,
+        outbound_manager
+            .group_url_test(proxy, group.name(), &q.url, timeout)
+            .await,
+    )
+}
+
+fn delay_response(
+    headers: HeaderMap,
+    name: &str,
+    result: io::Result<(u16, u16)>,
+) -> Response {
+    match result {
         Ok((delay, mean_delay)) => {
             let mut r = HashMap::new();
             r.insert("delay".to_owned(), delay);
             r.insert("meanDelay".to_owned(), mean_delay);
-            (headers, axum::response::Json(r)).into_response()
+            (headers, Json(r)).into_response()
         }
-        Err(err) => (
-            StatusCode::BAD_REQUEST,
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => delay_error(
+            StatusCode::REQUEST_TIMEOUT,
             headers,
-            format!("get delay for {} failed with error: {}", n, err),
-        )
-            .into_response(),
+            format!("get delay for {} failed with error: {}", name, err),
+        ),
+        Err(err) => delay_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            headers,
+            format!("get delay for {} failed with error: {}", name, err),
+        ),
+    }
+}
+
+fn delay_error(status: StatusCode, headers: HeaderMap, message: String) -> Response {
+    let mut r = HashMap::new();
+    r.insert("message", message);
+    (status, headers, Json(r)).into_response()
+}
+
+#[derive(Serialize)]
+struct Limits {
+    up: Option<Bandwidth>,
+    down: Option<Bandwidth>,
+}
+
+fn limits(outbound_manager: &ThreadSafeOutboundManager, name: &str) -> Limits {
+    match outbound_manager.bandwidth_limit(name) {
+        Some(l) => Limits {
+            up: l.up.rate(),
+            down: l.down.rate(),
+        },
+        None => Limits {
+            up: None,
+            down: None,
+        },
     }
 }
+
+async fn get_limits(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+) -> impl IntoResponse {
+    Json(limits(&state.outbound_manager, proxy.name()))
+}
+
+/// a field that is left out is kept, `null` removes the limit
+#[derive(Deserialize)]
+struct UpdateLimitsRequest {
+    #[serde(default, deserialize_with = "present")]
+    up: Option<Option<Bandwidth>>,
+    #[serde(default, deserialize_with = "present")]
+    down: Option<Option<Bandwidth>>,
+}
+
+fn present<'de, D, T>(d: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(d).map(Some)
+}
+
+/// Changes the bandwidth limits of a proxy or a group until the next reload.
+/// The connections opened while it had no limit at all stay unlimited.
+async fn update_limits(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+    Json(payload): Json<UpdateLimitsRequest>,
+) -> impl IntoResponse {
+    let outbound_manager = &state.outbound_manager;
+    let current = limits(outbound_manager, proxy.name());
+    outbound_manager.set_bandwidth_limit(
+        proxy.name(),
+        payload.up.unwrap_or(current.up),
+        payload.down.unwrap_or(current.down),
+    );
+    Json(limits(outbound_manager, proxy.name()))
+}
@@ -1,53 +1,42 @@
-#[cfg(unix)]
-use std::os::unix::process::CommandExt;
+use std::sync::Arc;
 
-use axum::{response::IntoResponse, Json};
+use axum::{extract::State, response::IntoResponse, routing::post, Json, Router};
+use http::StatusCode;
 use serde_json::Map;
+use tokio::sync::Mutex;
 
-pub async fn handle() -> impl IntoResponse {
-    match std::env::current_exe() {
-        Ok(exec) => {
-            let mut map = Map::new();
-            map.insert("status".to_owned(), "ok".into());
-            tokio::spawn(async move {
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+use crate::{app::api::AppState, GlobalState, ReloadRequest};
 
-                #[cfg(unix)]
-                {
-                    use tracing::info;
+pub fn routes(global_state: Arc<Mutex<GlobalState>>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", post(handle))
+        .with_state(global_state)
+}
 
-                    let err = std::process::Command::new(exec)
-                        .args(std::env::args().skip(1))
-                        .envs(std::env::vars())
-                        .exec();
-                    info!("process restarted: {}", err);
-                }
-                #[cfg(windows)]
-                {
-                    use tracing::error;
+/// Restarts everything in process with the last loaded config and answers
+/// once the new listeners are started. The process exits if it can't come
+/// back up.
+async fn handle(
+    State(global_state): State<Arc<Mutex<GlobalState>>>,
+) -> impl IntoResponse {
+    let reload_tx = global_state.lock().await.reload_tx.clone();
+    let (done, wait) = tokio::sync::oneshot::channel();
+    if reload_tx.send(ReloadRequest::Restart(done)).await.is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not signal restart",
+        )
+            .into_response();
+    }
 
-                    match std::process::Command::new(exec)
-                        .args(std::env::args().skip(1))
-                        .envs(std::env::vars())
-                        .stdin(std::process::Stdio::inherit())
-                        .stdout(std::process::Stdio::inherit())
-                        .stderr(std::process::Stdio::inherit())
-                        .spawn()
-                    {
-                        Ok(_) => {
-                            // exit the current process
-                            std::process::exit(0);
-                        }
-                        Err(e) => {
-                            error!("Failed to restart: {}", e);
-                        }
-                    }
-                }
-            });
+    match wait.await {
+        Ok(Ok(())) => {
+            let mut map = Map::new();
+            map.insert("status".to_owned(), "ok".into());
             Json(map).into_response()
         }
-        Err(e) => {
-            (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
-        }
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "restart was aborted")
+            .into_response(),
     }
 }
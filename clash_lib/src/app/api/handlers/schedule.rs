@@ -0,0 +1,8 @@
+use axum::{response::IntoResponse, Json};
+
+use crate::common::scheduler;
+
+/// the provider updates and health checks, and when they run next
+pub async fn handle() -> impl IntoResponse {
+    Json(scheduler::schedule())
+}
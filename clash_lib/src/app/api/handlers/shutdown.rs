@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use axum::{extract::State, response::IntoResponse, routing::post, Json, Router};
+use http::StatusCode;
+use serde_json::Map;
+use tokio::sync::{mpsc::error::TrySendError, Mutex};
+
+use crate::{app::api::AppState, GlobalState};
+
+pub fn routes(global_state: Arc<Mutex<GlobalState>>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", post(handle))
+        .with_state(global_state)
+}
+
+/// Shuts down the same way SIGTERM does, answering right away as the api
+/// listener is one of the first things stopped.
+async fn handle(
+    State(global_state): State<Arc<Mutex<GlobalState>>>,
+) -> impl IntoResponse {
+    let shutdown_tx = global_state.lock().await.shutdown_tx.clone();
+    // full if a shutdown is already asked for
+    if let Err(TrySendError::Closed(_)) = shutdown_tx.try_send(()) {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not signal shutdown",
+        )
+            .into_response();
+    }
+
+    let mut map = Map::new();
+    map.insert("status".to_owned(), "ok".into());
+    Json(map).into_response()
+}
@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State, http::StatusCode, response::IntoResponse, routing::get, Json,
+    Router,
+};
+
+use crate::app::{api::AppState, dispatcher::StatisticsManager};
+
+pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_statistics).delete(reset_statistics))
+        .with_state(statistics_manager)
+}
+
+/// the totals of each proxy and rule since start
+async fn get_statistics(
+    State(statistics_manager): State<Arc<StatisticsManager>>,
+) -> impl IntoResponse {
+    Json(statistics_manager.totals().summary())
+}
+
+async fn reset_statistics(
+    State(statistics_manager): State<Arc<StatisticsManager>>,
+) -> impl IntoResponse {
+    let totals = statistics_manager.totals();
+    totals.reset();
+    // so a restart doesn't bring them back
+    totals.save().await;
+    StatusCode::NO_CONTENT
+}
@@ -5,8 +5,9 @@ use axum::{
     response::IntoResponse,
 };
 
+use futures::{SinkExt, StreamExt};
 use serde::Serialize;
-use tracing::warn;
+use tracing::{debug, warn};
 
 use crate::app::api::AppState;
 
@@ -17,28 +18,39 @@ struct TrafficResponse {
 }
 pub async fn handle(
     ws: WebSocketUpgrade,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    addr: Option<ConnectInfo<SocketAddr>>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
+    // there is no peer address over the unix socket
+    let addr = addr.map_or_else(|| "unix".to_owned(), |x| x.0.to_string());
     ws.on_failed_upgrade(move |e| {
         warn!("ws upgrade error: {} with {}", e, addr);
     })
-    .on_upgrade(move |mut socket| async move {
-        let mgr = state.statistics_manager.clone();
+    .on_upgrade(move |socket| async move {
+        let mut traffic = state.statistics_manager.subscribe_traffic();
+        let (mut tx, mut rx) = socket.split();
+
         loop {
-            let (up, down) = mgr.now();
-            let res = TrafficResponse { up, down };
-            let j = serde_json::to_vec(&res).unwrap();
+            tokio::select! {
+                changed = traffic.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let (up, down) = *traffic.borrow_and_update();
+                    let res = TrafficResponse { up, down };
+                    let j = serde_json::to_string(&res).unwrap();
 
-            if let Err(e) = socket
-                .send(Message::Text(String::from_utf8(j).unwrap().into()))
-                .await
-            {
-                warn!("ws send error: {}", e);
-                break;
+                    if let Err(e) = tx.send(Message::Text(j.into())).await {
+                        debug!("ws send error: {}", e);
+                        break;
+                    }
+                }
+                msg = rx.next() => match msg {
+                    // client gone
+                    None | Some(Ok(Message::Close(_))) | Some(Err(_)) => break,
+                    Some(Ok(_)) => {}
+                },
             }
-
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
         }
     })
 }
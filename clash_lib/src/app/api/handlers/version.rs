@@ -1,11 +1,18 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use axum::response::IntoResponse;
+use axum::{extract::State, response::IntoResponse};
+use serde_json::{Map, Value};
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+use crate::{app::api::AppState, common::build_info::build_info};
 
-pub async fn handle() -> impl IntoResponse {
-    let mut val = HashMap::new();
-    val.insert("version".to_owned(), VERSION.to_owned());
+pub async fn handle(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut val = match serde_json::to_value(build_info()) {
+        Ok(Value::Object(m)) => m,
+        _ => Map::new(),
+    };
+    val.insert(
+        "memory".to_owned(),
+        Value::from(state.statistics_manager.memory_usage()),
+    );
     axum::response::Json(val)
 }
@@ -1,22 +1,37 @@
-use axum::{body::Body, extract::Query, http::Request, response::Response};
+use std::net::SocketAddr;
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Query},
+    http::Request,
+    response::{IntoResponse, Response},
+    Json,
+};
 use futures::future::BoxFuture;
 
 use serde::Deserialize;
+use serde_json::Map;
 use tower::{Layer, Service};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Clone, Deserialize)]
 struct AuthQuery {
     token: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct AuthMiddlewareLayer {
-    pub token: String,
+    token: String,
+    skip_loopback: bool,
 }
 
 impl AuthMiddlewareLayer {
-    pub fn new(token: String) -> Self {
-        Self { token }
+    /// with `skip_loopback`, requests from loopback addresses don't need the
+    /// secret
+    pub fn new(token: String, skip_loopback: bool) -> Self {
+        Self {
+            token,
+            skip_loopback,
+        }
     }
 }
 
@@ -24,19 +39,25 @@ impl<S> Layer<S> for AuthMiddlewareLayer {
     type Service = AuthMiddleware<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        AuthMiddleware::new(inner, self.token.clone())
+        AuthMiddleware::new(inner, self.token.clone(), self.skip_loopback)
     }
 }
 
-#[derive(Debug, Clone)]
+/// Not Debug on purpose, the secret must never end up in logs.
+#[derive(Clone)]
 pub struct AuthMiddleware<S> {
     inner: S,
     token: String,
+    skip_loopback: bool,
 }
 
 impl<S> AuthMiddleware<S> {
-    pub fn new(inner: S, token: String) -> Self {
-        Self { inner, token }
+    pub fn new(inner: S, token: String, skip_loopback: bool) -> Self {
+        Self {
+            inner,
+            token,
+            skip_loopback,
+        }
     }
 
     fn is_websocket(&self, req: &Request<Body>) -> bool {
@@ -45,6 +66,51 @@ impl<S> AuthMiddleware<S> {
             .map(|upgrade| upgrade == "websocket")
             .unwrap_or(false)
     }
+
+    fn is_loopback(&self, req: &Request<Body>) -> bool {
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|x| x.0.ip().is_loopback())
+    }
+
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        if self.token.is_empty() || (self.skip_loopback && self.is_loopback(req)) {
+            return true;
+        }
+
+        let bearer = req
+            .headers()
+            .get("authorization")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+        if bearer
+            .is_some_and(|x| constant_time_eq(x.as_bytes(), self.token.as_bytes()))
+        {
+            return true;
+        }
+
+        // browsers can't set headers on websockets, dashboards send the
+        // secret in the query string instead
+        self.is_websocket(req)
+            && Query::<AuthQuery>::try_from_uri(req.uri()).is_ok_and(|q| {
+                constant_time_eq(q.token.as_bytes(), self.token.as_bytes())
+            })
+    }
+}
+
+/// doesn't bail out on the first mismatch, so the time taken doesn't tell how
+/// much of the secret was guessed right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unauthorized() -> Response {
+    let mut body = Map::new();
+    body.insert("message".to_owned(), "Unauthorized".into());
+    (http::StatusCode::UNAUTHORIZED, Json(body)).into_response()
 }
 
 impl<S> Service<Request<Body>> for AuthMiddleware<S>
@@ -64,36 +130,57 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        if self.token.is_empty() {
+        if self.authorized(&req) {
             return Box::pin(self.inner.call(req));
         }
 
-        let unauthorised = Response::builder()
-            .status(http::StatusCode::UNAUTHORIZED)
-            .body("unauthorized".to_string().into())
-            .unwrap();
+        Box::pin(async move { Ok(unauthorized()) })
+    }
+}
 
-        if self.is_websocket(&req) {
-            let q = Query::<AuthQuery>::try_from_uri(req.uri()).ok();
-            if let Some(q) = q {
-                if q.token == self.token {
-                    return Box::pin(self.inner.call(req));
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
 
-            return Box::pin(async move { Ok(unauthorised) });
-        }
+    use axum::{body::Body, extract::ConnectInfo, http::Request};
 
-        let header = req
-            .headers()
-            .get("authorization")
-            .map(|x| x.to_str().unwrap_or_default())
-            .unwrap_or_default();
+    use super::{constant_time_eq, AuthMiddleware};
 
-        if header == format!("Bearer {}", self.token) {
-            return Box::pin(self.inner.call(req));
+    fn request(uri: &str, auth: Option<&str>, websocket: bool) -> Request<Body> {
+        let mut b = Request::builder().uri(uri);
+        if let Some(auth) = auth {
+            b = b.header("authorization", auth);
+        }
+        if websocket {
+            b = b.header("upgrade", "websocket");
         }
+        let mut req = b.body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ConnectInfo(
+            "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+        ));
+        req
+    }
+
+    #[test]
+    fn test_authorized() {
+        let m = AuthMiddleware::new((), "secret".to_owned(), false);
+
+        assert!(m.authorized(&request("/configs", Some("Bearer secret"), false)));
+        assert!(!m.authorized(&request("/configs", Some("Bearer wrong"), false)));
+        assert!(!m.authorized(&request("/configs", None, false)));
+        // the query token is only for websockets
+        assert!(!m.authorized(&request("/configs?token=secret", None, false)));
+        assert!(m.authorized(&request("/logs?token=secret", None, true)));
+        assert!(m.authorized(&request("/logs", Some("Bearer secret"), true)));
+
+        let m = AuthMiddleware::new((), "secret".to_owned(), true);
+        assert!(m.authorized(&request("/configs", None, false)));
+    }
 
-        Box::pin(async move { Ok(unauthorised) })
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
     }
 }
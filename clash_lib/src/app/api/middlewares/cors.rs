@@ -0,0 +1,122 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, HeaderValue, Method, StatusCode};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::warn;
+
+use crate::config::internal::config::Controller;
+
+/// `None` allows any origin
+#[derive(Clone)]
+pub struct AllowedOrigins(Option<Vec<HeaderValue>>);
+
+impl AllowedOrigins {
+    pub fn new(cfg: &Controller) -> Self {
+        if cfg.cors_allow_origins.iter().any(|x| x == "*") {
+            if cfg.secret.as_ref().is_some_and(|x| !x.is_empty()) {
+                warn!(
+                    "external controller has a secret but allows any origin, \
+                     consider setting external-controller-cors.allow-origins"
+                );
+            }
+            return Self(None);
+        }
+
+        Self(Some(
+            cfg.cors_allow_origins
+                .iter()
+                .filter_map(|x| {
+                    x.parse()
+                        .map_err(|_| warn!("invalid cors origin ignored: {}", x))
+                        .ok()
+                })
+                .collect(),
+        ))
+    }
+
+    fn allows(&self, origin: &HeaderValue) -> bool {
+        match &self.0 {
+            None => true,
+            Some(list) => list.contains(origin),
+        }
+    }
+
+    pub fn cors_layer(&self, allow_private_network: bool) -> CorsLayer {
+        let layer = CorsLayer::new()
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+            .allow_private_network(allow_private_network);
+        match &self.0 {
+            None => layer.allow_origin(Any),
+            Some(list) => layer.allow_origin(AllowOrigin::list(list.clone())),
+        }
+    }
+}
+
+/// Browsers don't apply CORS to websockets, so their origin is checked here.
+/// Requests without an Origin header don't come from a browser page.
+pub async fn check_websocket_origin(
+    State(origins): State<AllowedOrigins>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let is_websocket = req
+        .headers()
+        .get(header::UPGRADE)
+        .is_some_and(|x| x.as_bytes().eq_ignore_ascii_case(b"websocket"));
+
+    if is_websocket {
+        if let Some(origin) = req.headers().get(header::ORIGIN) {
+            if !origins.allows(origin) {
+                return (StatusCode::FORBIDDEN, "origin not allowed")
+                    .into_response();
+            }
+        }
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::internal::config::Controller;
+
+    use super::AllowedOrigins;
+
+    fn controller(origins: &[&str]) -> Controller {
+        Controller {
+            external_controller: None,
+            external_ui: None,
+            external_ui_url: None,
+            cors_allow_origins: origins.iter().map(|x| x.to_string()).collect(),
+            cors_allow_private_network: true,
+            secret: None,
+            skip_auth_loopback: false,
+            external_controller_unix: None,
+            unix_socket_mode: 0o600,
+            skip_auth_unix: false,
+            allow_origin_tests: true,
+        }
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let any = AllowedOrigins::new(&controller(&["*"]));
+        assert!(any.allows(&"http://evil.example".parse().unwrap()));
+
+        let list = AllowedOrigins::new(&controller(&["http://yacd.example"]));
+        assert!(list.allows(&"http://yacd.example".parse().unwrap()));
+        assert!(!list.allows(&"http://evil.example".parse().unwrap()));
+    }
+}
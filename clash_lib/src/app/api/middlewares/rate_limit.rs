@@ -0,0 +1,288 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Query, RawPathParams, State},
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tokio::time::Instant;
+use tracing::warn;
+
+/// delay tests of one target, `/proxies/{name}/delay` and the like
+const DELAY_TESTS: (usize, Duration) = (10, Duration::from_secs(10));
+/// updates of one provider
+const PROVIDER_UPDATES: (usize, Duration) = (1, Duration::from_secs(30));
+
+/// At most `max` requests in any `window`, for each key. Kept in memory, a
+/// restart starts over.
+pub struct RateLimit {
+    max: usize,
+    window: Duration,
+    hits: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimit {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            hits: Default::default(),
+        }
+    }
+
+    /// counts a request for `key`, or tells how long until one is allowed
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        hits.retain(|_, x| {
+            while x.front().is_some_and(|t| now - *t >= self.window) {
+                x.pop_front();
+            }
+            !x.is_empty()
+        });
+        let x = hits.entry(key.to_owned()).or_default();
+        if x.len() >= self.max {
+            return Err(*x.front().unwrap() + self.window - now);
+        }
+        x.push_back(now);
+        Ok(())
+    }
+}
+
+/// The limits of the endpoints that have clash-rs fetch URLs or providers,
+/// keyed by the route and the names in it, so by endpoint and target.
+pub struct ApiLimits {
+    delay_test: RateLimit,
+    provider_update: RateLimit,
+    /// the URLs a delay test may fetch, any when None
+    delay_test_urls: Option<HashSet<String>>,
+}
+
+impl ApiLimits {
+    pub fn new(delay_test_urls: Option<HashSet<String>>) -> Self {
+        Self {
+            delay_test: RateLimit::new(DELAY_TESTS.0, DELAY_TESTS.1),
+            provider_update: RateLimit::new(PROVIDER_UPDATES.0, PROVIDER_UPDATES.1),
+            delay_test_urls,
+        }
+    }
+}
+
+/// the full route with the names in it decoded, so `/proxies/%61/delay`
+/// counts with `/proxies/a/delay`
+fn key(req: &Request<Body>, params: &RawPathParams) -> String {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|x| x.as_str())
+        .unwrap_or(req.uri().path());
+    params.iter().fold(route.to_owned(), |key, (name, value)| {
+        key.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+fn message(status: StatusCode, message: String) -> Response {
+    let mut r = HashMap::new();
+    r.insert("message", message);
+    (status, Json(r)).into_response()
+}
+
+fn too_many_requests(key: &str, retry_after: Duration) -> Response {
+    warn!("rate limited {}, retry after {:?}", key, retry_after);
+    let mut res = message(
+        StatusCode::TOO_MANY_REQUESTS,
+        format!("too many requests to {}", key),
+    );
+    // whole seconds, rounded up so a retry right then is allowed
+    let secs = retry_after.as_millis().div_ceil(1000).max(1);
+    res.headers_mut()
+        .insert(header::RETRY_AFTER, secs.to_string().parse().unwrap());
+    res
+}
+
+/// the delay tests, only to the health check URLs of the config with
+/// `external-controller-allow-origin-tests: false`
+pub async fn limit_delay_tests(
+    State(limits): State<Arc<ApiLimits>>,
+    Query(q): Query<HashMap<String, String>>,
+    params: RawPathParams,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if let Some(urls) = &limits.delay_test_urls {
+        let url = q.get("url").map(String::as_str).unwrap_or_default();
+        if !urls.contains(url) {
+            return message(
+                StatusCode::FORBIDDEN,
+                format!("{} is not a health check url of the config", url),
+            );
+        }
+    }
+    let key = key(&req, &params);
+    if let Err(retry_after) = limits.delay_test.check(&key) {
+        return too_many_requests(&key, retry_after);
+    }
+    next.run(req).await
+}
+
+/// the updates of a provider, reading it is not limited
+pub async fn limit_provider_updates(
+    State(limits): State<Arc<ApiLimits>>,
+    params: RawPathParams,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if req.method() != Method::PUT {
+        return next.run(req).await;
+    }
+    let key = key(&req, &params);
+    if let Err(retry_after) = limits.provider_update.check(&key) {
+        return too_many_requests(&key, retry_after);
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc, time::Duration};
+
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::{limit_delay_tests, limit_provider_updates, ApiLimits};
+
+    fn router(limits: ApiLimits) -> Router {
+        let limits = Arc::new(limits);
+        let delay = Router::new()
+            .route("/{name}/delay", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(
+                limits.clone(),
+                limit_delay_tests,
+            ));
+        let providers = Router::new()
+            .route("/{name}", get(|| async { "ok" }).put(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(
+                limits,
+                limit_provider_updates,
+            ));
+        Router::new()
+            .nest("/proxies", delay.clone())
+            .nest("/group", delay)
+            .nest("/providers/proxies", providers)
+    }
+
+    async fn call(router: &Router, method: &str, uri: &str) -> (StatusCode, u64) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let retry_after = res
+            .headers()
+            .get(header::RETRY_AFTER)
+            .map(|x| x.to_str().unwrap().parse().unwrap())
+            .unwrap_or_default();
+        (res.status(), retry_after)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_delay_tests() {
+        let router = router(ApiLimits::new(None));
+        let uri = "/proxies/a/delay?url=http://example.com";
+        for _ in 0..10 {
+            assert_eq!(call(&router, "GET", uri).await.0, StatusCode::OK);
+        }
+        assert_eq!(
+            call(&router, "GET", uri).await,
+            (StatusCode::TOO_MANY_REQUESTS, 10)
+        );
+
+        // the same target however it's encoded
+        assert_eq!(
+            call(&router, "GET", "/proxies/%61/delay?url=http://example.com")
+                .await
+                .0,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // other targets and endpoints have their own
+        for uri in [
+            "/proxies/b/delay?url=http://example.com",
+            "/group/a/delay?url=http://example.com",
+        ] {
+            assert_eq!(call(&router, "GET", uri).await.0, StatusCode::OK);
+        }
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(call(&router, "GET", uri).await.1, 6);
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert_eq!(call(&router, "GET", uri).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_provider_updates() {
+        let router = router(ApiLimits::new(None));
+        assert_eq!(
+            call(&router, "PUT", "/providers/proxies/p").await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&router, "PUT", "/providers/proxies/p").await,
+            (StatusCode::TOO_MANY_REQUESTS, 30)
+        );
+        assert_eq!(
+            call(&router, "PUT", "/providers/proxies/%70").await.0,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            call(&router, "GET", "/providers/proxies/p").await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&router, "PUT", "/providers/proxies/q").await.0,
+            StatusCode::OK
+        );
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(
+            call(&router, "PUT", "/providers/proxies/p").await.0,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delay_test_urls() {
+        let urls = HashSet::from(["http://www.gstatic.com/generate_204".to_owned()]);
+        let router = router(ApiLimits::new(Some(urls)));
+        assert_eq!(
+            call(
+                &router,
+                "GET",
+                "/proxies/a/delay?url=http://www.gstatic.com/generate_204"
+            )
+            .await
+            .0,
+            StatusCode::OK
+        );
+        for uri in [
+            "/proxies/a/delay?url=http://169.254.169.254/latest/meta-data",
+            "/proxies/a/delay",
+        ] {
+            assert_eq!(call(&router, "GET", uri).await.0, StatusCode::FORBIDDEN);
+        }
+    }
+}
@@ -1,35 +1,37 @@
 use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
-use axum::{
-    response::Redirect,
-    routing::{get, post},
-    Router,
-};
+use axum::{middleware, response::Redirect, routing::get, Router};
+use futures::future::BoxFuture;
 
-use http::{header, Method};
 use tokio::sync::{broadcast::Sender, Mutex};
 use tower::ServiceBuilder;
-use tower_http::{
-    cors::{Any, CorsLayer},
-    services::ServeDir,
-    trace::TraceLayer,
-};
-use tracing::{error, info};
+use tower_http::trace::TraceLayer;
+use tracing::{debug_span, error, info};
 
-use crate::{config::internal::config::Controller, GlobalState, Runner};
+use crate::{
+    common::http::new_http_client, config::internal::config::Controller,
+    GlobalState, Runner,
+};
 
 use super::{
-    dispatcher, dispatcher::StatisticsManager, dns::ThreadSafeDNSResolver,
-    inbound::manager::ThreadSafeInboundManager, logging::LogEvent,
-    outbound::manager::ThreadSafeOutboundManager, profile::ThreadSafeCacheFile,
+    dispatcher,
+    dispatcher::StatisticsManager,
+    dns::ThreadSafeDNSResolver,
+    inbound::manager::ThreadSafeInboundManager,
+    logging::{LogEvent, ThreadSafeRecentLogs},
+    outbound::manager::ThreadSafeOutboundManager,
+    profile::ThreadSafeCacheFile,
     router::ThreadSafeRouter,
 };
 
 mod handlers;
 mod middlewares;
+#[cfg(unix)]
+mod unix;
 
 pub struct AppState {
     log_source_tx: Sender<LogEvent>,
+    recent_logs: ThreadSafeRecentLogs,
     statistics_manager: Arc<StatisticsManager>,
 }
 
@@ -37,6 +39,7 @@ pub struct AppState {
 pub fn get_api_runner(
     controller_cfg: Controller,
     log_source: Sender<LogEvent>,
+    recent_logs: ThreadSafeRecentLogs,
     inbound_manager: ThreadSafeInboundManager,
     dispatcher: Arc<dispatcher::Dispatcher>,
     global_state: Arc<Mutex<GlobalState>>,
@@ -47,86 +50,200 @@ pub fn get_api_runner(
     router: ThreadSafeRouter,
     cwd: String,
 ) -> Option<Runner> {
-    if let Some(bind_addr) = controller_cfg.external_controller {
-        let app_state = Arc::new(AppState {
-            log_source_tx: log_source,
-            statistics_manager: statistics_manager.clone(),
-        });
-
-        let cors = CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH])
-            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
-            .allow_origin(Any);
-
-        let bind_addr = if bind_addr.starts_with(':') {
+    if controller_cfg.external_controller.is_none()
+        && controller_cfg.external_controller_unix.is_none()
+    {
+        return None;
+    }
+
+    let app_state = Arc::new(AppState {
+        log_source_tx: log_source,
+        recent_logs,
+        statistics_manager: statistics_manager.clone(),
+    });
+
+    let limits = Arc::new(middlewares::rate_limit::ApiLimits::new(
+        (!controller_cfg.allow_origin_tests)
+            .then(|| outbound_manager.health_check_urls().clone()),
+    ));
+
+    let origins = middlewares::cors::AllowedOrigins::new(&controller_cfg);
+    let cors = origins.cors_layer(controller_cfg.cors_allow_private_network);
+
+    let bind_addr = controller_cfg.external_controller.clone().map(|x| {
+        if x.starts_with(':') {
             info!("hostname not provided, listening on localhost");
-            format!("localhost{}", bind_addr)
+            format!("localhost{}", x)
         } else {
-            bind_addr
-        };
+            x
+        }
+    });
+    let unix_path = controller_cfg
+        .external_controller_unix
+        .as_ref()
+        .map(|x| PathBuf::from(&cwd).join(x));
 
-        let runner = async move {
-            info!("Starting API server at {}", bind_addr);
-            let mut app = Router::new()
-                .route("/", get(handlers::hello::handle))
-                .route("/logs", get(handlers::log::handle))
-                .route("/traffic", get(handlers::traffic::handle))
-                .route("/version", get(handlers::version::handle))
-                .route("/memory", get(handlers::memory::handle))
-                .route("/restart", post(handlers::restart::handle))
-                .nest(
-                    "/configs",
-                    handlers::config::routes(
-                        inbound_manager,
-                        dispatcher,
-                        global_state,
-                        dns_resolver.clone(),
-                    ),
-                )
-                .nest("/rules", handlers::rule::routes(router))
-                .nest(
-                    "/proxies",
-                    handlers::proxy::routes(outbound_manager.clone(), cache_store),
-                )
-                .nest(
-                    "/connections",
-                    handlers::connection::routes(statistics_manager),
-                )
-                .nest(
-                    "/providers/proxies",
-                    handlers::provider::routes(outbound_manager),
-                )
-                .nest("/dns", handlers::dns::routes(dns_resolver))
-                .route_layer(middlewares::auth::AuthMiddlewareLayer::new(
-                    controller_cfg.secret.unwrap_or_default(),
+    let runner = async move {
+        let external_ui = controller_cfg
+            .external_ui
+            .map(|dir| {
+                Ok::<_, std::io::Error>(handlers::external_ui::ExternalUi::new(
+                    PathBuf::from(&cwd).join(dir),
+                    controller_cfg.external_ui_url,
+                    new_http_client(dns_resolver.clone())?,
                 ))
-                .route_layer(cors)
-                .with_state(app_state)
-                .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()));
+            })
+            .transpose()?;
 
-            if let Some(external_ui) = controller_cfg.external_ui {
+        let mut api = Router::new()
+            .route("/", get(handlers::hello::handle))
+            .route(
+                "/logs",
+                get(handlers::log::handle).delete(handlers::log::clear),
+            )
+            .route("/traffic", get(handlers::traffic::handle))
+            .route("/version", get(handlers::version::handle))
+            .route("/memory", get(handlers::memory::handle))
+            .route("/network", get(handlers::network::handle))
+            .route("/debug/schedule", get(handlers::schedule::handle))
+            .nest("/restart", handlers::restart::routes(global_state.clone()))
+            .nest(
+                "/shutdown",
+                handlers::shutdown::routes(global_state.clone()),
+            )
+            .nest(
+                "/configs",
+                handlers::config::routes(
+                    inbound_manager.clone(),
+                    dispatcher,
+                    global_state,
+                    dns_resolver.clone(),
+                ),
+            )
+            .nest("/rules", handlers::rule::routes(router))
+            .nest(
+                "/proxies",
+                handlers::proxy::routes(
+                    outbound_manager.clone(),
+                    cache_store,
+                    statistics_manager.clone(),
+                    limits.clone(),
+                ),
+            )
+            .nest(
+                "/group",
+                handlers::group::routes(outbound_manager.clone(), limits.clone()),
+            )
+            .nest(
+                "/statistics",
+                handlers::statistics::routes(statistics_manager.clone()),
+            )
+            .nest(
+                "/connections",
+                handlers::connection::routes(statistics_manager, inbound_manager),
+            )
+            .nest(
+                "/providers/proxies",
+                handlers::provider::routes(outbound_manager, limits),
+            )
+            .nest("/cache", handlers::cache::routes(dns_resolver.clone()))
+            .nest("/dns", handlers::dns::routes(dns_resolver));
+
+        if let Some(ui) = &external_ui {
+            api = api.nest("/upgrade", handlers::external_ui::routes(ui.clone()));
+        }
+
+        // the listeners only differ in how the secret is checked
+        let secret = controller_cfg.secret.unwrap_or_default();
+        let app = |auth: middlewares::auth::AuthMiddlewareLayer| {
+            let mut app = api
+                .clone()
+                .route_layer(auth)
+                .route_layer(middleware::from_fn_with_state(
+                    origins.clone(),
+                    middlewares::cors::check_websocket_origin,
+                ))
+                .route_layer(cors.clone())
+                .with_state(app_state.clone())
+                .layer(ServiceBuilder::new().layer(
+                    // the query is left out as it may carry the secret
+                    TraceLayer::new_for_http().make_span_with(
+                        |req: &axum::http::Request<axum::body::Body>| {
+                            debug_span!(
+                                "request",
+                                method = %req.method(),
+                                path = %req.uri().path()
+                            )
+                        },
+                    ),
+                ));
+
+            if let Some(ui) = &external_ui {
                 app = app
                     .route("/ui", get(|| async { Redirect::to("/ui/") }))
-                    .nest_service(
-                        "/ui/",
-                        ServeDir::new(PathBuf::from(cwd).join(external_ui)),
-                    );
+                    .nest_service("/ui/", ui.service());
             }
+            app
+        };
 
-            let listener = tokio::net::TcpListener::bind(&bind_addr).await.unwrap();
+        if let Some(ui) = external_ui.clone() {
+            tokio::spawn(async move { ui.ensure_downloaded().await });
+        }
 
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .map_err(|x| {
-                error!("API server error: {}", x);
-                crate::Error::Operation(format!("API server error: {}", x))
-            })
-        };
-        Some(Box::pin(runner))
-    } else {
-        None
-    }
+        let mut servers: Vec<BoxFuture<'static, std::io::Result<()>>> = vec![];
+
+        if let Some(bind_addr) = bind_addr {
+            info!("Starting API server at {}", bind_addr);
+            let app = app(middlewares::auth::AuthMiddlewareLayer::new(
+                secret.clone(),
+                controller_cfg.skip_auth_loopback,
+            ));
+            let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
+            servers.push(Box::pin(async move {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await
+            }));
+        }
+
+        if let Some(path) = unix_path {
+            #[cfg(unix)]
+            {
+                info!("Starting API server at unix:{}", path.display());
+                let secret = if controller_cfg.skip_auth_unix {
+                    String::new()
+                } else {
+                    secret.clone()
+                };
+                let app =
+                    app(middlewares::auth::AuthMiddlewareLayer::new(secret, false));
+                let (listener, guard) =
+                    unix::bind(&path, controller_cfg.unix_socket_mode).await?;
+                servers.push(Box::pin(async move {
+                    // the socket file goes away with the server
+                    let _guard = guard;
+                    axum::serve(listener, app.into_make_service()).await
+                }));
+            }
+            #[cfg(not(unix))]
+            tracing::warn!(
+                "external-controller-unix {} is only supported on unix",
+                path.display()
+            );
+        }
+
+        if servers.is_empty() {
+            return Ok(());
+        }
+        futures::future::select_all(servers).await.0
+    };
+
+    Some(Box::pin(async move {
+        runner.await.map_err(|x: std::io::Error| {
+            error!("API server error: {}", x);
+            crate::Error::Operation(format!("API server error: {}", x))
+        })
+    }))
 }
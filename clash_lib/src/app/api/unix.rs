@@ -0,0 +1,181 @@
+use std::{
+    fs, io,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::net::UnixListener;
+use tracing::{debug, warn};
+
+/// Removes the socket file when the server using it is dropped.
+pub struct SocketGuard {
+    path: PathBuf,
+    // the file is left alone if it's been replaced, e.g. by the listener of a
+    // reloaded config
+    ino: u64,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        if fs::metadata(&self.path).is_ok_and(|x| x.ino() == self.ino) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// A socket file left behind by a crashed run is removed, one that still
+/// accepts connections belongs to another instance and is an error.
+pub async fn bind(
+    path: &Path,
+    mode: u32,
+) -> io::Result<(UnixListener, SocketGuard)> {
+    if path.exists() {
+        // on reload the previous listener may still be shutting down
+        let mut attempts = 10;
+        while UnixStream::connect(path).is_ok() {
+            attempts -= 1;
+            if attempts == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        if path.exists() {
+            debug!("removing stale socket {}", path.display());
+            fs::remove_file(path)?;
+        }
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let listener = bind_with_mode(path, mode)?;
+    let guard = SocketGuard {
+        path: path.to_owned(),
+        ino: fs::metadata(path)?.ino(),
+    };
+    Ok((listener, guard))
+}
+
+/// bind() creates the socket with the umask applied, so it's bound in a
+/// directory only we can enter and moved in place once its mode is set
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    // kept short, socket paths are limited to about 100 bytes
+    let dir = path.with_file_name(format!(".{:08x}", rand::random::<u32>()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let tmp = dir.join("s");
+    let res = UnixListener::bind(&tmp).and_then(|listener| {
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!("failed to remove {}: {}", dir.display(), e);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, sync::Arc};
+
+    use axum::{routing::get, Router};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+
+    use super::bind;
+    use crate::app::{
+        api::{handlers, AppState},
+        dispatcher::StatisticsManager,
+        logging::RecentLogs,
+    };
+
+    #[tokio::test]
+    async fn test_bind_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api.sock");
+
+        // a socket file nobody listens on, as left by a crash
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (listener, guard) = bind(&path, 0o600).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the directory it was bound in is gone
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        assert!(bind(&path, 0o600).await.is_err());
+
+        drop(listener);
+        drop(guard);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api.sock");
+
+        let state = Arc::new(AppState {
+            log_source_tx: tokio::sync::broadcast::channel(16).0,
+            recent_logs: Arc::new(RecentLogs::default()),
+            statistics_manager: StatisticsManager::new(),
+        });
+        let app = Router::new()
+            .route("/", get(handlers::hello::handle))
+            .route("/logs", get(handlers::log::handle))
+            .route("/traffic", get(handlers::traffic::handle))
+            .with_state(state);
+        let (listener, _guard) = bind(&path, 0o600).await.unwrap();
+        tokio::spawn(
+            async move { axum::serve(listener, app.into_make_service()).await },
+        );
+
+        // there is no ConnectInfo over the socket, the websockets must not
+        // depend on it
+        for (uri, upgrade, status) in [
+            ("/", false, "200"),
+            ("/logs", true, "101"),
+            ("/traffic", true, "101"),
+        ] {
+            let mut s = UnixStream::connect(&path).await.unwrap();
+            let mut req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", uri);
+            if upgrade {
+                req.push_str(
+                    "Connection: Upgrade\r\nUpgrade: \
+                     websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: \
+                     dGhlIHNhbXBsZSBub25jZQ==\r\n",
+                );
+            }
+            req.push_str("\r\n");
+            s.write_all(req.as_bytes()).await.unwrap();
+
+            let mut buf = vec![];
+            while !buf.windows(4).any(|x| x == b"\r\n\r\n") {
+                let mut chunk = [0; 256];
+                let n = s.read(&mut chunk).await.unwrap();
+                assert!(n > 0, "{}: {:?}", uri, String::from_utf8_lossy(&buf));
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let res = String::from_utf8_lossy(&buf);
+            assert!(
+                res.starts_with(&format!("HTTP/1.1 {} ", status)),
+                "{}: {}",
+                uri,
+                res
+            );
+        }
+    }
+}
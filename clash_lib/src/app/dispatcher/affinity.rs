@@ -0,0 +1,212 @@
+//! `same-exit-affinity` of the groups, which member each (source IP,
+//! destination host) went through, so its later connections, TCP or UDP,
+//! take the same one instead of whatever the group would pick now.
+//!
+//! An entry lasts for the group's window, and is dropped once the group
+//! switches to another member, e.g. a url-test finding a faster one or a
+//! selector being changed, or when dialing the member fails.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::{
+    app::outbound::manager::OutboundManager, proxy::AnyOutboundHandler,
+    session::Session,
+};
+
+/// how often expired entries are looked for, entries of sources that never
+/// come back are only dropped then
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// (source, destination host, group)
+type Key = (IpAddr, String, String);
+
+struct Entry {
+    member: String,
+    /// what the group said it was using when this was pinned
+    now: Option<String>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    pruned: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct Affinity {
+    entries: Mutex<Entries>,
+}
+
+fn key(sess: &Session, group: &str) -> Key {
+    (sess.source.ip(), sess.destination.host(), group.to_owned())
+}
+
+impl Affinity {
+    /// the member of `group` this session is pinned to, if any
+    pub async fn pinned(
+        &self,
+        mgr: &OutboundManager,
+        group: &AnyOutboundHandler,
+        sess: &Session,
+    ) -> Option<AnyOutboundHandler> {
+        mgr.affinity_window(group.name())?;
+        let key = key(sess, group.name());
+        let name = self.get(&key, &OutboundManager::now_of(group).await)?;
+        let member = mgr
+            .group_members(group)
+            .await?
+            .into_iter()
+            .find(|x| x.name() == name);
+        if member.is_none() {
+            self.entries.lock().unwrap().map.remove(&key);
+        }
+        member
+    }
+
+    /// pins this session to the member of `group` it went through, `chain`
+    /// being that of its connection, leaf first
+    pub async fn pin(
+        &self,
+        mgr: &OutboundManager,
+        group: &AnyOutboundHandler,
+        sess: &Session,
+        chain: &[String],
+    ) {
+        let Some(window) = mgr.affinity_window(group.name()) else {
+            return;
+        };
+        let Some(member) = member_of(chain, group.name()) else {
+            return;
+        };
+        debug!("{} pinned to {} of {}", sess, member, group.name());
+        self.insert(
+            key(sess, group.name()),
+            member.to_owned(),
+            OutboundManager::now_of(group).await,
+            window,
+        );
+    }
+
+    /// forgets the member of `group` this session was pinned to, e.g. as it
+    /// can't be dialed anymore
+    pub fn unpin(&self, group: &str, sess: &Session) {
+        self.entries.lock().unwrap().map.remove(&key(sess, group));
+    }
+
+    /// the member pinned under `key`, unless it expired or the group has
+    /// switched away from `now` since
+    fn get(&self, key: &Key, now: &Option<String>) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.map.get(key)?;
+        if entry.expires <= Instant::now() || &entry.now != now {
+            entries.map.remove(key);
+            return None;
+        }
+        Some(entry.member.clone())
+    }
+
+    fn insert(
+        &self,
+        key: Key,
+        member: String,
+        now: Option<String>,
+        window: Duration,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        let t = Instant::now();
+        if entries
+            .pruned
+            .is_none_or(|x| t.duration_since(x) >= PRUNE_INTERVAL)
+        {
+            entries.map.retain(|_, x| x.expires > t);
+            entries.pruned = Some(t);
+        }
+        entries.map.insert(
+            key,
+            Entry {
+                member,
+                now,
+                expires: t + window,
+            },
+        );
+    }
+}
+
+/// the name right below `group` in a leaf first chain
+fn member_of<'a>(chain: &'a [String], group: &str) -> Option<&'a str> {
+    let i = chain.iter().position(|x| x == group)?;
+    chain.get(i.checked_sub(1)?).map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::session::Session;
+
+    use super::{key, member_of, Affinity};
+
+    #[test]
+    fn test_member_of() {
+        let chain = ["hk-01", "auto-hk", "Proxy"].map(ToOwned::to_owned);
+        assert_eq!(member_of(&chain, "Proxy"), Some("auto-hk"));
+        assert_eq!(member_of(&chain, "auto-hk"), Some("hk-01"));
+        assert_eq!(member_of(&chain, "hk-01"), None);
+        assert_eq!(member_of(&chain, "other"), None);
+    }
+
+    #[test]
+    fn test_pin_follows_group() {
+        let a = Affinity::default();
+        let sess = Session::default();
+        let k = key(&sess, "auto");
+        let hk = Some("hk-01".to_owned());
+        let window = Duration::from_secs(60);
+
+        // load-balance has no `now`
+        a.insert(k.clone(), "hk-02".to_owned(), None, window);
+        assert_eq!(a.get(&k, &None).as_deref(), Some("hk-02"));
+
+        a.insert(k.clone(), "hk-01".to_owned(), hk.clone(), window);
+        assert_eq!(a.get(&k, &hk).as_deref(), Some("hk-01"));
+        // the group switching members drops the entry
+        assert_eq!(a.get(&k, &Some("jp-01".to_owned())), None);
+        assert_eq!(a.get(&k, &hk), None);
+
+        a.insert(k.clone(), "hk-01".to_owned(), hk.clone(), Duration::ZERO);
+        assert_eq!(a.get(&k, &hk), None);
+
+        a.insert(k.clone(), "hk-01".to_owned(), hk.clone(), window);
+        a.unpin("auto", &sess);
+        assert_eq!(a.get(&k, &hk), None);
+    }
+
+    #[test]
+    fn test_expired_entries_pruned() {
+        let a = Affinity::default();
+        let mut sess = Session::default();
+        for i in 0..10u8 {
+            sess.source.set_ip([10, 0, 0, i].into());
+            a.insert(key(&sess, "auto"), "hk-01".to_owned(), None, Duration::ZERO);
+        }
+        assert_eq!(a.entries.lock().unwrap().map.len(), 10);
+
+        // as if the interval passed
+        a.entries.lock().unwrap().pruned = None;
+        a.insert(
+            key(&sess, "other"),
+            "hk-01".to_owned(),
+            None,
+            Duration::ZERO,
+        );
+        assert_eq!(a.entries.lock().unwrap().map.len(), 1);
+    }
+}
@@ -4,32 +4,87 @@ use crate::{
         outbound::manager::ThreadSafeOutboundManager,
         router::ThreadSafeRouter,
     },
-    common::io::copy_buf_bidirectional_with_timeout,
+    common::{
+        io::{
+            copy_buf_bidirectional_with_timeout, AsTcpStream, CopyBidirectionalError,
+        },
+        pool::STREAM_BUFFER_SIZE,
+        rate_limit::{BandwidthLimit, Throttle, Throttled},
+    },
     config::{
-        def::RunMode,
-        internal::proxy::{PROXY_DIRECT, PROXY_GLOBAL},
+        def::{BandwidthLimits, RunMode, UdpNat},
+        internal::{
+            config::UdpSessions,
+            proxy::{PROXY_DIRECT, PROXY_GLOBAL, PROXY_REJECT},
+        },
+    },
+    proxy::{
+        datagram::UdpPacket,
+        utils::{mtu, mtu::TunnelMtu, DialError},
+        AnyInboundDatagram, AnyOutboundHandler, OutboundType,
     },
-    proxy::{datagram::UdpPacket, AnyInboundDatagram},
     session::{Session, SocksAddr},
 };
 use futures::{SinkExt, StreamExt};
 use std::{
     collections::HashMap,
     fmt::{Debug, Formatter},
-    net::SocketAddr,
+    future::Future,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use tokio::{
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
-    sync::RwLock,
-    task::JoinHandle,
-};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument};
 
 use crate::app::dns::ThreadSafeDNSResolver;
 
-use super::statistics_manager::Manager;
+use super::{
+    affinity::Affinity,
+    statistics_manager::{Manager, ProxyChain},
+    udp_session::{Lookup, UdpSessionManager},
+    BoxedChainedStream,
+};
+
+/// how long one direction is given to finish once the other is done
+const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Relays until both sides are done, with splice(2) where both are plain tcp
+/// sockets, e.g. a redir or socks inbound and a direct outbound.
+async fn relay<S>(
+    lhs: &mut S,
+    rhs: &mut TrackedStream,
+) -> Result<(u64, u64), CopyBidirectionalError>
+where
+    S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
+{
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let (Some(l), Some(r)) = (lhs.as_tcp_stream(), rhs.as_raw_tcp()) {
+        let up = r.counter.clone();
+        let down = r.counter;
+        return tokio::select! {
+            rv = crate::common::splice::splice_bidirectional(
+                l,
+                r.stream,
+                HALF_CLOSE_TIMEOUT,
+                HALF_CLOSE_TIMEOUT,
+                move |n| up.uploaded(n),
+                move |n| down.downloaded(n),
+            ) => rv,
+            _ = r.closed => Err(CopyBidirectionalError::Other(
+                std::io::ErrorKind::BrokenPipe.into(),
+            )),
+        };
+    }
+
+    copy_buf_bidirectional_with_timeout(
+        lhs,
+        rhs,
+        STREAM_BUFFER_SIZE,
+        HALF_CLOSE_TIMEOUT,
+        HALF_CLOSE_TIMEOUT,
+    )
+    .await
+}
 
 pub struct Dispatcher {
     outbound_manager: ThreadSafeOutboundManager,
@@ -38,6 +93,154 @@ pub struct Dispatcher {
     mode: Arc<Mutex<RunMode>>,
 
     manager: Arc<Manager>,
+    udp_sessions: Arc<UdpSessionManager>,
+    listener_bandwidth: Arc<HashMap<String, Arc<BandwidthLimit>>>,
+    affinity: Arc<Affinity>,
+    /// where UDP goes when the chosen proxy has none
+    udp_fallback: String,
+}
+
+/// What a UDP session goes through, the proxy the rules picked or the
+/// `udp-fallback` when it has no UDP.
+enum UdpRoute {
+    Dialed,
+    /// with why
+    Fallback(AnyOutboundHandler, String),
+    /// the fallback is REJECT, or has no UDP either
+    Drop(String),
+}
+
+/// `member` is the one `dialed` is using when it's a group, else `dialed`
+/// itself
+async fn udp_route(
+    dialed: &AnyOutboundHandler,
+    member: &AnyOutboundHandler,
+    fallback: Option<AnyOutboundHandler>,
+) -> UdpRoute {
+    if dialed.support_udp().await && member.support_udp().await {
+        return UdpRoute::Dialed;
+    }
+    let reason = if member.name() == dialed.name() {
+        format!("{} has no udp", dialed.name())
+    } else {
+        format!("{} of {} has no udp", member.name(), dialed.name())
+    };
+    match fallback {
+        Some(f) if f.name() != PROXY_REJECT && f.support_udp().await => {
+            UdpRoute::Fallback(f, reason)
+        }
+        _ => UdpRoute::Drop(reason),
+    }
+}
+
+/// how often the oversized packets of a UDP session are logged
+const OVERSIZED_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The packets of a UDP session too big for its outbound, dropped as they
+/// can't be sent at all, or sent and left to IP fragmentation on the way.
+#[derive(Default)]
+struct Oversized {
+    dropped: usize,
+    fragmented: usize,
+    logged_at: Option<Instant>,
+}
+
+impl Oversized {
+    /// whether `packet` is to be sent, through a datagram of `proto` that
+    /// takes payloads of at most `max_payload`
+    fn check(
+        &mut self,
+        sess: &Session,
+        packet: &UdpPacket,
+        max_payload: Option<usize>,
+        proto: &OutboundType,
+    ) -> bool {
+        let size = packet.data.len();
+        let drop = max_payload.is_some_and(|max| size > max);
+        if drop {
+            self.dropped += 1;
+        } else if mtu::datagram_overhead(proto).is_some_and(|x| !x.fragments)
+            && mtu::max_payload_size(
+                proto,
+                mtu::DEFAULT_LINK_MTU,
+                false,
+                &packet.dst_addr,
+            )
+            .is_some_and(|max| size > max)
+        {
+            self.fragmented += 1;
+        } else {
+            return true;
+        }
+
+        if self
+            .logged_at
+            .is_some_and(|x| x.elapsed() < OVERSIZED_LOG_INTERVAL)
+        {
+            return !drop;
+        }
+        if self.dropped > 0 {
+            warn!(
+                "{}: dropped {} UDP packets too big for {}, the largest that fits \
+                 is {} bytes",
+                sess,
+                self.dropped,
+                proto,
+                max_payload.unwrap_or_default()
+            );
+        }
+        if self.fragmented > 0 {
+            debug!(
+                "{}: {} UDP packets too big for one packet through {}, left to IP \
+                 fragmentation",
+                sess, self.fragmented, proto
+            );
+        }
+        self.dropped = 0;
+        self.fragmented = 0;
+        self.logged_at = Some(Instant::now());
+        !drop
+    }
+}
+
+/// The limits of the listener a connection came in on and of the proxies and
+/// groups it goes through.
+async fn throttle(
+    outbound_manager: &ThreadSafeOutboundManager,
+    listener_bandwidth: &HashMap<String, Arc<BandwidthLimit>>,
+    sess: &Session,
+    chain: &ProxyChain,
+) -> Throttle {
+    let mut throttle = Throttle::default();
+    if let Some(l) = listener_bandwidth.get(&sess.inbound_name) {
+        throttle.push(l.clone());
+    }
+    for name in chain.names().await {
+        if let Some(l) = outbound_manager.bandwidth_limit(&name) {
+            throttle.push(l);
+        }
+    }
+    throttle
+}
+
+/// Where the outbound connection is bound, as far as it can be told: the
+/// local address of a direct socket, or else the server of the proxy it
+/// went through.
+async fn bound_addr(
+    outbound_manager: &ThreadSafeOutboundManager,
+    rhs: &mut BoxedChainedStream,
+) -> SocksAddr {
+    if let Some(addr) = rhs.as_tcp_stream().and_then(|x| x.local_addr().ok()) {
+        return addr.into();
+    }
+    // the proxy that dialed is the first in the chain
+    rhs.chain()
+        .names()
+        .await
+        .first()
+        .and_then(|x| outbound_manager.get_outbound(x))
+        .and_then(|x| x.server())
+        .unwrap_or_else(SocksAddr::any_ipv4)
 }
 
 impl Debug for Dispatcher {
@@ -47,39 +250,54 @@ impl Debug for Dispatcher {
 }
 
 impl Dispatcher {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         outbound_manager: ThreadSafeOutboundManager,
         router: ThreadSafeRouter,
         resolver: ThreadSafeDNSResolver,
         mode: RunMode,
+        udp_sessions: UdpSessions,
+        udp_fallback: String,
+        listener_bandwidth: HashMap<String, BandwidthLimits>,
 
         statistics_manager: Arc<Manager>,
     ) -> Self {
+        let listener_bandwidth = listener_bandwidth
+            .into_iter()
+            .filter(|(_, x)| x.up.is_some() || x.down.is_some())
+            .map(|(k, x)| (k, BandwidthLimit::new(x.up, x.down)))
+            .collect();
         Self {
             outbound_manager,
             router,
             resolver,
             mode: Arc::new(Mutex::new(mode)),
+            udp_sessions: UdpSessionManager::new(
+                udp_sessions,
+                statistics_manager.clone(),
+            ),
+            listener_bandwidth: Arc::new(listener_bandwidth),
+            affinity: Default::default(),
+            udp_fallback,
             manager: statistics_manager,
         }
     }
 
-    pub async fn set_mode(&self, mode: RunMode) {
+    pub fn set_mode(&self, mode: RunMode) {
         info!("run mode switched to {}", mode);
 
         *self.mode.lock().unwrap() = mode;
     }
 
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub async fn get_mode(&self) -> RunMode {
         *self.mode.lock().unwrap()
     }
 
-    #[instrument(skip(self, sess, lhs))]
-    pub async fn dispatch_stream<S>(&self, mut sess: Session, mut lhs: S)
-    where
-        S: AsyncRead + AsyncWrite + Unpin + Send,
-    {
-        let dest: SocksAddr = match &sess.destination {
+    /// the domain behind a fake ip or a cached address, the address as is
+    /// otherwise. None if a fake ip is no longer known
+    async fn destination_of(&self, dest: &SocksAddr) -> Option<SocksAddr> {
+        Some(match dest {
             crate::session::SocksAddr::Ip(socket_addr) => {
                 if self.resolver.fake_ip_enabled() {
                     trace!("looking up fake ip: {}", socket_addr.ip());
@@ -92,7 +310,7 @@ impl Dispatcher {
                                 .expect("must be valid domain"),
                             None => {
                                 error!("failed to reverse lookup fake ip: {}", ip);
-                                return;
+                                return None;
                             }
                         }
                     } else {
@@ -116,15 +334,78 @@ impl Dispatcher {
                     .try_into()
                     .expect("must be valid domain")
             }
+        })
+    }
+
+    /// the tunnel of the proxy a TCP connection of `sess` would go through,
+    /// for the tun to clamp the MSS of its SYN to. None if no proxy has one
+    #[cfg_attr(not(feature = "tun"), allow(dead_code))]
+    pub async fn tunnel_mtu(&self, mut sess: Session) -> Option<TunnelMtu> {
+        let mgr = self.outbound_manager.clone();
+        if !mgr.has_tunnels() {
+            return None;
+        }
+
+        sess.destination = self.destination_of(&sess.destination).await?;
+        let mode = *self.mode.lock().unwrap();
+        sess.mode = mode;
+        let outbound_name = match mode {
+            RunMode::Global => PROXY_GLOBAL,
+            RunMode::Rule => self.router.match_route(&mut sess).await.0,
+            RunMode::Direct => return None,
+        };
+
+        let handler = mgr.get_outbound(outbound_name)?;
+        let handler = self
+            .affinity
+            .pinned(&mgr, &handler, &sess)
+            .await
+            .unwrap_or(handler);
+        mgr.selected_member(&handler).await.tunnel_mtu()
+    }
+
+    pub async fn dispatch_stream<S>(&self, sess: Session, lhs: S)
+    where
+        S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
+    {
+        self.dispatch_stream_with_reply(sess, lhs, |lhs, _| async { Ok(lhs) })
+            .await
+    }
+
+    /// [`Self::dispatch_stream`] for inbounds that tell the client how the
+    /// dial went before relaying, e.g. a SOCKS5 reply. `reply` is given the
+    /// address the outbound connection is bound to, or why it failed, and
+    /// hands the stream back to relay on.
+    #[instrument(skip(self, sess, lhs, reply))]
+    pub async fn dispatch_stream_with_reply<S, F, Fut>(
+        &self,
+        mut sess: Session,
+        lhs: S,
+        reply: F,
+    ) where
+        S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
+        F: FnOnce(S, Result<SocksAddr, std::io::ErrorKind>) -> Fut,
+        Fut: Future<Output = std::io::Result<S>>,
+    {
+        if !self.manager.is_accepting() {
+            debug!("shutting down, dropping {}", sess);
+            return;
+        }
+
+        let Some(dest) = self.destination_of(&sess.destination).await else {
+            return;
         };
 
         sess.destination = dest.clone();
 
         let mode = *self.mode.lock().unwrap();
-        let (outbound_name, rule) = match mode {
-            RunMode::Global => (PROXY_GLOBAL, None),
-            RunMode::Rule => self.router.match_route(&mut sess).await,
-            RunMode::Direct => (PROXY_DIRECT, None),
+        sess.mode = mode;
+        let special_proxy = sess.special_proxy.clone();
+        let (outbound_name, rule) = match (special_proxy.as_deref(), mode) {
+            (Some(proxy), _) => (proxy, None),
+            (None, RunMode::Global) => (PROXY_GLOBAL, None),
+            (None, RunMode::Rule) => self.router.match_route(&mut sess).await,
+            (None, RunMode::Direct) => (PROXY_DIRECT, None),
         };
 
         debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
@@ -134,14 +415,32 @@ impl Dispatcher {
             debug!("unknown rule: {}, fallback to direct", outbound_name);
             mgr.get_outbound(PROXY_DIRECT).unwrap()
         });
+        let pinned = self.affinity.pinned(&mgr, &handler, &sess).await;
+        let dialed = pinned.clone().unwrap_or(handler.clone());
 
-        match handler
+        match dialed
             .connect_stream(&sess, self.resolver.clone())
             .instrument(info_span!("connect_stream", outbound_name = outbound_name,))
             .await
         {
-            Ok(rhs) => {
+            Ok(mut rhs) => {
                 debug!("remote connection established {}", sess);
+                if pinned.is_some() {
+                    rhs.append_to_chain(handler.name()).await;
+                }
+                mgr.complete_chain(rhs.chain()).await;
+                if pinned.is_none() {
+                    let chain = rhs.chain().names().await;
+                    self.affinity.pin(&mgr, &handler, &sess, &chain).await;
+                }
+                let bound = bound_addr(&mgr, &mut rhs).await;
+                let mut lhs = match reply(lhs, Ok(bound)).await {
+                    Ok(lhs) => lhs,
+                    Err(e) => {
+                        warn!("failed to reply to {}: {}", sess, e);
+                        return;
+                    }
+                };
                 let mut rhs = TrackedStream::new(
                     rhs,
                     self.manager.clone(),
@@ -149,19 +448,26 @@ impl Dispatcher {
                     rule,
                 )
                 .await;
-                match copy_buf_bidirectional_with_timeout(
-                    &mut lhs,
-                    &mut rhs,
-                    4096,
-                    Duration::from_secs(10),
-                    Duration::from_secs(10),
+                let throttle = throttle(
+                    &self.outbound_manager,
+                    &self.listener_bandwidth,
+                    &sess,
+                    &rhs.tracker_info().proxy_chain_holder,
                 )
-                .instrument(info_span!(
-                    "copy_bidirectional",
-                    outbound_name = outbound_name,
-                ))
-                .await
-                {
+                .await;
+                let span =
+                    info_span!("copy_bidirectional", outbound_name = outbound_name);
+                let copied = if throttle.is_empty() {
+                    relay(&mut lhs, &mut rhs).instrument(span).await
+                } else {
+                    relay(&mut Throttled::new(&mut lhs, throttle), &mut rhs)
+                        .instrument(span)
+                        .await
+                };
+                if let Err(e) = &copied {
+                    rhs.tracker_info().set_error_with_category(e);
+                }
+                match copied {
                     Ok((up, down)) => {
                         debug!(
                             "connection {} closed with {} bytes up, {} bytes down",
@@ -228,11 +534,20 @@ impl Dispatcher {
             }
             Err(err) => {
                 warn!(
-                    "failed to establish remote connection {}, error: {}",
-                    sess, err
+                    dial_error = DialError::of(&err).map(tracing::field::display),
+                    "failed to establish remote connection {}, error: {}", sess, err
                 );
-                if let Err(e) = lhs.shutdown().await {
-                    warn!("error closing local connection {}: {}", sess, e)
+                if pinned.is_some() {
+                    self.affinity.unpin(handler.name(), &sess);
+                }
+                mgr.report_dial_error(&dialed, &err).await;
+                match reply(lhs, Err(err.kind())).await {
+                    Ok(mut lhs) => {
+                        if let Err(e) = lhs.shutdown().await {
+                            warn!("error closing local connection {}: {}", sess, e)
+                        }
+                    }
+                    Err(e) => debug!("failed to reply to {}: {}", sess, e),
                 }
             }
         }
@@ -246,13 +561,22 @@ impl Dispatcher {
         sess: Session,
         udp_inbound: AnyInboundDatagram,
     ) -> tokio::sync::oneshot::Sender<u8> {
-        let outbound_handle_guard = TimeoutUdpSessionManager::new();
+        if !self.manager.is_accepting() {
+            debug!("shutting down, dropping {}", sess);
+            return tokio::sync::oneshot::channel().0;
+        }
+
+        let udp_sessions = self.udp_sessions.clone();
+        let owner = udp_sessions.new_owner();
 
         let router = self.router.clone();
         let outbound_manager = self.outbound_manager.clone();
         let resolver = self.resolver.clone();
         let mode = self.mode.clone();
         let manager = self.manager.clone();
+        let listener_bandwidth = self.listener_bandwidth.clone();
+        let affinity = self.affinity.clone();
+        let udp_fallback = self.udp_fallback.clone();
 
         let (mut local_w, mut local_r) = udp_inbound.split();
         let (remote_receiver_w, mut remote_receiver_r) =
@@ -260,10 +584,13 @@ impl Dispatcher {
 
         let s = sess.clone();
         let ss = sess.clone();
+        let sessions = udp_sessions.clone();
         let t1 = tokio::spawn(async move {
             while let Some(packet) = local_r.next().await {
                 let mut sess = sess.clone();
-                sess.source = packet.src_addr.clone().must_into_socket_addr();
+                // this is only expected to be socket addr as it's from local udp
+                let src = packet.src_addr.clone().must_into_socket_addr();
+                sess.source = src;
 
                 let dest: SocksAddr = match &packet.dst_addr {
                     crate::session::SocksAddr::Ip(socket_addr) => {
@@ -309,18 +636,54 @@ impl Dispatcher {
                 // resolve is done in OutboundDatagramImpl so it's fine to have
                 // (Domain, port) here. ideally the OutboundDatagramImpl should only
                 // do Ip though?
-                packet.dst_addr = dest;
+                packet.dst_addr = dest.clone();
+
+                let cone = match sessions.get(owner, src, &dest) {
+                    Lookup::Session(handle) => {
+                        match handle.send(packet).await {
+                            // TODO: need to reset when GLOBAL select is changed
+                            Ok(_) => {
+                                debug!("reusing {} sent to remote", sess);
+                            }
+                            Err(err) => {
+                                error!("failed to send packet to remote: {}", err);
+                            }
+                        }
+                        continue;
+                    }
+                    Lookup::FullCone(outbound) => Some(outbound),
+                    Lookup::None => None,
+                };
 
                 let mode = *mode.lock().unwrap();
+                sess.mode = mode;
 
-                let (outbound_name, rule) = match mode {
-                    RunMode::Global => (PROXY_GLOBAL, None),
-                    RunMode::Rule => router.match_route(&mut sess).await,
-                    RunMode::Direct => (PROXY_DIRECT, None),
+                let special_proxy = sess.special_proxy.clone();
+                let (outbound_name, rule) = match (special_proxy.as_deref(), mode) {
+                    (Some(proxy), _) => (proxy, None),
+                    (None, RunMode::Global) => (PROXY_GLOBAL, None),
+                    (None, RunMode::Rule) => router.match_route(&mut sess).await,
+                    (None, RunMode::Direct) => (PROXY_DIRECT, None),
                 };
 
                 let outbound_name = outbound_name.to_string();
 
+                // through the full-cone session of the source only if the
+                // rules don't send it elsewhere, or reject it
+                if cone.as_ref() == Some(&outbound_name) {
+                    if let Some(handle) = sessions.join(owner, src, &dest) {
+                        match handle.send(packet).await {
+                            Ok(_) => {
+                                debug!("{} joined full-cone session", sess);
+                            }
+                            Err(err) => {
+                                error!("failed to send packet to remote: {}", err);
+                            }
+                        }
+                        continue;
+                    }
+                }
+
                 debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
 
                 let remote_receiver_w = remote_receiver_w.clone();
@@ -335,108 +698,165 @@ impl Dispatcher {
                         mgr.get_outbound(PROXY_DIRECT).unwrap()
                     });
 
-                match outbound_handle_guard
-                    .get_outbound_sender_mut(
-                        &outbound_name,
-                        packet.src_addr.clone().must_into_socket_addr(), /* this is only
-                                                                          * expected to be
-                                                                          * socket addr as it's
-                                                                          * from local
-                                                                          * udp */
-                    )
-                    .await
-                {
-                    None => {
-                        debug!("building {} outbound datagram connecting", sess);
-                        let outbound_datagram = match handler
-                            .connect_datagram(&sess, resolver.clone())
-                            .await
-                        {
-                            Ok(v) => v,
-                            Err(err) => {
-                                error!("failed to connect outbound: {}", err);
-                                continue;
-                            }
-                        };
-
-                        debug!("{} outbound datagram connected", sess);
-
-                        let outbound_datagram = TrackedDatagram::new(
-                            outbound_datagram,
-                            manager.clone(),
-                            sess.clone(),
-                            rule,
-                        )
-                        .await;
-
-                        let (mut remote_w, mut remote_r) = outbound_datagram.split();
-                        let (remote_sender, mut remote_forwarder) =
-                            tokio::sync::mpsc::channel::<UdpPacket>(32);
-
-                        // remote -> local
-                        let r_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_r.next().await {
-                                // NAT
-                                let mut packet = packet;
-                                packet.src_addr = sess.destination.clone();
-                                packet.dst_addr = sess.source.into();
+                let pinned = affinity.pinned(&mgr, &handler, &sess).await;
+                let mut dialed = pinned.clone().unwrap_or(handler.clone());
+                let mut member = mgr.selected_member(&dialed).await;
 
-                                debug!(
-                                    "UDP NAT for packet: {:?}, session: {}",
-                                    packet, sess
-                                );
-                                match remote_receiver_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        warn!(
-                                            "failed to send packet to local: {}",
-                                            err
-                                        );
-                                    }
-                                }
-                            }
-                        });
-                        // local -> remote
-                        let w_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_forwarder.recv().await {
-                                match remote_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        warn!(
-                                            "failed to send packet to remote: {}",
-                                            err
-                                        );
-                                    }
-                                }
+                // here rather than failing deep in the handler
+                let fallback = mgr.get_outbound(&udp_fallback);
+                let fell_back = match udp_route(&dialed, &member, fallback).await {
+                    UdpRoute::Dialed => false,
+                    UdpRoute::Fallback(f, reason) => {
+                        info!("{}: {}, through {} instead", sess, reason, f.name());
+                        sess.udp_fallback = Some(reason);
+                        member = mgr.selected_member(&f).await;
+                        dialed = f;
+                        true
+                    }
+                    UdpRoute::Drop(reason) => {
+                        debug!("dropping {}: {}", sess, reason);
+                        continue;
+                    }
+                };
+                // not pinned to the fallback either
+                let pinned = if fell_back { None } else { pinned };
+
+                let nat = match cone {
+                    // one of its own, the full-cone session stays
+                    Some(_) => UdpNat::Symmetric,
+                    None => member.udp_nat().unwrap_or(sessions.default_nat()),
+                };
+                let proto = member.proto();
+                sess.fixed_destination = nat == UdpNat::Symmetric;
+
+                debug!("building {} outbound datagram connecting", sess);
+                let outbound_datagram =
+                    match dialed.connect_datagram(&sess, resolver.clone()).await {
+                        Ok(v) => v,
+                        Err(err) => {
+                            error!(
+                                dial_error =
+                                    DialError::of(&err).map(tracing::field::display),
+                                "failed to connect outbound: {}", err
+                            );
+                            if pinned.is_some() {
+                                affinity.unpin(handler.name(), &sess);
                             }
-                        });
-
-                        outbound_handle_guard
-                            .insert(
-                                &outbound_name,
-                                packet.src_addr.clone().must_into_socket_addr(),
-                                r_handle,
-                                w_handle,
-                                remote_sender.clone(),
-                            )
-                            .await;
-
-                        match remote_sender.send(packet).await {
+                            mgr.report_dial_error(&dialed, &err).await;
+                            continue;
+                        }
+                    };
+
+                debug!("{} outbound datagram connected", sess);
+                if pinned.is_some() {
+                    outbound_datagram.append_to_chain(handler.name()).await;
+                }
+                mgr.complete_chain(outbound_datagram.chain()).await;
+                if pinned.is_none() && !fell_back {
+                    let chain = outbound_datagram.chain().names().await;
+                    affinity.pin(&mgr, &handler, &sess, &chain).await;
+                }
+
+                let outbound_datagram = TrackedDatagram::new(
+                    outbound_datagram,
+                    manager.clone(),
+                    sess.clone(),
+                    rule,
+                )
+                .await;
+                let throttle = throttle(
+                    &outbound_manager,
+                    &listener_bandwidth,
+                    &sess,
+                    &outbound_datagram.tracker_info().proxy_chain_holder,
+                )
+                .await;
+
+                let tunnel = outbound_datagram.tunnel_mtu();
+                let (mut remote_w, mut remote_r) = outbound_datagram.split();
+                let (remote_sender, mut remote_forwarder) =
+                    tokio::sync::mpsc::channel::<UdpPacket>(32);
+                let activity = sessions.new_activity();
+
+                // remote -> local
+                let active = activity.clone();
+                let down = throttle.clone();
+                let closer = sessions.clone();
+                let r_handle = tokio::spawn(async move {
+                    while let Some(packet) = remote_r.next().await {
+                        active.touch();
+                        down.wait_down(packet.data.len()).await;
+                        // NAT, a full-cone session is open to any peer, which
+                        // the client is then told about
+                        let mut packet = packet;
+                        if nat == UdpNat::Symmetric {
+                            packet.src_addr = sess.destination.clone();
+                        }
+                        packet.dst_addr = sess.source.into();
+
+                        debug!(
+                            "UDP NAT for packet: {:?}, session: {}",
+                            packet, sess
+                        );
+                        match remote_receiver_w.send(packet).await {
                             Ok(_) => {}
                             Err(err) => {
-                                error!("failed to send packet to remote: {}", err);
+                                warn!("failed to send packet to local: {}", err);
                             }
-                        };
+                        }
                     }
-                    Some(handle) => match handle.send(packet).await {
-                        // TODO: need to reset when GLOBAL select is changed
-                        Ok(_) => {
-                            debug!("reusing {} sent to remote", sess);
+                    // nothing more will come back through it
+                    closer.close(&active);
+                });
+                // local -> remote
+                let active = activity.clone();
+                let w_sess = sess.clone();
+                let closer = sessions.clone();
+                let w_handle = tokio::spawn(async move {
+                    let mut oversized = Oversized::default();
+                    while let Some(packet) = remote_forwarder.recv().await {
+                        active.touch();
+                        let max_payload =
+                            tunnel.map(|x| x.max_udp_payload(&packet.dst_addr));
+                        if !oversized.check(&w_sess, &packet, max_payload, &proto) {
+                            continue;
                         }
-                        Err(err) => {
-                            error!("failed to send packet to remote: {}", err);
+                        throttle.wait_up(packet.data.len()).await;
+                        match remote_w.send(packet).await {
+                            Ok(_) => {}
+                            // an ICMP error of an earlier packet, the peer is
+                            // gone
+                            Err(err)
+                                if err.kind()
+                                    == std::io::ErrorKind::ConnectionRefused =>
+                            {
+                                debug!("{} unreachable: {}", w_sess, err);
+                                closer.close(&active);
+                                break;
+                            }
+                            Err(err) => {
+                                warn!("failed to send packet to remote: {}", err);
+                            }
                         }
-                    },
+                    }
+                });
+
+                sessions.insert(
+                    owner,
+                    src,
+                    dest,
+                    outbound_name,
+                    nat,
+                    remote_sender.clone(),
+                    [r_handle, w_handle],
+                    activity,
+                );
+
+                match remote_sender.send(packet).await {
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!("failed to send packet to remote: {}", err);
+                    }
                 };
             }
 
@@ -466,154 +886,348 @@ impl Dispatcher {
             trace!("UDP close signal for {} received", s);
             t1.abort();
             t2.abort();
+            udp_sessions.remove_owner(owner);
         });
 
         return close_sender;
     }
 }
 
-type OutboundPacketSender = tokio::sync::mpsc::Sender<UdpPacket>; // outbound packet sender
+#[cfg(test)]
+impl Dispatcher {
+    /// One with only DIRECT and REJECT, picked between by `rules`, for the
+    /// tests of the inbounds. The destinations are to be IPs.
+    pub(crate) async fn for_test(
+        rules: Vec<crate::config::internal::rule::RuleType>,
+        nat: UdpNat,
+    ) -> Self {
+        use crate::{
+            app::{
+                dns::MockClashResolver, outbound::manager::OutboundManager,
+                profile::ThreadSafeCacheFile, router::Router,
+            },
+            common::{geodata::GeoData, http::new_http_client, mmdb::Mmdb},
+            config::internal::proxy::OutboundProxyProtocol,
+        };
 
-struct TimeoutUdpSessionManager {
-    map: Arc<RwLock<OutboundHandleMap>>,
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_fake_ip_enabled().return_const(false);
+        resolver.expect_cached_for().returning(|_| None);
+        resolver
+            .expect_resolve()
+            .returning(|host, _| Ok(host.parse().ok()));
+        let resolver = Arc::new(resolver);
 
-    cleaner: Option<JoinHandle<()>>,
-}
+        let dir = tempfile::tempdir().unwrap();
+        let geosite = dir.path().join("geosite.dat");
+        std::fs::write(&geosite, b"").unwrap();
+        let client = new_http_client(resolver.clone()).unwrap();
+        let mmdb = Mmdb::new(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/Country.mmdb"),
+            None,
+            client.clone(),
+        )
+        .await
+        .unwrap();
+        let geodata = GeoData::new(&geosite, None, client).await.unwrap();
+        let cwd = dir.path().to_string_lossy().to_string();
 
-impl Drop for TimeoutUdpSessionManager {
-    fn drop(&mut self) {
-        trace!("dropping timeout udp session manager");
-        if let Some(x) = self.cleaner.take() {
-            x.abort()
-        }
+        let router = Router::new(
+            rules,
+            Default::default(),
+            Default::default(),
+            resolver.clone(),
+            Arc::new(mmdb),
+            None,
+            Arc::new(geodata),
+            cwd.clone(),
+        )
+        .await;
+        let outbounds = OutboundManager::new(
+            vec![OutboundProxyProtocol::Direct, OutboundProxyProtocol::Reject],
+            vec![],
+            HashMap::new(),
+            vec![PROXY_DIRECT.to_owned(), PROXY_REJECT.to_owned()],
+            resolver.clone(),
+            ThreadSafeCacheFile::new(
+                dir.path().join("cache.db").to_str().unwrap(),
+                false,
+            ),
+            cwd,
+            None,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        Self::new(
+            Arc::new(outbounds),
+            Arc::new(router),
+            resolver,
+            RunMode::Rule,
+            UdpSessions {
+                idle_timeout: Duration::from_secs(60),
+                max: 0,
+                nat,
+            },
+            PROXY_REJECT.to_owned(),
+            HashMap::new(),
+            Manager::new(),
+        )
     }
 }
 
-impl TimeoutUdpSessionManager {
-    fn new() -> Self {
-        let map = Arc::new(RwLock::new(OutboundHandleMap::new()));
-        let timeout = Duration::from_secs(10);
-
-        let map_cloned = map.clone();
-
-        let cleaner = tokio::spawn(async move {
-            trace!("timeout udp session cleaner scanning");
-            let mut interval = tokio::time::interval(timeout);
-
-            loop {
-                interval.tick().await;
-                trace!("timeout udp session cleaner ticking");
-
-                let mut g = map_cloned.write().await;
-                let mut alived = 0;
-                let mut expired = 0;
-                g.0.retain(|k, x| {
-                    let (h1, h2, _, last) = x;
-                    let now = Instant::now();
-                    let alive = now.duration_since(*last) < timeout;
-                    if !alive {
-                        expired += 1;
-                        trace!("udp session expired: {:?}", k);
-                        h1.abort();
-                        h2.abort();
-                    } else {
-                        alived += 1;
-                    }
-                    alive
-                });
-                trace!(
-                    "timeout udp session cleaner finished, alived: {}, expired: {}",
-                    alived,
-                    expired
-                );
-            }
-        });
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
 
-        Self {
-            map,
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::{mpsc, RwLock},
+    };
 
-            cleaner: Some(cleaner),
-        }
+    use crate::{
+        app::dispatcher::{
+            statistics_manager::Manager, tracked::TrackedStream, BoxedChainedStream,
+            ChainedStreamWrapper,
+        },
+        config::{def::UdpNat, internal::rule::RuleType},
+        proxy::{
+            datagram::UdpPacket,
+            direct,
+            group::selector::{self, SelectorControl},
+            mocks::{MockDummyOutboundHandler, MockDummyProxyProvider},
+            reject,
+            tun::TunDatagram,
+            AnyOutboundHandler, AnyStream, OutboundType,
+        },
+        session::{Session, SocksAddr},
+    };
+
+    use super::{relay, udp_route, Dispatcher, Oversized, UdpRoute};
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let c = TcpStream::connect(l.local_addr().unwrap()).await.unwrap();
+        let (s, _) = l.accept().await.unwrap();
+        (c, s)
     }
 
-    async fn insert(
-        &self,
-        outbound_name: &str,
-        src_addr: SocketAddr,
-        recv_handle: JoinHandle<()>,
-        send_handle: JoinHandle<()>,
-        sender: OutboundPacketSender,
-    ) {
-        let mut map = self.map.write().await;
-        map.insert(outbound_name, src_addr, recv_handle, send_handle, sender);
+    /// client <-> lhs, relayed to rhs <-> server
+    async fn check_relay(outbound: fn(TcpStream) -> BoxedChainedStream, raw: bool) {
+        let (mut client, mut lhs) = pair().await;
+        let (rhs, mut server) = pair().await;
+        let mut rhs = TrackedStream::new(
+            outbound(rhs),
+            Manager::new(),
+            Session::default(),
+            None,
+        )
+        .await;
+        assert_eq!(rhs.as_raw_tcp().is_some(), raw);
+        let tracker = rhs.tracker_info();
+        let relayed = tokio::spawn(async move { relay(&mut lhs, &mut rhs).await });
+
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = vec![];
+        server.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+
+        server.write_all(b"pong!").await.unwrap();
+        server.shutdown().await.unwrap();
+        buf.clear();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong!");
+
+        assert_eq!(relayed.await.unwrap().unwrap(), (4, 5));
+        assert_eq!(tracker.upload_total.load(Ordering::Acquire), 4);
+        assert_eq!(tracker.download_total.load(Ordering::Acquire), 5);
     }
 
-    async fn get_outbound_sender_mut(
-        &self,
-        outbound_name: &str,
-        src_addr: SocketAddr,
-    ) -> Option<OutboundPacketSender> {
-        let mut map = self.map.write().await;
-        map.get_outbound_sender_mut(outbound_name, src_addr)
+    #[tokio::test]
+    async fn test_relay_plain_tcp() {
+        check_relay(|s| Box::new(ChainedStreamWrapper::new(s)), true).await;
     }
-}
 
-type OutboundHandleKey = (String, SocketAddr);
-type OutboundHandleVal = (
-    JoinHandle<()>,
-    JoinHandle<()>,
-    OutboundPacketSender,
-    Instant,
-);
+    #[tokio::test]
+    async fn test_relay_wrapped_falls_back() {
+        // e.g. anything behind tls or ws, which the copy loop has to go through
+        check_relay(
+            |s| Box::new(ChainedStreamWrapper::new(Box::new(s) as AnyStream)),
+            false,
+        )
+        .await;
+    }
 
-struct OutboundHandleMap(HashMap<OutboundHandleKey, OutboundHandleVal>);
+    #[test]
+    fn test_oversized() {
+        let packet = |size| UdpPacket {
+            data: vec![0; size],
+            src_addr: SocksAddr::any_ipv4(),
+            dst_addr: "1.1.1.1:443".parse::<SocketAddr>().unwrap().into(),
+        };
+        let sess = Session::default();
+        let mut o = Oversized::default();
 
-impl OutboundHandleMap {
-    fn new() -> Self {
-        Self(HashMap::new())
-    }
+        // the default wireguard tunnel
+        let wg = OutboundType::WireGuard;
+        assert!(o.check(&sess, &packet(1392), Some(1392), &wg));
+        assert!(!o.check(&sess, &packet(1393), Some(1392), &wg));
+        assert_eq!(o.dropped, 0, "logged and reset");
+        assert!(!o.check(&sess, &packet(1393), Some(1392), &wg));
+        assert_eq!(o.dropped, 1, "not logged again so soon");
 
-    fn insert(
-        &mut self,
-        outbound_name: &str,
-        src_addr: SocketAddr,
-        recv_handle: JoinHandle<()>,
-        send_handle: JoinHandle<()>,
-        sender: OutboundPacketSender,
-    ) {
-        self.0.insert(
-            (outbound_name.to_string(), src_addr),
-            (recv_handle, send_handle, sender, Instant::now()),
-        );
+        // sent, but fragmented on the way
+        let ss = OutboundType::Shadowsocks;
+        assert!(o.check(&sess, &packet(1417), None, &ss));
+        assert_eq!(o.fragmented, 0);
+        assert!(o.check(&sess, &packet(1418), None, &ss));
+        assert_eq!(o.fragmented, 1);
+        // which tuic does itself
+        assert!(o.check(&sess, &packet(9000), None, &OutboundType::Tuic));
+        assert_eq!(o.fragmented, 1);
     }
 
-    fn get_outbound_sender_mut(
-        &mut self,
-        outbound_name: &str,
-        src_addr: SocketAddr,
-    ) -> Option<OutboundPacketSender> {
-        self.0.get_mut(&(outbound_name.to_owned(), src_addr)).map(
-            |(_, _, sender, last)| {
-                trace!(
-                    "updating last access time for outbound {:?}",
-                    (outbound_name, src_addr)
-                );
-                *last = Instant::now();
-                sender.clone()
+    #[tokio::test]
+    async fn test_udp_route() {
+        let mut provider = MockDummyProxyProvider::new();
+        provider.expect_name().return_const("provider".to_owned());
+        provider.expect_proxies().returning(|| {
+            [("with-udp", true), ("tcp-only", false)]
+                .into_iter()
+                .map(|(name, udp)| {
+                    let mut p = MockDummyOutboundHandler::new();
+                    p.expect_name().return_const(name.to_owned());
+                    p.expect_support_udp().return_const(udp);
+                    Arc::new(p) as AnyOutboundHandler
+                })
+                .collect()
+        });
+        let mut group = selector::Handler::new(
+            selector::HandlerOptions {
+                name: "select".to_owned(),
+                udp: true,
+                ..Default::default()
             },
+            vec![Arc::new(RwLock::new(provider))],
+            None,
         )
+        .await;
+        let dialed: AnyOutboundHandler = Arc::new(group.clone());
+        let direct: AnyOutboundHandler = Arc::new(direct::Handler::new());
+        let reject: AnyOutboundHandler = Arc::new(reject::Handler::new());
+
+        assert!(matches!(
+            udp_route(&dialed, &dialed, Some(direct.clone())).await,
+            UdpRoute::Dialed
+        ));
+
+        // the selection flips to one without
+        group.select("tcp-only").await.unwrap();
+        match udp_route(&dialed, &dialed, Some(direct.clone())).await {
+            UdpRoute::Fallback(f, reason) => {
+                assert_eq!(f.name(), "DIRECT");
+                assert_eq!(reason, "select has no udp");
+            }
+            _ => panic!("should fall back to DIRECT"),
+        }
+        assert!(matches!(
+            udp_route(&dialed, &dialed, Some(reject)).await,
+            UdpRoute::Drop(_)
+        ));
+
+        // and back
+        group.select("with-udp").await.unwrap();
+        assert!(matches!(
+            udp_route(&dialed, &dialed, Some(direct)).await,
+            UdpRoute::Dialed
+        ));
     }
-}
 
-impl Drop for OutboundHandleMap {
-    fn drop(&mut self) {
-        trace!(
-            "dropping inner outbound handle map that has {} sessions",
-            self.0.len()
+    /// answers with the address the packet came from
+    async fn udp_peer() -> SocketAddr {
+        let s = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = s.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            while let Ok((_, from)) = s.recv_from(&mut buf).await {
+                let _ = s.send_to(from.to_string().as_bytes(), from).await;
+            }
+        });
+        addr
+    }
+
+    /// the answer sent back to `client`, where from and what it says
+    async fn pong(
+        rx: &mut mpsc::Receiver<UdpPacket>,
+        client: SocketAddr,
+    ) -> (SocksAddr, String) {
+        let p = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(p.dst_addr, SocksAddr::from(client));
+        (p.src_addr, String::from_utf8(p.data).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_full_cone_follows_rules() {
+        let a = udp_peer().await;
+        let b = udp_peer().await;
+        let rejected = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let dispatcher = Dispatcher::for_test(
+            vec![
+                RuleType::DSTPort {
+                    target: "REJECT".to_owned(),
+                    port: rejected.local_addr().unwrap().port(),
+                },
+                RuleType::Match {
+                    target: "DIRECT".to_owned(),
+                },
+            ],
+            UdpNat::FullCone,
+        )
+        .await;
+
+        let (l_tx, mut l_rx) = mpsc::channel(32);
+        let (d_tx, d_rx) = mpsc::channel(32);
+        let _closer = dispatcher.dispatch_datagram(
+            Session::default(),
+            Box::new(TunDatagram::new(l_tx, d_rx)),
         );
-        for (_, (recv_handle, send_handle, ..)) in self.0.drain() {
-            recv_handle.abort();
-            send_handle.abort();
-        }
+
+        let client: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let ping = |dst: SocketAddr| {
+            UdpPacket::new(b"ping".to_vec(), client.into(), dst.into())
+        };
+
+        d_tx.send(ping(a)).await.unwrap();
+        let (from, seen_by_a) = pong(&mut l_rx, client).await;
+        assert_eq!(from, SocksAddr::from(a));
+
+        // not let through the full-cone session to DIRECT
+        d_tx.send(ping(rejected.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let mut buf = [0; 64];
+        assert!(tokio::time::timeout(
+            Duration::from_millis(500),
+            rejected.recv_from(&mut buf)
+        )
+        .await
+        .is_err());
+
+        // while another peer DIRECT gets the same session, so the same port
+        d_tx.send(ping(b)).await.unwrap();
+        let (from, seen_by_b) = pong(&mut l_rx, client).await;
+        assert_eq!(from, SocksAddr::from(b));
+        assert_eq!(seen_by_a, seen_by_b);
     }
 }
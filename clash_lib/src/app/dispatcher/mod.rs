@@ -1,10 +1,17 @@
+mod affinity;
 mod dispatcher_impl;
+mod sources;
 mod statistics_manager;
 mod tracked;
+mod traffic_totals;
+mod udp_session;
 
 pub use dispatcher_impl::Dispatcher;
-pub use statistics_manager::Manager as StatisticsManager;
+pub use statistics_manager::{Manager as StatisticsManager, ProxyChain};
+#[cfg(test)]
+pub use tracked::TrackedStream;
 pub use tracked::{
     BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram,
     ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
 };
+pub use traffic_totals::TrafficTotals;
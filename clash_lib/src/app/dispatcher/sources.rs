@@ -0,0 +1,175 @@
+//! The clients using the proxy, by source IP, for `GET /connections/sources`.
+//!
+//! Like the totals of the proxies and rules, a connection holds the counters
+//! of its source from when it's tracked, and its bytes are added on the
+//! statistics tick and when it ends. A source is kept after its connections
+//! end, the one seen the longest ago being dropped once there are too many.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// how many sources are kept, the ones with connections open always are
+const MAX_SOURCES: usize = 1024;
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    active: AtomicU64,
+    upload: AtomicU64,
+    download: AtomicU64,
+    /// in unix millis
+    last_seen: AtomicI64,
+}
+
+impl Counters {
+    fn seen(&self) {
+        self.last_seen
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(&self, upload: u64, download: u64) {
+        self.upload.fetch_add(upload, Ordering::Relaxed);
+        self.download.fetch_add(download, Ordering::Relaxed);
+        self.seen();
+    }
+
+    pub(crate) fn close(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.seen();
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceInfo {
+    pub source: IpAddr,
+    /// from `clients`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub active_connections: u64,
+    pub total_up: u64,
+    pub total_down: u64,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct Sources {
+    table: Mutex<HashMap<IpAddr, Arc<Counters>>>,
+    /// the names of the clients, from `clients`
+    names: RwLock<HashMap<IpAddr, String>>,
+}
+
+impl Sources {
+    /// counts a new connection from `source`
+    pub(crate) fn open(&self, source: IpAddr) -> Arc<Counters> {
+        let source = source.to_canonical();
+        let mut table = self.table.lock().unwrap();
+        if !table.contains_key(&source) && table.len() >= MAX_SOURCES {
+            let oldest = table
+                .iter()
+                .filter(|(_, c)| c.active.load(Ordering::Relaxed) == 0)
+                .min_by_key(|(_, c)| c.last_seen.load(Ordering::Relaxed))
+                .map(|(ip, _)| *ip);
+            if let Some(ip) = oldest {
+                table.remove(&ip);
+            }
+        }
+        let c = table.entry(source).or_default().clone();
+        c.active.fetch_add(1, Ordering::Relaxed);
+        c.seen();
+        c
+    }
+
+    pub fn set_names(&self, names: HashMap<IpAddr, String>) {
+        *self.names.write().unwrap() = names;
+    }
+
+    /// the sources seen most recently first
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn list(&self) -> Vec<SourceInfo> {
+        let names = self.names.read().unwrap();
+        let mut sources = self
+            .table
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, c)| SourceInfo {
+                source: *ip,
+                name: names.get(ip).cloned(),
+                active_connections: c.active.load(Ordering::Relaxed),
+                total_up: c.upload.load(Ordering::Relaxed),
+                total_down: c.download.load(Ordering::Relaxed),
+                last_seen: DateTime::from_timestamp_millis(
+                    c.last_seen.load(Ordering::Relaxed),
+                )
+                .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        sources.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        sources
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::IpAddr, sync::atomic::Ordering};
+
+    use super::{Sources, MAX_SOURCES};
+
+    #[test]
+    fn test_sources() {
+        let sources = Sources::default();
+        let laptop: IpAddr = "192.168.1.10".parse().unwrap();
+        sources.set_names(HashMap::from([(laptop, "laptop".to_owned())]));
+
+        let a = sources.open(laptop);
+        // the same client over the dual-stack listeners
+        let b = sources.open("::ffff:192.168.1.10".parse().unwrap());
+        a.add(10, 100);
+        b.add(5, 0);
+        a.close();
+
+        let list = sources.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].name.as_deref(), Some("laptop"));
+        assert_eq!(list[0].active_connections, 1);
+        assert_eq!((list[0].total_up, list[0].total_down), (15, 100));
+
+        let j = serde_json::to_value(&list[0]).unwrap();
+        assert_eq!(j["source"], "192.168.1.10");
+        assert_eq!(j["activeConnections"], 1);
+        assert_eq!(j["totalUp"], 15);
+        assert_eq!(j["totalDown"], 100);
+        assert!(j["lastSeen"].is_string());
+    }
+
+    #[test]
+    fn test_oldest_dropped() {
+        let sources = Sources::default();
+        let open = sources.open("10.0.0.1".parse().unwrap());
+        for i in 0..MAX_SOURCES as u32 {
+            let c = sources.open(IpAddr::from((0x0b00_0000 + i).to_be_bytes()));
+            c.close();
+            c.last_seen.store(i as i64, Ordering::Relaxed);
+        }
+
+        let list = sources.list();
+        assert_eq!(list.len(), MAX_SOURCES);
+        // still has a connection open
+        assert!(list
+            .iter()
+            .any(|x| x.source == "10.0.0.1".parse::<IpAddr>().unwrap()));
+        assert!(!list
+            .iter()
+            .any(|x| x.source == "11.0.0.0".parse::<IpAddr>().unwrap()));
+        open.close();
+    }
+}
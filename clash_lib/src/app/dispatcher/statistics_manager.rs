@@ -1,7 +1,8 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
     sync::{
-        atomic::{AtomicI64, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
         Arc,
     },
 };
@@ -9,11 +10,19 @@ use std::{
 use chrono::Utc;
 use memory_stats::memory_stats;
 use serde::Serialize;
-use tokio::sync::{oneshot::Sender, Mutex, RwLock};
+use tokio::sync::{oneshot::Sender, watch, Mutex, RwLock};
+use tracing::debug;
 
-use crate::session::Session;
+use crate::{
+    config::internal::config::TcpIdle,
+    proxy::utils::DialError,
+    session::{Network, Session},
+};
 
-use super::tracked::Tracked;
+use super::{
+    tracked::Tracked,
+    traffic_totals::{Accounted, TrafficTotals},
+};
 
 #[derive(Default, Clone, Debug)]
 pub struct ProxyChain(Arc<RwLock<Vec<String>>>);
@@ -23,6 +32,14 @@ impl ProxyChain {
         let mut chain = self.0.write().await;
         chain.push(s);
     }
+
+    pub async fn names(&self) -> Vec<String> {
+        self.0.read().await.clone()
+    }
+
+    pub async fn set(&self, names: Vec<String>) {
+        *self.0.write().await = names;
+    }
 }
 
 #[derive(Serialize, Default)]
@@ -35,6 +52,12 @@ pub struct TrackerInfo {
     pub upload_total: AtomicU64,
     #[serde(rename = "download")]
     pub download_total: AtomicU64,
+    /// in bytes per second, what moved between the last two ticks of the
+    /// sampler. 0 once the connection is closed.
+    #[serde(rename = "uploadSpeed")]
+    pub upload_speed: AtomicU64,
+    #[serde(rename = "downloadSpeed")]
+    pub download_speed: AtomicU64,
     #[serde(rename = "start")]
     pub start_time: chrono::DateTime<Utc>,
     #[serde(rename = "chains")]
@@ -48,6 +71,128 @@ pub struct TrackerInfo {
     pub proxy_chain_holder: ProxyChain,
     #[serde(skip)]
     pub session_holder: Session,
+    /// why the connection ended, if it failed
+    #[serde(skip_serializing_if = "no_error")]
+    pub error: std::sync::Mutex<Option<String>>,
+    /// what the error is of, if it's known, for the access log
+    #[serde(skip)]
+    pub error_category: std::sync::Mutex<Option<DialError>>,
+
+    /// the bytes moved either way as of the last idle check
+    #[serde(skip)]
+    idle_total: AtomicU64,
+    /// seconds since bytes last moved, as counted by the idle check
+    #[serde(skip)]
+    idle_secs: AtomicU64,
+    /// the totals as of the last tick, for the speeds
+    #[serde(skip)]
+    sampled_upload: AtomicU64,
+    #[serde(skip)]
+    sampled_download: AtomicU64,
+}
+
+fn no_error(error: &std::sync::Mutex<Option<String>>) -> bool {
+    error.lock().unwrap().is_none()
+}
+
+impl TrackerInfo {
+    /// Only the first error is kept, it's the one that ended the connection,
+    /// e.g. `idle` rather than the broken pipe closing it caused.
+    pub fn set_error(&self, error: impl std::fmt::Display) {
+        self.error
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| error.to_string());
+    }
+
+    /// [`Self::set_error`] with what the error is of, which the access log
+    /// shows next to it
+    pub fn set_error_with_category(&self, e: &(dyn std::error::Error + 'static)) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(e.to_string());
+            *self.error_category.lock().unwrap() = DialError::find(e);
+        }
+    }
+
+    /// what the api shows of the connection as of now
+    async fn snapshot(&self) -> TrackerInfo {
+        let chain = self.proxy_chain_holder.0.read().await;
+        TrackerInfo {
+            uuid: self.uuid,
+            upload_total: AtomicU64::new(self.upload_total.load(Ordering::Acquire)),
+            download_total: AtomicU64::new(
+                self.download_total.load(Ordering::Acquire),
+            ),
+            upload_speed: AtomicU64::new(self.upload_speed.load(Ordering::Relaxed)),
+            download_speed: AtomicU64::new(
+                self.download_speed.load(Ordering::Relaxed),
+            ),
+            start_time: self.start_time,
+            proxy_chain: chain.clone(),
+            rule: self.rule.clone(),
+            rule_payload: self.rule_payload.clone(),
+            session: self.session_holder.as_map(),
+            error: std::sync::Mutex::new(self.error.lock().unwrap().clone()),
+            error_category: std::sync::Mutex::new(
+                *self.error_category.lock().unwrap(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// Called on each tick of the sampler, once a second, so the bytes moved
+    /// since the last one are the speed. The relay only counts the totals.
+    fn sample_speed(&self) {
+        let up = self.upload_total.load(Ordering::Relaxed);
+        let down = self.download_total.load(Ordering::Relaxed);
+        let last_up = self.sampled_upload.swap(up, Ordering::Relaxed);
+        let last_down = self.sampled_download.swap(down, Ordering::Relaxed);
+        self.upload_speed
+            .store(up.saturating_sub(last_up), Ordering::Relaxed);
+        self.download_speed
+            .store(down.saturating_sub(last_down), Ordering::Relaxed);
+    }
+
+    /// Called once a second, true once no bytes moved either way for
+    /// `timeout`. Only the totals are compared, so the relay doesn't do
+    /// anything more for it than counting the bytes as it already does.
+    fn idle_for(&self, timeout: u64) -> bool {
+        let total = self.upload_total.load(Ordering::Relaxed)
+            + self.download_total.load(Ordering::Relaxed);
+        if self.idle_total.swap(total, Ordering::Relaxed) != total {
+            self.idle_secs.store(0, Ordering::Relaxed);
+            return false;
+        }
+        self.idle_secs.fetch_add(1, Ordering::Relaxed) + 1 >= timeout
+    }
+
+    /// one line per finished connection when `log-connections` is set, all
+    /// fields are only formatted if info events are enabled
+    async fn log_access(&self) {
+        if !tracing::enabled!(tracing::Level::INFO) {
+            return;
+        }
+        let sess = &self.session_holder;
+        let chain = self.proxy_chain_holder.0.read().await;
+        let error = self.error.lock().unwrap().clone();
+        let category = *self.error_category.lock().unwrap();
+        tracing::info!(
+            network = %sess.network,
+            inbound = ?sess.typ,
+            source = %sess.source,
+            destination = %sess.destination,
+            rule = %self.rule,
+            rule_payload = %self.rule_payload,
+            chain = %chain.join(" -> "),
+            upload = self.upload_total.load(Ordering::Relaxed),
+            download = self.download_total.load(Ordering::Relaxed),
+            duration_ms = (Utc::now() - self.start_time).num_milliseconds(),
+            error = error.as_deref(),
+            error_category = category.map(tracing::field::display),
+            "connection closed"
+        );
+    }
 }
 
 #[derive(Serialize)]
@@ -56,31 +201,70 @@ pub struct Snapshot {
     download_total: i64,
     upload_total: i64,
     connections: Vec<TrackerInfo>,
+    /// the last connections to end, newest first, with why they ended
+    closed_connections: Vec<TrackerInfo>,
     memory: usize,
+    /// the UDP sessions open now
+    udp_sessions: u64,
+    /// the UDP sessions closed for being idle or to make room
+    udp_evictions: u64,
 }
 
-type ConnectionMap = HashMap<uuid::Uuid, (Tracked, Sender<()>)>;
+/// The close signal is taken when the connection is closed from here, the
+/// entry stays until the connection is done with it and untracks itself.
+type ConnectionMap = HashMap<uuid::Uuid, (Tracked, Option<Sender<()>>, Accounted)>;
+
+/// how many ended connections the api shows
+const CLOSED_HISTORY: usize = 100;
 
 pub struct Manager {
     connections: Arc<Mutex<ConnectionMap>>,
+    closed: Arc<std::sync::Mutex<VecDeque<Arc<TrackerInfo>>>>,
     upload_temp: AtomicI64,
     download_temp: AtomicI64,
     upload_blip: AtomicI64,
     download_blip: AtomicI64,
     upload_total: AtomicI64,
     download_total: AtomicI64,
+    /// the (up, down) speed, published once per second by the sampler
+    traffic_tx: watch::Sender<(i64, i64)>,
+    /// the memory in use, published once per second while anyone subscribes
+    memory_tx: watch::Sender<usize>,
+    memory_sampling: AtomicBool,
+    log_connections: AtomicBool,
+    udp_sessions: AtomicU64,
+    udp_evictions: AtomicU64,
+    tcp_idle: std::sync::Mutex<TcpIdle>,
+    /// cleared on shutdown, no connections are dispatched anymore
+    accepting: AtomicBool,
+    totals: Arc<TrafficTotals>,
 }
 
 impl Manager {
     pub fn new() -> Arc<Self> {
+        Self::with_totals(TrafficTotals::new())
+    }
+
+    /// `totals` are carried over from the manager being replaced on a reload
+    pub fn with_totals(totals: Arc<TrafficTotals>) -> Arc<Self> {
         let v = Arc::new(Self {
             connections: Arc::new(Mutex::new(HashMap::new())),
+            closed: Default::default(),
             upload_temp: AtomicI64::new(0),
             download_temp: AtomicI64::new(0),
             upload_blip: AtomicI64::new(0),
             download_blip: AtomicI64::new(0),
             upload_total: AtomicI64::new(0),
             download_total: AtomicI64::new(0),
+            traffic_tx: watch::channel((0, 0)).0,
+            memory_tx: watch::channel(0).0,
+            memory_sampling: AtomicBool::new(false),
+            log_connections: AtomicBool::new(false),
+            udp_sessions: AtomicU64::new(0),
+            udp_evictions: AtomicU64::new(0),
+            tcp_idle: Default::default(),
+            accepting: AtomicBool::new(true),
+            totals,
         });
         let c = v.clone();
         tokio::spawn(async move {
@@ -90,39 +274,103 @@ impl Manager {
     }
 
     pub async fn track(&self, item: Tracked, close_notify: Sender<()>) {
+        let accounted = {
+            let t = item.tracker_info();
+            let chain = t.proxy_chain_holder.names().await;
+            let rule = match (t.rule.as_str(), t.rule_payload.as_str()) {
+                ("", _) => None,
+                (rule, "") => Some(rule.to_owned()),
+                (rule, payload) => Some(format!("{}({})", rule, payload)),
+            };
+            self.totals
+                .open(&chain, rule.as_deref(), t.session_holder.source.ip())
+        };
+
         let mut connections = self.connections.lock().await;
 
-        connections.insert(item.id(), (item, close_notify));
+        connections.insert(item.id(), (item, Some(close_notify), accounted));
     }
 
     /// Untrack a connection.
     /// this method is not async because it is called in Drop.
     pub fn untrack(&self, id: uuid::Uuid) {
         let connections = self.connections.clone();
+        let closed = self.closed.clone();
+        let log_connections = self.log_connections.load(Ordering::Relaxed);
 
         tokio::spawn(async move {
-            let mut connections = connections.lock().await;
-            connections.remove(&id);
+            let Some((tracked, _, mut accounted)) =
+                connections.lock().await.remove(&id)
+            else {
+                return;
+            };
+            let info = tracked.tracker_info();
+            accounted.update(&info);
+            info.upload_speed.store(0, Ordering::Relaxed);
+            info.download_speed.store(0, Ordering::Relaxed);
+            {
+                let mut closed = closed.lock().unwrap();
+                if closed.len() == CLOSED_HISTORY {
+                    closed.pop_back();
+                }
+                closed.push_front(info.clone());
+            }
+            if log_connections {
+                info.log_access().await;
+            }
         });
     }
 
+    /// log every connection as it ends, see `TrackerInfo::log_access`
+    pub fn set_log_connections(&self, enabled: bool) {
+        self.log_connections.store(enabled, Ordering::Relaxed);
+    }
+
+    /// the names `GET /connections/sources` shows for the clients
+    pub fn set_client_names(&self, names: HashMap<IpAddr, String>) {
+        self.totals.sources().set_names(names);
+    }
+
+    /// close tcp connections once they are idle, see `idle_sweep`
+    pub fn set_tcp_idle(&self, tcp_idle: TcpIdle) {
+        *self.tcp_idle.lock().unwrap() = tcp_idle;
+    }
+
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub async fn close(&self, id: uuid::Uuid) {
         let connections = self.connections.clone();
 
         tokio::spawn(async move {
             let mut connections = connections.lock().await;
-            if let Some((_, close_notify)) = connections.remove(&id) {
+            if let Some(close_notify) =
+                connections.get_mut(&id).and_then(|(_, x, _)| x.take())
+            {
                 let _ = close_notify.send(());
             }
         });
     }
 
+    pub fn stop_accepting(&self) {
+        self.accepting.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Relaxed)
+    }
+
+    pub async fn connection_count(&self) -> usize {
+        self.connections.lock().await.len()
+    }
+
     pub async fn close_all(&self) {
         let connections = self.connections.clone();
 
         let mut connections = connections.lock().await;
-        for (_, (_, close_notify)) in connections.drain() {
-            let _ = close_notify.send(());
+        for (_, (tracked, close_notify, mut accounted)) in connections.drain() {
+            accounted.update(&tracked.tracker_info());
+            if let Some(close_notify) = close_notify {
+                let _ = close_notify.send(());
+            }
         }
     }
 
@@ -140,6 +388,13 @@ impl Manager {
             .fetch_add(n as i64, std::sync::atomic::Ordering::Relaxed);
     }
 
+    /// reported by the dispatcher as its UDP session table changes
+    pub fn set_udp_sessions(&self, open: usize, evicted: usize) {
+        self.udp_sessions.store(open as u64, Ordering::Relaxed);
+        self.udp_evictions
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
     // TODO: make this u64
     pub fn now(&self) -> (i64, i64) {
         (
@@ -149,25 +404,33 @@ impl Manager {
         )
     }
 
+    /// the (up, down) bytes moved since this manager was made
+    pub fn transferred(&self) -> (i64, i64) {
+        (
+            self.upload_total.load(Ordering::Relaxed),
+            self.download_total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Subscribes to the per second (up, down) speed, all the subscribers
+    /// share the same sampler.
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn subscribe_traffic(&self) -> watch::Receiver<(i64, i64)> {
+        self.traffic_tx.subscribe()
+    }
+
     pub async fn snapshot(&self) -> Snapshot {
         let mut connections = vec![];
         let conns = self.connections.lock().await;
         for (_, v) in conns.iter() {
-            let t = v.0.tracker_info();
-            let chain = t.proxy_chain_holder.0.read().await;
-            connections.push(TrackerInfo {
-                uuid: t.uuid,
-                upload_total: AtomicU64::new(t.upload_total.load(Ordering::Acquire)),
-                download_total: AtomicU64::new(
-                    t.download_total.load(Ordering::Acquire),
-                ),
-                start_time: t.start_time,
-                proxy_chain: chain.clone(),
-                rule: t.rule.clone(),
-                rule_payload: t.rule_payload.clone(),
-                session: t.session_holder.as_map(),
-                ..Default::default()
-            });
+            connections.push(v.0.tracker_info().snapshot().await);
+        }
+        drop(conns);
+
+        let closed = self.closed.lock().unwrap().clone();
+        let mut closed_connections = Vec::with_capacity(closed.len());
+        for t in closed {
+            closed_connections.push(t.snapshot().await);
         }
 
         Snapshot {
@@ -178,7 +441,10 @@ impl Manager {
                 .upload_total
                 .load(std::sync::atomic::Ordering::Relaxed),
             connections,
+            closed_connections,
             memory: self.memory_usage(),
+            udp_sessions: self.udp_sessions.load(Ordering::Relaxed),
+            udp_evictions: self.udp_evictions.load(Ordering::Relaxed),
         }
     }
 
@@ -192,22 +458,196 @@ impl Manager {
         self.download_total.store(0, Ordering::Relaxed);
     }
 
+    /// the traffic of each proxy and rule since start
+    pub fn totals(&self) -> Arc<TrafficTotals> {
+        self.totals.clone()
+    }
+
     pub fn memory_usage(&self) -> usize {
         memory_stats().map(|x| x.physical_mem).unwrap_or(0)
     }
 
+    /// Subscribes to the memory in use. The sampler is started by the first
+    /// subscriber and stops once the last one is gone.
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn subscribe_memory(self: &Arc<Self>) -> watch::Receiver<usize> {
+        let rx = self.memory_tx.subscribe();
+        if !self.memory_sampling.swap(true, Ordering::AcqRel) {
+            let this = self.clone();
+            tokio::spawn(async move { this.sample_memory().await });
+        }
+        rx
+    }
+
+    async fn sample_memory(&self) {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            if self.memory_tx.receiver_count() == 0 {
+                self.memory_sampling.store(false, Ordering::Release);
+                // keep going if someone subscribed in the meantime and didn't
+                // start another sampler
+                if self.memory_tx.receiver_count() == 0
+                    || self.memory_sampling.swap(true, Ordering::AcqRel)
+                {
+                    return;
+                }
+            }
+            self.memory_tx.send_replace(self.memory_usage());
+        }
+    }
+
     async fn kick_off(&self) {
         let mut ticker = tokio::time::interval(std::time::Duration::from_secs(1));
         loop {
             ticker.tick().await;
-            self.upload_blip
-                .store(self.upload_temp.load(Ordering::Relaxed), Ordering::Relaxed);
-            self.upload_temp.store(0, Ordering::Relaxed);
-            self.download_blip.store(
-                self.download_temp.load(Ordering::Relaxed),
-                Ordering::Relaxed,
-            );
-            self.download_temp.store(0, Ordering::Relaxed);
+            let up = self.upload_temp.swap(0, Ordering::Relaxed);
+            let down = self.download_temp.swap(0, Ordering::Relaxed);
+            self.upload_blip.store(up, Ordering::Relaxed);
+            self.download_blip.store(down, Ordering::Relaxed);
+            self.traffic_tx.send_replace((up, down));
+            self.idle_sweep().await;
+            self.update_totals().await;
+        }
+    }
+
+    /// adds what each open connection moved since the last tick to the totals,
+    /// and takes its speed
+    async fn update_totals(&self) {
+        let mut connections = self.connections.lock().await;
+        for (tracked, _, accounted) in connections.values_mut() {
+            let info = tracked.tracker_info();
+            accounted.update(&info);
+            info.sample_speed();
         }
     }
+
+    /// Closes the tcp connections nothing went through for `tcp-idle-timeout`,
+    /// checked on the same tick as the traffic instead of a timer for each
+    /// connection.
+    async fn idle_sweep(&self) {
+        let (timeout, exempt_ports) = {
+            let tcp_idle = self.tcp_idle.lock().unwrap();
+            match tcp_idle.timeout {
+                Some(timeout) => (timeout.as_secs(), tcp_idle.exempt_ports.clone()),
+                None => return,
+            }
+        };
+        let mut connections = self.connections.lock().await;
+        for (tracked, close_notify, _) in connections.values_mut() {
+            let t = tracked.tracker_info();
+            let sess = &t.session_holder;
+            if close_notify.is_none()
+                || sess.network != Network::Tcp
+                || exempt_ports.contains(&sess.destination.port())
+                || !t.idle_for(timeout)
+            {
+                continue;
+            }
+            debug!("closing idle connection {}", sess);
+            t.set_error("idle");
+            if let Some(close_notify) = close_notify.take() {
+                let _ = close_notify.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::{DialError, Manager, TrackerInfo};
+
+    #[test]
+    fn test_idle_for() {
+        let t = TrackerInfo::default();
+        assert!(!t.idle_for(3));
+        assert!(!t.idle_for(3));
+
+        // traffic starts the count over
+        t.upload_total.fetch_add(10, Ordering::Relaxed);
+        assert!(!t.idle_for(3));
+        assert!(!t.idle_for(3));
+        assert!(!t.idle_for(3));
+        assert!(t.idle_for(3));
+    }
+
+    #[test]
+    fn test_sample_speed() {
+        let t = TrackerInfo::default();
+        t.upload_total.fetch_add(100, Ordering::Relaxed);
+        t.download_total.fetch_add(1000, Ordering::Relaxed);
+        t.sample_speed();
+        t.download_total.fetch_add(500, Ordering::Relaxed);
+        t.sample_speed();
+        assert_eq!(t.upload_speed.load(Ordering::Relaxed), 0);
+        assert_eq!(t.download_speed.load(Ordering::Relaxed), 500);
+
+        let j = serde_json::to_value(&t).unwrap();
+        assert_eq!(j["uploadSpeed"], 0);
+        assert_eq!(j["downloadSpeed"], 500);
+        assert_eq!(j["download"], 1500);
+    }
+
+    #[test]
+    fn test_first_error_kept() {
+        let t = TrackerInfo::default();
+        t.set_error("idle");
+        t.set_error("broken pipe");
+        assert_eq!(t.error.lock().unwrap().as_deref(), Some("idle"));
+    }
+
+    #[test]
+    fn test_io_error_category() {
+        let t = TrackerInfo::default();
+        t.set_error_with_category(&std::io::Error::from(
+            std::io::ErrorKind::ConnectionReset,
+        ));
+        assert_eq!(*t.error_category.lock().unwrap(), Some(DialError::Connect));
+
+        // not when an error was set before
+        let t = TrackerInfo::default();
+        t.set_error("idle");
+        t.set_error_with_category(&std::io::Error::from(
+            std::io::ErrorKind::TimedOut,
+        ));
+        assert_eq!(t.error.lock().unwrap().as_deref(), Some("idle"));
+        assert_eq!(*t.error_category.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_traffic_subscribers_share_sampler() {
+        let mgr = Manager::new();
+        let mut a = mgr.subscribe_traffic();
+        let mut b = mgr.subscribe_traffic();
+
+        mgr.push_uploaded(100);
+        mgr.push_downloaded(200);
+
+        // the first tick fires immediately, the next one carries the bytes
+        loop {
+            a.changed().await.unwrap();
+            if *a.borrow_and_update() == (100, 200) {
+                break;
+            }
+        }
+        b.changed().await.unwrap();
+        assert_eq!(*b.borrow_and_update(), (100, 200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_sampler_runs_while_subscribed() {
+        let mgr = Manager::new();
+        assert!(!mgr.memory_sampling.load(Ordering::Acquire));
+
+        let mut rx = mgr.subscribe_memory();
+        rx.changed().await.unwrap();
+        assert!(mgr.memory_sampling.load(Ordering::Acquire));
+
+        drop(rx);
+        // noticed on the next tick
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(!mgr.memory_sampling.load(Ordering::Acquire));
+    }
 }
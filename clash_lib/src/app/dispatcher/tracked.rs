@@ -1,15 +1,18 @@
-use std::{fmt::Debug, pin::Pin, sync::Arc, task::Poll};
+use std::{any::Any, fmt::Debug, future::Future, pin::Pin, sync::Arc, task::Poll};
 
 use async_trait::async_trait;
 use futures::{Sink, Stream};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
     sync::oneshot::{error::TryRecvError, Receiver},
 };
 use tracing::debug;
 
 use crate::{
-    app::router::RuleMatcher, proxy::datagram::UdpPacket, session::Session,
+    app::router::RuleMatcher,
+    proxy::{datagram::UdpPacket, utils::mtu::TunnelMtu},
+    session::Session,
 };
 
 use super::statistics_manager::{Manager, ProxyChain, TrackerInfo};
@@ -32,6 +35,8 @@ pub trait ChainedStream:
 {
     fn chain(&self) -> &ProxyChain;
     async fn append_to_chain(&self, name: &str);
+    /// the socket itself if there is nothing on top of it, e.g. for direct
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream>;
 }
 
 pub type BoxedChainedStream = Box<dyn ChainedStream>;
@@ -54,7 +59,7 @@ impl<T> ChainedStreamWrapper<T> {
 #[async_trait]
 impl<T> ChainedStream for ChainedStreamWrapper<T>
 where
-    T: AsyncRead + AsyncWrite + Unpin + Debug + Send + Sync,
+    T: AsyncRead + AsyncWrite + Unpin + Debug + Send + Sync + 'static,
 {
     fn chain(&self) -> &ProxyChain {
         &self.chain
@@ -63,6 +68,10 @@ where
     async fn append_to_chain(&self, name: &str) {
         self.chain.push(name.to_owned()).await;
     }
+
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        (&mut self.inner as &mut dyn Any).downcast_mut()
+    }
 }
 
 impl<T> AsyncRead for ChainedStreamWrapper<T>
@@ -153,9 +162,54 @@ impl TrackedStream {
         self.tracker.uuid
     }
 
-    fn tracker_info(&self) -> Arc<TrackerInfo> {
+    pub fn tracker_info(&self) -> Arc<TrackerInfo> {
         self.tracker.clone()
     }
+
+    /// The socket under a plain tcp connection, for relaying on it directly.
+    /// The bytes moved that way have to be reported to the [`Counter`], and
+    /// the relay has to stop once `closed` resolves, which is when the
+    /// connection is closed from the api.
+    pub fn as_raw_tcp(&mut self) -> Option<RawTcp<'_>> {
+        let counter = Counter {
+            manager: self.manager.clone(),
+            tracker: self.tracker.clone(),
+        };
+        Some(RawTcp {
+            stream: self.inner.as_tcp_stream()?,
+            closed: &mut self.close_notify,
+            counter,
+        })
+    }
+}
+
+pub struct RawTcp<'a> {
+    pub stream: &'a mut TcpStream,
+    pub closed: &'a mut Receiver<()>,
+    pub counter: Counter,
+}
+
+/// the traffic accounting [`TrackedStream`] does as bytes go through it
+#[derive(Clone)]
+pub struct Counter {
+    manager: Arc<Manager>,
+    tracker: Arc<TrackerInfo>,
+}
+
+impl Counter {
+    pub fn uploaded(&self, n: usize) {
+        self.manager.push_uploaded(n);
+        self.tracker
+            .upload_total
+            .fetch_add(n as u64, std::sync::atomic::Ordering::Release);
+    }
+
+    pub fn downloaded(&self, n: usize) {
+        self.manager.push_downloaded(n);
+        self.tracker
+            .download_total
+            .fetch_add(n as u64, std::sync::atomic::Ordering::Release);
+    }
 }
 
 impl Drop for TrackedStream {
@@ -177,7 +231,16 @@ impl AsyncRead for TrackedStream {
                 return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
             }
             Err(e) => match e {
-                TryRecvError::Empty => {}
+                TryRecvError::Empty => {
+                    // wakes the relay up on close even if nothing is read,
+                    // e.g. when it's closed for being idle
+                    if Pin::new(&mut self.close_notify).poll(cx).is_ready() {
+                        debug!("connection closed by sig: {}", self.id());
+                        return Poll::Ready(Err(
+                            std::io::ErrorKind::BrokenPipe.into()
+                        ));
+                    }
+                }
                 TryRecvError::Closed => {
                     debug!("connection closed drop: {}", self.id());
                     return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
@@ -266,6 +329,12 @@ pub trait ChainedDatagram:
 {
     fn chain(&self) -> &ProxyChain;
     async fn append_to_chain(&self, name: &str);
+
+    /// the tunnel the packets go through, e.g. wireguard, whose packets
+    /// can't be fragmented. None if any payload can be sent.
+    fn tunnel_mtu(&self) -> Option<TunnelMtu> {
+        None
+    }
 }
 
 pub type BoxedChainedDatagram = Box<dyn ChainedDatagram + Send + Sync>;
@@ -283,12 +352,17 @@ where
     async fn append_to_chain(&self, name: &str) {
         self.chain.push(name.to_owned()).await;
     }
+
+    fn tunnel_mtu(&self) -> Option<TunnelMtu> {
+        self.tunnel_mtu
+    }
 }
 
 #[derive(Debug)]
 pub struct ChainedDatagramWrapper<T> {
     inner: T,
     chain: ProxyChain,
+    tunnel_mtu: Option<TunnelMtu>,
 }
 
 impl<T> ChainedDatagramWrapper<T> {
@@ -296,8 +370,15 @@ impl<T> ChainedDatagramWrapper<T> {
         Self {
             inner,
             chain: ProxyChain::default(),
+            tunnel_mtu: None,
         }
     }
+
+    #[cfg_attr(not(feature = "wireguard"), allow(dead_code))]
+    pub fn with_tunnel_mtu(mut self, mtu: TunnelMtu) -> Self {
+        self.tunnel_mtu = Some(mtu);
+        self
+    }
 }
 
 impl<T> Stream for ChainedDatagramWrapper<T>
@@ -396,6 +477,10 @@ impl TrackedDatagram {
     pub fn tracker_info(&self) -> Arc<TrackerInfo> {
         self.tracker.clone()
     }
+
+    pub fn tunnel_mtu(&self) -> Option<TunnelMtu> {
+        self.inner.tunnel_mtu()
+    }
 }
 
 impl Drop for TrackedDatagram {
@@ -0,0 +1,335 @@
+//! The traffic of each proxy and rule since start, as opposed to the live
+//! speed of the statistics manager.
+//!
+//! A connection looks up the counters of its chain and rule once, when it
+//! is tracked, and the bytes it moved are added to them on the statistics
+//! tick and when it ends, so the relay itself does no more than it already
+//! does. A group's totals include everything that went through it, as do
+//! the totals of each proxy in a relay. The totals of each source are kept
+//! the same way, see [`Sources`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::app::profile::ThreadSafeCacheFile;
+
+use super::{
+    sources::{self, Sources},
+    statistics_manager::TrackerInfo,
+};
+
+/// the cache section the totals are kept in with `profile.store-statistics`
+const SECTION: &str = "statistics";
+
+/// how often the totals are handed to the cache, which writes them out on
+/// its own flush
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Counters {
+    upload: AtomicU64,
+    download: AtomicU64,
+    connections: AtomicU64,
+}
+
+impl Counters {
+    fn totals(&self) -> Totals {
+        Totals {
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(&self, t: &Totals) {
+        self.upload.fetch_add(t.upload, Ordering::Relaxed);
+        self.download.fetch_add(t.download, Ordering::Relaxed);
+        self.connections.fetch_add(t.connections, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.upload.store(0, Ordering::Relaxed);
+        self.download.store(0, Ordering::Relaxed);
+        self.connections.store(0, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct Totals {
+    pub upload: u64,
+    pub download: u64,
+    pub connections: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Summary {
+    pub proxies: BTreeMap<String, Totals>,
+    /// keyed by `type(payload)`, e.g. `DomainSuffix(google.com)`
+    pub rules: BTreeMap<String, Totals>,
+}
+
+/// the names are interned, a connection shares them with the table
+type Table = RwLock<HashMap<Arc<str>, Arc<Counters>>>;
+
+fn counters(table: &Table, name: &str) -> Arc<Counters> {
+    if let Some(c) = table.read().unwrap().get(name) {
+        return c.clone();
+    }
+    table
+        .write()
+        .unwrap()
+        .entry(name.into())
+        .or_default()
+        .clone()
+}
+
+fn summarize(table: &Table) -> BTreeMap<String, Totals> {
+    table
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, c)| (name.to_string(), c.totals()))
+        .collect()
+}
+
+/// The counters a connection adds to, and how much of its bytes it already
+/// added.
+pub(crate) struct Accounted {
+    counters: Vec<Arc<Counters>>,
+    source: Arc<sources::Counters>,
+    upload: u64,
+    download: u64,
+}
+
+impl Accounted {
+    /// adds what moved since the last time
+    pub(crate) fn update(&mut self, info: &TrackerInfo) {
+        let upload = info.upload_total.load(Ordering::Relaxed);
+        let download = info.download_total.load(Ordering::Relaxed);
+        let delta = Totals {
+            upload: upload.saturating_sub(self.upload),
+            download: download.saturating_sub(self.download),
+            connections: 0,
+        };
+        if delta == Totals::default() {
+            return;
+        }
+        self.upload = upload;
+        self.download = download;
+        for c in &self.counters {
+            c.add(&delta);
+        }
+        self.source.add(delta.upload, delta.download);
+    }
+}
+
+impl Drop for Accounted {
+    fn drop(&mut self) {
+        self.source.close();
+    }
+}
+
+/// Shared by the statistics managers of a reload, so the totals count from
+/// the start of the process, or from further back when they are stored.
+#[derive(Default)]
+pub struct TrafficTotals {
+    proxies: Table,
+    rules: Table,
+    /// not stored, unlike the others
+    sources: Sources,
+    /// where the totals are saved to, with `profile.store-statistics`
+    cache: Mutex<Option<ThreadSafeCacheFile>>,
+    /// what was last saved, the cache isn't written again for nothing
+    saved: Mutex<Option<Summary>>,
+}
+
+impl TrafficTotals {
+    pub fn new() -> Arc<Self> {
+        let totals = Arc::new(Self::default());
+        let weak: Weak<Self> = Arc::downgrade(&totals);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SAVE_INTERVAL).await;
+                let Some(totals) = weak.upgrade() else {
+                    break;
+                };
+                totals.save().await;
+            }
+        });
+        totals
+    }
+
+    /// Counts a new connection from `source`, `rule` is the type and payload
+    /// of the rule it matched, if any.
+    pub(crate) fn open(
+        &self,
+        chain: &[String],
+        rule: Option<&str>,
+        source: IpAddr,
+    ) -> Accounted {
+        let mut all = chain
+            .iter()
+            .map(|name| counters(&self.proxies, name))
+            .collect::<Vec<_>>();
+        if let Some(rule) = rule {
+            all.push(counters(&self.rules, rule));
+        }
+        for c in &all {
+            c.connections.fetch_add(1, Ordering::Relaxed);
+        }
+        Accounted {
+            counters: all,
+            source: self.sources.open(source),
+            upload: 0,
+            download: 0,
+        }
+    }
+
+    pub fn proxy(&self, name: &str) -> Totals {
+        self.proxies
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|c| c.totals())
+            .unwrap_or_default()
+    }
+
+    pub fn sources(&self) -> &Sources {
+        &self.sources
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            proxies: summarize(&self.proxies),
+            rules: summarize(&self.rules),
+        }
+    }
+
+    /// Zeroes all the counters, the ones no open connection holds are
+    /// dropped.
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn reset(&self) {
+        for table in [&self.proxies, &self.rules] {
+            let mut table = table.write().unwrap();
+            table.retain(|_, c| {
+                c.reset();
+                Arc::strong_count(c) > 1
+            });
+        }
+    }
+
+    /// Adds the totals stored in `cache` to these, and saves them there from
+    /// now on. `None` stops saving them.
+    pub async fn store_in(&self, cache: Option<ThreadSafeCacheFile>, load: bool) {
+        if let (Some(cache), true) = (&cache, load) {
+            if let Some(stored) = cache.get::<Summary>(SECTION).await {
+                for (name, t) in stored.proxies {
+                    counters(&self.proxies, &name).add(&t);
+                }
+                for (name, t) in stored.rules {
+                    counters(&self.rules, &name).add(&t);
+                }
+            }
+        }
+        *self.cache.lock().unwrap() = cache;
+        *self.saved.lock().unwrap() = None;
+    }
+
+    /// hands the totals to the cache, it writes them out on its next flush
+    pub async fn save(&self) {
+        let cache = self.cache.lock().unwrap().clone();
+        let Some(cache) = cache else {
+            return;
+        };
+        let summary = self.summary();
+        if self.saved.lock().unwrap().as_ref() == Some(&summary) {
+            return;
+        }
+        cache.set(SECTION, &summary).await;
+        *self.saved.lock().unwrap() = Some(summary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use crate::app::{
+        dispatcher::statistics_manager::TrackerInfo, profile::ThreadSafeCacheFile,
+    };
+
+    use super::{Totals, TrafficTotals};
+
+    #[tokio::test]
+    async fn test_totals() {
+        let totals = TrafficTotals::new();
+        let chain = ["ss".to_owned(), "auto".to_owned()];
+        let info = TrackerInfo::default();
+
+        let source = "192.168.1.10".parse().unwrap();
+        let mut a = totals.open(&chain, Some("Match"), source);
+        info.upload_total.fetch_add(10, Ordering::Relaxed);
+        info.download_total.fetch_add(100, Ordering::Relaxed);
+        a.update(&info);
+        info.upload_total.fetch_add(5, Ordering::Relaxed);
+        a.update(&info);
+        a.update(&info);
+
+        let mut b = totals.open(&chain[..1], None, source);
+        b.update(&info);
+
+        let want = Totals {
+            upload: 15,
+            download: 100,
+            connections: 1,
+        };
+        assert_eq!(totals.proxy("auto"), want);
+        assert_eq!(totals.summary().rules.get("Match"), Some(&want));
+        assert_eq!(
+            totals.proxy("ss"),
+            Totals {
+                upload: 30,
+                download: 200,
+                connections: 2,
+            }
+        );
+
+        drop(a);
+        totals.reset();
+        assert_eq!(totals.proxy("ss"), Totals::default());
+        // still open on b
+        assert_eq!(totals.summary().proxies.len(), 1);
+        info.upload_total.fetch_add(1, Ordering::Relaxed);
+        b.update(&info);
+        assert_eq!(totals.proxy("ss").upload, 1);
+    }
+
+    #[tokio::test]
+    async fn test_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let path = path.to_str().unwrap();
+
+        let cache = ThreadSafeCacheFile::new(path, true);
+        let totals = TrafficTotals::new();
+        totals.store_in(Some(cache.clone()), true).await;
+        totals.open(&["ss".to_owned()], None, "127.0.0.1".parse().unwrap());
+        totals.save().await;
+        cache.flush().await;
+
+        let totals = TrafficTotals::new();
+        totals
+            .store_in(Some(ThreadSafeCacheFile::new(path, true)), true)
+            .await;
+        assert_eq!(totals.proxy("ss").connections, 1);
+    }
+}
@@ -0,0 +1,518 @@
+//! The UDP sessions of all the inbounds, one per (source, destination), each
+//! with the outbound datagram it goes through. A full-cone session is one
+//! per source, for any destination the rules send to the same outbound as
+//! the first one, the others get a session of their own.
+//!
+//! A session is closed once it has seen no traffic either way for the idle
+//! timeout, or, when the table is full, to make room for a new one, the
+//! least recently active first. Closing it removes it from the table and
+//! stops the tasks holding its outbound datagram under the same lock, so a
+//! packet coming in right after finds no session and starts a fresh one. A
+//! session whose outbound datagram failed, e.g. with the peer unreachable,
+//! is closed right away.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+use tracing::{debug, trace};
+
+use crate::{
+    config::{def::UdpNat, internal::config::UdpSessions},
+    proxy::datagram::UdpPacket,
+    session::SocksAddr,
+};
+
+use super::statistics_manager::Manager;
+
+pub type OutboundPacketSender = mpsc::Sender<UdpPacket>;
+
+/// what a packet finds in the table
+pub enum Lookup {
+    /// the session it goes through
+    Session(OutboundPacketSender),
+    /// only the full-cone session of its source, with the outbound it goes
+    /// to, which the packet can join if the rules send it there too
+    FullCone(String),
+    None,
+}
+
+/// sessions to DNS servers are one query and its answer
+const DNS_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DNS_PORT: u16 = 53;
+/// destinations a full-cone session remembers as routed to its outbound,
+/// forgotten all at once past that, to be routed again
+const MAX_CONE_PEERS: usize = 1024;
+
+/// the inbound association, the source and the destination, none for a
+/// full-cone session
+type Key = (u64, SocketAddr, Option<SocksAddr>);
+
+/// When a session last saw a packet, shared with the tasks moving its
+/// packets.
+#[derive(Clone)]
+pub struct Activity {
+    epoch: Instant,
+    /// millis since `epoch`
+    last: Arc<AtomicU64>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Activity {
+    fn new(epoch: Instant) -> Self {
+        let a = Self {
+            epoch,
+            last: Default::default(),
+            closed: Default::default(),
+        };
+        a.touch();
+        a
+    }
+
+    pub fn touch(&self) {
+        self.last
+            .store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> u64 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    fn idle(&self) -> Duration {
+        self.epoch.elapsed() - Duration::from_millis(self.last())
+    }
+
+    fn closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+struct Entry {
+    sender: OutboundPacketSender,
+    /// local -> remote and remote -> local, they own the outbound datagram
+    tasks: [JoinHandle<()>; 2],
+    activity: Activity,
+    idle_timeout: Duration,
+    /// the inbound association it belongs to
+    owner: u64,
+    /// the outbound the rules picked for it
+    outbound: String,
+    /// the destinations known to be routed to `outbound`, of a full-cone
+    /// session
+    peers: HashSet<SocksAddr>,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        for t in &self.tasks {
+            t.abort();
+        }
+    }
+}
+
+pub struct UdpSessionManager {
+    sessions: Mutex<HashMap<Key, Entry>>,
+    config: UdpSessions,
+    epoch: Instant,
+    next_owner: AtomicU64,
+    stats: Arc<Manager>,
+}
+
+impl UdpSessionManager {
+    pub fn new(config: UdpSessions, stats: Arc<Manager>) -> Arc<Self> {
+        let m = Arc::new(Self {
+            sessions: Default::default(),
+            config,
+            epoch: Instant::now(),
+            next_owner: AtomicU64::new(0),
+            stats,
+        });
+
+        let weak = Arc::downgrade(&m);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(DNS_IDLE_TIMEOUT.min(config.idle_timeout) / 2);
+            interval
+                .set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                match Weak::upgrade(&weak) {
+                    Some(m) => m.evict_idle(),
+                    None => break,
+                }
+            }
+        });
+
+        m
+    }
+
+    /// an id for an inbound association, to close its sessions when it ends
+    pub fn new_owner(&self) -> u64 {
+        self.next_owner.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The session of `owner` from `src` to `dst`, or the full-cone one of
+    /// `src` if `dst` was routed to it before, marked active.
+    pub fn get(&self, owner: u64, src: SocketAddr, dst: &SocksAddr) -> Lookup {
+        let sessions = self.sessions.lock().unwrap();
+        if let Some(e) = sessions.get(&(owner, src, Some(dst.clone()))) {
+            e.activity.touch();
+            return Lookup::Session(e.sender.clone());
+        }
+        match sessions.get(&(owner, src, None)) {
+            Some(e) if e.peers.contains(dst) => {
+                e.activity.touch();
+                Lookup::Session(e.sender.clone())
+            }
+            Some(e) => Lookup::FullCone(e.outbound.clone()),
+            None => Lookup::None,
+        }
+    }
+
+    /// Lets `dst`, which the rules send to the same outbound, through the
+    /// full-cone session of `src`, if it's still there.
+    pub fn join(
+        &self,
+        owner: u64,
+        src: SocketAddr,
+        dst: &SocksAddr,
+    ) -> Option<OutboundPacketSender> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let e = sessions.get_mut(&(owner, src, None))?;
+        if e.peers.len() >= MAX_CONE_PEERS {
+            e.peers.clear();
+        }
+        e.peers.insert(dst.clone());
+        e.activity.touch();
+        Some(e.sender.clone())
+    }
+
+    /// the NAT behavior of the proxies without one of their own
+    pub fn default_nat(&self) -> UdpNat {
+        self.config.nat
+    }
+
+    /// for the tasks of a session about to be inserted
+    pub fn new_activity(&self) -> Activity {
+        Activity::new(self.epoch)
+    }
+
+    /// Adds a session to `outbound`, closing the least recently active one if
+    /// the table is full. A session already there for the same pair, or for
+    /// the same source if full-cone, is replaced.
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert(
+        &self,
+        owner: u64,
+        src: SocketAddr,
+        dst: SocksAddr,
+        outbound: String,
+        nat: UdpNat,
+        sender: OutboundPacketSender,
+        tasks: [JoinHandle<()>; 2],
+        activity: Activity,
+    ) {
+        let (key, peers) = match nat {
+            UdpNat::Symmetric => ((owner, src, Some(dst)), HashSet::new()),
+            UdpNat::FullCone => ((owner, src, None), HashSet::from([dst])),
+        };
+        let idle_timeout = match &key.2 {
+            Some(dst) if dst.port() == DNS_PORT => {
+                DNS_IDLE_TIMEOUT.min(self.config.idle_timeout)
+            }
+            _ => self.config.idle_timeout,
+        };
+
+        let entry = Entry {
+            sender,
+            tasks,
+            activity,
+            idle_timeout,
+            owner,
+            outbound,
+            peers,
+        };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        // failed before it made it in
+        if entry.activity.closed() {
+            return;
+        }
+        let mut evicted = 0;
+        if self.config.max > 0
+            && sessions.len() >= self.config.max
+            && !sessions.contains_key(&key)
+        {
+            // a scan, but only once the table is full
+            let lru = sessions
+                .iter()
+                .min_by_key(|(_, e)| e.activity.last())
+                .map(|(k, _)| k.clone());
+            if let Some(k) = lru {
+                debug!("udp session table full, closing {:?}", k);
+                sessions.remove(&k);
+                evicted += 1;
+            }
+        }
+        sessions.insert(key, entry);
+        self.stats.set_udp_sessions(sessions.len(), evicted);
+    }
+
+    /// Closes the session of `activity` now, when its outbound datagram
+    /// failed, instead of leaving it until it idles out.
+    pub fn close(&self, activity: &Activity) {
+        let mut sessions = self.sessions.lock().unwrap();
+        activity.closed.store(true, Ordering::Relaxed);
+        sessions.retain(|k, e| {
+            let closing = Arc::ptr_eq(&e.activity.closed, &activity.closed);
+            if closing {
+                debug!("udp session {:?} failed, closing", k);
+            }
+            !closing
+        });
+        self.stats.set_udp_sessions(sessions.len(), 0);
+    }
+
+    /// Closes the sessions of an inbound association that ended.
+    pub fn remove_owner(&self, owner: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, e| e.owner != owner);
+        self.stats.set_udp_sessions(sessions.len(), 0);
+    }
+
+    fn evict_idle(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|k, e| {
+            let alive = e.activity.idle() < e.idle_timeout;
+            if !alive {
+                trace!("udp session {:?} idle, closing", k);
+            }
+            alive
+        });
+        let evicted = before - sessions.len();
+        if evicted > 0 {
+            debug!("closed {} idle udp sessions", evicted);
+        }
+        self.stats.set_udp_sessions(sessions.len(), evicted);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use crate::{
+        app::dispatcher::statistics_manager::Manager,
+        config::{def::UdpNat, internal::config::UdpSessions},
+        session::SocksAddr,
+    };
+
+    use super::{Lookup, UdpSessionManager};
+
+    fn insert(m: &UdpSessionManager, owner: u64, src: &str, dst: &str) {
+        insert_nat(m, owner, src, dst, UdpNat::Symmetric);
+    }
+
+    fn insert_nat(
+        m: &UdpSessionManager,
+        owner: u64,
+        src: &str,
+        dst: &str,
+        nat: UdpNat,
+    ) {
+        let (tx, _) = mpsc::channel(1);
+        let tasks = [tokio::spawn(async {}), tokio::spawn(async {})];
+        m.insert(
+            owner,
+            src.parse().unwrap(),
+            SocksAddr::Ip(dst.parse().unwrap()),
+            "DIRECT".to_owned(),
+            nat,
+            tx,
+            tasks,
+            m.new_activity(),
+        );
+    }
+
+    fn has(m: &UdpSessionManager, owner: u64, src: &str, dst: &str) -> bool {
+        matches!(
+            m.get(
+                owner,
+                src.parse().unwrap(),
+                &SocksAddr::Ip(dst.parse().unwrap()),
+            ),
+            Lookup::Session(_)
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_eviction() {
+        let m = UdpSessionManager::new(
+            UdpSessions {
+                idle_timeout: Duration::from_secs(60),
+                max: 0,
+                nat: UdpNat::Symmetric,
+            },
+            Manager::new(),
+        );
+        insert(&m, 0, "10.0.0.2:5000", "1.1.1.1:53");
+        insert(&m, 0, "10.0.0.2:5001", "1.1.1.1:443");
+
+        // the dns one is gone after 10s, the other one is kept alive
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        assert!(!has(&m, 0, "10.0.0.2:5000", "1.1.1.1:53"));
+        assert!(has(&m, 0, "10.0.0.2:5001", "1.1.1.1:443"));
+
+        tokio::time::sleep(Duration::from_secs(70)).await;
+        assert_eq!(m.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lru_eviction() {
+        let m = UdpSessionManager::new(
+            UdpSessions {
+                idle_timeout: Duration::from_secs(60),
+                max: 2,
+                nat: UdpNat::Symmetric,
+            },
+            Manager::new(),
+        );
+        insert(&m, 0, "10.0.0.2:5000", "1.1.1.1:443");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        insert(&m, 1, "10.0.0.2:5001", "1.1.1.1:443");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // the first one is now the most recently active
+        assert!(has(&m, 0, "10.0.0.2:5000", "1.1.1.1:443"));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        insert(&m, 1, "10.0.0.2:5002", "1.1.1.1:443");
+        assert_eq!(m.len(), 2);
+        assert!(!has(&m, 1, "10.0.0.2:5001", "1.1.1.1:443"));
+
+        m.remove_owner(1);
+        assert_eq!(m.len(), 1);
+        assert!(has(&m, 0, "10.0.0.2:5000", "1.1.1.1:443"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_owners() {
+        let m = UdpSessionManager::new(
+            UdpSessions {
+                idle_timeout: Duration::from_secs(60),
+                max: 0,
+                nat: UdpNat::Symmetric,
+            },
+            Manager::new(),
+        );
+        // e.g. two socks clients behind the same NAT, both given the same
+        // relay address
+        insert(&m, 0, "10.0.0.2:5000", "1.1.1.1:443");
+        insert(&m, 1, "10.0.0.2:5000", "1.1.1.1:443");
+        assert_eq!(m.len(), 2);
+
+        m.remove_owner(0);
+        assert!(!has(&m, 0, "10.0.0.2:5000", "1.1.1.1:443"));
+        assert!(has(&m, 1, "10.0.0.2:5000", "1.1.1.1:443"));
+        assert!(!has(&m, 2, "10.0.0.2:5000", "1.1.1.1:443"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_cone() {
+        let m = UdpSessionManager::new(
+            UdpSessions {
+                idle_timeout: Duration::from_secs(60),
+                max: 0,
+                nat: UdpNat::Symmetric,
+            },
+            Manager::new(),
+        );
+        insert_nat(&m, 0, "10.0.0.2:5000", "1.1.1.1:53", UdpNat::FullCone);
+        insert(&m, 0, "10.0.0.3:5000", "1.1.1.1:443");
+
+        // another destination of the client goes through its one session
+        // once routed to the same outbound
+        let src = "10.0.0.2:5000".parse().unwrap();
+        let stun = SocksAddr::Ip("8.8.8.8:3478".parse().unwrap());
+        assert!(matches!(
+            m.get(0, src, &stun),
+            Lookup::FullCone(outbound) if outbound == "DIRECT"
+        ));
+        assert!(!has(&m, 0, "10.0.0.2:5000", "8.8.8.8:3478"));
+        assert!(m.join(0, src, &stun).is_some());
+        assert!(has(&m, 0, "10.0.0.2:5000", "8.8.8.8:3478"));
+        assert!(m.join(1, src, &stun).is_none());
+        assert!(!has(&m, 0, "10.0.0.2:5001", "1.1.1.1:53"));
+        assert!(!has(&m, 0, "10.0.0.3:5000", "8.8.8.8:3478"));
+
+        // which isn't closed early for being to a dns server
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        assert!(has(&m, 0, "10.0.0.2:5000", "1.1.1.1:53"));
+
+        tokio::time::sleep(Duration::from_secs(70)).await;
+        assert_eq!(m.len(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close() {
+        let m = UdpSessionManager::new(
+            UdpSessions {
+                idle_timeout: Duration::from_secs(60),
+                max: 0,
+                nat: UdpNat::Symmetric,
+            },
+            Manager::new(),
+        );
+        insert(&m, 0, "10.0.0.2:5000", "1.1.1.1:443");
+
+        let (tx, _) = mpsc::channel(1);
+        let activity = m.new_activity();
+        let task = tokio::spawn(std::future::pending::<()>());
+        let abort = task.abort_handle();
+        m.insert(
+            0,
+            "10.0.0.2:5001".parse().unwrap(),
+            SocksAddr::Ip("1.1.1.1:443".parse().unwrap()),
+            "DIRECT".to_owned(),
+            UdpNat::Symmetric,
+            tx,
+            [task, tokio::spawn(async {})],
+            activity.clone(),
+        );
+        assert_eq!(m.len(), 2);
+
+        m.close(&activity);
+        assert_eq!(m.len(), 1);
+        assert!(!has(&m, 0, "10.0.0.2:5001", "1.1.1.1:443"));
+        tokio::task::yield_now().await;
+        assert!(abort.is_finished());
+
+        // one that failed before it was inserted isn't
+        let (tx, _) = mpsc::channel(1);
+        m.insert(
+            0,
+            "10.0.0.2:5002".parse().unwrap(),
+            SocksAddr::Ip("1.1.1.1:443".parse().unwrap()),
+            "DIRECT".to_owned(),
+            UdpNat::Symmetric,
+            tx,
+            [tokio::spawn(async {}), tokio::spawn(async {})],
+            activity,
+        );
+        assert_eq!(m.len(), 1);
+    }
+}
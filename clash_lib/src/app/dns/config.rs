@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     fmt::Display,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    path::PathBuf,
 };
 
 use ipnet::AddrParseError;
@@ -18,23 +18,59 @@ use crate::{
     Error,
 };
 
-use super::dns_client::DNSNetMode;
+use super::{dns_client::DNSNetMode, hosts::system_hosts_file};
 
 #[derive(Clone, Debug)]
 pub struct NameServer {
     pub net: DNSNetMode,
     pub address: String,
     pub interface: Option<String>,
+    /// `?sni=` of a DoT or DoH server, the name its certificate is checked
+    /// against while the address is dialed
+    pub sni: Option<String>,
 }
 impl Display for NameServer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}://{}#{}",
-            self.net,
-            self.address,
-            self.interface.as_ref().unwrap_or(&"".to_owned())
-        )
+        write!(f, "{}://{}", self.net, self.address)?;
+        if let Some(sni) = &self.sni {
+            write!(f, "?sni={}", sni)?;
+        }
+        write!(f, "#{}", self.interface.as_ref().unwrap_or(&"".to_owned()))
+    }
+}
+
+/// what a key of `nameserver-policy` matches
+#[derive(Debug, PartialEq)]
+pub enum PolicyKey<'a> {
+    Domain(&'a str),
+    /// the code of a geosite category, with its attributes if any
+    GeoSite(&'a str),
+    /// the name of a rule provider
+    RuleSet(&'a str),
+}
+
+impl<'a> PolicyKey<'a> {
+    pub fn parse(key: &'a str) -> Self {
+        match key.split_once(':') {
+            Some((kind, rest)) if kind.eq_ignore_ascii_case("geosite") => {
+                PolicyKey::GeoSite(rest.trim())
+            }
+            Some((kind, rest)) if kind.eq_ignore_ascii_case("rule-set") => {
+                PolicyKey::RuleSet(rest.trim())
+            }
+            _ => PolicyKey::Domain(key),
+        }
+    }
+
+    /// the keys of mihomo's comma-joined ones, e.g. `geosite:cn,private` or
+    /// `a.com,+.b.com`, the kind of the first goes for all of them
+    pub fn parse_all(key: &'a str) -> Vec<Self> {
+        let split = |x: &'a str| x.split(',').map(str::trim);
+        match Self::parse(key) {
+            PolicyKey::Domain(x) => split(x).map(PolicyKey::Domain).collect(),
+            PolicyKey::GeoSite(x) => split(x).map(PolicyKey::GeoSite).collect(),
+            PolicyKey::RuleSet(x) => split(x).map(PolicyKey::RuleSet).collect(),
+        }
     }
 }
 
@@ -59,8 +95,13 @@ pub struct Config {
     pub fake_ip_range: ipnet::IpNet,
     pub fake_ip_filter: Vec<String>,
     pub store_fake_ip: bool,
-    pub hosts: Option<trie::StringTrie<IpAddr>>,
+    /// the inline `hosts`, empty without `user-hosts`
+    pub hosts: HashMap<String, IpAddr>,
+    /// what the rest of the hosts table is read from
+    pub hosts_files: Vec<PathBuf>,
     pub nameserver_policy: HashMap<String, NameServer>,
+    pub filter_unsafe_answers: bool,
+    pub rebind_allow_domains: Vec<String>,
 }
 
 impl Config {
@@ -116,11 +157,23 @@ impl Config {
                 }
             }
 
+            let sni = url
+                .query_pairs()
+                .find(|(k, _)| k == "sni" || k == "hostname")
+                .map(|(_, v)| v.into_owned());
+            if sni.is_some() && !matches!(url.scheme(), "tls" | "https") {
+                return Err(Error::InvalidConfig(format!(
+                    "DNS nameserver [{}] {}: sni is only for tls and https",
+                    i, server
+                )));
+            }
+
             let net = net.parse()?;
             nameservers.push(NameServer {
                 address: addr,
                 net,
                 interface: iface.map(String::from),
+                sni,
             });
         }
 
@@ -135,7 +188,10 @@ impl Config {
         for (domain, server) in policy_map {
             let nameservers = Config::parse_nameserver(&[server.to_owned()])?;
 
-            let (_, valid) = trie::valid_and_split_domain(domain);
+            let valid = PolicyKey::parse_all(domain).iter().all(|x| match x {
+                PolicyKey::Domain(d) => trie::valid_and_split_domain(d).1,
+                PolicyKey::GeoSite(x) | PolicyKey::RuleSet(x) => !x.is_empty(),
+            });
             if !valid {
                 return Err(Error::InvalidConfig(format!(
                     "DNS ResolverRule invalid domain: {}",
@@ -164,19 +220,20 @@ impl Config {
 
     pub fn parse_hosts(
         hosts_mapping: &HashMap<String, String>,
-    ) -> anyhow::Result<trie::StringTrie<IpAddr>> {
-        let mut tree = trie::StringTrie::new();
-        tree.insert(
-            "localhost",
-            Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
-        );
-
-        for (host, ip_str) in hosts_mapping.iter() {
-            let ip = ip_str.parse::<IpAddr>()?;
-            tree.insert(host.as_str(), Arc::new(ip));
-        }
-
-        Ok(tree)
+    ) -> Result<HashMap<String, IpAddr>, Error> {
+        hosts_mapping
+            .iter()
+            .map(|(host, ip)| {
+                ip.parse::<IpAddr>()
+                    .map(|ip| (host.clone(), ip))
+                    .map_err(|_| {
+                        Error::InvalidConfig(format!(
+                            "invalid address of host {}: {}",
+                            host, ip
+                        ))
+                    })
+            })
+            .collect()
     }
 
     pub fn host_with_default_port(host: &str, port: &str) -> Result<String, Error> {
@@ -354,21 +411,49 @@ impl TryFrom<&crate::config::def::Config> for Config {
             )?,
             fake_ip_filter: dc.fake_ip_filter.clone(),
             store_fake_ip: c.profile.store_fake_ip,
-            hosts: if dc.user_hosts && !c.hosts.is_empty() {
-                Config::parse_hosts(&c.hosts).ok()
+            hosts: if dc.user_hosts {
+                Config::parse_hosts(&c.hosts)?
             } else {
-                let mut tree = trie::StringTrie::new();
-                tree.insert(
-                    "localhost",
-                    Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
-                );
-                Some(tree)
+                HashMap::new()
+            },
+            hosts_files: if dc.user_hosts {
+                // the first file with a name wins
+                dc.hosts_file
+                    .iter()
+                    .map(PathBuf::from)
+                    .chain(dc.use_system_hosts.then(system_hosts_file))
+                    .collect()
+            } else {
+                vec![]
             },
             nameserver_policy,
+            filter_unsafe_answers: dc
+                .filter_unsafe_answers
+                .unwrap_or_else(|| dc.listen.as_ref().is_some_and(listens_on_lan)),
+            rebind_allow_domains: dc.rebind_allow_domains.clone(),
         })
     }
 }
 
+/// whether any of the listen addresses is reachable from elsewhere, an
+/// address that doesn't parse is rejected with the rest of the listen config
+fn listens_on_lan(listen: &DNSListen) -> bool {
+    let addrs = match listen {
+        DNSListen::Udp(u) => vec![u.as_str()],
+        DNSListen::Multiple(map) => map
+            .values()
+            .filter_map(|v| {
+                v.as_str()
+                    .or_else(|| v.get("addr").and_then(|x| x.as_str()))
+            })
+            .collect(),
+    };
+    addrs
+        .into_iter()
+        .filter_map(|x| x.parse::<SocketAddr>().ok())
+        .any(|x| !x.ip().is_loopback())
+}
+
 impl From<crate::config::def::FallbackFilter> for FallbackFilter {
     fn from(c: crate::config::def::FallbackFilter) -> Self {
         let ipcidr = Config::parse_fallback_ip_cidr(&c.ip_cidr);
@@ -380,3 +465,69 @@ impl From<crate::config::def::FallbackFilter> for FallbackFilter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Config, PolicyKey};
+
+    #[test]
+    fn test_parse_nameserver_sni() {
+        let servers = Config::parse_nameserver(&[
+            "https://1.1.1.1/dns-query?sni=cloudflare-dns.com".to_owned(),
+            "tls://8.8.8.8?hostname=dns.google#eth0".to_owned(),
+            "https://dns.quad9.net/dns-query".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(servers[0].address, "1.1.1.1:443");
+        assert_eq!(servers[0].sni.as_deref(), Some("cloudflare-dns.com"));
+        assert_eq!(
+            servers[0].to_string(),
+            "DoH://1.1.1.1:443?sni=cloudflare-dns.com#"
+        );
+        assert_eq!(servers[1].sni.as_deref(), Some("dns.google"));
+        assert_eq!(servers[1].interface.as_deref(), Some("eth0"));
+        assert_eq!(servers[2].sni, None);
+
+        assert!(
+            Config::parse_nameserver(&["udp://1.1.1.1?sni=x".to_owned()]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_nameserver_policy() {
+        assert_eq!(
+            PolicyKey::parse("+.example.com"),
+            PolicyKey::Domain("+.example.com")
+        );
+        assert_eq!(PolicyKey::parse("geosite:cn"), PolicyKey::GeoSite("cn"));
+        assert_eq!(
+            PolicyKey::parse("GEOSITE:microsoft@cn"),
+            PolicyKey::GeoSite("microsoft@cn")
+        );
+        assert_eq!(
+            PolicyKey::parse("rule-set:my-domains"),
+            PolicyKey::RuleSet("my-domains")
+        );
+
+        assert_eq!(
+            PolicyKey::parse_all("geosite:cn, private"),
+            vec![PolicyKey::GeoSite("cn"), PolicyKey::GeoSite("private")]
+        );
+        assert_eq!(
+            PolicyKey::parse_all("a.com,+.b.com"),
+            vec![PolicyKey::Domain("a.com"), PolicyKey::Domain("+.b.com")]
+        );
+
+        let policy = HashMap::from([
+            ("geosite:cn,private".to_owned(), "223.5.5.5".to_owned()),
+            ("rule-set:my-domains".to_owned(), "10.0.0.1".to_owned()),
+        ]);
+        assert_eq!(Config::parse_nameserver_policy(&policy).unwrap().len(), 2);
+        for bad in ["geosite:", "geosite:cn,", "a.com,,b.com"] {
+            let policy = HashMap::from([(bad.to_owned(), "223.5.5.5".to_owned())]);
+            assert!(Config::parse_nameserver_policy(&policy).is_err(), "{}", bad);
+        }
+    }
+}
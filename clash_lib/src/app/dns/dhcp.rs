@@ -1,4 +1,5 @@
 use crate::{
+    app::net_monitor::NetworkEpoch,
     dns::{
         dns_client::DNSNetMode, helper::make_clients, Client, EnhancedResolver,
         ThreadSafeDNSClient,
@@ -38,6 +39,8 @@ struct Inner {
 
 pub struct DhcpClient {
     iface: String,
+    /// a new network may hand out other servers, even on the same interface
+    epoch: NetworkEpoch,
 
     inner: Mutex<Inner>,
 }
@@ -75,6 +78,7 @@ impl DhcpClient {
     pub async fn new(iface: &str) -> Self {
         Self {
             iface: iface.to_owned(),
+            epoch: Default::default(),
             inner: Mutex::new(Inner {
                 clients: vec![],
                 iface_expires_at: Instant::now(),
@@ -96,6 +100,7 @@ impl DhcpClient {
                         net: DNSNetMode::Udp,
                         address: format!("{}:53", s),
                         interface: None,
+                        sni: None,
                     })
                     .collect(),
                 None,
@@ -114,6 +119,11 @@ impl DhcpClient {
             return Ok(true);
         }
 
+        if self.epoch.changed() {
+            inner.iface_expires_at = Instant::now();
+            inner.dns_expires_at = Instant::now();
+        }
+
         if Instant::now() < inner.iface_expires_at {
             return Ok(false);
         }
@@ -1,7 +1,6 @@
 use std::{
     fmt::{Debug, Display, Formatter},
     net,
-    net::SocketAddr,
     str::FromStr,
     sync::Arc,
     time::Duration,
@@ -12,19 +11,21 @@ use async_trait::async_trait;
 use futures::{future::BoxFuture, TryFutureExt};
 use hickory_client::{
     client, client::AsyncClient, proto::iocompat::AsyncIoTokioAsStd,
-    tcp::TcpClientStream, udp::UdpClientStream,
+    tcp::TcpClientStream,
 };
 use hickory_proto::{
     error::ProtoError, rustls::tls_client_stream::tls_client_connect_with_future,
 };
+use rand::Rng;
 use rustls::ClientConfig;
-use tokio::{sync::RwLock, task::JoinHandle};
-use tracing::{info, warn};
+use tokio::{sync::RwLock, task::JoinHandle, time::Instant};
+use tracing::{info, instrument, warn};
 
 use crate::{
+    app::net_monitor::{default_interface, NetworkEpoch},
     common::tls::{self, GLOBAL_ROOT_STORE},
     dns::{dhcp::DhcpClient, ThreadSafeDNSClient},
-    proxy::utils::{new_tcp_stream, new_udp_socket},
+    proxy::utils::new_tcp_stream,
 };
 use hickory_proto::{
     h2::HttpsClientStreamBuilder,
@@ -32,11 +33,11 @@ use hickory_proto::{
     xfer::{DnsRequest, DnsRequestOptions, FirstAnswer},
     DnsHandle,
 };
-use tokio::net::{TcpStream as TokioTcpStream, UdpSocket as TokioUdpSocket};
+use tokio::net::TcpStream as TokioTcpStream;
 
 use crate::{proxy::utils::Interface, Error};
 
-use super::{ClashResolver, Client};
+use super::{udp_upstream::UdpUpstream, ClashResolver, Client};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum DNSNetMode {
@@ -81,8 +82,15 @@ pub struct Opts {
     pub port: u16,
     pub net: DNSNetMode,
     pub iface: Option<Interface>,
+    /// `interface: auto`, the default interface is used, and followed when
+    /// it changes
+    pub auto_iface: bool,
+    /// the name the certificate of DoT and DoH is checked against, instead
+    /// of `host`, which is then only dialed
+    pub sni: Option<String>,
 }
 
+#[derive(Clone)]
 enum DnsConfig {
     Udp(net::SocketAddr, Option<Interface>),
     Tcp(net::SocketAddr, Option<Interface>),
@@ -128,11 +136,43 @@ impl Display for DnsConfig {
 struct Inner {
     c: Option<client::AsyncClient>,
     bg_handle: Option<JoinHandle<Result<(), ProtoError>>>,
+    backoff: Backoff,
+}
+
+/// Spaces out the connects to an upstream that keeps failing, e.g. a DoT
+/// server that is down, whose background task ends right after it starts.
+/// A connect only counts as working once an exchange went through on it.
+#[derive(Default)]
+struct Backoff {
+    /// how long the connect after the next one has to wait
+    next: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    const MAX: Duration = Duration::from_secs(30);
+    const MIN: Duration = Duration::from_millis(100);
+
+    fn ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|x| now >= x)
+    }
+
+    fn connecting(&mut self, now: Instant) {
+        let jitter = rand::thread_rng().gen_range(0.8..1.2);
+        self.retry_at = Some(now + self.next.mul_f64(jitter));
+        self.next = (self.next * 2).clamp(Self::MIN, Self::MAX);
+    }
+
+    fn is_reset(&self) -> bool {
+        self.retry_at.is_none()
+    }
 }
 
 /// DnsClient
 pub struct DnsClient {
     inner: Arc<RwLock<Inner>>,
+    /// the connection is made again once the network changed
+    epoch: NetworkEpoch,
 
     cfg: DnsConfig,
 
@@ -141,6 +181,11 @@ pub struct DnsClient {
     port: u16,
     net: DNSNetMode,
     iface: Option<Interface>,
+    auto_iface: bool,
+    sni: Option<String>,
+    /// the socket shared by the queries of a UDP upstream, which doesn't use
+    /// the hickory client
+    udp: Option<UdpUpstream>,
 }
 
 impl DnsClient {
@@ -184,7 +229,9 @@ impl DnsClient {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                backoff: Default::default(),
                             })),
+                            epoch: Default::default(),
 
                             cfg,
 
@@ -192,6 +239,11 @@ impl DnsClient {
                             port: opts.port,
                             net: opts.net,
                             iface: opts.iface,
+                            auto_iface: opts.auto_iface,
+                            sni: opts.sni,
+                            udp: Some(UdpUpstream::new(net::SocketAddr::new(
+                                ip, opts.port,
+                            ))),
                         }))
                     }
                     DNSNetMode::Tcp => {
@@ -204,7 +256,9 @@ impl DnsClient {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                backoff: Default::default(),
                             })),
+                            epoch: Default::default(),
 
                             cfg,
 
@@ -212,12 +266,15 @@ impl DnsClient {
                             port: opts.port,
                             net: opts.net,
                             iface: opts.iface,
+                            auto_iface: opts.auto_iface,
+                            sni: opts.sni,
+                            udp: None,
                         }))
                     }
                     DNSNetMode::DoT => {
                         let cfg = DnsConfig::Tls(
                             net::SocketAddr::new(ip, opts.port),
-                            opts.host.clone(),
+                            opts.sni.clone().unwrap_or(opts.host.clone()),
                             opts.iface.clone(),
                         );
 
@@ -225,7 +282,9 @@ impl DnsClient {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                backoff: Default::default(),
                             })),
+                            epoch: Default::default(),
 
                             cfg,
 
@@ -233,12 +292,15 @@ impl DnsClient {
                             port: opts.port,
                             net: opts.net,
                             iface: opts.iface,
+                            auto_iface: opts.auto_iface,
+                            sni: opts.sni,
+                            udp: None,
                         }))
                     }
                     DNSNetMode::DoH => {
                         let cfg = DnsConfig::Https(
                             net::SocketAddr::new(ip, opts.port),
-                            opts.host.clone(),
+                            opts.sni.clone().unwrap_or(opts.host.clone()),
                             opts.iface.clone(),
                         );
 
@@ -246,13 +308,18 @@ impl DnsClient {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                backoff: Default::default(),
                             })),
+                            epoch: Default::default(),
 
                             cfg,
                             host: opts.host,
                             port: opts.port,
                             net: opts.net,
                             iface: opts.iface,
+                            auto_iface: opts.auto_iface,
+                            sni: opts.sni,
+                            udp: None,
                         }))
                     }
                     _ => unreachable!("."),
@@ -269,6 +336,7 @@ impl Debug for DnsClient {
             .field("port", &self.port)
             .field("net", &self.net)
             .field("iface", &self.iface)
+            .field("sni", &self.sni)
             .finish()
     }
 }
@@ -276,78 +344,122 @@ impl Debug for DnsClient {
 #[async_trait]
 impl Client for DnsClient {
     fn id(&self) -> String {
-        format!("{}#{}:{}", &self.net, &self.host, &self.port)
+        match &self.sni {
+            Some(sni) => {
+                format!("{}#{}:{}?sni={}", &self.net, &self.host, &self.port, sni)
+            }
+            None => format!("{}#{}:{}", &self.net, &self.host, &self.port),
+        }
     }
 
+    #[instrument(skip_all, fields(upstream = %self.id()))]
     async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
-        let mut inner = self.inner.write().await;
-
-        if let Some(bg) = &inner.bg_handle {
-            if bg.is_finished() {
-                warn!(
-                    "dns client background task is finished, likely connection \
-                     closed, restarting a new one"
-                );
-                let (client, bg) = dns_stream_builder(&self.cfg).await?;
-                inner.c.replace(client);
-                inner.bg_handle.replace(bg);
+        if let Some(udp) = &self.udp {
+            if self.epoch.changed() {
+                udp.reset().await;
             }
-        } else {
-            // initializing client
-            info!("initializing dns client: {}", &self.cfg);
-            let (client, bg) = dns_stream_builder(&self.cfg).await?;
-            inner.c.replace(client);
-            inner.bg_handle.replace(bg);
+            let DnsConfig::Udp(_, iface) = self.dial_cfg() else {
+                unreachable!("udp upstream with a {} config", self.cfg)
+            };
+            return udp.exchange(msg, iface).await;
         }
 
+        let client = self.client().await?;
+
         let mut req = DnsRequest::new(msg.clone(), DnsRequestOptions::default());
         if req.id() == 0 {
             req.set_id(rand::random::<u16>());
         }
-        inner
-            .c
-            .as_ref()
-            .unwrap()
-            .send(req)
-            .first_answer()
-            .await
-            .map_err(|x| Error::DNSError(x.to_string()).into())
+        let rv = client.send(req).first_answer().await;
+
+        if rv.is_ok() && !self.inner.read().await.backoff.is_reset() {
+            self.inner.write().await.backoff = Default::default();
+        }
+        rv.map_err(|x| Error::DNSError(x.to_string()).into())
             .map(|x| x.into())
     }
 }
 
+impl DnsClient {
+    /// The client is a handle on the background task, so the queries only
+    /// share the lock for as long as it takes to clone it. The write lock is
+    /// only taken to connect, the first time or once the task is done.
+    ///
+    /// While backing off it fails right away, for the resolver to go on with
+    /// the other upstreams.
+    async fn client(&self) -> anyhow::Result<AsyncClient> {
+        if self.epoch.changed() {
+            let mut inner = self.inner.write().await;
+            if let Some(bg) = inner.bg_handle.take() {
+                bg.abort();
+            }
+            inner.c = None;
+            inner.backoff = Default::default();
+        }
+
+        {
+            let inner = self.inner.read().await;
+            if let (Some(c), Some(bg)) = (&inner.c, &inner.bg_handle) {
+                if !bg.is_finished() {
+                    return Ok(c.clone());
+                }
+            }
+            if !inner.backoff.ready(Instant::now()) {
+                return Err(self.backing_off());
+            }
+        }
+
+        let mut inner = self.inner.write().await;
+        match &inner.bg_handle {
+            // reconnected by someone else while waiting for the lock
+            Some(bg) if !bg.is_finished() => {
+                return Ok(inner.c.clone().expect("client set with its task"));
+            }
+            _ if !inner.backoff.ready(Instant::now()) => {
+                return Err(self.backing_off());
+            }
+            Some(_) => warn!(
+                "dns client background task is finished, likely connection closed, \
+                 restarting a new one"
+            ),
+            None => info!("initializing dns client: {}", &self.cfg),
+        }
+        inner.backoff.connecting(Instant::now());
+        let (client, bg) = dns_stream_builder(&self.dial_cfg()).await?;
+        inner.c.replace(client.clone());
+        inner.bg_handle.replace(bg);
+        Ok(client)
+    }
+
+    /// with `interface: auto`, bound to the interface that is the default now
+    fn dial_cfg(&self) -> DnsConfig {
+        let mut cfg = self.cfg.clone();
+        if self.auto_iface {
+            let iface = default_interface().map(|x| Interface::Name(x.name));
+            match &mut cfg {
+                DnsConfig::Udp(_, i)
+                | DnsConfig::Tcp(_, i)
+                | DnsConfig::Tls(_, _, i)
+                | DnsConfig::Https(_, _, i) => *i = iface,
+            }
+        }
+        cfg
+    }
+
+    fn backing_off(&self) -> anyhow::Error {
+        Error::DNSError(format!(
+            "{} failed to connect recently, not retrying yet",
+            self.id()
+        ))
+        .into()
+    }
+}
+
 async fn dns_stream_builder(
     cfg: &DnsConfig,
 ) -> Result<(AsyncClient, JoinHandle<Result<(), ProtoError>>), Error> {
     match cfg {
-        DnsConfig::Udp(addr, iface) => {
-            let iface = iface.clone();
-
-            let closure = move |_: SocketAddr,
-                                _: SocketAddr|
-                  -> BoxFuture<
-                'static,
-                std::io::Result<tokio::net::UdpSocket>,
-            > {
-                Box::pin(new_udp_socket(
-                    None,
-                    iface.clone(),
-                    #[cfg(any(target_os = "linux", target_os = "android"))]
-                    None,
-                ))
-            };
-            let stream = UdpClientStream::<TokioUdpSocket>::with_creator(
-                net::SocketAddr::new(addr.ip(), addr.port()),
-                None,
-                Duration::from_secs(5),
-                Arc::new(closure),
-            );
-
-            client::AsyncClient::connect(stream)
-                .await
-                .map(|(x, y)| (x, tokio::spawn(y)))
-                .map_err(|x| Error::DNSError(x.to_string()))
-        }
+        DnsConfig::Udp(..) => unreachable!("udp upstreams go through UdpUpstream"),
         DnsConfig::Tcp(addr, iface) => {
             let fut = new_tcp_stream(
                 *addr,
@@ -412,6 +524,7 @@ async fn dns_stream_builder(
                 .with_no_client_auth();
             tls_config.alpn_protocols = vec!["h2".into()];
 
+            // dialed by IP with no `sni` to check the certificate against
             if host == &addr.ip().to_string() {
                 tls_config.dangerous().set_certificate_verifier(Arc::new(
                     tls::NoHostnameTlsVerifier::new(),
@@ -440,3 +553,195 @@ async fn dns_stream_builder(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use hickory_proto::{
+        op::{Message, Query},
+        rr,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::{mpsc, RwLock},
+    };
+
+    use super::{Backoff, DNSNetMode, DnsClient, DnsConfig, Inner};
+    use crate::app::dns::Client;
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    /// A TCP DNS server answering every query after [`DELAY`], counting the
+    /// connections it gets and the most queries it had pending at once.
+    async fn mock_server() -> (SocketAddr, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let peak_counter = peak.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = l.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let (mut r, mut w) = stream.into_split();
+                let (tx, mut rx) = mpsc::channel::<Vec<u8>>(256);
+                let pending = pending.clone();
+                let peak = peak_counter.clone();
+                tokio::spawn(async move {
+                    while let Some(answer) = rx.recv().await {
+                        w.write_u16(answer.len() as u16).await.unwrap();
+                        w.write_all(&answer).await.unwrap();
+                    }
+                });
+                tokio::spawn(async move {
+                    while let Ok(len) = r.read_u16().await {
+                        let mut buf = vec![0; len as usize];
+                        r.read_exact(&mut buf).await.unwrap();
+                        let tx = tx.clone();
+                        let n = pending.fetch_add(1, Ordering::Relaxed) + 1;
+                        peak.fetch_max(n, Ordering::Relaxed);
+                        let pending = pending.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(DELAY).await;
+                            pending.fetch_sub(1, Ordering::Relaxed);
+                            let query = Message::from_vec(&buf).unwrap();
+                            let q = query.query().unwrap().clone();
+                            let mut answer = Message::new();
+                            answer.set_id(query.id());
+                            answer.add_answer(rr::Record::from_rdata(
+                                q.name().clone(),
+                                60,
+                                rr::RData::A(rr::rdata::A::new(1, 2, 3, 4)),
+                            ));
+                            answer.add_query(q);
+                            let _ = tx.send(answer.to_vec().unwrap()).await;
+                        });
+                    }
+                });
+            }
+        });
+        (addr, connections, peak)
+    }
+
+    async fn exchange_all(c: &Arc<DnsClient>, n: u16) {
+        let queries = (1..=n).map(|id| {
+            let c = c.clone();
+            async move {
+                let mut m = Message::new();
+                m.set_id(id);
+                m.add_query(Query::query(
+                    rr::Name::from_utf8("example.com.").unwrap(),
+                    rr::RecordType::A,
+                ));
+                c.exchange(&m).await.unwrap()
+            }
+        });
+        for answer in futures::future::join_all(queries).await {
+            assert_eq!(answer.answer_count(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parallel_exchanges() {
+        let (addr, connections, peak) = mock_server().await;
+        let c = Arc::new(DnsClient {
+            inner: Arc::new(RwLock::new(Inner {
+                c: None,
+                bg_handle: None,
+                backoff: Default::default(),
+            })),
+            cfg: DnsConfig::Tcp(addr, None),
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            net: DNSNetMode::Tcp,
+            iface: None,
+            epoch: Default::default(),
+            auto_iface: false,
+            sni: None,
+            udp: None,
+        });
+
+        // one after another there would never be more than one pending
+        exchange_all(&c, 50).await;
+        assert!(peak.load(Ordering::Relaxed) > 1);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+
+        c.inner.read().await.bg_handle.as_ref().unwrap().abort();
+        while !c
+            .inner
+            .read()
+            .await
+            .bg_handle
+            .as_ref()
+            .unwrap()
+            .is_finished()
+        {
+            tokio::task::yield_now().await;
+        }
+
+        // reconnected once for all of them
+        exchange_all(&c, 50).await;
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_id_with_sni() {
+        let c = DnsClient {
+            inner: Arc::new(RwLock::new(Inner {
+                c: None,
+                bg_handle: None,
+                backoff: Default::default(),
+            })),
+            cfg: DnsConfig::Https(
+                "1.1.1.1:443".parse().unwrap(),
+                "cloudflare-dns.com".to_owned(),
+                None,
+            ),
+            host: "1.1.1.1".to_owned(),
+            port: 443,
+            net: DNSNetMode::DoH,
+            iface: None,
+            epoch: Default::default(),
+            auto_iface: false,
+            sni: Some("cloudflare-dns.com".to_owned()),
+            udp: None,
+        };
+        assert_eq!(c.id(), "DoH#1.1.1.1:443?sni=cloudflare-dns.com");
+        assert!(c.cfg.to_string().contains("host: cloudflare-dns.com"));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut b = Backoff::default();
+        let now = tokio::time::Instant::now();
+        assert!(b.ready(now));
+
+        // the first connect doesn't wait for anything
+        b.connecting(now);
+        assert!(b.ready(now));
+
+        b.connecting(now);
+        assert!(!b.ready(now));
+        assert!(b.ready(now + Duration::from_millis(120)));
+
+        for _ in 0..20 {
+            b.connecting(now);
+        }
+        assert!(!b.ready(now + Duration::from_secs(23)));
+        assert!(b.ready(now + Duration::from_secs(36)));
+
+        b = Backoff::default();
+        assert!(b.is_reset());
+    }
+}
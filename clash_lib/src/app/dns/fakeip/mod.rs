@@ -30,6 +30,7 @@ pub trait Store: Sync + Send {
     async fn del_by_ip(&mut self, ip: net::IpAddr);
     async fn exist(&mut self, ip: net::IpAddr) -> bool;
     async fn copy_to(&self, store: &mut Box<dyn Store>);
+    async fn flush(&mut self);
 }
 
 pub type ThreadSafeFakeDns = Arc<RwLock<FakeDns>>;
@@ -112,6 +113,12 @@ impl FakeDns {
         }
     }
 
+    /// the allocation offset is kept, so hosts looked up after the flush don't
+    /// get the IPs handed out before it
+    pub async fn flush(&mut self) {
+        self.store.flush().await;
+    }
+
     #[allow(dead_code)]
     pub fn gateway(&self) -> net::Ipv4Addr {
         net::Ipv4Addr::from(self.gateway)
@@ -195,6 +202,25 @@ mod tests {
         assert!(!pool.exist("::1".parse().unwrap()).await);
     }
 
+    #[tokio::test]
+    async fn test_inmem_flush() {
+        let ipnet = "192.168.0.0/28".parse::<ipnet::IpNet>().unwrap();
+        let store = Box::new(InMemStore::new(10));
+        let mut pool = FakeDns::new(Opts {
+            ipnet,
+            skipped_hostnames: None,
+            store,
+        })
+        .unwrap();
+
+        let before = pool.lookup("foo.com").await;
+        pool.flush().await;
+
+        assert_eq!(pool.reverse_lookup(before).await, None);
+        let after = pool.lookup("foo.com").await;
+        assert_ne!(before, after);
+    }
+
     #[tokio::test]
     async fn test_inmem_cycle_used() {
         let store = Box::new(InMemStore::new(10));
@@ -58,3 +58,69 @@ impl FallbackDomainFilter for DomainFilter {
         self.0.search(domain).is_some()
     }
 }
+
+/// Tells the upstream answers that could be a DNS rebinding attack, a public
+/// name pointing at the LAN or the host itself, from those of the domains
+/// allowed to.
+pub struct RebindFilter(trie::StringTrie<Option<String>>);
+
+impl RebindFilter {
+    pub fn new(allowed: &[String]) -> Self {
+        let mut f = RebindFilter(trie::StringTrie::new());
+        for d in allowed {
+            f.0.insert(d, Arc::new(None));
+        }
+        f
+    }
+
+    pub fn apply(&self, domain: &str, ip: &net::IpAddr) -> bool {
+        is_internal(ip) && self.0.search(domain).is_none()
+    }
+}
+
+/// loopback, link-local, RFC1918 and ULA addresses
+fn is_internal(ip: &net::IpAddr) -> bool {
+    match ip {
+        net::IpAddr::V4(ip) => {
+            ip.is_loopback() || ip.is_link_local() || ip.is_private()
+        }
+        net::IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_internal(&v4.into()),
+            None => {
+                ip.is_loopback()
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RebindFilter;
+
+    #[test]
+    fn test_rebind_filter() {
+        let f = RebindFilter::new(&["+.lan".to_owned()]);
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.1.1",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(f.apply("evil.example.com", &ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "172.32.0.1", "2001:db8::1"] {
+            assert!(!f.apply("evil.example.com", &ip.parse().unwrap()), "{}", ip);
+        }
+
+        assert!(!f.apply("nas.lan", &"192.168.1.1".parse().unwrap()));
+        assert!(!f.apply("a.nas.lan", &"192.168.1.1".parse().unwrap()));
+    }
+}
@@ -1,9 +1,10 @@
 use crate::{
+    app::net_monitor::default_interface,
     dns::{
         dns_client::{DNSNetMode, DnsClient, Opts},
         ClashResolver, ThreadSafeDNSClient,
     },
-    proxy::utils::{get_outbound_interface, Interface},
+    proxy::utils::Interface,
 };
 use std::sync::Arc;
 use tracing::{debug, warn};
@@ -41,12 +42,12 @@ pub async fn make_clients(
                 .interface
                 .as_ref()
                 .and_then(|x| match x.as_str() {
-                    "auto" => {
-                        get_outbound_interface().map(|x| Interface::Name(x.name))
-                    }
+                    "auto" => default_interface().map(|x| Interface::Name(x.name)),
                     _ => Some(Interface::Name(x.to_owned())),
                 })
                 .inspect(|x| debug!("DNS client interface: {:?}", x)),
+            auto_iface: s.interface.as_deref() == Some("auto"),
+            sni: s.sni.clone(),
         })
         .await
         {
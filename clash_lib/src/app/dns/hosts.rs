@@ -0,0 +1,272 @@
+//! The hosts table of the resolver: the inline `hosts` on top of the entries
+//! of `dns.hosts-file` and, with `dns.use-system-hosts`, of the system's
+//! hosts file. The files are checked for changes every few seconds, and the
+//! whole table is built again from them when one changed, so a name taken
+//! out of a file is gone from the table too.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::common::{
+    scheduler::{Schedule, Task},
+    trie::StringTrie,
+};
+
+/// how often the files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn system_hosts_file() -> PathBuf {
+    #[cfg(windows)]
+    {
+        let root = std::env::var("SystemRoot").unwrap_or("C:\\Windows".to_owned());
+        std::path::Path::new(&root).join("System32\\drivers\\etc\\hosts")
+    }
+    #[cfg(not(windows))]
+    {
+        PathBuf::from("/etc/hosts")
+    }
+}
+
+/// The (name, address) entries of a hosts file. Anything after a `#` is a
+/// comment, and a line is an address followed by any number of names. Lines
+/// with no valid address are skipped.
+pub fn parse_hosts_file(content: &str) -> Vec<(String, IpAddr)> {
+    let mut entries = vec![];
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(ip) = fields.next() else {
+            continue;
+        };
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            debug!("skipping hosts line with no address: {}", line);
+            continue;
+        };
+        for name in fields {
+            entries.push((name.trim_end_matches('.').to_lowercase(), ip));
+        }
+    }
+    entries
+}
+
+/// the addresses of a name, one of each family
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HostsEntry {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl HostsEntry {
+    fn set_if_unset(&mut self, ip: IpAddr) {
+        match ip {
+            IpAddr::V4(v4) => {
+                self.v4.get_or_insert(v4);
+            }
+            IpAddr::V6(v6) => {
+                self.v6.get_or_insert(v6);
+            }
+        }
+    }
+}
+
+impl From<IpAddr> for HostsEntry {
+    fn from(ip: IpAddr) -> Self {
+        let mut entry = Self::default();
+        entry.set_if_unset(ip);
+        entry
+    }
+}
+
+pub struct Hosts {
+    inline: HashMap<String, IpAddr>,
+    files: Vec<PathBuf>,
+    table: RwLock<Arc<StringTrie<HostsEntry>>>,
+    /// those of the files when the table was built, None if missing
+    mtimes: Mutex<Vec<Option<SystemTime>>>,
+    task: Mutex<Option<Task>>,
+}
+
+impl Hosts {
+    /// Builds the table, and watches `files` for changes for as long as this
+    /// is alive.
+    pub fn new(inline: HashMap<String, IpAddr>, files: Vec<PathBuf>) -> Arc<Self> {
+        let hosts = Arc::new(Self {
+            inline,
+            files,
+            table: Default::default(),
+            mtimes: Default::default(),
+            task: Default::default(),
+        });
+        hosts.reload();
+
+        if !hosts.files.is_empty() {
+            let weak = Arc::downgrade(&hosts);
+            let task = Task::spawn(
+                "hosts files",
+                Schedule {
+                    interval: POLL_INTERVAL,
+                    immediately: false,
+                },
+                move || {
+                    let weak = weak.clone();
+                    async move {
+                        if let Some(hosts) = weak.upgrade() {
+                            hosts.reload_if_changed();
+                        }
+                        Ok(())
+                    }
+                },
+            );
+            *hosts.task.lock().unwrap() = Some(task);
+        }
+        hosts
+    }
+
+    /// the addresses of `host`, if the table has it
+    pub fn get(&self, host: &str) -> Option<HostsEntry> {
+        let table = self.table.read().unwrap().clone();
+        table.search(host).and_then(|x| x.get_data().copied())
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.table.read().unwrap().search(host).is_some()
+    }
+
+    fn mtimes(&self) -> Vec<Option<SystemTime>> {
+        self.files
+            .iter()
+            .map(|x| std::fs::metadata(x).and_then(|x| x.modified()).ok())
+            .collect()
+    }
+
+    fn reload_if_changed(&self) {
+        if *self.mtimes.lock().unwrap() != self.mtimes() {
+            info!("hosts files changed, reloading");
+            self.reload();
+        }
+    }
+
+    /// builds the table anew, the first entry of a name in the files wins
+    /// for each family, and an inline one replaces both families of the name
+    fn reload(&self) {
+        let mtimes = self.mtimes();
+        let mut merged: HashMap<String, HostsEntry> = HashMap::new();
+        for file in &self.files {
+            match std::fs::read_to_string(file) {
+                Ok(content) => {
+                    for (name, ip) in parse_hosts_file(&content) {
+                        merged.entry(name).or_default().set_if_unset(ip);
+                    }
+                }
+                Err(e) => warn!("can't read hosts file {}: {}", file.display(), e),
+            }
+        }
+        merged.extend(self.inline.iter().map(|(k, v)| (k.clone(), (*v).into())));
+        merged
+            .entry("localhost".to_owned())
+            .or_insert(IpAddr::from([127, 0, 0, 1]).into());
+
+        let mut table = StringTrie::new();
+        for (name, entry) in merged {
+            table.insert(&name, Arc::new(entry));
+        }
+        *self.table.write().unwrap() = Arc::new(table);
+        *self.mtimes.lock().unwrap() = mtimes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    use super::{parse_hosts_file, Hosts, HostsEntry};
+
+    fn v4(x: &str) -> Ipv4Addr {
+        x.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_hosts_file() {
+        let entries = parse_hosts_file(concat!(
+            "# leases\n",
+            "192.168.1.10  laptop laptop.lan # the work one\n",
+            "\n",
+            "fd00::23\ttv.lan.\n",
+            "not-an-ip nas\n",
+        ));
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("laptop".to_owned(), ip("192.168.1.10")),
+                ("laptop.lan".to_owned(), ip("192.168.1.10")),
+                ("tv.lan".to_owned(), ip("fd00::23")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hosts");
+        std::fs::write(&file, "192.168.1.10 laptop\n192.168.1.11 phone\n").unwrap();
+
+        let inline =
+            HashMap::from([("phone".to_owned(), "10.0.0.2".parse().unwrap())]);
+        let hosts = Hosts::new(inline, vec![file.clone()]);
+        assert_eq!(
+            hosts.get("laptop").and_then(|x| x.v4),
+            Some(v4("192.168.1.10"))
+        );
+        // inline wins
+        assert_eq!(hosts.get("phone").and_then(|x| x.v4), Some(v4("10.0.0.2")));
+        assert!(hosts.contains("localhost"));
+
+        std::fs::write(&file, "192.168.1.12 tv\n").unwrap();
+        // moved back rather than waiting, coarse clocks may not see the write
+        filetime::set_file_mtime(&file, filetime::FileTime::zero()).unwrap();
+        hosts.reload_if_changed();
+        assert_eq!(hosts.get("laptop"), None);
+        assert_eq!(hosts.get("tv").and_then(|x| x.v4), Some(v4("192.168.1.12")));
+        assert_eq!(hosts.get("phone").and_then(|x| x.v4), Some(v4("10.0.0.2")));
+    }
+
+    #[tokio::test]
+    async fn test_both_families() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hosts");
+        std::fs::write(
+            &file,
+            "192.168.1.10 nas\nfd00::10 nas\n192.168.1.11 nas\n10.0.0.3 tv\n",
+        )
+        .unwrap();
+
+        let inline = HashMap::from([("tv".to_owned(), "fd00::3".parse().unwrap())]);
+        let hosts = Hosts::new(inline, vec![file]);
+        assert_eq!(
+            hosts.get("nas"),
+            Some(HostsEntry {
+                v4: Some(v4("192.168.1.10")),
+                v6: Some("fd00::10".parse().unwrap()),
+            })
+        );
+        // the inline one pins the name to its family
+        assert_eq!(
+            hosts.get("tv"),
+            Some(HostsEntry {
+                v4: None,
+                v6: Some("fd00::3".parse().unwrap()),
+            })
+        );
+    }
+}
@@ -1,10 +1,12 @@
 use async_trait::async_trait;
 
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug, time::Duration};
 
 use hickory_proto::op;
 use std::sync::Arc;
 
+use crate::app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider;
+
 #[cfg(test)]
 use mockall::automock;
 
@@ -14,10 +16,14 @@ mod dns_client;
 mod fakeip;
 mod filters;
 mod helper;
+mod hosts;
 pub mod resolver;
 mod server;
+mod singleflight;
+mod udp_upstream;
 
-pub use config::Config;
+pub use config::{Config, PolicyKey};
+pub use hosts::system_hosts_file;
 
 pub use resolver::{new as new_resolver, EnhancedResolver, SystemResolver};
 
@@ -39,6 +45,9 @@ pub enum ResolverKind {
 
 pub type ThreadSafeDNSResolver = Arc<dyn ClashResolver>;
 
+/// how long addresses are kept when the resolver doesn't know their ttl
+const DEFAULT_ADDR_TTL: Duration = Duration::from_secs(60);
+
 /// A implementation of "anti-poisoning" Resolver
 /// it can hold multiple clients in different protocols
 /// each client can also hold a "default_resolver"
@@ -62,6 +71,25 @@ pub trait ClashResolver: Sync + Send {
         enhanced: bool,
     ) -> anyhow::Result<Option<std::net::Ipv6Addr>>;
 
+    /// The addresses of `host` of one family and how long they can be kept,
+    /// for the callers caching them. `fresh` skips any cached answer, hosts
+    /// are used but fake ips never are.
+    async fn lookup_addrs(
+        &self,
+        host: &str,
+        v6: bool,
+        fresh: bool,
+    ) -> anyhow::Result<(Vec<std::net::IpAddr>, Duration)> {
+        // the system resolver has no cache to skip and doesn't tell the ttl
+        let _ = fresh;
+        let ip = if v6 {
+            self.resolve_v6(host, false).await?.map(Into::into)
+        } else {
+            self.resolve_v4(host, false).await?.map(Into::into)
+        };
+        Ok((ip.into_iter().collect(), DEFAULT_ADDR_TTL))
+    }
+
     async fn cached_for(&self, ip: std::net::IpAddr) -> Option<String>;
 
     /// Used for DNS Server
@@ -71,9 +99,27 @@ pub trait ClashResolver: Sync + Send {
     async fn reverse_lookup(&self, ip: std::net::IpAddr) -> Option<String>;
     async fn is_fake_ip(&self, ip: std::net::IpAddr) -> bool;
     fn fake_ip_enabled(&self) -> bool;
+    /// Drops all fake IP mappings, hosts looked up afterwards get new IPs
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    async fn flush_fake_ip(&self);
+
+    /// Drops all cached DNS answers
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    async fn flush_cache(&self);
 
     fn ipv6(&self) -> bool;
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     fn set_ipv6(&self, enable: bool);
 
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     fn kind(&self) -> ResolverKind;
+
+    /// The rule providers the `rule-set:` keys of `nameserver-policy` refer
+    /// to, they are loaded by the router after the resolver is made.
+    fn set_rule_providers(
+        &self,
+        providers: HashMap<String, ThreadSafeRuleProvider>,
+    ) {
+        let _ = providers;
+    }
 }
@@ -2,23 +2,29 @@ use async_trait::async_trait;
 use futures::{FutureExt, TryFutureExt};
 use rand::prelude::SliceRandom;
 use std::{
+    collections::HashMap,
     net,
     sync::{
         atomic::{AtomicBool, Ordering::Relaxed},
-        Arc,
+        Arc, Weak,
     },
     time::Duration,
 };
 use tokio::sync::RwLock;
-use tracing::{debug, error, instrument, trace, warn};
+use tracing::{debug, error, info, instrument, trace, warn};
 
 use hickory_proto::{op, rr};
 
 use crate::{
-    app::profile::ThreadSafeCacheFile,
-    common::{mmdb::Mmdb, trie},
+    app::{
+        profile::ThreadSafeCacheFile,
+        remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider,
+        router::GeoSiteMatcher,
+    },
+    common::{geodata::GeoData, mmdb::Mmdb, trie},
     config::def::DNSMode,
-    dns::{helper::make_clients, ThreadSafeDNSClient},
+    dns::{helper::make_clients, PolicyKey, ThreadSafeDNSClient},
+    session::{Session, SocksAddr},
     Error,
 };
 
@@ -26,16 +32,30 @@ use crate::dns::{
     fakeip::{self, FileStore, InMemStore, ThreadSafeFakeDns},
     filters::{
         DomainFilter, FallbackDomainFilter, FallbackIPFilter, GeoIPFilter,
-        IPNetFilter,
+        IPNetFilter, RebindFilter,
     },
+    hosts::Hosts,
+    singleflight::SingleFlight,
     ClashResolver, Config, ResolverKind,
 };
 
 static TTL: Duration = Duration::from_secs(60);
 
+/// a `geosite:` or `rule-set:` entry of `nameserver-policy`
+struct PolicySet {
+    matcher: PolicyMatcher,
+    clients: Vec<ThreadSafeDNSClient>,
+}
+
+enum PolicyMatcher {
+    GeoSite(GeoSiteMatcher),
+    /// looked up in the provider each time, so it follows its updates
+    RuleSet(String),
+}
+
 pub struct EnhancedResolver {
     ipv6: AtomicBool,
-    hosts: Option<trie::StringTrie<net::IpAddr>>,
+    hosts: Option<Arc<Hosts>>,
     main: Vec<ThreadSafeDNSClient>,
 
     fallback: Option<Vec<ThreadSafeDNSClient>>,
@@ -44,42 +64,71 @@ pub struct EnhancedResolver {
 
     lru_cache: Option<Arc<RwLock<lru_time_cache::LruCache<String, op::Message>>>>,
     policy: Option<trie::StringTrie<Vec<ThreadSafeDNSClient>>>,
+    /// tried after `policy`, so the domains given on their own win
+    policy_sets: Vec<PolicySet>,
+    rule_providers: std::sync::RwLock<HashMap<String, ThreadSafeRuleProvider>>,
+    /// strips the internal addresses from upstream answers
+    rebind_filter: Option<RebindFilter>,
 
     fake_dns: Option<ThreadSafeFakeDns>,
 
     reverse_lookup_cache:
         Option<Arc<RwLock<lru_time_cache::LruCache<net::IpAddr, String>>>>,
+
+    /// the upstream queries in flight, by the same key as the cache
+    in_flight: SingleFlight<String, Result<op::Message, String>>,
+    me: Weak<EnhancedResolver>,
 }
 
 impl EnhancedResolver {
     /// For testing purpose
     #[cfg(test)]
-    pub async fn new_default() -> Self {
+    pub async fn new_default() -> Arc<Self> {
         use crate::app::dns::dns_client::DNSNetMode;
 
         use crate::app::dns::config::NameServer;
 
-        EnhancedResolver {
-            ipv6: AtomicBool::new(false),
-            hosts: None,
-            main: make_clients(
+        Self::with_clients(
+            make_clients(
                 vec![NameServer {
                     net: DNSNetMode::Udp,
                     address: "8.8.8.8:53".to_string(),
                     interface: None,
+                    sni: None,
                 }],
                 None,
             )
             .await,
+        )
+    }
+
+    #[cfg(test)]
+    fn with_clients(main: Vec<ThreadSafeDNSClient>) -> Arc<Self> {
+        Self::unshared(main).into_arc()
+    }
+
+    /// [`Self::with_clients`] before it's shared, for the tests to set up
+    #[cfg(test)]
+    fn unshared(main: Vec<ThreadSafeDNSClient>) -> Self {
+        EnhancedResolver {
+            ipv6: AtomicBool::new(false),
+            hosts: None,
+            main,
             fallback: None,
             fallback_domain_filters: None,
             fallback_ip_filters: None,
             lru_cache: None,
             policy: None,
+            policy_sets: vec![],
+            rule_providers: Default::default(),
+            rebind_filter: None,
 
             fake_dns: None,
 
             reverse_lookup_cache: None,
+
+            in_flight: Default::default(),
+            me: Weak::new(),
         }
     }
 
@@ -87,8 +136,9 @@ impl EnhancedResolver {
         cfg: Config,
         store: ThreadSafeCacheFile,
         mmdb: Arc<Mmdb>,
-    ) -> Self {
-        let default_resolver = Arc::new(EnhancedResolver {
+        geodata: Option<Arc<GeoData>>,
+    ) -> Arc<Self> {
+        let default_resolver = EnhancedResolver {
             ipv6: AtomicBool::new(false),
             hosts: None,
             main: make_clients(cfg.default_nameserver.clone(), None).await,
@@ -97,11 +147,70 @@ impl EnhancedResolver {
             fallback_ip_filters: None,
             lru_cache: None,
             policy: None,
+            policy_sets: vec![],
+            rule_providers: Default::default(),
+            rebind_filter: None,
 
             fake_dns: None,
 
             reverse_lookup_cache: None,
-        });
+
+            in_flight: Default::default(),
+            me: Weak::new(),
+        }
+        .into_arc();
+
+        let mut policy = trie::StringTrie::new();
+        let mut has_policy = false;
+        let mut policy_sets = vec![];
+        // sorted for the sets matching the same domain to be tried in the
+        // same order every time
+        let mut entries = cfg.nameserver_policy.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(k, _)| *k);
+        for (key, ns) in entries {
+            let clients =
+                make_clients(vec![ns.to_owned()], Some(default_resolver.clone()))
+                    .await;
+            for sub in PolicyKey::parse_all(key) {
+                let matcher = match sub {
+                    PolicyKey::Domain(domain) => {
+                        policy.insert(domain, Arc::new(clients.clone()));
+                        has_policy = true;
+                        continue;
+                    }
+                    PolicyKey::GeoSite(code) => {
+                        let Some(geodata) = &geodata else {
+                            warn!(
+                                "no geosite loaded, nameserver-policy {} ignored",
+                                key
+                            );
+                            continue;
+                        };
+                        match GeoSiteMatcher::new(
+                            code.to_owned(),
+                            ns.to_string(),
+                            geodata,
+                        ) {
+                            Ok(m) => PolicyMatcher::GeoSite(m),
+                            Err(e) => {
+                                error!(
+                                    "nameserver-policy geosite:{} ignored: {}",
+                                    code, e
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                    PolicyKey::RuleSet(name) => {
+                        PolicyMatcher::RuleSet(name.to_owned())
+                    }
+                };
+                policy_sets.push(PolicySet {
+                    matcher,
+                    clients: clients.clone(),
+                });
+            }
+        }
 
         Self {
             ipv6: AtomicBool::new(cfg.ipv6),
@@ -110,7 +219,7 @@ impl EnhancedResolver {
                 Some(default_resolver.clone()),
             )
             .await,
-            hosts: cfg.hosts,
+            hosts: Some(Hosts::new(cfg.hosts, cfg.hosts_files)),
             fallback: if !cfg.fallback.is_empty() {
                 Some(
                     make_clients(
@@ -159,24 +268,12 @@ impl EnhancedResolver {
                     TTL, 4096,
                 ),
             ))),
-            policy: if !cfg.nameserver_policy.is_empty() {
-                let mut p = trie::StringTrie::new();
-                for (domain, ns) in &cfg.nameserver_policy {
-                    p.insert(
-                        domain.as_str(),
-                        Arc::new(
-                            make_clients(
-                                vec![ns.to_owned()],
-                                Some(default_resolver.clone()),
-                            )
-                            .await,
-                        ),
-                    );
-                }
-                Some(p)
-            } else {
-                None
-            },
+            policy: has_policy.then_some(policy),
+            policy_sets,
+            rule_providers: Default::default(),
+            rebind_filter: cfg
+                .filter_unsafe_answers
+                .then(|| RebindFilter::new(&cfg.rebind_allow_domains)),
             fake_dns: match cfg.enhance_mode {
                 DNSMode::FakeIp => Some(Arc::new(RwLock::new(
                     fakeip::FakeDns::new(fakeip::Opts {
@@ -217,7 +314,20 @@ impl EnhancedResolver {
                     4096,
                 ),
             ))),
+
+            in_flight: Default::default(),
+            me: Weak::new(),
         }
+        .into_arc()
+    }
+
+    /// The lookups run on their own tasks so they aren't cancelled with the
+    /// caller, and need a handle on the resolver for that.
+    fn into_arc(mut self) -> Arc<Self> {
+        Arc::new_cyclic(|me| {
+            self.me = me.clone();
+            self
+        })
     }
 
     pub async fn batch_exchange(
@@ -254,11 +364,10 @@ impl EnhancedResolver {
     }
 
     /// guaranteed to return at least 1 IP address when Ok
-    async fn lookup_ip(
-        &self,
+    fn ip_query(
         host: &str,
         record_type: rr::record_type::RecordType,
-    ) -> anyhow::Result<Vec<net::IpAddr>> {
+    ) -> anyhow::Result<op::Message> {
         let mut m = op::Message::new();
         let mut q = op::Query::new();
         let name = rr::Name::from_str_relaxed(host)
@@ -268,6 +377,15 @@ impl EnhancedResolver {
         q.set_query_type(record_type);
         m.add_query(q);
         m.set_recursion_desired(true);
+        Ok(m)
+    }
+
+    async fn lookup_ip(
+        &self,
+        host: &str,
+        record_type: rr::record_type::RecordType,
+    ) -> anyhow::Result<Vec<net::IpAddr>> {
+        let m = Self::ip_query(host, record_type)?;
 
         match self.exchange(&m).await {
             Ok(result) => {
@@ -282,6 +400,7 @@ impl EnhancedResolver {
         }
     }
 
+    #[instrument(skip_all, fields(query = ?message.query()))]
     async fn exchange(&self, message: &op::Message) -> anyhow::Result<op::Message> {
         if let Some(q) = message.query() {
             if let Some(lru) = &self.lru_cache {
@@ -292,7 +411,27 @@ impl EnhancedResolver {
                     return Ok(cached);
                 }
             }
-            self.exchange_no_cache(message).await
+
+            // concurrent lookups for the same query share one upstream
+            // query, e.g. a page opening many connections to a new domain
+            let Some(me) = self.me.upgrade() else {
+                return self.exchange_no_cache(message).await;
+            };
+            let m = message.clone();
+            let rv = self
+                .in_flight
+                .run(q.to_string(), async move {
+                    me.exchange_no_cache(&m).await.map_err(|e| e.to_string())
+                })
+                .await
+                .and_then(|rv| rv);
+            match rv {
+                Ok(mut rv) => {
+                    rv.set_id(message.id());
+                    Ok(rv)
+                }
+                Err(e) => Err(anyhow!(e)),
+            }
         } else {
             Err(anyhow!("invalid query"))
         }
@@ -316,7 +455,7 @@ impl EnhancedResolver {
             EnhancedResolver::batch_exchange(&self.main, message).await
         };
 
-        let rv = query.await;
+        let rv = query.await.map(|x| self.strip_unsafe_answers(x));
 
         if let Ok(msg) = &rv {
             if let Some(lru) = &self.lru_cache {
@@ -353,15 +492,82 @@ impl EnhancedResolver {
         rv
     }
 
+    /// drops the A/AAAA records the rebind filter tells, and with them the
+    /// whole answer if there's no address left, making it NODATA
+    fn strip_unsafe_answers(&self, mut msg: op::Message) -> op::Message {
+        let (Some(filter), Some(domain)) = (
+            &self.rebind_filter,
+            EnhancedResolver::domain_name_of_message(&msg),
+        ) else {
+            return msg;
+        };
+        let configured = |x: &str| {
+            self.hosts.as_ref().is_some_and(|h| h.contains(x))
+                || self.policy_for(x).is_some()
+        };
+        if configured(&domain) {
+            return msg;
+        }
+
+        let answers = msg.take_answers();
+        let had_ip = answers
+            .iter()
+            .any(|r| matches!(r.data(), rr::RData::A(_) | rr::RData::AAAA(_)));
+        let mut kept = answers
+            .into_iter()
+            .filter(|r| {
+                let ip = match r.data() {
+                    rr::RData::A(v4) => net::IpAddr::V4(**v4),
+                    rr::RData::AAAA(v6) => net::IpAddr::V6(**v6),
+                    _ => return true,
+                };
+                if filter.apply(&domain, &ip) {
+                    info!("stripped {} of {} from dns answer", ip, domain);
+                    return false;
+                }
+                true
+            })
+            .collect::<Vec<_>>();
+        let has_ip = kept
+            .iter()
+            .any(|r| matches!(r.data(), rr::RData::A(_) | rr::RData::AAAA(_)));
+        if had_ip && !has_ip {
+            kept.clear();
+        }
+        msg.set_answer_count(kept.len() as u16);
+        msg.insert_answers(kept);
+        msg
+    }
+
     fn match_policy(&self, m: &op::Message) -> Option<&Vec<ThreadSafeDNSClient>> {
-        if let (Some(_fallback), Some(_fallback_domain_filters), Some(policy)) =
-            (&self.fallback, &self.fallback_domain_filters, &self.policy)
-        {
-            if let Some(domain) = EnhancedResolver::domain_name_of_message(m) {
-                return policy.search(&domain).map(|n| n.get_data().unwrap());
-            }
+        let domain = EnhancedResolver::domain_name_of_message(m)?;
+        self.policy_for(&domain)
+    }
+
+    /// the nameservers `nameserver-policy` has for `domain`, those of the
+    /// domains given on their own first, then those of the geosite
+    /// categories and rule sets
+    fn policy_for(&self, domain: &str) -> Option<&Vec<ThreadSafeDNSClient>> {
+        if let Some(node) = self.policy.as_ref().and_then(|p| p.search(domain)) {
+            return node.get_data();
         }
-        None
+        if self.policy_sets.is_empty() {
+            return None;
+        }
+        let sess = Session {
+            destination: SocksAddr::Domain(domain.to_owned(), 0),
+            ..Default::default()
+        };
+        let providers = self.rule_providers.read().unwrap();
+        self.policy_sets
+            .iter()
+            .find(|x| match &x.matcher {
+                PolicyMatcher::GeoSite(m) => m.matches(domain),
+                PolicyMatcher::RuleSet(name) => {
+                    providers.get(name).is_some_and(|p| p.search(&sess))
+                }
+            })
+            .map(|x| &x.clients)
     }
 
     async fn ip_exchange(
@@ -505,11 +711,9 @@ impl ClashResolver for EnhancedResolver {
     ) -> anyhow::Result<Option<net::Ipv4Addr>> {
         if enhanced {
             if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V4(v4) => *v4,
-                        _ => unreachable!("invalid IP family"),
-                    }));
+                if let Some(entry) = hosts.get(host) {
+                    // none of this family
+                    return Ok(entry.v4);
                 }
             }
         }
@@ -550,11 +754,8 @@ impl ClashResolver for EnhancedResolver {
 
         if enhanced {
             if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V6(v6) => *v6,
-                        _ => unreachable!("invalid IP family"),
-                    }));
+                if let Some(entry) = hosts.get(host) {
+                    return Ok(entry.v6);
                 }
             }
         }
@@ -573,6 +774,55 @@ impl ClashResolver for EnhancedResolver {
         }
     }
 
+    async fn lookup_addrs(
+        &self,
+        host: &str,
+        v6: bool,
+        fresh: bool,
+    ) -> anyhow::Result<(Vec<net::IpAddr>, Duration)> {
+        if let Ok(ip) = host.parse::<net::IpAddr>() {
+            return Ok((vec![ip], Duration::MAX));
+        }
+        if v6 && !self.ipv6.load(Relaxed) {
+            return Err(Error::DNSError("ipv6 disabled".into()).into());
+        }
+        // a server pinned in hosts has no address of the other family
+        if let Some(entry) = self.hosts.as_ref().and_then(|h| h.get(host)) {
+            let ip = if v6 {
+                entry.v6.map(net::IpAddr::from)
+            } else {
+                entry.v4.map(net::IpAddr::from)
+            };
+            return Ok((ip.into_iter().collect(), Duration::MAX));
+        }
+
+        let m = Self::ip_query(
+            host,
+            if v6 {
+                rr::RecordType::AAAA
+            } else {
+                rr::RecordType::A
+            },
+        )?;
+        // the answer found fresh replaces the cached one
+        let rv = if fresh {
+            self.exchange_no_cache(&m).await?
+        } else {
+            self.exchange(&m).await?
+        };
+        let ips = EnhancedResolver::ip_list_of_message(&rv);
+        if ips.is_empty() {
+            return Err(anyhow!("no record for hostname: {}", host));
+        }
+        let ttl = rv
+            .answers()
+            .iter()
+            .map(|x| x.ttl())
+            .min()
+            .unwrap_or_default();
+        Ok((ips, Duration::from_secs(ttl as u64)))
+    }
+
     async fn cached_for(&self, ip: net::IpAddr) -> Option<String> {
         if let Some(lru) = &self.reverse_lookup_cache {
             if let Some(cached) = lru.read().await.peek(&ip) {
@@ -614,6 +864,20 @@ impl ClashResolver for EnhancedResolver {
         ResolverKind::Clash
     }
 
+    fn set_rule_providers(
+        &self,
+        providers: HashMap<String, ThreadSafeRuleProvider>,
+    ) {
+        for x in &self.policy_sets {
+            if let PolicyMatcher::RuleSet(name) = &x.matcher {
+                if !providers.contains_key(name) {
+                    warn!("rule provider {} of nameserver-policy not found", name);
+                }
+            }
+        }
+        *self.rule_providers.write().unwrap() = providers;
+    }
+
     fn fake_ip_enabled(&self) -> bool {
         self.fake_dns.is_some()
     }
@@ -636,24 +900,59 @@ impl ClashResolver for EnhancedResolver {
         let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
         fake_dns.reverse_lookup(ip).await
     }
+
+    async fn flush_fake_ip(&self) {
+        if let Some(fake_dns) = &self.fake_dns {
+            fake_dns.write().await.flush().await;
+        }
+    }
+
+    async fn flush_cache(&self) {
+        if let Some(lru) = &self.lru_cache {
+            lru.write().await.clear();
+        }
+        if let Some(cache) = &self.reverse_lookup_cache {
+            cache.write().await.clear();
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
+    use async_trait::async_trait;
     use hickory_client::{client, op};
     use hickory_proto::{
         rr,
         udp::UdpClientStream,
         xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer},
     };
-    use std::{sync::Arc, time::Duration};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
     use tokio::net::UdpSocket;
 
-    use crate::app::dns::{
-        dns_client::{DNSNetMode, DnsClient, Opts},
-        resolver::enhanced::EnhancedResolver,
-        ThreadSafeDNSClient,
+    use crate::{
+        app::{
+            dns::{
+                dns_client::{DNSNetMode, DnsClient, Opts},
+                hosts::Hosts,
+                resolver::enhanced::{EnhancedResolver, PolicyMatcher, PolicySet},
+                ClashResolver, Client, ThreadSafeDNSClient,
+            },
+            router::GeoSiteMatcher,
+        },
+        common::{
+            geodata::{
+                geodata_proto::{domain, Domain, GeoSite},
+                GeoData,
+            },
+            trie,
+        },
     };
 
     #[tokio::test]
@@ -695,6 +994,8 @@ mod tests {
             port: 53,
             net: DNSNetMode::Udp,
             iface: None,
+            auto_iface: false,
+            sni: None,
         })
         .await
         .expect("build client");
@@ -711,6 +1012,8 @@ mod tests {
             port: 53,
             net: DNSNetMode::Tcp,
             iface: None,
+            auto_iface: false,
+            sni: None,
         })
         .await
         .expect("build client");
@@ -722,11 +1025,13 @@ mod tests {
     #[ignore = "network unstable on CI"]
     async fn test_dot_resolve() {
         let c = DnsClient::new_client(Opts {
-            r: Some(Arc::new(EnhancedResolver::new_default().await)),
+            r: Some(EnhancedResolver::new_default().await),
             host: "dns.google".to_string(),
             port: 853,
             net: DNSNetMode::DoT,
             iface: None,
+            auto_iface: false,
+            sni: None,
         })
         .await
         .expect("build client");
@@ -737,7 +1042,7 @@ mod tests {
     #[tokio::test]
     #[ignore = "network unstable on CI"]
     async fn test_doh_resolve() {
-        let default_resolver = Arc::new(EnhancedResolver::new_default().await);
+        let default_resolver = EnhancedResolver::new_default().await;
 
         let c = DnsClient::new_client(Opts {
             r: Some(default_resolver.clone()),
@@ -745,6 +1050,8 @@ mod tests {
             port: 443,
             net: DNSNetMode::DoH,
             iface: None,
+            auto_iface: false,
+            sni: None,
         })
         .await
         .expect("build client");
@@ -761,6 +1068,8 @@ mod tests {
             port: 0,
             net: DNSNetMode::Dhcp,
             iface: None,
+            auto_iface: false,
+            sni: None,
         })
         .await
         .expect("build client");
@@ -768,6 +1077,123 @@ mod tests {
         test_client(c).await;
     }
 
+    #[derive(Debug, Default)]
+    struct CountingClient {
+        queries: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Client for CountingClient {
+        fn id(&self) -> String {
+            "counting".to_owned()
+        }
+
+        async fn exchange(&self, msg: &op::Message) -> anyhow::Result<op::Message> {
+            self.queries.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let q = msg.query().unwrap();
+            let mut rv = op::Message::new();
+            rv.set_id(msg.id());
+            rv.add_query(q.clone());
+            rv.add_answer(rr::Record::from_rdata(
+                q.name().clone(),
+                60,
+                rr::RData::A(rr::rdata::A::new(1, 2, 3, 4)),
+            ));
+            Ok(rv)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_share_query() {
+        let client = Arc::new(CountingClient::default());
+        let resolver = EnhancedResolver::with_clients(vec![client.clone()]);
+
+        let lookups = (0..100u16).map(|id| {
+            let resolver = resolver.clone();
+            async move {
+                let mut m = op::Message::new();
+                let mut q = op::Query::new();
+                q.set_name(rr::Name::from_utf8("example.com.").unwrap());
+                q.set_query_type(rr::RecordType::A);
+                m.add_query(q);
+                m.set_id(id);
+
+                let rv = ClashResolver::exchange(resolver.as_ref(), &m)
+                    .await
+                    .unwrap();
+                assert_eq!(rv.id(), id);
+                EnhancedResolver::ip_list_of_message(&rv)
+            }
+        });
+        for ips in futures::future::join_all(lookups).await {
+            assert_eq!(ips, vec!["1.2.3.4".parse::<std::net::IpAddr>().unwrap()]);
+        }
+
+        assert_eq!(client.queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_nameserver_policy_sets() {
+        let geodata = GeoData::from_sites(vec![GeoSite {
+            country_code: "CN".to_owned(),
+            domain: vec![Domain {
+                r#type: domain::Type::Domain as i32,
+                value: "example.cn".to_owned(),
+                attribute: vec![],
+            }],
+        }]);
+        let explicit: ThreadSafeDNSClient = Arc::new(CountingClient::default());
+        let geosite: ThreadSafeDNSClient = Arc::new(CountingClient::default());
+
+        let mut resolver = EnhancedResolver::unshared(vec![]);
+        let mut policy = trie::StringTrie::new();
+        policy.insert("www.example.cn", Arc::new(vec![explicit.clone()]));
+        resolver.policy = Some(policy);
+        resolver.policy_sets = vec![
+            PolicySet {
+                matcher: PolicyMatcher::RuleSet("not-loaded".to_owned()),
+                clients: vec![],
+            },
+            PolicySet {
+                matcher: PolicyMatcher::GeoSite(
+                    GeoSiteMatcher::new("cn".to_owned(), "".to_owned(), &geodata)
+                        .unwrap(),
+                ),
+                clients: vec![geosite.clone()],
+            },
+        ];
+
+        let got = |domain| resolver.policy_for(domain).map(|x| x[0].clone());
+        // the domain given on its own wins over the category
+        assert!(Arc::ptr_eq(&got("www.example.cn").unwrap(), &explicit));
+        assert!(Arc::ptr_eq(&got("cdn.example.cn").unwrap(), &geosite));
+        assert!(got("example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_addrs_uses_hosts() {
+        let mut resolver = EnhancedResolver::unshared(vec![]);
+        resolver.ipv6.store(true, Ordering::Relaxed);
+        resolver.hosts = Some(Hosts::new(
+            [("proxy.example".to_owned(), "192.0.2.1".parse().unwrap())].into(),
+            vec![],
+        ));
+
+        let (ips, ttl) = resolver
+            .lookup_addrs("proxy.example", false, true)
+            .await
+            .unwrap();
+        assert_eq!(ips, vec!["192.0.2.1".parse::<std::net::IpAddr>().unwrap()]);
+        assert_eq!(ttl, Duration::MAX);
+        let (ips, _) = resolver
+            .lookup_addrs("proxy.example", true, false)
+            .await
+            .unwrap();
+        assert!(ips.is_empty());
+    }
+
     async fn test_client(c: ThreadSafeDNSClient) {
         let mut m = op::Message::new();
         let mut q = op::Query::new();
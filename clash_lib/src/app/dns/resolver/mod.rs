@@ -12,7 +12,10 @@ use std::sync::Arc;
 pub use enhanced::EnhancedResolver;
 pub use system::SystemResolver;
 
-use crate::{app::profile::ThreadSafeCacheFile, common::mmdb::Mmdb};
+use crate::{
+    app::profile::ThreadSafeCacheFile,
+    common::{geodata::GeoData, mmdb::Mmdb},
+};
 
 use super::{Config, ThreadSafeDNSResolver};
 
@@ -20,11 +23,12 @@ pub async fn new(
     cfg: Config,
     store: Option<ThreadSafeCacheFile>,
     mmdb: Option<Arc<Mmdb>>,
+    geodata: Option<Arc<GeoData>>,
 ) -> ThreadSafeDNSResolver {
     if cfg.enable {
         match (store, mmdb) {
             (Some(store), Some(mmdb)) => {
-                Arc::new(EnhancedResolver::new(cfg, store, mmdb).await)
+                EnhancedResolver::new(cfg, store, mmdb, geodata).await
             }
             _ => panic!("enhanced resolver requires cache store and mmdb"),
         }
@@ -90,6 +90,12 @@ impl ClashResolver for SystemResolver {
     async fn reverse_lookup(&self, _: std::net::IpAddr) -> Option<String> {
         None
     }
+
+    async fn flush_fake_ip(&self) {}
+
+    async fn flush_cache(&self) {
+        self.inner.clear_cache();
+    }
 }
 
 #[cfg(test)]
@@ -109,6 +109,11 @@ impl ClashResolver for SystemResolver {
     async fn reverse_lookup(&self, _: std::net::IpAddr) -> Option<String> {
         None
     }
+
+    async fn flush_fake_ip(&self) {}
+
+    // getaddrinfo has no cache of its own to drop
+    async fn flush_cache(&self) {}
 }
 
 #[cfg(test)]
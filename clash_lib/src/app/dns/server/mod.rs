@@ -8,7 +8,7 @@ use hickory_proto::{
     },
 };
 
-use tracing::{debug, error};
+use tracing::{debug, error, instrument};
 use watfaq_dns::DNSListenAddr;
 
 use crate::Runner;
@@ -26,6 +26,7 @@ impl watfaq_dns::DnsMessageExchanger for DnsMessageExchanger {
         self.resolver.ipv6()
     }
 
+    #[instrument(skip_all, fields(query = ?message.query()))]
     async fn exchange(
         &self,
         message: &Message,
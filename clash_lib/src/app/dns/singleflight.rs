@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+type Call<V> = Shared<BoxFuture<'static, Result<V, String>>>;
+type Calls<K, V> = Arc<Mutex<HashMap<K, Call<V>>>>;
+
+/// Runs one call at a time for each key, the callers coming while it's in
+/// flight wait for it and all get its result.
+///
+/// The call runs on its own task, so it goes on if the caller that started
+/// it is cancelled, and the key is free again as soon as it's done: nothing
+/// is cached here, errors included. A call that panics is an `Err` for all
+/// its callers.
+pub struct SingleFlight<K, V> {
+    calls: Calls<K, V>,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            calls: Default::default(),
+        }
+    }
+}
+
+/// frees the key when the call's task ends, however it ends
+struct Done<K: Hash + Eq, V> {
+    calls: Calls<K, V>,
+    key: K,
+}
+
+impl<K: Hash + Eq, V> Drop for Done<K, V> {
+    fn drop(&mut self) {
+        self.calls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// `work` is only run if there is no call for `key` in flight
+    pub async fn run<F>(&self, key: K, work: F) -> Result<V, String>
+    where
+        F: Future<Output = V> + Send + 'static,
+    {
+        let call = {
+            let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+            match calls.get(&key) {
+                Some(call) => call.clone(),
+                None => {
+                    let done = Done {
+                        calls: self.calls.clone(),
+                        key: key.clone(),
+                    };
+                    // the entry is removed under the same lock it's inserted
+                    // with, so not before it's there
+                    let handle = tokio::spawn(async move {
+                        let _done = done;
+                        work.await
+                    });
+                    let call = handle
+                        .map(|x| {
+                            x.map_err(|e| format!("singleflight call failed: {}", e))
+                        })
+                        .boxed()
+                        .shared();
+                    calls.insert(key, call.clone());
+                    call
+                }
+            }
+        };
+        call.await
+    }
+
+    #[cfg(test)]
+    fn in_flight(&self) -> usize {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::SingleFlight;
+
+    #[tokio::test]
+    async fn test_cancelled_caller_doesnt_cancel_call() {
+        let sf = Arc::new(SingleFlight::<&str, usize>::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let work = |runs: Arc<AtomicUsize>| async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            runs.fetch_add(1, Ordering::Relaxed) + 1
+        };
+
+        let first = tokio::spawn({
+            let sf = sf.clone();
+            let w = work(runs.clone());
+            async move { sf.run("a", w).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        first.abort();
+
+        assert_eq!(sf.run("a", work(runs.clone())).await, Ok(1));
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert_eq!(sf.in_flight(), 0);
+
+        // done, so it runs again
+        assert_eq!(sf.run("a", work(runs.clone())).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_panicked_call() {
+        let sf = SingleFlight::<&str, usize>::default();
+
+        let rv = sf
+            .run("a", async {
+                tokio::task::yield_now().await;
+                panic!("boom")
+            })
+            .await;
+        assert!(rv.is_err());
+        assert_eq!(sf.in_flight(), 0);
+
+        // the key isn't stuck on the panicked call
+        assert_eq!(sf.run("a", async { 1 }).await, Ok(1));
+    }
+}
@@ -0,0 +1,336 @@
+//! The socket of a UDP upstream. All the queries to it go over one connected
+//! socket, told apart by their ids, instead of a socket for each query as the
+//! hickory client does, which leaves an entry per query in the conntrack of
+//! the router. The socket is only made again once it fails, keeps timing out,
+//! or the network changed.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use hickory_proto::op::{Message, Query};
+use tokio::{net::UdpSocket, sync::oneshot, task::JoinHandle};
+use tracing::{debug, trace};
+
+use crate::{
+    proxy::utils::{new_udp_socket, Interface},
+    Error,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// the timeouts in a row after which the socket is made again, in case the
+/// path to the upstream went away, e.g. with the NAT mapping on the way
+const MAX_TIMEOUTS: u32 = 3;
+
+/// the queries waiting for an answer, by the id they were sent with, with
+/// their questions the answer has to be to
+type Pending = Arc<Mutex<HashMap<u16, (Vec<Query>, oneshot::Sender<Message>)>>>;
+
+struct Conn {
+    socket: Arc<UdpSocket>,
+    pending: Pending,
+    reader: JoinHandle<()>,
+    timeouts: AtomicU32,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+pub struct UdpUpstream {
+    addr: SocketAddr,
+    conn: tokio::sync::Mutex<Option<Arc<Conn>>>,
+    /// the sockets made so far
+    created: AtomicU64,
+}
+
+impl UdpUpstream {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            conn: Default::default(),
+            created: AtomicU64::new(0),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn sockets_created(&self) -> u64 {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    /// the next query makes a new socket, e.g. once the network changed
+    pub async fn reset(&self) {
+        self.conn.lock().await.take();
+    }
+
+    /// `iface` is only used when a socket is made
+    pub async fn exchange(
+        &self,
+        msg: &Message,
+        iface: Option<Interface>,
+    ) -> anyhow::Result<Message> {
+        let conn = self.conn(iface).await?;
+
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut pending = conn.pending.lock().unwrap();
+            let mut id = rand::random::<u16>();
+            while pending.contains_key(&id) {
+                id = rand::random();
+            }
+            pending.insert(id, (msg.queries().to_vec(), tx));
+            id
+        };
+        let mut req = msg.clone();
+        req.set_id(id);
+
+        if let Err(e) = conn.socket.send(&req.to_vec()?).await {
+            conn.pending.lock().unwrap().remove(&id);
+            debug!("sending to dns upstream {} failed: {}", self.addr, e);
+            self.drop_conn(&conn).await;
+            return Err(e.into());
+        }
+
+        match tokio::time::timeout(TIMEOUT, rx).await {
+            Ok(Ok(mut answer)) => {
+                conn.timeouts.store(0, Ordering::Relaxed);
+                answer.set_id(msg.id());
+                Ok(answer)
+            }
+            // the socket failed, the next query makes a new one
+            Ok(Err(_)) => {
+                self.drop_conn(&conn).await;
+                Err(Error::DNSError(format!(
+                    "socket to dns upstream {} closed",
+                    self.addr
+                ))
+                .into())
+            }
+            Err(_) => {
+                conn.pending.lock().unwrap().remove(&id);
+                conn.timeouts.fetch_add(1, Ordering::Relaxed);
+                Err(
+                    Error::DNSError(format!("dns upstream {} timed out", self.addr))
+                        .into(),
+                )
+            }
+        }
+    }
+
+    async fn conn(&self, iface: Option<Interface>) -> io::Result<Arc<Conn>> {
+        let mut conn = self.conn.lock().await;
+        if let Some(c) = conn.as_ref().filter(|c| {
+            !c.reader.is_finished()
+                && c.timeouts.load(Ordering::Relaxed) < MAX_TIMEOUTS
+        }) {
+            return Ok(c.clone());
+        }
+
+        let src: SocketAddr = match self.addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = new_udp_socket(
+            Some(src),
+            iface,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await?;
+        socket.connect(self.addr).await?;
+        let n = self.created.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!("socket #{} to dns upstream {}", n, self.addr);
+
+        let socket = Arc::new(socket);
+        let pending = Pending::default();
+        let reader = tokio::spawn(read(socket.clone(), pending.clone(), self.addr));
+        let c = Arc::new(Conn {
+            socket,
+            pending,
+            reader,
+            timeouts: AtomicU32::new(0),
+        });
+        *conn = Some(c.clone());
+        Ok(c)
+    }
+
+    async fn drop_conn(&self, failed: &Arc<Conn>) {
+        let mut conn = self.conn.lock().await;
+        if conn.as_ref().is_some_and(|c| Arc::ptr_eq(c, failed)) {
+            conn.take();
+        }
+    }
+}
+
+/// hands the answers to the queries waiting for them until the socket
+/// fails, e.g. on an ICMP error, and the queries still waiting with it
+async fn read(socket: Arc<UdpSocket>, pending: Pending, addr: SocketAddr) {
+    let mut buf = vec![0; 65535];
+    loop {
+        let n = match socket.recv(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                debug!("receiving from dns upstream {} failed: {}", addr, e);
+                break;
+            }
+        };
+        let answer = match Message::from_vec(&buf[..n]) {
+            Ok(answer) => answer,
+            Err(e) => {
+                trace!("bad answer from dns upstream {}: {}", addr, e);
+                continue;
+            }
+        };
+        let mut waiting = pending.lock().unwrap();
+        let id = answer.id();
+        match waiting
+            .get(&id)
+            .map(|(queries, _)| same_questions(queries, answer.queries()))
+        {
+            Some(true) => {
+                if let Some((_, tx)) = waiting.remove(&id) {
+                    let _ = tx.send(answer);
+                }
+            }
+            // e.g. spoofed by someone who guessed the id, the query keeps
+            // waiting for the real one
+            Some(false) => {
+                trace!("answer {} from {} is to another question", id, addr)
+            }
+            None => trace!("answer {} from {} came too late", id, addr),
+        }
+    }
+    pending.lock().unwrap().clear();
+}
+
+/// the names are compared ignoring their case, which some servers change
+fn same_questions(sent: &[Query], answered: &[Query]) -> bool {
+    sent.len() == answered.len()
+        && sent.iter().zip(answered).all(|(a, b)| {
+            a.query_type() == b.query_type()
+                && a.query_class() == b.query_class()
+                && a.name() == b.name()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+    use hickory_proto::{
+        op::{Message, MessageType, Query},
+        rr,
+    };
+    use tokio::net::UdpSocket;
+
+    use crate::test_support::DnsServer;
+
+    use super::UdpUpstream;
+
+    fn query(id: u16) -> Message {
+        let mut m = Message::new();
+        m.set_id(id);
+        m.add_query(Query::query(
+            rr::Name::from_utf8("example.com.").unwrap(),
+            rr::RecordType::A,
+        ));
+        m
+    }
+
+    #[tokio::test]
+    async fn test_one_socket_for_all_queries() {
+        let server = DnsServer::new()
+            .answer_any("1.2.3.4".parse().unwrap())
+            .start()
+            .await;
+        let upstream = Arc::new(UdpUpstream::new(server.addr));
+
+        let answered = futures::stream::iter(0..10000u16)
+            .map(|id| {
+                let upstream = upstream.clone();
+                async move {
+                    let answer = upstream.exchange(&query(id), None).await.unwrap();
+                    assert_eq!(answer.id(), id);
+                    assert_eq!(answer.answer_count(), 1);
+                }
+            })
+            .buffer_unordered(100)
+            .count()
+            .await;
+
+        assert_eq!(answered, 10000);
+        assert_eq!(upstream.sockets_created(), 1);
+
+        upstream.reset().await;
+        upstream.exchange(&query(1), None).await.unwrap();
+        assert_eq!(upstream.sockets_created(), 2);
+        assert_eq!(server.requests().len(), 10001);
+    }
+
+    #[tokio::test]
+    async fn test_answer_to_another_question() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = UdpUpstream::new(server.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = vec![0; 512];
+            let (n, from) = server.recv_from(&mut buf).await.unwrap();
+            let req = Message::from_vec(&buf[..n]).unwrap();
+
+            // with the id right, but not the name
+            let mut spoofed = Message::new();
+            spoofed.set_id(req.id());
+            spoofed.set_message_type(MessageType::Response);
+            spoofed.add_query(Query::query(
+                rr::Name::from_utf8("example.org.").unwrap(),
+                rr::RecordType::A,
+            ));
+            server
+                .send_to(&spoofed.to_vec().unwrap(), from)
+                .await
+                .unwrap();
+
+            let mut real = req.clone();
+            real.set_message_type(MessageType::Response);
+            // with the case of the name changed
+            let mut q = req.queries()[0].clone();
+            q.set_name(rr::Name::from_utf8("EXAMPLE.com.").unwrap());
+            real.take_queries();
+            real.add_query(q);
+            real.add_answer(rr::Record::from_rdata(
+                rr::Name::from_utf8("example.com.").unwrap(),
+                60,
+                rr::RData::A(rr::rdata::A::new(1, 2, 3, 4)),
+            ));
+            server.send_to(&real.to_vec().unwrap(), from).await.unwrap();
+        });
+
+        let answer = upstream.exchange(&query(7), None).await.unwrap();
+        assert_eq!(answer.id(), 7);
+        assert_eq!(answer.answer_count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_new_socket_after_failure() {
+        // nothing listens there once it's dropped
+        let gone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = gone.local_addr().unwrap();
+        drop(gone);
+
+        let upstream = UdpUpstream::new(addr);
+        // the port unreachable ends the socket
+        assert!(upstream.exchange(&query(1), None).await.is_err());
+        assert!(upstream.exchange(&query(2), None).await.is_err());
+        assert_eq!(upstream.sockets_created(), 2);
+    }
+}
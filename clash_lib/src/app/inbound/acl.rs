@@ -0,0 +1,87 @@
+use std::net::IpAddr;
+
+use crate::{common::cidr_trie::CidrTrie, config::internal::config::ListenerAcl};
+
+/// The sources a listener takes connections and UDP packets from, by its
+/// `listener-acl`.
+pub struct Acl {
+    /// None allows all the sources not blocked
+    allowed: Option<CidrTrie>,
+    blocked: CidrTrie,
+}
+
+impl Acl {
+    pub fn new(acl: &ListenerAcl) -> Self {
+        let trie = |cidrs: &[ipnet::IpNet]| {
+            let mut trie = CidrTrie::new();
+            for cidr in cidrs {
+                trie.insert_net(*cidr);
+            }
+            trie
+        };
+        Self {
+            allowed: (!acl.allowed_ips.is_empty()).then(|| trie(&acl.allowed_ips)),
+            blocked: trie(&acl.blocked_ips),
+        }
+    }
+
+    /// blocked beats allowed. the dual-stack listeners see the IPv4 clients
+    /// as v4-mapped, they're matched as the IPv4 they are
+    pub fn allows(&self, src: IpAddr) -> bool {
+        let src = src.to_canonical();
+        !self.blocked.contains(src)
+            && self.allowed.as_ref().is_none_or(|x| x.contains(src))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::config::internal::config::ListenerAcl;
+
+    use super::Acl;
+
+    fn acl(allowed: &[&str], blocked: &[&str]) -> Acl {
+        Acl::new(&ListenerAcl {
+            allowed_ips: allowed.iter().map(|x| x.parse().unwrap()).collect(),
+            blocked_ips: blocked.iter().map(|x| x.parse().unwrap()).collect(),
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_block_beats_allow() {
+        let a = acl(&["10.0.0.0/8"], &["10.0.5.0/24"]);
+        assert!(a.allows(ip("10.1.2.3")));
+        assert!(!a.allows(ip("10.0.5.1")));
+        assert!(!a.allows(ip("192.168.1.1")));
+
+        // the same CIDR in both is blocked
+        let a = acl(&["10.0.0.0/8"], &["10.0.0.0/8"]);
+        assert!(!a.allows(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn test_empty_allowed() {
+        let a = acl(&[], &["10.0.5.0/24", "fd00::/8"]);
+        assert!(a.allows(ip("192.168.1.1")));
+        assert!(a.allows(ip("2001:db8::1")));
+        assert!(!a.allows(ip("10.0.5.1")));
+        assert!(!a.allows(ip("fd00::1")));
+
+        let a = acl(&[], &[]);
+        assert!(a.allows(ip("10.0.5.1")));
+    }
+
+    #[test]
+    fn test_v4_mapped() {
+        let a = acl(&["10.0.0.0/8"], &["10.0.5.0/24"]);
+        assert!(a.allows(ip("::ffff:10.1.2.3")));
+        assert!(!a.allows(ip("::ffff:10.0.5.1")));
+        assert!(!a.allows(ip("::ffff:192.168.1.1")));
+    }
+}
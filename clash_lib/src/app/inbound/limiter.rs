@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::warn;
+
+use super::acl::Acl;
+
+/// how often a listener is allowed to complain about rejected connections
+const REJECT_WARN_INTERVAL: Duration = Duration::from_secs(10);
+/// how many source IPs are named in a rejection warning
+const REJECT_WARN_TOP_SOURCES: usize = 3;
+
+#[derive(Serialize)]
+pub struct LimitStats {
+    pub active: usize,
+    pub limit: usize,
+    pub rejected: usize,
+    /// refused by the `listener-acl`, not counted in `rejected`
+    pub blocked: usize,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionLimiterStats {
+    pub global: LimitStats,
+    pub listeners: HashMap<String, LimitStats>,
+}
+
+/// Counts the inbound connections across all listeners.
+/// A limit of 0 means unlimited.
+pub struct ConnectionLimiter {
+    limit: usize,
+    active: AtomicUsize,
+    rejected: AtomicUsize,
+    listeners: Mutex<HashMap<String, Arc<ListenerLimiter>>>,
+}
+
+pub type ThreadSafeConnectionLimiter = Arc<ConnectionLimiter>;
+
+impl ConnectionLimiter {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            active: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            listeners: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the limiter of the listener with the given name.
+    /// The same limiter is reused when a listener is rebuilt so the connections
+    /// that are still alive are counted properly, only its limit and ACL are
+    /// updated.
+    pub fn for_listener(
+        self: &Arc<Self>,
+        name: &str,
+        limit: usize,
+        acl: Option<Arc<Acl>>,
+    ) -> Arc<ListenerLimiter> {
+        let mut listeners = self.listeners.lock().unwrap();
+        let listener = listeners
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                Arc::new(ListenerLimiter {
+                    name: name.to_owned(),
+                    limit: AtomicUsize::new(limit),
+                    active: AtomicUsize::new(0),
+                    rejected: AtomicUsize::new(0),
+                    blocked: AtomicUsize::new(0),
+                    global: self.clone(),
+                    acl: RwLock::new(None),
+                    reject_log: Mutex::new(RejectLog::default()),
+                    block_log: Mutex::new(RejectLog::default()),
+                })
+            })
+            .clone();
+        listener.limit.store(limit, Ordering::Relaxed);
+        *listener.acl.write().unwrap() = acl;
+        listener
+    }
+
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn stats(&self) -> ConnectionLimiterStats {
+        let listeners = self.listeners.lock().unwrap();
+        ConnectionLimiterStats {
+            global: LimitStats {
+                active: self.active.load(Ordering::Relaxed),
+                limit: self.limit,
+                rejected: self.rejected.load(Ordering::Relaxed),
+                blocked: listeners
+                    .values()
+                    .map(|x| x.blocked.load(Ordering::Relaxed))
+                    .sum(),
+            },
+            listeners: listeners
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        k.clone(),
+                        LimitStats {
+                            active: v.active.load(Ordering::Relaxed),
+                            limit: v.limit.load(Ordering::Relaxed),
+                            rejected: v.rejected.load(Ordering::Relaxed),
+                            blocked: v.blocked.load(Ordering::Relaxed),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Counts the connections a listener turned away, to warn about them once
+/// in a while rather than once each.
+#[derive(Default)]
+pub(crate) struct RejectLog {
+    last_warn: Option<Instant>,
+    sources: HashMap<IpAddr, usize>,
+}
+
+impl RejectLog {
+    /// counts a rejection from `src`, and once in a while returns how many
+    /// there were since the last time and the top sources, to warn about
+    pub(crate) fn record(&mut self, src: IpAddr) -> Option<(usize, String)> {
+        *self.sources.entry(src).or_default() += 1;
+
+        if self
+            .last_warn
+            .is_some_and(|x| x.elapsed() < REJECT_WARN_INTERVAL)
+        {
+            return None;
+        }
+
+        let total: usize = self.sources.values().sum();
+        let mut top = self.sources.drain().collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.cmp(&a.1));
+        top.truncate(REJECT_WARN_TOP_SOURCES);
+        self.last_warn = Some(Instant::now());
+
+        Some((
+            total,
+            top.iter()
+                .map(|(ip, n)| format!("{}({})", ip, n))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ))
+    }
+}
+
+/// Counts the inbound connections of a single listener.
+pub struct ListenerLimiter {
+    name: String,
+    limit: AtomicUsize,
+    active: AtomicUsize,
+    rejected: AtomicUsize,
+    blocked: AtomicUsize,
+    global: Arc<ConnectionLimiter>,
+    acl: RwLock<Option<Arc<Acl>>>,
+    reject_log: Mutex<RejectLog>,
+    block_log: Mutex<RejectLog>,
+}
+
+impl ListenerLimiter {
+    /// Reserves a slot for a new connection from `src`.
+    /// Returns None if the source isn't allowed by the ACL, or either the
+    /// listener or the global limit is reached, in which case the caller is
+    /// expected to drop the connection right away.
+    pub fn try_acquire(self: &Arc<Self>, src: IpAddr) -> Option<ConnectionGuard> {
+        if !self.allows(src) {
+            return None;
+        }
+        if !try_increment(&self.active, self.limit.load(Ordering::Relaxed)) {
+            self.on_rejected(src);
+            return None;
+        }
+        if !try_increment(&self.global.active, self.global.limit) {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+            self.global.rejected.fetch_add(1, Ordering::Relaxed);
+            self.on_rejected(src);
+            return None;
+        }
+
+        Some(ConnectionGuard(self.clone()))
+    }
+
+    /// Whether the ACL lets `src` in, for the UDP packets that don't take a
+    /// slot. A refused source is counted.
+    pub fn allows(&self, src: IpAddr) -> bool {
+        let allowed = self
+            .acl
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|x| x.allows(src));
+        if !allowed {
+            self.on_blocked(src);
+        }
+        allowed
+    }
+
+    fn on_rejected(&self, src: IpAddr) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+
+        if let Some((total, top)) = self.reject_log.lock().unwrap().record(src) {
+            warn!(
+                "{} listener connection limit reached, rejected {} connections, \
+                 top sources: {}",
+                self.name, total, top
+            );
+        }
+    }
+
+    fn on_blocked(&self, src: IpAddr) {
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+
+        if let Some((total, top)) = self.block_log.lock().unwrap().record(src) {
+            warn!(
+                "{} listener blocked {} connections by its ACL, top sources: {}",
+                self.name, total, top
+            );
+        }
+    }
+}
+
+fn try_increment(counter: &AtomicUsize, limit: usize) -> bool {
+    counter
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+            if limit != 0 && n >= limit {
+                None
+            } else {
+                Some(n + 1)
+            }
+        })
+        .is_ok()
+}
+
+/// Holds a connection slot until dropped.
+pub struct ConnectionGuard(Arc<ListenerLimiter>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+        self.0.global.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use crate::{app::inbound::acl::Acl, config::internal::config::ListenerAcl};
+
+    use super::ConnectionLimiter;
+
+    const SRC: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn test_listener_limit() {
+        let limiter = ConnectionLimiter::new(0);
+        let l = limiter.for_listener("SOCKS5", 2, None);
+
+        let g1 = l.try_acquire(SRC).expect("should acquire");
+        let _g2 = l.try_acquire(SRC).expect("should acquire");
+        assert!(l.try_acquire(SRC).is_none());
+
+        drop(g1);
+        assert!(l.try_acquire(SRC).is_some());
+
+        let stats = limiter.stats();
+        assert_eq!(stats.global.active, 1);
+        assert_eq!(stats.listeners["SOCKS5"].rejected, 1);
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let limiter = ConnectionLimiter::new(1);
+        let http = limiter.for_listener("HTTP", 0, None);
+        let socks = limiter.for_listener("SOCKS5", 0, None);
+
+        let _g = http.try_acquire(SRC).expect("should acquire");
+        assert!(socks.try_acquire(SRC).is_none());
+
+        let stats = limiter.stats();
+        assert_eq!(stats.global.active, 1);
+        assert_eq!(stats.global.rejected, 1);
+        assert_eq!(stats.listeners["SOCKS5"].active, 0);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = ConnectionLimiter::new(0);
+        let l = limiter.for_listener("Mixed", 0, None);
+        let guards = (0..1000)
+            .map(|_| l.try_acquire(SRC).expect("should acquire"))
+            .collect::<Vec<_>>();
+        assert_eq!(limiter.stats().global.active, guards.len());
+    }
+
+    #[test]
+    fn test_acl() {
+        let limiter = ConnectionLimiter::new(0);
+        let acl = Arc::new(Acl::new(&ListenerAcl {
+            allowed_ips: vec!["127.0.0.0/8".parse().unwrap()],
+            blocked_ips: vec!["127.0.0.2/32".parse().unwrap()],
+        }));
+        let l = limiter.for_listener("SOCKS5", 0, Some(acl));
+
+        let _g = l.try_acquire(SRC).expect("should acquire");
+        assert!(l.try_acquire("127.0.0.2".parse().unwrap()).is_none());
+        assert!(!l.allows("10.0.0.1".parse().unwrap()));
+
+        let stats = limiter.stats();
+        assert_eq!(stats.listeners["SOCKS5"].active, 1);
+        assert_eq!(stats.listeners["SOCKS5"].blocked, 2);
+        assert_eq!(stats.listeners["SOCKS5"].rejected, 0);
+
+        // rebuilt on reload without it
+        let l = limiter.for_listener("SOCKS5", 0, None);
+        assert!(l.try_acquire("127.0.0.2".parse().unwrap()).is_some());
+    }
+}
@@ -4,24 +4,44 @@ use tokio::sync::Mutex;
 use crate::{
     app::{
         dispatcher::Dispatcher,
-        inbound::network_listener::{ListenerType, NetworkInboundListener},
+        inbound::{
+            acl::Acl,
+            limiter::{
+                ConnectionLimiter, ListenerLimiter, ThreadSafeConnectionLimiter,
+            },
+            network_listener::{
+                listener_runners, ListenerType, NetworkInboundListener,
+            },
+            tls::ListenerTls,
+        },
     },
     common::{auth::ThreadSafeAuthenticator, errors::new_io_error},
-    config::internal::config::{BindAddress, Inbound},
+    config::{
+        def::SocksUdpSource,
+        internal::config::{BindAddress, Inbound, Tunnel},
+    },
+    proxy::tunnel,
     Error, Runner,
 };
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::Path, sync::Arc};
 
 pub struct InboundManager {
     network_listeners: HashMap<ListenerType, NetworkInboundListener>,
     dispatcher: Arc<Dispatcher>,
     bind_address: BindAddress,
     authenticator: ThreadSafeAuthenticator,
+    connection_limiter: ThreadSafeConnectionLimiter,
+    listener_max_connections: HashMap<String, usize>,
+    socks_udp_source: SocksUdpSource,
+    listener_tls: HashMap<String, Arc<ListenerTls>>,
+    listener_acl: HashMap<String, Arc<Acl>>,
+    tunnels: Vec<Tunnel>,
 }
 
+#[cfg_attr(not(feature = "api"), allow(dead_code))]
 pub type ThreadSafeInboundManager = Arc<Mutex<InboundManager>>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Ports {
     pub port: Option<u16>,
     #[serde(rename = "socks-port")]
@@ -39,14 +59,33 @@ impl InboundManager {
         inbound: Inbound,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        cwd: &Path,
     ) -> Result<Self, Error> {
         let network_listeners = HashMap::new();
+        // read on every reload, so renewed certificates are picked up
+        let listener_tls = inbound
+            .listener_tls
+            .iter()
+            .map(|(name, certs)| {
+                ListenerTls::load(certs, cwd).map(|x| (name.clone(), Arc::new(x)))
+            })
+            .collect::<Result<_, _>>()?;
 
         let mut s = Self {
             network_listeners,
             dispatcher,
             bind_address: inbound.bind_address,
             authenticator,
+            connection_limiter: ConnectionLimiter::new(inbound.max_connections),
+            listener_max_connections: inbound.listener_max_connections,
+            socks_udp_source: inbound.socks_udp_source,
+            listener_tls,
+            listener_acl: inbound
+                .listener_acl
+                .iter()
+                .map(|(name, acl)| (name.clone(), Arc::new(Acl::new(acl))))
+                .collect(),
+            tunnels: inbound.tunnels,
         };
 
         let ports = Ports {
@@ -66,6 +105,19 @@ impl InboundManager {
         for r in self.network_listeners.values() {
             runners.append(&mut r.listen()?);
         }
+        for t in &self.tunnels {
+            let listener = tunnel::Listener::new(
+                t.clone(),
+                self.dispatcher.clone(),
+                // not keyed like the others, so no listener-acl either
+                self.connection_limiter.for_listener("tunnel", 0, None),
+            );
+            runners.append(&mut listener_runners(
+                "tunnel",
+                t.address,
+                Arc::new(listener),
+            ));
+        }
 
         Ok(Box::pin(async move {
             let mut errors = Vec::new();
@@ -87,14 +139,21 @@ impl InboundManager {
     }
 
     /// API handlers below
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub fn get_bind_address(&self) -> &BindAddress {
         &self.bind_address
     }
 
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub fn set_bind_address(&mut self, bind_address: BindAddress) {
         self.bind_address = bind_address;
     }
 
+    pub fn connection_limiter(&self) -> ThreadSafeConnectionLimiter {
+        self.connection_limiter.clone()
+    }
+
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub fn get_ports(&self) -> Ports {
         let mut ports = Ports {
             port: None,
@@ -123,6 +182,24 @@ impl InboundManager {
         ports
     }
 
+    fn listener_limiter(
+        &self,
+        listener_type: ListenerType,
+        name: &str,
+    ) -> Arc<ListenerLimiter> {
+        let limit = self
+            .listener_max_connections
+            .get(listener_type.config_key())
+            .copied()
+            .unwrap_or_default();
+        let acl = self.listener_acl.get(listener_type.config_key()).cloned();
+        self.connection_limiter.for_listener(name, limit, acl)
+    }
+
+    fn listener_tls(&self, listener_type: ListenerType) -> Option<Arc<ListenerTls>> {
+        self.listener_tls.get(listener_type.config_key()).cloned()
+    }
+
     pub fn rebuild_listeners(&mut self, ports: Ports) {
         let mut network_listeners = HashMap::new();
         if let Some(http_port) = ports.port {
@@ -135,6 +212,9 @@ impl InboundManager {
                     listener_type: ListenerType::Http,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    limiter: self.listener_limiter(ListenerType::Http, "HTTP"),
+                    socks_udp_source: self.socks_udp_source,
+                    tls: self.listener_tls(ListenerType::Http),
                 },
             );
         }
@@ -149,6 +229,9 @@ impl InboundManager {
                     listener_type: ListenerType::Socks5,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    limiter: self.listener_limiter(ListenerType::Socks5, "SOCKS5"),
+                    socks_udp_source: self.socks_udp_source,
+                    tls: self.listener_tls(ListenerType::Socks5),
                 },
             );
         }
@@ -163,6 +246,9 @@ impl InboundManager {
                     listener_type: ListenerType::Mixed,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    limiter: self.listener_limiter(ListenerType::Mixed, "Mixed"),
+                    socks_udp_source: self.socks_udp_source,
+                    tls: self.listener_tls(ListenerType::Mixed),
                 },
             );
         }
@@ -177,6 +263,9 @@ impl InboundManager {
                     listener_type: ListenerType::Tproxy,
                     dispatcher: self.dispatcher.clone(),
                     authenticator: self.authenticator.clone(),
+                    limiter: self.listener_limiter(ListenerType::Tproxy, "TProxy"),
+                    socks_udp_source: self.socks_udp_source,
+                    tls: None,
                 },
             );
         }
@@ -1,2 +1,5 @@
+pub mod acl;
+pub mod limiter;
 pub mod manager;
 pub mod network_listener;
+pub mod tls;
@@ -1,5 +1,7 @@
 use crate::{
-    common::auth::ThreadSafeAuthenticator, config::internal::config::BindAddress,
+    app::inbound::{limiter::ListenerLimiter, tls::ListenerTls},
+    common::auth::ThreadSafeAuthenticator,
+    config::{def::SocksUdpSource, internal::config::BindAddress},
 };
 
 use crate::proxy::{http, mixed, socks, AnyInboundListener};
@@ -13,7 +15,7 @@ use network_interface::{Addr, NetworkInterfaceConfig};
 use tracing::{info, warn};
 
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
 };
 
@@ -25,6 +27,18 @@ pub enum ListenerType {
     Tproxy,
 }
 
+impl ListenerType {
+    /// the key used for this listener in `listener-max-connections`
+    pub fn config_key(&self) -> &'static str {
+        match self {
+            ListenerType::Http => "http",
+            ListenerType::Socks5 => "socks",
+            ListenerType::Mixed => "mixed",
+            ListenerType::Tproxy => "tproxy",
+        }
+    }
+}
+
 pub struct NetworkInboundListener {
     pub name: String,
     pub bind_addr: BindAddress,
@@ -32,6 +46,9 @@ pub struct NetworkInboundListener {
     pub listener_type: ListenerType,
     pub dispatcher: Arc<Dispatcher>,
     pub authenticator: ThreadSafeAuthenticator,
+    pub limiter: Arc<ListenerLimiter>,
+    pub socks_udp_source: SocksUdpSource,
+    pub tls: Option<Arc<ListenerTls>>,
 }
 
 impl NetworkInboundListener {
@@ -68,40 +85,58 @@ impl NetworkInboundListener {
                             continue;
                         }
 
-                        self.build_and_insert_listener(&mut runners, ip.unwrap());
+                        self.build_and_insert_listener(
+                            &mut runners,
+                            ip.unwrap().into(),
+                        );
                     }
                 }
                 #[cfg(not(target_os = "ios"))]
                 {
-                    let ip = "0.0.0.0".parse().expect("must parse");
+                    // dual-stack, but tproxy only redirects IPv4
+                    let ip = if self.listener_type == ListenerType::Tproxy {
+                        Ipv4Addr::UNSPECIFIED.into()
+                    } else {
+                        Ipv6Addr::UNSPECIFIED.into()
+                    };
                     self.build_and_insert_listener(&mut runners, ip);
                 }
             }
             BindAddress::One(iface) => match iface {
-                Interface::IpAddr(ip) => match ip {
-                    IpAddr::V4(ip) => {
-                        self.build_and_insert_listener(&mut runners, *ip)
-                    }
-                    IpAddr::V6(_) => unreachable!("unsupported listening v6"),
-                },
+                Interface::IpAddr(ip) => {
+                    self.build_and_insert_listener(&mut runners, *ip)
+                }
                 Interface::Name(iface) => {
-                    let ip = network_interface::NetworkInterface::show()
+                    let ips = network_interface::NetworkInterface::show()
                         .expect("list interfaces")
                         .into_iter()
                         .filter(|x| &x.name == iface)
                         .flat_map(|x| x.addr)
                         .map(|x| match x {
-                            Addr::V4(v4) => v4.ip,
-                            Addr::V6(_) => unreachable!(),
+                            Addr::V4(v4) => IpAddr::V4(v4.ip),
+                            Addr::V6(v6) => IpAddr::V6(v6.ip),
                         })
-                        .find(|x| {
-                            !x.is_unspecified()
-                                && !x.is_link_local()
-                                && !x.is_multicast()
+                        .filter(|x| match x {
+                            IpAddr::V4(x) => {
+                                !x.is_unspecified()
+                                    && !x.is_link_local()
+                                    && !x.is_multicast()
+                            }
+                            IpAddr::V6(x) => {
+                                !x.is_unspecified()
+                                    && !x.is_unicast_link_local()
+                                    && !x.is_multicast()
+                            }
                         })
+                        .collect::<Vec<_>>();
+                    // the IPv4 one if there is one, as before
+                    let ip = ips
+                        .iter()
+                        .find(|x| x.is_ipv4())
+                        .or(ips.first())
                         .expect("no valid ip");
 
-                    self.build_and_insert_listener(&mut runners, ip);
+                    self.build_and_insert_listener(&mut runners, *ip);
                 }
             },
         };
@@ -109,22 +144,30 @@ impl NetworkInboundListener {
         Ok(runners)
     }
 
-    fn build_and_insert_listener(&self, runners: &mut Vec<Runner>, ip: Ipv4Addr) {
+    fn build_and_insert_listener(&self, runners: &mut Vec<Runner>, ip: IpAddr) {
         let listener: AnyInboundListener = match self.listener_type {
             ListenerType::Http => Arc::new(http::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.limiter.clone(),
+                self.tls.clone(),
             )),
             ListenerType::Socks5 => Arc::new(socks::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.limiter.clone(),
+                self.socks_udp_source,
+                self.tls.clone(),
             )),
             ListenerType::Mixed => Arc::new(mixed::Listener::new(
                 (ip, self.port).into(),
                 self.dispatcher.clone(),
                 self.authenticator.clone(),
+                self.limiter.clone(),
+                self.socks_udp_source,
+                self.tls.clone(),
             )),
             ListenerType::Tproxy => {
                 #[cfg(target_os = "linux")]
@@ -132,6 +175,7 @@ impl NetworkInboundListener {
                     Arc::new(tproxy::Listener::new(
                         (ip, self.port).into(),
                         self.dispatcher.clone(),
+                        self.limiter.clone(),
                     ))
                 }
                 #[cfg(not(target_os = "linux"))]
@@ -142,37 +186,53 @@ impl NetworkInboundListener {
             }
         };
 
-        if listener.handle_tcp() {
-            let listener_type = self.listener_type.clone();
-            info!("{} TCP listening at: {}:{}", self.name, ip, self.port);
-
-            let tcp_listener = listener.clone();
-            runners.push(
-                async move {
-                    tcp_listener.listen_tcp().await.map_err(|e| {
-                        warn!(
-                            "handler of {:?} tcp listen failed: {}",
-                            listener_type, e
-                        );
-                        e.into()
-                    })
-                }
-                .boxed(),
-            );
-        }
+        runners.append(&mut listener_runners(
+            &self.name,
+            (ip, self.port).into(),
+            listener,
+        ));
+    }
+}
 
-        if listener.handle_udp() {
-            info!("{} UDP listening at: {}:{}", self.name, ip, self.port);
-            let udp_listener = listener.clone();
-            runners.push(
-                async move {
-                    udp_listener.listen_udp().await.map_err(|e| {
-                        warn!("handler udp listen failed: {}", e);
-                        e.into()
-                    })
-                }
-                .boxed(),
-            );
-        }
+/// the runners of the TCP and UDP sides of a listener, as it handles them
+pub fn listener_runners(
+    name: &str,
+    addr: SocketAddr,
+    listener: AnyInboundListener,
+) -> Vec<Runner> {
+    let mut runners = Vec::<Runner>::new();
+
+    if listener.handle_tcp() {
+        info!("{} TCP listening at: {}", name, addr);
+
+        let name = name.to_owned();
+        let tcp_listener = listener.clone();
+        runners.push(
+            async move {
+                tcp_listener.listen_tcp().await.map_err(|e| {
+                    warn!("handler of {} tcp listen failed: {}", name, e);
+                    e.into()
+                })
+            }
+            .boxed(),
+        );
     }
+
+    if listener.handle_udp() {
+        info!("{} UDP listening at: {}", name, addr);
+
+        let name = name.to_owned();
+        let udp_listener = listener.clone();
+        runners.push(
+            async move {
+                udp_listener.listen_udp().await.map_err(|e| {
+                    warn!("handler of {} udp listen failed: {}", name, e);
+                    e.into()
+                })
+            }
+            .boxed(),
+        );
+    }
+
+    runners
 }
@@ -0,0 +1,247 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rustls::{
+    client::verify_server_name,
+    pki_types::ServerName,
+    server::{ClientHello, ParsedCertificate, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, warn};
+
+use crate::{config::def::ListenerCertificate, Error};
+
+use super::limiter::RejectLog;
+
+/// the first byte of a TLS record carrying a handshake
+const TLS_HANDSHAKE: u8 = 0x16;
+/// how long a client has to get through the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The TLS a listener terminates before the proxy protocol. The certificates
+/// are read once, a reload builds a new one.
+pub struct ListenerTls {
+    acceptor: TlsAcceptor,
+    /// the plain connections, scanners send plenty of them
+    plain_log: Mutex<RejectLog>,
+}
+
+impl ListenerTls {
+    pub fn load(certs: &[ListenerCertificate], cwd: &Path) -> Result<Self, Error> {
+        let keys = certs
+            .iter()
+            .map(|c| load_certified_key(c, cwd).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err(Error::InvalidConfig("no listener certificate".to_owned()));
+        }
+        // no ALPN, the proxy protocols don't negotiate one
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SniResolver { keys }));
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            plain_log: Default::default(),
+        })
+    }
+
+    /// The stream to speak the proxy protocol on, None when the client
+    /// doesn't speak TLS or the handshake fails. Plain connections are closed
+    /// right away rather than waiting for a ClientHello that never comes.
+    pub async fn accept(
+        &self,
+        s: TcpStream,
+        src: SocketAddr,
+        listener: &str,
+    ) -> Option<TlsStream<TcpStream>> {
+        let mut first = [0; 1];
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, s.peek(&mut first)).await {
+            Ok(Ok(1)) if first[0] == TLS_HANDSHAKE => {}
+            Ok(Ok(1)) => {
+                let log = self.plain_log.lock().unwrap().record(src.ip());
+                if let Some((total, top)) = log {
+                    warn!(
+                        "{} listener rejected {} plain connections, it takes TLS \
+                         only, top sources: {}",
+                        listener, total, top
+                    );
+                }
+                return None;
+            }
+            _ => return None,
+        }
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(s)).await
+        {
+            Ok(Ok(s)) => Some(s),
+            Ok(Err(e)) => {
+                debug!(
+                    "{} listener TLS handshake with {} failed: {}",
+                    listener, src, e
+                );
+                None
+            }
+            Err(_) => {
+                debug!("{} listener TLS handshake with {} timed out", listener, src);
+                None
+            }
+        }
+    }
+}
+
+fn load_certified_key(
+    c: &ListenerCertificate,
+    cwd: &Path,
+) -> Result<CertifiedKey, Error> {
+    let open = |path: &str| {
+        File::open(cwd.join(path)).map(BufReader::new).map_err(|e| {
+            Error::InvalidConfig(format!("failed to read {}: {}", path, e))
+        })
+    };
+    let chain = rustls_pemfile::certs(&mut open(&c.certificate)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            Error::InvalidConfig(format!(
+                "invalid certificate {}: {}",
+                c.certificate, e
+            ))
+        })?;
+    if chain.is_empty() {
+        return Err(Error::InvalidConfig(format!(
+            "no certificate in {}",
+            c.certificate
+        )));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&c.private_key)?)
+        .ok()
+        .flatten()
+        .ok_or_else(|| {
+            Error::InvalidConfig(format!("no private key in {}", c.private_key))
+        })?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key).map_err(|e| {
+        Error::InvalidConfig(format!("invalid private key {}: {}", c.private_key, e))
+    })?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+/// picks the first certificate valid for the SNI, or the first of all
+#[derive(Debug)]
+struct SniResolver {
+    keys: Vec<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn pick(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let name = sni.and_then(|x| ServerName::try_from(x.to_owned()).ok());
+        name.and_then(|name| {
+            self.keys.iter().find(|k| {
+                k.end_entity_cert()
+                    .and_then(ParsedCertificate::try_from)
+                    .and_then(|c| verify_server_name(&c, &name))
+                    .is_ok()
+            })
+        })
+        .or(self.keys.first())
+        .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.pick(hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use rustls::pki_types::ServerName;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsConnector;
+
+    use crate::{common::tls::DummyTlsVerifier, config::def::ListenerCertificate};
+
+    use super::ListenerTls;
+
+    fn certs_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../clash/tests/data/config")
+    }
+
+    fn example_org() -> ListenerCertificate {
+        ListenerCertificate {
+            certificate: "example.org.pem".to_owned(),
+            private_key: "example.org-key.pem".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let tls =
+            Arc::new(ListenerTls::load(&[example_org()], &certs_dir()).unwrap());
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((s, src)) = l.accept().await {
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    if let Some(mut s) = tls.accept(s, src, "test").await {
+                        let (mut r, mut w) = tokio::io::split(&mut s);
+                        let _ = tokio::io::copy(&mut r, &mut w).await;
+                    }
+                });
+            }
+        });
+
+        // closed without waiting for the timeout
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        s.write_all(b"\x05\x01\x00").await.unwrap();
+        let mut buf = vec![];
+        let n = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            s.read_to_end(&mut buf),
+        )
+        .await
+        .expect("plain connection is closed quickly");
+        assert_eq!(n.unwrap_or_default(), 0);
+
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(DummyTlsVerifier::new()))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        // the only certificate is served to other names too
+        for sni in ["example.org", "other.example"] {
+            let s = TcpStream::connect(addr).await.unwrap();
+            let mut s = connector
+                .connect(ServerName::try_from(sni).unwrap().to_owned(), s)
+                .await
+                .unwrap();
+            s.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            assert!(s.get_ref().1.alpn_protocol().is_none());
+        }
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert!(ListenerTls::load(&[], &certs_dir()).is_err());
+        let swapped = ListenerCertificate {
+            certificate: "example.org-key.pem".to_owned(),
+            private_key: "example.org.pem".to_owned(),
+        };
+        assert!(ListenerTls::load(&[swapped], &certs_dir()).is_err());
+    }
+}
@@ -1,30 +1,42 @@
-use std::io::IsTerminal;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::IsTerminal,
+    sync::{Arc, Mutex},
+};
 
-use crate::def::LogLevel;
+use crate::{
+    config::internal::config::General,
+    def::{LogFormat, LogLevel},
+};
 use opentelemetry::{
     global::{self},
     trace::TracerProvider as _,
     KeyValue,
 };
-use opentelemetry_otlp::SpanExporter;
-use opentelemetry_sdk::{trace::TracerProvider, Resource};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, trace::TracerProvider, Resource,
+};
 use opentelemetry_semantic_conventions::{
     resource::{DEPLOYMENT_ENVIRONMENT_NAME, SERVICE_NAME, SERVICE_VERSION},
     SCHEMA_URL,
 };
 use serde::Serialize;
-use tokio::sync::broadcast::Sender;
+use tokio::sync::broadcast::{Receiver, Sender};
 
-use tracing::debug;
+use tracing::{debug, warn};
 use tracing_appender::non_blocking::WorkerGuard;
 #[cfg(target_os = "ios")]
 use tracing_oslog::OsLogger;
 use tracing_subscriber::{
     filter::{self, filter_fn, Directive},
     prelude::*,
-    EnvFilter, Layer,
+    reload, EnvFilter, Layer,
 };
 
+mod rotate;
+mod syslog;
+
 impl From<LogLevel> for filter::LevelFilter {
     fn from(level: LogLevel) -> Self {
         match level {
@@ -44,13 +56,124 @@ pub struct LogEvent {
     pub level: LogLevel,
     #[serde(rename = "payload")]
     pub msg: String,
+    /// the fields of the event besides the message
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, serde_json::Value>,
+}
+
+/// how many recent events are kept for late `/logs` subscribers by default
+pub const DEFAULT_LOG_BUFFER_SIZE: usize = 1000;
+/// the largest `log-buffer-size` taken, a bigger one is lowered to it
+pub const MAX_LOG_BUFFER_SIZE: usize = 100_000;
+/// the most bytes of messages and fields kept, however many events that is
+const RECENT_LOGS_MAX_BYTES: usize = 1024 * 1024;
+
+impl LogEvent {
+    /// roughly the memory taken by the text of the event
+    fn size(&self) -> usize {
+        self.msg.len()
+            + self
+                .fields
+                .iter()
+                .map(|(k, v)| k.len() + v.to_string().len())
+                .sum::<usize>()
+    }
 }
 
-pub struct EventCollector(Vec<Sender<LogEvent>>);
+/// The most recent log events, so a client subscribing to the logs right
+/// after something went wrong still sees it.
+pub struct RecentLogs {
+    events: Mutex<(VecDeque<(LogEvent, usize)>, usize)>,
+    capacity: usize,
+    max_bytes: usize,
+}
+
+pub type ThreadSafeRecentLogs = Arc<RecentLogs>;
+
+impl RecentLogs {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_max_bytes(capacity, RECENT_LOGS_MAX_BYTES)
+    }
+
+    fn with_max_bytes(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            events: Mutex::new((VecDeque::new(), 0)),
+            capacity,
+            max_bytes,
+        }
+    }
+
+    /// sends `event` to `receivers` and keeps it, under the lock `subscribe`
+    /// takes so a new subscriber gets every event exactly once
+    fn push(&self, event: LogEvent, receivers: &[Sender<LogEvent>]) {
+        let mut guard = self.events.lock().unwrap();
+        for tx in receivers {
+            _ = tx.send(event.clone());
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        let size = event.size();
+        let (events, bytes) = &mut *guard;
+        while !events.is_empty()
+            && (events.len() >= self.capacity || *bytes + size > self.max_bytes)
+        {
+            if let Some((_, s)) = events.pop_front() {
+                *bytes -= s;
+            }
+        }
+        *bytes += size;
+        events.push_back((event, size));
+    }
+
+    /// the recent events and a receiver of the ones logged after them
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn subscribe(
+        &self,
+        tx: &Sender<LogEvent>,
+    ) -> (Vec<LogEvent>, Receiver<LogEvent>) {
+        let guard = self.events.lock().unwrap();
+        let rx = tx.subscribe();
+        (guard.0.iter().map(|(e, _)| e.clone()).collect(), rx)
+    }
+
+    #[cfg(test)]
+    fn snapshot(&self) -> Vec<LogEvent> {
+        self.events
+            .lock()
+            .unwrap()
+            .0
+            .iter()
+            .map(|(e, _)| e.clone())
+            .collect()
+    }
+
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn clear(&self) {
+        *self.events.lock().unwrap() = Default::default();
+    }
+}
+
+impl Default for RecentLogs {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOG_BUFFER_SIZE)
+    }
+}
+
+pub struct EventCollector {
+    receivers: Vec<Sender<LogEvent>>,
+    recent: ThreadSafeRecentLogs,
+}
 
 impl EventCollector {
-    pub fn new(recivers: Vec<Sender<LogEvent>>) -> Self {
-        Self(recivers)
+    pub fn new(
+        recivers: Vec<Sender<LogEvent>>,
+        recent: ThreadSafeRecentLogs,
+    ) -> Self {
+        Self {
+            receivers: recivers,
+            recent,
+        }
     }
 }
 
@@ -63,8 +186,8 @@ where
         event: &tracing::Event<'_>,
         _ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
-        let mut strs = vec![];
-        event.record(&mut EventVisitor(&mut strs));
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
 
         let event = LogEvent {
             level: match *event.metadata().level() {
@@ -74,51 +197,128 @@ where
                 tracing::Level::DEBUG => LogLevel::Debug,
                 tracing::Level::TRACE => LogLevel::Trace,
             },
-            msg: strs.join(" "),
+            msg: visitor.message,
+            fields: visitor.fields,
         };
-        for tx in &self.0 {
-            _ = tx.send(event.clone());
-        }
+        self.recent.push(event, &self.receivers);
     }
 }
 
-pub fn setup_logging(
+/// `clash=<level>`, then the `log-directives` of the config, then RUST_LOG,
+/// a later directive for the same target replacing an earlier one
+fn make_filter(level: LogLevel, directives: &[String]) -> EnvFilter {
+    let env = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+    std::iter::once(format!("clash={}", level))
+        .chain(directives.iter().cloned())
+        .chain(env.split(',').map(ToOwned::to_owned))
+        .filter_map(|x| x.trim().parse::<Directive>().ok())
+        .fold(EnvFilter::default(), EnvFilter::add_directive)
+}
+
+/// Changes the filter of the logging set up by `setup_logging` at runtime.
+#[derive(Clone)]
+pub struct LogLevelHandle {
+    reload: Arc<dyn Fn(EnvFilter) -> anyhow::Result<()> + Send + Sync>,
+    directives: Arc<Mutex<Vec<String>>>,
+}
+
+impl LogLevelHandle {
+    pub fn set_level(&self, level: LogLevel) -> anyhow::Result<()> {
+        let filter = make_filter(level, &self.directives.lock().unwrap());
+        debug!("log filter: {}", filter);
+        (self.reload)(filter)
+    }
+
+    /// applied by the next `set_level`
+    pub fn set_directives(&self, directives: Vec<String>) {
+        *self.directives.lock().unwrap() = directives;
+    }
+}
+
+fn reloadable_filter<S>(
     level: LogLevel,
+    directives: Vec<String>,
+) -> (reload::Layer<EnvFilter, S>, LogLevelHandle)
+where
+    S: tracing::Subscriber + 'static,
+{
+    let (filter, handle) = reload::Layer::new(make_filter(level, &directives));
+    let handle = LogLevelHandle {
+        reload: Arc::new(move |f| {
+            handle
+                .reload(f)
+                .map_err(|x| anyhow!("failed to change log level: {}", x))
+        }),
+        directives: Arc::new(Mutex::new(directives)),
+    };
+    (filter, handle)
+}
+
+/// without an endpoint the exporter falls back to the OTEL_EXPORTER_OTLP_*
+/// env vars
+fn tracer_provider(endpoint: Option<&str>) -> anyhow::Result<TracerProvider> {
+    let mut exporter = SpanExporter::builder().with_tonic();
+    if let Some(endpoint) = endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+    let exporter = exporter.build()?;
+
+    Ok(TracerProvider::builder()
+        .with_resource(Resource::from_schema_url(
+            [
+                KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
+                KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
+                KeyValue::new(
+                    DEPLOYMENT_ENVIRONMENT_NAME,
+                    std::env::var("PROFILE").unwrap_or_default(),
+                ),
+            ],
+            SCHEMA_URL,
+        ))
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .build())
+}
+
+pub fn setup_logging(
+    general: &General,
     collector: EventCollector,
     cwd: &str,
     log_file: Option<String>,
-) -> anyhow::Result<Option<WorkerGuard>> {
-    let filter = EnvFilter::builder()
-        .with_default_directive(
-            format!("clash={}", level).parse::<Directive>().unwrap(),
-        )
-        .from_env_lossy();
-
-    let jaeger = if std::env::var("JAEGER_ENABLED").is_ok() {
-        global::set_text_map_propagator(
-            opentelemetry_jaeger_propagator::Propagator::new(),
-        );
+) -> anyhow::Result<(Option<WorkerGuard>, LogLevelHandle)> {
+    let level = general.log_level;
+    let format = general.log_format;
+    let log_directives = general.log_directives.clone();
+    let otlp_endpoint = general.otlp_endpoint.clone();
+    let filter_display = make_filter(level, &log_directives).to_string();
+    let (filter, level_handle) = reloadable_filter(level, log_directives);
+
+    // reported once logging is up, stdout keeps working either way
+    let mut warnings = vec![];
+
+    let jaeger_enabled = std::env::var("JAEGER_ENABLED").is_ok();
+    let otlp_endpoint = std::env::var("OTLP_ENDPOINT").ok().or(otlp_endpoint);
+    let otel = if jaeger_enabled || otlp_endpoint.is_some() {
+        if jaeger_enabled {
+            global::set_text_map_propagator(
+                opentelemetry_jaeger_propagator::Propagator::new(),
+            );
+        } else {
+            global::set_text_map_propagator(TraceContextPropagator::new());
+        }
 
-        let exporter = SpanExporter::builder().with_tonic().build()?;
-
-        let provider = TracerProvider::builder()
-            .with_resource(Resource::from_schema_url(
-                [
-                    KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
-                    KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
-                    KeyValue::new(
-                        DEPLOYMENT_ENVIRONMENT_NAME,
-                        std::env::var("PROFILE").unwrap_or_default(),
-                    ),
-                ],
-                SCHEMA_URL,
-            ))
-            .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
-            .build();
-
-        global::set_tracer_provider(provider.clone());
-
-        Some(tracing_opentelemetry::layer().with_tracer(provider.tracer("clash-rs")))
+        match tracer_provider(otlp_endpoint.as_deref()) {
+            Ok(provider) => {
+                global::set_tracer_provider(provider.clone());
+                Some(
+                    tracing_opentelemetry::layer()
+                        .with_tracer(provider.tracer("clash-rs")),
+                )
+            }
+            Err(e) => {
+                warnings.push(format!("failed to setup span exporter: {}", e));
+                None
+            }
+        }
     } else {
         None
     };
@@ -129,7 +329,23 @@ pub fn setup_logging(
     let ios_os_log =
         tracing_subscriber::fmt::Layer::new().with_writer(std::io::empty);
 
-    let (appender, g) = if let Some(log_file) = log_file {
+    let (appender, g) = if let Some(f) = &general.log_file {
+        let path = std::path::Path::new(cwd).join(&f.path);
+        match rotate::RotatingFile::open(path.clone(), f.max_size, f.max_backups) {
+            Ok(file) => {
+                let (non_blocking, guard) = tracing_appender::non_blocking(file);
+                (Some(non_blocking), Some(guard))
+            }
+            Err(e) => {
+                warnings.push(format!(
+                    "failed to open log file {}, logging to stdout only: {}",
+                    path.display(),
+                    e
+                ));
+                (None, None)
+            }
+        }
+    } else if let Some(log_file) = log_file {
         let file_appender = tracing_appender::rolling::daily(cwd, log_file);
         let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
         (Some(non_blocking), Some(guard))
@@ -137,6 +353,12 @@ pub fn setup_logging(
         (None, None)
     };
 
+    let syslog = general.log_syslog.clone().and_then(|target| {
+        syslog::SyslogLayer::new(target)
+            .map_err(|e| warnings.push(format!("failed to start syslog: {}", e)))
+            .ok()
+    });
+
     let console_layer = if cfg!(feature = "tracing") {
         Some(console_subscriber::spawn())
     } else {
@@ -150,21 +372,32 @@ pub fn setup_logging(
         }));
 
     let subscriber = tracing_subscriber::registry()
-        .with(jaeger)
+        .with(otel)
         .with(filter)
         .with(collector)
+        .with(syslog)
         .with(console_layer)
         .with(appender.map(|x| {
-            tracing_subscriber::fmt::Layer::new()
-                .with_ansi(false)
-                .compact()
-                .with_file(true)
-                .with_line_number(true)
-                .with_level(true)
-                .with_writer(x)
+            match format {
+                LogFormat::Text => tracing_subscriber::fmt::Layer::new()
+                    .with_ansi(false)
+                    .compact()
+                    .with_file(true)
+                    .with_line_number(true)
+                    .with_level(true)
+                    .with_writer(x)
+                    .boxed(),
+                LogFormat::Json => tracing_subscriber::fmt::Layer::new()
+                    .json()
+                    .flatten_event(true)
+                    .with_file(true)
+                    .with_line_number(true)
+                    .with_writer(x)
+                    .boxed(),
+            }
         }))
-        .with(
-            tracing_subscriber::fmt::Layer::new()
+        .with(match format {
+            LogFormat::Text => tracing_subscriber::fmt::Layer::new()
                 .with_ansi(std::io::stdout().is_terminal())
                 .compact()
                 .with_target(cfg!(debug_assertions))
@@ -172,38 +405,83 @@ pub fn setup_logging(
                 .with_line_number(true)
                 .with_level(true)
                 .with_thread_ids(cfg!(debug_assertions))
-                .with_writer(std::io::stdout),
-        )
+                .with_writer(std::io::stdout)
+                .boxed(),
+            // log shippers want the same keys whether it's a debug build or not
+            LogFormat::Json => tracing_subscriber::fmt::Layer::new()
+                .json()
+                .flatten_event(true)
+                .with_file(true)
+                .with_line_number(true)
+                .with_writer(std::io::stdout)
+                .boxed(),
+        })
         .with(ios_os_log)
         .with(opentelemetry_layer);
 
     tracing::subscriber::set_global_default(subscriber)
         .map_err(|x| anyhow!("setup logging error: {}", x))?;
 
+    for w in warnings {
+        warn!("{}", w);
+    }
+    debug!("log filter: {}", filter_display);
+
     if let Ok(jager_endpiont) = std::env::var("JAGER_ENDPOINT") {
         debug!("jager endpoint: {}", jager_endpiont);
     }
 
-    Ok(g)
+    Ok((g, level_handle))
 }
 
-struct EventVisitor<'a>(&'a mut Vec<String>);
+/// Collects the message and the other fields of an event, numbers and bools
+/// keep their type so they come out as such in the json of `/logs`.
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    fields: BTreeMap<String, serde_json::Value>,
+}
 
-impl tracing::field::Visit for EventVisitor<'_> {
+impl EventVisitor {
+    fn record(&mut self, field: &tracing::field::Field, value: serde_json::Value) {
+        if field.name() == "message" {
+            self.message = match value {
+                serde_json::Value::String(s) => s,
+                v => v.to_string(),
+            };
+        } else {
+            self.fields.insert(field.name().to_owned(), value);
+        }
+    }
+}
+
+impl tracing::field::Visit for EventVisitor {
     fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
-        println!("bool {} = {}", field.name(), value);
+        self.record(field, value.into());
     }
 
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        println!("i64 {} = {}", field.name(), value);
+        self.record(field, value.into());
     }
 
     fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
-        println!("u64 {} = {}", field.name(), value);
+        self.record(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.record(field, value.into());
     }
 
     fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
-        println!("str {} = {}", field.name(), value);
+        self.record(field, value.into());
+    }
+
+    fn record_error(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &(dyn std::error::Error + 'static),
+    ) {
+        self.record(field, value.to_string().into());
     }
 
     fn record_debug(
@@ -211,30 +489,139 @@ impl tracing::field::Visit for EventVisitor<'_> {
         field: &tracing::field::Field,
         value: &dyn std::fmt::Debug,
     ) {
-        if field.name() == "message" {
-            self.0.push(format!("{:?}", value));
-        } else {
-            println!("debug {} = {:?}", field.name(), value);
-        }
+        self.record(field, format!("{:?}", value).into());
     }
+}
 
-    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        println!("f64 {} = {}", field.name(), value);
+#[cfg(test)]
+mod tests {
+    use crate::def::LogLevel;
+
+    use std::sync::Arc;
+
+    use tracing_subscriber::prelude::*;
+
+    use super::{reloadable_filter, EventCollector, LogEvent, RecentLogs};
+
+    #[test]
+    fn test_reload_level() {
+        let recent = Arc::new(RecentLogs::new(10));
+        let (filter, handle) = reloadable_filter(LogLevel::Info, vec![]);
+        let subscriber = tracing_subscriber::registry()
+            .with(filter)
+            .with(EventCollector::new(vec![], recent.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("hidden");
+            handle.set_level(LogLevel::Debug).unwrap();
+            tracing::debug!("shown");
+            handle.set_level(LogLevel::Silent).unwrap();
+            tracing::error!("hidden");
+        });
+
+        let msgs = recent
+            .snapshot()
+            .into_iter()
+            .map(|x| x.msg)
+            .collect::<Vec<_>>();
+        assert_eq!(msgs, vec!["shown"]);
+    }
+
+    #[test]
+    fn test_event_fields() {
+        let recent = Arc::new(RecentLogs::new(10));
+        let (tx, mut rx) = tokio::sync::broadcast::channel(10);
+        let subscriber = tracing_subscriber::registry()
+            .with(EventCollector::new(vec![tx], recent.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(
+                port = 443u16,
+                offset = -1,
+                udp = true,
+                host = "example.com",
+                err = %"boom",
+                "dial {}",
+                "done"
+            );
+        });
+
+        let evt = rx.try_recv().unwrap();
+        assert_eq!(evt.level, LogLevel::Info);
+        assert_eq!(evt.msg, "dial done");
+        assert_eq!(evt.fields["port"], serde_json::json!(443));
+        assert_eq!(evt.fields["offset"], serde_json::json!(-1));
+        assert_eq!(evt.fields["udp"], serde_json::json!(true));
+        assert_eq!(evt.fields["host"], serde_json::json!("example.com"));
+        assert_eq!(evt.fields["err"], serde_json::json!("boom"));
+        assert_eq!(recent.snapshot().len(), 1);
     }
 
-    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
-        println!("u128 {} = {}", field.name(), value);
+    #[test]
+    fn test_recent_logs_bounded() {
+        let recent = RecentLogs::new(2);
+        for i in 0..3 {
+            recent.push(
+                LogEvent {
+                    level: LogLevel::Error,
+                    msg: i.to_string(),
+                    fields: Default::default(),
+                },
+                &[],
+            );
+        }
+        let msgs = recent
+            .snapshot()
+            .into_iter()
+            .map(|x| x.msg)
+            .collect::<Vec<_>>();
+        assert_eq!(msgs, vec!["1", "2"]);
+
+        // 3 bytes each, only two fit
+        let recent = RecentLogs::with_max_bytes(10, 7);
+        for i in 100..103 {
+            recent.push(
+                LogEvent {
+                    level: LogLevel::Error,
+                    msg: i.to_string(),
+                    fields: Default::default(),
+                },
+                &[],
+            );
+        }
+        assert_eq!(recent.snapshot().len(), 2);
+
+        recent.clear();
+        assert!(recent.snapshot().is_empty());
     }
 
-    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
-        println!("i128 {} = {}", field.name(), value);
+    #[test]
+    fn test_subscribe() {
+        let recent = RecentLogs::new(10);
+        let (tx, _) = tokio::sync::broadcast::channel(10);
+        let event = |msg: &str| LogEvent {
+            level: LogLevel::Info,
+            msg: msg.to_owned(),
+            fields: Default::default(),
+        };
+
+        recent.push(event("before"), &[tx.clone()]);
+        let (replayed, mut rx) = recent.subscribe(&tx);
+        recent.push(event("after"), &[tx.clone()]);
+
+        // each event is either replayed or received, never both
+        assert_eq!(
+            replayed.into_iter().map(|x| x.msg).collect::<Vec<_>>(),
+            vec!["before"]
+        );
+        assert_eq!(rx.try_recv().unwrap().msg, "after");
+        assert!(rx.try_recv().is_err());
     }
 
-    fn record_error(
-        &mut self,
-        field: &tracing::field::Field,
-        value: &(dyn std::error::Error + 'static),
-    ) {
-        println!("error {} = {}", field.name(), value);
+    #[test]
+    fn test_level_order() {
+        assert!(LogLevel::Error > LogLevel::Warning);
+        assert!(LogLevel::Debug < LogLevel::Info);
+        assert!(LogLevel::Error < LogLevel::Silent);
     }
 }
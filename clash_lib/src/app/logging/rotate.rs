@@ -0,0 +1,107 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// A log file that is renamed to `<path>.1` once it reaches `max_size`,
+/// shifting older ones up to `<path>.<max_backups>`, the oldest is dropped.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_backups: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(
+        path: PathBuf,
+        max_size: u64,
+        max_backups: usize,
+    ) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_backups,
+            file,
+            written,
+        })
+    }
+
+    fn backup(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_backups == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.max_backups).rev() {
+                rename_if_exists(&self.backup(n), &self.backup(n + 1))?;
+            }
+            fs::rename(&self.path, self.backup(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a line is never split over two files
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::RotatingFile;
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clash.log");
+
+        let mut f = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            f.write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name));
+        assert_eq!(read("clash.log").unwrap(), "fourth\n");
+        assert_eq!(read("clash.log.1").unwrap(), "third\n");
+        assert_eq!(read("clash.log.2").unwrap(), "second\n");
+        assert!(read("clash.log.3").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    time::{Duration, Instant},
+};
+
+use chrono::SecondsFormat;
+use tracing::Level;
+use tracing_subscriber::Layer;
+
+use crate::{
+    config::internal::config::SyslogTarget,
+    proxy::utils::{new_std_udp_socket, protect_socket},
+};
+
+const APP_NAME: &str = "clash-rs";
+/// facility `daemon`
+const FACILITY: u8 = 3;
+/// lines waiting for the writer, more are dropped instead of blocking
+const QUEUE_SIZE: usize = 1024;
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Sends events to syslog in RFC 5424 format. Writing happens on its own
+/// thread, so a slow or unreachable server never holds up the caller.
+pub struct SyslogLayer {
+    tx: SyncSender<String>,
+    hostname: String,
+}
+
+impl SyslogLayer {
+    pub fn new(target: SyslogTarget) -> io::Result<Self> {
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("syslog".to_owned())
+            .spawn(move || write_loop(target, rx))?;
+        Ok(Self {
+            tx,
+            hostname: hostname().unwrap_or_else(|| "-".to_owned()),
+        })
+    }
+}
+
+impl<S> Layer<S> for SyslogLayer
+where
+    S: tracing::Subscriber,
+{
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut visitor = super::EventVisitor::default();
+        event.record(&mut visitor);
+
+        let mut msg = visitor.message;
+        for (k, v) in visitor.fields {
+            let _ = write!(msg, " {}={}", k, v);
+        }
+
+        let line = format_line(
+            *event.metadata().level(),
+            &chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            &self.hostname,
+            std::process::id(),
+            &msg,
+        );
+        // a full queue or a gone writer both drop the event
+        let _ = self.tx.try_send(line);
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+fn format_line(
+    level: Level,
+    timestamp: &str,
+    hostname: &str,
+    pid: u32,
+    msg: &str,
+) -> String {
+    format!(
+        "<{}>1 {} {} {} {} - - {}",
+        FACILITY * 8 + severity(level),
+        timestamp,
+        hostname,
+        APP_NAME,
+        pid,
+        msg
+    )
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let r = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut _, buf.len()) };
+    if r != 0 {
+        return None;
+    }
+    let end = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..end].to_vec())
+        .ok()
+        .filter(|x| !x.is_empty())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+enum Conn {
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Conn {
+    fn open(target: &SyslogTarget) -> io::Result<Self> {
+        match target {
+            #[cfg(unix)]
+            SyslogTarget::Local => {
+                let s = std::os::unix::net::UnixDatagram::unbound()?;
+                s.connect("/dev/log")?;
+                Ok(Conn::Local(s))
+            }
+            #[cfg(not(unix))]
+            SyslogTarget::Local => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "local syslog is only supported on unix",
+            )),
+            SyslogTarget::Udp(addr) => {
+                let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no address")
+                })?;
+                let local = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let s = new_std_udp_socket(
+                    Some(local.parse().unwrap()),
+                    None,
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    None,
+                )?;
+                s.set_nonblocking(false)?;
+                s.connect(addr)?;
+                Ok(Conn::Udp(s))
+            }
+            SyslogTarget::Tcp(addr) => {
+                let s = connect_tcp(addr)?;
+                s.set_write_timeout(Some(Duration::from_secs(5)))?;
+                Ok(Conn::Tcp(s))
+            }
+        }
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Conn::Local(s) => s.send(line.as_bytes()).map(|_| ()),
+            Conn::Udp(s) => s.send(line.as_bytes()).map(|_| ()),
+            // octet counting framing of RFC 6587
+            Conn::Tcp(s) => {
+                s.write_all(format!("{} {}", line.len(), line).as_bytes())
+            }
+        }
+    }
+}
+
+/// `TcpStream::connect`, with the socket protected first, see
+/// [`protect_socket`]
+fn connect_tcp(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            None,
+        )?;
+        protect_socket(&socket)?;
+        match socket.connect(&addr.into()) {
+            Ok(()) => return Ok(socket.into()),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address")))
+}
+
+/// Reconnects with an exponential backoff, events coming in while the
+/// server is unreachable are dropped.
+fn write_loop(target: SyslogTarget, rx: Receiver<String>) {
+    let mut conn = None;
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = Instant::now();
+
+    for line in rx {
+        if conn.is_none() && Instant::now() >= retry_at {
+            match Conn::open(&target) {
+                Ok(c) => {
+                    conn = Some(c);
+                    backoff = Duration::from_secs(1);
+                }
+                Err(e) => {
+                    // not through tracing, that would loop back here
+                    eprintln!("failed to connect to syslog: {}", e);
+                    retry_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        if let Some(c) = conn.as_mut() {
+            if let Err(e) = c.send(&line) {
+                eprintln!("failed to write to syslog: {}", e);
+                conn = None;
+                retry_at = Instant::now() + backoff;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::Level;
+
+    use super::format_line;
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(
+            format_line(
+                Level::WARN,
+                "2024-01-01T00:00:00.000000Z",
+                "router",
+                42,
+                "dns timeout"
+            ),
+            "<28>1 2024-01-01T00:00:00.000000Z router clash-rs 42 - - dns timeout"
+        );
+    }
+}
@@ -1,9 +1,12 @@
+#[cfg(feature = "api")]
 pub mod api;
 pub mod dispatcher;
 pub mod dns;
 pub mod inbound;
 pub mod logging;
+pub mod net_monitor;
 pub mod outbound;
 pub mod profile;
 pub mod remote_content_manager;
 pub mod router;
+pub(crate) mod watcher;
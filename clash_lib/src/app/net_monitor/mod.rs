@@ -0,0 +1,255 @@
+//! Watches for the default interface changing, e.g. a laptop moving from
+//! Wi-Fi to Ethernet, or getting another address on the same network.
+//!
+//! The platform only tells that the links, addresses or routes changed, the
+//! default interface is then found again the way [`get_outbound_interface`]
+//! does it and compared with the last one. Where the platform can't tell,
+//! it's polled.
+//!
+//! The changes are broadcast, for the connection tracker and the api. The
+//! state tied to the old network, the connections a proxy keeps open or the
+//! addresses it resolved, checks a [`NetworkEpoch`] when it's used instead.
+
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::proxy::utils::{get_outbound_interface, OutboundInterface};
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+mod unix;
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+use unix::Notifier;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows::Notifier;
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
+mod other;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
+use other::Notifier;
+
+/// a switch comes as a burst of link, address and route messages
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// when the platform can't tell about the changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DefaultInterface {
+    pub name: String,
+    pub addr_v4: Option<Ipv4Addr>,
+    pub addr_v6: Option<Ipv6Addr>,
+}
+
+impl From<OutboundInterface> for DefaultInterface {
+    fn from(x: OutboundInterface) -> Self {
+        Self {
+            name: x.name,
+            addr_v4: x.addr_v4,
+            addr_v6: x.addr_v6,
+        }
+    }
+}
+
+impl Display for DefaultInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(v4) = self.addr_v4 {
+            write!(f, " {}", v4)?;
+        }
+        if let Some(v6) = self.addr_v6 {
+            write!(f, " {}", v6)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NetworkChange {
+    pub previous: Option<DefaultInterface>,
+    pub current: Option<DefaultInterface>,
+}
+
+struct Monitor {
+    tx: broadcast::Sender<NetworkChange>,
+    current: RwLock<Option<DefaultInterface>>,
+    /// bumped on every change
+    epoch: AtomicU64,
+    started: AtomicBool,
+}
+
+static MONITOR: Lazy<Monitor> = Lazy::new(|| Monitor {
+    tx: broadcast::channel(16).0,
+    current: RwLock::new(None),
+    epoch: AtomicU64::new(0),
+    started: AtomicBool::new(false),
+});
+
+/// Starts watching the network, once for the whole process, the reloads
+/// don't start it again.
+pub fn start() {
+    if MONITOR.started.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    *MONITOR.current.write().unwrap() = find_default_interface();
+    tokio::spawn(run());
+}
+
+pub fn subscribe() -> broadcast::Receiver<NetworkChange> {
+    MONITOR.tx.subscribe()
+}
+
+/// The interface the traffic goes out of, as of the last change seen when
+/// the monitor runs, which spares listing the interfaces every time.
+pub fn default_interface() -> Option<DefaultInterface> {
+    if MONITOR.started.load(Ordering::Relaxed) {
+        MONITOR.current.read().unwrap().clone()
+    } else {
+        find_default_interface()
+    }
+}
+
+fn find_default_interface() -> Option<DefaultInterface> {
+    get_outbound_interface().map(Into::into)
+}
+
+/// Tells whoever keeps state tied to the network, e.g. an open connection to
+/// a proxy server, that the network changed since it last looked.
+#[derive(Debug)]
+pub struct NetworkEpoch(AtomicU64);
+
+impl Default for NetworkEpoch {
+    fn default() -> Self {
+        Self(AtomicU64::new(MONITOR.epoch.load(Ordering::Relaxed)))
+    }
+}
+
+impl NetworkEpoch {
+    /// true once after each change
+    pub fn changed(&self) -> bool {
+        let now = MONITOR.epoch.load(Ordering::Relaxed);
+        self.0.swap(now, Ordering::Relaxed) != now
+    }
+}
+
+async fn run() {
+    let mut notifier = match Notifier::new() {
+        Ok(n) => Some(n),
+        Err(e) => {
+            warn!("can't watch the network, polling it instead: {}", e);
+            None
+        }
+    };
+
+    loop {
+        match notifier.as_mut() {
+            Some(n) => {
+                if let Err(e) = n.changed().await {
+                    warn!("network watch failed, polling it instead: {}", e);
+                    notifier = None;
+                    continue;
+                }
+                tokio::time::sleep(DEBOUNCE).await;
+                n.drain();
+            }
+            None => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+
+        let current = tokio::task::spawn_blocking(find_default_interface)
+            .await
+            .unwrap_or_default();
+        update(current);
+    }
+}
+
+fn update(current: Option<DefaultInterface>) {
+    let previous = {
+        let mut c = MONITOR.current.write().unwrap();
+        if *c == current {
+            return;
+        }
+        std::mem::replace(&mut *c, current.clone())
+    };
+    MONITOR.epoch.fetch_add(1, Ordering::Relaxed);
+
+    let show = |x: &Option<DefaultInterface>| {
+        x.as_ref()
+            .map(ToString::to_string)
+            .unwrap_or("none".to_owned())
+    };
+    info!(
+        "default interface changed: {} -> {}",
+        show(&previous),
+        show(&current)
+    );
+    let _ = MONITOR.tx.send(NetworkChange { previous, current });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update, DefaultInterface, NetworkEpoch};
+
+    #[test]
+    fn test_epoch() {
+        let iface = |name: &str| {
+            Some(DefaultInterface {
+                name: name.to_owned(),
+                addr_v4: Some("192.168.1.2".parse().unwrap()),
+                addr_v6: None,
+            })
+        };
+        let mut changes = super::subscribe();
+        let epoch = NetworkEpoch::default();
+        assert!(!epoch.changed());
+
+        update(iface("wlan0"));
+        update(iface("wlan0"));
+        assert!(epoch.changed());
+        assert!(!epoch.changed());
+
+        update(iface("eth0"));
+        assert!(epoch.changed());
+
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.current, iface("wlan0"));
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.previous, iface("wlan0"));
+        assert_eq!(change.current, iface("eth0"));
+        assert!(changes.try_recv().is_err());
+    }
+}
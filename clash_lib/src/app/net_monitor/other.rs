@@ -0,0 +1,18 @@
+use std::io;
+
+pub struct Notifier;
+
+impl Notifier {
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("not supported on {}", std::env::consts::OS),
+        ))
+    }
+
+    pub async fn changed(&mut self) -> io::Result<()> {
+        unreachable!("never created")
+    }
+
+    pub fn drain(&mut self) {}
+}
@@ -0,0 +1,120 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use tokio::io::unix::AsyncFd;
+
+/// A netlink socket on Linux, a route socket on macOS, readable whenever a
+/// link, an address or a route changes. What the messages say isn't looked
+/// at.
+pub struct Notifier {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Notifier {
+    pub fn new() -> io::Result<Self> {
+        let fd = open()?;
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub async fn changed(&mut self) -> io::Result<()> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| read_all(fd.get_ref())) {
+                Ok(rv) => return rv,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// drops the messages that came in the meantime
+    pub fn drain(&mut self) {
+        let _ = read_all(self.fd.get_ref());
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as _;
+    addr.nl_groups = (libc::RTMGRP_LINK
+        | libc::RTMGRP_IPV4_IFADDR
+        | libc::RTMGRP_IPV6_IFADDR
+        | libc::RTMGRP_IPV4_ROUTE
+        | libc::RTMGRP_IPV6_ROUTE) as u32;
+    let rv = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as _,
+        )
+    };
+    if rv < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn open() -> io::Result<OwnedFd> {
+    let fd =
+        unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+        if flags < 0
+            || libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK)
+                < 0
+            || libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(fd)
+}
+
+/// Reads until there is nothing left, WouldBlock if there was nothing at
+/// all.
+fn read_all(fd: &OwnedFd) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    let mut read = false;
+    loop {
+        let n = unsafe {
+            libc::recv(fd.as_raw_fd(), buf.as_mut_ptr() as _, buf.len(), 0)
+        };
+        if n > 0 {
+            read = true;
+            continue;
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::WouldBlock if read => return Ok(()),
+            io::ErrorKind::WouldBlock => return Err(e),
+            io::ErrorKind::Interrupted => {}
+            // the kernel dropped some messages, so something did change
+            _ if e.raw_os_error() == Some(libc::ENOBUFS) => read = true,
+            _ => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::{ffi::c_void, io};
+
+use tokio::sync::mpsc;
+use windows::Win32::{
+    Foundation::{HANDLE, NO_ERROR},
+    NetworkManagement::IpHelper::{
+        CancelMibChangeNotify2, NotifyIpInterfaceChange, MIB_IPINTERFACE_ROW,
+        MIB_NOTIFICATION_TYPE,
+    },
+    Networking::WinSock::AF_UNSPEC,
+};
+
+use crate::common::errors::new_io_error;
+
+type Tx = mpsc::UnboundedSender<()>;
+
+/// Called by NotifyIpInterfaceChange on an interface being added, removed
+/// or changed, on a thread of its own.
+pub struct Notifier {
+    handle: HANDLE,
+    /// the callback's context, freed once the notification is cancelled
+    tx: *mut Tx,
+    rx: mpsc::UnboundedReceiver<()>,
+}
+
+// the handle and the context are only touched on drop
+unsafe impl Send for Notifier {}
+
+unsafe extern "system" fn callback(
+    ctx: *const c_void,
+    _row: *const MIB_IPINTERFACE_ROW,
+    _typ: MIB_NOTIFICATION_TYPE,
+) {
+    let tx = unsafe { &*(ctx as *const Tx) };
+    let _ = tx.send(());
+}
+
+impl Notifier {
+    pub fn new() -> io::Result<Self> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let tx = Box::into_raw(Box::new(tx));
+        let mut handle = HANDLE::default();
+        let rv = unsafe {
+            NotifyIpInterfaceChange(
+                AF_UNSPEC,
+                Some(callback),
+                Some(tx as *const c_void),
+                false,
+                &mut handle,
+            )
+        };
+        if rv != NO_ERROR {
+            drop(unsafe { Box::from_raw(tx) });
+            return Err(io::Error::from_raw_os_error(rv.0 as i32));
+        }
+        Ok(Self { handle, tx, rx })
+    }
+
+    pub async fn changed(&mut self) -> io::Result<()> {
+        self.rx
+            .recv()
+            .await
+            .ok_or(new_io_error("interface change notification stopped"))
+    }
+
+    /// drops the notifications that came in the meantime
+    pub fn drain(&mut self) {
+        while self.rx.try_recv().is_ok() {}
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe {
+            // waits for a callback in progress, the context is unused after
+            let _ = CancelMibChangeNotify2(self.handle);
+            drop(Box::from_raw(self.tx));
+        }
+    }
+}
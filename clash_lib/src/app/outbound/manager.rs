@@ -1,14 +1,26 @@
 use anyhow::Result;
 use erased_serde::Serialize;
+use futures::StreamExt;
 use hyper::Uri;
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
-use tokio::sync::{Mutex, RwLock};
-use tracing::{debug, error, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{broadcast, Mutex, RwLock},
+    task::AbortHandle,
+};
+use tracing::{debug, error};
 
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::app::{
+    dispatcher::ProxyChain,
     dns::ThreadSafeDNSResolver,
+    net_monitor,
     profile::ThreadSafeCacheFile,
     remote_content_manager::{
         healthcheck::HealthCheck,
@@ -21,33 +33,31 @@ use crate::{
     app::remote_content_manager::providers::proxy_provider::{
         PlainProvider, ProxySetProvider, ThreadSafeProxyProvider,
     },
-    config::internal::proxy::{
-        OutboundProxyProviderDef, PROXY_DIRECT, PROXY_GLOBAL, PROXY_REJECT,
+    config::{
+        def::ClientFingerprint,
+        internal::proxy::{
+            GroupHealthCheck, HealthCheckType, OutboundProxyProviderDef,
+            PROXY_DIRECT, PROXY_GLOBAL, PROXY_REJECT,
+        },
     },
     proxy::{
-        fallback, loadbalance, selector, socks, trojan,
+        fallback, loadbalance, selector,
         utils::{DirectConnector, ProxyConnector},
-        vmess, wg, OutboundType,
+        OutboundType,
     },
 };
 
 use crate::{
     config::internal::proxy::{OutboundGroupProtocol, OutboundProxyProtocol},
     proxy::{
-        direct, reject, relay, selector::ThreadSafeSelectorControl, urltest,
-        AnyOutboundHandler,
+        relay, selector::ThreadSafeSelectorControl, urltest, AnyOutboundHandler,
     },
     Error,
 };
 
-use super::utils::proxy_groups_dag_sort;
+use crate::common::rate_limit::{Bandwidth, BandwidthLimit};
 
-#[cfg(feature = "shadowsocks")]
-use crate::proxy::shadowsocks;
-#[cfg(feature = "onion")]
-use crate::proxy::tor;
-#[cfg(feature = "tuic")]
-use crate::proxy::tuic;
+use super::utils::{build_handlers, proxy_groups_dag_sort};
 
 static RESERVED_PROVIDER_NAME: &str = "default";
 
@@ -56,13 +66,140 @@ pub struct OutboundManager {
     proxy_providers: HashMap<String, ThreadSafeProxyProvider>,
     proxy_manager: ProxyManager,
     selector_control: HashMap<String, ThreadSafeSelectorControl>,
+    /// the weights of the `strategy: weighted` load-balance groups
+    load_balance_weights: HashMap<String, loadbalance::Weights>,
+    /// hash of the config each proxy handler was built from, to tell which
+    /// handlers can be reused on reload
+    handler_hashes: HashMap<String, u64>,
+    /// the proxies that are or were limited, from the config or the API
+    bandwidth: std::sync::Mutex<HashMap<String, Arc<BandwidthLimit>>>,
+    /// the groups with `same-exit-affinity` and their windows
+    affinity: HashMap<String, Duration>,
+    /// the tasks keeping the connections of the `pre-connect` proxies open
+    /// and prefetching the servers, stopped with the manager
+    tasks: Vec<AbortHandle>,
+    /// the URLs the groups and providers check the proxies with
+    health_check_urls: HashSet<String>,
+    /// the chains of the dialer-proxies and the dialed hops of the relays as
+    /// of a moment ago, so the groups aren't asked for each connection, see
+    /// [`OutboundManager::complete_chain`]
+    dialer_chains: ChainCache,
+    relay_hops: ChainCache,
+}
+
+/// how long a chain in a [`ChainCache`] is used for, a change of selection
+/// shows in the connections after that
+const CHAIN_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// chains by the name of the handler they are of, with when they were made
+type ChainCache = std::sync::Mutex<HashMap<String, (Instant, Vec<String>)>>;
+
+fn cached_chain(cache: &ChainCache, name: &str) -> Option<Vec<String>> {
+    cache
+        .lock()
+        .unwrap()
+        .get(name)
+        .filter(|(at, _)| at.elapsed() < CHAIN_CACHE_TTL)
+        .map(|(_, chain)| chain.clone())
+}
+
+impl Drop for OutboundManager {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
 }
 
 static DEFAULT_LATENCY_TEST_URL: &str = "http://www.gstatic.com/generate_204";
 
+/// the url of a provider's health check, which `type: icmp` doesn't need
+fn hc_url(url: String) -> String {
+    if url.is_empty() {
+        DEFAULT_LATENCY_TEST_URL.to_owned()
+    } else {
+        url
+    }
+}
+
 pub type ThreadSafeOutboundManager = Arc<OutboundManager>;
 
+/// the URLs the config has the proxies checked with, the default one too
+fn health_check_urls(
+    groups: &[OutboundGroupProtocol],
+    providers: &HashMap<String, OutboundProxyProviderDef>,
+) -> HashSet<String> {
+    let groups = groups.iter().filter_map(|g| match g {
+        OutboundGroupProtocol::UrlTest(g) => Some(g.url.clone()),
+        OutboundGroupProtocol::Fallback(g) => Some(g.url.clone()),
+        OutboundGroupProtocol::LoadBalance(g) => Some(g.url.clone()),
+        OutboundGroupProtocol::Relay(_) | OutboundGroupProtocol::Select(_) => None,
+    });
+    let providers = providers.values().map(|p| match p {
+        OutboundProxyProviderDef::Http(p) => p.health_check.url.clone(),
+        OutboundProxyProviderDef::File(p) => p.health_check.url.clone(),
+    });
+    groups
+        .chain(providers)
+        .map(hc_url)
+        .chain([DEFAULT_LATENCY_TEST_URL.to_owned()])
+        .collect()
+}
+
+/// how many servers are looked up at once by the prefetch
+const PREFETCH_CONCURRENCY: usize = 8;
+
+/// how often the `pre-connect` proxies of a provider are looked for when it
+/// has none, they may come with an update
+const PROVIDER_PRE_CONNECT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// opens the connection `h` shares between its dials if it isn't open
+async fn warm_up(h: &AnyOutboundHandler, resolver: ThreadSafeDNSResolver) {
+    match h.warm_up(resolver).await {
+        Ok(_) => debug!("{} pre-connected", h.name()),
+        Err(e) => debug!("{} failed to pre-connect: {}", h.name(), e),
+    }
+}
+
+/// looks up the servers of `handlers`, returns how many were resolved and
+/// how many failed. the failures are left to the dials to report
+async fn prefetch_servers(
+    handlers: Vec<AnyOutboundHandler>,
+    resolver: ThreadSafeDNSResolver,
+) -> (usize, usize) {
+    let start = Instant::now();
+    let results = futures::stream::iter(handlers)
+        .map(|h| {
+            let resolver = resolver.clone();
+            async move {
+                let rv = h.prefetch(resolver).await;
+                if let Some(Err(e)) = &rv {
+                    debug!("failed to prefetch the server of {}: {}", h.name(), e);
+                }
+                rv
+            }
+        })
+        .buffer_unordered(PREFETCH_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    let resolved = results.iter().filter(|x| matches!(x, Some(Ok(_)))).count();
+    let failed = results.iter().filter(|x| matches!(x, Some(Err(_)))).count();
+    if resolved + failed > 0 {
+        info!(
+            "prefetched proxy servers in {:?}: {} resolved, {} failed",
+            start.elapsed(),
+            resolved,
+            failed
+        );
+    }
+    (resolved, failed)
+}
+
 impl OutboundManager {
+    /// `previous` is the manager of the running config when reloading, proxy
+    /// handlers whose config didn't change are taken over from it.
+    /// `client_fingerprint` is `global-client-fingerprint`.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         outbounds: Vec<OutboundProxyProtocol>,
         outbound_groups: Vec<OutboundGroupProtocol>,
@@ -71,43 +208,243 @@ impl OutboundManager {
         dns_resolver: ThreadSafeDNSResolver,
         cache_store: ThreadSafeCacheFile,
         cwd: String,
+        previous: Option<ThreadSafeOutboundManager>,
+        unified_delay: bool,
+        client_fingerprint: Option<ClientFingerprint>,
     ) -> Result<Self, Error> {
         let handlers = HashMap::new();
         let provider_registry = HashMap::new();
         let selector_control = HashMap::new();
-        let proxy_manager = ProxyManager::new(dns_resolver.clone());
+        let proxy_manager = ProxyManager::new(dns_resolver.clone())
+            .with_unified_delay(unified_delay);
 
         let mut m = Self {
             handlers,
             proxy_manager,
             selector_control,
+            load_balance_weights: HashMap::new(),
             proxy_providers: provider_registry,
+            handler_hashes: HashMap::new(),
+            bandwidth: Default::default(),
+            affinity: outbound_groups
+                .iter()
+                .filter_map(|g| Some((g.name().to_owned(), g.same_exit_affinity()?)))
+                .collect(),
+            tasks: vec![],
+            health_check_urls: health_check_urls(&outbound_groups, &proxy_providers),
+            dialer_chains: Default::default(),
+            relay_hops: Default::default(),
         };
 
+        let pre_connect = outbounds
+            .iter()
+            .filter(|x| x.pre_connect())
+            .map(|x| x.name().to_owned())
+            .collect::<Vec<_>>();
+        for outbound in &outbounds {
+            let (up, down) = outbound.bandwidth();
+            if up.is_some() || down.is_some() {
+                m.bandwidth.get_mut().unwrap().insert(
+                    outbound.name().to_owned(),
+                    BandwidthLimit::new(up, down),
+                );
+            }
+        }
+
         debug!("initializing proxy providers");
-        m.load_proxy_providers(cwd, proxy_providers, dns_resolver)
-            .await?;
+        m.load_proxy_providers(
+            cwd,
+            proxy_providers,
+            dns_resolver.clone(),
+            cache_store.clone(),
+            client_fingerprint,
+        )
+        .await?;
 
         debug!("initializing handlers");
-        m.load_handlers(outbounds, outbound_groups, proxy_names, cache_store)
+        let reused = m
+            .load_handlers(
+                outbounds,
+                outbound_groups,
+                proxy_names,
+                cache_store,
+                previous.as_deref(),
+                client_fingerprint,
+            )
             .await?;
 
+        if let Some(previous) = previous {
+            debug!("reusing {} unchanged proxy handlers", reused.len());
+            m.proxy_manager
+                .inherit(&previous.proxy_manager, &reused)
+                .await;
+        }
+
         debug!("initializing connectors");
         m.init_handler_connectors().await?;
 
+        m.start_prefetch(dns_resolver.clone());
+        m.start_pre_connect(&pre_connect, dns_resolver);
+
         Ok(m)
     }
 
+    /// looks up the servers of all the proxies, those of the providers too,
+    /// now and again after each network change, so the first dials don't
+    /// wait on DNS one after the other
+    fn start_prefetch(&mut self, resolver: ThreadSafeDNSResolver) {
+        let handlers = self.handlers.values().cloned().collect::<Vec<_>>();
+        let providers = self.proxy_providers.values().cloned().collect::<Vec<_>>();
+        let task = tokio::spawn(async move {
+            let mut changes = net_monitor::subscribe();
+            loop {
+                let mut all = handlers.clone();
+                for p in &providers {
+                    all.extend(p.read().await.proxies().await);
+                }
+                prefetch_servers(all, resolver.clone()).await;
+                if let Err(broadcast::error::RecvError::Closed) =
+                    changes.recv().await
+                {
+                    return;
+                }
+            }
+        });
+        self.tasks.push(task.abort_handle());
+    }
+
+    /// keeps a connection to the server of each `pre-connect` proxy open,
+    /// those of the providers too: opened now, again after each network
+    /// change, and checked a bit before the server would close it for being
+    /// idle
+    fn start_pre_connect(
+        &mut self,
+        names: &[String],
+        resolver: ThreadSafeDNSResolver,
+    ) {
+        for name in names {
+            let Some(h) = self.handlers.get(name).cloned() else {
+                continue;
+            };
+            let Some(refresh) = h.pre_connect_refresh() else {
+                debug!(
+                    "{} has no connection shared between dials, pre-connect ignored",
+                    name
+                );
+                continue;
+            };
+            let resolver = resolver.clone();
+            let task = tokio::spawn(async move {
+                let mut changes = net_monitor::subscribe();
+                loop {
+                    warm_up(&h, resolver.clone()).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(refresh) => {}
+                        r = changes.recv() => {
+                            if let Err(broadcast::error::RecvError::Closed) = r {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+            self.tasks.push(task.abort_handle());
+        }
+
+        // the proxies of a provider change with its updates, they are looked
+        // up again each round
+        for provider in self.proxy_providers.values().cloned() {
+            let resolver = resolver.clone();
+            let task = tokio::spawn(async move {
+                let mut changes = net_monitor::subscribe();
+                loop {
+                    let handlers = provider.read().await.pre_connect().await;
+                    let refresh = handlers
+                        .iter()
+                        .filter_map(|h| h.pre_connect_refresh())
+                        .min()
+                        .unwrap_or(PROVIDER_PRE_CONNECT_INTERVAL);
+                    futures::future::join_all(
+                        handlers
+                            .iter()
+                            .filter(|h| h.pre_connect_refresh().is_some())
+                            .map(|h| warm_up(h, resolver.clone())),
+                    )
+                    .await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(refresh) => {}
+                        r = changes.recv() => {
+                            if let Err(broadcast::error::RecvError::Closed) = r {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+            self.tasks.push(task.abort_handle());
+        }
+    }
+
+    /// what `external-controller-allow-origin-tests: false` limits the
+    /// delay tests of the API to
+    pub fn health_check_urls(&self) -> &HashSet<String> {
+        &self.health_check_urls
+    }
+
     pub fn get_outbound(&self, name: &str) -> Option<AnyOutboundHandler> {
         self.handlers.get(name).cloned()
     }
 
+    /// whether any proxy sends everything through a tunnel of its own, e.g.
+    /// wireguard
+    #[cfg_attr(not(feature = "tun"), allow(dead_code))]
+    pub fn has_tunnels(&self) -> bool {
+        self.handlers.values().any(|x| x.tunnel_mtu().is_some())
+    }
+
+    /// The limits a new connection through the proxy is subject to. A proxy
+    /// that was never limited has none, so its connections aren't throttled
+    /// at all.
+    pub fn bandwidth_limit(&self, name: &str) -> Option<Arc<BandwidthLimit>> {
+        self.bandwidth.lock().unwrap().get(name).cloned()
+    }
+
+    /// Sets the limits of a proxy, they apply right away to its connections
+    /// already limited and to the new ones. `None` removes a limit.
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn set_bandwidth_limit(
+        &self,
+        name: &str,
+        up: Option<Bandwidth>,
+        down: Option<Bandwidth>,
+    ) {
+        let mut limits = self.bandwidth.lock().unwrap();
+        match limits.get(name) {
+            Some(l) => {
+                l.up.set_rate(up);
+                l.down.set_rate(down);
+            }
+            None if up.is_some() || down.is_some() => {
+                limits.insert(name.to_owned(), BandwidthLimit::new(up, down));
+            }
+            None => {}
+        }
+    }
+
+    /// how long a group with `same-exit-affinity` keeps a source and
+    /// destination on the same member
+    pub fn affinity_window(&self, name: &str) -> Option<Duration> {
+        self.affinity.get(name).copied()
+    }
+
     /// this doesn't populate history/liveness information
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub fn get_proxy_provider(&self, name: &str) -> Option<ThreadSafeProxyProvider> {
         self.proxy_providers.get(name).cloned()
     }
 
     // API handles start
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub fn get_selector_control(
         &self,
         name: &str,
@@ -115,6 +452,15 @@ impl OutboundManager {
         self.selector_control.get(name).cloned()
     }
 
+    /// the weights of a `strategy: weighted` load-balance group
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn get_load_balance_weights(
+        &self,
+        name: &str,
+    ) -> Option<loadbalance::Weights> {
+        self.load_balance_weights.get(name).cloned()
+    }
+
     pub async fn get_proxies(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let mut r = HashMap::new();
 
@@ -130,6 +476,9 @@ impl OutboundManager {
 
             m.insert("history".to_string(), Box::new(history));
             m.insert("alive".to_string(), Box::new(alive));
+            if let Some(e) = proxy_manager.last_error(k).await {
+                m.insert("lastError".to_string(), Box::new(e));
+            }
             m.insert("name".to_string(), Box::new(k.to_owned()));
             m.insert("udp".to_string(), Box::new(support_udp));
 
@@ -150,6 +499,7 @@ impl OutboundManager {
         r
     }
 
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub async fn get_proxy(
         &self,
         proxy: &AnyOutboundHandler,
@@ -164,12 +514,216 @@ impl OutboundManager {
 
         r.insert("history".to_string(), Box::new(history));
         r.insert("alive".to_string(), Box::new(alive));
+        if let Some(e) = proxy_manager.last_error(proxy.name()).await {
+            r.insert("lastError".to_string(), Box::new(e));
+        }
         r.insert("name".to_string(), Box::new(proxy.name().to_owned()));
         r.insert("udp".to_string(), Box::new(support_udp));
 
         r
     }
 
+    /// follows the `now` of groups down to the proxy that actually carries the
+    /// traffic, nested groups included. proxies and groups without a `now`,
+    /// e.g. relay or load-balance, are returned as is.
+    pub async fn selected_member(
+        &self,
+        proxy: &AnyOutboundHandler,
+    ) -> AnyOutboundHandler {
+        self.selected_path(proxy)
+            .await
+            .pop()
+            .unwrap_or(proxy.clone())
+    }
+
+    /// `proxy` and the `now` of each group on the way to the selected member,
+    /// the member last
+    async fn selected_path(
+        &self,
+        proxy: &AnyOutboundHandler,
+    ) -> Vec<AnyOutboundHandler> {
+        let mut path = vec![proxy.clone()];
+        // bounded in case of a misconfigured cycle
+        for _ in 0..self.handlers.len() {
+            let Some(now) = Self::now_of(&path[path.len() - 1]).await else {
+                break;
+            };
+            match self.get_outbound(&now) {
+                Some(next) => path.push(next),
+                None => break,
+            }
+        }
+        path
+    }
+
+    /// the member a group says it's using, None for a proxy or a group
+    /// without one, e.g. load-balance
+    pub async fn now_of(proxy: &AnyOutboundHandler) -> Option<String> {
+        proxy
+            .as_map()
+            .await
+            .get("now")
+            .and_then(|x| serde_json::to_value(x).ok())
+            .and_then(|x| x.as_str().map(ToOwned::to_owned))
+    }
+
+    /// fills in the hops dialed through a connector, which a connection's
+    /// chain can't tell on its own: the dialer-proxy of the proxy it went
+    /// through, and all but the last hop of a relay. the chain stays leaf
+    /// first, e.g. `["entry-ss", "hk-03", "auto-hk"]`.
+    pub async fn complete_chain(&self, chain: &ProxyChain) {
+        let names = chain.names().await;
+        // the proxy carrying the traffic, then the groups above it
+        let Some((leaf, groups)) = names.split_first() else {
+            return;
+        };
+        let is_relay = |name: &str| {
+            self.get_outbound(name)
+                .is_some_and(|x| matches!(x.proto(), OutboundType::Relay))
+        };
+
+        let mut full = vec![];
+        // the hops of a relay are dialed with its connector instead
+        if !groups.iter().any(|x| is_relay(x)) {
+            if let Some(dialer) = self
+                .get_outbound(leaf)
+                .and_then(|x| x.support_dialer().and_then(|x| self.get_outbound(x)))
+            {
+                let chain = match cached_chain(&self.dialer_chains, dialer.name()) {
+                    Some(chain) => chain,
+                    None => {
+                        let chain = self.chain_of(&dialer).await;
+                        self.dialer_chains.lock().unwrap().insert(
+                            dialer.name().to_owned(),
+                            (Instant::now(), chain.clone()),
+                        );
+                        chain
+                    }
+                };
+                full.extend(chain);
+            }
+        }
+        full.push(leaf.to_owned());
+
+        for group in groups {
+            if is_relay(group) {
+                let dialed = match cached_chain(&self.relay_hops, group) {
+                    Some(dialed) => dialed,
+                    None => {
+                        let relay = self.get_outbound(group).unwrap();
+                        let hops =
+                            self.group_members(&relay).await.unwrap_or_default();
+                        let mut dialed = vec![];
+                        for hop in hops.iter().take(hops.len().saturating_sub(1)) {
+                            dialed.extend(self.chain_of(hop).await);
+                        }
+                        self.relay_hops.lock().unwrap().insert(
+                            group.to_owned(),
+                            (Instant::now(), dialed.clone()),
+                        );
+                        dialed
+                    }
+                };
+                full.splice(0..0, dialed);
+            }
+            full.push(group.to_owned());
+        }
+
+        chain.set(full).await;
+    }
+
+    /// the chain of a connection made through `proxy` now, leaf first
+    async fn chain_of(&self, proxy: &AnyOutboundHandler) -> Vec<String> {
+        self.selected_path(proxy)
+            .await
+            .iter()
+            .rev()
+            .map(|x| x.name().to_owned())
+            .collect()
+    }
+
+    /// the members listed in the `all` of a group, None if `proxy` is not a
+    /// group. members coming from proxy providers are looked up there.
+    pub async fn group_members(
+        &self,
+        proxy: &AnyOutboundHandler,
+    ) -> Option<Vec<AnyOutboundHandler>> {
+        let names: Vec<String> = proxy
+            .as_map()
+            .await
+            .get("all")
+            .and_then(|x| serde_json::to_value(x).ok())
+            .and_then(|x| serde_json::from_value(x).ok())?;
+
+        let mut from_providers = HashMap::new();
+        for p in self.proxy_providers.values() {
+            for x in p.read().await.proxies().await {
+                from_providers.entry(x.name().to_owned()).or_insert(x);
+            }
+        }
+
+        Some(
+            names
+                .iter()
+                .filter_map(|n| {
+                    self.get_outbound(n)
+                        .or_else(|| from_providers.get(n).cloned())
+                })
+                .collect(),
+        )
+    }
+
+    /// What `group` dials its member `member` with, the member itself, or
+    /// for a relay the hops up to and including it. None if `member` isn't
+    /// in `group`.
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub async fn member_via_group(
+        &self,
+        group: &AnyOutboundHandler,
+        member: &str,
+    ) -> Option<AnyOutboundHandler> {
+        let members = self.group_members(group).await?;
+        let i = members.iter().position(|x| x.name() == member)?;
+        if i == 0 || !matches!(group.proto(), OutboundType::Relay) {
+            return Some(members[i].clone());
+        }
+
+        let hops = members[..=i].to_vec();
+        let hc = HealthCheck::new(
+            hops.clone(),
+            DEFAULT_LATENCY_TEST_URL.to_owned(),
+            HealthCheckType::default(),
+            0,
+            true,
+            self.proxy_manager.clone(),
+        )
+        .ok()?;
+        let provider: ThreadSafeProxyProvider = Arc::new(RwLock::new(
+            PlainProvider::new(member.to_owned(), hops, hc).ok()?,
+        ));
+        Some(relay::Handler::new(
+            relay::HandlerOptions {
+                name: member.to_owned(),
+                ..Default::default()
+            },
+            vec![provider],
+        ))
+    }
+
+    /// like `url_test`, `proxy` being what `group` dials one of its members
+    /// with, see `member_via_group`
+    pub async fn group_url_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        group: &str,
+        url: &str,
+        timeout: Duration,
+    ) -> std::io::Result<(u16, u16)> {
+        self.proxy_manager
+            .group_url_test(proxy, Some(group), url, Some(timeout))
+            .await
+    }
+
     /// a wrapper of proxy_manager.url_test so that proxy_manager is not exposed
     pub async fn url_test(
         &self,
@@ -181,6 +735,24 @@ impl OutboundManager {
         proxy_manager.url_test(proxy, url, Some(timeout)).await
     }
 
+    /// A dial through `proxy` failing is the `lastError` of the proxy it went
+    /// to. On a certificate pin mismatch that proxy is down until a health
+    /// check finds it up again, as the server isn't the one expected.
+    pub async fn report_dial_error(
+        &self,
+        proxy: &AnyOutboundHandler,
+        e: &std::io::Error,
+    ) {
+        let proxy = self.selected_member(proxy).await;
+        self.proxy_manager.report_error(proxy.name(), e).await;
+        if !crate::common::tls::is_pin_mismatch(e) {
+            return;
+        }
+        warn!("certificate pin mismatch for {}", proxy.name());
+        self.proxy_manager.report_alive(proxy.name(), false).await;
+    }
+
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub fn get_proxy_providers(&self) -> HashMap<String, ThreadSafeProxyProvider> {
         self.proxy_providers.clone()
     }
@@ -217,84 +789,43 @@ impl OutboundManager {
         outbound_groups: Vec<OutboundGroupProtocol>,
         proxy_names: Vec<String>,
         cache_store: ThreadSafeCacheFile,
-    ) -> Result<(), Error> {
+        previous: Option<&OutboundManager>,
+        client_fingerprint: Option<ClientFingerprint>,
+    ) -> Result<Vec<String>, Error> {
         let proxy_manager = &self.proxy_manager;
         let provider_registry = &mut self.proxy_providers;
         let handlers = &mut self.handlers;
         let selector_control = &mut self.selector_control;
+        let load_balance_weights = &mut self.load_balance_weights;
+        let handler_hashes = &mut self.handler_hashes;
 
         let mut proxy_providers = vec![];
-
-        for outbound in outbounds.iter() {
-            match outbound {
-                OutboundProxyProtocol::Direct => {
-                    handlers.insert(PROXY_DIRECT.to_string(), {
-                        let h = direct::Handler::new();
-                        Arc::new(h)
-                    });
-                }
-
-                OutboundProxyProtocol::Reject => {
-                    handlers.insert(PROXY_REJECT.to_string(), {
-                        let h = reject::Handler::new();
-                        Arc::new(h)
-                    });
-                }
-                #[cfg(feature = "shadowsocks")]
-                OutboundProxyProtocol::Ss(s) => {
-                    handlers.insert(s.common_opts.name.clone(), {
-                        let h: shadowsocks::Handler = s.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                OutboundProxyProtocol::Socks5(s) => {
-                    handlers.insert(s.common_opts.name.clone(), {
-                        let h: socks::Handler = s.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                OutboundProxyProtocol::Vmess(v) => {
-                    handlers.insert(v.common_opts.name.clone(), {
-                        let h: vmess::Handler = v.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                OutboundProxyProtocol::Trojan(v) => {
-                    handlers.insert(v.common_opts.name.clone(), {
-                        let h: trojan::Handler = v.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-                OutboundProxyProtocol::Hysteria2(h) => {
-                    handlers.insert(h.name.clone(), h.clone().try_into()?);
-                }
-
-                OutboundProxyProtocol::Wireguard(wg) => {
-                    warn!("wireguard is experimental");
-                    handlers.insert(wg.common_opts.name.clone(), {
-                        let h: wg::Handler = wg.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                #[cfg(feature = "onion")]
-                OutboundProxyProtocol::Tor(tor) => {
-                    handlers.insert(tor.name.clone(), {
-                        let h: tor::Handler = tor.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-                #[cfg(feature = "tuic")]
-                OutboundProxyProtocol::Tuic(tuic) => {
-                    handlers.insert(tuic.common_opts.name.clone(), {
-                        let h: tuic::Handler = tuic.try_into()?;
-                        Arc::new(h) as _
-                    });
+        let mut reused = vec![];
+
+        let mut to_build = vec![];
+        for mut outbound in outbounds {
+            outbound.default_client_fingerprint(client_fingerprint);
+            let name = outbound.name();
+            if let Some(hash) = config_hash(&outbound) {
+                handler_hashes.insert(name.to_owned(), hash);
+
+                if let Some(h) = previous
+                    .filter(|p| p.handler_hashes.get(name) == Some(&hash))
+                    .and_then(|p| p.get_outbound(name))
+                {
+                    handlers.insert(name.to_owned(), h);
+                    reused.push(name.to_owned());
+                    continue;
                 }
             }
+            to_build.push(outbound);
+        }
+        for h in build_handlers(to_build).await? {
+            handlers.insert(h.name().to_owned(), h);
+        }
+        // the reused ones too, they still have the cache of the last config
+        for h in handlers.values() {
+            h.set_cache_store(cache_store.clone());
         }
 
         let mut outbound_groups = outbound_groups;
@@ -304,6 +835,7 @@ impl OutboundManager {
         fn make_provider_from_proxies(
             name: &str,
             proxies: &[String],
+            health_check: GroupHealthCheck,
             interval: u64,
             lazy: bool,
             handlers: &HashMap<String, AnyOutboundHandler>,
@@ -332,11 +864,13 @@ impl OutboundManager {
             let hc = HealthCheck::new(
                 proxies.clone(),
                 DEFAULT_LATENCY_TEST_URL.to_owned(),
+                health_check.typ,
                 interval,
                 lazy,
                 proxy_manager.clone(),
             )
-            .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
+            .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?
+            .with_sample_count(health_check.sample_count);
 
             let pd = Arc::new(RwLock::new(
                 PlainProvider::new(name.to_owned(), proxies, hc).map_err(|x| {
@@ -372,6 +906,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
+                            GroupHealthCheck::default(),
                             0,
                             true,
                             handlers,
@@ -400,6 +935,7 @@ impl OutboundManager {
                                 icon: proto.icon.clone(),
                                 ..Default::default()
                             },
+                            udp_over_tcp: proto.udp_over_tcp,
                         },
                         providers,
                     );
@@ -426,6 +962,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
+                            proto.health_check.clone().unwrap_or_default(),
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
                             handlers,
@@ -447,6 +984,20 @@ impl OutboundManager {
                         }
                     }
 
+                    if proto.max_loss_percent.is_some_and(|x| x > 100) {
+                        return Err(Error::InvalidConfig(format!(
+                            "proxy group {}: max-loss-percent is over 100",
+                            proto.name
+                        )));
+                    }
+                    let loss_rounds = proto.loss_rounds.unwrap_or(5);
+                    if !(1..=10).contains(&loss_rounds) {
+                        return Err(Error::InvalidConfig(format!(
+                            "proxy group {}: loss-rounds must be 1 to 10",
+                            proto.name
+                        )));
+                    }
+
                     let url_test = urltest::Handler::new(
                         urltest::HandlerOptions {
                             name: proto.name.clone(),
@@ -454,6 +1005,8 @@ impl OutboundManager {
                                 icon: proto.icon.clone(),
                                 ..Default::default()
                             },
+                            max_loss_percent: proto.max_loss_percent,
+                            loss_rounds,
                             ..Default::default()
                         },
                         proto.tolerance.unwrap_or_default(),
@@ -483,6 +1036,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
+                            proto.health_check.clone().unwrap_or_default(),
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
                             handlers,
@@ -539,6 +1093,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
+                            proto.health_check.clone().unwrap_or_default(),
                             proto.interval,
                             proto.lazy.unwrap_or_default(),
                             handlers,
@@ -563,15 +1118,24 @@ impl OutboundManager {
                     let load_balance = loadbalance::Handler::new(
                         loadbalance::HandlerOptions {
                             name: proto.name.clone(),
+                            udp: proto.udp.unwrap_or(true),
                             common_opts: crate::proxy::HandlerCommonOptions {
                                 icon: proto.icon.clone(),
                                 ..Default::default()
                             },
+                            strategy: proto.strategy.unwrap_or_default(),
+                            weights: Arc::new(std::sync::RwLock::new(
+                                proto.weights.clone().unwrap_or_default(),
+                            )),
                             ..Default::default()
                         },
                         providers,
+                        proxy_manager.clone(),
                     );
 
+                    if let Some(weights) = load_balance.weights() {
+                        load_balance_weights.insert(proto.name.clone(), weights);
+                    }
                     handlers.insert(proto.name.clone(), Arc::new(load_balance));
                 }
                 OutboundGroupProtocol::Select(proto) => {
@@ -594,6 +1158,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
+                            GroupHealthCheck::default(),
                             0,
                             true,
                             handlers,
@@ -648,6 +1213,7 @@ impl OutboundManager {
         let hc = HealthCheck::new(
             g.clone(),
             DEFAULT_LATENCY_TEST_URL.to_owned(),
+            HealthCheckType::default(),
             0, // this is a manual HC
             true,
             proxy_manager.clone(),
@@ -676,7 +1242,7 @@ impl OutboundManager {
         handlers.insert(PROXY_GLOBAL.to_owned(), Arc::new(h.clone()));
         selector_control.insert(PROXY_GLOBAL.to_owned(), Arc::new(Mutex::new(h)));
 
-        Ok(())
+        Ok(reused)
     }
 
     async fn load_proxy_providers(
@@ -684,6 +1250,8 @@ impl OutboundManager {
         cwd: String,
         proxy_providers: HashMap<String, OutboundProxyProviderDef>,
         resolver: ThreadSafeDNSResolver,
+        cache_store: ThreadSafeCacheFile,
+        client_fingerprint: Option<ClientFingerprint>,
     ) -> Result<(), Error> {
         let proxy_manager = &self.proxy_manager;
         let provider_registry = &mut self.proxy_providers;
@@ -700,19 +1268,23 @@ impl OutboundManager {
                     );
                     let hc = HealthCheck::new(
                         vec![],
-                        http.health_check.url,
+                        hc_url(http.health_check.url),
+                        http.health_check.typ,
                         http.health_check.interval,
                         http.health_check.lazy.unwrap_or_default(),
                         proxy_manager.clone(),
                     )
                     .map_err(|e| {
                         Error::InvalidConfig(format!("invalid hc config {}", e))
-                    })?;
+                    })?
+                    .with_sample_count(http.health_check.sample_count);
                     let provider = ProxySetProvider::new(
                         name.clone(),
                         Duration::from_secs(http.interval),
                         Arc::new(vehicle),
                         hc,
+                        cache_store.clone(),
+                        client_fingerprint,
                     )
                     .map_err(|x| {
                         Error::InvalidConfig(format!(
@@ -732,20 +1304,24 @@ impl OutboundManager {
                     );
                     let hc = HealthCheck::new(
                         vec![],
-                        file.health_check.url,
+                        hc_url(file.health_check.url),
+                        file.health_check.typ,
                         file.health_check.interval,
                         file.health_check.lazy.unwrap_or_default(),
                         proxy_manager.clone(),
                     )
                     .map_err(|e| {
                         Error::InvalidConfig(format!("invalid hc config {}", e))
-                    })?;
+                    })?
+                    .with_sample_count(file.health_check.sample_count);
 
                     let provider = ProxySetProvider::new(
                         name.clone(),
                         Duration::from_secs(file.interval.unwrap_or_default()),
                         Arc::new(vehicle),
                         hc,
+                        cache_store.clone(),
+                        client_fingerprint,
                     )
                     .map_err(|x| {
                         Error::InvalidConfig(format!(
@@ -778,3 +1354,139 @@ impl OutboundManager {
         Ok(())
     }
 }
+
+/// None for configs that can't be serialized, those are never reused
+fn config_hash(outbound: &OutboundProxyProtocol) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(outbound).ok()?.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::{
+        app::{
+            dispatcher::ProxyChain, dns::MockClashResolver,
+            profile::ThreadSafeCacheFile,
+        },
+        config::internal::{config::Config, proxy::OutboundProxy},
+        def,
+        proxy::{mocks::MockDummyOutboundHandler, AnyOutboundHandler},
+        session::SocksAddr,
+    };
+
+    use super::{prefetch_servers, OutboundManager};
+
+    fn handler(server: Option<SocksAddr>) -> AnyOutboundHandler {
+        let mut h = MockDummyOutboundHandler::new();
+        h.expect_name().return_const("proxy".to_owned());
+        h.expect_server().return_const(server);
+        Arc::new(h)
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_servers() {
+        let mut resolver = MockClashResolver::new();
+        resolver
+            .expect_resolve()
+            .withf(|host, _| host == "good.example")
+            .times(1)
+            .returning(|_, _| Ok(Some("1.2.3.4".parse().unwrap())));
+        resolver
+            .expect_resolve()
+            .withf(|host, _| host == "bad.example")
+            .times(1)
+            .returning(|_, _| Err(anyhow::anyhow!("timed out")));
+
+        let domain = |host: &str| SocksAddr::Domain(host.to_owned(), 443);
+        let handlers = vec![
+            handler(Some(domain("good.example"))),
+            handler(Some(domain("bad.example"))),
+            // nothing to look up for these
+            handler(Some(SocksAddr::Ip("1.2.3.4:443".parse().unwrap()))),
+            handler(None),
+        ];
+        assert_eq!(prefetch_servers(handlers, Arc::new(resolver)).await, (1, 1));
+    }
+
+    async fn manager(cfg: &str, dir: &std::path::Path) -> OutboundManager {
+        let c: Config = cfg.parse::<def::Config>().unwrap().try_into().unwrap();
+        OutboundManager::new(
+            c.proxies
+                .into_values()
+                .filter_map(|x| match x {
+                    OutboundProxy::ProxyServer(s) => Some(s),
+                    _ => None,
+                })
+                .collect(),
+            c.proxy_groups
+                .into_values()
+                .filter_map(|x| match x {
+                    OutboundProxy::ProxyGroup(g) => Some(g),
+                    _ => None,
+                })
+                .collect(),
+            HashMap::new(),
+            c.proxy_names,
+            Arc::new(MockClashResolver::new()),
+            ThreadSafeCacheFile::new(dir.join("cache.db").to_str().unwrap(), false),
+            dir.to_string_lossy().to_string(),
+            None,
+            false,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_complete_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let m = manager(
+            r#"
+proxies:
+  - {name: entry, type: socks5, server: 127.0.0.1, port: 1080}
+  - {name: hop, type: socks5, server: 127.0.0.1, port: 1081}
+  - {name: exit, type: socks5, server: 127.0.0.1, port: 1082, dialer-proxy: sel}
+proxy-groups:
+  - {name: sel, type: select, proxies: [entry, hop]}
+  - {name: chain, type: relay, proxies: [entry, hop]}
+  - {name: auto, type: select, proxies: [exit, chain]}
+"#,
+            dir.path(),
+        )
+        .await;
+
+        let complete = |names: &[&str]| {
+            let chain = ProxyChain::default();
+            let names = names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+            let m = &m;
+            async move {
+                chain.set(names).await;
+                m.complete_chain(&chain).await;
+                chain.names().await
+            }
+        };
+
+        // the dialer-proxy, with the group it is picked by
+        assert_eq!(
+            complete(&["exit", "auto"]).await,
+            vec!["entry", "sel", "exit", "auto"]
+        );
+        // all but the last hop of the relay, which is the leaf
+        assert_eq!(
+            complete(&["hop", "chain", "auto"]).await,
+            vec!["entry", "hop", "chain", "auto"]
+        );
+        // nothing to add
+        assert_eq!(complete(&["entry"]).await, vec!["entry"]);
+        assert_eq!(complete(&[]).await, Vec::<String>::new());
+
+        assert_eq!(
+            m.chain_of(&m.get_outbound("auto").unwrap()).await,
+            vec!["exit", "auto"]
+        );
+    }
+}
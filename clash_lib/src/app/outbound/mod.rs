@@ -1,3 +1,3 @@
 pub mod manager;
 
-mod utils;
+pub(crate) mod utils;
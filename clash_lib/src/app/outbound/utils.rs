@@ -1,9 +1,129 @@
 use std::{
     cell::RefCell,
     collections::{HashMap, VecDeque},
+    sync::Arc,
 };
 
-use crate::{config::internal::proxy::OutboundGroupProtocol, Error};
+#[cfg(feature = "wireguard")]
+use tracing::warn;
+
+use crate::{
+    config::internal::proxy::{OutboundGroupProtocol, OutboundProxyProtocol},
+    proxy::{direct, reject, socks, utils::DialLimited, AnyOutboundHandler},
+    Error,
+};
+
+#[cfg(feature = "shadowsocks")]
+use crate::proxy::shadowsocks;
+#[cfg(feature = "onion")]
+use crate::proxy::tor;
+#[cfg(feature = "trojan")]
+use crate::proxy::trojan;
+#[cfg(feature = "tuic")]
+use crate::proxy::tuic;
+#[cfg(feature = "vmess")]
+use crate::proxy::vmess;
+#[cfg(feature = "wireguard")]
+use crate::proxy::wg;
+
+/// fewer handlers than this are built on the calling thread, threads would
+/// cost more than they save
+const MIN_HANDLERS_PER_THREAD: usize = 64;
+
+pub(crate) fn build_handler(
+    outbound: OutboundProxyProtocol,
+) -> Result<AnyOutboundHandler, Error> {
+    let max_concurrent_dials = outbound.max_concurrent_dials();
+    let dial_timeout = outbound.dial_timeout();
+    let handler: AnyOutboundHandler = match outbound {
+        OutboundProxyProtocol::Direct => Arc::new(direct::Handler::new()),
+        OutboundProxyProtocol::Reject => Arc::new(reject::Handler::new()),
+        #[cfg(feature = "shadowsocks")]
+        OutboundProxyProtocol::Ss(s) => {
+            let h: shadowsocks::Handler = s.try_into()?;
+            Arc::new(h)
+        }
+        OutboundProxyProtocol::Socks5(s) => {
+            let h: socks::Handler = s.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "vmess")]
+        OutboundProxyProtocol::Vmess(v) => {
+            let h: vmess::Handler = v.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "trojan")]
+        OutboundProxyProtocol::Trojan(v) => {
+            let h: trojan::Handler = v.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "quic-protocols")]
+        OutboundProxyProtocol::Hysteria2(h) => h.try_into()?,
+        #[cfg(feature = "wireguard")]
+        OutboundProxyProtocol::Wireguard(wg) => {
+            warn!("wireguard is experimental");
+            let h: wg::Handler = wg.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "onion")]
+        OutboundProxyProtocol::Tor(tor) => {
+            let h: tor::Handler = tor.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "tuic")]
+        OutboundProxyProtocol::Tuic(tuic) => {
+            let h: tuic::Handler = tuic.try_into()?;
+            Arc::new(h)
+        }
+    };
+    Ok(match max_concurrent_dials {
+        Some(0) => {
+            return Err(Error::InvalidConfig(format!(
+                "proxy {}: max-concurrent-dials must be at least 1",
+                handler.name()
+            )));
+        }
+        Some(max) => Arc::new(DialLimited::new(handler, max, dial_timeout)),
+        None => handler,
+    })
+}
+
+/// Builds the handlers in order, split across a blocking task per core when
+/// there are many, e.g. from a large provider.
+///
+/// The handlers only check their options here and put off the costly parts,
+/// like TLS configs and keys, to the first dial, what is left is still
+/// worth spreading out with hundreds of them.
+pub(crate) async fn build_handlers(
+    outbounds: Vec<OutboundProxyProtocol>,
+) -> Result<Vec<AnyOutboundHandler>, Error> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let per_thread = outbounds
+        .len()
+        .div_ceil(threads)
+        .max(MIN_HANDLERS_PER_THREAD);
+    if outbounds.len() <= per_thread {
+        return outbounds.into_iter().map(build_handler).collect();
+    }
+
+    let mut outbounds = outbounds.into_iter().peekable();
+    let mut parts = vec![];
+    while outbounds.peek().is_some() {
+        let part = outbounds.by_ref().take(per_thread).collect::<Vec<_>>();
+        parts.push(tokio::task::spawn_blocking(move || {
+            part.into_iter()
+                .map(build_handler)
+                .collect::<Result<Vec<_>, _>>()
+        }));
+    }
+    let mut handlers = vec![];
+    for part in futures::future::join_all(parts).await {
+        handlers.extend(part.map_err(|_| {
+            Error::Operation("building outbound handlers panicked".to_owned())
+        })??);
+    }
+    Ok(handlers)
+}
 
 // copy paste from https://github.com/Dreamacro/clash/blob/6a661bff0c185f38c4bd9d21c91a3233ba5fdb97/config/utils.go#L21
 pub fn proxy_groups_dag_sort(
@@ -172,6 +292,12 @@ pub fn proxy_groups_dag_sort(
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "shadowsocks")]
+    use crate::config::internal::proxy::OutboundShadowsocks;
+    #[cfg(feature = "trojan")]
+    use crate::config::internal::proxy::{
+        CommonConfigOptions, OutboundProxyProtocol, OutboundTrojan,
+    };
     use crate::config::internal::proxy::{
         OutboundGroupFallback, OutboundGroupLoadBalance, OutboundGroupProtocol,
         OutboundGroupRelay, OutboundGroupSelect, OutboundGroupUrlTest,
@@ -268,4 +394,73 @@ mod tests {
         let e = super::proxy_groups_dag_sort(&mut groups).unwrap_err();
         assert!(e.to_string().contains("loop detected in proxy groups"));
     }
+
+    #[cfg(feature = "trojan")]
+    fn common(name: String) -> CommonConfigOptions {
+        CommonConfigOptions {
+            name,
+            server: "example.com".to_owned(),
+            port: 443,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "trojan")]
+    async fn test_build_many_handlers() {
+        const N: usize = 1000;
+
+        let outbounds = (0..N)
+            .map(|i| {
+                #[cfg(feature = "shadowsocks")]
+                if i % 2 == 0 {
+                    return OutboundProxyProtocol::Ss(OutboundShadowsocks {
+                        common_opts: common(format!("ss-{}", i)),
+                        cipher: "2022-blake3-aes-256-gcm".to_owned(),
+                        password: "Jv4AEfa6pqpJVgjtQXXNwSQJGK2WmDOOPxCJ3eN4UD0="
+                            .to_owned(),
+                        ..Default::default()
+                    });
+                }
+                OutboundProxyProtocol::Trojan(OutboundTrojan {
+                    common_opts: common(format!("trojan-{}", i)),
+                    password: "password".to_owned(),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let handlers = super::build_handlers(outbounds).await.unwrap();
+
+        assert_eq!(handlers.len(), N);
+        // in order
+        assert_eq!(handlers[N - 1].name(), format!("trojan-{}", N - 1));
+    }
+
+    #[test]
+    #[cfg(feature = "trojan")]
+    fn test_build_handlers_checks_options() {
+        let bad = OutboundProxyProtocol::Trojan(OutboundTrojan {
+            common_opts: common("trojan".to_owned()),
+            sni: Some("not a name".to_owned()),
+            ..Default::default()
+        });
+        assert!(super::build_handler(bad).is_err());
+
+        #[cfg(feature = "shadowsocks")]
+        for (cipher, password) in [
+            ("aes-512-gcm", "password"),
+            ("2022-blake3-aes-256-gcm", "password"),
+            // 16 bytes instead of 32
+            ("2022-blake3-aes-256-gcm", "AAAAAAAAAAAAAAAAAAAAAA=="),
+        ] {
+            let bad = OutboundProxyProtocol::Ss(OutboundShadowsocks {
+                common_opts: common("ss".to_owned()),
+                cipher: cipher.to_owned(),
+                password: password.to_owned(),
+                ..Default::default()
+            });
+            assert!(super::build_handler(bad).is_err(), "{}", cipher);
+        }
+    }
 }
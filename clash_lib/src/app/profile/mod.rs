@@ -1,66 +1,219 @@
-use std::{collections::HashMap, sync::Arc};
+//! The on-disk cache shared by the features that keep state across restarts.
+//!
+//! It is one yaml file holding a version and named sections. Selections and
+//! fake-ip mappings have their own typed accessors, any other feature keeps
+//! its data in a section of its own through [`ThreadSafeCacheFile::get`] and
+//! [`ThreadSafeCacheFile::set`]. A section that fails to parse is dropped
+//! with a warning, the rest of the file is kept.
 
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_yaml::Value;
+use tokio::sync::{Mutex, RwLock};
 use tracing::{error, trace, warn};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// bumped when a section changes in a way older versions can't read
+const CACHE_VERSION: u32 = 1;
+
+const SECTION_SELECTED: &str = "selected";
+const SECTION_FAKE_IP: &str = "fake-ip";
+
+/// how often changes are written out
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct FakeIp {
+    ip_to_host: HashMap<String, String>,
+    host_to_ip: HashMap<String, String>,
+}
+
+#[derive(Default, Clone)]
 struct Db {
+    selected: HashMap<String, String>,
+    fake_ip: FakeIp,
+    /// the sections of the other features, deserialized when asked for
+    sections: BTreeMap<String, Value>,
+}
+
+/// what is written to disk
+#[derive(Serialize, Deserialize)]
+struct DbFile {
+    version: u32,
+    sections: BTreeMap<String, Value>,
+}
+
+/// the layout before the cache had sections
+#[derive(Deserialize)]
+struct LegacyDb {
     selected: HashMap<String, String>,
     ip_to_host: HashMap<String, String>,
     host_to_ip: HashMap<String, String>,
 }
 
+impl Db {
+    fn load(s: &str) -> Self {
+        let mut sections = match serde_yaml::from_str::<DbFile>(s) {
+            Ok(f) if f.version > CACHE_VERSION => {
+                warn!(
+                    "cache file is version {}, newer than {}, starting a new one",
+                    f.version, CACHE_VERSION
+                );
+                return Db::default();
+            }
+            Ok(f) => f.sections,
+            Err(e) => match serde_yaml::from_str::<LegacyDb>(s) {
+                Ok(l) => {
+                    return Db {
+                        selected: l.selected,
+                        fake_ip: FakeIp {
+                            ip_to_host: l.ip_to_host,
+                            host_to_ip: l.host_to_ip,
+                        },
+                        sections: BTreeMap::new(),
+                    }
+                }
+                Err(_) => {
+                    error!("failed to parse cache file: {}, starting a new one", e);
+                    return Db::default();
+                }
+            },
+        };
+
+        Db {
+            selected: take_section(&mut sections, SECTION_SELECTED),
+            fake_ip: take_section(&mut sections, SECTION_FAKE_IP),
+            sections,
+        }
+    }
+
+    fn to_file(&self) -> DbFile {
+        let mut sections = self.sections.clone();
+        for (name, v) in [
+            (SECTION_SELECTED, serde_yaml::to_value(&self.selected)),
+            (SECTION_FAKE_IP, serde_yaml::to_value(&self.fake_ip)),
+        ] {
+            match v {
+                Ok(v) => {
+                    sections.insert(name.to_owned(), v);
+                }
+                Err(e) => {
+                    error!("failed to serialize cache section {}: {}", name, e)
+                }
+            }
+        }
+        DbFile {
+            version: CACHE_VERSION,
+            sections,
+        }
+    }
+}
+
+/// a section that doesn't parse is dropped rather than failing the file
+fn take_section<T: DeserializeOwned + Default>(
+    sections: &mut BTreeMap<String, Value>,
+    name: &str,
+) -> T {
+    let Some(v) = sections.remove(name) else {
+        return T::default();
+    };
+    serde_yaml::from_value(v).unwrap_or_else(|e| {
+        warn!("cache section {} is corrupted, discarding it: {}", name, e);
+        T::default()
+    })
+}
+
+struct Inner {
+    cache: RwLock<CacheFile>,
+    /// one write at a time, so an older snapshot never lands last
+    flush: Mutex<()>,
+}
+
 #[derive(Clone)]
-pub struct ThreadSafeCacheFile(Arc<tokio::sync::RwLock<CacheFile>>);
+pub struct ThreadSafeCacheFile(Arc<Inner>);
 
 impl ThreadSafeCacheFile {
     pub fn new(path: &str, store_selected: bool) -> Self {
-        let store = Arc::new(tokio::sync::RwLock::new(CacheFile::new(
-            path,
-            store_selected,
-        )));
-
-        let path = path.to_string();
-        let store_clone = store.clone();
-
-        if store_selected {
-            tokio::spawn(async move {
-                let store = store_clone;
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-                    let r = store.read().await;
-                    let db = r.db.clone();
-                    drop(r);
-
-                    let s = match serde_yaml::to_string(&db) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("failed to serialize cache file: {}", e);
-                            continue;
-                        }
-                    };
-
-                    if let Err(e) = tokio::fs::write(&path, s).await {
-                        error!("failed to write cache file: {}", e);
-                    } else {
-                        trace!("cache file flushed to {}", path);
-                    }
-                }
-            });
+        let inner = Arc::new(Inner {
+            cache: RwLock::new(CacheFile::new(path, store_selected)),
+            flush: Mutex::new(()),
+        });
+
+        // stops once the cache is dropped, e.g. replaced on a reload
+        let weak: Weak<Inner> = Arc::downgrade(&inner);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(FLUSH_INTERVAL).await;
+                let Some(inner) = weak.upgrade() else {
+                    break;
+                };
+                ThreadSafeCacheFile(inner).flush().await;
+            }
+        });
+
+        Self(inner)
+    }
+
+    /// writes the cache out if anything changed since the last time
+    pub async fn flush(&self) {
+        let _g = self.0.flush.lock().await;
+        let (path, file) = {
+            let mut c = self.0.cache.write().await;
+            if !c.dirty {
+                return;
+            }
+            c.dirty = false;
+            (c.path.clone(), c.db.to_file())
+        };
+
+        if !persist(&path, &file).await {
+            self.0.cache.write().await.dirty = true;
         }
+    }
+
+    /// Reads a section kept by some feature, `None` if it isn't there or
+    /// doesn't parse as `T` anymore.
+    pub async fn get<T: DeserializeOwned>(&self, section: &str) -> Option<T> {
+        let c = self.0.cache.read().await;
+        let v = c.db.sections.get(section)?.clone();
+        drop(c);
+        serde_yaml::from_value(v)
+            .map_err(|e| warn!("cache section {} doesn't parse: {}", section, e))
+            .ok()
+    }
 
-        Self(store)
+    /// Replaces a section, it is written out with the next flush.
+    pub async fn set<T: Serialize>(&self, section: &str, value: &T) {
+        debug_assert!(
+            section != SECTION_SELECTED && section != SECTION_FAKE_IP,
+            "section {} has its own accessors",
+            section
+        );
+        match serde_yaml::to_value(value) {
+            Ok(v) => {
+                let mut c = self.0.cache.write().await;
+                c.db.sections.insert(section.to_owned(), v);
+                c.dirty = true;
+            }
+            Err(e) => error!("failed to serialize cache section {}: {}", section, e),
+        }
     }
 
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub async fn set_selected(&self, group: &str, server: &str) {
-        let mut g = self.0.write().await;
+        let mut g = self.0.cache.write().await;
         if g.store_selected() {
             g.set_selected(group, server);
         }
     }
 
     pub async fn get_selected(&self, group: &str) -> Option<String> {
-        let g = self.0.read().await;
+        let g = self.0.cache.read().await;
         if g.store_selected() {
             g.db.selected.get(group).cloned()
         } else {
@@ -70,7 +223,7 @@ impl ThreadSafeCacheFile {
 
     #[allow(dead_code)]
     pub async fn get_selected_map(&self) -> HashMap<String, String> {
-        let g = self.0.read().await;
+        let g = self.0.cache.read().await;
         if g.store_selected() {
             g.get_selected_map()
         } else {
@@ -79,24 +232,98 @@ impl ThreadSafeCacheFile {
     }
 
     pub async fn set_ip_to_host(&self, ip: &str, host: &str) {
-        self.0.write().await.set_ip_to_host(ip, host);
+        self.0.cache.write().await.set_ip_to_host(ip, host);
     }
 
     pub async fn set_host_to_ip(&self, host: &str, ip: &str) {
-        self.0.write().await.set_host_to_ip(host, ip);
+        self.0.cache.write().await.set_host_to_ip(host, ip);
     }
 
     pub async fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
-        self.0.read().await.get_fake_ip(ip_or_host)
+        self.0.cache.read().await.get_fake_ip(ip_or_host)
     }
 
     pub async fn delete_fake_ip_pair(&self, ip: &str, host: &str) {
-        self.0.write().await.delete_fake_ip_pair(ip, host);
+        self.0.cache.write().await.delete_fake_ip_pair(ip, host);
+    }
+
+    /// clears the fake ip mappings and writes the file right away, so a
+    /// restart doesn't bring them back
+    pub async fn flush_fake_ip(&self) {
+        {
+            let mut g = self.0.cache.write().await;
+            g.db.fake_ip = FakeIp::default();
+            g.dirty = true;
+        }
+        self.flush().await;
+    }
+}
+
+/// written to a temporary file first and renamed over the cache, so a crash
+/// mid-write leaves the previous file in place
+async fn persist(path: &str, file: &DbFile) -> bool {
+    let s = match serde_yaml::to_string(file) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("failed to serialize cache file: {}", e);
+            return false;
+        }
+    };
+
+    // unique, another cache on the same path may be flushing too during a
+    // reload
+    let tmp = format!("{}.{:08x}.tmp", path, rand::random::<u32>());
+    if let Err(e) = write_synced(&tmp, s.as_bytes()).await {
+        error!("failed to write cache file: {}", e);
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return false;
+    }
+    if let Err(e) = tokio::fs::rename(&tmp, path).await {
+        error!("failed to replace cache file: {}", e);
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return false;
+    }
+    sync_parent(path).await;
+    trace!("cache file flushed to {}", path);
+    true
+}
+
+/// the data has to be on disk before the rename, otherwise a crash right
+/// after it can leave an empty file in place of the cache
+async fn write_synced(path: &str, data: &[u8]) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut f = tokio::fs::File::create(path).await?;
+    f.write_all(data).await?;
+    f.sync_all().await
+}
+
+/// makes the rename itself durable, best effort
+async fn sync_parent(path: &str) {
+    #[cfg(unix)]
+    {
+        let dir = match std::path::Path::new(path).parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_owned(),
+            _ => std::path::PathBuf::from("."),
+        };
+        let res = async { tokio::fs::File::open(&dir).await?.sync_all().await };
+        if let Err(e) = res.await {
+            warn!(
+                "failed to sync cache file directory {}: {}",
+                dir.display(),
+                e
+            );
+        }
     }
+    #[cfg(not(unix))]
+    let _ = path;
 }
 
 struct CacheFile {
     db: Db,
+    path: String,
+    /// changed since the last flush
+    dirty: bool,
 
     store_selected: bool,
 }
@@ -104,31 +331,19 @@ struct CacheFile {
 impl CacheFile {
     pub fn new(path: &str, store_selected: bool) -> Self {
         let db = match std::fs::read_to_string(path) {
-            Ok(s) => match serde_yaml::from_str(&s) {
-                Ok(db) => db,
-                Err(e) => {
-                    error!(
-                        "failed to parse cache file: {}, initilizing a new one",
-                        e
-                    );
-                    Db {
-                        selected: HashMap::new(),
-                        ip_to_host: HashMap::new(),
-                        host_to_ip: HashMap::new(),
-                    }
-                }
-            },
+            Ok(s) => Db::load(&s),
             Err(e) => {
                 warn!("failed to read cache file: {}, initializing a new one", e);
-                Db {
-                    selected: HashMap::new(),
-                    ip_to_host: HashMap::new(),
-                    host_to_ip: HashMap::new(),
-                }
+                Db::default()
             }
         };
 
-        Self { db, store_selected }
+        Self {
+            db,
+            path: path.to_string(),
+            dirty: false,
+            store_selected,
+        }
     }
 
     pub fn store_selected(&self) -> bool {
@@ -139,6 +354,7 @@ impl CacheFile {
         self.db
             .selected
             .insert(group.to_string(), server.to_string());
+        self.dirty = true;
     }
 
     pub fn get_selected_map(&self) -> HashMap<String, String> {
@@ -146,23 +362,86 @@ impl CacheFile {
     }
 
     pub fn set_ip_to_host(&mut self, ip: &str, host: &str) {
-        self.db.ip_to_host.insert(ip.to_string(), host.to_string());
+        self.db
+            .fake_ip
+            .ip_to_host
+            .insert(ip.to_string(), host.to_string());
+        self.dirty = true;
     }
 
     pub fn set_host_to_ip(&mut self, host: &str, ip: &str) {
-        self.db.host_to_ip.insert(host.to_string(), ip.to_string());
+        self.db
+            .fake_ip
+            .host_to_ip
+            .insert(host.to_string(), ip.to_string());
+        self.dirty = true;
     }
 
     pub fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
         self.db
+            .fake_ip
             .ip_to_host
             .get(ip_or_host)
-            .or_else(|| self.db.host_to_ip.get(ip_or_host))
+            .or_else(|| self.db.fake_ip.host_to_ip.get(ip_or_host))
             .cloned()
     }
 
     pub fn delete_fake_ip_pair(&mut self, ip: &str, host: &str) {
-        self.db.ip_to_host.remove(ip);
-        self.db.host_to_ip.remove(host);
+        self.db.fake_ip.ip_to_host.remove(ip);
+        self.db.fake_ip.host_to_ip.remove(host);
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Db, ThreadSafeCacheFile};
+
+    #[test]
+    fn test_load_legacy() {
+        let db = Db::load(
+            "selected:\n  proxy: ss\nip_to_host:\n  198.18.0.1: \
+             example.com\nhost_to_ip:\n  example.com: 198.18.0.1\n",
+        );
+        assert_eq!(db.selected.get("proxy").map(String::as_str), Some("ss"));
+        assert_eq!(db.fake_ip.host_to_ip.len(), 1);
+    }
+
+    #[test]
+    fn test_load_corrupted_section() {
+        let db = Db::load(
+            "version: 1\nsections:\n  selected: [not, a, map]\n  fake-ip:\n    \
+             ip-to-host: {198.18.0.1: example.com}\n    host-to-ip: {}\n  \
+             provider-etag: {sub: abc}\n",
+        );
+        assert!(db.selected.is_empty());
+        assert_eq!(db.fake_ip.ip_to_host.len(), 1);
+        assert!(db.sections.contains_key("provider-etag"));
+    }
+
+    #[tokio::test]
+    async fn test_sections_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let path = path.to_str().unwrap();
+
+        let cache = ThreadSafeCacheFile::new(path, true);
+        cache.set_selected("proxy", "ss").await;
+        cache
+            .set("provider-etag", &HashMap::from([("sub", "abc")]))
+            .await;
+        cache.flush().await;
+
+        let cache = ThreadSafeCacheFile::new(path, true);
+        assert_eq!(cache.get_selected("proxy").await.as_deref(), Some("ss"));
+        let etags: HashMap<String, String> =
+            cache.get("provider-etag").await.unwrap();
+        assert_eq!(etags.get("sub").map(String::as_str), Some("abc"));
+        assert!(cache.get::<Vec<u32>>("provider-etag").await.is_none());
+
+        // only the cache itself is left, no temporary files
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
     }
 }
@@ -1,22 +1,32 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use tokio::time::Instant;
 use tracing::debug;
 
-use crate::proxy::AnyOutboundHandler;
+use crate::{
+    common::scheduler::{Schedule, Task},
+    config::internal::proxy::HealthCheckType,
+    proxy::AnyOutboundHandler,
+};
 
 use super::ProxyManager;
 
+/// the most probes a proxy gets each round
+const MAX_SAMPLES: u8 = 10;
+
 struct HealCheckInner {
     last_check: Instant,
     proxies: Vec<AnyOutboundHandler>,
-    task_handle: Option<Arc<tokio::task::JoinHandle<()>>>,
+    task: Option<Task>,
 }
 
 pub struct HealthCheck {
     url: String,
+    typ: HealthCheckType,
     interval: u64,
     lazy: bool,
+    /// probes a proxy each round
+    samples: u8,
     proxy_manager: ProxyManager,
     inner: Arc<tokio::sync::RwLock<HealCheckInner>>,
 }
@@ -25,61 +35,102 @@ impl HealthCheck {
     pub fn new(
         proxies: Vec<AnyOutboundHandler>,
         url: String,
+        typ: HealthCheckType,
         interval: u64,
         lazy: bool,
         proxy_manager: ProxyManager,
     ) -> anyhow::Result<Self> {
         let health_check = Self {
             url,
+            typ,
             interval,
             lazy,
+            samples: 1,
             proxy_manager,
             inner: Arc::new(tokio::sync::RwLock::new(HealCheckInner {
                 last_check: tokio::time::Instant::now(),
                 proxies,
-                task_handle: None,
+                task: None,
             })),
         };
         Ok(health_check)
     }
 
+    /// `sample-count`, probes a proxy each round for its loss and jitter, 1
+    /// if None, 10 at most
+    pub fn with_sample_count(mut self, samples: Option<u8>) -> Self {
+        self.samples = samples.unwrap_or(1).clamp(1, MAX_SAMPLES);
+        self
+    }
+
     pub async fn kick_off(&self) {
         let proxy_manager = self.proxy_manager.clone();
         let interval = self.interval;
         let lazy = self.lazy;
+        let typ = self.typ;
+        let samples = self.samples;
         let proxies = self.inner.read().await.proxies.clone();
 
         {
             let url = self.url.clone();
-            let proxies = proxies.clone();
             tokio::spawn(async move {
-                proxy_manager.check(&proxies, &url, None).await;
+                proxy_manager
+                    .check_shared(
+                        &proxies,
+                        typ,
+                        &url,
+                        None,
+                        samples,
+                        Duration::from_secs(interval),
+                    )
+                    .await;
             });
         }
 
-        let inner = self.inner.clone();
+        // the task is kept in `inner`, so it's only held weakly here
+        let inner = Arc::downgrade(&self.inner);
         let proxy_manager = self.proxy_manager.clone();
         let url = self.url.clone();
-        let task_handle = tokio::spawn(async move {
-            let mut ticker =
-                tokio::time::interval(tokio::time::Duration::from_secs(interval));
-            loop {
-                tokio::select! {
-                    _ = ticker.tick() => {
-                        debug!("healthcheck ticking: {}, lazy: {}", url, lazy);
-                        let now = tokio::time::Instant::now();
-                        let last_check = inner.read().await.last_check;
-                        if !lazy || now.duration_since(last_check).as_secs() >= interval {
-                            proxy_manager.check(&proxies, &url, None).await;
-                            let mut w = inner.write().await;
-                            w.last_check = now;
-                        }
-                    },
+        let task = Task::spawn(
+            format!("healthcheck {}", url),
+            Schedule {
+                interval: Duration::from_secs(interval),
+                immediately: false,
+            },
+            move || {
+                let inner = inner.clone();
+                let proxy_manager = proxy_manager.clone();
+                let url = url.clone();
+                async move {
+                    let Some(inner) = inner.upgrade() else {
+                        return Ok(());
+                    };
+                    debug!("healthcheck ticking: {}, lazy: {}", url, lazy);
+                    let now = tokio::time::Instant::now();
+                    let (last_check, proxies) = {
+                        let r = inner.read().await;
+                        (r.last_check, r.proxies.clone())
+                    };
+                    if !lazy || now.duration_since(last_check).as_secs() >= interval
+                    {
+                        proxy_manager
+                            .check_shared(
+                                &proxies,
+                                typ,
+                                &url,
+                                None,
+                                samples,
+                                Duration::from_secs(interval),
+                            )
+                            .await;
+                        inner.write().await.last_check = now;
+                    }
+                    Ok(())
                 }
-            }
-        });
+            },
+        );
 
-        self.inner.write().await.task_handle = Some(Arc::new(task_handle));
+        self.inner.write().await.task = Some(task);
     }
 
     pub async fn touch(&self) {
@@ -88,7 +139,9 @@ impl HealthCheck {
 
     pub async fn check(&self) {
         let proxies = self.inner.read().await.proxies.clone();
-        self.proxy_manager.check(&proxies, &self.url, None).await;
+        self.proxy_manager
+            .check_with(&proxies, self.typ, &self.url, None, self.samples)
+            .await;
     }
 
     pub async fn update(&self, proxies: Vec<AnyOutboundHandler>) {
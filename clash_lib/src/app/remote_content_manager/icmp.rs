@@ -0,0 +1,171 @@
+//! The latency to a proxy server itself, not through it, for the health
+//! checks of `type: icmp`.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU16, Ordering},
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use crate::proxy::utils::{new_tcp_stream, protect_socket};
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+/// tells apart the replies to the pings running at the same time, raw
+/// sockets get all of them
+static SEQ: AtomicU16 = AtomicU16::new(0);
+
+/// The round trip of an echo request to `ip`, or the time it takes to
+/// connect to `tcp_port` when there is no ICMP socket to be had, e.g.
+/// without the privileges for a raw one. Without a `tcp_port`, for servers
+/// only listening on UDP, that is an `Unsupported` error.
+pub async fn ping(
+    ip: IpAddr,
+    tcp_port: Option<u16>,
+    timeout: Duration,
+) -> io::Result<Duration> {
+    let timed_out =
+        || io::Error::new(io::ErrorKind::TimedOut, format!("timeout for {}", ip));
+
+    match (icmp_socket(ip), tcp_port) {
+        (Ok(socket), _) => tokio::time::timeout(timeout, echo(&socket, ip))
+            .await
+            .map_err(|_| timed_out())?,
+        (Err(e), None) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("no icmp socket to ping {}: {}", ip, e),
+        )),
+        (Err(e), Some(port)) => {
+            debug!("no icmp socket for {}, timing a tcp connect: {}", ip, e);
+            let start = Instant::now();
+            tokio::time::timeout(
+                timeout,
+                new_tcp_stream(
+                    SocketAddr::new(ip, port),
+                    None,
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    None,
+                ),
+            )
+            .await
+            .map_err(|_| timed_out())??;
+            Ok(start.elapsed())
+        }
+    }
+}
+
+/// An unprivileged ICMP socket where the system allows it, like with
+/// `ping_group_range` on Linux, or else a raw one.
+fn icmp_socket(ip: IpAddr) -> io::Result<UdpSocket> {
+    let (domain, protocol) = match ip {
+        IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(protocol))
+        .or_else(|_| Socket::new(domain, Type::RAW, Some(protocol)))?;
+    protect_socket(&socket)?;
+    socket.set_nonblocking(true)?;
+    // only sendto and recvfrom are used, which work on any datagram socket
+    UdpSocket::from_std(socket.into())
+}
+
+async fn echo(socket: &UdpSocket, ip: IpAddr) -> io::Result<Duration> {
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+    let req = echo_request(ip.is_ipv4(), rand::random(), seq);
+
+    let start = Instant::now();
+    socket.send_to(&req, SocketAddr::new(ip, 0)).await?;
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        if from.ip() == ip && is_echo_reply(&buf[..n], ip.is_ipv4(), seq) {
+            return Ok(start.elapsed());
+        }
+    }
+}
+
+fn echo_request(v4: bool, id: u16, seq: u16) -> Vec<u8> {
+    let mut pkt = vec![0u8; 16];
+    pkt[0] = if v4 { ECHO_REQUEST_V4 } else { ECHO_REQUEST_V6 };
+    pkt[4..6].copy_from_slice(&id.to_be_bytes());
+    pkt[6..8].copy_from_slice(&seq.to_be_bytes());
+    pkt[8..].copy_from_slice(b"clash-rs");
+    // the kernel fills in the one of ICMPv6, it covers the IP header too
+    if v4 {
+        let sum = checksum(&pkt);
+        pkt[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    pkt
+}
+
+/// The id isn't compared, Linux replaces it with its own on unprivileged
+/// sockets.
+fn is_echo_reply(pkt: &[u8], v4: bool, seq: u16) -> bool {
+    // raw IPv4 sockets, and the unprivileged ones on macOS, get the IP
+    // header as well, which an ICMP message can't be mistaken for
+    let pkt = match pkt.first() {
+        Some(b) if v4 && b >> 4 == 4 => pkt.get(((b & 0x0f) as usize) * 4..),
+        _ => Some(pkt),
+    };
+    let Some(pkt) = pkt.filter(|x| x.len() >= 8) else {
+        return false;
+    };
+    let reply = if v4 { ECHO_REPLY_V4 } else { ECHO_REPLY_V6 };
+    pkt[0] == reply && pkt[6..8] == seq.to_be_bytes()
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], *x.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, echo_request, is_echo_reply};
+
+    #[test]
+    fn test_echo_request() {
+        let req = echo_request(true, 0x1234, 7);
+        assert_eq!(req[0], 8);
+        assert_eq!(&req[4..8], &[0x12, 0x34, 0, 7]);
+        // a message with its checksum in sums up to 0
+        assert_eq!(checksum(&req), 0);
+
+        assert_eq!(echo_request(false, 0x1234, 7)[0], 128);
+    }
+
+    #[test]
+    fn test_echo_reply() {
+        let mut reply = echo_request(true, 0x1234, 7);
+        reply[0] = 0;
+        assert!(is_echo_reply(&reply, true, 7));
+        assert!(!is_echo_reply(&reply, true, 8));
+        // the request itself, seen by a raw socket
+        assert!(!is_echo_reply(&echo_request(true, 0x1234, 7), true, 7));
+
+        let mut with_header = vec![0x45];
+        with_header.extend_from_slice(&[0; 19]);
+        with_header.extend_from_slice(&reply);
+        assert!(is_echo_reply(&with_header, true, 7));
+
+        let mut reply = echo_request(false, 0x1234, 7);
+        reply[0] = 129;
+        assert!(is_echo_reply(&reply, false, 7));
+        assert!(!is_echo_reply(&reply[..4], false, 7));
+    }
+}
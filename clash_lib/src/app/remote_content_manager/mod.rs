@@ -2,16 +2,16 @@ use std::{
     collections::{HashMap, VecDeque},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 
 use futures::{stream::FuturesUnordered, StreamExt};
-use http_body_util::Empty;
+use http_body_util::{BodyExt, Empty};
 use hyper::Request;
 use hyper_util::{client::legacy::Client, rt::TokioExecutor};
 use serde::Serialize;
@@ -20,7 +20,9 @@ use tracing::{debug, instrument, trace};
 
 use crate::{
     common::{errors::new_io_error, timed_future::TimedFuture},
-    proxy::AnyOutboundHandler,
+    config::internal::proxy::HealthCheckType,
+    proxy::{utils::DialError, AnyOutboundHandler, OutboundType},
+    session::SocksAddr,
 };
 
 use self::http_client::LocalConnector;
@@ -29,6 +31,7 @@ use super::dns::ThreadSafeDNSResolver;
 
 pub mod healthcheck;
 mod http_client;
+mod icmp;
 pub mod providers;
 
 #[derive(Clone, Serialize)]
@@ -37,12 +40,116 @@ pub struct DelayHistory {
     delay: u16,
     #[serde(rename = "meanDelay")]
     mean_delay: u16,
+    /// the delay of the first request, handshakes included, when `delay` is
+    /// the one of the second, see `unified-delay`. The same as `delay` when
+    /// the second request failed and the first is all there is
+    #[serde(rename = "handshakeDelay", skip_serializing_if = "Option::is_none")]
+    handshake_delay: Option<u16>,
+    /// the group the proxy was tested through, see
+    /// [`ProxyManager::group_url_test`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    /// of a round of several probes, see `sample-count`
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    samples: Option<Samples>,
+}
+
+/// how a round of several probes went, the delays are of the ones that
+/// didn't fail
+#[derive(Clone, Copy, Serialize)]
+pub struct Samples {
+    samples: u8,
+    /// how many failed
+    lost: u8,
+    /// in ms, how much the delays of the probes in a row differ on average
+    jitter: u16,
+}
+
+impl Samples {
+    fn new(samples: u8, delays: &[u16]) -> Self {
+        let jitter = match delays.len() {
+            0 | 1 => 0,
+            n => {
+                delays
+                    .windows(2)
+                    .map(|x| x[0].abs_diff(x[1]) as u32)
+                    .sum::<u32>()
+                    / (n as u32 - 1)
+            }
+        };
+        Self {
+            samples,
+            lost: samples - delays.len() as u8,
+            jitter: jitter as u16,
+        }
+    }
+}
+
+/// why the last dial or test through a proxy failed
+#[derive(Clone, Serialize)]
+pub struct LastError {
+    /// null when it isn't known
+    pub category: Option<DialError>,
+    pub message: String,
+    pub time: DateTime<Utc>,
+}
+
+impl LastError {
+    fn new(e: &std::io::Error) -> Self {
+        Self {
+            category: DialError::of(e),
+            message: e.to_string(),
+            time: Utc::now(),
+        }
+    }
 }
 
 #[derive(Default)]
 struct ProxyState {
     alive: AtomicBool,
     delay_history: VecDeque<DelayHistory>,
+    last_error: Option<LastError>,
+}
+
+/// (proxy, type, url, timeout, samples) of a test
+type TestKey = (String, HealthCheckType, String, Option<Duration>, u8);
+
+/// how often results nobody would take anymore are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// when a test was last run and how it went, locked while it runs so the
+/// groups asking for the same test at once wait for it instead
+type SharedResult = Arc<tokio::sync::Mutex<Option<TestResult>>>;
+
+struct TestResult {
+    at: Instant,
+    /// the longest `fresh` it was asked with, it's of no use past that
+    keep: Duration,
+    result: Result<(u16, u16), String>,
+}
+
+#[derive(Default)]
+struct SharedResults {
+    map: HashMap<TestKey, SharedResult>,
+    pruned: Option<Instant>,
+}
+
+impl SharedResults {
+    fn slot(&mut self, key: TestKey) -> SharedResult {
+        let now = Instant::now();
+        if self
+            .pruned
+            .is_none_or(|x| now.duration_since(x) >= PRUNE_INTERVAL)
+        {
+            // the ones being run are kept
+            self.map.retain(|_, x| match x.try_lock() {
+                Ok(x) => x.as_ref().is_some_and(|x| x.at.elapsed() < x.keep),
+                Err(_) => true,
+            });
+            self.pruned = Some(now);
+        }
+        self.map.entry(key).or_default().clone()
+    }
 }
 
 /// ProxyManager is the latency registry.
@@ -53,6 +160,11 @@ pub struct ProxyManager {
 
     connector_map:
         Arc<RwLock<HashMap<String, hyper_rustls::HttpsConnector<LocalConnector>>>>,
+    unified_delay: bool,
+
+    /// the results of the background checks, shared by all the groups
+    /// containing a proxy
+    results: Arc<Mutex<SharedResults>>,
 }
 
 impl ProxyManager {
@@ -61,6 +173,34 @@ impl ProxyManager {
             dns_resolver,
             proxy_state: Arc::new(RwLock::new(HashMap::new())),
             connector_map: Arc::new(RwLock::new(HashMap::new())),
+            unified_delay: false,
+            results: Default::default(),
+        }
+    }
+
+    /// url tests report the delay of a second request, on the connection the
+    /// first made
+    pub fn with_unified_delay(mut self, on: bool) -> Self {
+        self.unified_delay = on;
+        self
+    }
+
+    /// copies the liveness and latency history of `names` from `other`, so
+    /// handlers reused across a config reload keep their health state
+    pub async fn inherit(&self, other: &ProxyManager, names: &[String]) {
+        let other = other.proxy_state.read().await;
+        let mut state = self.proxy_state.write().await;
+        for name in names {
+            if let Some(s) = other.get(name) {
+                state.insert(
+                    name.to_owned(),
+                    ProxyState {
+                        alive: AtomicBool::new(s.alive.load(Ordering::Relaxed)),
+                        delay_history: s.delay_history.clone(),
+                        last_error: s.last_error.clone(),
+                    },
+                );
+            }
         }
     }
 
@@ -69,6 +209,49 @@ impl ProxyManager {
         proxies: &Vec<AnyOutboundHandler>,
         url: &str,
         timeout: Option<Duration>,
+    ) {
+        self.check_with(proxies, HealthCheckType::Http, url, timeout, 1)
+            .await
+    }
+
+    /// [`ProxyManager::check`] measuring the latency as `typ` says, with
+    /// `samples` probes a proxy
+    pub async fn check_with(
+        &self,
+        proxies: &Vec<AnyOutboundHandler>,
+        typ: HealthCheckType,
+        url: &str,
+        timeout: Option<Duration>,
+        samples: u8,
+    ) {
+        self.check_all(proxies, typ, url, timeout, samples, None)
+            .await
+    }
+
+    /// [`ProxyManager::check_with`] for the background checks of the groups,
+    /// a proxy in several of them isn't tested again with the same url and
+    /// timeout within `fresh` of the last time
+    pub async fn check_shared(
+        &self,
+        proxies: &Vec<AnyOutboundHandler>,
+        typ: HealthCheckType,
+        url: &str,
+        timeout: Option<Duration>,
+        samples: u8,
+        fresh: Duration,
+    ) {
+        self.check_all(proxies, typ, url, timeout, samples, Some(fresh))
+            .await
+    }
+
+    async fn check_all(
+        &self,
+        proxies: &Vec<AnyOutboundHandler>,
+        typ: HealthCheckType,
+        url: &str,
+        timeout: Option<Duration>,
+        samples: u8,
+        fresh: Option<Duration>,
     ) {
         let mut futs = vec![];
         for proxy in proxies {
@@ -76,10 +259,26 @@ impl ProxyManager {
             let url = url.to_owned();
             let manager = self.clone();
             futs.push(tokio::spawn(async move {
-                manager
-                    .url_test(proxy, url.as_str(), timeout)
-                    .await
-                    .map_err(|e| debug!("healthcheck failed: {}", e))
+                match fresh {
+                    Some(fresh) => {
+                        manager
+                            .shared_test(
+                                proxy,
+                                typ,
+                                url.as_str(),
+                                timeout,
+                                samples,
+                                fresh,
+                            )
+                            .await
+                    }
+                    None => {
+                        manager
+                            .test(proxy, typ, url.as_str(), timeout, samples)
+                            .await
+                    }
+                }
+                .map_err(|e| debug!("healthcheck failed: {}", e))
             }));
         }
 
@@ -87,6 +286,113 @@ impl ProxyManager {
         let _: Vec<_> = futs.collect().await;
     }
 
+    async fn test(
+        &self,
+        proxy: AnyOutboundHandler,
+        typ: HealthCheckType,
+        url: &str,
+        timeout: Option<Duration>,
+        samples: u8,
+    ) -> std::io::Result<(u16, u16)> {
+        if samples > 1 {
+            return self.sampled_test(proxy, typ, url, timeout, samples).await;
+        }
+        match typ {
+            HealthCheckType::Http => self.url_test(proxy, url, timeout).await,
+            HealthCheckType::Icmp => self.ping_test(proxy, url, timeout).await,
+        }
+    }
+
+    /// `samples` probes one after the other, kept as one entry of the
+    /// history with how many failed and the jitter. The delay is the median
+    /// of the others, the mean delay their mean.
+    async fn sampled_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        typ: HealthCheckType,
+        url: &str,
+        timeout: Option<Duration>,
+        samples: u8,
+    ) -> std::io::Result<(u16, u16)> {
+        let name = proxy.name().to_owned();
+        let server = match typ {
+            HealthCheckType::Http => None,
+            HealthCheckType::Icmp => proxy.server(),
+        };
+
+        let mut delays = vec![];
+        let mut last_err = None;
+        for _ in 0..samples {
+            let probe = match &server {
+                Some(server) => {
+                    self.ping_probe(&name, proxy.proto(), server, timeout).await
+                }
+                None => self
+                    .url_probe(proxy.clone(), None, url, timeout)
+                    .await
+                    .map(|(delay, mean_delay, _)| (delay, mean_delay)),
+            };
+            match probe {
+                Ok((delay, _)) => delays.push(delay),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        let stats = Samples::new(samples, &delays);
+        let result = match last_err {
+            Some(e) if delays.is_empty() => Err(e),
+            _ => {
+                let mean = delays.iter().map(|x| *x as u32).sum::<u32>()
+                    / delays.len() as u32;
+                let mut sorted = delays.clone();
+                sorted.sort_unstable();
+                Ok((sorted[sorted.len() / 2], mean as u16))
+            }
+        };
+        self.record(&name, &result, None, None, Some(stats)).await;
+        result
+    }
+
+    /// the result of the same test run within `fresh`, by this or another
+    /// group, or of running it now
+    async fn shared_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        typ: HealthCheckType,
+        url: &str,
+        timeout: Option<Duration>,
+        samples: u8,
+        fresh: Duration,
+    ) -> std::io::Result<(u16, u16)> {
+        let key = (
+            proxy.name().to_owned(),
+            typ,
+            url.to_owned(),
+            timeout,
+            samples,
+        );
+        let slot = self.results.lock().unwrap().slot(key);
+
+        let mut last = slot.lock().await;
+        let mut keep = fresh;
+        if let Some(last) = last.as_mut() {
+            last.keep = last.keep.max(fresh);
+            if last.at.elapsed() < fresh {
+                trace!("{} was tested with {} lately", proxy.name(), url);
+                return last.result.clone().map_err(new_io_error);
+            }
+            keep = last.keep;
+        }
+
+        let result = self.test(proxy, typ, url, timeout, samples).await;
+        *last = Some(TestResult {
+            at: Instant::now(),
+            keep,
+            result: result.as_ref().map(|x| *x).map_err(|e| e.to_string()),
+        });
+        result
+    }
+
     pub async fn alive(&self, name: &str) -> bool {
         self.proxy_state
             .read()
@@ -102,6 +408,21 @@ impl ProxyManager {
         state.alive.store(alive, Ordering::Relaxed)
     }
 
+    /// keeps `e` as the last error of `name`, e.g. a dial that failed
+    pub async fn report_error(&self, name: &str, e: &std::io::Error) {
+        let mut state = self.proxy_state.write().await;
+        state.entry(name.to_owned()).or_default().last_error =
+            Some(LastError::new(e));
+    }
+
+    pub async fn last_error(&self, name: &str) -> Option<LastError> {
+        self.proxy_state
+            .read()
+            .await
+            .get(name)
+            .and_then(|x| x.last_error.clone())
+    }
+
     pub async fn delay_history(&self, name: &str) -> Vec<DelayHistory> {
         self.proxy_state
             .read()
@@ -112,6 +433,23 @@ impl ProxyManager {
             .into()
     }
 
+    /// the percentage of the probes of the last `rounds` rounds of several
+    /// that failed, None if there were none of those
+    pub async fn loss(&self, name: &str, rounds: usize) -> Option<u8> {
+        let (samples, lost) = self
+            .delay_history(name)
+            .await
+            .iter()
+            .rev()
+            .filter(|x| x.group.is_none())
+            .filter_map(|x| x.samples)
+            .take(rounds)
+            .fold((0u32, 0u32), |(samples, lost), x| {
+                (samples + x.samples as u32, lost + x.lost as u32)
+            });
+        (samples > 0).then(|| (lost * 100 / samples) as u8)
+    }
+
     pub async fn last_delay(&self, name: &str) -> u16 {
         let max = u16::MAX;
         if !self.alive(name).await {
@@ -119,19 +457,62 @@ impl ProxyManager {
         }
         self.delay_history(name)
             .await
-            .last()
+            .iter()
+            .rev()
+            .find(|x| x.group.is_none())
             .map(|x| x.delay)
             .unwrap_or(max)
     }
 
-    #[instrument(skip(self, proxy))]
     pub async fn url_test(
         &self,
         proxy: AnyOutboundHandler,
         url: &str,
         timeout: Option<Duration>,
     ) -> std::io::Result<(u16, u16)> {
+        self.group_url_test(proxy, None, url, timeout).await
+    }
+
+    /// Tests `proxy` as dialed by `group`, e.g. the hops of a relay up to a
+    /// member, named after that member. The result goes in the member's
+    /// history tagged with the group, and neither its liveness nor the
+    /// delay the groups pick by is changed.
+    #[instrument(skip(self, proxy))]
+    pub async fn group_url_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        group: Option<&str>,
+        url: &str,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<(u16, u16)> {
+        let name = proxy.name().to_owned();
+        let (result, handshake_delay) =
+            match self.url_probe(proxy, group, url, timeout).await {
+                Ok((delay, mean_delay, handshake_delay)) => {
+                    (Ok((delay, mean_delay)), handshake_delay)
+                }
+                Err(e) => (Err(e), None),
+            };
+        self.record(&name, &result, handshake_delay, group, None)
+            .await;
+        result
+    }
+
+    /// fetches `url` through `proxy` as [`ProxyManager::group_url_test`]
+    /// does, the handshake delay last, without keeping the result
+    async fn url_probe(
+        &self,
+        proxy: AnyOutboundHandler,
+        group: Option<&str>,
+        url: &str,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<(u16, u16, Option<u16>)> {
         let name = proxy.name().to_owned();
+        // the connections of a group's handler aren't the member's
+        let connector_key = match group {
+            Some(group) => format!("{}/{}", group, name),
+            None => name.clone(),
+        };
         let name_clone = name.clone();
         let default_timeout = Duration::from_secs(5);
 
@@ -156,7 +537,7 @@ impl ProxyManager {
                     .wrap_connector(connector);
 
                 let mut g = self.connector_map.write().await;
-                let connector = g.entry(name.clone()).or_insert(connector);
+                let connector = g.entry(connector_key).or_insert(connector);
                 connector.clone()
             };
 
@@ -164,91 +545,176 @@ impl ProxyManager {
             let client: Client<_, Empty<Bytes>> =
                 Client::builder(TokioExecutor::new()).build(connector);
 
-            let req = Request::get(url)
-                .header("Connection", "Close")
-                .version(hyper::Version::HTTP_11)
-                .body(Empty::new())
-                .unwrap();
+            let unified = self.unified_delay;
+            let timeout = timeout.unwrap_or(default_timeout);
+            // the connection is only kept for the second request when it's the
+            // one measured
+            let request = |keep_alive: bool| {
+                let mut req = Request::get(url).version(hyper::Version::HTTP_11);
+                if !keep_alive {
+                    req = req.header("Connection", "Close");
+                }
+                req.body(Empty::new()).unwrap()
+            };
+            let ms = |d: Duration| u16::try_from(d.as_millis()).unwrap_or(u16::MAX);
 
-            let resp = TimedFuture::new(client.request(req), None);
+            let resp = TimedFuture::new(client.request(request(unified)), None);
+            let (res, delay) = match tokio::time::timeout(timeout, resp).await {
+                Ok((Ok(res), delay)) => (res, ms(delay)),
+                Ok((Err(e), _)) => {
+                    debug!(
+                        "urltest for proxy {} with url {} failed: {}",
+                        &name, url, e
+                    );
+                    let err = new_io_error(format!("{}: {}", url, e).as_str());
+                    return Err(match DialError::find(&e) {
+                        Some(category) => category.tag(err),
+                        None => err,
+                    });
+                }
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("timeout for {}", url),
+                    ))
+                }
+            };
+            trace!(
+                "urltest for proxy {} with url {} returned response {} in {}ms",
+                &name,
+                url,
+                res.status(),
+                delay
+            );
+            if unified {
+                // the connection goes back to the pool once the body is read
+                let _ =
+                    tokio::time::timeout(timeout, res.into_body().collect()).await;
+            }
 
-            let delay: u16 =
-                match tokio::time::timeout(timeout.unwrap_or(default_timeout), resp)
-                    .await
-                {
-                    Ok((res, delay)) => match res {
-                        Ok(res) => {
-                            let delay = delay
-                                .as_millis()
-                                .try_into()
-                                .expect("delay is too large");
-                            trace!(
-                                "urltest for proxy {} with url {} returned \
-                                 response {} in {}ms",
-                                &name,
-                                url,
-                                res.status(),
-                                delay
-                            );
-                            Ok(delay)
-                        }
-                        Err(e) => {
-                            debug!(
-                                "urltest for proxy {} with url {} failed: {}",
-                                &name, url, e
-                            );
-                            Err(new_io_error(format!("{}: {}", url, e).as_str()))
-                        }
-                    },
-                    Err(_) => {
-                        Err(new_io_error(format!("timeout for {}", url).as_str()))
-                    }
-                }?;
-
-            let req2 = Request::get(url)
-                .header("Connection", "Close")
-                .version(hyper::Version::HTTP_11)
-                .body(Empty::new())
-                .unwrap();
-            let resp2 = TimedFuture::new(client.request(req2), None);
-
-            let mean_delay: u16 = match tokio::time::timeout(
-                timeout.unwrap_or(default_timeout),
-                resp2,
-            )
-            .await
-            {
-                Ok((res, delay2)) => match res {
-                    Ok(_) => ((delay2.as_millis() + delay as u128) / 2)
-                        .try_into()
-                        .expect("delay is too large"),
-                    Err(_) => 0,
-                },
-                Err(_) => 0,
+            let resp2 = TimedFuture::new(client.request(request(false)), None);
+            let delay2 = match tokio::time::timeout(timeout, resp2).await {
+                Ok((Ok(_), delay2)) => Some(ms(delay2)),
+                _ => None,
             };
 
-            Ok((delay, mean_delay))
+            Ok(match (unified, delay2) {
+                (true, Some(delay2)) => {
+                    trace!(
+                        "urltest for proxy {} took {}ms on a warm connection",
+                        &name,
+                        delay2
+                    );
+                    (
+                        delay2,
+                        ((delay as u32 + delay2 as u32) / 2) as u16,
+                        Some(delay),
+                    )
+                }
+                (_, Some(delay2)) => {
+                    (delay, ((delay as u32 + delay2 as u32) / 2) as u16, None)
+                }
+                // the handshakes are in it, which is told by it being the
+                // handshake delay too
+                (true, None) => (delay, 0, Some(delay)),
+                (false, None) => (delay, 0, None),
+            })
+        };
+
+        tester.await
+    }
+
+    /// Pings the server of `proxy` instead of fetching `url` through it,
+    /// which is still done for the ones without a server, see
+    /// [`HealthCheckType::Icmp`].
+    pub async fn ping_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        url: &str,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<(u16, u16)> {
+        let Some(server) = proxy.server() else {
+            return self.url_test(proxy, url, timeout).await;
         };
+        let name = proxy.name().to_owned();
 
-        let result = tester.await;
+        let result = self
+            .ping_probe(&name, proxy.proto(), &server, timeout)
+            .await;
+        self.record(&name, &result, None, None, None).await;
+        result
+    }
 
-        self.report_alive(&name, result.is_ok()).await;
+    /// pings `server` as [`ProxyManager::ping_test`] does, without keeping
+    /// the result. Timing a TCP connect in place of the ping means nothing
+    /// for the protocols only listening on UDP, they fail as unsupported.
+    async fn ping_probe(
+        &self,
+        name: &str,
+        proto: OutboundType,
+        server: &SocksAddr,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<(u16, u16)> {
+        let timeout = timeout.unwrap_or(Duration::from_secs(5));
+
+        let ip = match server {
+            SocksAddr::Ip(addr) => addr.ip(),
+            SocksAddr::Domain(host, _) => self
+                .dns_resolver
+                .resolve(host, false)
+                .await
+                .map_err(|e| new_io_error(e.to_string()))?
+                .ok_or_else(|| new_io_error(format!("no address for {}", host)))?,
+        };
+        let ms = |d: Duration| u16::try_from(d.as_millis()).unwrap_or(u16::MAX);
+        let tcp_port = match proto {
+            OutboundType::Hysteria2
+            | OutboundType::Tuic
+            | OutboundType::WireGuard => None,
+            _ => Some(server.port()),
+        };
+
+        let delay = ms(icmp::ping(ip, tcp_port, timeout).await?);
+        trace!("ping for proxy {} to {} took {}ms", name, ip, delay);
+        let mean_delay = match icmp::ping(ip, tcp_port, timeout).await {
+            Ok(d) => ((ms(d) as u32 + delay as u32) / 2) as u16,
+            Err(_) => 0,
+        };
+        Ok((delay, mean_delay))
+    }
+
+    /// the outcome of a test, in the liveness and the latency history
+    async fn record(
+        &self,
+        name: &str,
+        result: &std::io::Result<(u16, u16)>,
+        handshake_delay: Option<u16>,
+        group: Option<&str>,
+        samples: Option<Samples>,
+    ) {
+        if group.is_none() {
+            self.report_alive(name, result.is_ok()).await;
+        }
 
         let ins = DelayHistory {
             time: Utc::now(),
             delay: result.as_ref().map(|x| x.0).unwrap_or(0),
             mean_delay: result.as_ref().map(|x| x.1).unwrap_or(0),
+            handshake_delay,
+            group: group.map(ToOwned::to_owned),
+            samples,
         };
 
         let mut state = self.proxy_state.write().await;
         let state = state.entry(name.to_owned()).or_default();
 
+        if let Err(e) = result {
+            state.last_error = Some(LastError::new(e));
+        }
         state.delay_history.push_back(ins);
         if state.delay_history.len() > 10 {
             state.delay_history.pop_front();
         }
-
-        result
     }
 }
 
@@ -263,8 +729,11 @@ mod tests {
             dispatcher::ChainedStreamWrapper, dns::MockClashResolver,
             remote_content_manager,
         },
-        config::internal::proxy::PROXY_DIRECT,
-        proxy::{direct, mocks::MockDummyOutboundHandler},
+        config::internal::proxy::{HealthCheckType, PROXY_DIRECT},
+        proxy::{
+            direct, mocks::MockDummyOutboundHandler, AnyOutboundHandler,
+            OutboundType,
+        },
     };
 
     #[tokio::test]
@@ -312,6 +781,51 @@ mod tests {
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 10);
     }
 
+    #[tokio::test]
+    async fn test_unified_delay() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/generate_204", listener.local_addr().unwrap());
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = s.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                        let _ =
+                            s.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+                    }
+                });
+            }
+        });
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some(Ipv4Addr::LOCALHOST.into())));
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver))
+                .with_unified_delay(true);
+
+        manager
+            .url_test(Arc::new(direct::Handler::new()), &url, None)
+            .await
+            .expect("test failed");
+
+        // both requests on the same connection
+        assert_eq!(conns.load(Ordering::SeqCst), 1);
+        let history = manager.delay_history(PROXY_DIRECT).await;
+        assert!(history[0].handshake_delay.is_some());
+    }
+
     #[tokio::test]
     async fn test_proxy_manager_timeout() {
         let mut mock_resolver = MockClashResolver::new();
@@ -350,4 +864,224 @@ mod tests {
         assert!(manager.last_delay(PROXY_DIRECT).await == u16::MAX);
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 1);
     }
+
+    #[tokio::test]
+    async fn test_shared_check() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+
+        let mut mock_handler = MockDummyOutboundHandler::new();
+        mock_handler.expect_name().return_const("shared".to_owned());
+        // the second group takes the result of the first
+        mock_handler
+            .expect_connect_stream()
+            .times(1)
+            .returning(|_, _| Err(std::io::Error::other("down")));
+        let proxies: Vec<AnyOutboundHandler> = vec![Arc::new(mock_handler)];
+
+        for _ in 0..2 {
+            manager
+                .check_shared(
+                    &proxies,
+                    HealthCheckType::Http,
+                    "http://www.gstatic.com/generate_204",
+                    None,
+                    1,
+                    Duration::from_secs(60),
+                )
+                .await;
+        }
+
+        assert!(!manager.alive("shared").await);
+        assert_eq!(manager.delay_history("shared").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shared_results_pruned() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+
+        let check = |name: &'static str| {
+            let mut mock_handler = MockDummyOutboundHandler::new();
+            mock_handler.expect_name().return_const(name.to_owned());
+            mock_handler
+                .expect_connect_stream()
+                .returning(|_, _| Err(std::io::Error::other("down")));
+            let proxies: Vec<AnyOutboundHandler> = vec![Arc::new(mock_handler)];
+            let manager = manager.clone();
+            async move {
+                manager
+                    .check_shared(
+                        &proxies,
+                        HealthCheckType::Http,
+                        "http://www.gstatic.com/generate_204",
+                        None,
+                        1,
+                        Duration::ZERO,
+                    )
+                    .await
+            }
+        };
+
+        check("a").await;
+        assert_eq!(manager.results.lock().unwrap().map.len(), 1);
+
+        // as if the interval passed, the result of `a` is of no use anymore
+        manager.results.lock().unwrap().pruned = None;
+        check("b").await;
+        let results = manager.results.lock().unwrap();
+        assert_eq!(results.map.len(), 1);
+        assert!(results.map.keys().all(|x| x.0 == "b"));
+    }
+
+    #[tokio::test]
+    async fn test_sampled_check() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    if s.read(&mut buf).await.is_ok() {
+                        let _ =
+                            s.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+                    }
+                });
+            }
+        });
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some(Ipv4Addr::LOCALHOST.into())));
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+
+        // the first two probes are lost
+        let calls = AtomicUsize::new(0);
+        let mut mock_handler = MockDummyOutboundHandler::new();
+        mock_handler.expect_name().return_const("lossy".to_owned());
+        mock_handler.expect_connect_stream().returning(move |_, _| {
+            if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Err(std::io::Error::other("lost"));
+            }
+            let s = std::net::TcpStream::connect(addr).unwrap();
+            s.set_nonblocking(true).unwrap();
+            Ok(Box::new(ChainedStreamWrapper::new(
+                tokio::net::TcpStream::from_std(s).unwrap(),
+            )))
+        });
+        let proxies: Vec<AnyOutboundHandler> = vec![Arc::new(mock_handler)];
+
+        manager
+            .check_with(
+                &proxies,
+                HealthCheckType::Http,
+                &format!("http://{}/generate_204", addr),
+                None,
+                4,
+            )
+            .await;
+
+        assert!(manager.alive("lossy").await);
+        let history = manager.delay_history("lossy").await;
+        assert_eq!(history.len(), 1);
+        let json = serde_json::to_value(&history[0]).unwrap();
+        assert_eq!(json["samples"], 4);
+        assert_eq!(json["lost"], 2);
+        assert_eq!(manager.loss("lossy", 5).await, Some(50));
+        assert_eq!(manager.loss(PROXY_DIRECT, 5).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_group_url_test() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+
+        let mut mock_handler = MockDummyOutboundHandler::new();
+        mock_handler.expect_name().return_const("hk-01".to_owned());
+        mock_handler
+            .expect_connect_stream()
+            .returning(|_, _| Err(std::io::Error::other("down")));
+
+        let result = manager
+            .group_url_test(
+                Arc::new(mock_handler),
+                Some("relay"),
+                "http://www.gstatic.com/generate_204",
+                None,
+            )
+            .await;
+
+        assert!(result.is_err());
+        // the group's picks don't go by it
+        assert!(manager.alive("hk-01").await);
+        assert_eq!(manager.last_delay("hk-01").await, u16::MAX);
+        let history = manager.delay_history("hk-01").await;
+        assert_eq!(history[0].group.as_deref(), Some("relay"));
+        assert_eq!(serde_json::to_value(&history[0]).unwrap()["group"], "relay");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_manager_ping() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+
+        // what's timed without an icmp socket
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+
+        let mut mock_handler = MockDummyOutboundHandler::new();
+        mock_handler.expect_name().return_const("server".to_owned());
+        mock_handler
+            .expect_proto()
+            .return_const(OutboundType::Shadowsocks);
+        mock_handler
+            .expect_server()
+            .returning(move || Some(server.into()));
+        // the proxy itself isn't used
+        mock_handler.expect_connect_stream().never();
+
+        manager
+            .ping_test(Arc::new(mock_handler), "http://unused", None)
+            .await
+            .expect("ping failed");
+
+        assert!(manager.alive("server").await);
+        assert_eq!(manager.delay_history("server").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_manager_ping_udp_only() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+
+        // a tcp connect would go through, but says nothing of a quic server
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+
+        let mut mock_handler = MockDummyOutboundHandler::new();
+        mock_handler.expect_name().return_const("server".to_owned());
+        mock_handler.expect_proto().return_const(OutboundType::Tuic);
+        mock_handler
+            .expect_server()
+            .returning(move || Some(server.into()));
+
+        match manager
+            .ping_test(Arc::new(mock_handler), "http://unused", None)
+            .await
+        {
+            // where there is an icmp socket
+            Ok(_) => {}
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported),
+        }
+    }
 }
@@ -8,9 +8,12 @@ use std::{
 use chrono::{DateTime, Utc};
 use futures::future::BoxFuture;
 use tokio::sync::{Mutex, RwLock};
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, info, trace};
 
-use crate::common::utils;
+use crate::common::{
+    scheduler::{Schedule, Task},
+    utils,
+};
 
 use super::{ProviderVehicleType, ThreadSafeProviderVehicle};
 
@@ -18,7 +21,7 @@ struct Inner {
     updated_at: SystemTime,
     hash: [u8; 16],
 
-    thread_handle: Option<tokio::task::JoinHandle<()>>,
+    task: Option<Arc<Task>>,
 }
 
 pub struct Fetcher<U, P> {
@@ -52,7 +55,7 @@ where
             inner: Arc::new(tokio::sync::RwLock::new(Inner {
                 updated_at: SystemTime::UNIX_EPOCH,
                 hash: [0; 16],
-                thread_handle: None,
+                task: None,
             })),
             parser: Arc::new(Mutex::new(parser)),
             on_update: on_update.map(|f| Arc::new(Mutex::new(f))),
@@ -123,16 +126,19 @@ where
         drop(inner);
 
         if !self.ticker_interval.is_zero() {
-            self.pull_loop(
-                immediately_update,
-                tokio::time::interval(self.ticker_interval),
-            )
-            .await;
+            self.pull_loop(immediately_update).await;
         }
 
         Ok(proxies)
     }
 
+    /// updates on the schedule's task, if there is one, so the next update
+    /// is an interval from now, `on_update` being called by the task
+    pub async fn run_now(&self) -> Option<anyhow::Result<()>> {
+        let task = self.inner.read().await.task.clone()?;
+        Some(task.run_now().await.map_err(|e| anyhow::anyhow!(e)))
+    }
+
     pub async fn update(&self) -> anyhow::Result<(T, bool)> {
         Fetcher::<U, P>::update_inner(
             self.inner.clone(),
@@ -181,65 +187,49 @@ where
 
     #[cfg(test)]
     pub async fn destroy(&mut self) {
-        if let Some(handle) = self.inner.write().await.thread_handle.take() {
-            handle.abort();
-        }
+        self.inner.write().await.task.take();
     }
 
-    async fn pull_loop(
-        &self,
-        immediately_update: bool,
-        mut ticker: tokio::time::Interval,
-    ) {
+    async fn pull_loop(&self, immediately_update: bool) {
         let inner = self.inner.clone();
         let vehicle = self.vehicle.clone();
         let parser = self.parser.clone();
         let on_update = self.on_update.clone();
         let name = self.name.clone();
-        let fire_immediately = immediately_update;
 
-        let thread_handle = Some(tokio::spawn(async move {
-            debug!("fetcher {} started", &name);
-            loop {
+        debug!("fetcher {} started", &name);
+        let task = Task::spawn(
+            format!("provider {}", name),
+            Schedule {
+                interval: self.ticker_interval,
+                immediately: immediately_update,
+            },
+            move || {
                 let inner = inner.clone();
                 let vehicle = vehicle.clone();
                 let parser = parser.clone();
                 let name = name.clone();
                 let on_update = on_update.clone();
-                let update = || async move {
+                async move {
                     let (elm, same) =
-                        match Fetcher::<U, P>::update_inner(inner, vehicle, parser)
-                            .await
-                        {
-                            Ok((elm, same)) => (elm, same),
-                            Err(e) => {
-                                warn!("{} update failed: {}", &name, e);
-                                return;
-                            }
-                        };
+                        Fetcher::<U, P>::update_inner(inner, vehicle, parser)
+                            .await?;
 
                     if same {
                         trace!("fetcher {} no update", &name);
-                        return;
+                        return Ok(());
                     }
 
                     if let Some(on_update) = on_update {
                         info!("fetcher {} updated", &name);
                         on_update.lock().await(elm).await;
                     }
-                };
-
-                if fire_immediately {
-                    update().await;
-                    ticker.tick().await;
-                } else {
-                    ticker.tick().await;
-                    update().await;
+                    Ok(())
                 }
-            }
-        }));
+            },
+        );
 
-        self.inner.write().await.thread_handle = thread_handle;
+        self.inner.write().await.task = Some(Arc::new(task));
     }
 }
 
@@ -298,7 +288,8 @@ mod tests {
 
         let _ = f.initial().await;
 
-        sleep(Duration::from_secs_f64(5.5)).await;
+        // the first update is an interval and up to one more of jitter later
+        sleep(Duration::from_secs_f64(7.5)).await;
         f.destroy().await;
 
         drop(tx);
@@ -68,7 +68,7 @@ impl ProviderVehicle for Vehicle {
 #[cfg(test)]
 mod tests {
     use super::ProviderVehicle;
-    use std::{str, sync::Arc};
+    use std::str;
 
     use hyper::Uri;
 
@@ -80,7 +80,7 @@ mod tests {
             .parse::<Uri>()
             .unwrap();
         let p = std::env::temp_dir().join("test_http_vehicle");
-        let r = Arc::new(EnhancedResolver::new_default().await);
+        let r = EnhancedResolver::new_default().await;
         let v = super::Vehicle::new(u, p, None, r.clone() as ThreadSafeDNSResolver);
 
         let data = v.read().await.unwrap();
@@ -22,4 +22,8 @@ pub trait ProxyProvider: Provider {
     async fn touch(&self);
     /// this is a blocking call, you may want to spawn a new task to run this
     async fn healthcheck(&self);
+    /// the proxies with `pre-connect` set
+    async fn pre_connect(&self) -> Vec<AnyOutboundHandler> {
+        vec![]
+    }
 }
@@ -1,34 +1,35 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use erased_serde::Serialize as ESerialize;
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Value;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use super::ProxyProvider;
 use crate::{
-    app::remote_content_manager::{
-        healthcheck::HealthCheck,
-        providers::{
-            fetcher::Fetcher, Provider, ProviderType, ProviderVehicleType,
-            ThreadSafeProviderVehicle,
+    app::{
+        outbound::utils::build_handlers,
+        profile::ThreadSafeCacheFile,
+        remote_content_manager::{
+            healthcheck::HealthCheck,
+            providers::{
+                fetcher::Fetcher, Provider, ProviderType, ProviderVehicleType,
+                ThreadSafeProviderVehicle,
+            },
         },
     },
     common::errors::map_io_error,
-    config::internal::proxy::OutboundProxyProtocol,
-    proxy::{direct, reject, socks, trojan, vmess, wg, AnyOutboundHandler},
+    config::{def::ClientFingerprint, internal::proxy::OutboundProxyProtocol},
+    proxy::AnyOutboundHandler,
     Error,
 };
 
-#[cfg(feature = "shadowsocks")]
-use crate::proxy::shadowsocks;
-#[cfg(feature = "onion")]
-use crate::proxy::tor;
-#[cfg(feature = "tuic")]
-use crate::proxy::tuic;
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ProviderScheme {
     #[serde(rename = "proxies")]
@@ -38,16 +39,23 @@ struct ProviderScheme {
 struct Inner {
     proxies: Vec<AnyOutboundHandler>,
     hc: Arc<HealthCheck>,
+    /// the names of the proxies with `pre-connect` set
+    pre_connect: HashSet<String>,
 }
 
 type ProxyUpdater = Box<
-    dyn Fn(Vec<AnyOutboundHandler>) -> BoxFuture<'static, ()>
+    dyn Fn(Vec<OutboundProxyProtocol>) -> BoxFuture<'static, ()>
         + Send
         + Sync
         + 'static,
 >;
+/// the proxies are only parsed here, they are built by the updater, off the
+/// runtime's threads
 type ProxyParser = Box<
-    dyn Fn(&[u8]) -> anyhow::Result<Vec<AnyOutboundHandler>> + Send + Sync + 'static,
+    dyn Fn(&[u8]) -> anyhow::Result<Vec<OutboundProxyProtocol>>
+        + Send
+        + Sync
+        + 'static,
 >;
 
 pub struct ProxySetProvider {
@@ -56,11 +64,15 @@ pub struct ProxySetProvider {
 }
 
 impl ProxySetProvider {
+    /// `client_fingerprint` is `global-client-fingerprint`, for the proxies
+    /// that don't set their own
     pub fn new(
         name: String,
         interval: Duration,
         vehicle: ThreadSafeProviderVehicle,
         hc: HealthCheck,
+        cache_store: ThreadSafeCacheFile,
+        client_fingerprint: Option<ClientFingerprint>,
     ) -> anyhow::Result<Self> {
         let hc = Arc::new(hc);
 
@@ -75,20 +87,38 @@ impl ProxySetProvider {
         let inner = Arc::new(tokio::sync::RwLock::new(Inner {
             proxies: vec![],
             hc: hc.clone(),
+            pre_connect: HashSet::new(),
         }));
 
         let inner_clone = inner.clone();
 
         let n = name.clone();
         let updater: ProxyUpdater = Box::new(
-            move |input: Vec<AnyOutboundHandler>| -> BoxFuture<'static, ()> {
+            move |input: Vec<OutboundProxyProtocol>| -> BoxFuture<'static, ()> {
                 let hc = hc.clone();
                 let n = n.clone();
                 let inner: Arc<tokio::sync::RwLock<Inner>> = inner_clone.clone();
+                let cache_store = cache_store.clone();
                 Box::pin(async move {
+                    let pre_connect = input
+                        .iter()
+                        .filter(|x| x.pre_connect())
+                        .map(|x| x.name().to_owned())
+                        .collect();
+                    let input = match build_handlers(input).await {
+                        Ok(input) => input,
+                        Err(e) => {
+                            warn!("failed to build the proxies of {}: {}", n, e);
+                            return;
+                        }
+                    };
+                    for h in &input {
+                        h.set_cache_store(cache_store.clone());
+                    }
                     let mut inner = inner.write().await;
                     debug!("updating {} proxies for: {}", n, input.len());
                     inner.proxies.clone_from(&input);
+                    inner.pre_connect = pre_connect;
                     hc.update(input).await;
                     // check once after update
                     tokio::spawn(async move {
@@ -110,51 +140,14 @@ impl ProxySetProvider {
                     })?;
                 let proxies = scheme.proxies;
                 if let Some(proxies) = proxies {
-                    let proxies = proxies
+                    Ok(proxies
                         .into_iter()
                         .filter_map(|x| OutboundProxyProtocol::try_from(x).ok())
-                        .map(|x| match x {
-                            OutboundProxyProtocol::Direct => {
-                                Ok(Arc::new(direct::Handler::new()) as _)
-                            }
-                            OutboundProxyProtocol::Reject => {
-                                Ok(Arc::new(reject::Handler::new()) as _)
-                            }
-                            #[cfg(feature = "shadowsocks")]
-                            OutboundProxyProtocol::Ss(s) => {
-                                let h: shadowsocks::Handler = s.try_into()?;
-                                Ok(Arc::new(h) as _)
-                            }
-                            OutboundProxyProtocol::Socks5(s) => {
-                                let h: socks::Handler = s.try_into()?;
-                                Ok(Arc::new(h) as _)
-                            }
-                            OutboundProxyProtocol::Trojan(tr) => {
-                                let h: trojan::Handler = tr.try_into()?;
-                                Ok(Arc::new(h) as _)
-                            }
-                            OutboundProxyProtocol::Vmess(vm) => {
-                                let h: vmess::Handler = vm.try_into()?;
-                                Ok(Arc::new(h) as _)
-                            }
-                            OutboundProxyProtocol::Hysteria2(h) => h.try_into(),
-                            OutboundProxyProtocol::Wireguard(wg) => {
-                                let h: wg::Handler = wg.try_into()?;
-                                Ok(Arc::new(h) as _)
-                            }
-                            #[cfg(feature = "onion")]
-                            OutboundProxyProtocol::Tor(tor) => {
-                                let h: tor::Handler = tor.try_into()?;
-                                Ok(Arc::new(h) as _)
-                            }
-                            #[cfg(feature = "tuic")]
-                            OutboundProxyProtocol::Tuic(tuic) => {
-                                let h: tuic::Handler = tuic.try_into()?;
-                                Ok(Arc::new(h) as _)
-                            }
+                        .map(|mut x| {
+                            x.default_client_fingerprint(client_fingerprint);
+                            x
                         })
-                        .collect::<Result<Vec<_>, crate::Error>>();
-                    Ok(proxies?)
+                        .collect())
                 } else {
                     Err(Error::InvalidConfig(format!("{}: proxies is empty", n))
                         .into())
@@ -191,6 +184,9 @@ impl Provider for ProxySetProvider {
     }
 
     async fn update(&self) -> std::io::Result<()> {
+        if let Some(r) = self.fetcher.run_now().await {
+            return r.map_err(map_io_error);
+        }
         let (ele, same) = self.fetcher.update().await.map_err(map_io_error)?;
         debug!(
             "{} updated with {} proxies, same? {}",
@@ -239,6 +235,16 @@ impl ProxyProvider for ProxySetProvider {
     async fn healthcheck(&self) {
         self.inner.read().await.hc.check().await;
     }
+
+    async fn pre_connect(&self) -> Vec<AnyOutboundHandler> {
+        let inner = self.inner.read().await;
+        inner
+            .proxies
+            .iter()
+            .filter(|x| inner.pre_connect.contains(x.name()))
+            .cloned()
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -247,33 +253,36 @@ mod tests {
 
     use tokio::time::sleep;
 
-    use crate::app::{
-        dns::MockClashResolver,
-        remote_content_manager::{
-            healthcheck::HealthCheck,
-            providers::{
-                proxy_provider::{
-                    proxy_set_provider::ProxySetProvider, ProxyProvider,
+    use crate::{
+        app::{
+            dns::MockClashResolver,
+            profile::ThreadSafeCacheFile,
+            remote_content_manager::{
+                healthcheck::HealthCheck,
+                providers::{
+                    proxy_provider::{
+                        proxy_set_provider::ProxySetProvider, ProxyProvider,
+                    },
+                    MockProviderVehicle, Provider, ProviderVehicleType,
                 },
-                MockProviderVehicle, Provider, ProviderVehicleType,
+                ProxyManager,
             },
-            ProxyManager,
         },
+        config::internal::proxy::HealthCheckType,
     };
 
     #[tokio::test]
     async fn test_proxy_set_provider() {
+        let dir = tempfile::tempdir().unwrap();
         let mut mock_vehicle = MockProviderVehicle::new();
 
         mock_vehicle.expect_read().returning(|| {
             Ok(r#"
 proxies:
-  - name: "ss"
-    type: ss
+  - name: "socks"
+    type: socks5
     server: localhost
-    port: 8388
-    cipher: aes-256-gcm
-    password: "password"
+    port: 1080
     udp: true
 "#
             .as_bytes()
@@ -294,6 +303,7 @@ proxies:
         let hc = HealthCheck::new(
             vec![],
             "http://www.google.com".to_owned(),
+            HealthCheckType::Http,
             0,
             true,
             latency_manager.clone(),
@@ -305,6 +315,11 @@ proxies:
             Duration::from_secs(1),
             vehicle,
             hc,
+            ThreadSafeCacheFile::new(
+                dir.path().join("cache.db").to_str().unwrap(),
+                false,
+            ),
+            None,
         )
         .unwrap();
 
@@ -1,4 +1,3 @@
-mod cidr_trie;
 mod provider;
 
 pub use provider::{RuleProviderImpl, RuleSetBehavior, ThreadSafeRuleProvider};
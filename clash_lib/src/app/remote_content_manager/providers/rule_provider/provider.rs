@@ -21,15 +21,14 @@ use crate::{
         router::{map_rule_type, RuleMatcher},
     },
     common::{
-        errors::map_io_error, geodata::GeoData, mmdb::Mmdb, succinct_set, trie,
+        cidr_trie::CidrTrie, errors::map_io_error, geodata::GeoData, mmdb::Mmdb,
+        succinct_set, trie,
     },
     config::internal::rule::RuleType,
     session::Session,
     Error,
 };
 
-use super::cidr_trie::CidrTrie;
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ProviderScheme {
     pub payload: Vec<String>,
@@ -62,19 +61,23 @@ enum RuleContent {
 
 struct Inner {
     content: RuleContent,
+    /// number of entries in the payload
+    count: usize,
 }
 
 pub trait RuleProvider: Provider {
     fn search(&self, sess: &Session) -> bool;
     fn behavior(&self) -> RuleSetBehavior;
+    /// number of rules in the set, 0 while it's being updated
+    fn count(&self) -> usize;
 }
 
 pub type ThreadSafeRuleProvider = Arc<dyn RuleProvider + Send + Sync>;
 
 type RuleUpdater =
-    Box<dyn Fn(RuleContent) -> BoxFuture<'static, ()> + Send + Sync + 'static>;
+    Box<dyn Fn(Inner) -> BoxFuture<'static, ()> + Send + Sync + 'static>;
 type RuleParser =
-    Box<dyn Fn(&[u8]) -> anyhow::Result<RuleContent> + Send + Sync + 'static>;
+    Box<dyn Fn(&[u8]) -> anyhow::Result<Inner> + Send + Sync + 'static>;
 
 pub struct RuleProviderImpl {
     fetcher: Fetcher<RuleUpdater, RuleParser>,
@@ -101,25 +104,26 @@ impl RuleProviderImpl {
                 }
                 RuleSetBehavior::Classical => RuleContent::Classical(vec![]),
             },
+            count: 0,
         }));
 
         let inner_clone = inner.clone();
 
         let n = name.clone();
         let updater: RuleUpdater =
-            Box::new(move |input: RuleContent| -> BoxFuture<'static, ()> {
+            Box::new(move |input: Inner| -> BoxFuture<'static, ()> {
                 let n = n.clone();
                 let inner: Arc<tokio::sync::RwLock<Inner>> = inner_clone.clone();
                 Box::pin(async move {
                     let mut inner = inner.write().await;
                     trace!("updated rules for: {}", n);
-                    inner.content = input;
+                    *inner = input;
                 })
             });
 
         let n = name.clone();
         let parser: RuleParser =
-            Box::new(move |input: &[u8]| -> anyhow::Result<RuleContent> {
+            Box::new(move |input: &[u8]| -> anyhow::Result<Inner> {
                 let scheme: ProviderScheme =
                     serde_yaml::from_slice(input).map_err(|x| {
                         Error::InvalidConfig(format!(
@@ -127,13 +131,14 @@ impl RuleProviderImpl {
                             n, x
                         ))
                     })?;
-                let rules = make_rules(
+                let count = scheme.payload.len();
+                let content = make_rules(
                     behovior,
                     scheme.payload,
                     mmdb.clone(),
                     geodata.clone(),
                 )?;
-                Ok(rules)
+                Ok(Inner { content, count })
             });
 
         let fetcher = Fetcher::new(name, interval, vehicle, parser, Some(updater));
@@ -178,6 +183,10 @@ impl RuleProvider for RuleProviderImpl {
     fn behavior(&self) -> RuleSetBehavior {
         self.behavior
     }
+
+    fn count(&self) -> usize {
+        self.inner.try_read().map(|x| x.count).unwrap_or_default()
+    }
 }
 
 #[async_trait]
@@ -204,6 +213,9 @@ impl Provider for RuleProviderImpl {
     }
 
     async fn update(&self) -> std::io::Result<()> {
+        if let Some(r) = self.fetcher.run_now().await {
+            return r.map_err(map_io_error);
+        }
         let (ele, same) = self.fetcher.update().await.map_err(map_io_error)?;
         debug!("rule provider {} updated. same? {}", self.name(), same);
         if !same {
@@ -231,6 +243,7 @@ impl Provider for RuleProviderImpl {
         );
 
         m.insert("behavior".to_owned(), Box::new(self.behavior().to_string()));
+        m.insert("ruleCount".to_owned(), Box::new(self.count()));
 
         m
     }
@@ -0,0 +1,154 @@
+//! The country and ASN of the destination IPs, shown by `/connections` and
+//! logged with the matched rule. Kept a while per IP, as a burst of
+//! connections to a CDN all go to the same few.
+
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use lru_time_cache::LruCache;
+use tracing::trace;
+
+use crate::common::mmdb::Mmdb;
+
+const CACHE_SIZE: usize = 1024;
+const CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// what the private ranges are reported as instead of a country
+pub const PRIVATE: &str = "PRIVATE";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// the ISO code, or [`PRIVATE`]
+    pub country: Option<String>,
+    /// e.g. `AS13335 CLOUDFLARENET`, only with an ASN db
+    pub asn: Option<String>,
+}
+
+pub struct GeoLookup {
+    country_mmdb: Arc<Mmdb>,
+    asn_mmdb: Option<Arc<Mmdb>>,
+    cache: Mutex<LruCache<IpAddr, GeoInfo>>,
+}
+
+impl GeoLookup {
+    pub fn new(country_mmdb: Arc<Mmdb>, asn_mmdb: Option<Arc<Mmdb>>) -> Self {
+        Self {
+            country_mmdb,
+            asn_mmdb,
+            cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(
+                CACHE_TTL, CACHE_SIZE,
+            )),
+        }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let ip = ip.to_canonical();
+        if let Some(info) = self.cache.lock().unwrap().get(&ip) {
+            return info.clone();
+        }
+
+        let info = if is_private(ip) {
+            GeoInfo {
+                country: Some(PRIVATE.to_owned()),
+                asn: None,
+            }
+        } else {
+            GeoInfo {
+                country: self
+                    .country_mmdb
+                    .lookup_contry(ip)
+                    .ok()
+                    .and_then(|x| x.country)
+                    .and_then(|x| x.iso_code)
+                    .map(ToOwned::to_owned),
+                asn: self.asn_mmdb.as_ref().and_then(|x| lookup_asn(x, ip)),
+            }
+        };
+        trace!("geo of {} is {:?}", ip, info);
+
+        self.cache.lock().unwrap().insert(ip, info.clone());
+        info
+    }
+}
+
+fn lookup_asn(mmdb: &Mmdb, ip: IpAddr) -> Option<String> {
+    match mmdb.lookup_asn(ip) {
+        Ok(asn) => match (
+            asn.autonomous_system_number,
+            asn.autonomous_system_organization,
+        ) {
+            (Some(n), Some(org)) => Some(format!("AS{} {}", n, org)),
+            (Some(n), None) => Some(format!("AS{}", n)),
+            (None, org) => org.map(ToOwned::to_owned),
+        },
+        // the simplified dbs have the country instead
+        Err(_) => mmdb
+            .lookup_contry(ip)
+            .ok()
+            .and_then(|x| x.country)
+            .and_then(|x| x.iso_code)
+            .map(ToOwned::to_owned),
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // CGNAT, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        app::dns::MockClashResolver,
+        common::{http::new_http_client, mmdb::Mmdb},
+    };
+
+    use super::{GeoLookup, PRIVATE};
+
+    #[tokio::test]
+    async fn test_geo_lookup() {
+        let client = new_http_client(Arc::new(MockClashResolver::new())).unwrap();
+        let mmdb = Mmdb::new(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/Country.mmdb"),
+            None,
+            client,
+        )
+        .await
+        .unwrap();
+        let geo = GeoLookup::new(Arc::new(mmdb), None);
+
+        let cn = geo.lookup("114.114.114.114".parse().unwrap());
+        assert_eq!(cn.country.as_deref(), Some("CN"));
+        assert_eq!(cn.asn, None);
+        // the cached one
+        assert_eq!(geo.lookup("114.114.114.114".parse().unwrap()), cn);
+
+        for ip in ["10.0.0.1", "100.64.1.1", "::ffff:192.168.1.1", "fd00::1"] {
+            assert_eq!(
+                geo.lookup(ip.parse().unwrap()).country.as_deref(),
+                Some(PRIVATE),
+                "{}",
+                ip
+            );
+        }
+    }
+}
@@ -9,14 +9,14 @@ use crate::{
 use crate::{
     common::mmdb::Mmdb,
     config::internal::{config::RuleProviderDef, rule::RuleType},
-    session::Session,
+    session::{Network, Session},
 };
 
 use crate::app::router::rules::final_::Final;
 use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
 use hyper::Uri;
-use tracing::{error, info, trace};
+use tracing::{error, info};
 
 use super::{
     dns::ThreadSafeDNSResolver,
@@ -26,16 +26,20 @@ use super::{
     },
 };
 
+pub mod geo;
 mod rules;
 
 use crate::common::geodata::GeoData;
-pub use rules::RuleMatcher;
+pub use rules::{geodata::GeoSiteMatcher, RuleMatcher};
 
 pub struct Router {
     rules: Vec<Box<dyn RuleMatcher>>,
+    rule_providers: HashMap<String, ThreadSafeRuleProvider>,
     dns_resolver: ThreadSafeDNSResolver,
+    /// the protocols assumed by destination port
+    port_hints: HashMap<u16, String>,
 
-    asn_mmdb: Option<Arc<Mmdb>>,
+    geo: geo::GeoLookup,
 }
 
 pub type ThreadSafeRouter = Arc<Router>;
@@ -43,9 +47,11 @@ pub type ThreadSafeRouter = Arc<Router>;
 const MATCH: &str = "MATCH";
 
 impl Router {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         rules: Vec<RuleType>,
         rule_providers: HashMap<String, RuleProviderDef>,
+        port_hints: HashMap<u16, String>,
         dns_resolver: ThreadSafeDNSResolver,
         country_mmdb: Arc<Mmdb>,
         asn_mmdb: Option<Arc<Mmdb>>,
@@ -77,18 +83,24 @@ impl Router {
                     )
                 })
                 .collect(),
+            rule_providers: rule_provider_registry,
             dns_resolver,
+            port_hints,
 
-            asn_mmdb,
+            geo: geo::GeoLookup::new(country_mmdb, asn_mmdb),
         }
     }
 
-    /// this mutates the session, attaching resolved IP and ASN
+    /// this mutates the session, attaching resolved IP, country, ASN and
+    /// the protocol assumed from the port
     pub async fn match_route(
         &self,
         sess: &mut Session,
     ) -> (&str, Option<&Box<dyn RuleMatcher>>) {
+        self.assume_protocol(sess);
+
         let mut sess_resolved = false;
+        let mut geo_known = false;
 
         for r in self.rules.iter() {
             if sess.destination.is_domain()
@@ -105,35 +117,20 @@ impl Router {
                 }
             }
 
-            let mayby_ip = sess.resolved_ip.or(sess.destination.ip());
-            if let (Some(ip), Some(asn_mmdb)) = (mayby_ip, &self.asn_mmdb) {
-                // try simplified mmdb first
-                let rv = asn_mmdb.lookup_contry(ip);
-                if let Ok(country) = rv {
-                    sess.asn = country
-                        .country
-                        .and_then(|c| c.iso_code)
-                        .map(|s| s.to_string());
-                }
-                if sess.asn.is_none() {
-                    match asn_mmdb.lookup_asn(ip) {
-                        Ok(asn) => {
-                            trace!("asn for {} is {:?}", ip, asn);
-                            sess.asn = asn
-                                .autonomous_system_organization
-                                .map(|s| s.to_string());
-                        }
-                        Err(e) => {
-                            trace!("failed to lookup ASN for {}: {}", ip, e);
-                        }
-                    }
+            if !geo_known {
+                if let Some(ip) = sess.resolved_ip.or(sess.destination.ip()) {
+                    let info = self.geo.lookup(ip);
+                    sess.destination_geoip = info.country;
+                    sess.asn = info.asn;
+                    geo_known = true;
                 }
             }
 
             if r.apply(sess) {
                 info!(
-                    "matched {} to target {}[{}]",
+                    "matched {}{} to target {}[{}]",
                     &sess,
+                    geo_label(sess),
                     r.target(),
                     r.type_name()
                 );
@@ -144,6 +141,15 @@ impl Router {
         (MATCH, None)
     }
 
+    /// the protocol of the destination port, for TCP only as the same port
+    /// over UDP carries something else, e.g. QUIC on 443
+    fn assume_protocol(&self, sess: &mut Session) {
+        if sess.network != Network::Tcp {
+            return;
+        }
+        sess.protocol = self.port_hints.get(&sess.destination.port()).cloned();
+    }
+
     async fn load_rule_providers(
         rule_providers: HashMap<String, RuleProviderDef>,
         rule_provider_registry: &mut HashMap<String, ThreadSafeRuleProvider>,
@@ -220,9 +226,14 @@ impl Router {
     }
 
     /// API handlers
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     pub fn get_all_rules(&self) -> &Vec<Box<dyn RuleMatcher>> {
         &self.rules
     }
+
+    pub fn rule_providers(&self) -> &HashMap<String, ThreadSafeRuleProvider> {
+        &self.rule_providers
+    }
 }
 
 pub fn map_rule_type(
@@ -335,13 +346,29 @@ pub fn map_rule_type(
                 unreachable!("you shouldn't nest rule-set within another rule-set")
             }
         },
+        RuleType::InUser { user, target } => {
+            Box::new(rules::in_user::InUser { user, target })
+        }
+        RuleType::Protocol { protocol, target } => {
+            Box::new(rules::protocol::Protocol { protocol, target })
+        }
         RuleType::Match { target } => Box::new(Final { target }),
     }
 }
 
+/// the country and ASN of the destination for the log, e.g. ` [US AS13335
+/// CLOUDFLARENET]`
+fn geo_label(sess: &Session) -> String {
+    match (&sess.destination_geoip, &sess.asn) {
+        (Some(country), Some(asn)) => format!(" [{} {}]", country, asn),
+        (Some(x), None) | (None, Some(x)) => format!(" [{}]", x),
+        (None, None) => String::new(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{collections::HashMap, sync::Arc};
 
     use anyhow::Ok;
 
@@ -349,7 +376,7 @@ mod tests {
         app::dns::{MockClashResolver, SystemResolver},
         common::{geodata::GeoData, http::new_http_client, mmdb::Mmdb},
         config::internal::rule::RuleType,
-        session::Session,
+        session::{Network, Session},
     };
 
     const GEO_DATA_DOWNLOAD_URL:&str = "https://github.com/Watfaq/v2ray-rules-dat/releases/download/test/geosite.dat";
@@ -397,6 +424,10 @@ mod tests {
 
         let router = super::Router::new(
             vec![
+                RuleType::Protocol {
+                    protocol: "ssh".to_string(),
+                    target: "SSH".to_string(),
+                },
                 RuleType::GeoIP {
                     target: "DIRECT".to_string(),
                     country_code: "CN".to_string(),
@@ -417,6 +448,7 @@ mod tests {
                 },
             ],
             Default::default(),
+            HashMap::from([(22, "ssh".to_string())]),
             mock_resolver,
             Arc::new(mmdb),
             None,
@@ -485,5 +517,25 @@ mod tests {
             "MATCH",
             "should fallback to MATCH when nothing matched"
         );
+
+        let mut sess = Session {
+            destination: crate::session::SocksAddr::Ip(
+                "10.0.0.2:22".parse().unwrap(),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(router.match_route(&mut sess).await.0, "SSH");
+        assert_eq!(sess.protocol.as_deref(), Some("ssh"));
+
+        // the hints are for TCP only
+        let mut sess = Session {
+            network: Network::Udp,
+            destination: crate::session::SocksAddr::Ip(
+                "10.0.0.2:22".parse().unwrap(),
+            ),
+            ..Default::default()
+        };
+        assert_ne!(router.match_route(&mut sess).await.0, "SSH");
+        assert_eq!(sess.protocol, None);
     }
 }
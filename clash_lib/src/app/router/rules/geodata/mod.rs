@@ -79,6 +79,10 @@ impl GeoSiteMatcher {
             matcher: matcher_group,
         })
     }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        self.matcher.apply(domain)
+    }
 }
 
 impl Display for GeoSiteMatcher {
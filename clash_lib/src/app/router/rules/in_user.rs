@@ -0,0 +1,60 @@
+use crate::{app::router::rules::RuleMatcher, session::Session};
+
+/// `IN-USER,alice,PROXY-A`, the user the client authenticated as on the
+/// inbound. An empty user matches the clients that didn't, e.g. on an inbound
+/// without `authentication`.
+#[derive(Clone)]
+pub struct InUser {
+    pub user: String,
+    pub target: String,
+}
+
+impl std::fmt::Display for InUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} inbound user {}", self.target, self.user)
+    }
+}
+
+impl RuleMatcher for InUser {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.inbound_user.as_deref().unwrap_or_default() == self.user
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.user.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "InUser"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::router::rules::RuleMatcher, session::Session};
+
+    use super::InUser;
+
+    #[test]
+    fn test_in_user() {
+        let rule = |user: &str| InUser {
+            user: user.to_owned(),
+            target: "DIRECT".to_owned(),
+        };
+        let alice = Session {
+            inbound_user: Some("alice".to_owned()),
+            ..Default::default()
+        };
+        let anonymous = Session::default();
+
+        assert!(rule("alice").apply(&alice));
+        assert!(!rule("bob").apply(&alice));
+        assert!(!rule("alice").apply(&anonymous));
+        assert!(rule("").apply(&anonymous));
+        assert!(!rule("").apply(&alice));
+    }
+}
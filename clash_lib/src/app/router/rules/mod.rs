@@ -10,9 +10,11 @@ pub mod domain_suffix;
 pub mod final_;
 pub mod geodata;
 pub mod geoip;
+pub mod in_user;
 pub mod ipcidr;
 pub mod port;
 pub mod process;
+pub mod protocol;
 pub mod ruleset;
 
 pub trait RuleMatcher: Send + Sync + Unpin + Display {
@@ -32,11 +34,17 @@ pub trait RuleMatcher: Send + Sync + Unpin + Display {
         false
     }
 
+    /// number of entries behind the rule, -1 for rules that aren't a set
+    fn size(&self) -> i64 {
+        -1
+    }
+
     fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let mut m: HashMap<String, Box<dyn Serialize + Send>> = HashMap::new();
         m.insert("type".to_string(), Box::new(self.type_name().to_owned()));
         m.insert("proxy".to_string(), Box::new(self.target().to_owned()));
         m.insert("payload".to_string(), Box::new(self.payload().to_owned()));
+        m.insert("size".to_string(), Box::new(self.size()));
         m
     }
 }
@@ -0,0 +1,57 @@
+use crate::{app::router::rules::RuleMatcher, session::Session};
+
+/// `PROTOCOL,ssh,DIRECT`, what the connection carries, assumed from its
+/// destination port by `port-hints`.
+#[derive(Clone)]
+pub struct Protocol {
+    pub protocol: String,
+    pub target: String,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} protocol {}", self.target, self.protocol)
+    }
+}
+
+impl RuleMatcher for Protocol {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.protocol
+            .as_ref()
+            .is_some_and(|x| x.eq_ignore_ascii_case(&self.protocol))
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.protocol.clone()
+    }
+
+    fn type_name(&self) -> &str {
+        "Protocol"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{app::router::rules::RuleMatcher, session::Session};
+
+    use super::Protocol;
+
+    #[test]
+    fn test_protocol() {
+        let rule = Protocol {
+            protocol: "SSH".to_owned(),
+            target: "DIRECT".to_owned(),
+        };
+        let ssh = Session {
+            protocol: Some("ssh".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(rule.apply(&ssh));
+        assert!(!rule.apply(&Session::default()));
+    }
+}
@@ -49,4 +49,85 @@ impl RuleMatcher for RuleSet {
     fn type_name(&self) -> &str {
         "RuleSet"
     }
+
+    fn size(&self) -> i64 {
+        self.rule_provider.count() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        app::{
+            dns::MockClashResolver,
+            remote_content_manager::providers::{
+                rule_provider::{RuleProviderImpl, RuleSetBehavior},
+                MockProviderVehicle, Provider, ProviderVehicleType,
+            },
+            router::rules::{domain_suffix::DomainSuffix, RuleMatcher},
+        },
+        common::{geodata::GeoData, http::new_http_client, mmdb::Mmdb},
+    };
+
+    use super::RuleSet;
+
+    #[tokio::test]
+    async fn test_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.yaml");
+        std::fs::write(
+            &path,
+            "payload:\n  - example.com\n  - '+.example.org'\n  - example.net\n",
+        )
+        .unwrap();
+        let geosite = dir.path().join("geosite.dat");
+        std::fs::write(&geosite, b"").unwrap();
+
+        let client = new_http_client(Arc::new(MockClashResolver::new())).unwrap();
+        let mmdb = Mmdb::new(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/Country.mmdb"),
+            None,
+            client.clone(),
+        )
+        .await
+        .unwrap();
+        let geodata = GeoData::new(&geosite, None, client).await.unwrap();
+
+        let mut vehicle = MockProviderVehicle::new();
+        vehicle
+            .expect_path()
+            .return_const(path.to_str().unwrap().to_owned());
+        vehicle.expect_typ().return_const(ProviderVehicleType::File);
+        let provider = Arc::new(RuleProviderImpl::new(
+            "rules".to_owned(),
+            RuleSetBehavior::Domain,
+            Duration::ZERO,
+            Arc::new(vehicle),
+            Arc::new(mmdb),
+            Arc::new(geodata),
+        ));
+        let rule =
+            RuleSet::new("rules".to_owned(), "PROXY".to_owned(), provider.clone());
+
+        // nothing loaded yet
+        assert_eq!(rule.size(), 0);
+        provider.initialize().await.unwrap();
+        assert_eq!(rule.size(), 3);
+
+        let m = serde_json::to_value(provider.as_map().await).unwrap();
+        assert_eq!(m["ruleCount"], 3);
+        let m = serde_json::to_value(rule.as_map()).unwrap();
+        assert_eq!(m["type"], "RuleSet");
+        assert_eq!(m["payload"], "rules");
+        assert_eq!(m["size"], 3);
+
+        // not a set
+        let rule = DomainSuffix {
+            suffix: "example.com".to_owned(),
+            target: "PROXY".to_owned(),
+        };
+        assert_eq!(serde_json::to_value(rule.as_map()).unwrap()["size"], -1);
+    }
 }
@@ -0,0 +1,293 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde_yaml::Value;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+
+use crate::{app::dns::system_hosts_file, config::merge, Config, ReloadRequest};
+
+/// saves within this window are applied as one reload
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reloads the config when one of its files, the files they include, or a
+/// file provider it uses, changes. The directories holding them are watched
+/// rather than the files, editors saving through a rename would otherwise
+/// leave the watch on a deleted file.
+pub async fn watch_config(
+    config_files: Vec<PathBuf>,
+    cwd: PathBuf,
+    reload_tx: mpsc::Sender<ReloadRequest>,
+) {
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let mut watcher =
+        match notify::recommended_watcher(move |r: notify::Result<notify::Event>| {
+            match r {
+                Ok(e) if !e.kind.is_access() => {
+                    let _ = event_tx.send(e.paths);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("config watch error: {}", e),
+            }
+        }) {
+            Ok(w) => w,
+            Err(e) => {
+                error!("failed to watch config: {}", e);
+                return;
+            }
+        };
+
+    let (mut current, mut sources) = match merge::load_files(&config_files) {
+        Ok(l) => (l.value, l.files),
+        Err(_) => (Value::Null, config_files.clone()),
+    };
+    let mut files = watched_files(&sources, &current, &cwd);
+    let mut dirs = watch_dirs(&mut watcher, &files, &HashSet::new());
+    info!(
+        "watching {} for changes",
+        sources
+            .iter()
+            .map(|x| x.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    while let Some(paths) = event_rx.recv().await {
+        let mut changed: BTreeSet<PathBuf> =
+            paths.into_iter().filter(|p| files.contains(p)).collect();
+        if changed.is_empty() {
+            continue;
+        }
+        // editors often write a file more than once per save
+        let deadline = tokio::time::Instant::now() + DEBOUNCE;
+        while let Ok(Some(paths)) =
+            tokio::time::timeout_at(deadline, event_rx.recv()).await
+        {
+            changed.extend(paths.into_iter().filter(|p| files.contains(p)));
+        }
+
+        let next = match merge::load_files(&config_files) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("config changed but can't be read, keeping it: {}", e);
+                continue;
+            }
+        };
+        let sections = changed_sections(&current, &next.value);
+        if sections.is_empty() && changed.iter().all(|p| sources.contains(p)) {
+            debug!("config file touched without changes");
+            continue;
+        }
+        info!(
+            "config changed, sections: [{}], files: [{}], reloading",
+            sections.join(", "),
+            changed
+                .iter()
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let req = ReloadRequest::Reload(
+            Config::Files(
+                config_files
+                    .iter()
+                    .map(|x| x.to_string_lossy().to_string())
+                    .collect(),
+            ),
+            done_tx,
+        );
+        if reload_tx.send(req).await.is_err() {
+            break;
+        }
+        match done_rx.await {
+            Ok(Ok(())) => {
+                info!("config reloaded");
+                current = next.value;
+                sources = next.files;
+                files = watched_files(&sources, &current, &cwd);
+                dirs = watch_dirs(&mut watcher, &files, &dirs);
+            }
+            Ok(Err(e)) => {
+                error!("invalid config, keeping the running one: {}", e)
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+/// the config files, the files of `type: file` providers, the certificates
+/// of the listeners and of the DNS server, and the hosts files. http
+/// providers are left out as they are written by the updater itself
+fn watched_files(
+    config_files: &[PathBuf],
+    config: &Value,
+    cwd: &Path,
+) -> HashSet<PathBuf> {
+    let mut files: HashSet<PathBuf> = config_files.iter().cloned().collect();
+    for section in ["proxy-providers", "rule-providers"] {
+        let Some(providers) = config.get(section).and_then(Value::as_mapping) else {
+            continue;
+        };
+        for p in providers.values() {
+            if p.get("type").and_then(Value::as_str) != Some("file") {
+                continue;
+            }
+            if let Some(path) = p.get("path").and_then(Value::as_str) {
+                files.insert(cwd.join(path));
+            }
+        }
+    }
+    if let Some(tls) = config.get("listener-tls").and_then(Value::as_mapping) {
+        for certs in tls.values() {
+            let certs = match certs.as_sequence() {
+                Some(certs) => certs.iter().collect(),
+                None => vec![certs],
+            };
+            for c in certs {
+                for key in ["certificate", "private-key"] {
+                    if let Some(path) = c.get(key).and_then(Value::as_str) {
+                        files.insert(cwd.join(path));
+                    }
+                }
+            }
+        }
+    }
+    if let Some(dns) = config.get("dns") {
+        if let Some(listen) = dns.get("listen") {
+            for server in ["dot", "doh", "doh3"] {
+                for key in ["ca-cert", "ca-key"] {
+                    let path = listen.get(server).and_then(|x| x.get(key));
+                    if let Some(path) = path.and_then(Value::as_str) {
+                        files.insert(cwd.join(path));
+                    }
+                }
+            }
+        }
+        let flag = |key: &str| dns.get(key).and_then(Value::as_bool);
+        if flag("user-hosts") != Some(false) {
+            if let Some(path) = dns.get("hosts-file").and_then(Value::as_str) {
+                files.insert(cwd.join(path));
+            }
+            if flag("use-system-hosts") == Some(true) {
+                files.insert(system_hosts_file());
+            }
+        }
+    }
+    files.into_iter().map(|x| absolute(&x)).collect()
+}
+
+/// events come with absolute paths
+fn absolute(p: &Path) -> PathBuf {
+    match (p.parent(), p.file_name()) {
+        (Some(dir), Some(name)) => {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            dir.canonicalize()
+                .map(|d| d.join(name))
+                .unwrap_or_else(|_| p.to_owned())
+        }
+        _ => p.to_owned(),
+    }
+}
+
+fn watch_dirs(
+    watcher: &mut RecommendedWatcher,
+    files: &HashSet<PathBuf>,
+    watching: &HashSet<PathBuf>,
+) -> HashSet<PathBuf> {
+    let dirs: HashSet<PathBuf> = files
+        .iter()
+        .filter_map(|f| f.parent().map(ToOwned::to_owned))
+        .collect();
+    for d in dirs.difference(watching) {
+        if let Err(e) = watcher.watch(d, RecursiveMode::NonRecursive) {
+            warn!("failed to watch {}: {}", d.display(), e);
+        }
+    }
+    for d in watching.difference(&dirs) {
+        let _ = watcher.unwatch(d);
+    }
+    dirs
+}
+
+/// top level keys whose value differs
+fn changed_sections(old: &Value, new: &Value) -> Vec<String> {
+    let empty = serde_yaml::Mapping::new();
+    let old = old.as_mapping().unwrap_or(&empty);
+    let new = new.as_mapping().unwrap_or(&empty);
+    let keys: BTreeSet<String> = old
+        .keys()
+        .chain(new.keys())
+        .filter_map(|k| k.as_str().map(ToOwned::to_owned))
+        .collect();
+    keys.into_iter()
+        .filter(|k| old.get(k.as_str()) != new.get(k.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{changed_sections, watched_files};
+
+    #[test]
+    fn test_changed_sections() {
+        let old =
+            serde_yaml::from_str("port: 7890\nmode: rule\nrules: [a]").unwrap();
+        let new = serde_yaml::from_str("port: 7890\nmode: global\nlog-level: debug")
+            .unwrap();
+        assert_eq!(
+            changed_sections(&old, &new),
+            vec!["log-level", "mode", "rules"]
+        );
+    }
+
+    #[test]
+    fn test_watched_files() {
+        let config = serde_yaml::from_str(
+            r#"
+proxy-providers:
+  local:
+    type: file
+    path: ./providers/local.yaml
+  remote:
+    type: http
+    url: https://example.com/sub
+    path: ./providers/remote.yaml
+listener-tls:
+  socks:
+    certificate: ./certs/fullchain.pem
+    private-key: ./certs/privkey.pem
+dns:
+  hosts-file: ./leases-hosts
+  listen:
+    dot:
+      addr: 127.0.0.1:853
+      ca-cert: ./certs/dns.crt
+      ca-key: ./certs/dns.key
+"#,
+        )
+        .unwrap();
+        let files = watched_files(
+            &[PathBuf::from("/etc/clash/config.yaml")],
+            &config,
+            Path::new("/etc/clash"),
+        );
+        assert_eq!(files.len(), 7);
+        assert!(files.iter().any(|f| f.ends_with("privkey.pem")));
+        assert!(files.iter().any(|f| f.ends_with("dns.key")));
+        assert!(files.iter().any(|f| f.ends_with("leases-hosts")));
+        assert!(files.iter().any(|f| f.ends_with("local.yaml")));
+        assert!(!files.iter().any(|f| f.ends_with("remote.yaml")));
+    }
+}
@@ -0,0 +1,50 @@
+//! What this build is, as filled in by build.rs, for `GET /version` and the
+//! line logged on startup.
+
+use std::fmt::Display;
+
+use serde::Serialize;
+
+/// The field names are those of mihomo's, dashboards go by them.
+#[derive(Serialize, Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub meta: bool,
+    pub premium: bool,
+    /// `git describe` of the source
+    pub commit: &'static str,
+    pub build_date: &'static str,
+    /// e.g. `linux/amd64`
+    pub platform: &'static str,
+    /// the cargo features compiled in, e.g. `shadowsocks`
+    pub features: Vec<&'static str>,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        meta: true,
+        premium: false,
+        commit: env!("CLASH_BUILD_COMMIT"),
+        build_date: env!("CLASH_BUILD_DATE"),
+        platform: env!("CLASH_BUILD_PLATFORM"),
+        features: env!("CLASH_BUILD_FEATURES")
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .collect(),
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "clash-rs {} ({}) built {} for {}, features: [{}]",
+            self.version,
+            self.commit,
+            self.build_date,
+            self.platform,
+            self.features.join(", ")
+        )
+    }
+}
@@ -59,6 +59,13 @@ impl GeoData {
         Ok(Self { cache })
     }
 
+    #[cfg(test)]
+    pub fn from_sites(sites: Vec<geodata_proto::GeoSite>) -> Self {
+        Self {
+            cache: geodata_proto::GeoSiteList { entry: sites },
+        }
+    }
+
     pub fn get(&self, list: &str) -> Option<&geodata_proto::GeoSite> {
         self.cache
             .entry
@@ -132,9 +132,17 @@ pub type HttpClient =
 
 pub fn new_http_client(
     dns_resolver: ThreadSafeDNSResolver,
+) -> std::io::Result<HttpClient> {
+    new_http_client_with_roots(dns_resolver, GLOBAL_ROOT_STORE.clone())
+}
+
+/// a client trusting `roots` instead of the bundled webpki roots
+pub fn new_http_client_with_roots(
+    dns_resolver: ThreadSafeDNSResolver,
+    roots: Arc<rustls::RootCertStore>,
 ) -> std::io::Result<HttpClient> {
     let mut tls_config = rustls::ClientConfig::builder()
-        .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+        .with_root_certificates(roots)
         .with_no_client_auth();
     tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
 
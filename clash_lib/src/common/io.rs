@@ -8,7 +8,15 @@ use std::{
 };
 
 use futures::ready;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    net::TcpStream,
+};
+
+use super::{
+    http::hyper::TokioIo,
+    pool::{PooledBuf, STREAM_POOL},
+};
 
 #[derive(Debug)]
 pub enum CopyBidirectionalError {
@@ -56,39 +64,26 @@ pub struct CopyBuffer {
     pos: usize,
     cap: usize,
     amt: u64,
-    buf: Box<[u8]>,
+    buf: PooledBuf,
 }
 
 impl CopyBuffer {
     #[allow(unused)]
     pub fn new() -> Self {
-        Self {
-            read_done: false,
-            need_flush: false,
-            pos: 0,
-            cap: 0,
-            amt: 0,
-            buf: vec![0; 2 * 1024].into_boxed_slice(),
-        }
+        Self::new_with_capacity(2 * 1024)
     }
 
-    pub fn new_with_capacity(size: usize) -> Result<Self, std::io::Error> {
-        let mut buf = Vec::new();
-        buf.try_reserve(size).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("new buffer failed: {}", e),
-            )
-        })?;
-        buf.resize(size, 0);
-        Ok(Self {
+    /// The buffer comes from [`STREAM_POOL`] unless `size` is larger than
+    /// its buffers.
+    pub fn new_with_capacity(size: usize) -> Self {
+        Self {
             read_done: false,
             need_flush: false,
             pos: 0,
             cap: 0,
             amt: 0,
-            buf: buf.into_boxed_slice(),
-        })
+            buf: STREAM_POOL.get(size),
+        }
     }
 
     pub fn amount_transfered(&self) -> u64 {
@@ -341,8 +336,8 @@ where
     CopyBidirectional {
         a,
         b,
-        a_to_b: TransferState::Running(CopyBuffer::new_with_capacity(size)?),
-        b_to_a: TransferState::Running(CopyBuffer::new_with_capacity(size)?),
+        a_to_b: TransferState::Running(CopyBuffer::new_with_capacity(size)),
+        b_to_a: TransferState::Running(CopyBuffer::new_with_capacity(size)),
         a_to_b_count: 0,
         b_to_a_count: 0,
         a_to_b_delay: None,
@@ -352,3 +347,113 @@ where
     }
     .await
 }
+
+/// Streams that can be a plain tcp socket, relaying between two of those
+/// skips the copy through userspace where the os allows it.
+pub trait AsTcpStream {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+}
+
+impl AsTcpStream for TcpStream {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        Some(self)
+    }
+}
+
+impl<T: AsTcpStream + ?Sized> AsTcpStream for &mut T {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        (**self).as_tcp_stream()
+    }
+}
+
+impl AsTcpStream for DuplexStream {}
+
+impl<T> AsTcpStream for TokioIo<T> {}
+
+// the prefix has to be read first
+impl<T> AsTcpStream for PrefixedStream<T> {}
+
+// the bytes on the socket are encrypted
+impl<T> AsTcpStream for tokio_rustls::server::TlsStream<T> {}
+
+/// A stream that replays `prefix` before reading from `inner`, for when some
+/// bytes had to be consumed to find out what protocol the peer speaks.
+#[derive(Debug)]
+pub struct PrefixedStream<T> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: T,
+}
+
+impl<T> PrefixedStream<T> {
+    pub fn new(prefix: Vec<u8>, inner: T) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for PrefixedStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let n = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::PrefixedStream;
+
+    #[tokio::test]
+    async fn test_prefixed_stream() {
+        let (left, mut right) = tokio::io::duplex(64);
+        let mut s = PrefixedStream::new(b"hello ".to_vec(), left);
+
+        right.write_all(b"world").await.unwrap();
+        drop(right);
+
+        let mut buf = String::new();
+        s.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hello world");
+    }
+}
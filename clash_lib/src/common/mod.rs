@@ -1,4 +1,7 @@
 pub mod auth;
+pub mod build_info;
+pub mod cidr_trie;
+#[cfg(feature = "vmess")]
 pub mod crypto;
 pub mod defer;
 pub mod errors;
@@ -6,6 +9,11 @@ pub mod geodata;
 pub mod http;
 pub mod io;
 pub mod mmdb;
+pub mod pool;
+pub mod rate_limit;
+pub mod scheduler;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod splice;
 pub mod succinct_set;
 pub mod timed_future;
 pub mod tls;
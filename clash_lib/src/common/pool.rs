@@ -0,0 +1,136 @@
+//! Buffers shared by every connection instead of allocated for each one, for
+//! the relay copy loops and the UDP datagrams.
+//!
+//! A pool holds up to a fixed number of free buffers of one size. Taking one
+//! from an empty pool allocates it, and a buffer given back to a full pool is
+//! freed, so the pool bounds the memory it keeps and never makes anyone wait.
+
+use std::{
+    fmt::{Debug, Formatter},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crossbeam_queue::ArrayQueue;
+use once_cell::sync::Lazy;
+
+/// the buffers of the relay copy loops, one for each direction
+pub const STREAM_BUFFER_SIZE: usize = 16 * 1024;
+/// room for the largest UDP payload
+pub const DATAGRAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// 16 MiB kept at most
+pub static STREAM_POOL: Lazy<BufferPool> =
+    Lazy::new(|| BufferPool::new(STREAM_BUFFER_SIZE, 1024));
+/// 16 MiB kept at most
+pub static DATAGRAM_POOL: Lazy<BufferPool> =
+    Lazy::new(|| BufferPool::new(DATAGRAM_BUFFER_SIZE, 256));
+
+pub struct BufferPool {
+    free: ArrayQueue<Box<[u8]>>,
+    size: usize,
+    allocated: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, max_free: usize) -> Self {
+        Self {
+            free: ArrayQueue::new(max_free),
+            size,
+            allocated: AtomicU64::new(0),
+        }
+    }
+
+    /// A buffer of `len` bytes, not zeroed if it was used before. One larger
+    /// than the pool's buffers is allocated and freed as usual.
+    pub fn get(&'static self, len: usize) -> PooledBuf {
+        if len > self.size {
+            return PooledBuf {
+                buf: vec![0; len].into_boxed_slice(),
+                len,
+                pool: None,
+            };
+        }
+        let buf = self.free.pop().unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            vec![0; self.size].into_boxed_slice()
+        });
+        PooledBuf {
+            buf,
+            len,
+            pool: Some(self),
+        }
+    }
+
+    /// how many buffers the pool had to allocate so far
+    pub fn allocated(&self) -> u64 {
+        self.allocated.load(Ordering::Relaxed)
+    }
+}
+
+/// A buffer from a [`BufferPool`], given back to it when dropped.
+pub struct PooledBuf {
+    buf: Box<[u8]>,
+    len: usize,
+    pool: Option<&'static BufferPool>,
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl Debug for PooledBuf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PooledBuf").field("len", &self.len).finish()
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool {
+            // freed if the pool is full
+            let _ = pool.free.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use once_cell::sync::Lazy;
+
+    use super::BufferPool;
+
+    static POOL: Lazy<BufferPool> = Lazy::new(|| BufferPool::new(1024, 2));
+
+    #[test]
+    fn test_buffer_pool() {
+        let a = POOL.get(512);
+        assert_eq!(a.len(), 512);
+        let b = POOL.get(1024);
+        let c = POOL.get(1024);
+        assert_eq!(POOL.allocated(), 3);
+
+        // one more than the pool keeps, it's freed
+        drop((a, b, c));
+        let _a = POOL.get(1024);
+        let _b = POOL.get(1024);
+        assert_eq!(POOL.allocated(), 3);
+        let _c = POOL.get(1024);
+        assert_eq!(POOL.allocated(), 4);
+
+        let large = POOL.get(4096);
+        assert_eq!(large.len(), 4096);
+        drop(large);
+        assert_eq!(POOL.allocated(), 4);
+    }
+}
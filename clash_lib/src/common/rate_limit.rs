@@ -0,0 +1,428 @@
+//! Bandwidth limits shared by all the connections of a proxy or a listener.
+//!
+//! A limit is a token bucket refilled at the configured rate. Moving bytes
+//! takes them out of the bucket even when it doesn't hold enough, the debt is
+//! how long the connection that ran it up waits before moving more, so the
+//! connections sharing a limit take turns instead of one of them starving
+//! the others.
+
+use std::{
+    fmt::{Display, Formatter},
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use super::io::AsTcpStream;
+
+/// what a bucket holds at least, so a single read of the relay buffer
+/// doesn't already put a slow limit in debt
+const MIN_BURST: u64 = 16 * 1024;
+
+/// A rate in bytes per second, written in bits per second in the config,
+/// e.g. `10mbps`, `512 kbps` or `1gbps`. A bare number is in mbps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bandwidth(u64);
+
+const UNITS: [(&str, u64); 4] = [
+    ("gbps", 1_000_000_000),
+    ("mbps", 1_000_000),
+    ("kbps", 1_000),
+    ("bps", 1),
+];
+
+#[cfg(test)]
+impl Bandwidth {
+    pub fn from_bytes_per_sec(n: u64) -> Self {
+        Self(n)
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Bandwidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.trim().to_ascii_lowercase();
+        let split = lower
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(lower.len());
+        let (n, unit) = lower.split_at(split);
+        let unit = unit.trim();
+
+        let n = n
+            .parse::<f64>()
+            .map_err(|_| format!("invalid bandwidth `{}`", s))?;
+        let bits = if unit.is_empty() {
+            1_000_000
+        } else {
+            UNITS
+                .iter()
+                .find(|(u, _)| *u == unit)
+                .map(|(_, bits)| *bits)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid bandwidth `{}`, expected a unit of bps, kbps, \
+                         mbps or gbps",
+                        s
+                    )
+                })?
+        };
+
+        let bytes = (n * bits as f64 / 8.0) as u64;
+        if bytes == 0 {
+            return Err(format!("bandwidth `{}` is zero", s));
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl Display for Bandwidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bits = self.0.saturating_mul(8);
+        let (unit, n) = UNITS
+            .iter()
+            .find(|(_, n)| bits % n == 0)
+            .expect("bps divides everything");
+        write!(f, "{}{}", bits / n, unit)
+    }
+}
+
+impl Serialize for Bandwidth {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bandwidth {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Bandwidth;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                f.write_str("a bandwidth such as `10mbps`")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Bandwidth, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Bandwidth, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Bandwidth, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Bandwidth, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+struct Bucket {
+    /// negative when in debt
+    tokens: f64,
+    last: Instant,
+}
+
+/// One direction of a limit.
+pub struct RateLimiter {
+    /// bytes per second, 0 when unlimited
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<Bandwidth>) -> Self {
+        let rate = rate.map(|x| x.0).unwrap_or_default();
+        Self {
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: burst(rate),
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    pub fn rate(&self) -> Option<Bandwidth> {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(Bandwidth(n)),
+        }
+    }
+
+    /// Takes effect for the bytes moved from now on, on every connection
+    /// sharing the limit.
+    pub fn set_rate(&self, rate: Option<Bandwidth>) {
+        self.rate
+            .store(rate.map(|x| x.0).unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Takes `n` bytes out of the bucket, returns how long to wait before
+    /// moving more if that put it in debt.
+    pub fn consume(&self, n: usize) -> Option<Duration> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 || n == 0 {
+            return None;
+        }
+
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last).as_secs_f64() * rate as f64;
+        bucket.tokens = (bucket.tokens + refill).min(burst(rate));
+        bucket.last = now;
+        bucket.tokens -= n as f64;
+
+        if bucket.tokens >= 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(-bucket.tokens / rate as f64))
+        }
+    }
+}
+
+/// a tenth of a second of traffic
+fn burst(rate: u64) -> f64 {
+    (rate / 10).max(MIN_BURST) as f64
+}
+
+/// The upload and download limits of a proxy or a listener.
+pub struct BandwidthLimit {
+    pub up: RateLimiter,
+    pub down: RateLimiter,
+}
+
+impl BandwidthLimit {
+    pub fn new(up: Option<Bandwidth>, down: Option<Bandwidth>) -> Arc<Self> {
+        Arc::new(Self {
+            up: RateLimiter::new(up),
+            down: RateLimiter::new(down),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn is_unlimited(&self) -> bool {
+        self.up.rate().is_none() && self.down.rate().is_none()
+    }
+}
+
+/// The limits a connection is subject to, e.g. those of its proxy and of the
+/// listener it came in on.
+#[derive(Clone, Default)]
+pub struct Throttle(Vec<Arc<BandwidthLimit>>);
+
+impl Throttle {
+    pub fn push(&mut self, limit: Arc<BandwidthLimit>) {
+        self.0.push(limit);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn up(&self, n: usize) -> Option<Duration> {
+        self.0.iter().filter_map(|x| x.up.consume(n)).max()
+    }
+
+    fn down(&self, n: usize) -> Option<Duration> {
+        self.0.iter().filter_map(|x| x.down.consume(n)).max()
+    }
+
+    /// for the datagrams, waits out the debt of sending `n` bytes
+    pub async fn wait_up(&self, n: usize) {
+        if let Some(d) = self.up(n) {
+            tokio::time::sleep(d).await;
+        }
+    }
+
+    pub async fn wait_down(&self, n: usize) {
+        if let Some(d) = self.down(n) {
+            tokio::time::sleep(d).await;
+        }
+    }
+}
+
+/// The local side of a relayed connection, what is read from it is the upload
+/// and what is written to it the download.
+pub struct Throttled<S> {
+    inner: S,
+    throttle: Throttle,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, throttle: Throttle) -> Self {
+        Self {
+            inner,
+            throttle,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+fn poll_delay(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    if let Some(d) = delay {
+        ready!(d.as_mut().poll(cx));
+        *delay = None;
+    }
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.read_delay, cx));
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(d) = this.throttle.up(buf.filled().len() - before) {
+            this.read_delay = Some(Box::pin(tokio::time::sleep(d)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(poll_delay(&mut this.write_delay, cx));
+
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(d) = this.throttle.down(n) {
+            this.write_delay = Some(Box::pin(tokio::time::sleep(d)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// spliced bytes would bypass the limits
+impl<S> AsTcpStream for Throttled<S> {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use super::{Bandwidth, BandwidthLimit, Throttle, Throttled};
+
+    #[test]
+    fn test_parse_bandwidth() {
+        let b = "10mbps".parse::<Bandwidth>().unwrap();
+        assert_eq!(b.bytes_per_sec(), 1_250_000);
+        assert_eq!(b.to_string(), "10mbps");
+        assert_eq!(
+            "512 Kbps".parse::<Bandwidth>().unwrap().bytes_per_sec(),
+            64_000
+        );
+        assert_eq!(
+            "1.5gbps".parse::<Bandwidth>().unwrap().to_string(),
+            "1500mbps"
+        );
+        assert_eq!(
+            serde_yaml::from_str::<Bandwidth>("50").unwrap().to_string(),
+            "50mbps"
+        );
+
+        assert!("10 mb".parse::<Bandwidth>().is_err());
+        assert!("0mbps".parse::<Bandwidth>().is_err());
+        assert!("fast".parse::<Bandwidth>().is_err());
+
+        // saturates instead of overflowing
+        let huge = "100000000000000000000gbps".parse::<Bandwidth>().unwrap();
+        assert_eq!(huge.bytes_per_sec(), u64::MAX);
+        assert_eq!(huge.to_string(), format!("{}bps", u64::MAX));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter() {
+        let limit =
+            BandwidthLimit::new(Some(Bandwidth::from_bytes_per_sec(100_000)), None);
+        // the bucket starts with its burst
+        assert_eq!(limit.up.consume(16 * 1024), None);
+        assert_eq!(limit.up.consume(100_000), Some(Duration::from_secs(1)));
+        assert_eq!(limit.down.consume(1 << 30), None);
+
+        limit.up.set_rate(None);
+        assert!(limit.is_unlimited());
+        assert_eq!(limit.up.consume(1 << 30), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttled_stream() {
+        let limit =
+            BandwidthLimit::new(Some(Bandwidth::from_bytes_per_sec(100_000)), None);
+        let mut throttle = Throttle::default();
+        throttle.push(limit);
+
+        let (client, local) = tokio::io::duplex(64 * 1024);
+        let mut local = Throttled::new(local, throttle);
+        tokio::spawn(async move {
+            let mut client = client;
+            client.write_all(&[0; 316 * 1024]).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        let start = Instant::now();
+        let mut received = vec![];
+        local.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 316 * 1024);
+        // all but the burst at 100k/s
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+}
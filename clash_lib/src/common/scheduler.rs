@@ -0,0 +1,315 @@
+//! The periodic jobs, like the provider updates and the health checks, each
+//! run by a [`Task`].
+//!
+//! The first run is put off by a random jitter, so that after a reboot all
+//! of them don't hit the network at once, and a failed run is retried with
+//! a backoff instead of waiting out the whole interval. A task stops when
+//! its [`Task`] is dropped, with the provider or group owning it when the
+//! config is reloaded.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, warn};
+
+use crate::common::utils::rand_range;
+
+/// the first retry of a failed run, doubled each time up to the interval
+const RETRY_MIN: Duration = Duration::from_secs(5);
+/// at most this long of the interval is taken as jitter
+const MAX_JITTER: Duration = Duration::from_secs(30);
+
+/// all the tasks alive, for `GET /debug/schedule`
+static TASKS: Lazy<Mutex<Vec<Weak<State>>>> = Lazy::new(Default::default);
+
+pub struct Schedule {
+    pub interval: Duration,
+    /// whether the first run is right away, after the jitter, instead of an
+    /// interval later
+    pub immediately: bool,
+}
+
+#[derive(Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskInfo {
+    pub name: String,
+    /// in seconds
+    pub interval: u64,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// the runs failed in a row
+    pub failures: u32,
+}
+
+struct State {
+    info: Mutex<TaskInfo>,
+    run_now: Notify,
+    /// whether the job is running, `runs` is bumped under it
+    running: Mutex<bool>,
+    /// bumped after each run
+    runs: watch::Sender<u64>,
+}
+
+/// A job run every interval until this is dropped.
+pub struct Task {
+    state: Arc<State>,
+    handle: JoinHandle<()>,
+}
+
+impl Task {
+    pub fn spawn<F, Fut>(name: impl Into<String>, schedule: Schedule, job: F) -> Self
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        let state = Arc::new(State {
+            info: Mutex::new(TaskInfo {
+                name: name.into(),
+                interval: schedule.interval.as_secs(),
+                ..Default::default()
+            }),
+            run_now: Notify::new(),
+            running: Mutex::new(false),
+            runs: watch::Sender::new(0),
+        });
+
+        {
+            let mut tasks = TASKS.lock().unwrap();
+            tasks.retain(|x| x.strong_count() > 0);
+            tasks.push(Arc::downgrade(&state));
+        }
+
+        let handle = tokio::spawn(run(state.clone(), schedule, job));
+        Self { state, handle }
+    }
+
+    /// runs the job now instead of at its next run, e.g. when asked by the
+    /// API, and waits for it, the interval starts over from there. If it's
+    /// running already that run is waited for instead of starting another.
+    pub async fn run_now(&self) -> Result<(), String> {
+        let mut runs = self.state.runs.subscribe();
+        {
+            let running = self.state.running.lock().unwrap();
+            runs.mark_unchanged();
+            if !*running {
+                self.state.run_now.notify_one();
+            }
+        }
+        let _ = runs.changed().await;
+        match self.info().last_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub fn info(&self) -> TaskInfo {
+        self.state.info.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// when the tasks alive run next, soonest first
+pub fn schedule() -> Vec<TaskInfo> {
+    let mut tasks = TASKS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|x| x.info.lock().unwrap().clone())
+        .collect::<Vec<_>>();
+    tasks.sort_by_key(|x| x.next_run);
+    tasks
+}
+
+fn jitter(interval: Duration) -> Duration {
+    let max = interval.min(MAX_JITTER);
+    if max.is_zero() {
+        return max;
+    }
+    Duration::from_millis(rand_range(0..max.as_millis() as u64))
+}
+
+async fn run<F, Fut>(state: Arc<State>, schedule: Schedule, job: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut wait = jitter(schedule.interval);
+    if !schedule.immediately {
+        wait += schedule.interval;
+    }
+
+    loop {
+        state.info.lock().unwrap().next_run = Some(Utc::now() + wait);
+        tokio::select! {
+            _ = tokio::time::sleep_until(Instant::now() + wait) => {}
+            _ = state.run_now.notified() => {}
+        }
+
+        {
+            *state.running.lock().unwrap() = true;
+            // a run_now that came in as the timer fired is taken by this run
+            let _ = state.run_now.notified().now_or_never();
+        }
+
+        let name = state.info.lock().unwrap().name.clone();
+        debug!("running scheduled {}", name);
+        let result = job().await;
+
+        let mut info = state.info.lock().unwrap();
+        info.last_run = Some(Utc::now());
+        wait = match result {
+            Ok(()) => {
+                info.failures = 0;
+                info.last_error = None;
+                schedule.interval
+            }
+            Err(e) => {
+                info.failures += 1;
+                info.last_error = Some(e.to_string());
+                let backoff = RETRY_MIN
+                    .saturating_mul(1 << (info.failures - 1).min(16))
+                    .min(schedule.interval);
+                warn!("{} failed: {}, retrying in {:?}", name, e, backoff);
+                backoff
+            }
+        };
+        drop(info);
+
+        {
+            let mut running = state.running.lock().unwrap();
+            *running = false;
+            state.runs.send_modify(|x| *x += 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::FutureExt;
+
+    use super::{Schedule, Task};
+
+    fn counting(
+        interval: u64,
+        immediately: bool,
+        fail_first: usize,
+    ) -> (Task, Arc<AtomicUsize>) {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let r = runs.clone();
+        let task = Task::spawn(
+            "test",
+            Schedule {
+                interval: Duration::from_secs(interval),
+                immediately,
+            },
+            move || {
+                let n = r.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if n < fail_first {
+                        anyhow::bail!("run {} failed", n);
+                    }
+                    Ok(())
+                }
+            },
+        );
+        (task, runs)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_jitter_and_interval() {
+        let (_task, runs) = counting(600, true, 0);
+        // at most 30s of jitter
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        let (task, runs) = counting(600, false, 0);
+        tokio::time::sleep(Duration::from_secs(599)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        assert!(task.info().next_run.is_some());
+
+        task.run_now().await.expect("run failed");
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        drop(task);
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1, "stopped once dropped");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_backoff() {
+        let (task, runs) = counting(600, true, 2);
+        while runs.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(task.info().failures, 1);
+
+        // 5s, then 10s
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(task.info().failures, 0);
+        assert!(task.info().last_error.is_none());
+
+        assert!(task.run_now().await.is_ok());
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_now_while_running() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let r = runs.clone();
+        let task = Task::spawn(
+            "test",
+            Schedule {
+                interval: Duration::from_secs(600),
+                immediately: false,
+            },
+            move || {
+                r.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(10))
+                    .map(|_| Ok::<_, anyhow::Error>(()))
+            },
+        );
+
+        // the second comes in while the first one's run is going
+        let (a, b) = tokio::join!(task.run_now(), async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            task.run_now().await
+        });
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1, "no run queued after it");
+    }
+}
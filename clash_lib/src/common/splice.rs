@@ -0,0 +1,229 @@
+//! Relaying between two plain tcp sockets with splice(2): the bytes go from
+//! one socket into a pipe and from the pipe into the other socket, they are
+//! never copied to userspace.
+
+use std::{
+    io,
+    net::Shutdown,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use futures::future::{self, Either};
+use tokio::{io::Interest, net::TcpStream};
+
+use super::io::CopyBidirectionalError;
+
+/// the most moved by one splice call, the default pipe capacity
+const PIPE_SIZE: usize = 64 * 1024;
+
+struct Pipe {
+    r: OwnedFd,
+    w: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two fds
+        if unsafe {
+            libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC)
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both were just created and nothing else owns them
+        Ok(unsafe {
+            Self {
+                r: OwnedFd::from_raw_fd(fds[0]),
+                w: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both fds stay open for the call, null offsets are what pipes
+    // and sockets take
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Moves everything from `from` to `to` until `from` reaches EOF, then shuts
+/// `to` down for writing. `on_bytes` is called with every chunk once it is
+/// written.
+async fn splice_one(
+    from: &TcpStream,
+    to: &TcpStream,
+    total: &AtomicU64,
+    on_bytes: &mut (dyn FnMut(usize) + Send),
+) -> io::Result<()> {
+    let pipe = Pipe::new()?;
+    loop {
+        // the pipe is empty here, so a would block is the socket's
+        let n = from
+            .async_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.w.as_raw_fd(), PIPE_SIZE)
+            })
+            .await?;
+        if n == 0 {
+            break;
+        }
+
+        let mut pending = n;
+        while pending > 0 {
+            let m = to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.r.as_raw_fd(), to.as_raw_fd(), pending)
+                })
+                .await?;
+            if m == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            pending -= m;
+        }
+
+        total.fetch_add(n as u64, Ordering::Relaxed);
+        on_bytes(n);
+    }
+    shutdown_write(to)
+}
+
+fn shutdown_write(s: &TcpStream) -> io::Result<()> {
+    match socket2::SockRef::from(s).shutdown(Shutdown::Write) {
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        r => r,
+    }
+}
+
+/// What [`super::io::copy_buf_bidirectional_with_timeout`] does, with
+/// splice. Once one direction is done the other gets the timeout given for
+/// it to finish before the socket it writes to is shut down.
+///
+/// `on_a_to_b` and `on_b_to_a` see the bytes as they are moved, the totals
+/// are returned.
+pub async fn splice_bidirectional(
+    a: &TcpStream,
+    b: &TcpStream,
+    a_to_b_timeout: Duration,
+    b_to_a_timeout: Duration,
+    mut on_a_to_b: impl FnMut(usize) + Send,
+    mut on_b_to_a: impl FnMut(usize) + Send,
+) -> Result<(u64, u64), CopyBidirectionalError> {
+    let up = AtomicU64::new(0);
+    let down = AtomicU64::new(0);
+
+    let a_to_b = splice_one(a, b, &up, &mut on_a_to_b);
+    let b_to_a = splice_one(b, a, &down, &mut on_b_to_a);
+    tokio::pin!(a_to_b, b_to_a);
+
+    match future::select(a_to_b, b_to_a).await {
+        Either::Left((r, b_to_a)) => {
+            r.map_err(CopyBidirectionalError::LeftClosed)?;
+            match tokio::time::timeout(b_to_a_timeout, b_to_a).await {
+                Ok(r) => r.map_err(CopyBidirectionalError::RightClosed)?,
+                Err(_) => {
+                    shutdown_write(a).map_err(CopyBidirectionalError::RightClosed)?
+                }
+            }
+        }
+        Either::Right((r, a_to_b)) => {
+            r.map_err(CopyBidirectionalError::RightClosed)?;
+            match tokio::time::timeout(a_to_b_timeout, a_to_b).await {
+                Ok(r) => r.map_err(CopyBidirectionalError::LeftClosed)?,
+                Err(_) => {
+                    shutdown_write(b).map_err(CopyBidirectionalError::LeftClosed)?
+                }
+            }
+        }
+    }
+
+    Ok((up.load(Ordering::Relaxed), down.load(Ordering::Relaxed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::splice_bidirectional;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let c = TcpStream::connect(l.local_addr().unwrap()).await.unwrap();
+        let (s, _) = l.accept().await.unwrap();
+        (c, s)
+    }
+
+    #[tokio::test]
+    async fn test_splice_bidirectional() {
+        // client <-> (a, b) <-> server, relayed in the middle
+        let (mut client, a) = pair().await;
+        let (b, mut server) = pair().await;
+
+        let up = Arc::new(AtomicU64::new(0));
+        let counted = up.clone();
+        let relay = tokio::spawn(async move {
+            splice_bidirectional(
+                &a,
+                &b,
+                Duration::from_secs(1),
+                Duration::from_secs(1),
+                move |n| {
+                    counted.fetch_add(n as u64, Ordering::Relaxed);
+                },
+                |_| {},
+            )
+            .await
+        });
+
+        // more than the socket buffers hold, so it has to be read meanwhile
+        let data = vec![7u8; 4 * 1024 * 1024];
+        let mut received = vec![];
+        let (w, r) = tokio::join!(
+            async {
+                client.write_all(&data).await?;
+                client.shutdown().await
+            },
+            server.read_to_end(&mut received)
+        );
+        w.unwrap();
+        r.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"bye").await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+
+        let (a_to_b, b_to_a) = relay.await.unwrap().unwrap();
+        assert_eq!(a_to_b, data.len() as u64);
+        assert_eq!(b_to_a, 3);
+        assert_eq!(up.load(Ordering::Relaxed), data.len() as u64);
+    }
+}
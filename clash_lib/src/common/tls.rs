@@ -1,12 +1,14 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
 use once_cell::sync::Lazy;
 use rustls::{
     client::{danger::ServerCertVerifier, WebPkiServerVerifier},
     pki_types::{CertificateDer, ServerName, UnixTime},
     RootCertStore,
 };
+use sha2::{Digest, Sha256};
 use tracing::warn;
 
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
 pub static GLOBAL_ROOT_STORE: Lazy<Arc<RootCertStore>> =
     Lazy::new(global_root_store);
@@ -16,6 +18,25 @@ fn global_root_store() -> Arc<RootCertStore> {
     Arc::new(root_store)
 }
 
+/// The roots of the OS trust store, so a private CA installed on the host is
+/// trusted too. Falls back to the bundled roots if none can be loaded.
+pub static SYSTEM_ROOT_STORE: Lazy<Arc<RootCertStore>> =
+    Lazy::new(system_root_store);
+
+fn system_root_store() -> Arc<RootCertStore> {
+    let loaded = rustls_native_certs::load_native_certs();
+    for e in &loaded.errors {
+        warn!("failed to load a system root certificate: {}", e);
+    }
+    let mut root_store = RootCertStore::empty();
+    let (added, _) = root_store.add_parsable_certificates(loaded.certs);
+    if added == 0 {
+        warn!("no system root certificates found, using the bundled ones");
+        return GLOBAL_ROOT_STORE.clone();
+    }
+    Arc::new(root_store)
+}
+
 /// Warning: NO validation on certs.
 #[derive(Debug)]
 pub struct DummyTlsVerifier(Arc<WebPkiServerVerifier>);
@@ -127,3 +148,208 @@ impl ServerCertVerifier for NoHostnameTlsVerifier {
         self.0.supported_verify_schemes()
     }
 }
+
+/// None of the certificates the server presented has a pinned key.
+#[derive(Debug, thiserror::Error)]
+#[error("certificate pin mismatch")]
+pub struct CertPinMismatch;
+
+/// if `e` is a handshake failing for [`CertPinMismatch`]
+pub fn is_pin_mismatch(e: &io::Error) -> bool {
+    let Some(rustls::Error::Other(rustls::OtherError(e))) =
+        e.get_ref().and_then(|x| x.downcast_ref::<rustls::Error>())
+    else {
+        return false;
+    };
+    e.is::<CertPinMismatch>()
+}
+
+/// The pins of `pinned-cert-chain-sha256`, each the base64 of the SHA-256
+/// of a DER SubjectPublicKeyInfo. For a PEM certificate that's
+///
+/// ```sh
+/// openssl x509 -in cert.pem -pubkey -noout \
+///     | openssl pkey -pubin -outform der \
+///     | openssl dgst -sha256 -binary | base64
+/// ```
+pub fn parse_pins(pins: &[String]) -> Result<Vec<[u8; 32]>, String> {
+    pins.iter()
+        .map(|x| {
+            STANDARD
+                .decode(x.trim())
+                .ok()
+                .and_then(|x| x.try_into().ok())
+                .ok_or(format!("invalid certificate pin: {}", x))
+        })
+        .collect()
+}
+
+/// Accepts a server only if one of the certificates it presents has its
+/// SPKI pinned. The chain is validated too unless `chain` is false, which
+/// is for servers with certificates of a private CA that isn't trusted
+/// otherwise. The handshake signatures are checked either way.
+#[derive(Debug)]
+pub struct PinnedCertVerifier {
+    pins: Vec<[u8; 32]>,
+    chain: bool,
+    pki: Arc<WebPkiServerVerifier>,
+}
+
+impl PinnedCertVerifier {
+    pub fn new(pins: Vec<[u8; 32]>, chain: bool) -> io::Result<Self> {
+        Ok(Self {
+            pins,
+            chain,
+            pki: WebPkiServerVerifier::builder(GLOBAL_ROOT_STORE.clone())
+                .build()
+                .map_err(io::Error::other)?,
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if self.chain {
+            self.pki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|x| spki_sha256(x))
+            .any(|x| self.pins.contains(&x));
+        if !pinned {
+            return Err(rustls::Error::Other(rustls::OtherError(Arc::new(
+                CertPinMismatch,
+            ))));
+        }
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.pki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.pki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.pki.supported_verify_schemes()
+    }
+}
+
+/// the SHA-256 of the DER SubjectPublicKeyInfo of `cert`
+fn spki_sha256(cert: &[u8]) -> Option<[u8; 32]> {
+    let (_, cert, _) = der_next(cert)?;
+    let (_, mut tbs, _) = der_next(cert)?;
+    // the version is optional, tagged [0]
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_next(tbs)?.2;
+    }
+    // the serial, the signature algorithm, the issuer, the validity and the
+    // subject come before it
+    for _ in 0..5 {
+        tbs = der_next(tbs)?.2;
+    }
+    let (tlv, ..) = der_next(tbs)?;
+    Some(Sha256::digest(tlv).into())
+}
+
+/// the next DER element of `input`, as the whole of it, its contents and
+/// what follows it
+fn der_next(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *input.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + n)?
+            .iter()
+            .fold(0usize, |acc, b| acc << 8 | *b as usize);
+        (len, 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    Some((
+        input.get(..end)?,
+        input.get(header..end)?,
+        input.get(end..)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_pin_mismatch, parse_pins, spki_sha256, CertPinMismatch};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use std::{io, sync::Arc};
+
+    const CERT: &str =
+        include_str!("../../../clash/tests/data/config/example.org.pem");
+    // from the openssl pipeline in the doc of parse_pins
+    const PIN: &str = "ljfopnWLvEfZ8N/c4hYH4hBrNun7Z8UNeYVZIPECUpk=";
+
+    #[test]
+    fn test_spki_sha256() {
+        let der = rustls_pemfile::certs(&mut CERT.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let hash = spki_sha256(&der).unwrap();
+        assert_eq!(STANDARD.encode(hash), PIN);
+
+        assert!(spki_sha256(&der[..100]).is_none());
+        assert!(spki_sha256(&[]).is_none());
+    }
+
+    #[test]
+    fn test_parse_pins() {
+        let pins = parse_pins(&[PIN.to_owned()]).unwrap();
+        assert_eq!(pins.len(), 1);
+
+        assert!(parse_pins(&["not base64".to_owned()]).is_err());
+        // an SHA-1
+        assert!(parse_pins(&["2jmj7l5rSw0yVb/vlWAYkK/YBwk=".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_is_pin_mismatch() {
+        let e = rustls::Error::Other(rustls::OtherError(Arc::new(CertPinMismatch)));
+        assert!(is_pin_mismatch(&io::Error::new(
+            io::ErrorKind::InvalidData,
+            e
+        )));
+
+        let e = rustls::Error::General("other".to_owned());
+        assert!(!is_pin_mismatch(&io::Error::new(
+            io::ErrorKind::InvalidData,
+            e
+        )));
+        assert!(!is_pin_mismatch(&io::ErrorKind::TimedOut.into()));
+    }
+}
@@ -20,6 +20,7 @@ where
     rng.gen_range(range)
 }
 
+#[cfg_attr(not(feature = "vmess"), allow(dead_code))]
 pub fn rand_fill<T>(buf: &mut T)
 where
     T: Fill + ?Sized,
@@ -36,6 +37,10 @@ pub fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
         .collect()
 }
 
+#[cfg_attr(
+    not(any(feature = "trojan", feature = "quic-protocols")),
+    allow(dead_code)
+)]
 pub fn encode_hex(bytes: &[u8]) -> String {
     let mut s = String::with_capacity(bytes.len() * 2);
     for &b in bytes {
@@ -1,4 +1,4 @@
-use crate::Error;
+use crate::{common::rate_limit::Bandwidth, Error};
 use std::{collections::HashMap, fmt::Display, path::PathBuf, str::FromStr};
 
 use serde::{Deserialize, Serialize};
@@ -25,26 +25,53 @@ impl Default for DnsHijack {
 #[serde(rename_all = "kebab-case")]
 pub struct TunConfig {
     pub enable: bool,
-    #[serde(alias = "device-url")]
+    /// `dev://<name>` or `fd://<fd>`
+    #[serde(alias = "device-url", default)]
     pub device_id: String,
-    /// tun interface address
-    #[serde(default = "default_tun_address")]
+    /// tun device name, a shorthand for `device-id: dev://<name>`
+    pub device: Option<String>,
+    /// tun interface IPv4 address
+    #[serde(alias = "inet4-address", default = "default_tun_address")]
     pub gateway: String,
+    /// tun interface IPv6 address
+    pub inet6_address: Option<String>,
+    /// prefixes routed into the tun device when `route-all` is false
+    #[serde(alias = "route-address")]
     pub routes: Option<Vec<String>>,
+    /// prefixes that never go into the tun device, carved out of `routes`
+    /// or, with `route-all`, out of the default route
+    /// # Example
+    /// ```yaml
+    /// route-address:
+    ///   - 10.0.0.0/8
+    /// route-exclude-address:
+    ///   - 10.8.0.0/16 # e.g. a WireGuard network
+    /// ```
+    #[serde(default)]
+    pub route_exclude_address: Vec<String>,
     #[serde(default)]
     pub route_all: bool,
+    /// MTU of the tun device, at least 576. TCP MSS is clamped to fit.
     pub mtu: Option<i32>,
     /// fwmark on Linux only
     pub so_mark: Option<u32>,
     /// policy routing table on Linux only
     pub route_table: Option<u32>,
-    /// Will hijack UDP:53 DNS queries to the Clash DNS server if set to true
-    /// setting to a list has the same effect as setting to true
+    /// Answers the DNS queries going through the tun device with the Clash
+    /// DNS server, over both UDP and TCP.
+    /// `true` is the same as `[any:53]`, `any` matches any destination IP.
+    /// Traffic to a hijacked address that isn't DNS is proxied as usual.
+    /// # Example
+    /// ```yaml
+    /// dns-hijack:
+    ///   - any:53
+    ///   - 198.18.0.2:53
+    /// ```
     #[serde(default)]
     pub dns_hijack: DnsHijack,
 }
 
-#[derive(Serialize, Deserialize, Default, Copy, Clone)]
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum RunMode {
     #[serde(alias = "Global")]
@@ -66,19 +93,99 @@ impl Display for RunMode {
     }
 }
 
-#[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
+/// How UDP is NATed on its way out. Symmetric keeps a session per client
+/// and destination, only the destination can answer. Full-cone keeps one
+/// per client for any destination, so any peer it has talked to, or that
+/// learnt its address from one, can reach the client, as some games need.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UdpNat {
+    #[default]
+    Symmetric,
+    FullCone,
+}
+
+/// The browser the ClientHello of TLS to a server is shaped like, as far as
+/// rustls goes: the order of the cipher suites and key exchange groups. The
+/// extensions are rustls' own, so it's no match for uTLS.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientFingerprint {
+    Chrome,
+    Firefox,
+    Safari,
+    Ios,
+    Edge,
+    /// one of the others, picked when the proxy is built
+    Random,
+}
+
+impl ClientFingerprint {
+    /// a browser for `random`, the others as they are
+    pub fn pick(self) -> Self {
+        const BROWSERS: [ClientFingerprint; 5] = [
+            ClientFingerprint::Chrome,
+            ClientFingerprint::Firefox,
+            ClientFingerprint::Safari,
+            ClientFingerprint::Ios,
+            ClientFingerprint::Edge,
+        ];
+        match self {
+            ClientFingerprint::Random => {
+                BROWSERS[rand::random::<usize>() % BROWSERS.len()]
+            }
+            fp => fp,
+        }
+    }
+}
+
+/// Which sources a SOCKS5 UDP association takes datagrams from.
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SocksUdpSource {
+    /// the IP of the client, any port
+    #[default]
+    Loose,
+    /// the address declared in the request
+    Strict,
+}
+
+/// ordered by verbosity, `Trace` being the most verbose
+#[derive(
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    Default,
+    Copy,
+    Clone,
+    Debug,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Trace,
     Debug,
     #[default]
     Info,
+    #[serde(alias = "warn")]
     Warning,
     Error,
     #[serde(alias = "off")]
     Silent,
 }
 
+/// how log lines are written to stdout and the log file
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// one object per line, event fields as keys
+    Json,
+}
+
 impl Display for LogLevel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -137,6 +244,8 @@ impl Display for LogLevel {
 ///     - 1.1.1.1 # default value
 ///     - tls://1.1.1.1:853 # DNS over TLS
 ///     - https://1.1.1.1/dns-query # DNS over HTTPS
+///     # dialed by IP, the certificate checked against the name
+///     - https://1.1.1.1/dns-query?sni=cloudflare-dns.com
 /// #    - dhcp://en0 # dns from dhcp
 ///
 /// allow-lan: true
@@ -144,6 +253,7 @@ impl Display for LogLevel {
 /// log-level: debug
 /// external-controller: 127.0.0.1:9090
 /// external-ui: "public"
+/// # external-ui-url: "https://github.com/MetaCubeX/metacubexd/archive/refs/heads/gh-pages.zip"
 /// # secret: "clash-rs"
 /// experimental:
 ///   ignore-resolve-fail: true
@@ -151,6 +261,7 @@ impl Display for LogLevel {
 /// profile:
 ///   store-selected: true
 ///   store-fake-ip: false
+///   store-statistics: false
 ///
 /// proxy-groups:
 ///   - name: "relay" type: relay proxies:
@@ -260,7 +371,7 @@ impl Display for LogLevel {
 pub struct Config {
     /// The HTTP proxy port
     pub port: Option<u16>,
-    /// The SOCKS5 proxy port
+    /// The SOCKS4/SOCKS4a/SOCKS5 proxy port
     pub socks_port: Option<u16>,
     /// The redir port
     #[doc(hidden)]
@@ -274,14 +385,185 @@ pub struct Config {
     pub mixed_port: Option<u16>,
 
     /// HTTP and SOCKS5 proxy authentication
+    /// # Note
+    /// SOCKS4 has no password field, the USERID of a SOCKS4 request is
+    /// checked as `user:pass`, or as `user` with an empty password if it
+    /// contains no colon.
     pub authentication: Vec<String>,
+    /// Max number of concurrent inbound connections across all listeners.
+    /// `0` means unlimited.
+    /// # Note
+    /// - connections over the limit are accepted and closed immediately
+    /// - UDP sessions over the limit are dropped
+    pub max_connections: usize,
+    /// Max number of concurrent inbound connections per listener, keyed by
+    /// `http`, `socks`, `mixed` or `tproxy`. `0` means unlimited.
+    /// # Example
+    /// ```yaml
+    /// listener-max-connections:
+    ///   socks: 1000
+    /// ```
+    pub listener_max_connections: HashMap<String, usize>,
+    /// Bandwidth shared by all the connections of a listener, TCP and UDP,
+    /// keyed like `listener-max-connections`.
+    /// # Example
+    /// ```yaml
+    /// listener-bandwidth:
+    ///   socks:
+    ///     up: 10mbps
+    ///     down: 50mbps
+    /// ```
+    pub listener_bandwidth: HashMap<String, BandwidthLimits>,
+    /// TLS the `http`, `socks` or `mixed` listener terminates before the proxy
+    /// handshake, keyed like `listener-max-connections`. With more than one
+    /// certificate, the one valid for the SNI the client sent is used, the
+    /// first when there's none. The files are read again on every reload, and
+    /// a watched config is reloaded when they change.
+    /// # Example
+    /// ```yaml
+    /// listener-tls:
+    ///   socks:
+    ///     certificate: ./certs/fullchain.pem
+    ///     private-key: ./certs/privkey.pem
+    ///   mixed:
+    ///     - certificate: ./certs/a.example.com.pem
+    ///       private-key: ./certs/a.example.com-key.pem
+    ///     - certificate: ./certs/b.example.com.pem
+    ///       private-key: ./certs/b.example.com-key.pem
+    /// ```
+    pub listener_tls: HashMap<String, ListenerTlsDef>,
+    /// The sources a listener takes connections from, keyed like
+    /// `listener-max-connections`, checked before any handshake and for the
+    /// UDP packets that would start a session. A source in `blocked-ips` is
+    /// refused even if it's in `allowed-ips` too, and an empty `allowed-ips`
+    /// allows all the sources not blocked. This is on top of `allow-lan`.
+    /// The `tunnels` can't have one, they only have `allow-lan`.
+    /// # Example
+    /// ```yaml
+    /// listener-acl:
+    ///   socks:
+    ///     allowed-ips: [10.0.0.0/8]
+    ///     blocked-ips: [10.0.5.0/24]
+    /// ```
+    pub listener_acl: HashMap<String, ListenerAclDef>,
+    /// Local addresses forwarded to a fixed destination, through the given
+    /// proxy or group, or by the rules when there is none.
+    /// # Example
+    /// ```yaml
+    /// tunnels:
+    ///   # network,address,target[,proxy]
+    ///   - tcp/udp,127.0.0.1:6553,8.8.8.8:53,PROXY
+    ///   - network: [tcp]
+    ///     address: 127.0.0.1:2222
+    ///     target: example.com:22
+    /// ```
+    pub tunnels: Vec<TunnelDef>,
+    /// Which datagrams a SOCKS5 UDP association takes. `loose`, the
+    /// default, takes those from the IP of the client on any port, as RFC
+    /// 1928 asks, so clients behind NAT work. `strict` takes only those from
+    /// the address the client declared in its UDP ASSOCIATE, if it did.
+    /// # Example
+    /// ```yaml
+    /// socks-udp-source: strict
+    /// ```
+    pub socks_udp_source: SocksUdpSource,
+    /// Seconds a UDP session is kept without traffic either way, default 60.
+    /// Sessions to port 53 are kept for 10 at most.
+    pub udp_timeout: u64,
+    /// Max number of UDP sessions kept across all inbounds, the least
+    /// recently active one is closed to make room for a new one. `0` means
+    /// unlimited, default 16384
+    pub max_udp_sessions: usize,
+    /// The NAT behavior of UDP through DIRECT, and through the proxies that
+    /// support full-cone and don't set their own `udp-nat`, socks5 for now.
+    /// A full-cone mapping is kept for `udp-timeout` without traffic. With
+    /// fake-ip, the answers come from the real address of a peer.
+    /// # Example
+    /// ```yaml
+    /// udp-nat: full-cone # or symmetric, the default
+    /// ```
+    pub udp_nat: UdpNat,
+    /// Where UDP goes when the proxy the rules picked, or the proxy a group
+    /// is using, has no UDP. `REJECT`, the default, drops it, `DIRECT` or
+    /// the name of a proxy or group sends it there instead.
+    /// # Example
+    /// ```yaml
+    /// udp-fallback: DIRECT
+    /// ```
+    pub udp_fallback: String,
+    /// Seconds a TCP connection is kept without traffic either way before
+    /// it's closed, default 0 for never
+    pub tcp_idle_timeout: u64,
+    /// Destination ports `tcp-idle-timeout` doesn't apply to, for protocols
+    /// that stay quiet for long, default ssh and IRC
+    /// # Example
+    /// ```yaml
+    /// tcp-idle-timeout: 300
+    /// tcp-idle-exempt-ports: [22, 6667, 6697, 5222]
+    /// ```
+    pub tcp_idle_exempt_ports: Vec<u16>,
+    /// The protocol a TCP connection is assumed to carry by its destination
+    /// port, for the `PROTOCOL` rules. UDP isn't tagged, 443 there is QUIC.
+    /// Default ssh, http and tls on 22, 80 and 443, setting it replaces
+    /// those.
+    /// # Example
+    /// ```yaml
+    /// port-hints:
+    ///   22: ssh
+    ///   443: tls
+    ///   853: tls
+    /// rules:
+    ///   - PROTOCOL,ssh,DIRECT
+    /// ```
+    pub port_hints: HashMap<u16, String>,
+    /// Seconds connecting to a server may take before giving up, 1 to 300,
+    /// default 10. A proxy's own `dial-timeout` takes precedence.
+    /// # Example
+    /// ```yaml
+    /// connect-timeout: 15
+    /// proxies:
+    ///   - name: dc
+    ///     type: ss
+    ///     dial-timeout: 2
+    /// ```
+    pub connect_timeout: u64,
+    /// The sizes in bytes of the kernel buffers of the sockets, those to the
+    /// servers and of the inbound connections, for a single connection to
+    /// go fast over a long fat path. 0, the default, leaves them to the OS,
+    /// and sizes are clamped to 4 KiB - 64 MiB. The kernel may cap them
+    /// further, e.g. at `net.core.wmem_max` on Linux. A proxy can set its
+    /// own.
+    /// # Example
+    /// ```yaml
+    /// socket-send-buffer-size: 4194304
+    /// socket-recv-buffer-size: 4194304
+    /// proxies:
+    ///   - name: far
+    ///     type: trojan
+    ///     socket-recv-buffer-size: 16777216
+    /// ```
+    pub socket_send_buffer_size: u32,
+    pub socket_recv_buffer_size: u32,
+    /// The `client-fingerprint` of the TLS of the proxies that don't set
+    /// their own, those from providers included: trojan, and vmess and
+    /// socks5 with `tls`. Changing it rebuilds only those proxies on reload.
+    /// # Example
+    /// ```yaml
+    /// global-client-fingerprint: chrome
+    /// proxies:
+    ///   - name: tj
+    ///     type: trojan
+    ///     client-fingerprint: firefox
+    /// ```
+    pub global_client_fingerprint: Option<ClientFingerprint>,
     /// Allow connections to the local-end server from other LAN IP addresses
     #[deprecated = "dont use. see `bind_address`"]
     pub allow_lan: bool,
     /// The address that the inbound listens on
     /// # Note
-    /// - setting this to `*` will listen on all interfaces, which is
-    ///   essentially the same as setting it to `0.0.0.0`
+    /// - setting this to `*` will listen on all interfaces, over both IPv4 and
+    ///   IPv6, which is essentially the same as setting it to `::`
+    /// - an IPv6 address can be written bare or in brackets, e.g. `[::1]`
     /// - setting this to non local IP will enable `allow_lan` automatically
     /// - and if you don't want `allow_lan` to be enabled, you should set this
     ///   to `localhost` or `127.1`
@@ -289,9 +571,98 @@ pub struct Config {
     /// Clash router working mode
     /// Either `rule`, `global` or `direct`
     pub mode: RunMode,
+    /// Reload the config when its file, a file provider it uses, a
+    /// certificate of a listener or of the DNS server, or a hosts file
+    /// changes
+    pub config_watch: bool,
+    /// Fail on fields clash-rs doesn't know instead of ignoring them with a
+    /// warning, e.g. the ones only mihomo has
+    pub strict_config: bool,
+    /// Fetch the config from this url instead, everything else in this file
+    /// is ignored. The fetched config is cached next to it so it can still
+    /// start when the url can't be reached.
+    pub config_url: Option<String>,
+    /// Headers sent when fetching `config-url`, e.g. for auth
+    pub config_url_headers: HashMap<String, String>,
+    /// How long open connections get to finish on shutdown before they are
+    /// closed, e.g. `500ms`, `10s` or `1m`, default `10s`. A second ctrl-c
+    /// skips the wait.
+    pub shutdown_grace: Option<String>,
+    /// Close the open connections when the default interface changes, e.g.
+    /// moving from Wi-Fi to Ethernet, instead of leaving them to time out
+    pub close_connections_on_network_change: bool,
+    /// Report the latency of a second request on the connection the health
+    /// check already made, leaving out the handshakes that make the delays
+    /// of different protocols hard to compare
+    pub unified_delay: bool,
     /// Log level
     /// Either `debug`, `info`, `warning`, `error` or `off`
     pub log_level: LogLevel,
+    /// Extra tracing directives on top of `log-level`, RUST_LOG wins over
+    /// them when set
+    /// # Example
+    /// ```yaml
+    /// log-directives:
+    ///   - clash_lib::app::dns=trace
+    ///   - clash_lib::proxy=warn
+    /// ```
+    pub log_directives: Vec<String>,
+    /// Log format, either `text` or `json`, only read at startup
+    pub log_format: LogFormat,
+    /// Log file path relative to the $CWD, written besides stdout
+    /// # Example
+    /// ```yaml
+    /// log-file: /var/log/clash.log
+    /// log-max-size: 10mb
+    /// log-max-backups: 3
+    /// ```
+    pub log_file: Option<String>,
+    /// Size the log file is rotated at, e.g. `512kb`, `10mb` or `1gb`,
+    /// default `10mb`. Rotated files get numeric suffixes, `clash.log.1`
+    /// being the newest.
+    pub log_max_size: Option<String>,
+    /// How many rotated log files are kept, default 3
+    pub log_max_backups: Option<usize>,
+    /// How many recent log events are kept for `/logs` clients connecting
+    /// later, default 1000, 0 disables it
+    pub log_buffer_size: usize,
+    /// Send logs to syslog as well, /dev/log unless `log-syslog-server` is
+    /// set
+    pub log_syslog: bool,
+    /// Remote syslog server, `udp://host:port` or `tcp://host:port`
+    /// # Example
+    /// ```yaml
+    /// log-syslog: true
+    /// log-syslog-server: udp://192.168.1.1:514
+    /// ```
+    pub log_syslog_server: Option<String>,
+    /// Log one line per finished connection at info level, with its source,
+    /// destination, rule, proxy chain, traffic, duration and error
+    pub log_connections: bool,
+    /// Names for the LAN clients, shown by `GET /connections/sources`
+    /// # Example
+    /// ```yaml
+    /// clients:
+    ///   192.168.1.10: laptop
+    ///   192.168.1.23: tv
+    /// ```
+    pub clients: HashMap<String, String>,
+    /// Tracing settings, only read at startup
+    /// # Example
+    /// ```yaml
+    /// tracing:
+    ///   otlp-endpoint: http://localhost:4317
+    /// ```
+    pub tracing: Tracing,
+    /// Tokio runtime settings, only read at startup and overridden by the
+    /// command line. Can't be set by a config fetched from a url.
+    /// # Example
+    /// ```yaml
+    /// runtime:
+    ///   worker-threads: 2
+    ///   flavor: multi
+    /// ```
+    pub runtime: Runtime,
     /// DNS client/server settings
     pub dns: DNS,
     /// Profile settings
@@ -329,10 +700,44 @@ pub struct Config {
     pub ipv6: bool,
     /// external controller address
     pub external_controller: Option<String>,
-    /// dashboard folder path relative to the $CWD
+    /// CORS of the external controller, for dashboards served from another
+    /// origin
+    /// # Example
+    /// ```yaml
+    /// external-controller-cors:
+    ///   allow-origins:
+    ///     - https://yacd.metacubex.one
+    ///   allow-private-network: true
+    /// ```
+    pub external_controller_cors: ExternalControllerCors,
+    /// dashboard folder path relative to the $CWD, served at `/ui/`
     pub external_ui: Option<String>,
-    /// external controller secret
+    /// zip archive of a dashboard release, downloaded into `external-ui` when
+    /// the folder doesn't exist yet and on `POST /upgrade/ui`
+    pub external_ui_url: Option<String>,
+    /// external controller secret, sent by clients as
+    /// `Authorization: Bearer <secret>`, or `?token=<secret>` for websockets
     pub secret: Option<String>,
+    /// requests to the external controller from loopback addresses don't
+    /// need the secret
+    pub external_controller_skip_auth_loopback: bool,
+    /// unix socket to serve the external controller on as well, or instead
+    /// when `external-controller` is not set. the secret still applies to
+    /// it unless `external-controller-unix-skip-auth` is set.
+    /// # Example
+    /// ```yaml
+    /// external-controller-unix: /run/clash/api.sock
+    /// external-controller-unix-mode: "0660"
+    /// ```
+    pub external_controller_unix: Option<String>,
+    /// permissions of the socket file as an octal string, default "0600"
+    pub external_controller_unix_mode: Option<String>,
+    /// requests over the unix socket don't need the secret
+    pub external_controller_unix_skip_auth: bool,
+    /// delay tests through the API may fetch any `url`. set to false, they
+    /// can only fetch the health check URLs of the groups and providers,
+    /// so the API can't be used to have requests sent anywhere
+    pub external_controller_allow_origin_tests: bool,
     #[serde(rename = "interface-name")]
     /// outbound interface name
     /// # Note
@@ -359,15 +764,72 @@ pub struct Config {
     ///   device-id: "dev://utun1989"
     /// ```
     pub tun: Option<TunConfig>,
+
+    /// key paths of the values filled in from `${VAR}` references, they are
+    /// hidden in config dumps
+    #[serde(skip)]
+    pub redacted_paths: Vec<String>,
+    /// key paths of the fields that were ignored, the sections kept as raw
+    /// yaml here are checked when they are parsed
+    #[serde(skip)]
+    pub unknown_fields: Vec<String>,
+}
+
+impl Config {
+    /// the config as yaml with the values that came from the environment,
+    /// the controller secret, the config url, credentials like passwords and
+    /// keys, and everything but the origin of provider urls replaced, for
+    /// dumping it anywhere
+    pub fn redacted(&self) -> Result<Value, Error> {
+        let mut val = serde_yaml::to_value(self)
+            .map_err(|x| Error::InvalidConfig(x.to_string()))?;
+        let mut paths = self.redacted_paths.clone();
+        paths.extend(
+            ["secret", "config-url", "config-url-headers"].map(ToOwned::to_owned),
+        );
+        super::env::redact(&mut val, &paths);
+        super::env::redact_secrets(&mut val);
+
+        for section in ["proxy-providers", "rule-providers"] {
+            let Some(Value::Mapping(providers)) = val.get_mut(section) else {
+                continue;
+            };
+            for (_, provider) in providers.iter_mut() {
+                if let Some(Value::String(url)) = provider.get_mut("url") {
+                    *url = super::remote::redact_url(url);
+                }
+            }
+        }
+        Ok(val)
+    }
+
+    /// Reads the files and merges them in order, see [`super::merge`] for
+    /// how.
+    pub fn from_files(paths: &[PathBuf]) -> Result<Self, Error> {
+        Self::from_value(super::merge::load_files(paths)?.value)
+    }
+
+    fn from_value(mut val: Value) -> Result<Self, Error> {
+        let redacted_paths = super::env::expand(&mut val)?;
+
+        let mut unknown_fields = vec![];
+        let mut config: Config = serde_ignored::deserialize(val, |path| {
+            unknown_fields.push(path.to_string())
+        })
+        .map_err(|x| {
+            Error::InvalidConfig(format!("cound not parse config content: {}", x))
+        })?;
+        config.redacted_paths = redacted_paths;
+        config.unknown_fields = unknown_fields;
+        Ok(config)
+    }
 }
 
 impl TryFrom<PathBuf> for Config {
     type Error = Error;
 
     fn try_from(value: PathBuf) -> Result<Self, Self::Error> {
-        let content = std::fs::read_to_string(value)?;
-        let config = content.parse::<Config>()?;
-        Ok(config)
+        Self::from_files(&[value])
     }
 }
 
@@ -389,15 +851,52 @@ impl FromStr for Config {
             ))
         })?;
 
-        serde_yaml::from_value(val).map_err(|x| {
-            Error::InvalidConfig(format!(
-                "cound not parse config content {}: {}",
-                s, x
-            ))
-        })
+        Self::from_value(val)
     }
 }
 
+/// `up` is what is sent to the remote, `down` what is received from it.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+pub struct BandwidthLimits {
+    pub up: Option<Bandwidth>,
+    pub down: Option<Bandwidth>,
+}
+
+/// a certificate chain and its key, both PEM files
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListenerCertificate {
+    pub certificate: String,
+    pub private_key: String,
+}
+
+/// one certificate, or a few picked by SNI
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ListenerTlsDef {
+    One(ListenerCertificate),
+    Many(Vec<ListenerCertificate>),
+}
+
+impl ListenerTlsDef {
+    pub fn certificates(self) -> Vec<ListenerCertificate> {
+        match self {
+            ListenerTlsDef::One(c) => vec![c],
+            ListenerTlsDef::Many(c) => c,
+        }
+    }
+}
+
+/// CIDRs, a bare IP is one address
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ListenerAclDef {
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    #[serde(default)]
+    pub blocked_ips: Vec<String>,
+}
+
 impl Default for Config {
     fn default() -> Self {
         #[allow(deprecated)]
@@ -408,14 +907,62 @@ impl Default for Config {
             tproxy_port: Default::default(),
             mixed_port: Default::default(),
             authentication: Default::default(),
+            max_connections: Default::default(),
+            listener_max_connections: Default::default(),
+            socks_udp_source: Default::default(),
+            listener_bandwidth: Default::default(),
+            listener_tls: Default::default(),
+            listener_acl: Default::default(),
+            tunnels: Default::default(),
+            udp_timeout: 60,
+            max_udp_sessions: 16384,
+            udp_nat: Default::default(),
+            udp_fallback: "REJECT".to_owned(),
+            tcp_idle_timeout: 0,
+            tcp_idle_exempt_ports: vec![22, 6667, 6697],
+            port_hints: HashMap::from([
+                (22, "ssh".to_owned()),
+                (80, "http".to_owned()),
+                (443, "tls".to_owned()),
+            ]),
+            connect_timeout: 10,
+            socket_send_buffer_size: 0,
+            socket_recv_buffer_size: 0,
+            global_client_fingerprint: Default::default(),
             allow_lan: Default::default(),
             bind_address: String::from("*"),
             mode: Default::default(),
+            config_watch: Default::default(),
+            strict_config: Default::default(),
+            config_url: Default::default(),
+            config_url_headers: Default::default(),
+            shutdown_grace: Default::default(),
+            close_connections_on_network_change: Default::default(),
+            unified_delay: Default::default(),
             log_level: Default::default(),
+            log_directives: Default::default(),
+            log_format: Default::default(),
+            log_file: Default::default(),
+            log_max_size: Default::default(),
+            log_max_backups: Default::default(),
+            log_buffer_size: crate::app::logging::DEFAULT_LOG_BUFFER_SIZE,
+            log_syslog: Default::default(),
+            log_syslog_server: Default::default(),
+            log_connections: Default::default(),
+            clients: Default::default(),
+            tracing: Default::default(),
+            runtime: Default::default(),
             ipv6: Default::default(),
             external_controller: Default::default(),
+            external_controller_cors: Default::default(),
             external_ui: Default::default(),
+            external_ui_url: Default::default(),
             secret: Default::default(),
+            external_controller_skip_auth_loopback: Default::default(),
+            external_controller_unix: Default::default(),
+            external_controller_unix_mode: Default::default(),
+            external_controller_unix_skip_auth: Default::default(),
+            external_controller_allow_origin_tests: true,
             interface: Default::default(),
             routing_mask: Default::default(),
             proxy_provider: Default::default(),
@@ -437,10 +984,26 @@ impl Default for Config {
             geosite: "geosite.dat".to_string(),
             geosite_download_url: Some("https://github.com/Loyalsoldier/v2ray-rules-dat/releases/download/202406182210/geosite.dat".to_owned()),
             tun: Default::default(),
+            redacted_paths: Default::default(),
+            unknown_fields: Default::default(),
         }
     }
 }
 
+/// `network,address,target[,proxy]` with `network` one of `tcp`, `udp` or
+/// `tcp/udp`, or the same as a map
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum TunnelDef {
+    Short(String),
+    Full {
+        network: Vec<String>,
+        address: String,
+        target: String,
+        proxy: Option<String>,
+    },
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum DNSListen {
@@ -479,6 +1042,20 @@ pub struct DNS {
     pub ipv6: bool,
     /// Whether to `Config::hosts` as when resolving hostnames
     pub user_hosts: bool,
+    /// Whether to add the entries of the system's hosts file, e.g.
+    /// `/etc/hosts`, to `hosts`
+    pub use_system_hosts: bool,
+    /// A hosts file whose entries are added to `hosts`, e.g. the leases
+    /// written by a DHCP server, relative to the config dir. `hosts` wins on
+    /// a name in both, a name with a line of each family in the file gets
+    /// both, and the file is read again when it changes.
+    /// # Example
+    /// ```yaml
+    /// dns:
+    ///   use-system-hosts: true
+    ///   hosts-file: /var/lib/misc/dnsmasq.leases-hosts
+    /// ```
+    pub hosts_file: Option<String>,
     /// DNS servers
     pub nameserver: Vec<String>,
     /// Fallback DNS servers
@@ -496,8 +1073,30 @@ pub struct DNS {
     pub fake_ip_filter: Vec<String>,
     /// Default nameservers, used to resolve DoH hostnames
     pub default_nameserver: Vec<String>,
-    /// Lookup domains via specific nameservers
+    /// Lookup domains via specific nameservers. A key is a domain, or
+    /// `geosite:<code>` or `rule-set:<provider>` for all the domains of a
+    /// geosite category or a rule provider, and several of one kind can be
+    /// joined with commas, e.g. `geosite:cn,private`. The domains given on
+    /// their own win over those of the categories and rule sets.
+    ///
+    /// It applies whether or not `fallback` is set. Before, it was ignored
+    /// unless both `fallback` and `fallback-filter` were, so a config with
+    /// a policy and no fallback now resolves those domains with it.
+    /// # Example
+    /// ```yaml
+    /// nameserver-policy:
+    ///   'www.baidu.com': '114.114.114.114'
+    ///   'geosite:cn,private': '223.5.5.5'
+    ///   'rule-set:my-domains': 'tls://10.0.0.1'
+    /// ```
     pub nameserver_policy: HashMap<String, String>,
+    /// Strip the A/AAAA records of loopback, link-local and private addresses
+    /// from upstream answers, against DNS rebinding. A name left with none
+    /// gets an empty answer. On by default when `listen` is reachable from
+    /// the LAN. Names in `hosts` or `nameserver-policy` are left alone.
+    pub filter_unsafe_answers: Option<bool>,
+    /// Domains that may resolve to such addresses anyway, e.g. `+.lan`
+    pub rebind_allow_domains: Vec<String>,
 }
 
 impl Default for DNS {
@@ -506,6 +1105,8 @@ impl Default for DNS {
             enable: Default::default(),
             ipv6: Default::default(),
             user_hosts: true,
+            use_system_hosts: Default::default(),
+            hosts_file: Default::default(),
             nameserver: Default::default(),
             fallback: Default::default(),
             fallback_filter: Default::default(),
@@ -518,6 +1119,8 @@ impl Default for DNS {
                 String::from("8.8.8.8"),
             ],
             nameserver_policy: Default::default(),
+            filter_unsafe_answers: Default::default(),
+            rebind_allow_domains: Default::default(),
         }
     }
 }
@@ -557,6 +1160,59 @@ impl Default for FallbackFilter {
 #[derive(Serialize, Deserialize, Default)]
 pub struct Experimental {}
 
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExternalControllerCors {
+    /// origins allowed to call the API, including its websockets.
+    /// `*` allows any
+    pub allow_origins: Vec<String>,
+    /// answer Chrome's private network access preflights
+    pub allow_private_network: bool,
+}
+
+impl Default for ExternalControllerCors {
+    fn default() -> Self {
+        Self {
+            allow_origins: vec!["*".to_owned()],
+            allow_private_network: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Tracing {
+    /// OTLP/gRPC collector spans are exported to, e.g.
+    /// `http://localhost:4317`. The `OTLP_ENDPOINT` env var takes
+    /// precedence.
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Everything runs on the one runtime, the tun stack included: with
+/// `current`, or few workers, the packets from the tun are handled on the
+/// same threads as the connections they carry, so heavy tun traffic competes
+/// with the rest.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Runtime {
+    /// Threads of the `multi` runtime, one per core by default
+    pub worker_threads: Option<usize>,
+    /// `multi` for a runtime with a thread per core, or `current` to run
+    /// everything on the main thread, default `multi`
+    pub flavor: RuntimeFlavor,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuntimeFlavor {
+    #[default]
+    Multi,
+    Current,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
@@ -565,6 +1221,11 @@ pub struct Profile {
     pub store_selected: bool,
     /// persistence fakeip
     pub store_fake_ip: bool,
+    /// keep the traffic totals of each proxy and rule across restarts
+    pub store_statistics: bool,
+    /// where the cache is kept, relative to the working directory,
+    /// `cache.db` by default
+    pub cache_file_path: Option<String>,
 }
 
 impl Default for Profile {
@@ -572,6 +1233,8 @@ impl Default for Profile {
         Self {
             store_selected: true,
             store_fake_ip: false,
+            store_statistics: false,
+            cache_file_path: None,
         }
     }
 }
@@ -681,6 +1344,9 @@ profile:
   # persistence fakeip
   store-fake-ip: true
 
+  # where the cache above is kept, relative to the working directory
+  # cache-file-path: cache.db
+
 # DNS server settings
 # This section is optional. When not present, the DNS server will be disabled.
 dns:
@@ -748,6 +1414,8 @@ dns:
   # nameserver-policy:
   #   'www.baidu.com': '114.114.114.114'
   #   '+.internal.crop.com': '10.0.0.1'
+  #   'geosite:cn': '223.5.5.5'
+  #   'rule-set:my-domains': '10.0.0.1'
 
 proxies:
   # Shadowsocks
@@ -955,7 +1623,8 @@ proxies:
     # udp: true
 
 proxy-groups:
-  # relay chains the proxies. proxies shall not contain a relay. No UDP support.
+  # relay chains the proxies. proxies shall not contain a relay. UDP works when
+  # every hop carries it.
   # Traffic: clash <-> http <-> vmess <-> ss1 <-> ss2 <-> Internet
   - name: "relay"
     type: relay
@@ -964,6 +1633,9 @@ proxy-groups:
       - vmess
       - ss1
       - ss2
+    # UDP over the hops that take streams only, their servers have to speak
+    # sing-box's udp-over-tcp v2
+    # udp-over-tcp: true
 
   # url-test select which proxy will be used by benchmarking speed to a URL.
   - name: "auto"
@@ -1077,4 +1749,39 @@ rules:
             Some("websocket")
         );
     }
+
+    #[test]
+    fn test_redacted() {
+        let cfg = r#"
+authentication:
+  - "user:hunter2"
+proxies:
+  - name: ss
+    type: ss
+    server: 127.0.0.1
+    port: 8388
+    cipher: aes-128-gcm
+    password: hunter2
+  - name: wg
+    type: wireguard
+    server: 127.0.0.1
+    port: 51820
+    private-key: hunter2-key
+    ip: 10.0.0.2
+proxy-providers:
+  sub:
+    type: http
+    url: https://sub.example.com/api?token=hunter2
+    interval: 3600
+"#;
+        let c = cfg.parse::<Config>().expect("should parse");
+        let val = c.redacted().expect("should redact");
+        let dump = serde_yaml::to_string(&val).unwrap();
+        assert!(!dump.contains("hunter2"), "{}", dump);
+        assert_eq!(
+            val["proxy-providers"]["sub"]["url"].as_str(),
+            Some("https://sub.example.com/******")
+        );
+        assert_eq!(val["proxies"][0]["name"].as_str(), Some("ss"));
+    }
 }
@@ -0,0 +1,339 @@
+//! `${VAR}` substitution in config values, done on the parsed yaml before it
+//! is deserialized.
+//!
+//! - `${VAR}` is replaced with the value of `VAR`
+//! - `${VAR:-default}` uses `default` when `VAR` is unset or empty
+//! - `$$` is a literal `$`, a `$` followed by anything else is kept as is
+//!
+//! Only string values are expanded and keys are left alone. A value that is
+//! nothing but a single `${VAR}` is read back as a yaml scalar, so
+//! `port: ${PORT}` gives a number and `allow-lan: ${LAN}` a bool, the same as
+//! writing the value in place. Quote it in the variable, e.g. `PASS='"1234"'`,
+//! to keep such a value a string. Anything else stays a string.
+
+use serde_yaml::Value;
+
+use crate::Error;
+
+/// what substituted values are replaced with in config dumps
+pub const REDACTED: &str = "******";
+
+/// Expands every string value in `val`, returning the dotted key paths of the
+/// values that had a variable substituted, e.g. `proxies.0.password`, so they
+/// can be redacted later.
+pub fn expand(val: &mut Value) -> Result<Vec<String>, Error> {
+    expand_with(val, |name| std::env::var(name).ok())
+}
+
+fn expand_with(
+    val: &mut Value,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<String>, Error> {
+    let mut ctx = Context {
+        lookup: &lookup,
+        path: vec![],
+        substituted: vec![],
+        unset: vec![],
+        invalid: vec![],
+    };
+    ctx.walk(val);
+
+    let mut errors = vec![];
+    if !ctx.unset.is_empty() {
+        errors.push(format!(
+            "environment variables not set: {}",
+            ctx.unset.join(", ")
+        ));
+    }
+    errors.extend(ctx.invalid);
+    if !errors.is_empty() {
+        return Err(Error::InvalidConfig(errors.join("; ")));
+    }
+
+    Ok(ctx.substituted)
+}
+
+struct Context<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    path: Vec<String>,
+    substituted: Vec<String>,
+    unset: Vec<String>,
+    invalid: Vec<String>,
+}
+
+impl Context<'_> {
+    fn walk(&mut self, val: &mut Value) {
+        match val {
+            Value::String(s) => {
+                if let Some((expanded, substituted)) = self.expand_str(s) {
+                    if substituted {
+                        self.substituted.push(self.path.join("."));
+                    }
+                    *val = if substituted && is_single_reference(s) {
+                        as_scalar(expanded)
+                    } else {
+                        Value::String(expanded)
+                    };
+                }
+            }
+            Value::Sequence(seq) => {
+                for (i, v) in seq.iter_mut().enumerate() {
+                    self.path.push(i.to_string());
+                    self.walk(v);
+                    self.path.pop();
+                }
+            }
+            Value::Mapping(map) => {
+                for (k, v) in map.iter_mut() {
+                    self.path.push(key_name(k));
+                    self.walk(v);
+                    self.path.pop();
+                }
+            }
+            Value::Tagged(t) => self.walk(&mut t.value),
+            _ => {}
+        }
+    }
+
+    /// the expanded string and whether a variable was substituted, `None`
+    /// if there is nothing to expand or the string is invalid
+    fn expand_str(&mut self, s: &str) -> Option<(String, bool)> {
+        if !s.contains('$') {
+            return None;
+        }
+
+        let mut out = String::with_capacity(s.len());
+        let mut substituted = false;
+        let mut rest = s;
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            if let Some(r) = rest.strip_prefix("$$") {
+                out.push('$');
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("${") {
+                let Some(end) = r.find('}') else {
+                    self.invalid.push(format!(
+                        "unterminated ${{ in {}",
+                        self.path.join(".")
+                    ));
+                    return None;
+                };
+                let (name, default) = match r[..end].split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (&r[..end], None),
+                };
+                if !is_valid_name(name) {
+                    self.invalid.push(format!(
+                        "invalid variable name `{}` in {}",
+                        name,
+                        self.path.join(".")
+                    ));
+                    return None;
+                }
+
+                match ((self.lookup)(name), default) {
+                    (Some(v), Some(default)) if v.is_empty() => {
+                        out.push_str(default)
+                    }
+                    (Some(v), _) => out.push_str(&v),
+                    (None, Some(default)) => out.push_str(default),
+                    (None, None) => {
+                        if !self.unset.iter().any(|x| x == name) {
+                            self.unset.push(name.to_owned());
+                        }
+                    }
+                }
+                substituted = true;
+                rest = &r[end + 1..];
+            } else {
+                out.push('$');
+                rest = &rest[1..];
+            }
+        }
+        out.push_str(rest);
+
+        Some((out, substituted))
+    }
+}
+
+fn key_name(k: &Value) -> String {
+    match k {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        _ => "?".to_owned(),
+    }
+}
+
+/// whether `s` is exactly one `${...}` with nothing around it
+fn is_single_reference(s: &str) -> bool {
+    s.starts_with("${") && s.find('}') == Some(s.len() - 1)
+}
+
+/// `s` read as a yaml scalar, only numbers, bools and quoted strings are
+/// taken, so an empty value doesn't turn into null and a `#` or `[` in it is
+/// kept as is
+fn as_scalar(s: String) -> Value {
+    let quoted = s.starts_with('"') || s.starts_with('\'');
+    match serde_yaml::from_str::<Value>(&s) {
+        Ok(v @ (Value::Number(_) | Value::Bool(_))) => v,
+        Ok(v @ Value::String(_)) if quoted => v,
+        _ => Value::String(s),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replaces the values at the given dotted paths with [`REDACTED`], paths
+/// that no longer exist or are null are ignored.
+pub fn redact(val: &mut Value, paths: &[String]) {
+    for path in paths {
+        let found = path.split('.').try_fold(&mut *val, |cur, seg| match cur {
+            Value::Mapping(map) => map.get_mut(seg),
+            Value::Sequence(seq) => {
+                seg.parse::<usize>().ok().and_then(|i| seq.get_mut(i))
+            }
+            _ => None,
+        });
+        if let Some(v) = found.filter(|v| !v.is_null()) {
+            *v = Value::String(REDACTED.to_owned());
+        }
+    }
+}
+
+/// keys that hold credentials wherever they are, in proxies, listeners or
+/// at the top level
+const SECRET_KEYS: &[&str] = &[
+    "authentication",
+    "password",
+    "obfs-password",
+    "uuid",
+    "private-key",
+    "private-key-passphrase",
+    "preshared-key",
+    "auth-str",
+    "token",
+];
+
+/// Replaces the value of every key in [`SECRET_KEYS`], at any depth, with
+/// [`REDACTED`].
+pub fn redact_secrets(val: &mut Value) {
+    match val {
+        Value::Sequence(seq) => seq.iter_mut().for_each(redact_secrets),
+        Value::Mapping(map) => {
+            for (k, v) in map.iter_mut() {
+                if k.as_str().is_some_and(|k| SECRET_KEYS.contains(&k)) {
+                    if !v.is_null() {
+                        *v = Value::String(REDACTED.to_owned());
+                    }
+                } else {
+                    redact_secrets(v);
+                }
+            }
+        }
+        Value::Tagged(t) => redact_secrets(&mut t.value),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::{expand_with, redact, REDACTED};
+    use crate::config::def::Config;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "SS_PASSWORD" => Some("hunter2".to_owned()),
+            "EMPTY" => Some("".to_owned()),
+            "PORT" => Some("7890".to_owned()),
+            "LAN" => Some("true".to_owned()),
+            "PIN" => Some("'1234'".to_owned()),
+            "LIST" => Some("[a, b] # c".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut val: Value = serde_yaml::from_str(
+            r#"
+external-controller: ${API_LISTEN:-127.0.0.1:9090}
+secret: ${EMPTY:-fallback}
+price: $$5 and $HOME
+proxies:
+  - name: ss
+    password: "${SS_PASSWORD}"
+"#,
+        )
+        .unwrap();
+
+        let substituted = expand_with(&mut val, lookup).unwrap();
+        assert_eq!(val["external-controller"].as_str(), Some("127.0.0.1:9090"));
+        assert_eq!(val["secret"].as_str(), Some("fallback"));
+        assert_eq!(val["price"].as_str(), Some("$5 and $HOME"));
+        assert_eq!(val["proxies"][0]["password"].as_str(), Some("hunter2"));
+        assert_eq!(
+            substituted,
+            vec!["external-controller", "secret", "proxies.0.password"]
+        );
+
+        redact(&mut val, &substituted);
+        assert_eq!(val["proxies"][0]["password"].as_str(), Some(REDACTED));
+        assert_eq!(val["price"].as_str(), Some("$5 and $HOME"));
+    }
+
+    #[test]
+    fn test_expand_lists_all_unset() {
+        let mut val: Value = serde_yaml::from_str(
+            "a: ${FOO}\nb: [\"${BAR}\", \"${FOO}\"]\nc: ${SS_PASSWORD}",
+        )
+        .unwrap();
+
+        let err = expand_with(&mut val, lookup).unwrap_err().to_string();
+        assert!(err.contains("FOO, BAR"), "{}", err);
+    }
+
+    #[test]
+    fn test_expand_whole_value_as_scalar() {
+        let mut val: Value = serde_yaml::from_str(
+            r#"
+port: ${PORT}
+allow-lan: ${LAN}
+pin: ${PIN}
+list: ${LIST}
+empty: ${EMPTY}
+suffixed: ${PORT}0
+prefixed: "x${PORT}"
+"#,
+        )
+        .unwrap();
+
+        expand_with(&mut val, lookup).unwrap();
+        assert_eq!(val["port"].as_u64(), Some(7890));
+        assert_eq!(val["allow-lan"].as_bool(), Some(true));
+        assert_eq!(val["pin"].as_str(), Some("1234"));
+        assert_eq!(val["list"].as_str(), Some("[a, b] # c"));
+        assert_eq!(val["empty"].as_str(), Some(""));
+        assert_eq!(val["suffixed"].as_str(), Some("78900"));
+        assert_eq!(val["prefixed"].as_str(), Some("x7890"));
+    }
+
+    #[test]
+    fn test_expand_numeric_field() {
+        let mut val: Value = serde_yaml::from_str("mixed-port: ${PORT}").unwrap();
+        expand_with(&mut val, lookup).unwrap();
+
+        let config: Config = serde_yaml::from_value(val).unwrap();
+        assert_eq!(config.mixed_port, Some(7890));
+    }
+}
@@ -0,0 +1,724 @@
+//! Checks run on a parsed config before anything is started with it. They are
+//! shared by the runtime loader and `clash-rs -t`, so a config that passes
+//! the test is one that loads.
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    app::{
+        dns::PolicyKey, logging::MAX_LOG_BUFFER_SIZE,
+        outbound::utils::proxy_groups_dag_sort,
+    },
+    config::internal::{
+        config::{BindAddress, Config, RuleProviderDef, LISTENER_NAMES},
+        proxy::{HealthCheckType, OutboundProxy, OutboundProxyProviderDef},
+        rule::RuleType,
+    },
+    proxy::utils::Interface,
+    Error,
+};
+
+/// the problems found in a config, errors stop it from being loaded
+#[derive(Default, Debug)]
+pub struct Diagnostics {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Diagnostics {
+    fn error(&mut self, msg: impl Display) {
+        self.errors.push(msg.to_string());
+    }
+
+    fn warn(&mut self, msg: impl Display) {
+        self.warnings.push(msg.to_string());
+    }
+
+    pub fn extend(&mut self, other: Diagnostics) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+    }
+
+    /// logs the warnings and fails with all the errors at once
+    pub fn into_result(self) -> Result<(), Error> {
+        for w in &self.warnings {
+            warn!("config: {}", w);
+        }
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfig(self.errors.join("; ")))
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Config {
+    /// Checks that only need the config itself. Syntax errors such as a bad
+    /// CIDR or port already fail the parsing, this is about how the parts
+    /// refer to each other.
+    pub fn check(&self) -> Diagnostics {
+        let mut d = Diagnostics::default();
+
+        for name in self.general.inbound.listener_max_connections.keys() {
+            if !LISTENER_NAMES.contains(&name.as_str()) {
+                d.error(format!(
+                    "unknown listener `{}` in listener-max-connections, expected \
+                     one of {:?}",
+                    name, LISTENER_NAMES
+                ));
+            }
+        }
+        for name in self.general.inbound.listener_bandwidth.keys() {
+            if !LISTENER_NAMES.contains(&name.as_str()) {
+                d.error(format!(
+                    "unknown listener `{}` in listener-bandwidth, expected one of \
+                     {:?}",
+                    name, LISTENER_NAMES
+                ));
+            }
+        }
+        for name in self.general.inbound.listener_acl.keys() {
+            if !LISTENER_NAMES.contains(&name.as_str()) {
+                d.error(format!(
+                    "unknown listener `{}` in listener-acl, expected one of {:?}",
+                    name, LISTENER_NAMES
+                ));
+            }
+        }
+
+        for (name, certs) in &self.general.inbound.listener_tls {
+            if !["http", "socks", "mixed"].contains(&name.as_str()) {
+                d.error(format!(
+                    "unknown listener `{}` in listener-tls, expected one of \
+                     [\"http\", \"socks\", \"mixed\"]",
+                    name
+                ));
+            } else if certs.is_empty() {
+                d.error(format!("listener-tls of {} has no certificate", name));
+            }
+        }
+
+        if self.general.udp_sessions.idle_timeout.is_zero() {
+            d.error("udp-timeout must be at least 1 second");
+        }
+        if self.general.log_buffer_size > MAX_LOG_BUFFER_SIZE {
+            d.warn(format!(
+                "log-buffer-size {} is more than {}, {} is used",
+                self.general.log_buffer_size,
+                MAX_LOG_BUFFER_SIZE,
+                MAX_LOG_BUFFER_SIZE
+            ));
+        }
+
+        let fingerprinted = self.general.global_client_fingerprint.is_some()
+            || self.proxies.values().any(|p| {
+                matches!(p, OutboundProxy::ProxyServer(p)
+                    if p.client_fingerprint().is_some())
+            });
+        if fingerprinted {
+            d.warn(
+                "client-fingerprint only orders the cipher suites and key exchange \
+                 groups like the browser, the rest of the TLS ClientHello is still \
+                 rustls' own and doesn't pass for one",
+            );
+        }
+
+        #[cfg(feature = "wireguard")]
+        for p in self.proxies.values() {
+            use crate::config::internal::proxy::OutboundProxyProtocol;
+            if let OutboundProxy::ProxyServer(OutboundProxyProtocol::Wireguard(wg)) =
+                p
+            {
+                if wg.common_opts.dial_timeout.is_some() {
+                    d.error(format!(
+                        "proxy {}: dial-timeout isn't supported by wireguard, \
+                         there is no connection to the server to time",
+                        wg.common_opts.name
+                    ));
+                }
+            }
+        }
+
+        let fallback = &self.general.udp_fallback;
+        if !self.proxies.contains_key(fallback)
+            && !self.proxy_groups.contains_key(fallback)
+        {
+            d.error(format!("udp-fallback proxy `{}` was not found", fallback));
+        }
+
+        self.check_rules(&mut d);
+        self.check_groups(&mut d);
+        self.check_providers(&mut d);
+        self.check_tunnels(&mut d);
+        self.check_ports(&mut d);
+        self.check_unknown_fields(&mut d);
+
+        d
+    }
+
+    /// One warning for all the ignored fields, they are often left over from a
+    /// config written for another clash and would drown the rest.
+    fn check_unknown_fields(&self, d: &mut Diagnostics) {
+        if self.unknown_fields.is_empty() {
+            return;
+        }
+        let mut sections: Vec<&str> = vec![];
+        for path in &self.unknown_fields {
+            debug!("unknown config field ignored: {}", path);
+            let section = path.split('.').next().unwrap_or(path);
+            if !sections.contains(&section) {
+                sections.push(section);
+            }
+        }
+        d.warn(format!(
+            "{} unknown field{} ignored in {} (set strict-config: true for details)",
+            self.unknown_fields.len(),
+            if self.unknown_fields.len() == 1 {
+                ""
+            } else {
+                "s"
+            },
+            sections.join(", ")
+        ));
+    }
+
+    /// Checks the files the config points to can be read, relative paths
+    /// are relative to `cwd`. Nothing is fetched, remote providers are only
+    /// checked in [`Config::check`].
+    pub fn check_files(&self, cwd: &Path) -> Diagnostics {
+        let mut d = Diagnostics::default();
+
+        let readable = |what: String, path: &Path| {
+            std::fs::File::open(cwd.join(path))
+                .map(|_| ())
+                .map_err(|e| format!("{} {}: {}", what, path.display(), e))
+        };
+
+        if self.dns.enable {
+            let listen = &self.dns.listen;
+            if let Some(dot) = &listen.dot {
+                if let Err(e) =
+                    readable("dns dot ca-cert".to_owned(), dot.ca_cert.as_ref())
+                {
+                    d.error(e);
+                }
+                if let Err(e) =
+                    readable("dns dot ca-key".to_owned(), dot.ca_key.as_ref())
+                {
+                    d.error(e);
+                }
+            }
+            if let Some(doh) = &listen.doh {
+                if let Err(e) =
+                    readable("dns doh ca-cert".to_owned(), doh.ca_cert.as_ref())
+                {
+                    d.error(e);
+                }
+                if let Err(e) =
+                    readable("dns doh ca-key".to_owned(), doh.ca_key.as_ref())
+                {
+                    d.error(e);
+                }
+            }
+            if let Some(doh3) = &listen.doh3 {
+                if let Err(e) =
+                    readable("dns doh3 ca-cert".to_owned(), doh3.ca_cert.as_ref())
+                {
+                    d.error(e);
+                }
+                if let Err(e) =
+                    readable("dns doh3 ca-key".to_owned(), doh3.ca_key.as_ref())
+                {
+                    d.error(e);
+                }
+            }
+        }
+
+        for (name, certs) in &self.general.inbound.listener_tls {
+            for c in certs {
+                if let Err(e) = readable(
+                    format!("listener-tls {} certificate", name),
+                    c.certificate.as_ref(),
+                ) {
+                    d.error(e);
+                }
+                if let Err(e) = readable(
+                    format!("listener-tls {} private-key", name),
+                    c.private_key.as_ref(),
+                ) {
+                    d.error(e);
+                }
+            }
+        }
+
+        // a provider that fails to load is left empty, it doesn't stop the
+        // rest from starting
+        for (name, p) in &self.proxy_providers {
+            if let OutboundProxyProviderDef::File(p) = p {
+                if let Err(e) =
+                    readable(format!("proxy provider {}", name), p.path.as_ref())
+                {
+                    d.warn(e);
+                }
+            }
+        }
+        for (name, p) in &self.rule_providers {
+            if let RuleProviderDef::File(p) = p {
+                if let Err(e) =
+                    readable(format!("rule provider {}", name), p.path.as_ref())
+                {
+                    d.warn(e);
+                }
+            }
+        }
+
+        d
+    }
+
+    fn check_rules(&self, d: &mut Diagnostics) {
+        for r in self.rules.iter() {
+            if !self.proxies.contains_key(r.target())
+                && !self.proxy_groups.contains_key(r.target())
+            {
+                d.error(format!(
+                    "proxy `{}` referenced in a rule was not found",
+                    r.target()
+                ));
+            }
+            if let RuleType::RuleSet { rule_set, .. } = r {
+                if !self.rule_providers.contains_key(rule_set) {
+                    d.error(format!(
+                        "rule provider `{}` referenced in a rule was not found",
+                        rule_set
+                    ));
+                }
+            }
+        }
+
+        for key in self.dns.nameserver_policy.keys() {
+            if let PolicyKey::RuleSet(name) = PolicyKey::parse(key) {
+                if !self.rule_providers.contains_key(name) {
+                    d.error(format!(
+                        "rule provider `{}` referenced in nameserver-policy was \
+                         not found",
+                        name
+                    ));
+                }
+            }
+        }
+
+        if self
+            .rules
+            .last()
+            .is_some_and(|r| !matches!(r, RuleType::Match { .. }))
+        {
+            d.warn("the last rule is not MATCH");
+        }
+    }
+
+    fn check_tunnels(&self, d: &mut Diagnostics) {
+        for t in &self.general.inbound.tunnels {
+            let Some(proxy) = &t.proxy else {
+                continue;
+            };
+            if !self.proxies.contains_key(proxy)
+                && !self.proxy_groups.contains_key(proxy)
+            {
+                d.error(format!(
+                    "proxy `{}` of the tunnel on {} was not found",
+                    proxy, t.address
+                ));
+            }
+        }
+    }
+
+    fn check_groups(&self, d: &mut Diagnostics) {
+        let mut groups = vec![];
+        let mut missing = false;
+        for (name, g) in &self.proxy_groups {
+            let OutboundProxy::ProxyGroup(g) = g else {
+                continue;
+            };
+            for member in g.proxies().into_iter().flatten() {
+                if !self.proxies.contains_key(member)
+                    && !self.proxy_groups.contains_key(member)
+                {
+                    missing = true;
+                    d.error(format!(
+                        "proxy `{}` in group {} was not found",
+                        member, name
+                    ));
+                }
+            }
+            for provider in g.use_provider().into_iter().flatten() {
+                if !self.proxy_providers.contains_key(provider) {
+                    d.error(format!(
+                        "proxy provider `{}` in group {} was not found",
+                        provider, name
+                    ));
+                }
+            }
+            if g.proxies().is_none_or(|x| x.is_empty())
+                && g.use_provider().is_none_or(|x| x.is_empty())
+            {
+                d.warn(format!("group {} has no proxies or providers", name));
+            }
+            groups.push(g.clone());
+        }
+
+        // the sort can't deal with missing members, they are reported above
+        if !missing {
+            match proxy_groups_dag_sort(&mut groups) {
+                Ok(()) => {}
+                Err(Error::InvalidConfig(e)) => d.error(e),
+                Err(e) => d.error(e),
+            }
+        }
+    }
+
+    fn check_providers(&self, d: &mut Diagnostics) {
+        let mut check_url = |what: String, u: &str| match url::Url::parse(u) {
+            Ok(u) if matches!(u.scheme(), "http" | "https") => {}
+            Ok(u) => d.error(format!(
+                "{} url {} has unsupported scheme {}",
+                what,
+                u,
+                u.scheme()
+            )),
+            Err(e) => d.error(format!("{} url {}: {}", what, u, e)),
+        };
+
+        for (name, p) in &self.proxy_providers {
+            let hc = match p {
+                OutboundProxyProviderDef::Http(p) => {
+                    check_url(format!("proxy provider {}", name), &p.url);
+                    &p.health_check
+                }
+                OutboundProxyProviderDef::File(p) => &p.health_check,
+            };
+            // an icmp check only falls back to the url, the default one if
+            // not set
+            if hc.enable && (hc.typ == HealthCheckType::Http || !hc.url.is_empty()) {
+                check_url(format!("proxy provider {} health check", name), &hc.url);
+            }
+        }
+        for (name, p) in &self.rule_providers {
+            if let RuleProviderDef::Http(p) = p {
+                check_url(format!("rule provider {}", name), &p.url);
+            }
+        }
+    }
+
+    /// listeners sharing a port on overlapping addresses
+    fn check_ports(&self, d: &mut Diagnostics) {
+        let inbound = &self.general.inbound;
+        let inbound_ip = match &inbound.bind_address {
+            BindAddress::One(Interface::IpAddr(ip)) => *ip,
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+
+        let mut listeners: Vec<(&str, Transport, SocketAddr)> = vec![];
+        let mut add = |name, transports: &[Transport], addr: Option<SocketAddr>| {
+            if let Some(addr) = addr.filter(|x| x.port() != 0) {
+                for t in transports {
+                    listeners.push((name, *t, addr));
+                }
+            }
+        };
+        let on = |port: Option<u16>| port.map(|p| SocketAddr::new(inbound_ip, p));
+
+        use Transport::*;
+        add("port", &[Tcp], on(inbound.port));
+        add("socks-port", &[Tcp, Udp], on(inbound.socks_port));
+        add("redir-port", &[Tcp], on(inbound.redir_port));
+        add("tproxy-port", &[Tcp, Udp], on(inbound.tproxy_port));
+        add("mixed-port", &[Tcp, Udp], on(inbound.mixed_port));
+        for t in &inbound.tunnels {
+            let transports = match (t.tcp, t.udp) {
+                (true, true) => &[Tcp, Udp][..],
+                (true, false) => &[Tcp],
+                _ => &[Udp],
+            };
+            add("tunnel", transports, Some(t.address));
+        }
+
+        if self.dns.enable {
+            let listen = &self.dns.listen;
+            add("dns udp", &[Udp], listen.udp);
+            add("dns tcp", &[Tcp], listen.tcp);
+            add("dns dot", &[Tcp], listen.dot.as_ref().map(|x| x.addr));
+            add("dns doh", &[Tcp], listen.doh.as_ref().map(|x| x.addr));
+            add("dns doh3", &[Udp], listen.doh3.as_ref().map(|x| x.addr));
+        }
+
+        if let Some(addr) = &self.general.controller.external_controller {
+            add("external-controller", &[Tcp], controller_addr(addr));
+        }
+
+        let mut reported = HashSet::new();
+        for (i, (a, ta, aa)) in listeners.iter().enumerate() {
+            for (b, tb, ab) in &listeners[i + 1..] {
+                let overlap = aa.ip() == ab.ip()
+                    || aa.ip().is_unspecified()
+                    || ab.ip().is_unspecified();
+                if ta == tb
+                    && aa.port() == ab.port()
+                    && overlap
+                    && reported.insert((*a, *b))
+                {
+                    d.error(format!(
+                        "{} and {} both listen on port {}",
+                        a,
+                        b,
+                        aa.port()
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// the controller address is given as `host:port`, `:port` or `localhost`
+/// are accepted as well
+fn controller_addr(addr: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = addr.parse() {
+        return Some(addr);
+    }
+    let (host, port) = addr.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let ip = match host {
+        "" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        "localhost" => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        // a hostname could be anything, assume the worst
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app::logging::MAX_LOG_BUFFER_SIZE, config::internal::config::Config, def,
+    };
+
+    fn check(cfg: &str) -> super::Diagnostics {
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        // the checks run as part of the conversion too, so it would fail on
+        // the errors tested for here
+        let c: Result<Config, _> = c.try_into();
+        match c {
+            Ok(c) => c.check(),
+            Err(e) => super::Diagnostics {
+                errors: vec![e.to_string()],
+                warnings: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_check_ok() {
+        let d = check(
+            r#"
+port: 7890
+socks-port: 7891
+external-controller: 127.0.0.1:9090
+proxy-groups:
+  - name: select
+    type: select
+    proxies: [DIRECT]
+rules:
+  - MATCH,select
+"#,
+        );
+        assert!(d.errors.is_empty(), "{:?}", d.errors);
+        assert!(d.warnings.is_empty(), "{:?}", d.warnings);
+    }
+
+    #[test]
+    fn test_check_errors() {
+        let d = check(
+            r#"
+port: 7890
+mixed-port: 7890
+external-controller: :7891
+socks-port: 7891
+udp-fallback: gone
+proxy-groups:
+  - name: a
+    type: select
+    proxies: [b, missing]
+  - name: b
+    type: select
+    proxies: [a]
+rules:
+  - DOMAIN,example.com,nowhere
+"#,
+        );
+        let errors = d.errors.join("\n");
+        // all of them are reported, not only the first one
+        assert!(errors.contains("port and mixed-port"), "{}", errors);
+        assert!(
+            errors.contains("socks-port and external-controller"),
+            "{}",
+            errors
+        );
+        assert!(errors.contains("`missing` in group a"), "{}", errors);
+        assert!(errors.contains("udp-fallback proxy `gone`"), "{}", errors);
+        assert!(
+            errors.contains("`nowhere` referenced in a rule"),
+            "{}",
+            errors
+        );
+    }
+
+    #[test]
+    fn test_check_tunnels() {
+        let d = check(
+            r#"
+mixed-port: 7890
+tunnels:
+  - tcp/udp,127.0.0.1:7890,8.8.8.8:53,nowhere
+  - udp,127.0.0.1:6553,8.8.8.8:53
+"#,
+        );
+        let errors = d.errors.join("\n");
+        assert!(errors.contains("`nowhere` of the tunnel"), "{}", errors);
+        assert!(errors.contains("mixed-port and tunnel"), "{}", errors);
+        assert_eq!(d.errors.len(), 2, "{}", errors);
+    }
+
+    #[test]
+    fn test_check_group_cycle() {
+        let d = check(
+            r#"
+proxy-groups:
+  - name: a
+    type: select
+    proxies: [b]
+  - name: b
+    type: select
+    proxies: [a]
+rules:
+  - MATCH,a
+"#,
+        );
+        assert!(
+            d.errors.join("\n").contains("loop detected"),
+            "{:?}",
+            d.errors
+        );
+    }
+
+    #[test]
+    fn test_check_unknown_fields() {
+        let cfg = r#"
+geodata-mode: true
+dns:
+  enable: true
+  respect-rules: true
+proxy-groups:
+  - name: select
+    type: select
+    proxies: [DIRECT]
+    hidden: true
+rules:
+  - MATCH,select
+"#;
+        let d = check(cfg);
+        assert!(d.errors.is_empty(), "{:?}", d.errors);
+        assert_eq!(
+            d.warnings,
+            vec![
+                "3 unknown fields ignored in geodata-mode, dns, proxy-groups (set \
+                 strict-config: true for details)"
+            ]
+        );
+
+        let d = check(&format!("strict-config: true\n{}", cfg));
+        let errors = d.errors.join("\n");
+        assert!(errors.contains("dns.respect-rules"), "{}", errors);
+        assert!(errors.contains("proxy-groups.select.hidden"), "{}", errors);
+    }
+
+    #[test]
+    #[cfg(feature = "wireguard")]
+    fn test_wireguard_dial_timeout() {
+        let d = check(
+            r#"
+proxies:
+  - name: wg
+    type: wireguard
+    server: 127.0.0.1
+    port: 51820
+    ip: 10.0.0.2
+    private-key: KIlDUePHyYwzjgn18przw/ZwPioJhh2aEyhxb/dtCXI=
+    public-key: INBZyvB715sA5zatkiX8Jn3Dh5tZZboZ09x4pkr66ig=
+    dial-timeout: 5
+"#,
+        );
+        assert!(
+            d.errors.join("\n").contains("proxy wg: dial-timeout"),
+            "{:?}",
+            d.errors
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "trojan")]
+    fn test_client_fingerprint_warned() {
+        let d = check(
+            r#"
+proxies:
+  - name: tj
+    type: trojan
+    server: 127.0.0.1
+    port: 443
+    password: pass
+    client-fingerprint: chrome
+"#,
+        );
+        assert!(
+            d.warnings
+                .iter()
+                .any(|w| w.contains("client-fingerprint only orders")),
+            "{:?}",
+            d.warnings
+        );
+
+        let d = check("global-client-fingerprint: firefox");
+        assert_eq!(d.warnings.len(), 1, "{:?}", d.warnings);
+    }
+
+    #[test]
+    fn test_log_buffer_size_clamped() {
+        let cfg = "log-buffer-size: 100000000";
+
+        let c = Config::from_def(cfg.parse::<def::Config>().unwrap()).unwrap();
+        let d = c.check();
+        assert!(d.errors.is_empty(), "{:?}", d.errors);
+        assert!(
+            d.warnings.contains(
+                &"log-buffer-size 100000000 is more than 100000, 100000 is used"
+                    .to_owned()
+            ),
+            "{:?}",
+            d.warnings
+        );
+
+        let c: Config = cfg.parse::<def::Config>().unwrap().try_into().unwrap();
+        assert_eq!(c.general.log_buffer_size, MAX_LOG_BUFFER_SIZE);
+    }
+}
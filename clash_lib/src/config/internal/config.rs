@@ -1,6 +1,11 @@
 use std::collections::HashMap;
 
-use std::{fmt::Display, net::IpAddr, str::FromStr};
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
 
 use ipnet::IpNet;
 use serde::{de::value::MapDeserializer, Deserialize, Serialize};
@@ -10,13 +15,15 @@ use crate::{
     app::{dns, remote_content_manager::providers::rule_provider::RuleSetBehavior},
     common::auth,
     config::{
-        def::{self, LogLevel, RunMode},
+        def::{self, ClientFingerprint, LogFormat, LogLevel, RunMode, UdpNat},
         internal::{
             proxy::{OutboundProxy, PROXY_DIRECT, PROXY_REJECT},
             rule::RuleType,
         },
+        utils::check_dial_timeout,
     },
     proxy::utils::Interface,
+    session::SocksAddr,
     Error,
 };
 
@@ -38,20 +45,21 @@ pub struct Config {
     pub proxies: HashMap<String, OutboundProxy>,
     pub proxy_groups: HashMap<String, OutboundProxy>,
     pub proxy_providers: HashMap<String, OutboundProxyProviderDef>,
+    /// the config this was built from with the files merged, redacted, for
+    /// `GET /configs?effective=true`
+    pub effective: Value,
+    /// key paths of the fields that were ignored, only set without
+    /// `strict-config`
+    pub unknown_fields: Vec<String>,
 }
 
 impl Config {
-    fn validate(self) -> Result<Self, crate::Error> {
-        for r in self.rules.iter() {
-            if !self.proxies.contains_key(r.target())
-                && !self.proxy_groups.contains_key(r.target())
-            {
-                return Err(Error::InvalidConfig(format!(
-                    "proxy `{}` referenced in a rule was not found",
-                    r.target()
-                )));
-            }
-        }
+    fn validate(mut self) -> Result<Self, crate::Error> {
+        self.check().into_result()?;
+        self.general.log_buffer_size = self
+            .general
+            .log_buffer_size
+            .min(crate::app::logging::MAX_LOG_BUFFER_SIZE);
         Ok(self)
     }
 }
@@ -60,10 +68,28 @@ impl TryFrom<def::Config> for Config {
     type Error = crate::Error;
 
     fn try_from(c: def::Config) -> Result<Self, Self::Error> {
+        Self::from_def(c)?.validate()
+    }
+}
+
+impl Config {
+    /// the conversion without [`Config::check`], so a config test can report
+    /// every problem instead of the first one
+    pub(crate) fn from_def(c: def::Config) -> Result<Self, Error> {
+        let effective = c.redacted()?;
+        let unknown_fields = super::unknown::collect(&c);
+        if c.strict_config && !unknown_fields.is_empty() {
+            return Err(Error::InvalidConfig(format!(
+                "unknown fields with strict-config: {}",
+                unknown_fields.join(", ")
+            )));
+        }
+        // only used to build the runtime, checked here so `-t` catches it
+        crate::TokioRuntime::from_def(&c.runtime)?;
         let mut proxy_names =
             vec![String::from(PROXY_DIRECT), String::from(PROXY_REJECT)];
         #[allow(deprecated)]
-        Self {
+        Ok(Self {
             general: General {
                 inbound: Inbound {
                     port: c.port,
@@ -73,14 +99,157 @@ impl TryFrom<def::Config> for Config {
                     mixed_port: c.mixed_port,
                     authentication: c.authentication.clone(),
                     bind_address: c.bind_address.parse()?,
+                    max_connections: c.max_connections,
+                    listener_max_connections: c.listener_max_connections,
+                    listener_bandwidth: c.listener_bandwidth,
+                    listener_tls: c
+                        .listener_tls
+                        .into_iter()
+                        .map(|(k, v)| (k, v.certificates()))
+                        .collect(),
+                    listener_acl: c
+                        .listener_acl
+                        .into_iter()
+                        .map(|(k, v)| ListenerAcl::try_from(v).map(|v| (k, v)))
+                        .collect::<Result<_, _>>()?,
+                    socks_udp_source: c.socks_udp_source,
+                    tunnels: c
+                        .tunnels
+                        .into_iter()
+                        .map(Tunnel::try_from)
+                        .collect::<Result<_, _>>()?,
                 },
                 controller: Controller {
                     external_controller: c.external_controller.clone(),
                     external_ui: c.external_ui.clone(),
+                    external_ui_url: c.external_ui_url.clone(),
+                    cors_allow_origins: c
+                        .external_controller_cors
+                        .allow_origins
+                        .clone(),
+                    cors_allow_private_network: c
+                        .external_controller_cors
+                        .allow_private_network,
                     secret: c.secret.clone(),
+                    skip_auth_loopback: c.external_controller_skip_auth_loopback,
+                    external_controller_unix: c.external_controller_unix.clone(),
+                    unix_socket_mode: c
+                        .external_controller_unix_mode
+                        .as_deref()
+                        .map(|x| {
+                            u32::from_str_radix(x, 8).map_err(|_| {
+                                Error::InvalidConfig(format!(
+                                    "invalid external-controller-unix-mode: {}",
+                                    x
+                                ))
+                            })
+                        })
+                        .transpose()?
+                        .unwrap_or(0o600),
+                    skip_auth_unix: c.external_controller_unix_skip_auth,
+                    allow_origin_tests: c.external_controller_allow_origin_tests,
                 },
                 mode: c.mode,
                 log_level: c.log_level,
+                config_watch: c.config_watch,
+                log_directives: c
+                    .log_directives
+                    .iter()
+                    .map(|x| {
+                        x.parse::<tracing_subscriber::filter::Directive>()
+                            .map(|_| x.clone())
+                            .map_err(|e| {
+                                Error::InvalidConfig(format!(
+                                    "invalid log directive {}: {}",
+                                    x, e
+                                ))
+                            })
+                    })
+                    .collect::<Result<_, _>>()?,
+                log_format: c.log_format,
+                otlp_endpoint: c.tracing.otlp_endpoint.clone(),
+                log_connections: c.log_connections,
+                clients: c
+                    .clients
+                    .iter()
+                    .map(|(ip, name)| {
+                        ip.parse::<IpAddr>().map(|ip| (ip, name.clone())).map_err(
+                            |_| {
+                                Error::InvalidConfig(format!(
+                                    "invalid client address: {}",
+                                    ip
+                                ))
+                            },
+                        )
+                    })
+                    .collect::<Result<_, _>>()?,
+                log_buffer_size: c.log_buffer_size,
+                udp_sessions: UdpSessions {
+                    idle_timeout: Duration::from_secs(c.udp_timeout),
+                    max: c.max_udp_sessions,
+                    nat: c.udp_nat,
+                },
+                udp_fallback: c.udp_fallback.clone(),
+                shutdown_grace: c
+                    .shutdown_grace
+                    .as_deref()
+                    .map(parse_duration)
+                    .transpose()?
+                    .unwrap_or(DEFAULT_SHUTDOWN_GRACE),
+                close_connections_on_network_change: c
+                    .close_connections_on_network_change,
+                unified_delay: c.unified_delay,
+                global_client_fingerprint: c.global_client_fingerprint,
+                connect_timeout: check_dial_timeout(c.connect_timeout).map_err(
+                    |e| Error::InvalidConfig(format!("connect-timeout: {}", e)),
+                )?,
+                socket_send_buffer_size: c.socket_send_buffer_size,
+                socket_recv_buffer_size: c.socket_recv_buffer_size,
+                port_hints: c
+                    .port_hints
+                    .iter()
+                    .map(|(port, name)| match name.trim() {
+                        "" => Err(Error::InvalidConfig(format!(
+                            "port-hints: no protocol for port {}",
+                            port
+                        ))),
+                        name => Ok((*port, name.to_lowercase())),
+                    })
+                    .collect::<Result<_, _>>()?,
+                tcp_idle: TcpIdle {
+                    timeout: (c.tcp_idle_timeout > 0)
+                        .then(|| Duration::from_secs(c.tcp_idle_timeout)),
+                    exempt_ports: c.tcp_idle_exempt_ports.clone(),
+                },
+                log_syslog: if c.log_syslog {
+                    Some(
+                        c.log_syslog_server
+                            .as_deref()
+                            .map(str::parse)
+                            .transpose()?
+                            .unwrap_or(SyslogTarget::Local),
+                    )
+                } else {
+                    None
+                },
+                log_file: c
+                    .log_file
+                    .as_ref()
+                    .map(|path| -> Result<_, Error> {
+                        Ok(LogFile {
+                            path: path.clone(),
+                            max_size: c
+                                .log_max_size
+                                .as_deref()
+                                .map(parse_size)
+                                .transpose()?
+                                .unwrap_or(DEFAULT_LOG_MAX_SIZE),
+                            max_backups: c
+                                .log_max_backups
+                                .unwrap_or(DEFAULT_LOG_MAX_BACKUPS),
+                        })
+                    })
+                    .transpose()?,
                 ipv6: c.ipv6,
                 interface: c.interface.as_ref().map(|iface| {
                     if let Ok(addr) = iface.parse::<IpAddr>() {
@@ -102,7 +271,22 @@ impl TryFrom<def::Config> for Config {
             tun: match c.tun {
                 Some(t) => TunConfig {
                     enable: t.enable,
-                    device_id: t.device_id,
+                    device_id: match (t.device, t.device_id.is_empty()) {
+                        (Some(dev), true) => format!("dev://{}", dev),
+                        (None, false) => t.device_id,
+                        (Some(_), false) => {
+                            return Err(Error::InvalidConfig(
+                                "tun device and device-id are mutually exclusive"
+                                    .to_owned(),
+                            ));
+                        }
+                        (None, true) if t.enable => {
+                            return Err(Error::InvalidConfig(
+                                "tun device or device-id is required".to_owned(),
+                            ));
+                        }
+                        (None, true) => t.device_id,
+                    },
                     route_all: t.route_all,
                     routes: t
                         .routes
@@ -116,21 +300,69 @@ impl TryFrom<def::Config> for Config {
                             Error::InvalidConfig(format!("parse tun routes: {}", x))
                         })?
                         .unwrap_or_default(),
-                    gateway: t.gateway.parse().map_err(|x| {
+                    route_exclude_address: t
+                        .route_exclude_address
+                        .into_iter()
+                        .map(|x| x.parse())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|x| {
+                            Error::InvalidConfig(format!(
+                                "parse tun route-exclude-address: {}",
+                                x
+                            ))
+                        })?,
+                    gateway: match t.gateway.parse().map_err(|x| {
                         Error::InvalidConfig(format!("parse tun gateway: {}", x))
-                    })?,
-                    mtu: t.mtu,
+                    })? {
+                        IpNet::V4(net) => IpNet::V4(net),
+                        IpNet::V6(_) => {
+                            return Err(Error::InvalidConfig(
+                                "tun gateway must be an IPv4 address".to_owned(),
+                            ));
+                        }
+                    },
+                    inet6_address: t
+                        .inet6_address
+                        .map(|x| match x.parse() {
+                            Ok(IpNet::V6(net)) => Ok(IpNet::V6(net)),
+                            _ => Err(Error::InvalidConfig(format!(
+                                "invalid tun inet6-address: {}",
+                                x
+                            ))),
+                        })
+                        .transpose()?,
+                    mtu: match t.mtu {
+                        Some(mtu) if mtu < MIN_TUN_MTU => {
+                            return Err(Error::InvalidConfig(format!(
+                                "tun mtu must be at least {}",
+                                MIN_TUN_MTU
+                            )));
+                        }
+                        mtu => mtu,
+                    },
                     so_mark: t.so_mark,
                     route_table: t.route_table,
                     dns_hijack: match t.dns_hijack {
-                        def::DnsHijack::Switch(b) => b,
-                        def::DnsHijack::List(_) => true,
+                        def::DnsHijack::Switch(true) => {
+                            vec![DnsHijackTarget { ip: None, port: 53 }]
+                        }
+                        def::DnsHijack::Switch(false) => vec![],
+                        def::DnsHijack::List(l) => l
+                            .into_iter()
+                            .map(|x| x.parse())
+                            .collect::<Result<Vec<_>, _>>()?,
                     },
                 },
                 None => TunConfig::default(),
             },
             profile: Profile {
                 store_selected: c.profile.store_selected,
+                store_statistics: c.profile.store_statistics,
+                cache_file_path: c
+                    .profile
+                    .cache_file_path
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_CACHE_FILE.to_owned()),
             },
             rules: c
                 .rule
@@ -162,8 +394,8 @@ impl TryFrom<def::Config> for Config {
                                     Error,
                                 >(rv)
                             })
-                            .expect("proxy provider parse error")
                 })
+                .transpose()?
                 .unwrap_or_default(),
             users: c
                 .authentication
@@ -231,8 +463,9 @@ impl TryFrom<def::Config> for Config {
             proxy_providers: c
                 .proxy_provider
                 .map(|m| {
-                    m.into_iter()
-                        .try_fold(HashMap::new(), |mut rv, (name, mut body)| {
+                    m.into_iter().try_fold(
+                        HashMap::new(),
+                        |mut rv, (name, mut body)| {
                             body.insert(
                                 "name".to_owned(),
                                 serde_yaml::Value::String(name.clone()),
@@ -252,20 +485,28 @@ impl TryFrom<def::Config> for Config {
                                 >,
                                 Error,
                             >(rv)
-                        })
-                        .expect("proxy provider parse error")
+                        },
+                    )
                 })
+                .transpose()?
                 .unwrap_or_default(),
-        }
-        .validate()
+            effective,
+            unknown_fields,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::def;
+    use std::{collections::HashMap, time::Duration};
+
+    use ipnet::IpNet;
 
-    use super::Config;
+    use crate::{config::internal::proxy::OutboundProxy, def, session::SocksAddr};
+
+    use super::{
+        parse_duration, parse_size, BindAddress, Config, SyslogTarget, Tunnel,
+    };
 
     #[test]
     fn from_def_config() {
@@ -277,6 +518,379 @@ mod tests {
         let cc: Config = c.try_into().expect("should into");
         assert_eq!(cc.general.inbound.port, Some(9090));
     }
+
+    #[test]
+    fn bind_address() {
+        for (s, want) in [
+            ("*", "*"),
+            ("::", "::"),
+            ("[::]", "::"),
+            ("[::1]", "::1"),
+            ("127.0.0.1", "127.0.0.1"),
+            ("eth0", "eth0"),
+        ] {
+            let a = s.parse::<BindAddress>().expect("should parse");
+            assert_eq!(a.to_string(), want);
+        }
+    }
+
+    #[test]
+    fn listener_acl() {
+        let cfg = r#"
+        listener-acl:
+          socks:
+            allowed-ips: [10.0.0.0/8, 192.168.1.2]
+            blocked-ips: [10.0.5.7/24]
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        let acl = &cc.general.inbound.listener_acl["socks"];
+        assert_eq!(
+            acl.allowed_ips,
+            vec![
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "192.168.1.2/32".parse().unwrap()
+            ]
+        );
+        assert_eq!(acl.blocked_ips, vec!["10.0.5.0/24".parse().unwrap()]);
+
+        for cfg in [
+            "listener-acl: {socks: {blocked-ips: [10.0.0.0/33]}}",
+            "listener-acl: {socks5: {blocked-ips: [10.0.0.0/8]}}",
+        ] {
+            let c = cfg.parse::<def::Config>().expect("should parse");
+            assert!(TryInto::<Config>::try_into(c).is_err(), "{}", cfg);
+        }
+    }
+
+    #[test]
+    fn listener_max_connections() {
+        let cfg = r#"
+        max-connections: 100
+        listener-max-connections:
+          socks: 10
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.inbound.max_connections, 100);
+        assert_eq!(cc.general.inbound.listener_max_connections["socks"], 10);
+
+        let cfg = r#"
+        listener-max-connections:
+          socks5: 10
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        assert!(TryInto::<Config>::try_into(c).is_err());
+    }
+
+    #[test]
+    fn bandwidth_limits() {
+        let cfg = r#"
+        listener-bandwidth:
+          socks:
+            down: 50mbps
+        proxies:
+          - name: socks01
+            type: socks5
+            server: 10.0.0.13
+            port: 1080
+            up: 10mbps
+            down: 50mbps
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        let socks = cc.general.inbound.listener_bandwidth["socks"];
+        assert_eq!(socks.up, None);
+        assert_eq!(socks.down.unwrap().to_string(), "50mbps");
+        let OutboundProxy::ProxyServer(socks) = &cc.proxies["socks01"] else {
+            panic!("socks01 is a proxy");
+        };
+        let (up, down) = socks.bandwidth();
+        assert_eq!(up.unwrap().bytes_per_sec(), 1_250_000);
+        assert_eq!(down.unwrap().bytes_per_sec(), 6_250_000);
+
+        let cfg = r#"
+        listener-bandwidth:
+          socks:
+            up: 10 mb
+        "#;
+        assert!(cfg.parse::<def::Config>().is_err());
+    }
+
+    #[test]
+    fn log_file_rotation() {
+        let cfg = r#"
+        log-file: clash.log
+        log-max-size: 512kb
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        let f = cc.general.log_file.expect("log file");
+        assert_eq!(f.max_size, 512 * 1024);
+        assert_eq!(f.max_backups, 3);
+
+        assert_eq!(parse_size("10MB").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert!(parse_size("10tb").is_err());
+        assert!(parse_size("mb").is_err());
+        assert!(parse_size("18446744073709551615gb")
+            .unwrap_err()
+            .to_string()
+            .contains("too large"));
+    }
+
+    #[test]
+    fn shutdown_grace() {
+        let c = "shutdown-grace: 500ms".parse::<def::Config>().unwrap();
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.shutdown_grace, Duration::from_millis(500));
+
+        let c = "mode: rule".parse::<def::Config>().unwrap();
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.shutdown_grace, Duration::from_secs(10));
+
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("3").unwrap(), Duration::from_secs(3));
+        assert!(parse_duration("1h").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn port_hints() {
+        let c = "mode: rule".parse::<def::Config>().unwrap();
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.port_hints[&22], "ssh");
+        assert_eq!(cc.general.port_hints.len(), 3);
+
+        let c = "port-hints: {853: TLS}".parse::<def::Config>().unwrap();
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(
+            cc.general.port_hints,
+            HashMap::from([(853, "tls".to_owned())])
+        );
+
+        let c = "port-hints: {853: ''}".parse::<def::Config>().unwrap();
+        let err = TryInto::<Config>::try_into(c).err().expect("should fail");
+        assert!(err.to_string().contains("port-hints"), "{}", err);
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let c = "mode: rule".parse::<def::Config>().unwrap();
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.connect_timeout, Duration::from_secs(10));
+
+        let c = "connect-timeout: 15".parse::<def::Config>().unwrap();
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.connect_timeout, Duration::from_secs(15));
+
+        for bad in ["connect-timeout: 0", "connect-timeout: 301"] {
+            let c = bad.parse::<def::Config>().unwrap();
+            let err = TryInto::<Config>::try_into(c).err().expect("should fail");
+            assert!(err.to_string().contains("connect-timeout"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn runtime() {
+        let c = "runtime: {worker-threads: 2}"
+            .parse::<def::Config>()
+            .unwrap();
+        assert!(matches!(
+            crate::TokioRuntime::from_def(&c.runtime),
+            Ok(crate::TokioRuntime::Workers(2))
+        ));
+        let c = "runtime: {flavor: current}".parse::<def::Config>().unwrap();
+        assert!(matches!(
+            crate::TokioRuntime::from_def(&c.runtime),
+            Ok(crate::TokioRuntime::SingleThread)
+        ));
+
+        let c = "runtime: {worker-threads: 0}"
+            .parse::<def::Config>()
+            .unwrap();
+        assert!(Config::try_from(c).is_err());
+        let c = "runtime: {worker-threads: 2, flavor: current}"
+            .parse::<def::Config>()
+            .unwrap();
+        assert!(Config::try_from(c).is_err());
+    }
+
+    #[test]
+    fn log_syslog() {
+        let cfg = r#"
+        log-syslog: true
+        log-syslog-server: udp://192.168.1.1:514
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(
+            cc.general.log_syslog,
+            Some(SyslogTarget::Udp("192.168.1.1:514".to_owned()))
+        );
+
+        assert!("http://192.168.1.1".parse::<SyslogTarget>().is_err());
+    }
+
+    #[test]
+    fn log_directives() {
+        let cfg = r#"
+        log-directives:
+          - clash_lib::app::dns=trace
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.general.log_directives, vec!["clash_lib::app::dns=trace"]);
+
+        let cfg = r#"
+        log-directives:
+          - clash_lib::app::dns=loud
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = TryInto::<Config>::try_into(c).err().expect("should fail");
+        assert!(err.to_string().contains("clash_lib::app::dns=loud"));
+    }
+
+    #[test]
+    fn clients() {
+        let cfg = r#"
+        clients:
+          192.168.1.10: laptop
+          "fd00::23": tv
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(
+            cc.general.clients.get(&"fd00::23".parse().unwrap()),
+            Some(&"tv".to_owned())
+        );
+
+        let cfg = r#"
+        clients:
+          laptop: 192.168.1.10
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let err = TryInto::<Config>::try_into(c).err().expect("should fail");
+        assert!(err.to_string().contains("laptop"));
+    }
+
+    #[test]
+    fn tun_dns_hijack() {
+        let cfg = r#"
+        tun:
+          enable: true
+          device-id: "dev://utun1989"
+          dns-hijack:
+            - any:53
+            - 198.18.0.2:53
+            - "[::1]:5353"
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        let targets = cc.tun.dns_hijack;
+        assert_eq!(targets.len(), 3);
+        assert!(targets[0].matches(&"8.8.8.8:53".parse().unwrap()));
+        assert!(!targets[0].matches(&"8.8.8.8:5353".parse().unwrap()));
+        assert!(targets[1].matches(&"198.18.0.2:53".parse().unwrap()));
+        assert!(!targets[1].matches(&"198.18.0.3:53".parse().unwrap()));
+        assert!(targets[2].matches(&"[::1]:5353".parse().unwrap()));
+
+        let cfg = r#"
+        tun:
+          enable: true
+          device-id: "dev://utun1989"
+          dns-hijack:
+            - localhost:53
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        assert!(TryInto::<Config>::try_into(c).is_err());
+    }
+
+    #[test]
+    fn tun_addresses() {
+        let cfg = r#"
+        tun:
+          enable: true
+          device: utun1989
+          inet4-address: 198.19.0.1/30
+          inet6-address: fdfe:dcba:9876::1/126
+          mtu: 1280
+          route-address:
+            - 10.0.0.0/8
+          route-exclude-address:
+            - 10.8.0.0/16
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(cc.tun.device_id, "dev://utun1989");
+        assert_eq!(cc.tun.gateway, "198.19.0.1/30".parse().unwrap());
+        assert_eq!(
+            cc.tun.inet6_address,
+            Some("fdfe:dcba:9876::1/126".parse().unwrap())
+        );
+        assert_eq!(cc.tun.routes, vec!["10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(
+            cc.tun.route_exclude_address,
+            vec!["10.8.0.0/16".parse().unwrap()]
+        );
+
+        for bad in [
+            "inet6-address: 10.0.0.1/8",
+            "route-exclude-address: [10.8.0.0]",
+            "mtu: 500",
+            "device-id: dev://utun1",
+        ] {
+            let cfg =
+                format!("tun:\n  enable: true\n  device: utun1989\n  {}\n", bad);
+            let c = cfg.parse::<def::Config>().expect("should parse");
+            assert!(TryInto::<Config>::try_into(c).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn tunnels() {
+        let cfg = r#"
+        tunnels:
+          - tcp/udp,127.0.0.1:6553,8.8.8.8:53,DIRECT
+          - network: [tcp]
+            address: "[::1]:2222"
+            target: example.com:22
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc: Config = c.try_into().expect("should into");
+        assert_eq!(
+            cc.general.inbound.tunnels,
+            vec![
+                Tunnel {
+                    tcp: true,
+                    udp: true,
+                    address: "127.0.0.1:6553".parse().unwrap(),
+                    target: SocksAddr::from(
+                        "8.8.8.8:53".parse::<std::net::SocketAddr>().unwrap()
+                    ),
+                    proxy: Some("DIRECT".to_owned()),
+                },
+                Tunnel {
+                    tcp: true,
+                    udp: false,
+                    address: "[::1]:2222".parse().unwrap(),
+                    target: SocksAddr::Domain("example.com".to_owned(), 22),
+                    proxy: None,
+                },
+            ]
+        );
+
+        for bad in [
+            "icmp,127.0.0.1:6553,8.8.8.8:53",
+            "tcp,127.0.0.1,8.8.8.8:53",
+            "tcp,127.0.0.1:6553,8.8.8.8",
+            "tcp,127.0.0.1:6553",
+        ] {
+            let cfg = format!("tunnels:\n  - {}\n", bad);
+            let c = cfg.parse::<def::Config>().expect("should parse");
+            assert!(TryInto::<Config>::try_into(c).is_err(), "{}", bad);
+        }
+    }
 }
 
 pub struct General {
@@ -284,6 +898,32 @@ pub struct General {
     pub(crate) controller: Controller,
     pub mode: RunMode,
     pub log_level: LogLevel,
+    pub config_watch: bool,
+    pub log_directives: Vec<String>,
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+    pub log_connections: bool,
+    /// the names of the clients, by source IP
+    pub clients: HashMap<IpAddr, String>,
+    pub log_buffer_size: usize,
+    pub udp_sessions: UdpSessions,
+    /// the proxy UDP goes through when the chosen one has none
+    pub udp_fallback: String,
+    pub tcp_idle: TcpIdle,
+    /// the protocols assumed by destination port
+    pub port_hints: HashMap<u16, String>,
+    /// how long connecting to a server may take, unless the proxy says
+    pub connect_timeout: Duration,
+    /// in bytes, 0 for the OS default
+    pub socket_send_buffer_size: u32,
+    pub socket_recv_buffer_size: u32,
+    /// the `client-fingerprint` of the proxies that don't set it
+    pub global_client_fingerprint: Option<ClientFingerprint>,
+    pub shutdown_grace: Duration,
+    pub close_connections_on_network_change: bool,
+    pub unified_delay: bool,
+    pub log_syslog: Option<SyslogTarget>,
+    pub log_file: Option<LogFile>,
     pub ipv6: bool,
     pub interface: Option<Interface>,
     pub routing_mask: Option<u32>,
@@ -296,23 +936,176 @@ pub struct General {
     pub geosite_download_url: Option<String>,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct UdpSessions {
+    /// how long a session is kept without traffic
+    pub idle_timeout: Duration,
+    /// 0 for no limit
+    pub max: usize,
+    /// for the proxies without a `udp-nat` of their own
+    pub nat: UdpNat,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TcpIdle {
+    /// how long a connection is kept without traffic, None for ever
+    pub timeout: Option<Duration>,
+    /// destination ports the timeout doesn't apply to
+    pub exempt_ports: Vec<u16>,
+}
+
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_MAX_BACKUPS: usize = 3;
+
+#[derive(Clone)]
+pub struct LogFile {
+    pub path: String,
+    /// bytes
+    pub max_size: u64,
+    pub max_backups: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyslogTarget {
+    /// /dev/log
+    Local,
+    /// `host:port`
+    Udp(String),
+    Tcp(String),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = Error;
+
+    /// `udp://host:port` or `tcp://host:port`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            Some(("udp", addr)) if !addr.is_empty() => {
+                Ok(Self::Udp(addr.to_owned()))
+            }
+            Some(("tcp", addr)) if !addr.is_empty() => {
+                Ok(Self::Tcp(addr.to_owned()))
+            }
+            _ => Err(Error::InvalidConfig(format!(
+                "invalid log-syslog-server: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// sizes like `512kb`, `10mb`, `1gb`, or plain bytes
+fn parse_size(s: &str) -> Result<u64, Error> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (num, unit) = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .map(|i| lower.split_at(i))
+        .unwrap_or((&lower, ""));
+    let unit = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return Err(Error::InvalidConfig(format!("invalid size: {}", s))),
+    };
+    let num = num
+        .parse::<u64>()
+        .ok()
+        .filter(|x| *x > 0)
+        .ok_or_else(|| Error::InvalidConfig(format!("invalid size: {}", s)))?;
+    num.checked_mul(unit)
+        .ok_or_else(|| Error::InvalidConfig(format!("size too large: {}", s)))
+}
+
+/// durations like `500ms`, `10s`, `1m`, or plain seconds
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (num, unit) = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .map(|i| lower.split_at(i))
+        .unwrap_or((&lower, ""));
+    let num = num
+        .parse::<u64>()
+        .map_err(|_| Error::InvalidConfig(format!("invalid duration: {}", s)))?;
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(num)),
+        "" | "s" => Ok(Duration::from_secs(num)),
+        "m" => num.checked_mul(60).map(Duration::from_secs).ok_or_else(|| {
+            Error::InvalidConfig(format!("duration too large: {}", s))
+        }),
+        _ => Err(Error::InvalidConfig(format!("invalid duration: {}", s))),
+    }
+}
+
+const DEFAULT_CACHE_FILE: &str = "cache.db";
+
 pub struct Profile {
     pub store_selected: bool,
+    pub store_statistics: bool,
+    pub cache_file_path: String,
     // this is read to dns config directly
     // store_fake_ip: bool,
 }
 
+/// the minimum IPv4 MTU, anything smaller can't carry a full IP header
+/// plus a reasonable TCP segment
+const MIN_TUN_MTU: i32 = 576;
+
 #[derive(Default)]
+#[cfg_attr(not(feature = "tun"), allow(dead_code))]
 pub struct TunConfig {
     pub enable: bool,
     pub device_id: String,
     pub route_all: bool,
     pub routes: Vec<IpNet>,
+    pub route_exclude_address: Vec<IpNet>,
     pub gateway: IpNet,
+    pub inet6_address: Option<IpNet>,
     pub mtu: Option<i32>,
     pub so_mark: Option<u32>,
     pub route_table: Option<u32>,
-    pub dns_hijack: bool,
+    pub dns_hijack: Vec<DnsHijackTarget>,
+}
+
+/// A destination whose DNS queries are answered by the Clash DNS server
+/// when they go through the tun device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsHijackTarget {
+    /// None matches any destination IP
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+}
+
+impl DnsHijackTarget {
+    #[cfg_attr(not(feature = "tun"), allow(dead_code))]
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        self.port == addr.port() && self.ip.is_none_or(|ip| ip == addr.ip())
+    }
+}
+
+impl FromStr for DnsHijackTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || Error::InvalidConfig(format!("invalid dns-hijack: {}", s));
+
+        let (host, port) = s.rsplit_once(':').ok_or_else(err)?;
+        let port = port.parse::<u16>().map_err(|_| err())?;
+        let ip = if host == "any" {
+            None
+        } else {
+            Some(
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .map_err(|_| err())?,
+            )
+        };
+
+        Ok(Self { ip, port })
+    }
 }
 
 #[derive(Clone, Default)]
@@ -344,7 +1137,12 @@ impl FromStr for BindAddress {
                 Ok(Self::One(Interface::IpAddr(IpAddr::from([127, 0, 0, 1]))))
             }
             _ => {
-                if let Ok(ip) = s.parse::<IpAddr>() {
+                // `[::]` as well as `::`
+                let ip = s
+                    .strip_prefix('[')
+                    .and_then(|x| x.strip_suffix(']'))
+                    .unwrap_or(s);
+                if let Ok(ip) = ip.parse::<IpAddr>() {
                     Ok(BindAddress::One(Interface::IpAddr(ip)))
                 } else {
                     Ok(BindAddress::One(Interface::Name(s.to_string())))
@@ -354,6 +1152,9 @@ impl FromStr for BindAddress {
     }
 }
 
+/// listener names accepted in `listener-max-connections`
+pub(super) const LISTENER_NAMES: [&str; 4] = ["http", "socks", "mixed", "tproxy"];
+
 pub struct Inbound {
     pub port: Option<u16>,
     pub socks_port: Option<u16>,
@@ -362,13 +1163,148 @@ pub struct Inbound {
     pub mixed_port: Option<u16>,
     pub authentication: Vec<String>,
     pub bind_address: BindAddress,
+    pub max_connections: usize,
+    pub listener_max_connections: HashMap<String, usize>,
+    pub listener_bandwidth: HashMap<String, def::BandwidthLimits>,
+    pub listener_tls: HashMap<String, Vec<def::ListenerCertificate>>,
+    pub listener_acl: HashMap<String, ListenerAcl>,
+    pub socks_udp_source: def::SocksUdpSource,
+    pub tunnels: Vec<Tunnel>,
+}
+
+/// the `listener-acl` of a listener
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListenerAcl {
+    pub allowed_ips: Vec<IpNet>,
+    pub blocked_ips: Vec<IpNet>,
+}
+
+impl TryFrom<def::ListenerAclDef> for ListenerAcl {
+    type Error = Error;
+
+    fn try_from(def: def::ListenerAclDef) -> Result<Self, Self::Error> {
+        let parse = |cidrs: Vec<String>| {
+            cidrs
+                .into_iter()
+                .map(|x| {
+                    x.parse::<IpNet>()
+                        .or_else(|_| x.parse::<IpAddr>().map(IpNet::from))
+                        .map(|x| x.trunc())
+                        .map_err(|_| {
+                            Error::InvalidConfig(format!(
+                                "invalid CIDR {} in listener-acl",
+                                x
+                            ))
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            allowed_ips: parse(def.allowed_ips)?,
+            blocked_ips: parse(def.blocked_ips)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tunnel {
+    pub tcp: bool,
+    pub udp: bool,
+    pub address: SocketAddr,
+    pub target: SocksAddr,
+    /// the rules decide when it's not set
+    pub proxy: Option<String>,
+}
+
+impl TryFrom<def::TunnelDef> for Tunnel {
+    type Error = Error;
+
+    fn try_from(def: def::TunnelDef) -> Result<Self, Self::Error> {
+        let (network, address, target, proxy) = match &def {
+            def::TunnelDef::Short(s) => {
+                let parts = s.split(',').map(str::trim).collect::<Vec<_>>();
+                if !(3..=4).contains(&parts.len()) {
+                    return Err(Error::InvalidConfig(format!(
+                        "invalid tunnel {}, expected network,address,target[,proxy]",
+                        s
+                    )));
+                }
+                (
+                    parts[0].split('/').map(ToOwned::to_owned).collect(),
+                    parts[1],
+                    parts[2],
+                    parts.get(3).map(|x| x.to_string()),
+                )
+            }
+            def::TunnelDef::Full {
+                network,
+                address,
+                target,
+                proxy,
+            } => (
+                network.clone(),
+                address.as_str(),
+                target.as_str(),
+                proxy.clone(),
+            ),
+        };
+
+        let invalid = |what: &str| {
+            Error::InvalidConfig(format!("tunnel {}: invalid {}", address, what))
+        };
+
+        let mut tcp = false;
+        let mut udp = false;
+        for n in &network {
+            match n.as_str() {
+                "tcp" => tcp = true,
+                "udp" => udp = true,
+                _ => return Err(invalid(&format!("network {}", n))),
+            }
+        }
+        if !tcp && !udp {
+            return Err(invalid("network"));
+        }
+
+        let address = address
+            .parse::<SocketAddr>()
+            .map_err(|_| invalid("address"))?;
+        let target = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| {
+                let port = port.parse::<u16>().ok().filter(|x| *x != 0)?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                if host.is_empty() {
+                    return None;
+                }
+                SocksAddr::try_from((host.to_owned(), port)).ok()
+            })
+            .ok_or_else(|| invalid(&format!("target {}", target)))?;
+
+        Ok(Self {
+            tcp,
+            udp,
+            address,
+            target,
+            proxy: proxy.filter(|x| !x.is_empty()),
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct Controller {
     pub external_controller: Option<String>,
     pub external_ui: Option<String>,
+    pub external_ui_url: Option<String>,
+    pub cors_allow_origins: Vec<String>,
+    pub cors_allow_private_network: bool,
     pub secret: Option<String>,
+    pub skip_auth_loopback: bool,
+    pub external_controller_unix: Option<String>,
+    pub unix_socket_mode: u32,
+    pub skip_auth_unix: bool,
+    /// delay tests may fetch any url, or only the health check ones
+    pub allow_origin_tests: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -1,5 +1,8 @@
+mod check;
 pub mod config;
 pub mod proxy;
 pub mod rule;
+mod unknown;
 
+pub use check::Diagnostics;
 pub use config::Config as InternalConfig;
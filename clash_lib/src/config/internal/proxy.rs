@@ -1,9 +1,17 @@
-use crate::{common::utils::default_bool_true, config::utils, Error};
+use crate::{
+    common::{rate_limit::Bandwidth, utils::default_bool_true},
+    config::{
+        def::{ClientFingerprint, UdpNat},
+        utils,
+    },
+    Error,
+};
 use serde::{de::value::MapDeserializer, Deserialize};
 use serde_yaml::Value;
 use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
+    time::Duration,
 };
 use uuid::Uuid;
 
@@ -56,10 +64,13 @@ pub enum OutboundProxyProtocol {
     Ss(OutboundShadowsocks),
     #[serde(rename = "socks5")]
     Socks5(OutboundSocks5),
+    #[cfg(feature = "trojan")]
     #[serde(rename = "trojan")]
     Trojan(OutboundTrojan),
+    #[cfg(feature = "vmess")]
     #[serde(rename = "vmess")]
     Vmess(OutboundVmess),
+    #[cfg(feature = "wireguard")]
     #[serde(rename = "wireguard")]
     Wireguard(OutboundWireguard),
     #[cfg(feature = "onion")]
@@ -68,20 +79,42 @@ pub enum OutboundProxyProtocol {
     #[cfg(feature = "tuic")]
     #[serde(rename = "tuic")]
     Tuic(OutboundTuic),
+    #[cfg(feature = "quic-protocols")]
     #[serde(rename = "hysteria2")]
     Hysteria2(OutboundHysteria2),
 }
 
+/// the proxy types left out of this build, and the features they come with
+const DISABLED_TYPES: &[(&str, &str)] = &[
+    #[cfg(not(feature = "shadowsocks"))]
+    ("ss", "shadowsocks"),
+    #[cfg(not(feature = "trojan"))]
+    ("trojan", "trojan"),
+    #[cfg(not(feature = "vmess"))]
+    ("vmess", "vmess"),
+    #[cfg(not(feature = "wireguard"))]
+    ("wireguard", "wireguard"),
+    #[cfg(not(feature = "onion"))]
+    ("tor", "onion"),
+    #[cfg(not(feature = "tuic"))]
+    ("tuic", "tuic"),
+    #[cfg(not(feature = "quic-protocols"))]
+    ("hysteria2", "quic-protocols"),
+];
+
 impl OutboundProxyProtocol {
-    fn name(&self) -> &str {
+    pub(crate) fn name(&self) -> &str {
         match &self {
             OutboundProxyProtocol::Direct => PROXY_DIRECT,
             OutboundProxyProtocol::Reject => PROXY_REJECT,
             #[cfg(feature = "shadowsocks")]
             OutboundProxyProtocol::Ss(ss) => &ss.common_opts.name,
             OutboundProxyProtocol::Socks5(socks5) => &socks5.common_opts.name,
+            #[cfg(feature = "trojan")]
             OutboundProxyProtocol::Trojan(trojan) => &trojan.common_opts.name,
+            #[cfg(feature = "vmess")]
             OutboundProxyProtocol::Vmess(vmess) => &vmess.common_opts.name,
+            #[cfg(feature = "wireguard")]
             OutboundProxyProtocol::Wireguard(wireguard) => {
                 &wireguard.common_opts.name
             }
@@ -89,11 +122,101 @@ impl OutboundProxyProtocol {
             OutboundProxyProtocol::Tor(tor) => &tor.name,
             #[cfg(feature = "tuic")]
             OutboundProxyProtocol::Tuic(tuic) => &tuic.common_opts.name,
+            #[cfg(feature = "quic-protocols")]
             OutboundProxyProtocol::Hysteria2(hysteria2) => &hysteria2.name,
         }
     }
 }
 
+impl OutboundProxyProtocol {
+    fn common_opts(&self) -> Option<&CommonConfigOptions> {
+        Some(match &self {
+            #[cfg(feature = "shadowsocks")]
+            OutboundProxyProtocol::Ss(ss) => &ss.common_opts,
+            OutboundProxyProtocol::Socks5(socks5) => &socks5.common_opts,
+            #[cfg(feature = "trojan")]
+            OutboundProxyProtocol::Trojan(trojan) => &trojan.common_opts,
+            #[cfg(feature = "vmess")]
+            OutboundProxyProtocol::Vmess(vmess) => &vmess.common_opts,
+            #[cfg(feature = "wireguard")]
+            OutboundProxyProtocol::Wireguard(wireguard) => &wireguard.common_opts,
+            #[cfg(feature = "tuic")]
+            OutboundProxyProtocol::Tuic(tuic) => &tuic.common_opts,
+            _ => return None,
+        })
+    }
+
+    /// the `up` and `down` limits of the proxies that take them
+    pub(crate) fn bandwidth(&self) -> (Option<Bandwidth>, Option<Bandwidth>) {
+        // hysteria2 has its own `up` and `down`, the bandwidth it asks the
+        // server for
+        self.common_opts()
+            .map_or((None, None), |common| (common.up, common.down))
+    }
+
+    /// whether `pre-connect` is on
+    pub(crate) fn pre_connect(&self) -> bool {
+        match self {
+            #[cfg(feature = "quic-protocols")]
+            OutboundProxyProtocol::Hysteria2(hysteria2) => hysteria2.pre_connect,
+            _ => self.common_opts().is_some_and(|x| x.pre_connect),
+        }
+    }
+
+    pub(crate) fn max_concurrent_dials(&self) -> Option<usize> {
+        self.common_opts().and_then(|x| x.max_concurrent_dials)
+    }
+
+    pub(crate) fn dial_timeout(&self) -> Option<Duration> {
+        match self {
+            #[cfg(feature = "quic-protocols")]
+            OutboundProxyProtocol::Hysteria2(hysteria2) => hysteria2.dial_timeout,
+            _ => self.common_opts().and_then(|x| x.dial_timeout),
+        }
+        .map(Duration::from_secs)
+    }
+
+    /// the `client-fingerprint` the proxy sets, if it uses TLS
+    pub(crate) fn client_fingerprint(&self) -> Option<ClientFingerprint> {
+        match self {
+            OutboundProxyProtocol::Socks5(socks5) if socks5.tls => {
+                socks5.client_fingerprint
+            }
+            #[cfg(feature = "trojan")]
+            OutboundProxyProtocol::Trojan(trojan) => trojan.client_fingerprint,
+            #[cfg(feature = "vmess")]
+            OutboundProxyProtocol::Vmess(vmess) if vmess.tls.unwrap_or_default() => {
+                vmess.client_fingerprint
+            }
+            _ => None,
+        }
+    }
+
+    /// sets `client-fingerprint` to `global-client-fingerprint` on the
+    /// proxies using TLS that don't set their own, before the config is
+    /// hashed, so a change of the global only rebuilds those
+    pub(crate) fn default_client_fingerprint(
+        &mut self,
+        global: Option<ClientFingerprint>,
+    ) {
+        let fp = match self {
+            OutboundProxyProtocol::Socks5(socks5) if socks5.tls => {
+                &mut socks5.client_fingerprint
+            }
+            #[cfg(feature = "trojan")]
+            OutboundProxyProtocol::Trojan(trojan) => &mut trojan.client_fingerprint,
+            #[cfg(feature = "vmess")]
+            OutboundProxyProtocol::Vmess(vmess) if vmess.tls.unwrap_or_default() => {
+                &mut vmess.client_fingerprint
+            }
+            _ => return,
+        };
+        if fp.is_none() {
+            *fp = global;
+        }
+    }
+}
+
 impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
     type Error = crate::Error;
 
@@ -105,11 +228,118 @@ impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
                 "missing field `name` in outbound proxy protocol".to_owned(),
             ))?
             .to_owned();
+        let typ = mapping.get("type").and_then(|x| x.as_str());
+        if let Some((typ, feature)) =
+            DISABLED_TYPES.iter().find(|(x, _)| Some(*x) == typ)
+        {
+            return Err(Error::InvalidConfig(format!(
+                "proxy {}: compiled without {} support, needed by type {}",
+                name, feature, typ
+            )));
+        }
+        let mut mapping = mapping;
+        expand_servers(&name, &mut mapping)?;
         OutboundProxyProtocol::deserialize(MapDeserializer::new(mapping.into_iter()))
             .map_err(map_serde_error(name))
     }
 }
 
+/// `server` as a list goes in `servers`, its first one in `server`, and
+/// `ports` gives `port` if that's not set, for the proxies that take several
+fn expand_servers(
+    name: &str,
+    mapping: &mut HashMap<String, Value>,
+) -> Result<(), Error> {
+    let several = mapping.get("server").is_some_and(|x| x.is_sequence())
+        || mapping.contains_key("ports");
+    if !several {
+        return Ok(());
+    }
+    let typ = mapping.get("type").and_then(|x| x.as_str());
+    if !matches!(typ, Some("ss" | "trojan")) {
+        return Err(Error::InvalidConfig(format!(
+            "proxy {}: only ss and trojan take a list of servers or ports",
+            name
+        )));
+    }
+
+    if let Some(Value::Sequence(servers)) = mapping.get("server") {
+        let first = servers.first().cloned().ok_or_else(|| {
+            Error::InvalidConfig(format!("proxy {}: empty server list", name))
+        })?;
+        let servers = Value::Sequence(servers.clone());
+        mapping.insert("servers".to_owned(), servers);
+        mapping.insert("server".to_owned(), first);
+    }
+    if let Some(ports) = mapping.get("ports").cloned() {
+        let ports = match ports {
+            Value::Number(n) => n.to_string(),
+            Value::String(s) => s,
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "proxy {}: ports must be like 8000-8010",
+                    name
+                )))
+            }
+        };
+        let first = *utils::parse_ports(&ports)
+            .map_err(|e| Error::InvalidConfig(format!("proxy {}: {}", name, e)))?
+            .first()
+            .expect("at least one port");
+        mapping
+            .entry("port".to_owned())
+            .or_insert(Value::from(first));
+        mapping.insert("ports".to_owned(), Value::String(ports));
+    }
+    Ok(())
+}
+
+/// the most servers and ports a proxy takes together, each is kept with
+/// its own resolved addresses
+#[cfg(any(feature = "shadowsocks", feature = "trojan"))]
+const MAX_SERVER_CANDIDATES: usize = 1024;
+
+/// each of `servers`, or `server`, with each of `ports`, or `port`, in the
+/// order they're tried. A server given twice is an error, and so are more
+/// than [`MAX_SERVER_CANDIDATES`] of them.
+#[cfg(any(feature = "shadowsocks", feature = "trojan"))]
+pub(crate) fn server_candidates(
+    common_opts: &CommonConfigOptions,
+    servers: Option<&[String]>,
+    ports: Option<&str>,
+) -> Result<Vec<(String, u16)>, Error> {
+    let invalid = |e: String| {
+        Error::InvalidConfig(format!("proxy {}: {}", common_opts.name, e))
+    };
+
+    let servers = match servers {
+        Some([]) => return Err(invalid("empty server list".to_owned())),
+        Some(servers) => servers.to_vec(),
+        None => vec![common_opts.server.clone()],
+    };
+    for (i, server) in servers.iter().enumerate() {
+        if servers[..i].iter().any(|x| x.eq_ignore_ascii_case(server)) {
+            return Err(invalid(format!("server {} is given twice", server)));
+        }
+    }
+    let ports = match ports {
+        Some(ports) => utils::parse_ports(ports).map_err(invalid)?,
+        None => vec![common_opts.port],
+    };
+    let count = servers.len().saturating_mul(ports.len());
+    if count > MAX_SERVER_CANDIDATES {
+        return Err(invalid(format!(
+            "{} servers and ports, at most {} are taken",
+            count, MAX_SERVER_CANDIDATES
+        )));
+    }
+
+    Ok(servers
+        .iter()
+        .flat_map(|server| ports.iter().map(|port| (server.clone(), *port)))
+        .collect())
+}
+
 impl Display for OutboundProxyProtocol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -118,13 +348,17 @@ impl Display for OutboundProxyProtocol {
             OutboundProxyProtocol::Socks5(_) => write!(f, "Socks5"),
             OutboundProxyProtocol::Direct => write!(f, "{}", PROXY_DIRECT),
             OutboundProxyProtocol::Reject => write!(f, "{}", PROXY_REJECT),
+            #[cfg(feature = "trojan")]
             OutboundProxyProtocol::Trojan(_) => write!(f, "Trojan"),
+            #[cfg(feature = "vmess")]
             OutboundProxyProtocol::Vmess(_) => write!(f, "Vmess"),
+            #[cfg(feature = "wireguard")]
             OutboundProxyProtocol::Wireguard(_) => write!(f, "Wireguard"),
             #[cfg(feature = "onion")]
             OutboundProxyProtocol::Tor(_) => write!(f, "Tor"),
             #[cfg(feature = "tuic")]
             OutboundProxyProtocol::Tuic(_) => write!(f, "Tuic"),
+            #[cfg(feature = "quic-protocols")]
             OutboundProxyProtocol::Hysteria2(_) => write!(f, "Hysteria2"),
         }
     }
@@ -142,6 +376,43 @@ pub struct CommonConfigOptions {
     /// nothing
     #[serde(alias = "dialer-proxy")]
     pub connect_via: Option<String>,
+    /// shared by all the connections through this proxy, e.g. `10mbps`
+    pub up: Option<Bandwidth>,
+    pub down: Option<Bandwidth>,
+    /// which addresses of `server` are dialed, by default v4 ones, and v6
+    /// ones too if the resolver has ipv6 on
+    pub resolve_strategy: Option<ResolveStrategy>,
+    /// how many connections may be being made to the server at once, the
+    /// rest wait their turn, e.g. so a burst of TLS handshakes doesn't trip
+    /// the server's rate limit. Unlimited by default.
+    pub max_concurrent_dials: Option<usize>,
+    /// in seconds, how long connecting to the server may take, overriding
+    /// `connect-timeout`, 1 to 300
+    #[serde(default, deserialize_with = "utils::deserialize_dial_timeout")]
+    pub dial_timeout: Option<u64>,
+    /// in seconds, how long the handshake with the server may take, the TLS
+    /// one included, `dial-timeout` by default
+    pub handshake_timeout: Option<u64>,
+    /// in bytes, the socket buffers of the connections to the server, the
+    /// UDP ones of ss and socks5 included, overriding
+    /// `socket-send-buffer-size` and `socket-recv-buffer-size`, 0 for the
+    /// OS default
+    pub socket_send_buffer_size: Option<u32>,
+    pub socket_recv_buffer_size: Option<u32>,
+    /// keeps a connection to the server open ahead of the first dial, for
+    /// the proxies that share one between their connections, the others
+    /// ignore it
+    #[serde(default)]
+    pub pre_connect: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolveStrategy {
+    PreferV4,
+    PreferV6,
+    OnlyV4,
+    OnlyV6,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -153,6 +424,26 @@ pub struct OutboundShadowsocks {
     pub password: String,
     #[serde(default = "default_bool_true")]
     pub udp: bool,
+    /// every server when `server` is a list, each is dialed with each of
+    /// `ports`, the one that worked last first, and the next one when it
+    /// fails. Which one it is is kept in the cache.
+    /// ```yaml
+    /// server: [a.example.com, b.example.com]
+    /// ports: 8000-8010 # or 443,8443
+    /// ```
+    pub servers: Option<Vec<String>>,
+    /// the ports to try instead of `port`, see `servers`
+    pub ports: Option<String>,
+    /// `obfs`, `v2ray-plugin`, `shadow-tls`, or `sip003` for an external
+    /// plugin binary, run with the SIP003 environment. TCP goes through it,
+    /// not through `dialer-proxy`, UDP goes to the server as is.
+    /// ```yaml
+    /// plugin: sip003
+    /// plugin-opts:
+    ///   command: /usr/local/bin/xray-plugin
+    ///   args: ["-loglevel", "warning"]
+    ///   opts: tls;host=example.com
+    /// ```
     pub plugin: Option<String>,
     pub plugin_opts: Option<HashMap<String, serde_yaml::Value>>,
 }
@@ -169,8 +460,15 @@ pub struct OutboundSocks5 {
     pub sni: Option<String>,
     #[serde(default = "Default::default")]
     pub skip_cert_verify: bool,
+    pub pinned_cert_chain_sha256: Option<Vec<String>>,
+    #[serde(default = "Default::default")]
+    pub pinned_cert_only: bool,
+    /// the global `global-client-fingerprint` if not set, with `tls`
+    pub client_fingerprint: Option<ClientFingerprint>,
     #[serde(default = "default_bool_true")]
     pub udp: bool,
+    /// the global `udp-nat` if not set
+    pub udp_nat: Option<UdpNat>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
@@ -203,7 +501,15 @@ pub struct OutboundTrojan {
     pub alpn: Option<Vec<String>>,
     pub sni: Option<String>,
     pub skip_cert_verify: Option<bool>,
+    pub pinned_cert_chain_sha256: Option<Vec<String>>,
+    pub pinned_cert_only: Option<bool>,
+    /// the global `global-client-fingerprint` if not set
+    pub client_fingerprint: Option<ClientFingerprint>,
     pub udp: Option<bool>,
+    /// as with ss, every server when `server` is a list
+    pub servers: Option<Vec<String>>,
+    /// the ports to try instead of `port`
+    pub ports: Option<String>,
     pub network: Option<String>,
     pub grpc_opts: Option<GrpcOpt>,
     pub ws_opts: Option<WsOpt>,
@@ -221,6 +527,10 @@ pub struct OutboundVmess {
     pub udp: Option<bool>,
     pub tls: Option<bool>,
     pub skip_cert_verify: Option<bool>,
+    pub pinned_cert_chain_sha256: Option<Vec<String>>,
+    pub pinned_cert_only: Option<bool>,
+    /// the global `global-client-fingerprint` if not set, with `tls`
+    pub client_fingerprint: Option<ClientFingerprint>,
     #[serde(alias = "servername")]
     pub server_name: Option<String>,
     pub network: Option<String>,
@@ -320,6 +630,42 @@ impl OutboundGroupProtocol {
             OutboundGroupProtocol::Select(g) => g.proxies.as_ref(),
         }
     }
+
+    pub fn use_provider(&self) -> Option<&Vec<String>> {
+        match &self {
+            OutboundGroupProtocol::Relay(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::UrlTest(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::Fallback(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::LoadBalance(g) => g.use_provider.as_ref(),
+            OutboundGroupProtocol::Select(g) => g.use_provider.as_ref(),
+        }
+    }
+}
+
+/// how long a source and destination stay pinned to a member
+const DEFAULT_AFFINITY_WINDOW: Duration = Duration::from_secs(600);
+
+impl OutboundGroupProtocol {
+    /// the window of `same-exit-affinity`, None when it's off
+    pub fn same_exit_affinity(&self) -> Option<Duration> {
+        let (on, window) = match &self {
+            OutboundGroupProtocol::Relay(_) => return None,
+            OutboundGroupProtocol::UrlTest(g) => {
+                (g.same_exit_affinity, g.same_exit_affinity_window)
+            }
+            OutboundGroupProtocol::Fallback(g) => {
+                (g.same_exit_affinity, g.same_exit_affinity_window)
+            }
+            OutboundGroupProtocol::LoadBalance(g) => {
+                (g.same_exit_affinity, g.same_exit_affinity_window)
+            }
+            OutboundGroupProtocol::Select(g) => {
+                (g.same_exit_affinity, g.same_exit_affinity_window)
+            }
+        };
+        on.unwrap_or_default()
+            .then(|| window.map_or(DEFAULT_AFFINITY_WINDOW, Duration::from_secs))
+    }
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundGroupProtocol {
@@ -357,6 +703,9 @@ pub struct OutboundGroupRelay {
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
     pub icon: Option<String>,
+    /// UDP over the hops taking streams only, as sing-box's udp-over-tcp v2
+    #[serde(default, rename = "udp-over-tcp")]
+    pub udp_over_tcp: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -371,7 +720,26 @@ pub struct OutboundGroupUrlTest {
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    #[serde(rename = "health-check")]
+    pub health_check: Option<GroupHealthCheck>,
     pub tolerance: Option<u16>,
+    /// the members that lost more than this percentage of the probes of the
+    /// last `loss-rounds` rounds, 5 by default, aren't picked, unless all
+    /// did. the loss is only known with a `sample-count` above 1 in the
+    /// `health-check` of the group or of its providers.
+    #[serde(rename = "max-loss-percent")]
+    pub max_loss_percent: Option<u8>,
+    #[serde(rename = "loss-rounds")]
+    pub loss_rounds: Option<usize>,
+    /// pins the connections from a source to a destination host, TCP and
+    /// UDP alike, to the member the first of them took, for
+    /// `same-exit-affinity-window` seconds, 10 minutes by default. e.g. for
+    /// STUN/TURN, whose control and media must leave through the same proxy.
+    /// only applies where the group is what a rule or the mode picked.
+    #[serde(rename = "same-exit-affinity")]
+    pub same_exit_affinity: Option<bool>,
+    #[serde(rename = "same-exit-affinity-window")]
+    pub same_exit_affinity_window: Option<u64>,
     pub icon: Option<String>,
 }
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -386,6 +754,12 @@ pub struct OutboundGroupFallback {
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    #[serde(rename = "health-check")]
+    pub health_check: Option<GroupHealthCheck>,
+    #[serde(rename = "same-exit-affinity")]
+    pub same_exit_affinity: Option<bool>,
+    #[serde(rename = "same-exit-affinity-window")]
+    pub same_exit_affinity_window: Option<u64>,
     pub icon: Option<String>,
 }
 
@@ -401,8 +775,17 @@ pub struct OutboundGroupLoadBalance {
     #[serde(deserialize_with = "utils::deserialize_u64")]
     pub interval: u64,
     pub lazy: Option<bool>,
+    #[serde(rename = "health-check")]
+    pub health_check: Option<GroupHealthCheck>,
     pub strategy: Option<LoadBalanceStrategy>,
+    /// by member name, for `strategy: weighted`
+    pub weights: Option<HashMap<String, u32>>,
+    #[serde(rename = "same-exit-affinity")]
+    pub same_exit_affinity: Option<bool>,
+    #[serde(rename = "same-exit-affinity-window")]
+    pub same_exit_affinity_window: Option<u64>,
     pub icon: Option<String>,
+    pub udp: Option<bool>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default)]
@@ -412,6 +795,10 @@ pub enum LoadBalanceStrategy {
     ConsistentHashing,
     #[serde(rename = "round-robin")]
     RoundRobin,
+    /// smooth weighted round-robin over the alive members by `weights`,
+    /// weight 0 being a backup
+    #[serde(rename = "weighted")]
+    Weighted,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -422,6 +809,10 @@ pub struct OutboundGroupSelect {
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
     pub udp: Option<bool>,
+    #[serde(rename = "same-exit-affinity")]
+    pub same_exit_affinity: Option<bool>,
+    #[serde(rename = "same-exit-affinity-window")]
+    pub same_exit_affinity_window: Option<u64>,
     pub icon: Option<String>,
 }
 
@@ -457,9 +848,53 @@ pub struct OutboundFileProvider {
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct HealthCheck {
     pub enable: bool,
+    /// not needed for `type: icmp`
+    #[serde(default)]
     pub url: String,
     pub interval: u64,
     pub lazy: Option<bool>,
+    #[serde(rename = "type", default)]
+    pub typ: HealthCheckType,
+    /// probes a proxy each round, for the loss and jitter in the history, 1
+    /// to 10, 1 by default
+    #[serde(rename = "sample-count")]
+    pub sample_count: Option<u8>,
+}
+
+/// `health-check` of a group, the url and interval are the group's
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
+pub struct GroupHealthCheck {
+    #[serde(rename = "type", default)]
+    pub typ: HealthCheckType,
+    /// see [`HealthCheck::sample_count`]
+    #[serde(rename = "sample-count")]
+    pub sample_count: Option<u8>,
+}
+
+/// How a health check measures the latency.
+///
+/// `icmp` pings the server of each proxy, or times a TCP connect to its port
+/// where ICMP isn't available, except for hysteria2, tuic and wireguard that
+/// only listen on UDP, those fail the check then. That only tells the server is
+/// reachable, not that the proxy works through it, but it doesn't depend on a
+/// URL the exit may block or answer itself. The ones without a server of their
+/// own, e.g. DIRECT or a nested group, are checked over HTTP still.
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    #[default]
+    Http,
+    Icmp,
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundProxyProviderDef {
@@ -503,6 +938,13 @@ pub struct OutboundHysteria2 {
     pub fingerprint: Option<String>,
     /// bbr congestion control window
     pub cwnd: Option<u64>,
+    /// like the `pre-connect` of the other proxies
+    #[serde(default)]
+    pub pre_connect: bool,
+    /// like the `dial-timeout` of the other proxies, how long the QUIC
+    /// handshake and the auth may take
+    #[serde(default, deserialize_with = "utils::deserialize_dial_timeout")]
+    pub dial_timeout: Option<u64>,
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
@@ -510,3 +952,142 @@ pub struct OutboundHysteria2 {
 pub enum Hysteria2Obfs {
     Salamander,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_yaml::Value;
+
+    use crate::config::def::ClientFingerprint;
+
+    #[cfg(feature = "trojan")]
+    use super::server_candidates;
+    use super::{OutboundProxyProtocol, DISABLED_TYPES};
+
+    fn proxy(typ: &str) -> HashMap<String, Value> {
+        serde_yaml::from_str(&format!(
+            "{{name: p, type: {}, server: example.com, port: 443}}",
+            typ
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_disabled_type() {
+        for (typ, feature) in DISABLED_TYPES {
+            let e = OutboundProxyProtocol::try_from(proxy(typ)).unwrap_err();
+            assert!(
+                e.to_string()
+                    .contains(&format!("compiled without {} support", feature)),
+                "{}",
+                e
+            );
+        }
+
+        let e = OutboundProxyProtocol::try_from(proxy("nope")).unwrap_err();
+        assert!(!e.to_string().contains("compiled without"), "{}", e);
+    }
+
+    #[test]
+    fn test_dial_timeout() {
+        let mut p = proxy("socks5");
+        p.insert("dial-timeout".to_owned(), Value::from(15));
+        match OutboundProxyProtocol::try_from(p).unwrap() {
+            OutboundProxyProtocol::Socks5(s) => {
+                assert_eq!(s.common_opts.dial_timeout, Some(15))
+            }
+            _ => unreachable!(),
+        }
+
+        for bad in [0, 301] {
+            let mut p = proxy("socks5");
+            p.insert("dial-timeout".to_owned(), Value::from(bad));
+            let e = OutboundProxyProtocol::try_from(p).unwrap_err();
+            assert!(e.to_string().contains("dial timeout"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_pre_connect() {
+        assert!(!OutboundProxyProtocol::try_from(proxy("socks5"))
+            .unwrap()
+            .pre_connect());
+        let mut p = proxy("socks5");
+        p.insert("pre-connect".to_owned(), Value::from(true));
+        assert!(OutboundProxyProtocol::try_from(p).unwrap().pre_connect());
+    }
+
+    #[test]
+    fn test_default_client_fingerprint() {
+        let fingerprint = |p: HashMap<String, Value>| {
+            let mut p = OutboundProxyProtocol::try_from(p).unwrap();
+            p.default_client_fingerprint(Some(ClientFingerprint::Chrome));
+            match p {
+                OutboundProxyProtocol::Socks5(s) => s.client_fingerprint,
+                _ => unreachable!(),
+            }
+        };
+
+        // no TLS, nothing to shape
+        assert_eq!(fingerprint(proxy("socks5")), None);
+
+        let mut p = proxy("socks5");
+        p.insert("tls".to_owned(), Value::from(true));
+        assert_eq!(fingerprint(p.clone()), Some(ClientFingerprint::Chrome));
+
+        p.insert("client-fingerprint".to_owned(), Value::from("firefox"));
+        assert_eq!(fingerprint(p.clone()), Some(ClientFingerprint::Firefox));
+
+        p.insert("client-fingerprint".to_owned(), Value::from("netscape"));
+        assert!(OutboundProxyProtocol::try_from(p).is_err());
+    }
+
+    #[cfg(feature = "trojan")]
+    #[test]
+    fn test_server_list() {
+        let parse = |yaml: &str| {
+            let p: HashMap<String, Value> = serde_yaml::from_str(yaml).unwrap();
+            OutboundProxyProtocol::try_from(p).and_then(|p| match p {
+                OutboundProxyProtocol::Trojan(t) => server_candidates(
+                    &t.common_opts,
+                    t.servers.as_deref(),
+                    t.ports.as_deref(),
+                ),
+                _ => unreachable!(),
+            })
+        };
+
+        let candidates = parse(
+            "{name: p, type: trojan, password: x, server: [a.example, b.example], \
+             ports: 8000-8001}",
+        )
+        .unwrap();
+        assert_eq!(
+            candidates,
+            [
+                ("a.example".to_owned(), 8000),
+                ("a.example".to_owned(), 8001),
+                ("b.example".to_owned(), 8000),
+                ("b.example".to_owned(), 8001),
+            ]
+        );
+        let candidates = parse(
+            "{name: p, type: trojan, password: x, server: a.example, port: 443}",
+        )
+        .unwrap();
+        assert_eq!(candidates, [("a.example".to_owned(), 443)]);
+
+        for bad in [
+            "{name: p, type: trojan, password: x, server: [], port: 443}",
+            "{name: p, type: trojan, password: x, server: [a, b, a], port: 443}",
+            "{name: p, type: trojan, password: x, server: a, ports: \
+             '8000-8010,8005'}",
+            "{name: p, type: trojan, password: x, server: a, ports: 9-8}",
+            "{name: p, type: trojan, password: x, server: [a, b], ports: 1000-1600}",
+            "{name: p, type: vmess, uuid: x, alter-id: 0, server: [a, b], port: 1}",
+        ] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
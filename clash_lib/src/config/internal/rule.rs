@@ -53,6 +53,14 @@ pub enum RuleType {
         rule_set: String,
         target: String,
     },
+    InUser {
+        user: String,
+        target: String,
+    },
+    Protocol {
+        protocol: String,
+        target: String,
+    },
     Match {
         target: String,
     },
@@ -73,6 +81,8 @@ impl RuleType {
             RuleType::ProcessName { target, .. } => target,
             RuleType::ProcessPath { target, .. } => target,
             RuleType::RuleSet { target, .. } => target,
+            RuleType::InUser { target, .. } => target,
+            RuleType::Protocol { target, .. } => target,
             RuleType::Match { target } => target,
         }
     }
@@ -95,6 +105,8 @@ impl Display for RuleType {
             RuleType::ProcessName { .. } => write!(f, "PROCESS-NAME"),
             RuleType::ProcessPath { .. } => write!(f, "PROCESS-PATH"),
             RuleType::RuleSet { .. } => write!(f, "RULE-SET"),
+            RuleType::InUser { .. } => write!(f, "IN-USER"),
+            RuleType::Protocol { .. } => write!(f, "PROTOCOL"),
             RuleType::Match { .. } => write!(f, "MATCH"),
         }
     }
@@ -175,6 +187,14 @@ impl RuleType {
                 rule_set: payload.to_string(),
                 target: target.to_string(),
             }),
+            "IN-USER" => Ok(RuleType::InUser {
+                user: payload.to_string(),
+                target: target.to_string(),
+            }),
+            "PROTOCOL" => Ok(RuleType::Protocol {
+                protocol: payload.to_lowercase(),
+                target: target.to_string(),
+            }),
             "MATCH" => Ok(RuleType::Match {
                 target: target.to_string(),
             }),
@@ -0,0 +1,200 @@
+//! Unknown fields in the sections kept as raw maps by [`def::Config`] and
+//! parsed later: proxies, proxy groups and the providers.
+//!
+//! Those are internally tagged and use `#[serde(flatten)]`, which swallow
+//! unknown fields before `serde_ignored` sees them, so instead an entry is
+//! parsed, serialized back, and the keys that didn't make it through are the
+//! unknown ones.
+//!
+//! [`def::Config`]: crate::config::def::Config
+
+use std::collections::HashMap;
+
+use serde::{
+    de::{value::MapDeserializer, DeserializeOwned},
+    Serialize,
+};
+use serde_yaml::Value;
+
+use crate::config::def;
+
+use super::{
+    config::RuleProviderDef,
+    proxy::{
+        OutboundGroupProtocol, OutboundProxyProtocol, OutboundProxyProviderDef,
+    },
+};
+
+/// serde aliases, accepted on input but serialized under their main name
+const ALIASES: [&str; 3] = ["dialer-proxy", "alterId", "servername"];
+
+/// Every unknown field in the config, the ones `serde_ignored` found while
+/// parsing it and the ones in the raw sections.
+pub(super) fn collect(c: &def::Config) -> Vec<String> {
+    let mut rv = c.unknown_fields.clone();
+
+    let entry_name = |x: &HashMap<String, Value>, i: usize| {
+        x.get("name")
+            .and_then(|x| x.as_str())
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| i.to_string())
+    };
+    for (i, x) in c.proxy.iter().enumerate() {
+        let prefix = format!("proxies.{}", entry_name(x, i));
+        rv.extend(unknown_fields::<OutboundProxyProtocol>(&prefix, x));
+    }
+    for (i, x) in c.proxy_group.iter().enumerate() {
+        let prefix = format!("proxy-groups.{}", entry_name(x, i));
+        rv.extend(unknown_fields::<OutboundGroupProtocol>(&prefix, x));
+    }
+    for (name, x) in c.proxy_provider.iter().flatten() {
+        let prefix = format!("proxy-providers.{}", name);
+        rv.extend(unknown_fields::<OutboundProxyProviderDef>(&prefix, x));
+    }
+    for (name, x) in c.rule_provider.iter().flatten() {
+        let prefix = format!("rule-providers.{}", name);
+        rv.extend(unknown_fields::<RuleProviderDef>(&prefix, x));
+    }
+
+    rv
+}
+
+/// The key paths under `prefix` of the fields in `raw` that `T` ignores, e.g.
+/// `proxies.ss01.udp-over-tcp`. An entry `T` can't parse is left to the
+/// conversion to report and has no unknown fields.
+pub(super) fn unknown_fields<T>(
+    prefix: &str,
+    raw: &HashMap<String, Value>,
+) -> Vec<String>
+where
+    T: DeserializeOwned + Serialize,
+{
+    let parsed = T::deserialize(MapDeserializer::<_, serde_yaml::Error>::new(
+        raw.clone().into_iter(),
+    ));
+    let Ok(known) = parsed.and_then(serde_yaml::to_value) else {
+        return vec![];
+    };
+
+    let mut rv = vec![];
+    let mut keys = raw.keys().collect::<Vec<_>>();
+    keys.sort();
+    for k in keys {
+        // the name is filled in for providers but not serialized
+        if k == "name" || ALIASES.contains(&k.as_str()) {
+            continue;
+        }
+        diff(
+            &raw[k],
+            known.get(k.as_str()),
+            &mut vec![prefix.to_owned(), k.clone()],
+            &mut rv,
+        );
+    }
+    rv
+}
+
+fn diff(
+    raw: &Value,
+    known: Option<&Value>,
+    path: &mut Vec<String>,
+    rv: &mut Vec<String>,
+) {
+    match (raw, known) {
+        // an explicit null is the same as leaving the field out
+        (Value::Null, _) => {}
+        (_, None) => rv.push(path.join(".")),
+        (Value::Mapping(raw), Some(Value::Mapping(known))) => {
+            for (k, v) in raw {
+                let Some(name) = k.as_str() else {
+                    continue;
+                };
+                if ALIASES.contains(&name) {
+                    continue;
+                }
+                path.push(name.to_owned());
+                diff(v, known.get(name), path, rv);
+                path.pop();
+            }
+        }
+        (Value::Sequence(raw), Some(Value::Sequence(known))) => {
+            for (i, (r, k)) in raw.iter().zip(known).enumerate() {
+                path.push(i.to_string());
+                diff(r, Some(k), path, rv);
+                path.pop();
+            }
+        }
+        // a map parsed into something else, e.g. a custom deserializer
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_yaml::Value;
+
+    use crate::config::internal::proxy::{
+        OutboundGroupProtocol, OutboundProxyProtocol,
+    };
+
+    use super::unknown_fields;
+
+    fn raw(s: &str) -> HashMap<String, Value> {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[cfg(feature = "vmess")]
+    #[test]
+    fn test_unknown_proxy_fields() {
+        let proxy = raw(r#"
+name: vmess
+type: vmess
+server: 10.0.0.13
+port: 16823
+uuid: b831381d-6324-4d53-ad4f-8cda48b30811
+alterId: 0
+cipher: auto
+udp: true
+skip-cert-verify: null
+network: ws
+ws-opts:
+  path: /api/v3/download.getFile
+  max-early-data: 2048
+  v2ray-http-upgrade: true
+packet-encoding: xudp
+"#);
+        assert_eq!(
+            unknown_fields::<OutboundProxyProtocol>("proxies.vmess", &proxy),
+            vec![
+                "proxies.vmess.packet-encoding",
+                "proxies.vmess.ws-opts.v2ray-http-upgrade",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_group_fields() {
+        let group = raw(r#"
+name: auto
+type: url-test
+proxies: [a, b]
+url: http://www.gstatic.com/generate_204
+interval: 300
+timeout: 5000
+"#);
+        assert_eq!(
+            unknown_fields::<OutboundGroupProtocol>("proxy-groups.auto", &group),
+            vec!["proxy-groups.auto.timeout"]
+        );
+
+        // can't be parsed, the conversion reports it
+        let group = raw("name: auto\ntype: url-test\nfoo: bar");
+        assert!(unknown_fields::<OutboundGroupProtocol>(
+            "proxy-groups.auto",
+            &group
+        )
+        .is_empty());
+    }
+}
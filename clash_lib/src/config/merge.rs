@@ -0,0 +1,298 @@
+//! A config spread over several files.
+//!
+//! The files given with `-f` more than once, and the files a config lists
+//! under `include:`, are merged into one, each over the ones before it:
+//!
+//! - maps are merged key by key
+//! - anything else, lists included, replaces the earlier value
+//! - a `+key` list is appended to the earlier `key` list instead of replacing
+//!   it, e.g. `+rules: [...]` adds rules after the earlier ones
+//! - a map on one side and anything else on the other is an error naming the
+//!   key path
+//!
+//! `include:` takes a path or a list of paths, relative to the file holding
+//! it. The included files are merged first, in order, and the including file
+//! goes on top of them so it can override what it includes. Anchors and `<<`
+//! merge keys work within a file only.
+
+use std::path::{Path, PathBuf};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::Error;
+
+const INCLUDE: &str = "include";
+const APPEND_PREFIX: char = '+';
+
+pub struct Loaded {
+    /// all the files merged into one
+    pub value: Value,
+    /// every file read, includes too, in the order they were merged
+    pub files: Vec<PathBuf>,
+}
+
+/// Reads and merges the given files in order.
+pub fn load_files(paths: &[PathBuf]) -> Result<Loaded, Error> {
+    let mut value = Value::Mapping(Mapping::new());
+    let mut files = vec![];
+    for path in paths {
+        let v = load_file(path, &mut vec![], &mut files)?;
+        merge(&mut value, v, &mut vec![])?;
+    }
+    Ok(Loaded { value, files })
+}
+
+/// the file with its includes merged under it, `stack` is the chain of
+/// includes leading here
+fn load_file(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> Result<Value, Error> {
+    let path = path.canonicalize().map_err(|x| {
+        Error::InvalidConfig(format!("config file {}: {}", path.display(), x))
+    })?;
+    if stack.contains(&path) {
+        return Err(Error::InvalidConfig(format!(
+            "config file {} includes itself",
+            path.display()
+        )));
+    }
+
+    let content = std::fs::read_to_string(&path)?;
+    let mut val: Value = serde_yaml::from_str(&content).map_err(|x| {
+        Error::InvalidConfig(format!(
+            "could not parse config file {}: {}",
+            path.display(),
+            x
+        ))
+    })?;
+    val.apply_merge().map_err(|x| {
+        Error::InvalidConfig(format!(
+            "failed to process anchors in config file {}: {}",
+            path.display(),
+            x
+        ))
+    })?;
+    let Value::Mapping(mut map) = val else {
+        return Err(Error::InvalidConfig(format!(
+            "config file {} is not a map",
+            path.display()
+        )));
+    };
+
+    let includes = match map.remove(INCLUDE) {
+        None => vec![],
+        Some(Value::String(s)) => vec![s],
+        Some(Value::Sequence(seq)) => seq
+            .into_iter()
+            .map(|x| match x {
+                Value::String(s) => Ok(s),
+                _ => Err(Error::InvalidConfig(format!(
+                    "include in {} must be a list of paths",
+                    path.display()
+                ))),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(Error::InvalidConfig(format!(
+                "include in {} must be a path or a list of paths",
+                path.display()
+            )))
+        }
+    };
+
+    let dir = path.parent().unwrap_or(Path::new(".")).to_owned();
+    let mut merged = Value::Mapping(Mapping::new());
+    stack.push(path.clone());
+    for inc in includes {
+        let v = load_file(&dir.join(inc), stack, files)?;
+        merge(&mut merged, v, &mut vec![])?;
+    }
+    stack.pop();
+
+    files.push(path);
+    merge(&mut merged, Value::Mapping(map), &mut vec![])?;
+    Ok(merged)
+}
+
+/// Merges `over` into `base`, `path` is where in the config this is, for the
+/// errors.
+pub fn merge(
+    base: &mut Value,
+    over: Value,
+    path: &mut Vec<String>,
+) -> Result<(), Error> {
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => merge_map(base, over, path),
+        (base, over) if base.is_mapping() || over.is_mapping() => {
+            Err(conflict(path))
+        }
+        (base, over) => {
+            *base = over;
+            Ok(())
+        }
+    }
+}
+
+fn merge_map(
+    base: &mut Mapping,
+    over: Mapping,
+    path: &mut Vec<String>,
+) -> Result<(), Error> {
+    for (k, v) in over {
+        let append = k
+            .as_str()
+            .and_then(|x| x.strip_prefix(APPEND_PREFIX))
+            .map(ToOwned::to_owned);
+        let key = match &append {
+            Some(name) => Value::String(name.clone()),
+            None => k,
+        };
+        path.push(key.as_str().unwrap_or("?").to_owned());
+
+        match (append, base.get_mut(&key)) {
+            (Some(name), existing) => {
+                let Value::Sequence(items) = v else {
+                    return Err(Error::InvalidConfig(format!(
+                        "+{} at {} must be a list",
+                        name,
+                        path.join(".")
+                    )));
+                };
+                match existing {
+                    Some(Value::Sequence(seq)) => seq.extend(items),
+                    Some(_) => {
+                        return Err(Error::InvalidConfig(format!(
+                            "+{} can't be appended to {}, it is not a list",
+                            name,
+                            path.join(".")
+                        )))
+                    }
+                    None => {
+                        base.insert(key, Value::Sequence(items));
+                    }
+                }
+            }
+            (None, Some(existing)) => merge(existing, v, path)?,
+            (None, None) if v.is_mapping() => {
+                // merged into an empty map so the `+key`s in it get resolved
+                let mut m = Value::Mapping(Mapping::new());
+                merge(&mut m, v, path)?;
+                base.insert(key, m);
+            }
+            (None, None) => {
+                base.insert(key, v);
+            }
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+fn conflict(path: &[String]) -> Error {
+    Error::InvalidConfig(format!(
+        "config files disagree on the type of {}: a map and something else",
+        if path.is_empty() {
+            "the top level".to_owned()
+        } else {
+            path.join(".")
+        }
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::{load_files, merge};
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base = yaml(
+            r#"
+port: 7890
+dns:
+  enable: true
+  nameserver: [1.1.1.1]
+rules: [a, b]
+"#,
+        );
+        merge(
+            &mut base,
+            yaml(
+                r#"
+port: 7891
+dns:
+  nameserver: [8.8.8.8]
++rules: [c]
+proxies: [x]
+"#,
+            ),
+            &mut vec![],
+        )
+        .unwrap();
+
+        assert_eq!(
+            base,
+            yaml(
+                r#"
+port: 7891
+dns:
+  enable: true
+  nameserver: [8.8.8.8]
+rules: [a, b, c]
+proxies: [x]
+"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let mut base = yaml("dns:\n  listen:\n    udp: 127.0.0.1:53");
+        let err =
+            merge(&mut base, yaml("dns:\n  listen: 127.0.0.1:53"), &mut vec![])
+                .unwrap_err();
+        assert!(err.to_string().contains("dns.listen"), "{}", err);
+
+        let mut base = yaml("rules: [a]");
+        let err = merge(&mut base, yaml("+rules: b"), &mut vec![]).unwrap_err();
+        assert!(err.to_string().contains("+rules"), "{}", err);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("proxies.yaml"),
+            "proxies: [p1]\nmode: global\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("base.yaml"),
+            "include: proxies.yaml\nmode: rule\nrules: [r1]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("local.yaml"), "+rules: [r2]\n").unwrap();
+        std::fs::write(dir.path().join("loop.yaml"), "include: [loop.yaml]\n")
+            .unwrap();
+
+        let loaded = load_files(&[
+            dir.path().join("base.yaml"),
+            dir.path().join("local.yaml"),
+        ])
+        .unwrap();
+        assert_eq!(
+            loaded.value,
+            yaml("proxies: [p1]\nmode: rule\nrules: [r1, r2]")
+        );
+        assert_eq!(loaded.files.len(), 3);
+
+        assert!(load_files(&[dir.path().join("loop.yaml")]).is_err());
+    }
+}
@@ -1,5 +1,8 @@
 pub mod def;
+mod env;
 pub mod internal;
+pub(crate) mod merge;
+pub(crate) mod remote;
 mod utils;
 pub use def::DNSListen;
 pub use internal::InternalConfig as RuntimeConfig;
@@ -1,6 +1,66 @@
 use serde::Deserialize;
 
-use std::{fmt::Display, str::FromStr};
+use std::{fmt::Display, ops::RangeInclusive, str::FromStr, time::Duration};
+
+/// what `connect-timeout` and the `dial-timeout` of the proxies may be, in
+/// seconds
+pub const DIAL_TIMEOUT_RANGE: RangeInclusive<u64> = 1..=300;
+
+pub fn check_dial_timeout(secs: u64) -> Result<Duration, String> {
+    if DIAL_TIMEOUT_RANGE.contains(&secs) {
+        Ok(Duration::from_secs(secs))
+    } else {
+        Err(format!(
+            "dial timeout must be between {} and {} seconds, got {}",
+            DIAL_TIMEOUT_RANGE.start(),
+            DIAL_TIMEOUT_RANGE.end(),
+            secs
+        ))
+    }
+}
+
+/// the ports of e.g. `8000-8010` or `443,8443,9000-9002`, in that order. A
+/// port given twice, also by overlapping ranges, is an error.
+pub fn parse_ports(ports: &str) -> Result<Vec<u16>, String> {
+    let mut rv: Vec<u16> = vec![];
+    for part in ports.split(',').map(str::trim) {
+        let parse = |x: &str| {
+            x.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|x| *x != 0)
+                .ok_or_else(|| format!("invalid port {} in {}", x, ports))
+        };
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(part)?, parse(part)?),
+        };
+        if start > end {
+            return Err(format!("invalid port range {}", part));
+        }
+        for port in start..=end {
+            if rv.contains(&port) {
+                return Err(format!("port {} is given twice in {}", port, ports));
+            }
+            rv.push(port);
+        }
+    }
+    Ok(rv)
+}
+
+/// an optional [`check_dial_timeout`]ed number of seconds
+pub fn deserialize_dial_timeout<'de, D>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let secs = Option::<u64>::deserialize(deserializer)?;
+    if let Some(secs) = secs {
+        check_dial_timeout(secs).map_err(serde::de::Error::custom)?;
+    }
+    Ok(secs)
+}
 
 pub fn deserialize_u64<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
@@ -16,34 +16,63 @@ use crate::{
     },
 };
 use app::{
-    dispatcher::StatisticsManager,
+    dispatcher::{StatisticsManager, TrafficTotals},
     dns::{SystemResolver, ThreadSafeDNSResolver},
+    logging::LogLevelHandle,
     profile,
 };
 use common::{auth, http::new_http_client, mmdb};
-use config::def::LogLevel;
-use once_cell::sync::OnceCell;
+use once_cell::sync::Lazy;
+#[cfg(feature = "tun")]
 use proxy::tun::get_tun_runner;
 
-use std::{io, path::PathBuf, sync::Arc};
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use thiserror::Error;
 use tokio::{
     sync::{broadcast, mpsc, oneshot, Mutex},
     task::JoinHandle,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 mod app;
 mod common;
 mod config;
 mod proxy;
 mod session;
+#[cfg(test)]
+mod test_support;
 
 use crate::common::geodata;
+
+/// internals measured by the benches in `benches/`
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub use crate::common::splice::splice_bidirectional;
+    pub use crate::common::{
+        io::copy_buf_bidirectional_with_timeout,
+        pool::{STREAM_BUFFER_SIZE, STREAM_POOL},
+    };
+}
+pub use app::logging::LogEvent;
 pub use config::{
-    def::{Config as ClashConfigDef, DNS as ClashDNSConfigDef},
+    def::{Config as ClashConfigDef, LogLevel, RunMode, DNS as ClashDNSConfigDef},
+    internal::Diagnostics as ClashConfigDiagnostics,
     DNSListen as ClashDNSListen, RuntimeConfig as ClashRuntimeConfig,
 };
+pub use proxy::utils::SocketProtectError;
+#[cfg(unix)]
+pub use proxy::utils::{set_socket_protector, SocketProtector};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -77,6 +106,50 @@ pub struct Options {
 pub enum TokioRuntime {
     MultiThread,
     SingleThread,
+    /// multi thread with this many workers instead of one per core
+    Workers(usize),
+    /// An already running runtime, e.g. one the embedder has, [`start`]
+    /// blocks on it so it can't be called from inside that runtime.
+    Handle(tokio::runtime::Handle),
+}
+
+impl TokioRuntime {
+    /// the `runtime` section of the config
+    pub(crate) fn from_def(c: &def::Runtime) -> Result<Self, Error> {
+        match (c.flavor, c.worker_threads) {
+            (_, Some(0)) => Err(Error::InvalidConfig(
+                "runtime worker-threads must be at least 1".to_owned(),
+            )),
+            (def::RuntimeFlavor::Current, Some(_)) => Err(Error::InvalidConfig(
+                "runtime worker-threads is only for the multi flavor".to_owned(),
+            )),
+            (def::RuntimeFlavor::Current, None) => Ok(Self::SingleThread),
+            (def::RuntimeFlavor::Multi, Some(n)) => Ok(Self::Workers(n)),
+            (def::RuntimeFlavor::Multi, None) => Ok(Self::MultiThread),
+        }
+    }
+
+    fn build(self) -> io::Result<tokio::runtime::Runtime> {
+        let mut builder = match self {
+            TokioRuntime::SingleThread => {
+                tokio::runtime::Builder::new_current_thread()
+            }
+            TokioRuntime::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+            TokioRuntime::Workers(n) => {
+                let mut b = tokio::runtime::Builder::new_multi_thread();
+                b.worker_threads(n);
+                b
+            }
+            TokioRuntime::Handle(_) => unreachable!("nothing to build"),
+        };
+        builder
+            .thread_name_fn(|| {
+                static ID: AtomicUsize = AtomicUsize::new(0);
+                format!("clash-worker-{}", ID.fetch_add(1, Ordering::Relaxed))
+            })
+            .enable_all()
+            .build()
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -84,89 +157,366 @@ pub enum Config {
     Def(ClashConfigDef),
     Internal(InternalConfig),
     File(String),
+    /// merged in order, later files override earlier ones
+    Files(Vec<String>),
+    /// fetched over http(s) every time it is loaded
+    Url(String),
     Str(String),
 }
 
 impl Config {
+    /// a copy that can be parsed again, None for already parsed configs
+    fn reloadable_copy(&self) -> Option<Config> {
+        match self {
+            Config::File(f) => Some(Config::File(f.clone())),
+            Config::Files(f) => Some(Config::Files(f.clone())),
+            Config::Url(u) => Some(Config::Url(u.clone())),
+            Config::Str(s) => Some(Config::Str(s.clone())),
+            Config::Def(_) | Config::Internal(_) => None,
+        }
+    }
+
+    /// Parses the config, a [`Config::Url`] has to go through
+    /// [`Config::load`] to be fetched first.
     pub fn try_parse(self) -> Result<InternalConfig, Error> {
         match self {
-            Config::Def(c) => c.try_into(),
             Config::Internal(c) => Ok(c),
-            Config::File(file) => {
-                TryInto::<def::Config>::try_into(PathBuf::from(file))?.try_into()
+            c => c.parse_def()?.try_into(),
+        }
+    }
+
+    fn parse_def(self) -> Result<def::Config, Error> {
+        match self {
+            Config::Def(c) => Ok(c),
+            Config::File(file) => PathBuf::from(file).try_into(),
+            Config::Files(files) => def::Config::from_files(
+                &files.into_iter().map(PathBuf::from).collect::<Vec<_>>(),
+            ),
+            Config::Str(s) => s.parse(),
+            Config::Url(url) => Err(Error::InvalidConfig(format!(
+                "config from {} has to be fetched first",
+                config::remote::redact_url(&url)
+            ))),
+            Config::Internal(_) => {
+                Err(Error::InvalidConfig("config is already parsed".to_owned()))
             }
-            Config::Str(s) => s.parse::<def::Config>()?.try_into(),
         }
     }
+
+    /// The `runtime` section, read before there is a runtime to load the
+    /// config with. None if it can't be read, loading the config later
+    /// reports why, or for a url, which would have to be fetched.
+    fn runtime(&self) -> Option<TokioRuntime> {
+        let c = match self {
+            Config::Def(c) => return TokioRuntime::from_def(&c.runtime).ok(),
+            Config::Internal(_) | Config::Url(_) => return None,
+            c => c.reloadable_copy()?.parse_def().ok()?,
+        };
+        TokioRuntime::from_def(&c.runtime).ok()
+    }
+
+    /// the files to watch for changes, None if the config isn't from files
+    fn files(&self) -> Option<Vec<PathBuf>> {
+        match self {
+            Config::File(f) => Some(vec![PathBuf::from(f)]),
+            Config::Files(f) => Some(f.iter().map(PathBuf::from).collect()),
+            _ => None,
+        }
+    }
+
+    /// Fetches the config if it is a url, or a file with `config-url:`,
+    /// falling back to the copy cached in `cwd` when that fails.
+    async fn fetch(self, cwd: &Path) -> Result<Config, Error> {
+        let remote = match self {
+            Config::Url(url) => config::remote::RemoteConfig {
+                url,
+                headers: Default::default(),
+            },
+            c @ (Config::File(_) | Config::Files(_)) => {
+                let c = c.parse_def()?;
+                match c.config_url.clone() {
+                    Some(url) => config::remote::RemoteConfig {
+                        url,
+                        headers: c.config_url_headers.clone(),
+                    },
+                    None => return Ok(Config::Def(c)),
+                }
+            }
+            c => return Ok(c),
+        };
+        let body =
+            config::remote::fetch(&remote, &cwd.join(config::remote::CACHE_FILE))
+                .await?;
+        Ok(Config::Str(body))
+    }
+
+    /// what starting and reloading go through, the config is fetched if it
+    /// is remote and the files it refers to are checked on top of parsing it
+    async fn load(self, cwd: &Path) -> Result<InternalConfig, Error> {
+        let config = self.fetch(cwd).await?.try_parse()?;
+        config.check_files(cwd).into_result()?;
+        Ok(config)
+    }
+
+    /// Runs the checks loading the config would, without starting anything
+    /// or touching the network, a [`Config::Url`] is tested from the copy
+    /// cached by the last fetch. All the problems found are returned, the
+    /// error is for a config that can't be parsed at all.
+    pub fn test(self, cwd: &Path) -> Result<ClashConfigDiagnostics, Error> {
+        let config = match self {
+            Config::Internal(c) => c,
+            Config::Url(url) => {
+                let cache = cwd.join(config::remote::CACHE_FILE);
+                let s = std::fs::read_to_string(&cache).map_err(|e| {
+                    Error::InvalidConfig(format!(
+                        "config from {} is not cached at {}: {}",
+                        config::remote::redact_url(&url),
+                        cache.display(),
+                        e
+                    ))
+                })?;
+                InternalConfig::from_def(s.parse()?)?
+            }
+            c => InternalConfig::from_def(c.parse_def()?)?,
+        };
+        let mut d = config.check();
+        d.extend(config.check_files(cwd));
+        Ok(d)
+    }
 }
 
 pub struct GlobalState {
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     log_level: LogLevel,
+    log_level_handle: Option<LogLevelHandle>,
     // must be Some otherwise we'll refuse to start
     inbound_listener_handle: JoinHandle<Result<(), Error>>,
 
     tunnel_listener_handle: Option<JoinHandle<Result<(), Error>>>,
     api_listener_handle: Option<JoinHandle<Result<(), Error>>>,
     dns_listener_handle: Option<JoinHandle<Result<(), Error>>>,
-    reload_tx: mpsc::Sender<(Config, oneshot::Sender<()>)>,
+    reload_tx: mpsc::Sender<ReloadRequest>,
+    shutdown_tx: mpsc::Sender<()>,
+    /// see [`graceful_shutdown`]
+    shutdown_grace: Duration,
+    close_connections_on_network_change: bool,
+    statistics_manager: Arc<StatisticsManager>,
+    cache_store: profile::ThreadSafeCacheFile,
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     cwd: String,
+    /// see [`InternalConfig::effective`]
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    effective_config: serde_yaml::Value,
 }
 
-pub struct RuntimeController {
+/// Handled one at a time by the reload task. The result is sent back, the
+/// error being the reason the config was rejected.
+enum ReloadRequest {
+    /// switches to a new config, unchanged proxy handlers are kept
+    Reload(Config, oneshot::Sender<Result<(), String>>),
+    /// reloads the last loaded config from where it came from, a url is
+    /// fetched again
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    Refresh(oneshot::Sender<Result<(), String>>),
+    /// tears everything down and starts again from the last loaded config
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
+    Restart(oneshot::Sender<Result<(), String>>),
+}
+
+/// how long a restart waits for open connections to finish before closing
+/// them
+const RESTART_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// What the embedding functions, [`shutdown`], [`reload`], [`set_mode`] and
+/// [`traffic`], reach the running instance through. Set once it's up, the
+/// dispatcher and the statistics are swapped on each reload.
+struct RuntimeController {
     shutdown_tx: mpsc::Sender<()>,
+    reload_tx: mpsc::Sender<ReloadRequest>,
+    dispatcher: Arc<Dispatcher>,
+    statistics_manager: Arc<StatisticsManager>,
 }
 
-static RUNTIME_CONTROLLER: OnceCell<RuntimeController> = OnceCell::new();
+static RUNTIME_CONTROLLER: std::sync::Mutex<Option<RuntimeController>> =
+    std::sync::Mutex::new(None);
+
+/// set for as long as [`start`] runs, so it can't run twice at once
+static RUNNING: AtomicBool = AtomicBool::new(false);
 
+/// shared by every start in the process, logging is only set up once
+static LOG_TX: Lazy<broadcast::Sender<LogEvent>> =
+    Lazy::new(|| broadcast::channel(100).0);
+
+/// Runs until shut down, on the runtime `opts.rt` asks for, or else the one
+/// set in the config.
 pub fn start(opts: Options) -> Result<(), Error> {
-    let rt = match opts.rt.as_ref().unwrap_or(&TokioRuntime::MultiThread) {
-        TokioRuntime::MultiThread => tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()?,
-        TokioRuntime::SingleThread => tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?,
-    };
+    start_inner(opts, None)
+}
+
+/// Starts on a thread of its own, returning once it's running, or with the
+/// reason it couldn't start. The thread ends when it's shut down.
+pub fn spawn(
+    opts: Options,
+) -> Result<std::thread::JoinHandle<Result<(), Error>>, Error> {
+    let (ready_tx, ready_rx) = std::sync::mpsc::sync_channel(1);
+    let handle = std::thread::Builder::new()
+        .name("clash".to_owned())
+        .spawn(move || start_inner(opts, Some(ready_tx)))?;
+    match ready_rx.recv() {
+        Ok(()) => Ok(handle),
+        // it stopped before it was ready
+        Err(_) => match handle.join() {
+            Ok(Err(e)) => Err(e),
+            Ok(Ok(())) => Err(Error::Operation("stopped while starting".to_owned())),
+            Err(_) => Err(Error::Operation("panicked while starting".to_owned())),
+        },
+    }
+}
 
-    rt.block_on(async {
-        match start_async(opts).await {
+fn start_inner(
+    mut opts: Options,
+    ready: Option<std::sync::mpsc::SyncSender<()>>,
+) -> Result<(), Error> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(Error::Operation("already running".to_owned()));
+    }
+    crate::defer! {
+        RUNTIME_CONTROLLER.lock().unwrap().take();
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+
+    let rt = opts
+        .rt
+        .take()
+        .or_else(|| opts.config.runtime())
+        .unwrap_or(TokioRuntime::MultiThread);
+
+    let run = async {
+        match start_async(opts, ready).await {
             Err(e) => {
                 eprintln!("start error: {}", e);
                 Err(e)
             }
             Ok(_) => Ok(()),
         }
-    })
+    };
+    match rt {
+        TokioRuntime::Handle(h) => h.block_on(run),
+        rt => rt.build()?.block_on(run),
+    }
 }
 
+/// Asks it to stop, without waiting, so it can be called from anywhere, the
+/// runtime it runs on included. False if it isn't running.
 pub fn shutdown() -> bool {
-    match RUNTIME_CONTROLLER.get() {
-        Some(controller) => controller.shutdown_tx.blocking_send(()).is_ok(),
-        _ => false,
+    let tx = match RUNTIME_CONTROLLER.lock().unwrap().as_ref() {
+        Some(controller) => controller.shutdown_tx.clone(),
+        None => return false,
+    };
+    match tx.try_send(()) {
+        // asked already
+        Ok(()) | Err(mpsc::error::TrySendError::Full(())) => true,
+        Err(mpsc::error::TrySendError::Closed(())) => false,
     }
 }
 
-async fn start_async(opts: Options) -> Result<(), Error> {
-    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
 
-    let _ = RUNTIME_CONTROLLER.get_or_init(|| RuntimeController { shutdown_tx });
+/// Switches to `config` the way `PUT /configs` does, blocking until it's in
+/// use or rejected. That can't be done on a runtime, it's an error there.
+pub fn reload(config: Config) -> Result<(), Error> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Err(Error::Operation(
+            "reload blocks, it can't be called from an async context".to_owned(),
+        ));
+    }
+    let tx = match RUNTIME_CONTROLLER.lock().unwrap().as_ref() {
+        Some(controller) => controller.reload_tx.clone(),
+        None => return Err(Error::Operation("not running".to_owned())),
+    };
+    let (done_tx, done_rx) = oneshot::channel();
+    tx.blocking_send(ReloadRequest::Reload(config, done_tx))
+        .map_err(|_| Error::Operation("not running".to_owned()))?;
+    done_rx
+        .blocking_recv()
+        .map_err(|_| Error::Operation("stopped while reloading".to_owned()))?
+        .map_err(Error::InvalidConfig)
+}
+
+/// The mode new connections are routed with, until the next reload.
+pub fn set_mode(mode: RunMode) -> Result<(), Error> {
+    let dispatcher = match RUNTIME_CONTROLLER.lock().unwrap().as_ref() {
+        Some(controller) => controller.dispatcher.clone(),
+        None => return Err(Error::Operation("not running".to_owned())),
+    };
+    dispatcher.set_mode(mode);
+    Ok(())
+}
+
+/// The log events from now on, whether it's running yet or not.
+pub fn subscribe_logs() -> broadcast::Receiver<LogEvent> {
+    LOG_TX.subscribe()
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Traffic {
+    /// bytes per second over the last second
+    pub up: i64,
+    pub down: i64,
+    /// bytes since the last reload
+    pub up_total: i64,
+    pub down_total: i64,
+}
+
+pub fn traffic() -> Option<Traffic> {
+    let controller = RUNTIME_CONTROLLER.lock().unwrap();
+    let manager = &controller.as_ref()?.statistics_manager;
+    let (up, down) = manager.now();
+    let (up_total, down_total) = manager.transferred();
+    Some(Traffic {
+        up,
+        down,
+        up_total,
+        down_total,
+    })
+}
 
-    let config: InternalConfig = opts.config.try_parse()?;
+async fn start_async(
+    opts: Options,
+    ready: Option<std::sync::mpsc::SyncSender<()>>,
+) -> Result<(), Error> {
+    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
 
     let cwd = opts.cwd.unwrap_or_else(|| ".".to_string());
 
-    let (log_tx, _) = broadcast::channel(100);
+    let mut config_source = opts.config.reloadable_copy();
+    let config: InternalConfig = opts.config.load(Path::new(&cwd)).await?;
+
+    let log_tx = LOG_TX.clone();
+
+    let recent_logs = Arc::new(app::logging::RecentLogs::new(
+        config.general.log_buffer_size,
+    ));
 
-    let log_collector = app::logging::EventCollector::new(vec![log_tx.clone()]);
+    let log_collector =
+        app::logging::EventCollector::new(vec![log_tx.clone()], recent_logs.clone());
 
-    let _g = app::logging::setup_logging(
-        config.general.log_level,
+    let (_g, log_level_handle) = match app::logging::setup_logging(
+        &config.general,
         log_collector,
         &cwd,
         opts.log_file,
-    )
-    .map_err(|x| eprintln!("failed to setup logging: {}", x))
-    .unwrap_or_default();
+    ) {
+        Ok((g, h)) => (g, Some(h)),
+        Err(e) => {
+            eprintln!("failed to setup logging: {}", e);
+            (None, None)
+        }
+    };
+
+    info!("{}", common::build_info::build_info());
 
     let default_panic = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |info| {
@@ -182,8 +532,15 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     // things we need to clone before consuming config
     let controller_cfg = config.general.controller.clone();
     let log_level = config.general.log_level;
+    let config_watch = config.general.config_watch;
+    let shutdown_grace = config.general.shutdown_grace;
+    let close_connections_on_network_change =
+        config.general.close_connections_on_network_change;
+    let effective_config = config.effective.clone();
+
+    app::net_monitor::start();
 
-    let components = create_components(cwd.clone(), config).await?;
+    let components = create_components(cwd.clone(), config, None, None).await?;
 
     let inbound_runner = components.inbound_manager.lock().await.get_runner()?;
     let inbound_listener_handle = tokio::spawn(inbound_runner);
@@ -195,17 +552,73 @@ async fn start_async(opts: Options) -> Result<(), Error> {
 
     let global_state = Arc::new(Mutex::new(GlobalState {
         log_level,
+        log_level_handle,
         inbound_listener_handle,
         tunnel_listener_handle: tun_runner_handle,
         dns_listener_handle,
         reload_tx,
+        shutdown_tx,
+        shutdown_grace,
+        close_connections_on_network_change,
+        statistics_manager: components.statistics_manager.clone(),
+        cache_store: components.cache_store.clone(),
         api_listener_handle: None,
         cwd: cwd.to_string_lossy().to_string(),
+        effective_config,
     }));
 
+    if config_watch {
+        match config_source.as_ref().and_then(Config::files) {
+            Some(files) => {
+                tokio::spawn(app::watcher::watch_config(
+                    files,
+                    cwd.clone(),
+                    global_state.lock().await.reload_tx.clone(),
+                ));
+            }
+            None => warn!("config-watch needs a config loaded from a file"),
+        }
+    }
+
+    let network_state = global_state.clone();
+    tokio::spawn(async move {
+        let mut changes = app::net_monitor::subscribe();
+        loop {
+            match changes.recv().await {
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+            let statistics_manager = {
+                let g = network_state.lock().await;
+                if !g.close_connections_on_network_change {
+                    continue;
+                }
+                g.statistics_manager.clone()
+            };
+            info!("network changed, closing all connections");
+            statistics_manager.close_all().await;
+        }
+    });
+
+    let mut running_outbound_manager = components.outbound_manager.clone();
+
+    {
+        let g = global_state.lock().await;
+        *RUNTIME_CONTROLLER.lock().unwrap() = Some(RuntimeController {
+            shutdown_tx: g.shutdown_tx.clone(),
+            reload_tx: g.reload_tx.clone(),
+            dispatcher: components.dispatcher.clone(),
+            statistics_manager: components.statistics_manager.clone(),
+        });
+    }
+
+    #[cfg(not(feature = "api"))]
+    let api_runner = no_api_runner(&controller_cfg);
+    #[cfg(feature = "api")]
     let api_runner = app::api::get_api_runner(
         controller_cfg,
         log_tx.clone(),
+        recent_logs.clone(),
         components.inbound_manager,
         components.dispatcher,
         global_state.clone(),
@@ -221,6 +634,10 @@ async fn start_async(opts: Options) -> Result<(), Error> {
         global_state.lock().await.api_listener_handle = Some(api_listener_handle);
     }
 
+    if let Some(ready) = ready {
+        let _ = ready.send(());
+    }
+
     runners.push(Box::pin(async move {
         shutdown_rx.recv().await;
         info!("receiving shutdown signal");
@@ -233,46 +650,170 @@ async fn start_async(opts: Options) -> Result<(), Error> {
 
     tasks.push(Box::pin(async move {
         let _ = tokio::signal::ctrl_c().await;
+        info!("receiving ctrl-c, press it again to close the connections now");
         Ok(())
     }));
 
+    #[cfg(unix)]
+    tasks.push(Box::pin(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+                info!("receiving SIGTERM");
+            }
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+        Ok(())
+    }));
+
+    let reload_state = global_state.clone();
+
     tasks.push(Box::pin(async move {
-        while let Some((config, done)) = reload_rx.recv().await {
-            info!("reloading config");
-            let config = match config.try_parse() {
+        while let Some(req) = reload_rx.recv().await {
+            let (restart, source, done) = match req {
+                ReloadRequest::Reload(config, done) => {
+                    info!("reloading config");
+                    (false, config, done)
+                }
+                ReloadRequest::Refresh(done) => {
+                    info!("reloading config from its source");
+                    match config_source.as_ref().and_then(Config::reloadable_copy) {
+                        Some(c) => (false, c, done),
+                        None => {
+                            let _ = done
+                                .send(Err("reloading needs a config loaded from \
+                                           a file, a url or a string"
+                                    .to_owned()));
+                            continue;
+                        }
+                    }
+                }
+                ReloadRequest::Restart(done) => {
+                    info!("restarting");
+                    match config_source.as_ref().and_then(Config::reloadable_copy) {
+                        Some(c) => (true, c, done),
+                        None => {
+                            let _ = done
+                                .send(Err("restarting needs a config loaded from \
+                                           a file, a url or a string"
+                                    .to_owned()));
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            // nothing running is touched until the new config is parsed
+            let next_source = source.reloadable_copy();
+            let config = match source.load(&cwd).await {
                 Ok(c) => c,
                 Err(e) => {
-                    error!("failed to reload config: {}", e);
+                    error!("failed to load config: {}", e);
+                    let _ = done.send(Err(e.to_string()));
                     continue;
                 }
             };
 
             let controller_cfg = config.general.controller.clone();
-
-            let new_componenets = create_components(cwd.clone(), config).await?;
-
-            done.send(()).unwrap();
+            let log_level = config.general.log_level;
+            let log_directives = config.general.log_directives.clone();
+            let shutdown_grace = config.general.shutdown_grace;
+            let close_connections_on_network_change =
+                config.general.close_connections_on_network_change;
+            let effective_config = config.effective.clone();
+            let totals = reload_state.lock().await.statistics_manager.totals();
+
+            let new_componenets = if restart {
+                // everything is stopped first so the new components can take
+                // over the ports and the tun device
+                let statistics_manager = {
+                    let mut g = reload_state.lock().await;
+                    stop_listeners(&mut g);
+                    g.statistics_manager.clone()
+                };
+                drain_connections(
+                    &statistics_manager,
+                    RESTART_GRACE_PERIOD,
+                    std::future::pending(),
+                )
+                .await;
+
+                match create_components(cwd.clone(), config, None, Some(totals))
+                    .await
+                {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("failed to restart, shutting down: {}", e);
+                        let _ = done.send(Err(e.to_string()));
+                        return Err(e);
+                    }
+                }
+            } else {
+                match create_components(
+                    cwd.clone(),
+                    config,
+                    Some(running_outbound_manager.clone()),
+                    Some(totals),
+                )
+                .await
+                {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("failed to reload config: {}", e);
+                        let _ = done.send(Err(e.to_string()));
+                        continue;
+                    }
+                }
+            };
+            running_outbound_manager = new_componenets.outbound_manager.clone();
+            config_source = next_source.or(config_source);
+
+            // a reload answers right away, a restart once its listeners are up
+            let done = if restart {
+                Some(done)
+            } else {
+                let _ = done.send(Ok(()));
+                None
+            };
 
             debug!("stopping listeners");
-            let mut g = global_state.lock().await;
-            g.inbound_listener_handle.abort();
-            if let Some(h) = g.tunnel_listener_handle.take() {
-                h.abort();
-            }
-            if let Some(h) = g.dns_listener_handle.take() {
-                h.abort();
+            let mut g = reload_state.lock().await;
+            stop_listeners(&mut g);
+            g.shutdown_grace = shutdown_grace;
+            g.close_connections_on_network_change =
+                close_connections_on_network_change;
+            g.statistics_manager = new_componenets.statistics_manager.clone();
+            g.cache_store = new_componenets.cache_store.clone();
+            if let Some(c) = RUNTIME_CONTROLLER.lock().unwrap().as_mut() {
+                c.dispatcher = new_componenets.dispatcher.clone();
+                c.statistics_manager = new_componenets.statistics_manager.clone();
             }
-            if let Some(h) = g.api_listener_handle.take() {
-                h.abort();
+
+            if let Some(h) = &g.log_level_handle {
+                h.set_directives(log_directives);
             }
+            set_log_level(&mut g, log_level);
+            g.effective_config = effective_config;
 
             debug!("reloading inbound listener");
-            let inbound_listener_handle = new_componenets
-                .inbound_manager
-                .lock()
-                .await
-                .get_runner()
-                .map(tokio::spawn)?;
+            let inbound_listener_handle =
+                match new_componenets.inbound_manager.lock().await.get_runner() {
+                    Ok(r) => tokio::spawn(r),
+                    Err(e) => {
+                        error!(
+                            "failed to start inbound listeners, shutting down: {}",
+                            e
+                        );
+                        if let Some(done) = done {
+                            let _ = done.send(Err(e.to_string()));
+                        }
+                        return Err(e);
+                    }
+                };
 
             debug!("reloading tun runner");
             let tun_runner_handle = new_componenets.tun_runner.map(tokio::spawn);
@@ -281,12 +822,17 @@ async fn start_async(opts: Options) -> Result<(), Error> {
             let dns_listener_handle = new_componenets.dns_listener.map(tokio::spawn);
 
             debug!("reloading api listener");
+            #[cfg(not(feature = "api"))]
+            let api_listener_handle =
+                no_api_runner(&controller_cfg).map(tokio::spawn);
+            #[cfg(feature = "api")]
             let api_listener_handle = app::api::get_api_runner(
                 controller_cfg,
                 log_tx.clone(),
+                recent_logs.clone(),
                 new_componenets.inbound_manager,
                 new_componenets.dispatcher,
-                global_state.clone(),
+                reload_state.clone(),
                 new_componenets.dns_resolver,
                 new_componenets.outbound_manager,
                 new_componenets.statistics_manager,
@@ -300,6 +846,11 @@ async fn start_async(opts: Options) -> Result<(), Error> {
             g.tunnel_listener_handle = tun_runner_handle;
             g.dns_listener_handle = dns_listener_handle;
             g.api_listener_handle = api_listener_handle;
+
+            if let Some(done) = done {
+                info!("restarted");
+                let _ = done.send(Ok(()));
+            }
         }
         Ok(())
     }));
@@ -307,13 +858,121 @@ async fn start_async(opts: Options) -> Result<(), Error> {
     futures::future::select_all(tasks).await.0.map_err(|x| {
         error!("runtime error: {}, shutting down", x);
         x
+    })?;
+
+    graceful_shutdown(&global_state, async {
+        let _ = tokio::signal::ctrl_c().await;
     })
+    .await;
+    info!("bye");
+    Ok(())
+}
+
+fn set_log_level(g: &mut GlobalState, level: LogLevel) {
+    if let Some(h) = &g.log_level_handle {
+        if let Err(e) = h.set_level(level) {
+            error!("{}", e);
+            return;
+        }
+    }
+    g.log_level = level;
+}
+
+/// the api listener only stops accepting, requests in flight, e.g. the one
+/// asking for a restart, still get their response
+fn stop_listeners(g: &mut GlobalState) {
+    g.inbound_listener_handle.abort();
+    if let Some(h) = g.tunnel_listener_handle.take() {
+        h.abort();
+    }
+    if let Some(h) = g.dns_listener_handle.take() {
+        h.abort();
+    }
+    if let Some(h) = g.api_listener_handle.take() {
+        h.abort();
+    }
+}
+
+/// waits up to `grace` for the open connections to finish, or until `hurry`
+/// resolves, then closes the rest
+async fn drain_connections(
+    statistics_manager: &StatisticsManager,
+    grace: Duration,
+    hurry: impl Future<Output = ()>,
+) {
+    let deadline = tokio::time::Instant::now() + grace;
+    let wait = async {
+        while statistics_manager.connection_count().await > 0
+            && tokio::time::Instant::now() < deadline
+        {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::select! {
+        _ = wait => {}
+        _ = hurry => info!("closing the open connections now"),
+    }
+    statistics_manager.close_all().await;
+}
+
+/// Stops accepting on every listener and gives the open connections the
+/// `shutdown-grace` period to finish, then closes the rest and writes the
+/// cache out, fake ips included. The tun goes last, with its routes, as it
+/// carries its connections until then.
+async fn graceful_shutdown(
+    global_state: &Mutex<GlobalState>,
+    hurry: impl Future<Output = ()>,
+) {
+    let (tun, statistics_manager, cache_store, grace) = {
+        let mut g = global_state.lock().await;
+        let tun = g.tunnel_listener_handle.take();
+        stop_listeners(&mut g);
+        (
+            tun,
+            g.statistics_manager.clone(),
+            g.cache_store.clone(),
+            g.shutdown_grace,
+        )
+    };
+    statistics_manager.stop_accepting();
+
+    let open = statistics_manager.connection_count().await;
+    if open > 0 {
+        info!(
+            "shutting down, waiting up to {:?} for {} connections to finish",
+            grace, open
+        );
+    }
+    drain_connections(&statistics_manager, grace, hurry).await;
+
+    statistics_manager.totals().save().await;
+    cache_store.flush().await;
+
+    if let Some(tun) = tun {
+        tun.abort();
+        let _ = tun.await;
+    }
+}
+
+/// a build without the api can't serve the `external-controller` asked for
+#[cfg(not(feature = "api"))]
+fn no_api_runner(
+    controller_cfg: &config::internal::config::Controller,
+) -> Option<Runner> {
+    if controller_cfg.external_controller.is_some()
+        || controller_cfg.external_controller_unix.is_some()
+    {
+        warn!("compiled without api support, the external controller is off");
+    }
+    None
 }
 
 struct RuntimeComponents {
     cache_store: profile::ThreadSafeCacheFile,
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     dns_resolver: ThreadSafeDNSResolver,
     outbound_manager: Arc<OutboundManager>,
+    #[cfg_attr(not(feature = "api"), allow(dead_code))]
     router: Arc<Router>,
     dispatcher: Arc<Dispatcher>,
     statistics_manager: Arc<StatisticsManager>,
@@ -326,6 +985,8 @@ struct RuntimeComponents {
 async fn create_components(
     cwd: PathBuf,
     config: InternalConfig,
+    previous_outbound_manager: Option<Arc<OutboundManager>>,
+    previous_totals: Option<Arc<TrafficTotals>>,
 ) -> Result<RuntimeComponents, Error> {
     let system_resolver = Arc::new(
         SystemResolver::new(config.dns.ipv6)
@@ -355,16 +1016,23 @@ async fn create_components(
 
     debug!("initializing cache store");
     let cache_store = profile::ThreadSafeCacheFile::new(
-        cwd.join("cache.db").as_path().to_str().unwrap(),
+        cwd.join(&config.profile.cache_file_path)
+            .as_path()
+            .to_str()
+            .unwrap(),
         config.profile.store_selected,
     );
 
     let dns_listen = config.dns.listen.clone();
+    let mut dns_config = config.dns;
+    dns_config.hosts_files =
+        dns_config.hosts_files.iter().map(|x| cwd.join(x)).collect();
     debug!("initializing dns resolver");
     let dns_resolver = dns::new_resolver(
-        config.dns,
+        dns_config,
         Some(cache_store.clone()),
         Some(country_mmdb.clone()),
+        Some(geodata.clone()),
     )
     .await;
 
@@ -392,6 +1060,9 @@ async fn create_components(
             dns_resolver.clone(),
             cache_store.clone(),
             cwd.to_string_lossy().to_string(),
+            previous_outbound_manager,
+            config.general.unified_delay,
+            config.general.global_client_fingerprint,
         )
         .await?,
     );
@@ -412,6 +1083,7 @@ async fn create_components(
         Router::new(
             config.rules,
             config.rule_providers,
+            config.general.port_hints,
             dns_resolver.clone(),
             country_mmdb,
             asn_mmdb,
@@ -420,8 +1092,29 @@ async fn create_components(
         )
         .await,
     );
+    dns_resolver.set_rule_providers(router.rule_providers().clone());
 
-    let statistics_manager = StatisticsManager::new();
+    // the stored totals are only loaded on start, after that the ones in
+    // memory are carried over
+    let (totals, load) = match previous_totals {
+        Some(totals) => (totals, false),
+        None => (TrafficTotals::new(), true),
+    };
+    totals
+        .store_in(
+            config.profile.store_statistics.then(|| cache_store.clone()),
+            load,
+        )
+        .await;
+    let statistics_manager = StatisticsManager::with_totals(totals);
+    statistics_manager.set_log_connections(config.general.log_connections);
+    statistics_manager.set_client_names(config.general.clients.clone());
+    statistics_manager.set_tcp_idle(config.general.tcp_idle.clone());
+    proxy::utils::set_connect_timeout(config.general.connect_timeout);
+    proxy::utils::set_socket_buffers(proxy::utils::SocketBuffers::new(
+        Some(config.general.socket_send_buffer_size),
+        Some(config.general.socket_recv_buffer_size),
+    ));
 
     debug!("initializing dispatcher");
     let dispatcher = Arc::new(Dispatcher::new(
@@ -429,6 +1122,9 @@ async fn create_components(
         router.clone(),
         dns_resolver.clone(),
         config.general.mode,
+        config.general.udp_sessions,
+        config.general.udp_fallback.clone(),
+        config.general.inbound.listener_bandwidth.clone(),
         statistics_manager.clone(),
     ));
 
@@ -440,11 +1136,21 @@ async fn create_components(
         config.general.inbound,
         dispatcher.clone(),
         authenticator,
+        &cwd,
     )?));
 
     debug!("initializing tun runner");
+    #[cfg(feature = "tun")]
     let tun_runner =
         get_tun_runner(config.tun, dispatcher.clone(), dns_resolver.clone())?;
+    #[cfg(not(feature = "tun"))]
+    let tun_runner: Option<Runner> = if config.tun.enable {
+        return Err(Error::InvalidConfig(
+            "compiled without tun support".to_owned(),
+        ));
+    } else {
+        None
+    };
 
     debug!("initializing dns listener");
     let dns_listener =
@@ -466,8 +1172,21 @@ async fn create_components(
 
 #[cfg(test)]
 mod tests {
-    use crate::{shutdown, start, Config, Options};
-    use std::{sync::Once, thread, time::Duration};
+    use crate::{
+        app::{
+            dispatcher::{ChainedStreamWrapper, StatisticsManager, TrackedStream},
+            profile,
+        },
+        config::def::LogLevel,
+        graceful_shutdown, shutdown, start, Config, Error, GlobalState, Options,
+    };
+    use std::{future::Future, sync::Once, thread, time::Duration};
+    use tokio::{
+        io::AsyncReadExt,
+        sync::{mpsc, Mutex},
+        task::JoinHandle,
+        time::Instant,
+    };
 
     static INIT: Once = Once::new();
 
@@ -503,4 +1222,82 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    fn listener() -> JoinHandle<Result<(), Error>> {
+        tokio::spawn(std::future::pending())
+    }
+
+    /// Shuts down with a connection that only ends once closed, returns
+    /// how long it took.
+    async fn shutdown_with_open_connection(
+        hurry: impl Future<Output = ()>,
+    ) -> Duration {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("cache.db");
+        let cache_store =
+            profile::ThreadSafeCacheFile::new(cache_path.to_str().unwrap(), true);
+        cache_store.set_selected("group", "proxy").await;
+
+        let statistics_manager = StatisticsManager::new();
+        let (a, _b) = tokio::io::duplex(1024);
+        let mut conn = TrackedStream::new(
+            Box::new(ChainedStreamWrapper::new(a)),
+            statistics_manager.clone(),
+            Default::default(),
+            None,
+        )
+        .await;
+        let conn = tokio::spawn(async move {
+            let mut buf = [0; 1];
+            let _ = conn.read(&mut buf).await;
+        });
+
+        let global_state = Mutex::new(GlobalState {
+            log_level: LogLevel::Info,
+            log_level_handle: None,
+            inbound_listener_handle: listener(),
+            tunnel_listener_handle: Some(listener()),
+            api_listener_handle: Some(listener()),
+            dns_listener_handle: None,
+            reload_tx: mpsc::channel(1).0,
+            shutdown_tx: mpsc::channel(1).0,
+            shutdown_grace: Duration::from_secs(10),
+            close_connections_on_network_change: false,
+            statistics_manager: statistics_manager.clone(),
+            cache_store,
+            cwd: ".".to_owned(),
+            effective_config: Default::default(),
+        });
+
+        let start = Instant::now();
+        graceful_shutdown(&global_state, hurry).await;
+        let took = start.elapsed();
+
+        assert!(!statistics_manager.is_accepting());
+        // the open connection is closed and the cache written
+        conn.await.unwrap();
+        assert!(cache_path.exists());
+        let g = global_state.lock().await;
+        assert!(g.tunnel_listener_handle.is_none());
+        assert!(g.api_listener_handle.is_none());
+        tokio::task::yield_now().await;
+        assert!(g.inbound_listener_handle.is_finished());
+        took
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_graceful_shutdown_waits_grace() {
+        let took = shutdown_with_open_connection(std::future::pending()).await;
+        assert!(took >= Duration::from_secs(10), "took {:?}", took);
+        assert!(took < Duration::from_secs(11), "took {:?}", took);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_graceful_shutdown_hurried() {
+        let took = shutdown_with_open_connection(async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        })
+        .await;
+        assert!(took < Duration::from_secs(2), "took {:?}", took);
+    }
 }
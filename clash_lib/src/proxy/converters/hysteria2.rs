@@ -131,6 +131,7 @@ impl TryFrom<OutboundHysteria2> for AnyOutboundHandler {
             up_down: value.up.zip(value.down),
             ca_str: value.ca_str,
             cwnd: value.cwnd,
+            dial_timeout: value.dial_timeout.map(std::time::Duration::from_secs),
         };
 
         let c = Handler::new(opts).unwrap();
@@ -1,11 +1,15 @@
+#[cfg(feature = "quic-protocols")]
 pub mod hysteria2;
 #[cfg(feature = "shadowsocks")]
 pub mod shadowsocks;
 pub mod socks5;
 #[cfg(feature = "onion")]
 pub mod tor;
+#[cfg(feature = "trojan")]
 pub mod trojan;
 #[cfg(feature = "tuic")]
 pub mod tuic;
+#[cfg(feature = "vmess")]
 pub mod vmess;
+#[cfg(feature = "wireguard")]
 pub mod wireguard;
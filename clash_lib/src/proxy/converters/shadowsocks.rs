@@ -1,12 +1,15 @@
 use std::collections::HashMap;
 
+use base64::{engine::general_purpose::STANDARD, Engine};
+
 use crate::{
-    config::internal::proxy::OutboundShadowsocks,
+    config::internal::proxy::{server_candidates, OutboundShadowsocks},
     proxy::{
         shadowsocks::{
-            Handler, HandlerOptions, OBFSOption, ShadowTlsOption, SimpleOBFSMode,
-            SimpleOBFSOption, V2RayOBFSOption,
+            cipher_kind, Handler, HandlerOptions, OBFSOption, ShadowTlsOption,
+            SimpleOBFSMode, SimpleOBFSOption, Sip003Option, V2RayOBFSOption,
         },
+        utils::SocketBuffers,
         HandlerCommonOptions,
     },
     Error,
@@ -24,10 +27,36 @@ impl TryFrom<&OutboundShadowsocks> for Handler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundShadowsocks) -> Result<Self, Self::Error> {
+        check_key(&s.cipher, &s.password)?;
+        let servers = server_candidates(
+            &s.common_opts,
+            s.servers.as_deref(),
+            s.ports.as_deref(),
+        )?;
+        if servers.len() > 1 && s.plugin.as_deref() == Some("sip003") {
+            return Err(Error::InvalidConfig(format!(
+                "proxy {}: a sip003 plugin dials just the one server",
+                s.common_opts.name
+            )));
+        }
+
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                resolve_strategy: s.common_opts.resolve_strategy,
+                dial_timeout: s
+                    .common_opts
+                    .dial_timeout
+                    .map(std::time::Duration::from_secs),
+                handshake_timeout: s
+                    .common_opts
+                    .handshake_timeout
+                    .map(std::time::Duration::from_secs),
+                socket_buffers: SocketBuffers::new(
+                    s.common_opts.socket_send_buffer_size,
+                    s.common_opts.socket_recv_buffer_size,
+                ),
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -68,6 +97,15 @@ impl TryFrom<&OutboundShadowsocks> for Handler {
                         .try_into()
                         .map(OBFSOption::ShadowTls)
                         .ok(),
+                    "sip003" => Some(OBFSOption::Sip003(
+                        s.plugin_opts
+                            .clone()
+                            .ok_or(Error::InvalidConfig(
+                                "plugin_opts is required for plugin sip003"
+                                    .to_owned(),
+                            ))?
+                            .try_into()?,
+                    )),
                     _ => {
                         return Err(Error::InvalidConfig(format!(
                             "unsupported plugin: {}",
@@ -78,11 +116,35 @@ impl TryFrom<&OutboundShadowsocks> for Handler {
                 None => None,
             },
             udp: s.udp,
+            servers: if servers.len() > 1 { servers } else { vec![] },
         });
         Ok(h)
     }
 }
 
+/// The key is only derived on the first dial, this checks the cipher and
+/// that a 2022 key is base64 of the right length without deriving it.
+fn check_key(cipher: &str, password: &str) -> Result<(), Error> {
+    let kind = cipher_kind(cipher).ok_or_else(|| {
+        Error::InvalidConfig(format!("unsupported cipher: {}", cipher))
+    })?;
+    if !cipher.starts_with("2022-") {
+        return Ok(());
+    }
+    // identity keys come first, separated by ':'
+    for key in password.split(':') {
+        let len = STANDARD.decode(key).map(|k| k.len()).unwrap_or(0);
+        if len != kind.key_len() {
+            return Err(Error::InvalidConfig(format!(
+                "{} expects a base64 encoded {} bytes key",
+                cipher,
+                kind.key_len()
+            )));
+        }
+    }
+    Ok(())
+}
+
 impl TryFrom<HashMap<String, serde_yaml::Value>> for SimpleOBFSOption {
     type Error = crate::Error;
 
@@ -168,6 +230,41 @@ impl TryFrom<HashMap<String, serde_yaml::Value>> for V2RayOBFSOption {
     }
 }
 
+impl TryFrom<HashMap<String, serde_yaml::Value>> for Sip003Option {
+    type Error = crate::Error;
+
+    fn try_from(
+        value: HashMap<String, serde_yaml::Value>,
+    ) -> Result<Self, Self::Error> {
+        let command = value.get("command").and_then(|x| x.as_str()).ok_or(
+            Error::InvalidConfig("sip003 command is required".to_owned()),
+        )?;
+        let args = match value.get("args") {
+            Some(args) => args
+                .as_sequence()
+                .and_then(|x| {
+                    x.iter()
+                        .map(|x| x.as_str().map(ToOwned::to_owned))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or(Error::InvalidConfig(
+                    "sip003 args must be a list of strings".to_owned(),
+                ))?,
+            None => vec![],
+        };
+        let options = value
+            .get("opts")
+            .and_then(|x| x.as_str())
+            .map(ToOwned::to_owned);
+
+        Ok(Sip003Option {
+            command: command.to_owned(),
+            args,
+            options,
+        })
+    }
+}
+
 impl TryFrom<HashMap<String, serde_yaml::Value>> for ShadowTlsOption {
     type Error = crate::Error;
 
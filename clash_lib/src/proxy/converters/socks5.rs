@@ -1,9 +1,12 @@
 use crate::{
-    config::internal::proxy::OutboundSocks5,
+    common::tls::parse_pins,
+    config::{def::ClientFingerprint, internal::proxy::OutboundSocks5},
     proxy::{
         socks::{Handler, HandlerOptions},
+        utils::SocketBuffers,
         HandlerCommonOptions,
     },
+    Error,
 };
 
 impl TryFrom<OutboundSocks5> for Handler {
@@ -18,10 +21,27 @@ impl TryFrom<&OutboundSocks5> for Handler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundSocks5) -> Result<Self, Self::Error> {
+        let pinned_cert_chain_sha256 =
+            parse_pins(s.pinned_cert_chain_sha256.as_deref().unwrap_or_default())
+                .map_err(Error::InvalidConfig)?;
+
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                resolve_strategy: s.common_opts.resolve_strategy,
+                dial_timeout: s
+                    .common_opts
+                    .dial_timeout
+                    .map(std::time::Duration::from_secs),
+                handshake_timeout: s
+                    .common_opts
+                    .handshake_timeout
+                    .map(std::time::Duration::from_secs),
+                socket_buffers: SocketBuffers::new(
+                    s.common_opts.socket_send_buffer_size,
+                    s.common_opts.socket_recv_buffer_size,
+                ),
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -32,6 +52,10 @@ impl TryFrom<&OutboundSocks5> for Handler {
             tls: s.tls,
             sni: s.sni.clone().unwrap_or(s.common_opts.server.to_owned()),
             skip_cert_verify: s.skip_cert_verify,
+            pinned_cert_chain_sha256,
+            pinned_cert_only: s.pinned_cert_only,
+            client_fingerprint: s.client_fingerprint.map(ClientFingerprint::pick),
+            udp_nat: s.udp_nat,
         });
         Ok(h)
     }
@@ -1,10 +1,15 @@
 use tracing::warn;
 
 use crate::{
-    config::internal::proxy::OutboundTrojan,
+    common::tls::parse_pins,
+    config::{
+        def::ClientFingerprint,
+        internal::proxy::{server_candidates, OutboundTrojan},
+    },
     proxy::{
         options::{GrpcOption, WsOption},
         trojan::{Handler, HandlerOptions, Transport},
+        utils::SocketBuffers,
         HandlerCommonOptions,
     },
     Error,
@@ -30,10 +35,38 @@ impl TryFrom<&OutboundTrojan> for Handler {
             );
         }
 
+        // the TLS config is only built on the first dial, a bad name is
+        // caught here instead
+        let sni = s.sni.as_ref().unwrap_or(&s.common_opts.server);
+        rustls::pki_types::ServerName::try_from(sni.as_str()).map_err(|_| {
+            Error::InvalidConfig(format!("invalid trojan sni: {}", sni))
+        })?;
+        let pinned_cert_chain_sha256 =
+            parse_pins(s.pinned_cert_chain_sha256.as_deref().unwrap_or_default())
+                .map_err(Error::InvalidConfig)?;
+        let servers = server_candidates(
+            &s.common_opts,
+            s.servers.as_deref(),
+            s.ports.as_deref(),
+        )?;
+
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                resolve_strategy: s.common_opts.resolve_strategy,
+                dial_timeout: s
+                    .common_opts
+                    .dial_timeout
+                    .map(std::time::Duration::from_secs),
+                handshake_timeout: s
+                    .common_opts
+                    .handshake_timeout
+                    .map(std::time::Duration::from_secs),
+                socket_buffers: SocketBuffers::new(
+                    s.common_opts.socket_send_buffer_size,
+                    s.common_opts.socket_recv_buffer_size,
+                ),
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -47,6 +80,10 @@ impl TryFrom<&OutboundTrojan> for Handler {
                 .unwrap_or(s.common_opts.server.to_owned()),
             alpn: s.alpn.as_ref().map(|x| x.to_owned()),
             skip_cert_verify,
+            pinned_cert_chain_sha256,
+            pinned_cert_only: s.pinned_cert_only.unwrap_or_default(),
+            client_fingerprint: s.client_fingerprint.map(ClientFingerprint::pick),
+            servers: if servers.len() > 1 { servers } else { vec![] },
             transport: s
                 .network
                 .as_ref()
@@ -27,6 +27,11 @@ impl TryFrom<&OutboundTuic> for Handler {
             server: s.common_opts.server.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                resolve_strategy: s.common_opts.resolve_strategy,
+                dial_timeout: s
+                    .common_opts
+                    .dial_timeout
+                    .map(std::time::Duration::from_secs),
                 ..Default::default()
             },
             port: s.common_opts.port,
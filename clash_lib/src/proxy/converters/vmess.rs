@@ -1,10 +1,12 @@
 use tracing::warn;
 
 use crate::{
-    config::internal::proxy::OutboundVmess,
+    common::tls::parse_pins,
+    config::{def::ClientFingerprint, internal::proxy::OutboundVmess},
     proxy::{
         options::{GrpcOption, Http2Option, WsOption},
         transport::TLSOptions,
+        utils::SocketBuffers,
         vmess::{Handler, HandlerOptions, VmessTransport},
         HandlerCommonOptions,
     },
@@ -30,11 +32,27 @@ impl TryFrom<&OutboundVmess> for Handler {
                 s.common_opts.server
             );
         }
+        let pinned_cert_chain_sha256 =
+            parse_pins(s.pinned_cert_chain_sha256.as_deref().unwrap_or_default())
+                .map_err(Error::InvalidConfig)?;
 
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                resolve_strategy: s.common_opts.resolve_strategy,
+                dial_timeout: s
+                    .common_opts
+                    .dial_timeout
+                    .map(std::time::Duration::from_secs),
+                handshake_timeout: s
+                    .common_opts
+                    .handshake_timeout
+                    .map(std::time::Duration::from_secs),
+                socket_buffers: SocketBuffers::new(
+                    s.common_opts.socket_send_buffer_size,
+                    s.common_opts.socket_recv_buffer_size,
+                ),
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -152,6 +170,9 @@ impl TryFrom<&OutboundVmess> for Handler {
                             ))),
                         })
                         .transpose()?,
+                    pinned_cert_chain_sha256,
+                    pinned_cert_only: s.pinned_cert_only.unwrap_or_default(),
+                    fingerprint: s.client_fingerprint.map(ClientFingerprint::pick),
                 }),
                 false => None,
             },
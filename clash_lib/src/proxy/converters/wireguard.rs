@@ -25,6 +25,7 @@ impl TryFrom<&OutboundWireguard> for Handler {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                resolve_strategy: s.common_opts.resolve_strategy,
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -1,15 +1,18 @@
 use crate::{
-    app::dns::ThreadSafeDNSResolver, common::errors::new_io_error,
+    app::dns::ThreadSafeDNSResolver,
+    common::{errors::new_io_error, pool::DATAGRAM_POOL},
     session::SocksAddr,
 };
 use futures::{ready, Sink, Stream};
 use std::{
     fmt::{Debug, Display, Formatter},
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
 use tokio::{io::ReadBuf, net::UdpSocket};
+use tracing::debug;
 
 #[derive(Clone)]
 pub struct UdpPacket {
@@ -67,6 +70,8 @@ pub struct OutboundDatagramImpl {
     resolver: ThreadSafeDNSResolver,
     flushed: bool,
     pkt: Option<UdpPacket>,
+    /// what the socket is connected to, if it is
+    peer: Option<SocketAddr>,
 }
 
 impl OutboundDatagramImpl {
@@ -76,6 +81,21 @@ impl OutboundDatagramImpl {
             resolver,
             flushed: true,
             pkt: None,
+            peer: None,
+        }
+    }
+
+    /// For a socket connected to `peer`. Everything is sent to it whatever
+    /// the packets say, only it can answer, and the ICMP errors it triggers,
+    /// e.g. port unreachable, end the stream instead of being dropped.
+    pub fn connected(
+        udp: UdpSocket,
+        peer: SocketAddr,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Self {
+        Self {
+            peer: Some(peer),
+            ..Self::new(udp, resolver)
         }
     }
 }
@@ -116,6 +136,7 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
             ref mut inner,
             ref mut pkt,
             ref resolver,
+            peer,
             ..
         } = *self;
 
@@ -123,8 +144,10 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
             let p = pkt.as_ref().unwrap();
             let dst = &p.dst_addr;
             let data = &p.data;
-            let dst = match dst {
-                SocksAddr::Domain(domain, port) => {
+            let dst = match (peer, dst) {
+                // whatever the packet says
+                (Some(peer), _) => peer,
+                (None, SocksAddr::Domain(domain, port)) => {
                     let domain = domain.to_string();
                     let port = *port;
                     let mut fut = resolver.resolve(domain.as_str(), false);
@@ -140,10 +163,13 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
                         )));
                     }
                 }
-                SocksAddr::Ip(addr) => *addr,
+                (None, SocksAddr::Ip(addr)) => *addr,
             };
 
-            let n = ready!(inner.poll_send_to(cx, data.as_slice(), dst))?;
+            let n = match peer {
+                Some(_) => ready!(inner.poll_send(cx, data.as_slice()))?,
+                None => ready!(inner.poll_send_to(cx, data.as_slice(), dst))?,
+            };
             let wrote_all = n == data.len();
             self.pkt = None;
             self.flushed = true;
@@ -180,10 +206,18 @@ impl Stream for OutboundDatagramImpl {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let Self { ref mut inner, .. } = *self;
-        let mut mem = vec![0u8; 65535];
+        let Self {
+            ref mut inner,
+            peer,
+            ..
+        } = *self;
+        let mut mem = DATAGRAM_POOL.get(65535);
         let mut buf = ReadBuf::new(&mut mem);
-        match ready!(inner.poll_recv_from(cx, &mut buf)) {
+        let res = match peer {
+            Some(peer) => ready!(inner.poll_recv(cx, &mut buf)).map(|_| peer),
+            None => ready!(inner.poll_recv_from(cx, &mut buf)),
+        };
+        match res {
             Ok(src) => {
                 let data = buf.filled().to_vec();
                 Poll::Ready(Some(UdpPacket {
@@ -192,7 +226,10 @@ impl Stream for OutboundDatagramImpl {
                     dst_addr: SocksAddr::any_ipv4(),
                 }))
             }
-            Err(_) => Poll::Ready(None),
+            Err(e) => {
+                debug!("outbound datagram ended: {}", e);
+                Poll::Ready(None)
+            }
         }
     }
 }
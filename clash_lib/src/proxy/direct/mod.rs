@@ -1,4 +1,7 @@
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
 use crate::{
     app::{
@@ -9,10 +12,10 @@ use crate::{
         dns::ThreadSafeDNSResolver,
     },
     common::errors::map_io_error,
-    config::internal::proxy::PROXY_DIRECT,
+    config::{def::UdpNat, internal::proxy::PROXY_DIRECT},
     proxy::{
         datagram::OutboundDatagramImpl,
-        utils::{new_tcp_stream, new_udp_socket},
+        utils::{new_tcp_stream, new_udp_socket, DialError},
         OutboundHandler,
     },
     session::Session,
@@ -57,6 +60,10 @@ impl OutboundHandler for Handler {
         true
     }
 
+    fn udp_nat(&self) -> Option<UdpNat> {
+        None
+    }
+
     async fn connect_stream(
         &self,
         sess: &Session,
@@ -64,11 +71,9 @@ impl OutboundHandler for Handler {
     ) -> std::io::Result<BoxedChainedStream> {
         let remote_ip = resolver
             .resolve(sess.destination.host().as_str(), false)
-            .map_err(map_io_error)
+            .map_err(|e| DialError::Dns.tag(map_io_error(e)))
             .await?
-            .ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::Other, "no dns result")
-            })?;
+            .ok_or_else(|| DialError::Dns.error("no dns result"))?;
 
         let s = new_tcp_stream(
             (remote_ip, sess.destination.port()).into(),
@@ -88,14 +93,39 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedDatagram> {
-        let d = new_udp_socket(
-            None,
-            sess.iface.clone(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            sess.so_mark,
-        )
-        .await
-        .map(|x| OutboundDatagramImpl::new(x, resolver))?;
+        // connected when it only goes to the one destination, so the kernel
+        // drops what others send and tells us when the peer is unreachable
+        let d = if sess.fixed_destination {
+            let remote_ip = resolver
+                .resolve(sess.destination.host().as_str(), false)
+                .map_err(|e| DialError::Dns.tag(map_io_error(e)))
+                .await?
+                .ok_or_else(|| DialError::Dns.error("no dns result"))?;
+            let peer = SocketAddr::new(remote_ip, sess.destination.port());
+            let unspecified: IpAddr = match remote_ip {
+                IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+
+            let socket = new_udp_socket(
+                Some((unspecified, 0).into()),
+                sess.iface.clone(),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                sess.so_mark,
+            )
+            .await?;
+            socket.connect(peer).await?;
+            OutboundDatagramImpl::connected(socket, peer, resolver)
+        } else {
+            new_udp_socket(
+                None,
+                sess.iface.clone(),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                sess.so_mark,
+            )
+            .await
+            .map(|x| OutboundDatagramImpl::new(x, resolver))?
+        };
 
         let d = ChainedDatagramWrapper::new(d);
         d.append_to_chain(self.name()).await;
@@ -148,3 +178,98 @@ impl OutboundHandler for Handler {
         Ok(Box::new(d))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        app::dns::MockClashResolver,
+        proxy::{datagram::UdpPacket, OutboundHandler},
+        session::{Session, SocksAddr},
+    };
+
+    use super::Handler;
+
+    /// a peer the client never sent anything to reaches it through the
+    /// mapping another peer learnt, as with full-cone NAT
+    #[tokio::test]
+    async fn test_udp_from_any_peer() {
+        let peer_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut d = Handler::new()
+            .connect_datagram(
+                &Session::default(),
+                Arc::new(MockClashResolver::new()),
+            )
+            .await
+            .unwrap();
+
+        d.send(UdpPacket {
+            data: b"hello".to_vec(),
+            src_addr: SocksAddr::any_ipv4(),
+            dst_addr: peer_a.local_addr().unwrap().into(),
+        })
+        .await
+        .unwrap();
+
+        let mut buf = [0u8; 16];
+        let (n, mapped) = peer_a.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        peer_a.send_to(b"from a", mapped).await.unwrap();
+        peer_b.send_to(b"from b", mapped).await.unwrap();
+
+        for (peer, data) in [(&peer_a, b"from a"), (&peer_b, b"from b")] {
+            let pkt = tokio::time::timeout(Duration::from_secs(5), d.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(pkt.src_addr, peer.local_addr().unwrap().into());
+            assert_eq!(&pkt.data, data);
+        }
+    }
+
+    /// connected to the one destination, a closed port there ends the
+    /// stream right away instead of it waiting for an answer
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_udp_fixed_destination_unreachable() {
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let mut resolver = MockClashResolver::new();
+        resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some(std::net::Ipv4Addr::LOCALHOST.into())));
+        let sess = Session {
+            destination: ("localhost".to_owned(), closed_addr.port())
+                .try_into()
+                .unwrap(),
+            fixed_destination: true,
+            ..Default::default()
+        };
+        let mut d = Handler::new()
+            .connect_datagram(&sess, Arc::new(resolver))
+            .await
+            .unwrap();
+
+        d.send(UdpPacket {
+            data: b"hello".to_vec(),
+            src_addr: SocksAddr::any_ipv4(),
+            dst_addr: sess.destination.clone(),
+        })
+        .await
+        .unwrap();
+
+        let next = tokio::time::timeout(Duration::from_secs(5), d.next())
+            .await
+            .expect("the port unreachable should end the stream");
+        assert!(next.is_none());
+    }
+}
@@ -1,4 +1,8 @@
-use std::io::Cursor;
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::{Arc, RwLock},
+};
 
 use futures::future::BoxFuture;
 use murmur3::murmur3_32;
@@ -15,6 +19,10 @@ pub type StrategyFn = Box<
         + Sync,
 >;
 
+/// the `weights` of a weighted group by member name, shared with the API so
+/// a change is seen by the next connection. members not in it weigh 1.
+pub type Weights = Arc<RwLock<HashMap<String, u32>>>;
+
 fn get_key(sess: &Session) -> String {
     match &sess.destination {
         crate::session::SocksAddr::Ip(addr) => addr.ip().to_string(),
@@ -63,3 +71,45 @@ pub fn strategy_consistent_hashring() -> StrategyFn {
         )))
     })
 }
+
+/// smooth weighted round-robin over the members with a weight, those with
+/// weight 0 are only picked when they're all that's left. the group hands in
+/// the alive members only.
+pub fn strategy_weighted(weights: Weights) -> StrategyFn {
+    let mut current: HashMap<String, i64> = HashMap::new();
+    Box::new(move |proxies, _| {
+        let weights = weights.read().unwrap();
+        let mut candidates = proxies
+            .iter()
+            .map(|p| (p, weights.get(p.name()).copied().unwrap_or(1) as i64))
+            .filter(|(_, w)| *w > 0)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            // backups only, share evenly
+            candidates = proxies.iter().map(|p| (p, 1)).collect();
+        }
+        drop(weights);
+
+        current.retain(|k, _| candidates.iter().any(|(p, _)| p.name() == k));
+        let total: i64 = candidates.iter().map(|(_, w)| w).sum();
+        let mut best: Option<(&AnyOutboundHandler, i64)> = None;
+        for (p, w) in &candidates {
+            let c = current.entry(p.name().to_owned()).or_default();
+            *c += w;
+            if best.is_none_or(|(_, b)| *c > b) {
+                best = Some((p, *c));
+            }
+        }
+
+        match best {
+            Some((p, _)) => {
+                *current.get_mut(p.name()).unwrap() -= total;
+                Box::pin(futures::future::ok(p.clone()))
+            }
+            None => Box::pin(futures::future::err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "no proxy found",
+            ))),
+        }
+    })
+}
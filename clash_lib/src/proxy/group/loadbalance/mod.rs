@@ -10,7 +10,9 @@ use crate::{
     app::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
-        remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
+        remote_content_manager::{
+            providers::proxy_provider::ThreadSafeProxyProvider, ProxyManager,
+        },
     },
     config::internal::proxy::LoadBalanceStrategy,
     proxy::{
@@ -21,7 +23,10 @@ use crate::{
     session::Session,
 };
 
-use self::helpers::{strategy_consistent_hashring, strategy_rr, StrategyFn};
+pub use self::helpers::Weights;
+use self::helpers::{
+    strategy_consistent_hashring, strategy_rr, strategy_weighted, StrategyFn,
+};
 
 #[derive(Default, Clone)]
 pub struct HandlerOptions {
@@ -29,6 +34,8 @@ pub struct HandlerOptions {
     pub name: String,
     pub udp: bool,
     pub strategy: LoadBalanceStrategy,
+    /// the members' weights with `strategy: weighted`
+    pub weights: Weights,
 }
 
 struct HandlerInner {
@@ -41,6 +48,8 @@ pub struct Handler {
     providers: Vec<ThreadSafeProxyProvider>,
 
     inner: Arc<Mutex<HandlerInner>>,
+
+    proxy_manager: ProxyManager,
 }
 
 impl std::fmt::Debug for Handler {
@@ -55,22 +64,71 @@ impl Handler {
     pub fn new(
         opts: HandlerOptions,
         providers: Vec<ThreadSafeProxyProvider>,
+        proxy_manager: ProxyManager,
     ) -> Self {
         let strategy_fn = match opts.strategy {
             LoadBalanceStrategy::ConsistentHashing => strategy_consistent_hashring(),
             LoadBalanceStrategy::RoundRobin => strategy_rr(),
+            LoadBalanceStrategy::Weighted => strategy_weighted(opts.weights.clone()),
         };
 
         Self {
             opts,
             providers,
             inner: Arc::new(Mutex::new(HandlerInner { strategy_fn })),
+            proxy_manager,
         }
     }
 
     async fn get_proxies(&self, touch: bool) -> Vec<AnyOutboundHandler> {
         get_proxies_from_providers(&self.providers, touch).await
     }
+
+    /// the weights, None unless `strategy: weighted`
+    pub fn weights(&self) -> Option<Weights> {
+        matches!(self.opts.strategy, LoadBalanceStrategy::Weighted)
+            .then(|| self.opts.weights.clone())
+    }
+
+    /// the member a new connection goes through, one with UDP if `udp`
+    async fn pick(
+        &self,
+        sess: &Session,
+        udp: bool,
+    ) -> io::Result<AnyOutboundHandler> {
+        let mut proxies = self.get_proxies(false).await;
+        if udp {
+            let mut with_udp = vec![];
+            for p in proxies {
+                if p.support_udp().await {
+                    with_udp.push(p);
+                }
+            }
+            proxies = with_udp;
+        }
+        if proxies.is_empty() {
+            return Err(io::Error::other(format!(
+                "{} has no member{}",
+                self.name(),
+                if udp { " with udp" } else { "" }
+            )));
+        }
+        if matches!(self.opts.strategy, LoadBalanceStrategy::Weighted) {
+            let mut alive = vec![];
+            for p in &proxies {
+                if self.proxy_manager.alive(p.name()).await {
+                    alive.push(p.clone());
+                }
+            }
+            // all dead, keep going through the weighted ones
+            if !alive.is_empty() {
+                proxies = alive;
+            }
+        }
+        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
+        debug!("{} use proxy {}", self.name(), proxy.name());
+        Ok(proxy)
+    }
 }
 
 impl DialWithConnector for Handler {}
@@ -88,9 +146,18 @@ impl OutboundHandler for Handler {
         OutboundType::LoadBalance
     }
 
-    /// whether the outbound handler support UDP
+    /// whether the outbound handler support UDP, unless `udp: false` as long
+    /// as a member has it, datagram sessions only going through those
     async fn support_udp(&self) -> bool {
-        self.opts.udp
+        if !self.opts.udp {
+            return false;
+        }
+        for proxy in self.get_proxies(false).await {
+            if proxy.support_udp().await {
+                return true;
+            }
+        }
+        false
     }
 
     /// connect to remote target via TCP
@@ -99,9 +166,7 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
+        let proxy = self.pick(sess, false).await?;
         match proxy.connect_stream(sess, resolver).await {
             Ok(s) => {
                 s.append_to_chain(self.name()).await;
@@ -117,9 +182,7 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
+        let proxy = self.pick(sess, true).await?;
         proxy.connect_datagram(sess, resolver).await
     }
 
@@ -133,9 +196,7 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let proxies = self.get_proxies(false).await;
-        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
-        debug!("{} use proxy {}", self.name(), proxy.name());
+        let proxy = self.pick(sess, false).await?;
         proxy
             .connect_stream_with_connector(sess, resolver, connector)
             .await
@@ -152,6 +213,12 @@ impl OutboundHandler for Handler {
             Box::new(all.iter().map(|x| x.name().to_owned()).collect::<Vec<_>>())
                 as _,
         );
+        if let Some(weights) = self.weights() {
+            m.insert(
+                "weights".to_string(),
+                Box::new(weights.read().unwrap().clone()) as _,
+            );
+        }
         m
     }
 
@@ -159,3 +226,147 @@ impl OutboundHandler for Handler {
         self.opts.common_opts.icon.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, RwLock},
+    };
+
+    use crate::{
+        app::{dns::MockClashResolver, remote_content_manager::ProxyManager},
+        config::internal::proxy::LoadBalanceStrategy,
+        proxy::{
+            mocks::{MockDummyOutboundHandler, MockDummyProxyProvider},
+            OutboundHandler,
+        },
+        session::Session,
+    };
+
+    async fn distribution(
+        handler: &super::Handler,
+        n: usize,
+    ) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for _ in 0..n {
+            let p = handler.pick(&Session::default(), false).await.unwrap();
+            *counts.entry(p.name().to_owned()).or_default() += 1;
+        }
+        counts
+    }
+
+    fn assert_share(counts: &HashMap<String, usize>, name: &str, share: f64) {
+        let got = *counts.get(name).unwrap_or(&0) as f64 / 10000.0;
+        assert!(
+            (got - share).abs() < 0.01,
+            "{} got {} of {}",
+            name,
+            got,
+            share
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weighted() {
+        let mut provider = MockDummyProxyProvider::new();
+        provider.expect_proxies().returning(|| {
+            ["a", "b", "c", "backup"]
+                .into_iter()
+                .map(|name| {
+                    let mut p = MockDummyOutboundHandler::new();
+                    p.expect_name().return_const(name.to_owned());
+                    Arc::new(p) as _
+                })
+                .collect()
+        });
+
+        let weights = Arc::new(RwLock::new(HashMap::from([
+            ("a".to_owned(), 5),
+            ("b".to_owned(), 3),
+            ("backup".to_owned(), 0),
+        ])));
+        let proxy_manager = ProxyManager::new(Arc::new(MockClashResolver::new()));
+        let handler = super::Handler::new(
+            super::HandlerOptions {
+                name: "lb".to_owned(),
+                strategy: LoadBalanceStrategy::Weighted,
+                weights: weights.clone(),
+                ..Default::default()
+            },
+            vec![Arc::new(tokio::sync::RwLock::new(provider))],
+            proxy_manager.clone(),
+        );
+
+        // c weighs 1 by default
+        let counts = distribution(&handler, 10000).await;
+        assert_share(&counts, "a", 5.0 / 9.0);
+        assert_share(&counts, "b", 3.0 / 9.0);
+        assert_share(&counts, "c", 1.0 / 9.0);
+        assert_share(&counts, "backup", 0.0);
+
+        proxy_manager.report_alive("a", false).await;
+        let counts = distribution(&handler, 10000).await;
+        assert_share(&counts, "a", 0.0);
+        assert_share(&counts, "b", 0.75);
+        assert_share(&counts, "c", 0.25);
+
+        proxy_manager.report_alive("b", false).await;
+        proxy_manager.report_alive("c", false).await;
+        let counts = distribution(&handler, 10000).await;
+        assert_share(&counts, "backup", 1.0);
+
+        // as the API does
+        for name in ["a", "b", "c"] {
+            proxy_manager.report_alive(name, true).await;
+        }
+        weights
+            .write()
+            .unwrap()
+            .extend([("a".to_owned(), 1), ("c".to_owned(), 4)]);
+        let counts = distribution(&handler, 10000).await;
+        assert_share(&counts, "a", 1.0 / 8.0);
+        assert_share(&counts, "b", 3.0 / 8.0);
+        assert_share(&counts, "c", 4.0 / 8.0);
+        assert_share(&counts, "backup", 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_datagram_members() {
+        let lb = |udp| {
+            let mut provider = MockDummyProxyProvider::new();
+            provider.expect_proxies().returning(|| {
+                [("tcp-only", false), ("with-udp", true)]
+                    .into_iter()
+                    .map(|(name, udp)| {
+                        let mut p = MockDummyOutboundHandler::new();
+                        p.expect_name().return_const(name.to_owned());
+                        p.expect_support_udp().return_const(udp);
+                        Arc::new(p) as _
+                    })
+                    .collect()
+            });
+            super::Handler::new(
+                super::HandlerOptions {
+                    name: "lb".to_owned(),
+                    udp,
+                    strategy: LoadBalanceStrategy::RoundRobin,
+                    ..Default::default()
+                },
+                vec![Arc::new(tokio::sync::RwLock::new(provider))],
+                ProxyManager::new(Arc::new(MockClashResolver::new())),
+            )
+        };
+
+        let handler = lb(true);
+        assert!(handler.support_udp().await);
+        for _ in 0..4 {
+            let p = handler.pick(&Session::default(), true).await.unwrap();
+            assert_eq!(p.name(), "with-udp");
+        }
+        let counts = distribution(&handler, 10000).await;
+        assert_share(&counts, "tcp-only", 0.5);
+
+        assert!(!lb(false).support_udp().await);
+    }
+}
@@ -7,16 +7,14 @@ use tracing::debug;
 
 use crate::{
     app::{
-        dispatcher::{
-            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram,
-            ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
-        },
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
         remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
     },
     common::errors::new_io_error,
     proxy::{
         utils::{
+            carries_stream, connect_datagram_via, native_udp,
             provider_helper::get_proxies_from_providers, DirectConnector,
             ProxyConnector, RemoteConnector,
         },
@@ -30,6 +28,8 @@ use crate::{
 pub struct HandlerOptions {
     pub common_opts: HandlerCommonOptions,
     pub name: String,
+    /// the UDP through the hops without UDP of their own goes over a stream
+    pub udp_over_tcp: bool,
 }
 
 pub struct Handler {
@@ -71,13 +71,19 @@ impl OutboundHandler for Handler {
         OutboundType::Relay
     }
 
+    /// every hop has to carry the UDP of the ones after it, with its own UDP
+    /// or with udp-over-tcp
     async fn support_udp(&self) -> bool {
-        for proxy in self.get_proxies(false).await {
-            match proxy.support_connector().await {
-                ConnectorType::All => return true,
-                ConnectorType::None | ConnectorType::Tcp => (),
-            }
-            if !proxy.support_udp().await {
+        let proxies = self.get_proxies(false).await;
+        if proxies.is_empty() {
+            return false;
+        }
+        for (i, proxy) in proxies.iter().enumerate() {
+            let first = i == 0;
+            if !native_udp(proxy.as_ref(), first).await
+                && !(self.opts.udp_over_tcp
+                    && carries_stream(proxy.as_ref(), first).await)
+            {
                 return false;
             }
         }
@@ -122,9 +128,8 @@ impl OutboundHandler for Handler {
                     )
                     .await?;
 
-                let chained = ChainedStreamWrapper::new(s);
-                chained.append_to_chain(self.name()).await;
-                Ok(Box::new(chained))
+                s.append_to_chain(self.name()).await;
+                Ok(s)
             }
         }
     }
@@ -142,7 +147,19 @@ impl OutboundHandler for Handler {
             1 => {
                 let proxy = proxies[0].clone();
                 debug!("udp relay `{}` via proxy `{}`", self.name(), proxy.name());
-                proxy.connect_datagram(sess, resolver).await
+                if self.opts.udp_over_tcp && !native_udp(proxy.as_ref(), true).await
+                {
+                    connect_datagram_via(
+                        &proxy,
+                        sess,
+                        resolver,
+                        &DirectConnector::new(),
+                        true,
+                    )
+                    .await
+                } else {
+                    proxy.connect_datagram(sess, resolver).await
+                }
             }
             _ => {
                 let mut connector: Box<dyn RemoteConnector> =
@@ -154,22 +171,24 @@ impl OutboundHandler for Handler {
                         self.name(),
                         proxy.name()
                     );
-                    connector =
-                        Box::new(ProxyConnector::new(proxy.clone(), connector));
+                    connector = Box::new(
+                        ProxyConnector::new(proxy.clone(), connector)
+                            .udp_over_tcp(self.opts.udp_over_tcp),
+                    );
                 }
 
                 debug!("relay `{}` via proxy `{}`", self.name(), last[0].name());
-                let d = last[0]
-                    .connect_datagram_with_connector(
-                        sess,
-                        resolver,
-                        connector.as_ref(),
-                    )
-                    .await?;
-
-                let chained = ChainedDatagramWrapper::new(d);
-                chained.append_to_chain(self.name()).await;
-                Ok(Box::new(chained))
+                let d = connect_datagram_via(
+                    &last[0],
+                    sess,
+                    resolver,
+                    connector.as_ref(),
+                    self.opts.udp_over_tcp,
+                )
+                .await?;
+
+                d.append_to_chain(self.name()).await;
+                Ok(d)
             }
         }
     }
@@ -197,6 +216,171 @@ impl OutboundHandler for Handler {
     }
 }
 
+#[cfg(all(test, feature = "trojan"))]
+mod mock_tests {
+    use std::sync::Arc;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::RwLock,
+    };
+
+    use crate::{
+        proxy::{
+            datagram::UdpPacket, mocks::MockDummyProxyProvider, socks, trojan,
+            AnyOutboundHandler, OutboundHandler,
+        },
+        session::{Session, SocksAddr},
+        test_support::{self, Socks5Server, TrojanServer},
+    };
+
+    use super::{Handler, HandlerOptions};
+
+    fn relay(proxies: Vec<AnyOutboundHandler>, udp_over_tcp: bool) -> Handler {
+        let mut provider = MockDummyProxyProvider::new();
+        provider.expect_touch().returning(|| ());
+        provider.expect_proxies().returning(move || proxies.clone());
+        Handler {
+            opts: HandlerOptions {
+                name: "relay".to_owned(),
+                udp_over_tcp,
+                ..Default::default()
+            },
+            providers: vec![Arc::new(RwLock::new(provider))],
+        }
+    }
+
+    fn trojan(port: u16) -> AnyOutboundHandler {
+        Arc::new(trojan::Handler::new(trojan::HandlerOptions {
+            name: "trojan".to_owned(),
+            common_opts: Default::default(),
+            server: "127.0.0.1".to_owned(),
+            port,
+            password: "password".to_owned(),
+            udp: true,
+            sni: "example.org".to_owned(),
+            alpn: None,
+            skip_cert_verify: true,
+            pinned_cert_chain_sha256: vec![],
+            pinned_cert_only: false,
+            client_fingerprint: None,
+            transport: None,
+            servers: vec![],
+        }))
+    }
+
+    fn socks(port: u16, udp: bool) -> AnyOutboundHandler {
+        Arc::new(socks::Handler::new(socks::HandlerOptions {
+            name: "socks".to_owned(),
+            server: "127.0.0.1".to_owned(),
+            port,
+            udp,
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_udp_through_chain() {
+        let trojan_server = TrojanServer::new("password").relay().start().await;
+        let socks_server = Socks5Server::new().start().await;
+        let handler = relay(
+            vec![
+                trojan(trojan_server.addr.port()),
+                socks(socks_server.addr.port(), true),
+            ],
+            false,
+        );
+        assert!(handler.support_udp().await);
+
+        let sess = Session {
+            destination: SocksAddr::Ip("1.2.3.4:53".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut d = handler
+            .connect_datagram(&sess, test_support::resolver())
+            .await
+            .unwrap();
+        d.send(UdpPacket {
+            data: b"ping".to_vec(),
+            src_addr: SocksAddr::any_ipv4(),
+            dst_addr: sess.destination.clone(),
+        })
+        .await
+        .unwrap();
+        // the socks relay echoes it back, header and all
+        let pkt = d.next().await.unwrap();
+        assert_eq!(pkt.data, b"ping");
+        assert_eq!(pkt.src_addr, sess.destination);
+        assert_eq!(
+            d.chain().names().await,
+            vec!["socks".to_owned(), "relay".to_owned()]
+        );
+
+        // the associate and its packets both went through trojan
+        let socks_requests = socks_server.requests();
+        assert_eq!(socks_requests.len(), 1);
+        assert!(socks_requests[0].udp);
+        let trojan_requests = trojan_server.requests();
+        assert_eq!(trojan_requests.len(), 2);
+        assert!(!trojan_requests[0].udp);
+        assert_eq!(
+            trojan_requests[0].target,
+            SocksAddr::from(socks_server.addr)
+        );
+        assert!(trojan_requests[1].udp);
+    }
+
+    #[tokio::test]
+    async fn test_stream_through_chain() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = s.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+        let trojan_server = TrojanServer::new("password").relay().start().await;
+        let socks_server = Socks5Server::new().relay().start().await;
+        let handler = relay(
+            vec![
+                trojan(trojan_server.addr.port()),
+                socks(socks_server.addr.port(), true),
+            ],
+            false,
+        );
+
+        let sess = Session {
+            destination: echo_addr.into(),
+            ..Default::default()
+        };
+        let mut s = handler
+            .connect_stream(&sess, test_support::resolver())
+            .await
+            .unwrap();
+        s.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(socks_server.requests()[0].target, sess.destination);
+    }
+
+    #[tokio::test]
+    async fn test_support_udp() {
+        // nothing to carry it
+        assert!(!relay(vec![], false).support_udp().await);
+
+        let stream_only = || vec![trojan(1), socks(2, false)];
+        assert!(!relay(stream_only(), false).support_udp().await);
+        assert!(relay(stream_only(), true).support_udp().await);
+
+        // a hop without UDP used to be missed after one with it
+        let middle = vec![trojan(1), socks(2, false), socks(3, true)];
+        assert!(!relay(middle, false).support_udp().await);
+    }
+}
+
 #[cfg(feature = "shadowsocks")]
 #[cfg(all(test, docker_test))]
 mod tests {
@@ -239,6 +423,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin_opts: Default::default(),
             udp: false,
+            servers: vec![],
         };
         let port = ss_opts.port;
         let ss_handler: AnyOutboundHandler =
@@ -275,6 +460,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin_opts: Default::default(),
             udp: false,
+            servers: vec![],
         };
         let port = ss_opts.port;
         let ss_handler: AnyOutboundHandler =
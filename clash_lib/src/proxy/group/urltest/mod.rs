@@ -26,6 +26,10 @@ pub struct HandlerOptions {
     pub common_opts: HandlerCommonOptions,
     pub name: String,
     pub udp: bool,
+    /// the members losing more of the probes than this aren't picked
+    pub max_loss_percent: Option<u8>,
+    /// how many of the last rounds of probes the loss is of
+    pub loss_rounds: usize,
 }
 
 struct HandlerInner {
@@ -72,11 +76,52 @@ impl Handler {
         get_proxies_from_providers(&self.providers, touch).await
     }
 
+    /// those of `proxies` not losing too many probes, all of them if none is
+    async fn usable(
+        &self,
+        proxies: Vec<AnyOutboundHandler>,
+    ) -> Vec<AnyOutboundHandler> {
+        let Some(max_loss) = self.opts.max_loss_percent else {
+            return proxies;
+        };
+        let mut usable = vec![];
+        for proxy in &proxies {
+            match self
+                .proxy_manager
+                .loss(proxy.name(), self.opts.loss_rounds)
+                .await
+            {
+                Some(loss) if loss > max_loss => {
+                    trace!(
+                        "`{}` skips `{}`, {}% lost",
+                        self.name(),
+                        proxy.name(),
+                        loss
+                    )
+                }
+                _ => usable.push(proxy.clone()),
+            }
+        }
+        if usable.is_empty() {
+            proxies
+        } else {
+            usable
+        }
+    }
+
     async fn fastest(&self, touch: bool) -> AnyOutboundHandler {
         let proxy_manager = self.proxy_manager.clone();
         let mut inner = self.inner.lock().await;
 
-        let proxies = self.get_proxies(touch).await;
+        let proxies = self.usable(self.get_proxies(touch).await).await;
+        // the one picked before may be losing too many now
+        if inner
+            .fastest_proxy
+            .as_ref()
+            .is_some_and(|x| !proxies.iter().any(|p| p.name() == x.name()))
+        {
+            inner.fastest_proxy = None;
+        }
         let mut fastest = proxies
             .first()
             .unwrap_or_else(|| panic!("no proxy found for {}", self.name()));
@@ -29,11 +29,12 @@ fn decode_basic_proxy_authorization(cred: &str) -> Option<(String, String)> {
     Some((user.to_owned(), pass.to_owned()))
 }
 
-/// returns a auth required response on auth failure
+/// returns the user authenticated as, or an auth required response on auth
+/// failure
 pub fn authenticate_req(
     req: &Request<hyper::body::Incoming>,
     authenticator: ThreadSafeAuthenticator,
-) -> Option<Response<HyperResponseBody>> {
+) -> Result<String, Response<HyperResponseBody>> {
     let auth_resp = Response::builder()
         .status(hyper::StatusCode::PROXY_AUTHENTICATION_REQUIRED)
         .header(hyper::header::PROXY_AUTHENTICATE, "Basic")
@@ -45,19 +46,19 @@ pub fn authenticate_req(
         .unwrap();
     let cred = parse_basic_proxy_authorization(req);
     if cred.is_none() {
-        return Some(auth_resp);
+        return Err(auth_resp);
     }
     let cred = decode_basic_proxy_authorization(cred.unwrap());
     if cred.is_none() {
-        return Some(auth_resp);
+        return Err(auth_resp);
     }
 
     let (user, pass) = cred.unwrap();
 
     if authenticator.authenticate(&user, &pass) {
-        None
+        Ok(user)
     } else {
         warn!("proxy authentication failed");
-        Some(auth_resp)
+        Err(auth_resp)
     }
 }
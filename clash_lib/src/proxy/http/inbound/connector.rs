@@ -20,12 +20,24 @@ use super::proxy::maybe_socks_addr;
 #[derive(Clone)]
 pub struct Connector {
     src: SocketAddr,
+    inbound: &'static str,
+    user: Option<String>,
     dispatcher: Arc<Dispatcher>,
 }
 
 impl Connector {
-    pub fn new(src: SocketAddr, dispatcher: Arc<Dispatcher>) -> Self {
-        Self { src, dispatcher }
+    pub fn new(
+        src: SocketAddr,
+        inbound: &'static str,
+        user: Option<String>,
+        dispatcher: Arc<Dispatcher>,
+    ) -> Self {
+        Self {
+            src,
+            inbound,
+            user,
+            dispatcher,
+        }
     }
 }
 
@@ -44,6 +56,8 @@ impl tower::Service<Uri> for Connector {
 
     fn call(&mut self, url: Uri) -> Self::Future {
         let src = self.src;
+        let inbound = self.inbound;
+        let user = self.user.clone();
         let dispatcher = self.dispatcher.clone();
 
         let destination = maybe_socks_addr(&url);
@@ -57,6 +71,8 @@ impl tower::Service<Uri> for Connector {
                 source: src,
                 destination: destination
                     .ok_or(ProxyError::InvalidUrl(url.to_string()))?,
+                inbound_name: inbound.to_owned(),
+                inbound_user: user,
                 ..Default::default()
             };
 
@@ -3,8 +3,12 @@ mod connector;
 mod proxy;
 
 use crate::{
+    app::inbound::{limiter::ListenerLimiter, tls::ListenerTls},
     common::auth::ThreadSafeAuthenticator,
-    proxy::{utils::apply_tcp_options, InboundListener},
+    proxy::{
+        utils::{apply_tcp_options, new_tcp_listener, unmapped},
+        AnyStream, InboundListener,
+    },
     Dispatcher,
 };
 use async_trait::async_trait;
@@ -12,7 +16,6 @@ use async_trait::async_trait;
 pub use proxy::handle as handle_http;
 
 use std::{io, net::SocketAddr, sync::Arc};
-use tokio::net::TcpListener;
 use tracing::warn;
 
 #[derive(Clone)]
@@ -20,6 +23,8 @@ pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    limiter: Arc<ListenerLimiter>,
+    tls: Option<Arc<ListenerTls>>,
 }
 
 impl Drop for Listener {
@@ -33,11 +38,15 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        limiter: Arc<ListenerLimiter>,
+        tls: Option<Arc<ListenerTls>>,
     ) -> Self {
         Self {
             addr,
             dispatcher,
             authenticator,
+            limiter,
+            tls,
         }
     }
 }
@@ -53,18 +62,41 @@ impl InboundListener for Listener {
     }
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener = new_tcp_listener(self.addr)?;
 
         loop {
             let (socket, src_addr) = listener.accept().await?;
+            let src_addr = unmapped(src_addr);
+
+            let Some(guard) = self.limiter.try_acquire(src_addr.ip()) else {
+                continue;
+            };
 
             let socket = apply_tcp_options(socket)?;
+            let local = socket.local_addr()?;
 
             let dispatcher = self.dispatcher.clone();
             let author = self.authenticator.clone();
+            let tls = self.tls.clone();
 
             tokio::spawn(async move {
-                proxy::handle(Box::new(socket), src_addr, dispatcher, author).await
+                let socket: AnyStream = match tls {
+                    Some(tls) => match tls.accept(socket, src_addr, "HTTP").await {
+                        Some(s) => Box::new(s),
+                        None => return,
+                    },
+                    None => Box::new(socket),
+                };
+                proxy::handle(
+                    socket,
+                    src_addr,
+                    local,
+                    "http",
+                    dispatcher,
+                    author,
+                    Some(guard),
+                )
+                .await;
             });
         }
     }
@@ -73,3 +105,175 @@ impl InboundListener for Listener {
         Err(io::Error::new(io::ErrorKind::Other, "unsupported"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        app::{dispatcher::Dispatcher, inbound::limiter::ConnectionLimiter},
+        common::auth::PlainAuthenticator,
+        config::{def::UdpNat, internal::rule::RuleType},
+        proxy::InboundListener,
+    };
+
+    use super::Listener;
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        // only loopback sources go DIRECT, an IPv4 client still seen as
+        // ::ffff:127.0.0.1 would be rejected
+        let rules = ["127.0.0.1/32", "::1/128"]
+            .map(|x| RuleType::SrcCidr {
+                ipnet: x.parse().unwrap(),
+                target: "DIRECT".to_owned(),
+                no_resolve: true,
+            })
+            .into_iter()
+            .chain([RuleType::Match {
+                target: "REJECT".to_owned(),
+            }])
+            .collect();
+        let dispatcher =
+            Arc::new(Dispatcher::for_test(rules, UdpNat::Symmetric).await);
+
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = origin.accept().await {
+                let _ = s.write_all(b"hi").await;
+            }
+        });
+
+        let port = TcpListener::bind("[::]:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let listener = Listener::new(
+            (Ipv6Addr::UNSPECIFIED, port).into(),
+            dispatcher,
+            Arc::new(PlainAuthenticator::new(vec![])),
+            ConnectionLimiter::new(0).for_listener("HTTP", 0, None),
+            None,
+        );
+        tokio::spawn(async move { listener.listen_tcp().await });
+
+        for ip in ["127.0.0.1", "::1"] {
+            let ip = ip.parse::<IpAddr>().unwrap();
+            let mut c = None;
+            for _ in 0..50 {
+                if let Ok(s) = TcpStream::connect((ip, port)).await {
+                    c = Some(s);
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            let mut c = c.expect("listening");
+
+            c.write_all(
+                format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", origin_addr)
+                    .as_bytes(),
+            )
+            .await
+            .unwrap();
+            let mut buf = vec![];
+            while !buf.ends_with(b"hi") {
+                let mut chunk = [0; 256];
+                let n = c.read(&mut chunk).await.unwrap();
+                assert!(n > 0, "{} not let through: {:?}", ip, buf);
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limit_held_by_tunnel() {
+        let dispatcher = Arc::new(
+            Dispatcher::for_test(
+                vec![RuleType::Match {
+                    target: "DIRECT".to_owned(),
+                }],
+                UdpNat::Symmetric,
+            )
+            .await,
+        );
+
+        // the origin keeps every tunnel open
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut open = vec![];
+            while let Ok((mut s, _)) = origin.accept().await {
+                let _ = s.write_all(b"hi").await;
+                open.push(s);
+            }
+        });
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let limiter = ConnectionLimiter::new(1);
+        let listener = Listener::new(
+            (Ipv4Addr::LOCALHOST, port).into(),
+            dispatcher,
+            Arc::new(PlainAuthenticator::new(vec![])),
+            limiter.for_listener("HTTP", 0, None),
+            None,
+        );
+        tokio::spawn(async move { listener.listen_tcp().await });
+
+        let mut c = None;
+        for _ in 0..50 {
+            if let Ok(s) = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+                c = Some(s);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut c = c.expect("listening");
+
+        c.write_all(
+            format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", origin_addr)
+                .as_bytes(),
+        )
+        .await
+        .unwrap();
+        let mut buf = vec![];
+        while !buf.ends_with(b"hi") {
+            let mut chunk = [0; 256];
+            let n = c.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "tunnel closed: {:?}", buf);
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert_eq!(limiter.stats().global.active, 1);
+
+        // the tunnel above still holds the only slot
+        let mut second = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        let mut chunk = [0; 256];
+        let read =
+            tokio::time::timeout(Duration::from_secs(5), second.read(&mut chunk))
+                .await
+                .expect("second connection not refused");
+        assert!(matches!(read, Ok(0) | Err(_)), "{:?}", read);
+
+        drop(c);
+    }
+}
@@ -1,18 +1,24 @@
 use std::{
+    collections::HashMap,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use futures::{future::BoxFuture, TryFutureExt};
 
 use http_body_util::{BodyExt, Empty, Full};
-use hyper::{body::Incoming, server::conn::http1, Method, Request, Response, Uri};
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderMap, HeaderName},
+    server::conn::http1,
+    Method, Request, Response, StatusCode, Uri,
+};
 
 use hyper_util::{client::legacy::Client, rt::TokioExecutor};
-use tracing::{instrument, warn};
+use tracing::{debug, instrument, warn};
 
 use crate::{
-    app::dispatcher::Dispatcher,
+    app::{dispatcher::Dispatcher, inbound::limiter::ConnectionGuard},
     common::{
         auth::ThreadSafeAuthenticator,
         errors::map_io_error,
@@ -42,27 +48,85 @@ pub fn maybe_socks_addr(r: &Uri) -> Option<SocksAddr> {
     })
 }
 
+/// the client forwarding the plain HTTP requests of a connection, keeping the
+/// outbound connections alive between them
+type HttpClient = Client<Connector, Incoming>;
+
+/// one client per user of a connection, as the user goes in the session
+type HttpClients = Arc<Mutex<HashMap<Option<String>, HttpClient>>>;
+
+/// Turns an origin-form request, e.g. `GET / HTTP/1.1` with a `Host` header
+/// as sent by clients treating the proxy as the server, into the
+/// absolute-form the client needs to tell where to forward it. False if
+/// there is no telling, or it's not plain HTTP.
+fn absolute_form<B>(req: &mut Request<B>) -> bool {
+    if req.uri().authority().is_none() {
+        let Some(host) = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|x| x.to_str().ok())
+        else {
+            return false;
+        };
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|x| x.as_str())
+            .unwrap_or("/");
+        match format!("http://{}{}", host, path).parse::<Uri>() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(_) => return false,
+        }
+    }
+    // the outbound connection is plain, so no `https://` here
+    req.uri().scheme_str().is_none_or(|x| x == "http")
+}
+
+/// Whether `uri` is the listener itself, which the connection reached at
+/// `local`, e.g. from a `Host` naming the proxy. Forwarding it would have
+/// the proxy connect to itself over and over. Any loopback address is taken
+/// as the listener on its port.
+fn is_self(uri: &Uri, local: SocketAddr) -> bool {
+    match maybe_socks_addr(uri) {
+        Some(SocksAddr::Ip(addr)) => {
+            let ip = addr.ip().to_canonical();
+            addr.port() == local.port()
+                && (ip == local.ip().to_canonical()
+                    || (ip.is_loopback() && local.ip().to_canonical().is_loopback()))
+        }
+        Some(SocksAddr::Domain(host, port)) => {
+            port == local.port()
+                && host.eq_ignore_ascii_case("localhost")
+                && local.ip().to_canonical().is_loopback()
+        }
+        None => false,
+    }
+}
+
 async fn proxy(
     req: Request<hyper::body::Incoming>,
     src: SocketAddr,
+    local: SocketAddr,
+    inbound: &'static str,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    clients: HttpClients,
+    guard: Option<Arc<ConnectionGuard>>,
 ) -> Result<Response<HyperResponseBody>, ProxyError> {
-    if authenticator.enabled() {
-        if let Some(res) = authenticate_req(&req, authenticator) {
-            return Ok(res);
+    let user = if authenticator.enabled() {
+        match authenticate_req(&req, authenticator) {
+            Ok(user) => Some(user),
+            Err(res) => return Ok(res),
         }
-    }
-
-    let client = Client::builder(TokioExecutor::new())
-        .http1_title_case_headers(true)
-        .http1_preserve_header_case(true)
-        .build(Connector::new(src, dispatcher.clone()));
+    } else {
+        None
+    };
 
-    // TODO: handle other upgrades: https://github.com/hyperium/hyper/blob/master/examples/upgrades.rs
     if req.method() == Method::CONNECT {
         if let Some(addr) = maybe_socks_addr(req.uri()) {
             tokio::task::spawn(async move {
+                // the connection is only counted as closed with the tunnel
+                let _guard = guard;
                 match hyper::upgrade::on(req).await {
                     Ok(upgraded) => {
                         let sess = Session {
@@ -70,7 +134,8 @@ async fn proxy(
                             typ: Type::HttpConnect,
                             source: src,
                             destination: addr,
-
+                            inbound_name: inbound.to_owned(),
+                            inbound_user: user,
                             ..Default::default()
                         };
 
@@ -94,12 +159,83 @@ async fn proxy(
                 .unwrap())
         }
     } else {
+        let mut req = req;
+        if !absolute_form(&mut req) {
+            return Ok(Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(
+                    Full::new(format!("invalid request uri: {}", req.uri()).into())
+                        .map_err(map_io_error)
+                        .boxed(),
+                )
+                .unwrap());
+        }
+        if is_self(req.uri(), local) {
+            warn!("{} from {} is to the proxy itself", req.uri(), src);
+            return Ok(Response::builder()
+                .status(hyper::StatusCode::LOOP_DETECTED)
+                .body(Empty::new().map_err(map_io_error).boxed())
+                .unwrap());
+        }
+
+        // the requests of a connection may go to different hosts, each
+        // with outbound connections of its own in the client's pool
+        let client = clients
+            .lock()
+            .unwrap()
+            .entry(user.clone())
+            .or_insert_with(|| {
+                Client::builder(TokioExecutor::new())
+                    .http1_title_case_headers(true)
+                    .http1_preserve_header_case(true)
+                    .build(Connector::new(src, inbound, user, dispatcher))
+            })
+            .clone();
+
+        let is_upgrade = is_upgrade_request(req.headers());
+        strip_hop_by_hop_headers(req.headers_mut(), is_upgrade);
+
+        // e.g. plain http:// websockets, the client side of the upgrade has to
+        // be taken before the request is moved into the client
+        let client_upgrade = is_upgrade.then(|| hyper::upgrade::on(&mut req));
+
         match client
             .request(req)
             .map_err(|x| ProxyError::General(x.to_string()))
             .await
         {
-            Ok(res) => Ok(res.map(|b| b.map_err(map_io_error).boxed())),
+            Ok(mut res) => {
+                match client_upgrade {
+                    Some(client_upgrade)
+                        if res.status() == StatusCode::SWITCHING_PROTOCOLS =>
+                    {
+                        let server_upgrade = hyper::upgrade::on(&mut res);
+                        tokio::spawn(async move {
+                            let _guard = guard;
+                            match futures::try_join!(client_upgrade, server_upgrade)
+                            {
+                                Ok((client, server)) => {
+                                    let mut client = TokioIo::new(client);
+                                    let mut server = TokioIo::new(server);
+                                    if let Err(e) = tokio::io::copy_bidirectional(
+                                        &mut client,
+                                        &mut server,
+                                    )
+                                    .await
+                                    {
+                                        debug!("http upgrade relay closed: {}", e);
+                                    }
+                                }
+                                Err(e) => warn!("HTTP upgrade failure, {}", e),
+                            }
+                        });
+                    }
+                    _ => {
+                        strip_hop_by_hop_headers(res.headers_mut(), false);
+                    }
+                }
+                Ok(res.map(|b| b.map_err(map_io_error).boxed()))
+            }
             Err(e) => {
                 warn!("http proxy error: {}", e);
                 Ok(Response::builder()
@@ -111,10 +247,70 @@ async fn proxy(
     }
 }
 
+/// headers that only make sense for a single hop and must not be forwarded,
+/// Connection and Upgrade are handled separately as an upgrade needs them.
+/// Transfer-Encoding is left to hyper which reframes the body itself.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "keep-alive",
+    "te",
+    "trailer",
+];
+
+fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && connection_tokens(headers).any(|x| x.eq_ignore_ascii_case("upgrade"))
+}
+
+fn connection_tokens(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+}
+
+/// Removes the hop-by-hop headers, including the ones listed in Connection.
+/// When `keep_upgrade` is set, Upgrade and `Connection: upgrade` are kept
+/// so the upgrade can be negotiated with the remote end.
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap, keep_upgrade: bool) {
+    let listed = connection_tokens(headers)
+        .filter(|x| !(keep_upgrade && x.eq_ignore_ascii_case("upgrade")))
+        .filter_map(|x| HeaderName::from_bytes(x.as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+
+    if keep_upgrade {
+        headers.insert(
+            header::CONNECTION,
+            header::HeaderValue::from_static("upgrade"),
+        );
+    } else {
+        headers.remove(header::CONNECTION);
+        headers.remove(header::UPGRADE);
+    }
+}
+
 struct ProxyService {
     src: SocketAddr,
+    local: SocketAddr,
+    inbound: &'static str,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    clients: HttpClients,
+    /// the slot of the connection, held by the tunnels and upgrades that
+    /// outlive the HTTP connection too
+    guard: Option<Arc<ConnectionGuard>>,
 }
 
 impl hyper::service::Service<Request<hyper::body::Incoming>> for ProxyService {
@@ -126,35 +322,444 @@ impl hyper::service::Service<Request<hyper::body::Incoming>> for ProxyService {
         Box::pin(proxy(
             req,
             self.src,
+            self.local,
+            self.inbound,
             self.dispatcher.clone(),
             self.authenticator.clone(),
+            self.clients.clone(),
+            self.guard.clone(),
         ))
     }
 }
 
-#[instrument(skip(stream, dispatcher, authenticator))]
+#[instrument(skip(stream, dispatcher, authenticator, guard))]
+/// `inbound` is the listener the connection came in on, e.g. `mixed`, at
+/// `local`. `guard` is released once the connection and whatever was
+/// tunneled or upgraded over it are closed.
 pub async fn handle(
     stream: AnyStream,
     src: SocketAddr,
+    local: SocketAddr,
+    inbound: &'static str,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    guard: Option<ConnectionGuard>,
 ) {
-    tokio::task::spawn(async move {
-        if let Err(http_err) = http1::Builder::new()
-            .preserve_header_case(true)
-            .title_case_headers(true)
-            .serve_connection(
-                stream,
-                ProxyService {
+    if let Err(http_err) = http1::Builder::new()
+        .preserve_header_case(true)
+        .title_case_headers(true)
+        .serve_connection(
+            stream,
+            ProxyService {
+                src,
+                local,
+                inbound,
+                dispatcher,
+                authenticator,
+                clients: Default::default(),
+                guard: guard.map(Arc::new),
+            },
+        )
+        .with_upgrades()
+        .await
+    {
+        warn!("Error while serving HTTP connection: {}", http_err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use http_body_util::{combinators::BoxBody, BodyExt, Empty, StreamBody};
+    use hyper::{
+        body::{Frame, Incoming},
+        client::conn::http1::{handshake, SendRequest},
+        header::{self, HeaderMap, HeaderValue},
+        server::conn::http1,
+        service::service_fn,
+        Request, Response, StatusCode,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        app::dispatcher::Dispatcher,
+        common::{auth::PlainAuthenticator, http::hyper::TokioIo},
+        config::{def::UdpNat, internal::rule::RuleType},
+    };
+
+    use super::{
+        absolute_form, handle, is_self, is_upgrade_request, strip_hop_by_hop_headers,
+    };
+
+    /// answers with the method and path of the request, then its body, in
+    /// chunks
+    async fn echo(
+        req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let head = format!("{} {} ", req.method(), req.uri().path());
+        let body = req.into_body().collect().await?.to_bytes();
+        let chunks = [Frame::data(Bytes::from(head)), Frame::data(body)];
+        Ok(Response::new(
+            StreamBody::new(futures::stream::iter(chunks.map(Ok))).boxed(),
+        ))
+    }
+
+    /// the server the requests are for, with the number of connections it
+    /// was given
+    async fn origin() -> (SocketAddr, Arc<AtomicUsize>) {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counted = conns.clone();
+        tokio::spawn(async move {
+            while let Ok((s, _)) = l.accept().await {
+                counted.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(
+                    http1::Builder::new()
+                        .serve_connection(TokioIo::new(s), service_fn(echo)),
+                );
+            }
+        });
+        (addr, conns)
+    }
+
+    /// an HTTP listener forwarding everything DIRECT
+    async fn listener() -> SocketAddr {
+        let dispatcher = Arc::new(
+            Dispatcher::for_test(
+                vec![RuleType::Match {
+                    target: "DIRECT".to_owned(),
+                }],
+                UdpNat::Symmetric,
+            )
+            .await,
+        );
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((s, src)) = l.accept().await {
+                let local = s.local_addr().unwrap();
+                tokio::spawn(handle(
+                    Box::new(s),
                     src,
-                    dispatcher,
-                    authenticator,
-                },
+                    local,
+                    "http",
+                    dispatcher.clone(),
+                    Arc::new(PlainAuthenticator::new(vec![])),
+                    None,
+                ));
+            }
+        });
+        addr
+    }
+
+    async fn connect<B>(proxy: SocketAddr) -> SendRequest<B>
+    where
+        B: hyper::body::Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let s = TcpStream::connect(proxy).await.unwrap();
+        let (sender, conn) = handshake(TokioIo::new(s)).await.unwrap();
+        tokio::spawn(conn);
+        sender
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let (origin, conns) = origin().await;
+        let mut sender = connect(listener().await).await;
+
+        for path in ["/a", "/b"] {
+            let req = Request::get(format!("http://{}{}", origin, path))
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            let res = sender.send_request(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&body[..], format!("GET {} ", path).as_bytes());
+        }
+        // the second one over the outbound connection of the first
+        assert_eq!(conns.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chunked() {
+        let (origin, _) = origin().await;
+        let mut sender = connect(listener().await).await;
+
+        let chunks = ["hel", "lo"].map(|x| {
+            Ok::<_, Infallible>(Frame::data(Bytes::from_static(x.as_bytes())))
+        });
+        let req = Request::post(format!("http://{}/upload", origin))
+            .body(StreamBody::new(futures::stream::iter(chunks)))
+            .unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        assert_eq!(res.headers()[header::TRANSFER_ENCODING], "chunked");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"POST /upload hello");
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        let (origin, _) = origin().await;
+        let mut c = TcpStream::connect(listener().await).await.unwrap();
+
+        c.write_all(
+            format!(
+                "POST http://{0}/upload HTTP/1.1\r\nHost: {0}\r\nContent-Length: \
+                 5\r\nExpect: 100-continue\r\n\r\n",
+                origin
             )
-            .with_upgrades()
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        // the body is only sent once asked for
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(5), c.read(&mut buf))
             .await
-        {
-            warn!("Error while serving HTTP connection: {}", http_err);
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        c.write_all(b"hello").await.unwrap();
+        let mut res = vec![];
+        while !res.ends_with(b"0\r\n\r\n") {
+            let n = tokio::time::timeout(Duration::from_secs(5), c.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(n, 0, "closed before the end of the response");
+            res.extend_from_slice(&buf[..n]);
         }
-    });
+        assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(res.windows(5).any(|x| x == b"hello"));
+    }
+
+    #[tokio::test]
+    async fn test_loop() {
+        let proxy = listener().await;
+        let mut sender = connect(proxy).await;
+
+        let req = Request::get("/")
+            .header(header::HOST, proxy.to_string())
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let res = sender.send_request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::LOOP_DETECTED);
+    }
+
+    /// reads from `s` until the end of the head and, if it's chunked, of
+    /// the body, returning the head
+    async fn read_head(s: &mut TcpStream) -> String {
+        let mut buf = vec![];
+        loop {
+            let mut chunk = [0; 1024];
+            let n = s.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "closed after {:?}", String::from_utf8_lossy(&buf));
+            buf.extend_from_slice(&chunk[..n]);
+
+            let Some(end) = buf.windows(4).position(|x| x == b"\r\n\r\n") else {
+                continue;
+            };
+            let head = String::from_utf8_lossy(&buf[..end + 4]).to_lowercase();
+            if !head.contains("transfer-encoding: chunked")
+                || buf[end + 4..].ends_with(b"0\r\n\r\n")
+            {
+                return head;
+            }
+        }
+    }
+
+    /// a websocket server that switches protocols, then echoes, along with
+    /// the head of the request it got
+    async fn upgrading_origin() -> (SocketAddr, tokio::sync::mpsc::Receiver<String>)
+    {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(async move {
+            let (mut s, _) = l.accept().await.unwrap();
+            let head = read_head(&mut s).await;
+            tx.send(head).await.unwrap();
+            s.write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nConnection: \
+                  Upgrade\r\nUpgrade: websocket\r\n\r\n",
+            )
+            .await
+            .unwrap();
+            let (mut r, mut w) = s.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+        (addr, rx)
+    }
+
+    /// a plain http:// websocket through the proxy, the request carrying
+    /// `body_headers` and `body`
+    async fn check_upgrade(body_headers: &str, body: &str) {
+        let (origin, mut heads) = upgrading_origin().await;
+        let mut c = TcpStream::connect(listener().await).await.unwrap();
+
+        c.write_all(
+            format!(
+                "GET http://{0}/chat HTTP/1.1\r\nHost: {0}\r\nUpgrade: \
+                 websocket\r\nConnection: keep-alive, Upgrade\r\nProxy-Connection: \
+                 keep-alive\r\nSec-WebSocket-Key: \
+                 dGhlIHNhbXBsZSBub25jZQ==\r\n{1}\r\n{2}",
+                origin, body_headers, body
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+        let res = read_head(&mut c).await;
+        assert!(res.starts_with("http/1.1 101 "), "{}", res);
+        assert!(res.contains("upgrade: websocket"), "{}", res);
+
+        let req = heads.recv().await.unwrap();
+        assert!(req.starts_with("get /chat http/1.1\r\n"), "{}", req);
+        assert!(req.contains("upgrade: websocket"), "{}", req);
+        assert!(req.contains("connection: upgrade"), "{}", req);
+        assert!(req.contains("sec-websocket-key:"), "{}", req);
+        assert!(!req.contains("proxy-connection"), "{}", req);
+
+        // raw bytes both ways from here on, nothing HTTP about them
+        c.write_all(b"\x81\x04ping").await.unwrap();
+        let mut buf = [0; 6];
+        c.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x81\x04ping");
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        check_upgrade("", "").await;
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_zero_length() {
+        check_upgrade("Content-Length: 0\r\n", "").await;
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_chunked() {
+        check_upgrade("Transfer-Encoding: chunked\r\n", "0\r\n\r\n").await;
+    }
+
+    #[test]
+    fn test_is_self() {
+        let uri = |x: &str| x.parse().unwrap();
+        let local = "127.0.0.1:7890".parse().unwrap();
+        assert!(is_self(&uri("http://127.0.0.1:7890/"), local));
+        assert!(is_self(&uri("http://[::1]:7890/"), local));
+        assert!(is_self(&uri("http://localhost:7890/"), local));
+        assert!(!is_self(&uri("http://127.0.0.1/"), local));
+        assert!(!is_self(&uri("http://example.com:7890/"), local));
+
+        let lan = "192.168.1.2:7890".parse().unwrap();
+        assert!(is_self(&uri("http://192.168.1.2:7890/"), lan));
+        assert!(!is_self(&uri("http://127.0.0.1:7890/"), lan));
+    }
+
+    fn websocket_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("example.com"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, Upgrade"),
+        );
+        headers.insert("proxy-connection", HeaderValue::from_static("keep-alive"));
+        headers.insert(
+            "sec-websocket-key",
+            HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_keep_upgrade_headers() {
+        let mut headers = websocket_headers();
+        assert!(is_upgrade_request(&headers));
+
+        strip_hop_by_hop_headers(&mut headers, true);
+        assert_eq!(headers[header::UPGRADE], "websocket");
+        assert_eq!(headers[header::CONNECTION], "upgrade");
+        assert!(headers.contains_key("sec-websocket-key"));
+        assert!(!headers.contains_key("proxy-connection"));
+        assert!(!headers.contains_key(header::PROXY_AUTHORIZATION));
+    }
+
+    #[test]
+    fn test_chunked_upgrade_request() {
+        let mut headers = websocket_headers();
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        assert!(is_upgrade_request(&headers));
+
+        strip_hop_by_hop_headers(&mut headers, true);
+        // hyper needs the framing of the request body
+        assert_eq!(headers[header::TRANSFER_ENCODING], "chunked");
+        assert_eq!(headers[header::UPGRADE], "websocket");
+    }
+
+    #[test]
+    fn test_zero_length_upgrade_request() {
+        let mut headers = websocket_headers();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("0"));
+
+        strip_hop_by_hop_headers(&mut headers, true);
+        assert_eq!(headers[header::CONTENT_LENGTH], "0");
+        assert_eq!(headers[header::CONNECTION], "upgrade");
+    }
+
+    #[test]
+    fn test_absolute_form() {
+        let mut req = Request::get("http://example.com:8080/a?b=1")
+            .body(())
+            .unwrap();
+        assert!(absolute_form(&mut req));
+        assert_eq!(req.uri(), "http://example.com:8080/a?b=1");
+
+        let mut req = Request::get("/a?b=1")
+            .header(header::HOST, "example.com")
+            .body(())
+            .unwrap();
+        assert!(absolute_form(&mut req));
+        assert_eq!(req.uri(), "http://example.com/a?b=1");
+
+        assert!(!absolute_form(&mut Request::get("/").body(()).unwrap()));
+        assert!(!absolute_form(
+            &mut Request::get("https://example.com/").body(()).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_strip_non_upgrade_request() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE, HeaderValue::from_static("h2c"));
+        headers.insert(header::CONNECTION, HeaderValue::from_static("x-custom"));
+        headers.insert("x-custom", HeaderValue::from_static("1"));
+        headers.insert("proxy-connection", HeaderValue::from_static("keep-alive"));
+        assert!(!is_upgrade_request(&headers));
+
+        strip_hop_by_hop_headers(&mut headers, false);
+        assert!(headers.is_empty());
+    }
 }
@@ -1,11 +1,11 @@
 use std::{
     fmt::{Debug, Formatter},
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr},
     num::ParseIntError,
     path::PathBuf,
     pin::Pin,
     str::FromStr,
-    sync::{Arc, RwLock},
+    sync::{Arc, OnceLock, RwLock},
     task::{Context, Poll},
 };
 mod codec;
@@ -40,9 +40,9 @@ use crate::{
             BoxedChainedDatagram, BoxedChainedStream, ChainedStreamWrapper,
         },
         dns::ThreadSafeDNSResolver,
+        net_monitor::NetworkEpoch,
     },
     common::{
-        errors::new_io_error,
         tls::GLOBAL_ROOT_STORE,
         utils::{encode_hex, sha256},
     },
@@ -57,8 +57,9 @@ use self::{
 };
 
 use super::{
-    converters::hysteria2::PortGenrateor, utils::new_udp_socket, ConnectorType,
-    DialWithConnector, OutboundHandler, OutboundType,
+    converters::hysteria2::PortGenrateor,
+    utils::{new_udp_socket, DialError, ServerAddr},
+    ConnectorType, DialWithConnector, OutboundHandler, OutboundType,
 };
 
 #[derive(Clone)]
@@ -89,6 +90,8 @@ pub struct HystOption {
     pub ca_str: Option<String>,
     #[allow(dead_code)]
     pub cwnd: Option<u64>,
+    /// how long making a session may take, unbounded if not set
+    pub dial_timeout: Option<std::time::Duration>,
 }
 
 #[derive(Debug)]
@@ -189,9 +192,12 @@ impl FromStr for CcRx {
 
 pub struct Handler {
     opts: HystOption,
-    ep_config: quinn::EndpointConfig,
-    client_config: quinn::ClientConfig,
+    server: ServerAddr,
+    /// built on the first dial
+    configs: OnceLock<(quinn::EndpointConfig, quinn::ClientConfig)>,
     session: Mutex<Option<Arc<quinn::Connection>>>,
+    /// the session is made again once the network changed
+    epoch: NetworkEpoch,
     // a send request guard to keep the connection alive
     guard: Mutex<Option<SendRequest<OpenStreams, Bytes>>>,
     // support udp is decided by server
@@ -209,6 +215,24 @@ impl Handler {
         std::time::Duration::from_secs(300);
 
     pub fn new(opts: HystOption) -> anyhow::Result<Self> {
+        Ok(Self {
+            server: ServerAddr::new(&opts.addr.host(), opts.addr.port(), None),
+            opts,
+            configs: OnceLock::new(),
+            session: Mutex::new(None),
+            epoch: Default::default(),
+            guard: Mutex::new(None),
+            support_udp: RwLock::new(true),
+        })
+    }
+
+    fn configs(&self) -> &(quinn::EndpointConfig, quinn::ClientConfig) {
+        self.configs.get_or_init(|| Self::build_configs(&self.opts))
+    }
+
+    fn build_configs(
+        opts: &HystOption,
+    ) -> (quinn::EndpointConfig, quinn::ClientConfig) {
         let verify = CertVerifyOption::new(
             opts.fingerprint.clone(),
             opts.ca.clone(),
@@ -239,14 +263,7 @@ impl Handler {
         client_config.transport_config(Arc::new(transport));
         let ep_config = quinn::EndpointConfig::default();
 
-        Ok(Self {
-            opts,
-            ep_config,
-            client_config,
-            session: Mutex::new(None),
-            guard: Mutex::new(None),
-            support_udp: RwLock::new(true),
-        })
+        (ep_config, client_config)
     }
 
     async fn new_authed_session(
@@ -256,16 +273,7 @@ impl Handler {
     ) -> anyhow::Result<(Connection, SendRequest<OpenStreams, Bytes>)> {
         // Everytime we enstablish a new session, we should lookup the server
         // address. maybe it changed since it use ddns
-        let server_socket_addr = match self.opts.addr.clone() {
-            SocksAddr::Ip(ip) => ip,
-            SocksAddr::Domain(d, port) => {
-                let ip = resolver
-                    .resolve(d.as_str(), true)
-                    .await?
-                    .ok_or_else(|| anyhow!("resolve domain {} failed", d))?;
-                SocketAddr::new(ip, port)
-            }
-        };
+        let server_socket_addr = self.server.socket_addr(&resolver).await?;
 
         // Here maybe we should use a AsyncUdpSocket which implement salamander obfs
         // and port hopping
@@ -289,6 +297,7 @@ impl Handler {
             }
         };
 
+        let (ep_config, client_config) = self.configs();
         let mut ep = if let Some(obfs) = self.opts.obfs.as_ref() {
             match obfs {
                 Obfs::Salamander(salamander_obfs) => {
@@ -299,7 +308,7 @@ impl Handler {
                     )?;
 
                     quinn::Endpoint::new_with_abstract_socket(
-                        self.ep_config.clone(),
+                        ep_config.clone(),
                         None,
                         Arc::new(obfs),
                         Arc::new(TokioRuntime),
@@ -311,9 +320,12 @@ impl Handler {
                 server_socket_addr.port(),
                 port_gen.clone(),
                 None,
+                sess.iface.clone(),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                sess.so_mark,
             )?;
             quinn::Endpoint::new_with_abstract_socket(
-                self.ep_config.clone(),
+                ep_config.clone(),
                 None,
                 Arc::new(udp_hop),
                 Arc::new(TokioRuntime),
@@ -340,14 +352,14 @@ impl Handler {
             };
 
             quinn::Endpoint::new(
-                self.ep_config.clone(),
+                ep_config.clone(),
                 None,
                 socket.into_std()?,
                 Arc::new(TokioRuntime),
             )?
         };
 
-        ep.set_default_client_config(self.client_config.clone());
+        ep.set_default_client_config(client_config.clone());
 
         let session = ep
             .connect(server_socket_addr, self.opts.sni.as_deref().unwrap_or(""))?
@@ -372,6 +384,57 @@ impl Handler {
         Ok((session, guard))
     }
 
+    /// the session shared by the streams, made again once it's closed or
+    /// the network changed
+    async fn get_session(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> std::io::Result<Arc<quinn::Connection>> {
+        let mut session_lock = self.session.lock().await;
+        if self.epoch.changed() {
+            if let Some(s) = session_lock.take() {
+                debug!("network changed, closing the old connection");
+                s.close(0u32.into(), b"network changed");
+            }
+        }
+
+        Ok(
+            match (*session_lock).as_ref().filter(|s| match s.close_reason() {
+                // rust should have inspect method on Option and Result!
+                Some(reason) => {
+                    tracing::debug!("old connection closed: {:?}", reason);
+                    false
+                }
+                None => true,
+            }) {
+                Some(s) => s.clone(),
+                None => {
+                    let connect = self.new_authed_session(sess, resolver);
+                    let connected = match self.opts.dial_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, connect)
+                            .await
+                            .unwrap_or_else(|e| Err(e.into())),
+                        None => connect.await,
+                    };
+                    let (session, guard) = connected.map_err(|e| {
+                        DialError::from_anyhow(
+                            e.context(format!(
+                                "connect to {} failed",
+                                self.opts.addr
+                            )),
+                            DialError::ProxyHandshake,
+                        )
+                    })?;
+                    let session = Arc::new(session);
+                    *session_lock = Some(session.clone());
+                    *self.guard.lock().await = Some(guard);
+                    session
+                }
+            },
+        )
+    }
+
     async fn auth(
         conn: &quinn::Connection,
         passwd: &str,
@@ -429,6 +492,10 @@ impl OutboundHandler for Handler {
         OutboundType::Hysteria2
     }
 
+    fn server(&self) -> Option<SocksAddr> {
+        Some(self.opts.addr.clone())
+    }
+
     async fn support_udp(&self) -> bool {
         *self.support_udp.read().unwrap()
     }
@@ -437,13 +504,23 @@ impl OutboundHandler for Handler {
         ConnectorType::Tcp
     }
 
+    fn pre_connect_refresh(&self) -> Option<std::time::Duration> {
+        Some(Self::DEFAULT_MAX_IDLE_TIMEOUT * 4 / 5)
+    }
+
+    async fn warm_up(&self, resolver: ThreadSafeDNSResolver) -> std::io::Result<()> {
+        self.get_session(&Session::default(), resolver)
+            .await
+            .map(|_| ())
+    }
+
     /// connect to remote target via UDP
     async fn connect_datagram(
         &self,
         _sess: &Session,
         _resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedDatagram> {
-        Err(new_io_error("hysteria2 udp is not implemented yet"))
+        Err(DialError::Unsupported.error("hysteria2 udp is not implemented yet"))
     }
 
     async fn connect_stream(
@@ -451,44 +528,17 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedStream> {
-        let authed_conn = {
-            let mut session_lock = self.session.lock().await;
+        let authed_conn = self.get_session(sess, resolver).await?;
 
-            match (*session_lock).as_ref().filter(|s| match s.close_reason() {
-                // rust should have inspect method on Option and Result!
-                Some(reason) => {
-                    tracing::debug!("old connection closed: {:?}", reason);
-                    false
-                }
-                None => true,
-            }) {
-                Some(s) => s.clone(),
-                None => {
-                    let (session, guard) = self
-                        .new_authed_session(sess, resolver)
-                        .await
-                        .map_err(|e| {
-                            std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                format!(
-                                    "connect to {} failed: {}",
-                                    self.opts.addr, e
-                                ),
-                            )
-                        })?;
-                    let session = Arc::new(session);
-                    *session_lock = Some(session.clone());
-                    *self.guard.lock().await = Some(guard);
-                    session
-                }
-            }
-        };
-
-        let (mut tx, mut rx) = authed_conn.open_bi().await?;
+        let (mut tx, mut rx) = authed_conn
+            .open_bi()
+            .await
+            .map_err(|e| DialError::of_quic(&e).error(e))?;
 
         tokio_util::codec::FramedWrite::new(&mut tx, Hy2TcpCodec)
             .send(&sess.destination)
-            .await?;
+            .await
+            .map_err(|e| DialError::ProxyHandshake.tag(e))?;
 
         match tokio_util::codec::FramedRead::new(&mut rx, Hy2TcpCodec)
             .next()
@@ -496,13 +546,10 @@ impl OutboundHandler for Handler {
         {
             Some(Ok(resp)) => {
                 if resp.status != 0x00 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!(
-                            "server response error: addr: {}, msg: {:?}",
-                            self.opts.addr, resp.msg
-                        ),
-                    ));
+                    return Err(DialError::ProxyHandshake.error(format!(
+                        "server response error: addr: {}, msg: {:?}",
+                        self.opts.addr, resp.msg
+                    )));
                 } else {
                     debug!(
                         "hysteria2 tcp request success: status: {}, msg: {:?}",
@@ -510,8 +557,9 @@ impl OutboundHandler for Handler {
                     );
                 }
             }
-            _ => {
-                return Err(std::io::Error::other(format!(
+            Some(Err(e)) => return Err(DialError::ProxyHandshake.tag(e)),
+            None => {
+                return Err(DialError::ProxyHandshake.error(format!(
                     "not receive hysteria2 response from server: {}",
                     self.opts.addr
                 )));
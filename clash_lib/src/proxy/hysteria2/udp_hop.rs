@@ -11,7 +11,10 @@ use std::{
 
 use quinn::{udp::Transmit, AsyncUdpSocket, Runtime, TokioRuntime, UdpPoller};
 
-use crate::proxy::converters::hysteria2::PortGenrateor;
+use crate::proxy::{
+    converters::hysteria2::PortGenrateor,
+    utils::{new_std_udp_socket, Interface},
+};
 
 struct HopState {
     prev_conn: Option<Arc<dyn AsyncUdpSocket>>,
@@ -36,6 +39,10 @@ pub struct UdpHop {
     port_range: PortGenrateor,
     /// interval to hop
     interval: Duration,
+    /// where the sockets of each hop are bound
+    iface: Option<Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    so_mark: Option<u32>,
 }
 
 impl UdpHop {
@@ -45,9 +52,15 @@ impl UdpHop {
         port: u16,
         port_range: PortGenrateor,
         interval: Option<Duration>,
+        iface: Option<Interface>,
+        #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
     ) -> io::Result<Self> {
-        let socket =
-            std::net::UdpSocket::bind(SocketAddr::new([0, 0, 0, 0].into(), 0))?;
+        let socket = new_std_udp_socket(
+            Some(SocketAddr::new([0, 0, 0, 0].into(), 0)),
+            iface.clone(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            so_mark,
+        )?;
 
         let state = HopState {
             prev_conn: None,
@@ -62,6 +75,9 @@ impl UdpHop {
             init_port: port,
             port_range,
             interval: interval.unwrap_or(Self::DEFAULT_INTERVAL),
+            iface,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            so_mark,
         })
     }
 
@@ -81,15 +97,20 @@ impl UdpHop {
             *last = now;
             tracing::trace!("port hopping");
 
-            std::net::UdpSocket::bind(SocketAddr::new([0, 0, 0, 0].into(), 0))
-                .and_then(|udp| TokioRuntime.wrap_udp_socket(udp))
-                .map(|new_conn| {
-                    *new_hop_port = self.port_range.get();
-                    *prev_conn = Some(std::mem::replace(cur_conn, new_conn));
-                })
-                .unwrap_or_else(|e| {
-                    tracing::error!("port hopping err {}", e);
-                });
+            new_std_udp_socket(
+                Some(SocketAddr::new([0, 0, 0, 0].into(), 0)),
+                self.iface.clone(),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                self.so_mark,
+            )
+            .and_then(|udp| TokioRuntime.wrap_udp_socket(udp))
+            .map(|new_conn| {
+                *new_hop_port = self.port_range.get();
+                *prev_conn = Some(std::mem::replace(cur_conn, new_conn));
+            })
+            .unwrap_or_else(|e| {
+                tracing::error!("port hopping err {}", e);
+            });
         }
         *new_hop_port
     }
@@ -1,21 +1,35 @@
 use crate::{
-    common::auth::ThreadSafeAuthenticator,
-    proxy::InboundListener,
+    app::inbound::{
+        limiter::{ConnectionGuard, ListenerLimiter},
+        tls::ListenerTls,
+    },
+    common::{
+        auth::ThreadSafeAuthenticator,
+        io::{AsTcpStream, PrefixedStream},
+    },
+    config::def::SocksUdpSource,
+    proxy::{InboundListener, ProxyStream},
     session::{Network, Session},
     Dispatcher,
 };
 use async_trait::async_trait;
 use std::{net::SocketAddr, sync::Arc};
 
-use tokio::net::TcpListener;
+use tokio::io::AsyncReadExt;
 use tracing::warn;
 
-use super::{http, socks, utils::apply_tcp_options};
+use super::{
+    http, socks,
+    utils::{apply_tcp_options, new_tcp_listener, unmapped},
+};
 
 pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    limiter: Arc<ListenerLimiter>,
+    udp_source: SocksUdpSource,
+    tls: Option<Arc<ListenerTls>>,
 }
 
 impl Drop for Listener {
@@ -29,11 +43,70 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        limiter: Arc<ListenerLimiter>,
+        udp_source: SocksUdpSource,
+        tls: Option<Arc<ListenerTls>>,
     ) -> Self {
         Self {
             addr,
             dispatcher,
             authenticator,
+            limiter,
+            udp_source,
+            tls,
+        }
+    }
+}
+
+/// hands `s` to SOCKS or HTTP depending on its first byte, `guard` is held
+/// for as long as either needs it
+#[allow(clippy::too_many_arguments)]
+async fn handle<S>(
+    mut s: S,
+    first: u8,
+    src_addr: SocketAddr,
+    local: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    authenticator: ThreadSafeAuthenticator,
+    udp_source: SocksUdpSource,
+    limiter: Arc<ListenerLimiter>,
+    guard: ConnectionGuard,
+) where
+    S: ProxyStream + AsTcpStream + 'static,
+{
+    match first {
+        socks::SOCKS4_VERSION | socks::SOCKS5_VERSION => {
+            let mut sess = Session {
+                network: Network::Tcp,
+                source: src_addr,
+                inbound_name: "mixed".to_owned(),
+
+                ..Default::default()
+            };
+            let _ = socks::handle_tcp(
+                &mut sess,
+                &mut s,
+                local,
+                dispatcher,
+                authenticator,
+                udp_source,
+                limiter,
+            )
+            .await;
+            drop(guard);
+        }
+
+        _ => {
+            http::handle_http(
+                Box::new(s),
+                src_addr,
+                local,
+                "mixed",
+                dispatcher,
+                authenticator,
+                Some(guard),
+            )
+            .await;
         }
     }
 }
@@ -49,53 +122,70 @@ impl InboundListener for Listener {
     }
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener = new_tcp_listener(self.addr)?;
 
         loop {
-            let (socket, _) = listener.accept().await?;
-            let mut socket = apply_tcp_options(socket)?;
+            let (socket, src_addr) = listener.accept().await?;
+            let src_addr = unmapped(src_addr);
 
-            let mut p = [0; 1];
-            let n = socket.peek(&mut p).await?;
-            if n != 1 {
-                warn!("failed to peek socket on mixed listener {}", self.addr);
+            let Some(guard) = self.limiter.try_acquire(src_addr.ip()) else {
                 continue;
-            }
+            };
+
+            let socket = apply_tcp_options(socket)?;
+            let local = socket.local_addr()?;
 
             let dispatcher = self.dispatcher.clone();
             let authenticator = self.authenticator.clone();
-
-            match p[0] {
-                socks::SOCKS5_VERSION => {
-                    let mut sess = Session {
-                        network: Network::Tcp,
-                        source: socket.peer_addr()?,
-
-                        ..Default::default()
-                    };
-
-                    tokio::spawn(async move {
-                        socks::handle_tcp(
-                            &mut sess,
-                            &mut socket,
-                            dispatcher,
-                            authenticator,
-                        )
-                        .await
-                    });
+            let udp_source = self.udp_source;
+            let limiter = self.limiter.clone();
+
+            let Some(tls) = self.tls.clone() else {
+                let mut p = [0; 1];
+                let n = socket.peek(&mut p).await?;
+                if n != 1 {
+                    warn!("failed to peek socket on mixed listener {}", self.addr);
+                    continue;
                 }
 
-                _ => {
-                    let src = socket.peer_addr()?;
-                    http::handle_http(
-                        Box::new(socket),
-                        src,
+                tokio::spawn(async move {
+                    handle(
+                        socket,
+                        p[0],
+                        src_addr,
+                        local,
                         dispatcher,
                         authenticator,
+                        udp_source,
+                        limiter,
+                        guard,
                     )
                     .await;
-                }
-            }
+                });
+                continue;
+            };
+
+            tokio::spawn(async move {
+                let Some(mut s) = tls.accept(socket, src_addr, "Mixed").await else {
+                    return;
+                };
+                // TLS streams can't be peeked, the byte is put back in front
+                let Ok(first) = s.read_u8().await else {
+                    return;
+                };
+                handle(
+                    PrefixedStream::new(vec![first], s),
+                    first,
+                    src_addr,
+                    local,
+                    dispatcher,
+                    authenticator,
+                    udp_source,
+                    limiter,
+                    guard,
+                )
+                .await;
+            });
         }
     }
 
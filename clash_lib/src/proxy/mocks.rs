@@ -12,7 +12,8 @@ use crate::{
             ProviderVehicleType,
         },
     },
-    session::Session,
+    config::def::UdpNat,
+    session::{Session, SocksAddr},
 };
 
 use super::{AnyOutboundHandler, DialWithConnector, OutboundHandler, OutboundType};
@@ -73,6 +74,10 @@ mock! {
 
         /// relay related
         async fn support_connector(&self) -> crate::proxy::ConnectorType;
+
+        fn server(&self) -> Option<SocksAddr>;
+
+        fn udp_nat(&self) -> Option<UdpNat>;
     }
 
     impl DialWithConnector for DummyOutboundHandler {}
@@ -2,9 +2,12 @@ use crate::{
     app::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
+        profile::ThreadSafeCacheFile,
     },
+    common::errors::new_io_error,
+    config::def::UdpNat,
     proxy::datagram::UdpPacket,
-    session::Session,
+    session::{Session, SocksAddr},
 };
 use async_trait::async_trait;
 use erased_serde::Serialize as ESerialize;
@@ -17,11 +20,12 @@ use std::{
     fmt::{Debug, Display},
     io,
     sync::Arc,
+    time::Duration,
 };
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use self::utils::RemoteConnector;
+use self::utils::{mtu::TunnelMtu, RemoteConnector};
 
 pub mod direct;
 pub mod reject;
@@ -34,18 +38,23 @@ pub mod tproxy;
 pub(crate) mod datagram;
 
 pub mod converters;
+#[cfg(feature = "quic-protocols")]
 pub mod hysteria2;
 #[cfg(feature = "shadowsocks")]
 pub mod shadowsocks;
 pub mod socks;
 #[cfg(feature = "onion")]
 pub mod tor;
+#[cfg(feature = "trojan")]
 pub mod trojan;
 #[cfg(feature = "tuic")]
 pub mod tuic;
 pub mod tun;
+pub mod tunnel;
 pub mod utils;
+#[cfg(feature = "vmess")]
 pub mod vmess;
+#[cfg(feature = "wireguard")]
 pub mod wg;
 
 pub mod group;
@@ -235,6 +244,58 @@ pub trait OutboundHandler: Sync + Send + Unpin + DialWithConnector + Debug {
     fn icon(&self) -> Option<String> {
         None
     }
+
+    /// the server it connects to, pinged by the icmp health check, None
+    /// for the ones without a server of their own like groups
+    fn server(&self) -> Option<SocksAddr> {
+        None
+    }
+
+    /// the tunnel it sends everything through, e.g. wireguard, whose MTU
+    /// TCP to it is clamped to. None if it carries streams and datagrams
+    /// as they are
+    fn tunnel_mtu(&self) -> Option<TunnelMtu> {
+        None
+    }
+
+    /// how the UDP it relays is NATed, None for the global `udp-nat`. the
+    /// ones that can't relay from any peer are symmetric
+    fn udp_nat(&self) -> Option<UdpNat> {
+        Some(UdpNat::Symmetric)
+    }
+
+    /// how often `pre-connect` checks the connection shared with the server
+    /// is open, a bit less than the server's idle timeout. None for the ones
+    /// without a connection shared between dials, they ignore `pre-connect`
+    fn pre_connect_refresh(&self) -> Option<Duration> {
+        None
+    }
+
+    /// opens the connection shared with the server if it isn't open, or not
+    /// on the current network, so the next dial doesn't wait for it
+    async fn warm_up(&self, _resolver: ThreadSafeDNSResolver) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// the cache of the running config, for the ones that keep something in
+    /// it. Set again when the handler is taken over by a reload
+    fn set_cache_store(&self, _cache_store: ThreadSafeCacheFile) {}
+
+    /// looks the server up ahead of the first dial, for the DNS cache and
+    /// the addresses the handler keeps. None when there's no name to look up
+    async fn prefetch(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Option<io::Result<()>> {
+        let SocksAddr::Domain(host, _) = self.server()? else {
+            return None;
+        };
+        Some(match resolver.resolve(&host, false).await {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(new_io_error(format!("no address for {}", host))),
+            Err(e) => Err(new_io_error(format!("can't resolve {}: {}", host, e))),
+        })
+    }
 }
 pub type AnyOutboundHandler = Arc<dyn OutboundHandler>;
 
@@ -1,5 +1,13 @@
+#[cfg(any(feature = "vmess", feature = "trojan"))]
 use std::collections::HashMap;
+use std::time::Duration;
 
+use crate::{
+    config::internal::proxy::ResolveStrategy,
+    proxy::utils::{connect_timeout, SocketBuffers},
+};
+
+#[cfg(feature = "vmess")]
 #[allow(dead_code)]
 pub struct HttpOption {
     pub method: String,
@@ -7,16 +15,19 @@ pub struct HttpOption {
     pub headers: HashMap<String, String>,
 }
 
+#[cfg(feature = "vmess")]
 pub struct Http2Option {
     pub host: Vec<String>,
     pub path: String,
 }
 
+#[cfg(any(feature = "vmess", feature = "trojan"))]
 pub struct GrpcOption {
     pub host: String,
     pub service_name: String,
 }
 
+#[cfg(any(feature = "vmess", feature = "trojan"))]
 pub struct WsOption {
     pub path: String,
     pub headers: HashMap<String, String>,
@@ -28,4 +39,25 @@ pub struct WsOption {
 pub struct HandlerCommonOptions {
     pub connector: Option<String>,
     pub icon: Option<String>,
+    /// how the server's name is resolved, when it's dialed directly
+    pub resolve_strategy: Option<ResolveStrategy>,
+    /// how long connecting to the server may take, instead of
+    /// `connect-timeout`
+    pub dial_timeout: Option<Duration>,
+    /// how long the protocol's handshake with the server may take
+    pub handshake_timeout: Option<Duration>,
+    /// of the connections to the server, instead of the global ones
+    pub socket_buffers: SocketBuffers,
+}
+
+impl HandlerCommonOptions {
+    pub fn dial_timeout(&self) -> Duration {
+        self.dial_timeout.unwrap_or_else(connect_timeout)
+    }
+
+    /// the dial timeout unless set
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+            .unwrap_or_else(|| self.dial_timeout())
+    }
 }
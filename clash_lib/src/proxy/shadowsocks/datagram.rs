@@ -21,7 +21,10 @@ use tokio::io::ReadBuf;
 use tracing::{debug, error, instrument};
 
 use crate::{
-    common::errors::new_io_error,
+    common::{
+        errors::new_io_error,
+        pool::{PooledBuf, DATAGRAM_POOL},
+    },
     proxy::{datagram::UdpPacket, AnyOutboundDatagram},
     session::SocksAddr,
 };
@@ -33,7 +36,7 @@ pub struct OutboundDatagramShadowsocks<S> {
     remote_addr: SocketAddr,
     flushed: bool,
     pkt: Option<UdpPacket>,
-    buf: Vec<u8>,
+    buf: PooledBuf,
 
     ss_control: UdpSocketControlData,
 }
@@ -48,7 +51,7 @@ impl<S> OutboundDatagramShadowsocks<S> {
             flushed: true,
             pkt: None,
             remote_addr,
-            buf: vec![0u8; 65535],
+            buf: DATAGRAM_POOL.get(65535),
 
             ss_control,
         }
@@ -1,12 +1,15 @@
 mod datagram;
 mod shadow_tls;
 mod simple_obfs;
+mod sip003;
 mod stream;
 mod v2ray;
 
 use self::{datagram::OutboundDatagramShadowsocks, stream::ShadowSocksStream};
 use super::{
-    utils::{RemoteConnector, GLOBAL_DIRECT_CONNECTOR},
+    utils::{
+        DialError, RemoteConnector, ServerList, TimedStream, GLOBAL_DIRECT_CONNECTOR,
+    },
     AnyStream, ConnectorType, DialWithConnector, OutboundType,
 };
 use crate::{
@@ -16,22 +19,46 @@ use crate::{
             ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
         },
         dns::ThreadSafeDNSResolver,
+        profile::ThreadSafeCacheFile,
     },
-    common::errors::new_io_error,
     impl_default_connector,
     proxy::{HandlerCommonOptions, OutboundHandler},
-    session::Session,
+    session::{Session, SocksAddr},
 };
 use async_trait::async_trait;
 use datagram::ShadowsocksUdpIo;
+use erased_serde::Serialize as ESerialize;
 use shadowsocks::{
     config::ServerType, context::Context, crypto::CipherKind,
     relay::udprelay::proxy_socket::UdpSocketType, ProxyClientStream, ProxySocket,
     ServerConfig,
 };
-use std::{collections::HashMap, fmt::Debug, io, sync::Arc};
+pub use sip003::Sip003Option;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    sync::{Arc, OnceLock},
+};
 use tracing::debug;
 
+pub(crate) fn cipher_kind(cipher: &str) -> Option<CipherKind> {
+    Some(match cipher {
+        "aes-128-gcm" => CipherKind::AES_128_GCM,
+        "aes-256-gcm" => CipherKind::AES_256_GCM,
+        "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
+
+        "2022-blake3-aes-128-gcm" => CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+        "2022-blake3-aes-256-gcm" => CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+        "2022-blake3-chacha20-ietf-poly1305" => {
+            CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305
+        }
+
+        "rc4-md5" => CipherKind::SS_RC4_MD5,
+        _ => return None,
+    })
+}
+
 #[derive(Clone, Copy)]
 pub enum SimpleOBFSMode {
     Http,
@@ -65,6 +92,8 @@ pub enum OBFSOption {
     Simple(SimpleOBFSOption),
     V2Ray(V2RayOBFSOption),
     ShadowTls(ShadowTlsOption),
+    /// an external plugin, dialed instead of the server
+    Sip003(Sip003Option),
 }
 
 pub struct HandlerOptions {
@@ -76,10 +105,17 @@ pub struct HandlerOptions {
     pub cipher: String,
     pub plugin_opts: Option<OBFSOption>,
     pub udp: bool,
+    /// every (server, port) to try in turn, when there are several, `server`
+    /// and `port` being the first
+    pub servers: Vec<(String, u16)>,
 }
 
 pub struct Handler {
     opts: HandlerOptions,
+    server_addr: ServerList,
+    server_config: OnceLock<ServerConfig>,
+    /// started on the first dial
+    plugin: tokio::sync::OnceCell<sip003::Plugin>,
 
     connector: tokio::sync::Mutex<Option<Arc<dyn RemoteConnector>>>,
 }
@@ -97,7 +133,20 @@ impl Debug for Handler {
 impl Handler {
     pub fn new(opts: HandlerOptions) -> Self {
         Self {
+            server_addr: ServerList::new(
+                &opts.name,
+                if opts.servers.is_empty() {
+                    vec![(opts.server.clone(), opts.port)]
+                } else {
+                    opts.servers.clone()
+                },
+                opts.common_opts.resolve_strategy,
+                opts.common_opts.dial_timeout,
+                opts.common_opts.socket_buffers,
+            ),
             opts,
+            server_config: OnceLock::new(),
+            plugin: tokio::sync::OnceCell::new(),
             connector: tokio::sync::Mutex::new(None),
         }
     }
@@ -108,13 +157,21 @@ impl Handler {
         sess: &Session,
         #[allow(unused_variables)] _resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<AnyStream> {
+        // the plugins' handshakes, the one of shadowsocks itself is sent
+        // with the first data
+        let (s, handshake) = TimedStream::new(
+            s,
+            "shadowsocks handshake",
+            self.opts.common_opts.handshake_timeout(),
+        );
+        let s: AnyStream = Box::new(s);
         let stream: AnyStream = match &self.opts.plugin_opts {
             Some(plugin) => match plugin {
                 OBFSOption::Simple(opts) => match opts.mode {
                     SimpleOBFSMode::Http => simple_obfs::SimpleObfsHTTP::new(
                         s,
                         opts.host.clone(),
-                        self.opts.port,
+                        self.server_addr.current().port(),
                     )
                     .into(),
                     SimpleOBFSMode::Tls => {
@@ -127,11 +184,17 @@ impl Handler {
                 OBFSOption::ShadowTls(opts) => {
                     tracing::trace!("using shadow-tls");
 
-                    (shadow_tls::Connector::wrap(opts, s).await?) as _
+                    (shadow_tls::Connector::wrap(opts, s)
+                        .await
+                        .map_err(|e| DialError::ProxyHandshake.tag(e))?)
+                        as _
                 }
+                // already through the plugin
+                OBFSOption::Sip003(_) => s,
             },
             None => s,
         };
+        handshake.done();
 
         let ctx = Context::new_shared(ServerType::Local);
         let cfg = self.server_config()?;
@@ -147,30 +210,18 @@ impl Handler {
     }
 
     fn server_config(&self) -> Result<ServerConfig, io::Error> {
+        // the key is derived from the password here, once
+        if let Some(cfg) = self.server_config.get() {
+            return Ok(cfg.clone());
+        }
+        let cipher = cipher_kind(&self.opts.cipher)
+            .ok_or_else(|| DialError::Unsupported.error("unsupported cipher"))?;
         let cfg = ServerConfig::new(
             (self.opts.server.to_owned(), self.opts.port),
             self.opts.password.to_owned(),
-            match self.opts.cipher.as_str() {
-                "aes-128-gcm" => CipherKind::AES_128_GCM,
-                "aes-256-gcm" => CipherKind::AES_256_GCM,
-                "chacha20-ietf-poly1305" => CipherKind::CHACHA20_POLY1305,
-
-                "2022-blake3-aes-128-gcm" => CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
-                "2022-blake3-aes-256-gcm" => CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
-                "2022-blake3-chacha20-ietf-poly1305" => {
-                    CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305
-                }
-
-                "rc4-md5" => CipherKind::SS_RC4_MD5,
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "unsupported cipher",
-                    ))
-                }
-            },
+            cipher,
         );
-        Ok(cfg)
+        Ok(self.server_config.get_or_init(|| cfg).clone())
     }
 }
 
@@ -184,6 +235,30 @@ impl OutboundHandler for Handler {
         OutboundType::Shadowsocks
     }
 
+    fn server(&self) -> Option<SocksAddr> {
+        self.server_addr.socks_addr()
+    }
+
+    fn set_cache_store(&self, cache_store: ThreadSafeCacheFile) {
+        self.server_addr.set_cache(cache_store);
+    }
+
+    async fn prefetch(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Option<io::Result<()>> {
+        self.server_addr.prefetch(&resolver).await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = HashMap::new();
+        m.insert("type".to_string(), Box::new(self.proto()) as _);
+        if let Some(active) = self.server_addr.active() {
+            m.insert("activeServer".to_string(), Box::new(active) as _);
+        }
+        m
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -242,16 +317,38 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = connector
-            .connect_stream(
-                resolver.clone(),
-                self.opts.server.as_str(),
-                self.opts.port,
-                sess.iface.as_ref(),
-                #[cfg(any(target_os = "linux", target_os = "android"))]
-                sess.so_mark,
-            )
-            .await?;
+        let stream: AnyStream = match &self.opts.plugin_opts {
+            Some(OBFSOption::Sip003(opts)) => {
+                let plugin = self
+                    .plugin
+                    .get_or_try_init(|| async {
+                        sip003::Plugin::start(
+                            &self.opts.name,
+                            opts,
+                            &self.opts.server,
+                            self.opts.port,
+                        )
+                    })
+                    .await?;
+                Box::new(
+                    plugin
+                        .connect()
+                        .await
+                        .map_err(|e| DialError::Connect.tag(e))?,
+                )
+            }
+            _ => {
+                self.server_addr
+                    .connect_stream(
+                        connector,
+                        resolver.clone(),
+                        sess.iface.as_ref(),
+                        #[cfg(any(target_os = "linux", target_os = "android"))]
+                        sess.so_mark,
+                    )
+                    .await?
+            }
+        };
 
         let s = self.proxy_stream(stream, sess, resolver).await?;
         let chained = ChainedStreamWrapper::new(s);
@@ -267,12 +364,13 @@ impl OutboundHandler for Handler {
     ) -> io::Result<BoxedChainedDatagram> {
         let ctx = Context::new_shared(ServerType::Local);
         let cfg = self.server_config()?;
+        let server = self.server_addr.current();
 
-        let socket = connector
+        let socket = server
             .connect_datagram(
+                connector,
                 resolver.clone(),
-                None,
-                (self.opts.server.clone(), self.opts.port).try_into()?,
+                (server.host().to_owned(), server.port()).try_into()?,
                 sess.iface.as_ref().cloned(),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 sess.so_mark,
@@ -286,21 +384,21 @@ impl OutboundHandler for Handler {
             ShadowsocksUdpIo::new(socket),
         );
         let server_addr = resolver
-            .resolve(&self.opts.server, false)
+            .resolve(server.host(), false)
             .await
             .map_err(|x| {
-                new_io_error(format!(
+                DialError::Dns.error(format!(
                     "failed to resolve {}: {}",
-                    self.opts.server, x
+                    server.host(),
+                    x
                 ))
             })?
-            .ok_or(new_io_error(format!(
-                "failed to resolve {}",
-                self.opts.server
-            )))?;
+            .ok_or_else(|| {
+                DialError::Dns.error(format!("failed to resolve {}", server.host()))
+            })?;
         let d = OutboundDatagramShadowsocks::new(
             socket,
-            (server_addr, self.opts.port).into(),
+            (server_addr, server.port()).into(),
         );
         let d = ChainedDatagramWrapper::new(d);
         d.append_to_chain(self.name()).await;
@@ -351,6 +449,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin_opts: Default::default(),
             udp: false,
+            servers: vec![],
         };
         let port = opts.port;
         let handler = Arc::new(Handler::new(opts));
@@ -410,6 +509,7 @@ mod tests {
                 strict: true,
             })),
             udp: false,
+            servers: vec![],
         };
         let handler: Arc<dyn OutboundHandler> = Arc::new(Handler::new(opts));
         // we need to store all the runners in a container, to make sure all of
@@ -465,6 +565,7 @@ mod tests {
                 mode,
             })),
             udp: false,
+            servers: vec![],
         };
 
         let handler: Arc<dyn OutboundHandler> = Arc::new(Handler::new(opts));
@@ -0,0 +1,197 @@
+//! An external SIP003 plugin, a process listening on a loopback port that
+//! carries what's sent to it to the server, e.g. `xray-plugin`.
+//!
+//! The process is only started on the first dial, then restarted whenever it
+//! exits, and killed with the handler, on a reload or on shutdown.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    process::Stdio,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    net::TcpStream,
+    process::Command,
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, warn};
+
+use crate::{common::errors::new_io_error, proxy::utils::new_tcp_stream};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// how long it may take to start listening
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct Sip003Option {
+    /// the plugin binary
+    pub command: String,
+    pub args: Vec<String>,
+    /// SS_PLUGIN_OPTIONS, e.g. `tls;host=example.com`
+    pub options: Option<String>,
+}
+
+pub struct Plugin {
+    local: SocketAddr,
+    supervisor: JoinHandle<()>,
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        // the child is killed with the task owning it
+        self.supervisor.abort();
+    }
+}
+
+impl Plugin {
+    /// Starts the plugin of proxy `name` for `server:port`, on a free
+    /// loopback port.
+    pub fn start(
+        name: &str,
+        opts: &Sip003Option,
+        server: &str,
+        port: u16,
+    ) -> std::io::Result<Self> {
+        let local =
+            std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?;
+
+        let mut cmd = Command::new(&opts.command);
+        cmd.args(&opts.args)
+            .env("SS_REMOTE_HOST", server)
+            .env("SS_REMOTE_PORT", port.to_string())
+            .env("SS_LOCAL_HOST", local.ip().to_string())
+            .env("SS_LOCAL_PORT", local.port().to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(options) = &opts.options {
+            cmd.env("SS_PLUGIN_OPTIONS", options);
+        }
+
+        let supervisor = tokio::spawn(supervise(name.to_owned(), cmd));
+        Ok(Self { local, supervisor })
+    }
+
+    /// A connection to the plugin, waiting for it to listen if it's only
+    /// just started.
+    pub async fn connect(&self) -> std::io::Result<TcpStream> {
+        let deadline = Instant::now() + START_TIMEOUT;
+        loop {
+            match new_tcp_stream(
+                self.local,
+                None,
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await
+            {
+                Ok(s) => return Ok(s),
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(new_io_error(format!(
+                        "sip003 plugin not listening on {}: {}",
+                        self.local, e
+                    )));
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
+}
+
+/// Runs the plugin until the task is aborted, restarting it with a backoff
+/// that's reset once it stays up for long enough.
+async fn supervise(name: String, mut cmd: Command) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        match cmd.spawn() {
+            Ok(mut child) => {
+                debug!("sip003 plugin of {} started", name);
+                if let Some(out) = child.stdout.take() {
+                    tokio::spawn(log_lines(name.clone(), out));
+                }
+                if let Some(err) = child.stderr.take() {
+                    tokio::spawn(log_lines(name.clone(), err));
+                }
+                match child.wait().await {
+                    Ok(status) => {
+                        warn!("sip003 plugin of {} exited: {}", name, status)
+                    }
+                    Err(e) => warn!("sip003 plugin of {} lost: {}", name, e),
+                }
+            }
+            Err(e) => warn!("failed to start sip003 plugin of {}: {}", name, e),
+        }
+
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn log_lines(name: String, r: impl AsyncRead + Unpin) {
+    let mut lines = BufReader::new(r).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        debug!(proxy = %name, "sip003 plugin: {}", line);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Plugin, Sip003Option};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarted_with_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("env");
+        let opts = Sip003Option {
+            command: "sh".to_owned(),
+            args: vec![
+                "-c".to_owned(),
+                format!(
+                    "echo \"$SS_REMOTE_HOST:$SS_REMOTE_PORT \
+                     $SS_LOCAL_HOST:$SS_LOCAL_PORT $SS_PLUGIN_OPTIONS\" >> {}",
+                    out.display()
+                ),
+            ],
+            options: Some("tls;host=example.com".to_owned()),
+        };
+
+        let plugin = Plugin::start("ss", &opts, "example.com", 8388).unwrap();
+        // exits right away, to be started again a second later. The process
+        // runs in real time, so the clock is only moved on between real
+        // waits for it, up to a minute of it
+        let mut lines = vec![];
+        for _ in 0..600 {
+            tokio::task::spawn_blocking(|| {
+                std::thread::sleep(Duration::from_millis(10))
+            })
+            .await
+            .unwrap();
+            lines = std::fs::read_to_string(&out)
+                .unwrap_or_default()
+                .lines()
+                .map(ToOwned::to_owned)
+                .collect::<Vec<_>>();
+            if lines.len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        drop(plugin);
+
+        assert!(lines.len() >= 2, "not restarted: {:?}", lines);
+        assert_eq!(lines[0], lines[1]);
+        assert!(lines[0].starts_with("example.com:8388 127.0.0.1:"));
+        assert!(lines[0].ends_with(" tls;host=example.com"));
+    }
+}
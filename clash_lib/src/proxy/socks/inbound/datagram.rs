@@ -1,16 +1,21 @@
-use crate::{proxy::datagram::UdpPacket, session::SocksAddr};
+use crate::{
+    app::inbound::limiter::ListenerLimiter, proxy::datagram::UdpPacket,
+    session::SocksAddr,
+};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use futures::{Sink, SinkExt, Stream, StreamExt};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
 use std::{
     io,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 use tokio_util::{
     codec::{Decoder, Encoder},
     udp::UdpFramed,
 };
+use tracing::trace;
 
 // +----+------+------+----------+----------+----------+
 // |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
@@ -75,8 +80,27 @@ impl Decoder for Socks5UDPCodec {
     }
 }
 
+/// Where an association takes datagrams from, those from anywhere else are
+/// dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AllowedSource {
+    pub ip: IpAddr,
+    /// any port when None
+    pub port: Option<u16>,
+}
+
+impl AllowedSource {
+    pub fn allows(&self, src: SocketAddr) -> bool {
+        src.ip().to_canonical() == self.ip.to_canonical()
+            && (self.port.is_none() || self.port == Some(src.port()))
+    }
+}
+
 pub struct InboundUdp<I> {
     inner: I,
+    source: AllowedSource,
+    /// of the listener the association was made on, for its ACL
+    limiter: Arc<ListenerLimiter>,
 }
 
 impl<I> InboundUdp<I>
@@ -84,8 +108,16 @@ where
     I: Stream + Unpin,
     I: Sink<((Bytes, SocksAddr), SocketAddr)>,
 {
-    pub fn new(inner: I) -> Self {
-        Self { inner }
+    pub fn new(
+        inner: I,
+        source: AllowedSource,
+        limiter: Arc<ListenerLimiter>,
+    ) -> Self {
+        Self {
+            inner,
+            source,
+            limiter,
+        }
     }
 }
 
@@ -104,19 +136,31 @@ impl Stream for InboundUdp<UdpFramed<Socks5UDPCodec>> {
     ) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
 
-        match pin.inner.poll_next_unpin(cx) {
-            Poll::Ready(item) => match item {
-                None => Poll::Ready(None),
-                Some(item) => match item {
-                    Ok(((dst, pkt), src)) => Poll::Ready(Some(UdpPacket {
+        loop {
+            match ready!(pin.inner.poll_next_unpin(cx)) {
+                None => return Poll::Ready(None),
+                Some(Ok(((dst, pkt), src))) => {
+                    if !pin.source.allows(src) {
+                        trace!(
+                            "dropping socks5 datagram from {}, expected {:?}",
+                            src,
+                            pin.source
+                        );
+                        continue;
+                    }
+                    // the declared source of a strict one may not be the
+                    // client that passed the ACL
+                    if !pin.limiter.allows(src.ip()) {
+                        continue;
+                    }
+                    return Poll::Ready(Some(UdpPacket {
                         data: pkt.to_vec(),
                         src_addr: SocksAddr::Ip(src),
                         dst_addr: dst,
-                    })),
-                    Err(_) => Poll::Ready(None),
-                },
-            },
-            Poll::Pending => Poll::Pending,
+                    }));
+                }
+                Some(Err(_)) => return Poll::Ready(None),
+            }
         }
     }
 }
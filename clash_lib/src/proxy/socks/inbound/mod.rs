@@ -2,15 +2,19 @@ mod datagram;
 mod stream;
 
 use crate::{
+    app::inbound::{limiter::ListenerLimiter, tls::ListenerTls},
     common::auth::ThreadSafeAuthenticator,
-    proxy::{utils::apply_tcp_options, InboundListener},
+    config::def::SocksUdpSource,
+    proxy::{
+        utils::{apply_tcp_options, new_tcp_listener, unmapped},
+        InboundListener,
+    },
     session::{Network, Session, Type},
     Dispatcher,
 };
 use async_trait::async_trait;
 use std::{net::SocketAddr, sync::Arc};
 pub use stream::handle_tcp;
-use tokio::net::TcpListener;
 use tracing::warn;
 
 pub use datagram::Socks5UDPCodec;
@@ -19,6 +23,9 @@ pub struct Listener {
     addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    limiter: Arc<ListenerLimiter>,
+    udp_source: SocksUdpSource,
+    tls: Option<Arc<ListenerTls>>,
 }
 
 impl Drop for Listener {
@@ -32,11 +39,17 @@ impl Listener {
         addr: SocketAddr,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        limiter: Arc<ListenerLimiter>,
+        udp_source: SocksUdpSource,
+        tls: Option<Arc<ListenerTls>>,
     ) -> Self {
         Self {
             addr,
             dispatcher,
             authenticator,
+            limiter,
+            udp_source,
+            tls,
         }
     }
 }
@@ -52,26 +65,68 @@ impl InboundListener for Listener {
     }
 
     async fn listen_tcp(&self) -> std::io::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener = new_tcp_listener(self.addr)?;
 
         loop {
-            let (socket, _) = listener.accept().await?;
+            let (socket, src_addr) = listener.accept().await?;
+            let src_addr = unmapped(src_addr);
+
+            let Some(guard) = self.limiter.try_acquire(src_addr.ip()) else {
+                continue;
+            };
 
             let mut socket = apply_tcp_options(socket)?;
 
             let mut sess = Session {
                 network: Network::Tcp,
                 typ: Type::Socks5,
-                source: socket.peer_addr()?,
+                source: src_addr,
+                inbound_name: "socks".to_owned(),
 
                 ..Default::default()
             };
 
+            let local = socket.local_addr()?;
             let dispatcher = self.dispatcher.clone();
             let authenticator = self.authenticator.clone();
+            let udp_source = self.udp_source;
+            let tls = self.tls.clone();
+            let limiter = self.limiter.clone();
 
             tokio::spawn(async move {
-                handle_tcp(&mut sess, &mut socket, dispatcher, authenticator).await
+                let rv = match tls {
+                    Some(tls) => {
+                        let Some(mut socket) =
+                            tls.accept(socket, src_addr, "SOCKS5").await
+                        else {
+                            return Ok(());
+                        };
+                        handle_tcp(
+                            &mut sess,
+                            &mut socket,
+                            local,
+                            dispatcher,
+                            authenticator,
+                            udp_source,
+                            limiter,
+                        )
+                        .await
+                    }
+                    None => {
+                        handle_tcp(
+                            &mut sess,
+                            &mut socket,
+                            local,
+                            dispatcher,
+                            authenticator,
+                            udp_source,
+                            limiter,
+                        )
+                        .await
+                    }
+                };
+                drop(guard);
+                rv
             });
         }
     }
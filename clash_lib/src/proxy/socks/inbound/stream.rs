@@ -1,8 +1,11 @@
 use crate::{
-    common::{auth::ThreadSafeAuthenticator, errors::new_io_error},
+    app::inbound::limiter::ListenerLimiter,
+    common::{auth::ThreadSafeAuthenticator, errors::new_io_error, io::AsTcpStream},
+    config::def::SocksUdpSource,
     proxy::{
         socks::{
-            inbound::datagram::InboundUdp,
+            inbound::datagram::{AllowedSource, InboundUdp},
+            socks4::{self, socks4_command, SOCKS4_VERSION},
             socks5::{auth_methods, response_code, socks_command},
             Socks5UDPCodec, SOCKS5_VERSION,
         },
@@ -14,20 +17,26 @@ use crate::{
 use bytes::{BufMut, BytesMut};
 
 use std::{io, net::SocketAddr, str, sync::Arc};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_util::udp::UdpFramed;
 use tracing::{instrument, trace, warn};
 
-#[instrument(skip(sess, s, dispatcher, authenticator))]
-pub async fn handle_tcp<'a>(
+/// `local` is the address the client reached the listener at, `s` may be
+/// wrapped in TLS so it's given apart. `limiter` is of the listener, for the
+/// ACL of the UDP associations.
+#[instrument(skip(sess, s, dispatcher, authenticator, limiter))]
+pub async fn handle_tcp<'a, S>(
     sess: &'a mut Session,
-    s: &'a mut TcpStream,
+    s: &'a mut S,
+    local: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
-) -> io::Result<()> {
+    udp_source: SocksUdpSource,
+    limiter: Arc<ListenerLimiter>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
+{
     // handshake
     let mut buf = BytesMut::new();
     {
@@ -35,6 +44,10 @@ pub async fn handle_tcp<'a>(
         buf.resize(2, 0);
         s.read_exact(&mut buf[..]).await?;
 
+        if buf[0] == SOCKS4_VERSION {
+            return handle_socks4(sess, s, buf[1], dispatcher, authenticator).await;
+        }
+
         if buf[0] != SOCKS5_VERSION {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
@@ -98,6 +111,7 @@ pub async fn handle_tcp<'a>(
                 true => {
                     response = [0x1, response_code::SUCCEEDED];
                     s.write_all(&response).await?;
+                    sess.inbound_user = Some(user);
                 }
                 false => {
                     response = [0x1, response_code::FAILURE];
@@ -133,23 +147,20 @@ pub async fn handle_tcp<'a>(
 
     match buf[1] {
         socks_command::CONNECT => {
-            trace!("Got a CONNECT request from {}", s.peer_addr()?);
+            trace!("Got a CONNECT request from {}", sess.source);
 
-            buf.clear();
-            buf.put_u8(SOCKS5_VERSION);
-            buf.put_u8(response_code::SUCCEEDED);
-            buf.put_u8(0x0);
-            let bnd = SocksAddr::from(s.local_addr()?);
-            bnd.write_buf(&mut buf);
-            s.write_all(&buf[..]).await?;
             sess.destination = dst;
 
-            dispatcher.dispatch_stream(sess.to_owned(), s).await;
+            // replied to once the outbound is up, with where it's bound
+            dispatcher
+                .dispatch_stream_with_reply(sess.to_owned(), s, connect_reply)
+                .await;
 
             Ok(())
         }
         socks_command::UDP_ASSOCIATE => {
-            let udp_addr = SocketAddr::new(s.local_addr()?.ip(), 0);
+            let udp_addr = udp_relay_addr(local);
+            let source = allowed_source(sess.source, &dst, udp_source);
             let udp_inbound = new_udp_socket(
                 Some(udp_addr),
                 None,
@@ -159,9 +170,11 @@ pub async fn handle_tcp<'a>(
             .await?;
 
             trace!(
-                "Got a UDP_ASSOCIATE request from {}, UDP assigned at {}",
-                s.peer_addr()?,
-                udp_inbound.local_addr()?
+                "Got a UDP_ASSOCIATE request from {}, UDP assigned at {}, taking \
+                 datagrams from {:?}",
+                sess.source,
+                udp_inbound.local_addr()?,
+                source
             );
 
             buf.clear();
@@ -180,14 +193,18 @@ pub async fn handle_tcp<'a>(
                 typ: Type::Socks5,
                 so_mark: None,
                 iface: None,
+                inbound_name: sess.inbound_name.clone(),
+                inbound_user: sess.inbound_user.clone(),
                 ..Default::default()
             };
 
             let dispatcher_cloned = dispatcher.clone();
 
             tokio::spawn(async move {
-                let handle = dispatcher_cloned
-                    .dispatch_datagram(sess, Box::new(InboundUdp::new(framed)));
+                let handle = dispatcher_cloned.dispatch_datagram(
+                    sess,
+                    Box::new(InboundUdp::new(framed, source, limiter)),
+                );
                 close_listener.await.ok();
                 handle.send(0).ok();
             });
@@ -222,3 +239,295 @@ pub async fn handle_tcp<'a>(
         }
     }
 }
+
+/// Where the relay of a UDP association is bound, on the address the client
+/// reached us at so it's of the family the client uses. A client over IPv4
+/// to a dual-stack listener gets an IPv4 one.
+fn udp_relay_addr(local: SocketAddr) -> SocketAddr {
+    match local {
+        SocketAddr::V6(a) if a.ip().to_ipv4_mapped().is_some() => {
+            SocketAddr::new(a.ip().to_canonical(), 0)
+        }
+        mut a => {
+            a.set_port(0);
+            a
+        }
+    }
+}
+
+/// The IP of the client on any port, or with `strict`, the address it
+/// declared in the request, the parts of it it declared.
+fn allowed_source(
+    peer: SocketAddr,
+    declared: &SocksAddr,
+    udp_source: SocksUdpSource,
+) -> AllowedSource {
+    let loose = AllowedSource {
+        ip: peer.ip(),
+        port: None,
+    };
+    match (udp_source, declared) {
+        (SocksUdpSource::Loose, _) => loose,
+        (SocksUdpSource::Strict, SocksAddr::Ip(addr)) => AllowedSource {
+            ip: if addr.ip().is_unspecified() {
+                peer.ip()
+            } else {
+                addr.ip()
+            },
+            port: Some(addr.port()).filter(|x| *x != 0),
+        },
+        (SocksUdpSource::Strict, SocksAddr::Domain(..)) => loose,
+    }
+}
+
+async fn connect_reply<S: AsyncWrite + Unpin>(
+    mut s: S,
+    dialed: Result<SocksAddr, io::ErrorKind>,
+) -> io::Result<S> {
+    let mut buf = BytesMut::new();
+    buf.put_u8(SOCKS5_VERSION);
+    let bnd = match dialed {
+        Ok(bnd) => {
+            buf.put_u8(response_code::SUCCEEDED);
+            bnd
+        }
+        Err(kind) => {
+            buf.put_u8(response_code::for_dial_error(kind));
+            SocksAddr::any_ipv4()
+        }
+    };
+    buf.put_u8(0x0);
+    bnd.write_buf(&mut buf);
+    s.write_all(&buf[..]).await?;
+    Ok(s)
+}
+
+/// handles a SOCKS4/SOCKS4a request whose version and command bytes have
+/// already been consumed
+async fn handle_socks4<S>(
+    sess: &mut Session,
+    s: &mut S,
+    command: u8,
+    dispatcher: Arc<Dispatcher>,
+    authenticator: ThreadSafeAuthenticator,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
+{
+    let req = socks4::Request::read_from(s).await?;
+    let mut buf = BytesMut::new();
+
+    if authenticator.enabled() {
+        let (user, pass) = req.credentials();
+        if !authenticator.authenticate(user, pass) {
+            socks4::write_response(&mut buf, socks4::response_code::REJECTED);
+            s.write_all(&buf).await?;
+            s.shutdown().await?;
+            return Err(new_io_error("auth failure"));
+        }
+        sess.inbound_user = Some(user.to_owned());
+    }
+
+    match command {
+        socks4_command::CONNECT => {
+            trace!("Got a SOCKS4 CONNECT request from {}", sess.source);
+
+            socks4::write_response(&mut buf, socks4::response_code::GRANTED);
+            s.write_all(&buf).await?;
+            sess.typ = Type::Socks4;
+            sess.destination = req.destination;
+
+            dispatcher.dispatch_stream(sess.to_owned(), s).await;
+
+            Ok(())
+        }
+        // BIND is not supported
+        _ => {
+            socks4::write_response(&mut buf, socks4::response_code::REJECTED);
+            s.write_all(&buf).await?;
+            Err(new_io_error("unsupported SOCKS4 command"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, net::IpAddr, sync::Arc, time::Duration};
+
+    use bytes::{Bytes, BytesMut};
+    use futures::StreamExt;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
+    use tokio_util::{codec::Encoder, udp::UdpFramed};
+
+    use super::{allowed_source, connect_reply, udp_relay_addr};
+    use crate::{
+        app::inbound::{acl::Acl, limiter::ConnectionLimiter},
+        config::{def::SocksUdpSource, internal::config::ListenerAcl},
+        proxy::socks::{
+            inbound::datagram::{AllowedSource, InboundUdp},
+            Socks5UDPCodec,
+        },
+        session::SocksAddr,
+    };
+
+    async fn reply(dialed: Result<SocksAddr, io::ErrorKind>) -> Vec<u8> {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut c = TcpStream::connect(l.local_addr().unwrap()).await.unwrap();
+        let (mut s, _) = l.accept().await.unwrap();
+        connect_reply(&mut s, dialed).await.unwrap();
+        drop(s);
+        let mut buf = vec![];
+        c.read_to_end(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_connect_reply() {
+        let bound = SocksAddr::Ip("10.0.0.2:40000".parse().unwrap());
+        assert_eq!(
+            reply(Ok(bound)).await,
+            [5, 0, 0, 1, 10, 0, 0, 2, 0x9c, 0x40]
+        );
+
+        let server = SocksAddr::Domain("example.com".to_owned(), 443);
+        let r = reply(Ok(server)).await;
+        assert_eq!(&r[..5], &[5, 0, 0, 3, 11]);
+        assert_eq!(&r[5..16], b"example.com");
+
+        assert_eq!(
+            reply(Err(io::ErrorKind::ConnectionRefused)).await,
+            [5, 5, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(reply(Err(io::ErrorKind::TimedOut)).await[1], 6);
+        assert_eq!(reply(Err(io::ErrorKind::HostUnreachable)).await[1], 4);
+        assert_eq!(reply(Err(io::ErrorKind::Other)).await[1], 1);
+    }
+
+    fn datagram(data: &'static [u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        Socks5UDPCodec
+            .encode(
+                (
+                    Bytes::from_static(data),
+                    SocksAddr::Ip("1.1.1.1:53".parse().unwrap()),
+                ),
+                &mut buf,
+            )
+            .unwrap();
+        buf.to_vec()
+    }
+
+    /// an association made over `connect` to a listener on `listen`, with
+    /// the client declaring the address it sends from
+    async fn check_association(listen: &str, connect: IpAddr) {
+        let l = TcpListener::bind(listen).await.unwrap();
+        let _c = TcpStream::connect((connect, l.local_addr().unwrap().port()))
+            .await
+            .unwrap();
+        let (s, peer) = l.accept().await.unwrap();
+
+        let relay_addr = udp_relay_addr(s.local_addr().unwrap());
+        assert_eq!(relay_addr.ip(), connect, "{}", listen);
+        let relay = UdpSocket::bind(relay_addr).await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        // what the client is told, of its family
+        let mut bnd = BytesMut::new();
+        SocksAddr::from(relay_addr).write_buf(&mut bnd);
+        assert_eq!(bnd[0], if connect.is_ipv4() { 1 } else { 4 });
+
+        let a = UdpSocket::bind((connect, 0)).await.unwrap();
+        let b = UdpSocket::bind((connect, 0)).await.unwrap();
+        let declared = a.local_addr().unwrap().into();
+
+        let loose = allowed_source(peer, &declared, SocksUdpSource::Loose);
+        assert_eq!(
+            loose,
+            AllowedSource {
+                ip: peer.ip(),
+                port: None
+            }
+        );
+
+        let strict = allowed_source(peer, &declared, SocksUdpSource::Strict);
+        let mut inbound = InboundUdp::new(
+            UdpFramed::new(relay, Socks5UDPCodec),
+            strict,
+            ConnectionLimiter::new(0).for_listener("SOCKS5", 0, None),
+        );
+        b.send_to(&datagram(b"from b"), relay_addr).await.unwrap();
+        a.send_to(&datagram(b"from a"), relay_addr).await.unwrap();
+        let pkt = tokio::time::timeout(Duration::from_secs(5), inbound.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pkt.data, b"from a");
+        assert_eq!(pkt.src_addr, declared);
+    }
+
+    #[tokio::test]
+    async fn test_udp_association_v4() {
+        check_association("127.0.0.1:0", "127.0.0.1".parse().unwrap()).await;
+        // over IPv4 to a dual-stack listener
+        check_association("[::]:0", "127.0.0.1".parse().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_udp_association_v6() {
+        check_association("[::1]:0", "::1".parse().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_udp_blocked_source() {
+        let limiter = ConnectionLimiter::new(0);
+        let acl = Acl::new(&ListenerAcl {
+            allowed_ips: vec![],
+            blocked_ips: vec!["127.0.0.1/32".parse().unwrap()],
+        });
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // e.g. the one a strict association declared
+        let source = AllowedSource {
+            ip: client.local_addr().unwrap().ip(),
+            port: None,
+        };
+        let mut inbound = InboundUdp::new(
+            UdpFramed::new(relay, Socks5UDPCodec),
+            source,
+            limiter.for_listener("SOCKS5", 0, Some(Arc::new(acl))),
+        );
+        client
+            .send_to(&datagram(b"blocked"), relay_addr)
+            .await
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), inbound.next())
+                .await
+                .is_err()
+        );
+        assert_eq!(limiter.stats().listeners["SOCKS5"].blocked, 1);
+    }
+
+    #[test]
+    fn test_allowed_source() {
+        let peer = "[::ffff:192.168.1.10]:40000".parse().unwrap();
+        let any = SocksAddr::any_ipv4();
+
+        let loose = allowed_source(peer, &any, SocksUdpSource::Loose);
+        assert!(loose.allows("192.168.1.10:5000".parse().unwrap()));
+        assert!(!loose.allows("192.168.1.11:5000".parse().unwrap()));
+
+        // nothing declared, as loose
+        let strict = allowed_source(peer, &any, SocksUdpSource::Strict);
+        assert_eq!(strict, loose);
+
+        let declared = SocksAddr::Ip("0.0.0.0:5000".parse().unwrap());
+        let strict = allowed_source(peer, &declared, SocksUdpSource::Strict);
+        assert!(strict.allows("192.168.1.10:5000".parse().unwrap()));
+        assert!(!strict.allows("192.168.1.10:5001".parse().unwrap()));
+    }
+}
@@ -1,7 +1,9 @@
 mod inbound;
 mod outbound;
+mod socks4;
 mod socks5;
 
 pub use inbound::{handle_tcp, Listener, Socks5UDPCodec};
 pub use outbound::{Handler, HandlerOptions};
+pub use socks4::SOCKS4_VERSION;
 pub use socks5::SOCKS5_VERSION;
@@ -1,38 +1,39 @@
 use std::{
-    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
 
-use futures::{Sink, SinkExt, Stream, StreamExt};
-use tokio::net::UdpSocket;
-use tokio_util::udp::UdpFramed;
+use bytes::BytesMut;
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio_util::codec::{Decoder, Encoder};
 use tracing::{error, trace};
 
 use crate::{
-    proxy::{datagram::UdpPacket, socks::Socks5UDPCodec, AnyStream},
+    proxy::{
+        datagram::UdpPacket, socks::Socks5UDPCodec, AnyOutboundDatagram, AnyStream,
+    },
     session::SocksAddr,
 };
 
+/// The packets of a UDP associate, each with the SOCKS5 UDP header, sent to
+/// the relay over whatever reaches it, a proxy of a relay group included.
 pub(crate) struct Socks5Datagram {
     // hold the socket to keep it alive and drop it when this is dropped
     _socket: AnyStream,
-    remote: SocketAddr,
-    inner: UdpFramed<Socks5UDPCodec>,
+    remote: SocksAddr,
+    inner: AnyOutboundDatagram,
 }
 
 impl Socks5Datagram {
     pub(crate) fn new(
         socket: AnyStream,
-        remote: SocketAddr,
-        udp_socket: UdpSocket,
+        remote: SocksAddr,
+        inner: AnyOutboundDatagram,
     ) -> Self {
-        let framed = UdpFramed::new(udp_socket, Socks5UDPCodec);
-
         Self {
             _socket: socket,
             remote,
-            inner: framed,
+            inner,
         }
     }
 }
@@ -62,15 +63,19 @@ impl Sink<UdpPacket> for Socks5Datagram {
     }
 
     fn start_send(self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
-        let remote = self.remote;
+        let pin = self.get_mut();
         trace!(
             "sending UDP packet to {}, item dst: {}",
-            remote,
+            pin.remote,
             item.dst_addr
         );
-        let pin = self.get_mut();
-        pin.inner
-            .start_send_unpin(((item.data.into(), item.dst_addr), remote))
+        let mut buf = BytesMut::new();
+        Socks5UDPCodec.encode((item.data.into(), item.dst_addr), &mut buf)?;
+        pin.inner.start_send_unpin(UdpPacket {
+            data: buf.to_vec(),
+            src_addr: item.src_addr,
+            dst_addr: pin.remote.clone(),
+        })
     }
 
     fn poll_flush(
@@ -98,25 +103,22 @@ impl Stream for Socks5Datagram {
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
-        pin.inner.poll_next_unpin(cx).map(|opt| {
-            opt.map(|res| match res {
-                Ok(((src, data), dst)) => {
-                    trace!("received UDP packet from {} to {}", src, dst,);
-                    UdpPacket {
+        loop {
+            let Some(pkt) = ready!(pin.inner.poll_next_unpin(cx)) else {
+                return Poll::Ready(None);
+            };
+            let mut buf = BytesMut::from(&pkt.data[..]);
+            match Socks5UDPCodec.decode(&mut buf) {
+                Ok(Some((src, data))) => {
+                    trace!("received UDP packet from {} to {}", src, pkt.dst_addr);
+                    return Poll::Ready(Some(UdpPacket {
                         src_addr: src,
-                        dst_addr: SocksAddr::Ip(dst),
-                        data: data.into(),
-                    }
-                }
-                Err(_) => {
-                    error!("failed to decode UDP packet from remote");
-                    UdpPacket {
-                        src_addr: SocksAddr::any_ipv4(),
-                        dst_addr: SocksAddr::any_ipv4(),
-                        data: Vec::new(),
-                    }
+                        dst_addr: pkt.dst_addr,
+                        data: data.to_vec(),
+                    }));
                 }
-            })
-        })
+                _ => error!("failed to decode UDP packet from remote"),
+            }
+        }
     }
 }
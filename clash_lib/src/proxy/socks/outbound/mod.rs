@@ -11,14 +11,18 @@ use crate::{
         dns::ThreadSafeDNSResolver,
     },
     common::errors::new_io_error,
+    config::def::{ClientFingerprint, UdpNat},
     impl_default_connector,
     proxy::{
         transport::{self, TLSOptions},
-        utils::{new_udp_socket, RemoteConnector, GLOBAL_DIRECT_CONNECTOR},
+        utils::{
+            DialError, RemoteConnector, ServerAddr, TimedStream,
+            GLOBAL_DIRECT_CONNECTOR,
+        },
         AnyStream, ConnectorType, DialWithConnector, HandlerCommonOptions,
         OutboundHandler, OutboundType,
     },
-    session::Session,
+    session::{Session, SocksAddr},
 };
 
 use async_trait::async_trait;
@@ -39,10 +43,15 @@ pub struct HandlerOptions {
     pub tls: bool,
     pub sni: String,
     pub skip_cert_verify: bool,
+    pub pinned_cert_chain_sha256: Vec<[u8; 32]>,
+    pub pinned_cert_only: bool,
+    pub client_fingerprint: Option<ClientFingerprint>,
+    pub udp_nat: Option<UdpNat>,
 }
 
 pub struct Handler {
     opts: HandlerOptions,
+    server_addr: ServerAddr,
 
     connector: tokio::sync::Mutex<Option<Arc<dyn RemoteConnector>>>,
 }
@@ -60,6 +69,13 @@ impl Debug for Handler {
 impl Handler {
     pub fn new(opts: HandlerOptions) -> Self {
         Self {
+            server_addr: ServerAddr::new(
+                &opts.server,
+                opts.port,
+                opts.common_opts.resolve_strategy,
+            )
+            .with_dial_timeout(opts.common_opts.dial_timeout)
+            .with_socket_buffers(opts.common_opts.socket_buffers),
             opts,
             connector: tokio::sync::Mutex::new(None),
         }
@@ -70,6 +86,12 @@ impl Handler {
         s: AnyStream,
         sess: &Session,
     ) -> std::io::Result<AnyStream> {
+        let (s, handshake) = TimedStream::new(
+            s,
+            "socks5 handshake",
+            self.opts.common_opts.handshake_timeout(),
+        );
+        let s: AnyStream = Box::new(s);
         let mut s = if self.opts.tls {
             trace!(
                 "TLS config - enabled: {}, skip_cert_verify: {}, sni: {}",
@@ -81,6 +103,9 @@ impl Handler {
                 skip_cert_verify: self.opts.skip_cert_verify,
                 sni: self.opts.sni.clone(),
                 alpn: None,
+                pinned_cert_chain_sha256: self.opts.pinned_cert_chain_sha256.clone(),
+                pinned_cert_only: self.opts.pinned_cert_only,
+                fingerprint: self.opts.client_fingerprint,
             };
 
             transport::tls::wrap_stream(s, tls_opt, None).await?
@@ -95,7 +120,9 @@ impl Handler {
             self.opts.user.clone(),
             self.opts.password.clone(),
         )
-        .await?;
+        .await
+        .map_err(|e| DialError::ProxyHandshake.tag(e))?;
+        handshake.done();
 
         Ok(s)
     }
@@ -105,12 +132,22 @@ impl Handler {
         s: AnyStream,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
     ) -> std::io::Result<Socks5Datagram> {
+        let (s, handshake) = TimedStream::new(
+            s,
+            "socks5 handshake",
+            self.opts.common_opts.handshake_timeout(),
+        );
+        let s: AnyStream = Box::new(s);
         let mut s = if self.opts.tls {
             let tls_opt = TLSOptions {
                 skip_cert_verify: self.opts.skip_cert_verify,
                 sni: self.opts.sni.clone(),
                 alpn: None,
+                pinned_cert_chain_sha256: self.opts.pinned_cert_chain_sha256.clone(),
+                pinned_cert_only: self.opts.pinned_cert_only,
+                fingerprint: self.opts.client_fingerprint,
             };
 
             transport::tls::wrap_stream(s, tls_opt, None).await?
@@ -125,7 +162,9 @@ impl Handler {
             self.opts.user.clone(),
             self.opts.password.clone(),
         )
-        .await?;
+        .await
+        .map_err(|e| DialError::ProxyHandshake.tag(e))?;
+        handshake.done();
 
         let bind_ip = bind_addr
             .ip()
@@ -147,19 +186,22 @@ impl Handler {
         let bind_port = bind_addr.port();
         trace!("bind address resolved to {}:{}", bind_ip, bind_port);
 
-        let udp_socket = new_udp_socket(
-            None,
-            sess.iface.clone(),
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            None,
-        )
-        .await?;
+        // to the relay the way the server was reached, so it works behind
+        // the other proxies of a relay group as well
+        let remote: SocksAddr = (bind_ip, bind_port).into();
+        let udp = self
+            .server_addr
+            .connect_datagram(
+                connector,
+                resolver,
+                remote.clone(),
+                sess.iface.clone(),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                sess.so_mark,
+            )
+            .await?;
 
-        Ok(Socks5Datagram::new(
-            s,
-            (bind_ip, bind_port).into(),
-            udp_socket,
-        ))
+        Ok(Socks5Datagram::new(s, remote, udp))
     }
 }
 
@@ -173,6 +215,22 @@ impl OutboundHandler for Handler {
         OutboundType::Socks5
     }
 
+    fn server(&self) -> Option<SocksAddr> {
+        self.server_addr.socks_addr()
+    }
+
+    async fn prefetch(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Option<std::io::Result<()>> {
+        self.server_addr.prefetch(&resolver).await
+    }
+
+    /// the relay of a UDP associate takes packets from any peer
+    fn udp_nat(&self) -> Option<UdpNat> {
+        self.opts.udp_nat
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -233,11 +291,11 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> std::io::Result<BoxedChainedStream> {
-        let s = connector
+        let s = self
+            .server_addr
             .connect_stream(
+                connector,
                 resolver,
-                self.opts.server.as_str(),
-                self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 sess.so_mark,
@@ -257,18 +315,20 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> std::io::Result<BoxedChainedDatagram> {
-        let s = connector
+        let s = self
+            .server_addr
             .connect_stream(
+                connector,
                 resolver.clone(),
-                self.opts.server.as_str(),
-                self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 sess.so_mark,
             )
             .await?;
 
-        let d = self.inner_connect_datagram(s, sess, resolver).await?;
+        let d = self
+            .inner_connect_datagram(s, sess, resolver, connector)
+            .await?;
 
         let d = ChainedDatagramWrapper::new(d);
         d.append_to_chain(self.name()).await;
@@ -276,6 +336,70 @@ impl OutboundHandler for Handler {
     }
 }
 
+#[cfg(test)]
+mod mock_tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        proxy::{
+            socks::{Handler, HandlerOptions},
+            utils::GLOBAL_DIRECT_CONNECTOR,
+            OutboundHandler,
+        },
+        session::{Session, SocksAddr},
+        test_support::{self, Socks5Server},
+    };
+
+    async fn echo(user: Option<&str>) {
+        let mut server = Socks5Server::new();
+        if let Some(user) = user {
+            server = server.auth(user, "password");
+        }
+        let server = server.start().await;
+        let handler = Handler::new(HandlerOptions {
+            name: "socks".to_owned(),
+            server: server.addr.ip().to_string(),
+            port: server.addr.port(),
+            user: user.map(|x| x.to_owned()),
+            password: user.map(|_| "password".to_owned()),
+            ..Default::default()
+        });
+
+        let sess = Session {
+            destination: SocksAddr::try_from(("example.com".to_owned(), 443))
+                .unwrap(),
+            ..Default::default()
+        };
+        let mut s = handler
+            .connect_stream_with_connector(
+                &sess,
+                test_support::resolver(),
+                GLOBAL_DIRECT_CONNECTOR.as_ref(),
+            )
+            .await
+            .unwrap();
+        s.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].target, sess.destination);
+        assert_eq!(requests[0].user.as_deref(), user);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_no_auth() {
+        echo(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_socks5_auth() {
+        echo(Some("user")).await;
+    }
+}
+
 #[cfg(all(test, docker_test))]
 mod tests {
 
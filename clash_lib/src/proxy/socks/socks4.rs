@@ -0,0 +1,143 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{common::errors::new_io_error, session::SocksAddr};
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+
+/// the USERID and the SOCKS4a hostname are both NULL terminated strings
+/// without a length prefix, this caps how much we are willing to read
+const MAX_FIELD_LEN: usize = 255;
+
+pub(crate) mod socks4_command {
+    pub const CONNECT: u8 = 0x01;
+    // pub const BIND: u8 = 0x02;
+}
+
+pub(crate) mod response_code {
+    pub const GRANTED: u8 = 0x5a;
+    pub const REJECTED: u8 = 0x5b;
+}
+
+/// A SOCKS4/SOCKS4a request, after the version and command bytes.
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+/// | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+///   1    1      2              4           variable       1
+///
+/// for SOCKS4a, DSTIP is 0.0.0.x (x != 0) and the hostname follows USERID
+/// +----+....+----+
+/// | HOST     |NULL|
+/// +----+....+----+
+pub(crate) struct Request {
+    pub destination: SocksAddr,
+    pub user_id: String,
+}
+
+impl Request {
+    pub async fn read_from<T: AsyncRead + Unpin>(
+        r: &mut T,
+    ) -> std::io::Result<Self> {
+        let port = r.read_u16().await?;
+        let mut ip = [0u8; 4];
+        r.read_exact(&mut ip).await?;
+        let ip = Ipv4Addr::from(ip);
+
+        let user_id = read_null_terminated(r).await?;
+
+        let destination = if is_socks4a(&ip) {
+            let host = read_null_terminated(r).await?;
+            if host.is_empty() {
+                return Err(new_io_error("empty SOCKS4a hostname"));
+            }
+            SocksAddr::Domain(host, port)
+        } else {
+            SocksAddr::Ip(SocketAddr::new(ip.into(), port))
+        };
+
+        Ok(Self {
+            destination,
+            user_id,
+        })
+    }
+
+    /// The SOCKS4 USERID carries the credentials as `user:pass`, a USERID
+    /// without a colon is taken as the username with an empty password.
+    pub fn credentials(&self) -> (&str, &str) {
+        self.user_id.split_once(':').unwrap_or((&self.user_id, ""))
+    }
+}
+
+/// +----+----+----+----+----+----+----+----+
+/// | VN | CD | DSTPORT |      DSTIP        |
+/// +----+----+----+----+----+----+----+----+
+///   1    1      2              4
+pub(crate) fn write_response(buf: &mut BytesMut, code: u8) {
+    buf.put_u8(0x00);
+    buf.put_u8(code);
+    buf.put_u16(0);
+    buf.put_slice(&Ipv4Addr::UNSPECIFIED.octets());
+}
+
+fn is_socks4a(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[..3] == [0, 0, 0] && octets[3] != 0
+}
+
+async fn read_null_terminated<T: AsyncRead + Unpin>(
+    r: &mut T,
+) -> std::io::Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        let b = r.read_u8().await?;
+        if b == 0 {
+            break;
+        }
+        if buf.len() >= MAX_FIELD_LEN {
+            return Err(new_io_error("SOCKS4 field too long"));
+        }
+        buf.push(b);
+    }
+    String::from_utf8(buf).map_err(|_| new_io_error("invalid SOCKS4 field"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::session::SocksAddr;
+
+    use super::Request;
+
+    #[tokio::test]
+    async fn test_read_socks4_request() {
+        let mut data: &[u8] = &[0x00, 0x50, 1, 2, 3, 4, b'b', b'o', b'b', 0x00];
+        let req = Request::read_from(&mut data).await.unwrap();
+        assert_eq!(
+            req.destination,
+            SocksAddr::Ip("1.2.3.4:80".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(req.credentials(), ("bob", ""));
+    }
+
+    #[tokio::test]
+    async fn test_read_socks4a_request() {
+        let mut data = vec![0x01, 0xbb, 0, 0, 0, 1];
+        data.extend_from_slice(b"bob:secret\0example.com\0");
+        let req = Request::read_from(&mut data.as_slice()).await.unwrap();
+        assert_eq!(
+            req.destination,
+            SocksAddr::Domain("example.com".to_owned(), 443)
+        );
+        assert_eq!(req.credentials(), ("bob", "secret"));
+    }
+
+    #[tokio::test]
+    async fn test_read_unterminated_user_id() {
+        let mut data = vec![0x00, 0x50, 1, 2, 3, 4];
+        data.extend_from_slice(&[b'a'; 300]);
+        assert!(Request::read_from(&mut data.as_slice()).await.is_err());
+    }
+}
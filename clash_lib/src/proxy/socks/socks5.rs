@@ -21,15 +21,28 @@ pub(crate) mod socks_command {
 }
 
 pub(crate) mod response_code {
+    use std::io::ErrorKind;
+
     pub const SUCCEEDED: u8 = 0x00;
     pub const FAILURE: u8 = 0x01;
     // pub const RULE_FAILURE: u8 = 0x02;
-    // pub const NETWORK_UNREACHABLE: u8 = 0x03;
-    // pub const HOST_UNREACHABLE: u8 = 0x04;
-    // pub const CONNECTION_REFUSED: u8 = 0x05;
-    // pub const TTL_EXPIRED: u8 = 0x06;
+    pub const NETWORK_UNREACHABLE: u8 = 0x03;
+    pub const HOST_UNREACHABLE: u8 = 0x04;
+    pub const CONNECTION_REFUSED: u8 = 0x05;
+    pub const TTL_EXPIRED: u8 = 0x06;
     pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
     // pub const ADDR_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+    /// the reply to a CONNECT whose dial failed with `kind`
+    pub fn for_dial_error(kind: ErrorKind) -> u8 {
+        match kind {
+            ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+            ErrorKind::HostUnreachable => HOST_UNREACHABLE,
+            ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+            ErrorKind::TimedOut => TTL_EXPIRED,
+            _ => FAILURE,
+        }
+    }
 }
 
 const ERROR_CODE_LOOKUP: &[&str] = &[
@@ -17,7 +17,10 @@ use crate::{
 
 use self::stream::StreamWrapper;
 
-use super::{ConnectorType, DialWithConnector, OutboundHandler, OutboundType};
+use super::{
+    utils::DialError, ConnectorType, DialWithConnector, OutboundHandler,
+    OutboundType,
+};
 
 pub struct HandlerOptions {
     pub name: String,
@@ -94,7 +97,8 @@ impl OutboundHandler for Handler {
         _sess: &Session,
         _resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedDatagram> {
-        Err(new_io_error("Tor outbound handler does not support UDP"))
+        Err(DialError::Unsupported
+            .error("Tor outbound handler does not support UDP"))
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -1,22 +1,32 @@
 use super::tun::TunDatagram;
 use crate::{
-    app::dispatcher::Dispatcher,
+    app::{
+        dispatcher::Dispatcher,
+        inbound::limiter::{ConnectionGuard, ListenerLimiter},
+    },
     proxy::{datagram::UdpPacket, utils::apply_tcp_options, InboundListener},
     session::{Network, Session, Type},
 };
 use async_trait::async_trait;
 use socket2::{Domain, Socket};
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     os::fd::{AsFd, AsRawFd},
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::net::TcpListener;
 use tracing::{trace, warn};
 
+/// a UDP source that has been quiet for this long no longer counts towards
+/// the connection limit
+const UDP_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct Listener {
     addr: SocketAddr,
     dispather: Arc<Dispatcher>,
+    limiter: Arc<ListenerLimiter>,
 }
 
 impl Drop for Listener {
@@ -26,8 +36,16 @@ impl Drop for Listener {
 }
 
 impl Listener {
-    pub fn new(addr: SocketAddr, dispather: Arc<Dispatcher>) -> Self {
-        Self { addr, dispather }
+    pub fn new(
+        addr: SocketAddr,
+        dispather: Arc<Dispatcher>,
+        limiter: Arc<ListenerLimiter>,
+    ) -> Self {
+        Self {
+            addr,
+            dispather,
+            limiter,
+        }
     }
 }
 
@@ -54,6 +72,10 @@ impl InboundListener for Listener {
         loop {
             let (socket, src_addr) = listener.accept().await?;
 
+            let Some(guard) = self.limiter.try_acquire(src_addr.ip()) else {
+                continue;
+            };
+
             let socket = apply_tcp_options(socket)?;
 
             // local_addr is getsockname
@@ -64,6 +86,7 @@ impl InboundListener for Listener {
                 typ: Type::Tproxy,
                 source: src_addr,
                 destination: orig_dst.into(),
+                inbound_name: "tproxy".to_owned(),
                 ..Default::default()
             };
 
@@ -72,6 +95,7 @@ impl InboundListener for Listener {
             let dispatcher = self.dispather.clone();
             tokio::spawn(async move {
                 dispatcher.dispatch_stream(sess, socket).await;
+                drop(guard);
             });
         }
     }
@@ -97,13 +121,19 @@ impl InboundListener for Listener {
 
         let listener = unix_udp_sock::UdpSocket::from_std(socket.into())?;
 
-        handle_inbound_datagram(Arc::new(listener), self.dispather.clone()).await
+        handle_inbound_datagram(
+            Arc::new(listener),
+            self.dispather.clone(),
+            self.limiter.clone(),
+        )
+        .await
     }
 }
 
 async fn handle_inbound_datagram(
     socket: Arc<unix_udp_sock::UdpSocket>,
     dispatcher: Arc<Dispatcher>,
+    limiter: Arc<ListenerLimiter>,
 ) -> std::io::Result<()> {
     // dispatcher <-> tproxy communications
     let (l_tx, mut l_rx) = tokio::sync::mpsc::channel(32);
@@ -119,6 +149,7 @@ async fn handle_inbound_datagram(
     let sess = Session {
         network: Network::Udp,
         typ: Type::Tproxy,
+        inbound_name: "tproxy".to_owned(),
         ..Default::default()
     };
 
@@ -146,7 +177,34 @@ async fn handle_inbound_datagram(
     // tproxy -> dispatcher
     let fut2 = tokio::spawn(async move {
         let mut buf = vec![0_u8; 1024 * 64];
+        // every source address counts as one session against the limit
+        let mut sessions: HashMap<SocketAddr, (ConnectionGuard, Instant)> =
+            HashMap::new();
+        let mut last_evict = Instant::now();
         while let Ok(meta) = socket.recv_msg(&mut buf).await {
+            if last_evict.elapsed() > UDP_SESSION_IDLE_TIMEOUT {
+                sessions.retain(|_, (_, last_seen)| {
+                    last_seen.elapsed() < UDP_SESSION_IDLE_TIMEOUT
+                });
+                last_evict = Instant::now();
+            }
+            match sessions.get_mut(&meta.addr) {
+                // checked again as the ACL may have changed on a reload
+                Some((_, last_seen)) if limiter.allows(meta.addr.ip()) => {
+                    *last_seen = Instant::now()
+                }
+                Some(_) => {
+                    sessions.remove(&meta.addr);
+                    continue;
+                }
+                None => match limiter.try_acquire(meta.addr.ip()) {
+                    Some(guard) => {
+                        sessions.insert(meta.addr, (guard, Instant::now()));
+                    }
+                    None => continue,
+                },
+            }
+
             match meta.orig_dst {
                 Some(orig_dst) => {
                     if orig_dst.ip().is_multicast()
@@ -1,16 +1,24 @@
+#[cfg(any(feature = "vmess", feature = "trojan"))]
 mod grpc;
+#[cfg(feature = "vmess")]
 mod h2;
 #[path = "tls.rs"]
 mod internal_tls;
+#[cfg(any(feature = "vmess", feature = "trojan"))]
 mod ws;
 
+#[cfg(any(feature = "vmess", feature = "trojan"))]
 pub use ws::WebsocketStreamBuilder;
 
+#[cfg(any(feature = "vmess", feature = "trojan"))]
 pub use grpc::GrpcStreamBuilder;
 
+#[cfg(feature = "vmess")]
 pub use self::h2::Http2Config;
 
 pub mod tls {
-    pub use super::internal_tls::wrap_stream;
+    pub use super::internal_tls::{
+        client_config, wrap_stream, wrap_stream_with_config,
+    };
 }
 pub use internal_tls::TLSOptions;
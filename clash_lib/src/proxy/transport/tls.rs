@@ -1,36 +1,107 @@
-use std::io;
+use std::{io, sync::Arc};
 
 use serde::Serialize;
 
-use crate::proxy::AnyStream;
+use crate::{
+    config::def::ClientFingerprint,
+    proxy::{utils::DialError, AnyStream},
+};
 
 #[derive(Serialize, Clone)]
 pub struct TLSOptions {
     pub skip_cert_verify: bool,
     pub sni: String,
     pub alpn: Option<Vec<String>>,
+    /// the SPKI SHA-256 of certificates one of which the server must present
+    pub pinned_cert_chain_sha256: Vec<[u8; 32]>,
+    /// the pins are checked instead of the chain
+    pub pinned_cert_only: bool,
+    /// picked already, never `random`
+    pub fingerprint: Option<ClientFingerprint>,
 }
 
-pub async fn wrap_stream(
-    stream: AnyStream,
-    opt: TLSOptions,
-    expected_alpn: Option<&str>,
-) -> io::Result<AnyStream> {
-    use std::sync::Arc;
+/// the ring provider with the cipher suites and key exchange groups in the
+/// order the browser offers them, those rustls has
+fn fingerprint_provider(fp: ClientFingerprint) -> rustls::crypto::CryptoProvider {
+    use rustls::crypto::ring::{cipher_suite::*, default_provider, kx_group};
+
+    let cipher_suites = match fp {
+        ClientFingerprint::Firefox => vec![
+            TLS13_AES_128_GCM_SHA256,
+            TLS13_CHACHA20_POLY1305_SHA256,
+            TLS13_AES_256_GCM_SHA384,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        ],
+        ClientFingerprint::Safari | ClientFingerprint::Ios => vec![
+            TLS13_AES_128_GCM_SHA256,
+            TLS13_AES_256_GCM_SHA384,
+            TLS13_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ],
+        ClientFingerprint::Chrome
+        | ClientFingerprint::Edge
+        | ClientFingerprint::Random => vec![
+            TLS13_AES_128_GCM_SHA256,
+            TLS13_AES_256_GCM_SHA384,
+            TLS13_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ],
+    };
+    rustls::crypto::CryptoProvider {
+        cipher_suites,
+        // all of them put x25519 first
+        kx_groups: vec![kx_group::X25519, kx_group::SECP256R1, kx_group::SECP384R1],
+        ..default_provider()
+    }
+}
 
+/// The client config for `opt`, building it takes a copy of the root store,
+/// so handlers dialing with the same options keep it.
+pub fn client_config(opt: &TLSOptions) -> io::Result<Arc<rustls::ClientConfig>> {
     use crate::common::tls::{self, GLOBAL_ROOT_STORE};
 
-    let mut tls_config = rustls::ClientConfig::builder()
+    let builder = match opt.fingerprint {
+        Some(fp) => rustls::ClientConfig::builder_with_provider(Arc::new(
+            fingerprint_provider(fp),
+        ))
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider does TLS 1.2 and 1.3"),
+        None => rustls::ClientConfig::builder(),
+    };
+    let mut tls_config = builder
         .with_root_certificates(GLOBAL_ROOT_STORE.clone())
         .with_no_client_auth();
     tls_config.alpn_protocols = opt
         .alpn
+        .clone()
         .unwrap_or_default()
         .into_iter()
         .map(|x| x.as_bytes().to_vec())
         .collect();
 
-    if opt.skip_cert_verify {
+    if !opt.pinned_cert_chain_sha256.is_empty() {
+        tls_config.dangerous().set_certificate_verifier(Arc::new(
+            tls::PinnedCertVerifier::new(
+                opt.pinned_cert_chain_sha256.clone(),
+                !opt.skip_cert_verify && !opt.pinned_cert_only,
+            )?,
+        ));
+    } else if opt.skip_cert_verify {
         tls_config
             .dangerous()
             .set_certificate_verifier(Arc::new(tls::DummyTlsVerifier::new()));
@@ -38,26 +109,90 @@ pub async fn wrap_stream(
 
     tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
 
-    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
-    let dns_name =
-        rustls::pki_types::ServerName::try_from(opt.sni.as_str().to_owned())
-            .unwrap_or_else(|_| panic!("invalid server name: {}", opt.sni));
-
-    let c = connector.connect(dns_name, stream).await.and_then(|x| {
-        if let Some(expected_alpn) = expected_alpn {
-            if x.get_ref().1.alpn_protocol() != Some(expected_alpn.as_bytes()) {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
+    Ok(Arc::new(tls_config))
+}
+
+pub async fn wrap_stream(
+    stream: AnyStream,
+    opt: TLSOptions,
+    expected_alpn: Option<&str>,
+) -> io::Result<AnyStream> {
+    wrap_stream_with_config(stream, client_config(&opt)?, &opt.sni, expected_alpn)
+        .await
+}
+
+/// [`wrap_stream`] with a config from [`client_config`]
+pub async fn wrap_stream_with_config(
+    stream: AnyStream,
+    tls_config: Arc<rustls::ClientConfig>,
+    sni: &str,
+    expected_alpn: Option<&str>,
+) -> io::Result<AnyStream> {
+    let connector = tokio_rustls::TlsConnector::from(tls_config);
+    let dns_name = rustls::pki_types::ServerName::try_from(sni.to_owned())
+        .unwrap_or_else(|_| panic!("invalid server name: {}", sni));
+
+    // the rustls errors are told as they are, e.g. for the pin checks
+    let c = connector
+        .connect(dns_name, stream)
+        .await
+        .map_err(|e| DialError::Tls.tag(e))
+        .and_then(|x| {
+            if let Some(expected_alpn) = expected_alpn {
+                if x.get_ref().1.alpn_protocol() != Some(expected_alpn.as_bytes()) {
+                    return Err(DialError::Tls.error(format!(
                         "unexpected alpn protocol: {:?}, expected: {:?}",
                         x.get_ref().1.alpn_protocol(),
                         expected_alpn
-                    ),
-                ));
+                    )));
+                }
             }
-        }
 
-        Ok(x)
-    });
+            Ok(x)
+        });
     c.map(|x| Box::new(x) as _)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::def::ClientFingerprint;
+
+    use super::{client_config, TLSOptions};
+
+    #[test]
+    fn test_fingerprint_suite_order() {
+        let opt = |fingerprint| TLSOptions {
+            skip_cert_verify: false,
+            sni: "example.com".to_owned(),
+            alpn: None,
+            pinned_cert_chain_sha256: vec![],
+            pinned_cert_only: false,
+            fingerprint,
+        };
+        let first_suites = |fp| {
+            client_config(&opt(fp))
+                .unwrap()
+                .crypto_provider()
+                .cipher_suites
+                .iter()
+                .take(2)
+                .map(|x| x.suite())
+                .collect::<Vec<_>>()
+        };
+
+        use rustls::CipherSuite::*;
+        assert_eq!(
+            first_suites(Some(ClientFingerprint::Chrome)),
+            [TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384]
+        );
+        assert_eq!(
+            first_suites(Some(ClientFingerprint::Firefox)),
+            [TLS13_AES_128_GCM_SHA256, TLS13_CHACHA20_POLY1305_SHA256]
+        );
+        // rustls' own order
+        assert_eq!(
+            first_suites(None),
+            [TLS13_AES_256_GCM_SHA384, TLS13_AES_128_GCM_SHA256]
+        );
+    }
+}
@@ -1,7 +1,12 @@
-use std::{io, sync::Arc};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, OnceLock},
+};
 
 use async_trait::async_trait;
 use bytes::{BufMut, BytesMut};
+use erased_serde::Serialize as ESerialize;
 use sha2::{Digest, Sha224};
 use tokio::io::AsyncWriteExt;
 use tracing::debug;
@@ -13,10 +18,12 @@ use crate::{
             ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
         },
         dns::ThreadSafeDNSResolver,
+        profile::ThreadSafeCacheFile,
     },
     common::utils,
+    config::def::ClientFingerprint,
     impl_default_connector,
-    session::Session,
+    session::{Session, SocksAddr},
 };
 
 use self::datagram::OutboundDatagramTrojan;
@@ -24,7 +31,9 @@ use self::datagram::OutboundDatagramTrojan;
 use super::{
     options::{GrpcOption, WsOption},
     transport::{self, TLSOptions},
-    utils::{RemoteConnector, GLOBAL_DIRECT_CONNECTOR},
+    utils::{
+        DialError, RemoteConnector, ServerList, TimedStream, GLOBAL_DIRECT_CONNECTOR,
+    },
     AnyStream, ConnectorType, DialWithConnector, HandlerCommonOptions,
     OutboundHandler, OutboundType,
 };
@@ -48,11 +57,21 @@ pub struct HandlerOptions {
     pub sni: String,
     pub alpn: Option<Vec<String>>,
     pub skip_cert_verify: bool,
+    pub pinned_cert_chain_sha256: Vec<[u8; 32]>,
+    pub pinned_cert_only: bool,
+    pub client_fingerprint: Option<ClientFingerprint>,
     pub transport: Option<Transport>,
+    /// every (server, port) to try in turn, when there are several, `server`
+    /// and `port` being the first
+    pub servers: Vec<(String, u16)>,
 }
 
 pub struct Handler {
     opts: HandlerOptions,
+    server_addr: ServerList,
+    /// built on the first dial, as is the password hash
+    tls_config: OnceLock<Arc<rustls::ClientConfig>>,
+    password_hash: OnceLock<String>,
 
     connector: tokio::sync::Mutex<Option<Arc<dyn RemoteConnector>>>,
 }
@@ -70,7 +89,20 @@ impl std::fmt::Debug for Handler {
 impl Handler {
     pub fn new(opts: HandlerOptions) -> Self {
         Self {
+            server_addr: ServerList::new(
+                &opts.name,
+                if opts.servers.is_empty() {
+                    vec![(opts.server.clone(), opts.port)]
+                } else {
+                    opts.servers.clone()
+                },
+                opts.common_opts.resolve_strategy,
+                opts.common_opts.dial_timeout,
+                opts.common_opts.socket_buffers,
+            ),
             opts,
+            tls_config: OnceLock::new(),
+            password_hash: OnceLock::new(),
             connector: tokio::sync::Mutex::new(None),
         }
     }
@@ -83,19 +115,42 @@ impl Handler {
         sess: &Session,
         udp: bool,
     ) -> io::Result<AnyStream> {
-        let tls_opt = TLSOptions {
-            skip_cert_verify: self.opts.skip_cert_verify,
-            sni: self.opts.sni.clone(),
-            alpn: self.opts.alpn.clone().or(Some(
-                DEFAULT_ALPN
-                    .iter()
-                    .copied()
-                    .map(|x| x.to_owned())
-                    .collect::<Vec<String>>(),
-            )),
+        let (s, handshake) = TimedStream::new(
+            s,
+            "trojan handshake",
+            self.opts.common_opts.handshake_timeout(),
+        );
+        let tls_config = match self.tls_config.get() {
+            Some(c) => c.clone(),
+            None => {
+                let c = transport::tls::client_config(&TLSOptions {
+                    skip_cert_verify: self.opts.skip_cert_verify,
+                    sni: self.opts.sni.clone(),
+                    alpn: self.opts.alpn.clone().or(Some(
+                        DEFAULT_ALPN
+                            .iter()
+                            .copied()
+                            .map(|x| x.to_owned())
+                            .collect::<Vec<String>>(),
+                    )),
+                    pinned_cert_chain_sha256: self
+                        .opts
+                        .pinned_cert_chain_sha256
+                        .clone(),
+                    pinned_cert_only: self.opts.pinned_cert_only,
+                    fingerprint: self.opts.client_fingerprint,
+                })?;
+                self.tls_config.get_or_init(|| c).clone()
+            }
         };
 
-        let s = transport::tls::wrap_stream(s, tls_opt, None).await?;
+        let s = transport::tls::wrap_stream_with_config(
+            Box::new(s),
+            tls_config,
+            &self.opts.sni,
+            None,
+        )
+        .await?;
 
         let mut s = if let Some(transport) = self.opts.transport.as_ref() {
             match transport {
@@ -110,7 +165,10 @@ impl Handler {
                         ws_opts.early_data_header_name.clone(),
                     );
 
-                    ws_builder.proxy_stream(s).await?
+                    ws_builder
+                        .proxy_stream(s)
+                        .await
+                        .map_err(|e| DialError::ProxyHandshake.tag(e))?
                 }
                 Transport::Grpc(grpc_opts) => {
                     let grpc_builder = transport::GrpcStreamBuilder::new(
@@ -121,7 +179,10 @@ impl Handler {
                             .try_into()
                             .expect("invalid gRPC service path"),
                     );
-                    grpc_builder.proxy_stream(s).await?
+                    grpc_builder
+                        .proxy_stream(s)
+                        .await
+                        .map_err(|e| DialError::ProxyHandshake.tag(e))?
                 }
             }
         } else {
@@ -129,14 +190,18 @@ impl Handler {
         };
 
         let mut buf = BytesMut::new();
-        let password = Sha224::digest(self.opts.password.as_bytes());
-        let password = utils::encode_hex(&password[..]);
+        let password = self.password_hash.get_or_init(|| {
+            utils::encode_hex(&Sha224::digest(self.opts.password.as_bytes())[..])
+        });
         buf.put_slice(password.as_bytes());
         buf.put_slice(b"\r\n");
         buf.put_u8(if udp { 0x03 } else { 0x01 });
         sess.destination.write_buf(&mut buf);
         buf.put_slice(b"\r\n");
-        s.write_all(&buf).await?;
+        s.write_all(&buf)
+            .await
+            .map_err(|e| DialError::ProxyHandshake.tag(e))?;
+        handshake.done();
 
         Ok(s)
     }
@@ -152,6 +217,30 @@ impl OutboundHandler for Handler {
         OutboundType::Trojan
     }
 
+    fn server(&self) -> Option<SocksAddr> {
+        self.server_addr.socks_addr()
+    }
+
+    fn set_cache_store(&self, cache_store: ThreadSafeCacheFile) {
+        self.server_addr.set_cache(cache_store);
+    }
+
+    async fn prefetch(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Option<io::Result<()>> {
+        self.server_addr.prefetch(&resolver).await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = HashMap::new();
+        m.insert("type".to_string(), Box::new(self.proto()) as _);
+        if let Some(active) = self.server_addr.active() {
+            m.insert("activeServer".to_string(), Box::new(active) as _);
+        }
+        m
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -210,11 +299,11 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = connector
+        let stream = self
+            .server_addr
             .connect_stream(
+                connector,
                 resolver,
-                self.opts.server.as_str(),
-                self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 sess.so_mark,
@@ -233,11 +322,11 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedDatagram> {
-        let stream = connector
+        let stream = self
+            .server_addr
             .connect_stream(
+                connector,
                 resolver,
-                self.opts.server.as_str(),
-                self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 sess.so_mark,
@@ -254,6 +343,114 @@ impl OutboundHandler for Handler {
     }
 }
 
+#[cfg(test)]
+mod mock_tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        proxy::{
+            datagram::UdpPacket,
+            trojan::{Handler, HandlerOptions},
+            utils::GLOBAL_DIRECT_CONNECTOR,
+            OutboundHandler,
+        },
+        session::{Session, SocksAddr},
+        test_support::{self, TrojanServer},
+    };
+
+    fn handler(port: u16, password: &str) -> Handler {
+        Handler::new(HandlerOptions {
+            name: "trojan".to_owned(),
+            common_opts: Default::default(),
+            server: "127.0.0.1".to_owned(),
+            port,
+            password: password.to_owned(),
+            udp: true,
+            sni: "example.org".to_owned(),
+            alpn: None,
+            skip_cert_verify: true,
+            pinned_cert_chain_sha256: vec![],
+            pinned_cert_only: false,
+            client_fingerprint: None,
+            transport: None,
+            servers: vec![],
+        })
+    }
+
+    fn session() -> Session {
+        Session {
+            destination: SocksAddr::try_from(("example.com".to_owned(), 53))
+                .unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trojan_stream_and_udp() {
+        let server = TrojanServer::new("password").start().await;
+        let handler = handler(server.addr.port(), "password");
+
+        let mut s = handler
+            .connect_stream_with_connector(
+                &session(),
+                test_support::resolver(),
+                GLOBAL_DIRECT_CONNECTOR.as_ref(),
+            )
+            .await
+            .unwrap();
+        s.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut d = handler
+            .connect_datagram_with_connector(
+                &session(),
+                test_support::resolver(),
+                GLOBAL_DIRECT_CONNECTOR.as_ref(),
+            )
+            .await
+            .unwrap();
+        d.send(UdpPacket {
+            data: b"ping".to_vec(),
+            src_addr: SocksAddr::any_ipv4(),
+            dst_addr: session().destination,
+        })
+        .await
+        .unwrap();
+        let pkt = d.next().await.unwrap();
+        assert_eq!(pkt.data, b"ping");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|x| x.accepted));
+        assert!(!requests[0].udp);
+        assert!(requests[1].udp);
+        assert_eq!(requests[1].target, session().destination);
+    }
+
+    #[tokio::test]
+    async fn test_trojan_wrong_password() {
+        let server = TrojanServer::new("password").start().await;
+        let handler = handler(server.addr.port(), "wrong");
+
+        // trojan has no answer to the request, the server just hangs up
+        let mut s = handler
+            .connect_stream_with_connector(
+                &session(),
+                test_support::resolver(),
+                GLOBAL_DIRECT_CONNECTOR.as_ref(),
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![];
+        let _ = s.read_to_end(&mut buf).await;
+        assert!(buf.is_empty());
+        assert!(!server.requests()[0].accepted);
+    }
+}
+
 #[cfg(all(test, docker_test))]
 mod tests {
 
@@ -301,6 +498,9 @@ mod tests {
             sni: "example.org".to_owned(),
             alpn: None,
             skip_cert_verify: true,
+            pinned_cert_chain_sha256: vec![],
+            pinned_cert_only: false,
+            client_fingerprint: None,
             transport: Some(Transport::Ws(WsOption {
                 path: "".to_owned(),
                 headers: [("Host".to_owned(), "example.org".to_owned())]
@@ -310,6 +510,7 @@ mod tests {
                 max_early_data: 0,
                 early_data_header_name: "".to_owned(),
             })),
+            servers: vec![],
         };
         let handler = Arc::new(Handler::new(opts));
         handler
@@ -350,10 +551,14 @@ mod tests {
             sni: "example.org".to_owned(),
             alpn: None,
             skip_cert_verify: true,
+            pinned_cert_chain_sha256: vec![],
+            pinned_cert_only: false,
+            client_fingerprint: None,
             transport: Some(Transport::Grpc(GrpcOption {
                 host: "example.org".to_owned(),
                 service_name: "example".to_owned(),
             })),
+            servers: vec![],
         };
         let handler = Arc::new(Handler::new(opts));
         handler
@@ -3,7 +3,10 @@ mod handle_stream;
 mod handle_task;
 pub(crate) mod types;
 
-use crate::proxy::{tuic::types::SocketAdderTrans, utils::new_udp_socket};
+use crate::proxy::{
+    tuic::types::SocketAdderTrans,
+    utils::{new_udp_socket, DialError},
+};
 use anyhow::Result;
 use async_trait::async_trait;
 
@@ -32,12 +35,10 @@ use crate::{
             ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
         },
         dns::ThreadSafeDNSResolver,
+        net_monitor::NetworkEpoch,
     },
     common::tls::GLOBAL_ROOT_STORE,
-    proxy::{
-        tuic::types::{ServerAddr, TuicEndpoint},
-        DialWithConnector,
-    },
+    proxy::{tuic::types::TuicEndpoint, utils::ServerAddr, DialWithConnector},
     session::Session,
 };
 
@@ -57,6 +58,10 @@ use super::{
     OutboundType,
 };
 
+/// how often `pre-connect` checks the connection at most, a
+/// `request-timeout` of 0 would have it check all the time
+const MIN_PRE_CONNECT_REFRESH: Duration = Duration::from_secs(3);
+
 #[derive(Debug, Clone)]
 pub struct HandlerOptions {
     pub name: String,
@@ -96,6 +101,7 @@ pub struct Handler {
     opts: HandlerOptions,
     ep: OnceCell<TuicEndpoint>,
     conn: AsyncMutex<Option<Arc<TuicConnection>>>,
+    epoch: NetworkEpoch,
     next_assoc_id: AtomicU16,
 }
 
@@ -119,6 +125,10 @@ impl OutboundHandler for Handler {
         OutboundType::Tuic
     }
 
+    fn server(&self) -> Option<ClashSocksAddr> {
+        ClashSocksAddr::try_from((self.opts.server.clone(), self.opts.port)).ok()
+    }
+
     async fn support_udp(&self) -> bool {
         true
     }
@@ -130,7 +140,7 @@ impl OutboundHandler for Handler {
     ) -> std::io::Result<BoxedChainedStream> {
         self.do_connect_stream(sess, resolver).await.map_err(|e| {
             tracing::error!("{:?}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+            DialError::from_anyhow(e, DialError::ProxyHandshake)
         })
     }
 
@@ -141,13 +151,24 @@ impl OutboundHandler for Handler {
     ) -> std::io::Result<BoxedChainedDatagram> {
         self.do_connect_datagram(sess, resolver).await.map_err(|e| {
             tracing::error!("{:?}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+            DialError::from_anyhow(e, DialError::ProxyHandshake)
         })
     }
 
     async fn support_connector(&self) -> ConnectorType {
         ConnectorType::None
     }
+
+    fn pre_connect_refresh(&self) -> Option<Duration> {
+        Some((self.opts.idle_timeout * 4 / 5).max(MIN_PRE_CONNECT_REFRESH))
+    }
+
+    async fn warm_up(&self, resolver: ThreadSafeDNSResolver) -> std::io::Result<()> {
+        self.get_conn(&resolver, &Session::default())
+            .await
+            .map(|_| ())
+            .map_err(|e| DialError::from_anyhow(e, DialError::ProxyHandshake))
+    }
 }
 
 impl Handler {
@@ -156,6 +177,7 @@ impl Handler {
             opts,
             ep: OnceCell::new(),
             conn: AsyncMutex::new(None),
+            epoch: Default::default(),
             next_assoc_id: AtomicU16::new(0),
         }
     }
@@ -228,7 +250,11 @@ impl Handler {
         endpoint.set_default_client_config(quinn_config);
         let endpoint = TuicEndpoint {
             ep: endpoint,
-            server: ServerAddr::new(opts.server.clone(), opts.port, None),
+            server: ServerAddr::new(
+                &opts.server,
+                opts.port,
+                opts.common_opts.resolve_strategy,
+            ),
             uuid: opts.uuid,
             password: Arc::from(
                 opts.password.clone().into_bytes().into_boxed_slice(),
@@ -262,7 +288,8 @@ impl Handler {
                 *guard = Some(endpoint.connect(resolver, false).await?);
             }
             let conn = guard.take().unwrap();
-            let conn = if conn.check_open().is_err() {
+            // the socket is still bound to the old network
+            let conn = if self.epoch.changed() || conn.check_open().is_err() {
                 // reconnect
                 endpoint.connect(resolver, true).await?
             } else {
@@ -272,7 +299,13 @@ impl Handler {
             Ok(conn)
         };
 
-        tokio::time::timeout(self.opts.request_timeout, fut).await?
+        // `dial-timeout` over `request-timeout`, which used to be it
+        let timeout = self
+            .opts
+            .common_opts
+            .dial_timeout
+            .unwrap_or(self.opts.request_timeout);
+        tokio::time::timeout(timeout, fut).await?
     }
 
     async fn do_connect_stream(
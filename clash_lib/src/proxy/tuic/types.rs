@@ -1,6 +1,6 @@
 use crate::{
-    app::dns::ThreadSafeDNSResolver,
-    proxy::utils::{get_outbound_interface, new_udp_socket, Interface},
+    app::{dns::ThreadSafeDNSResolver, net_monitor::default_interface},
+    proxy::utils::{new_udp_socket, Interface, ServerAddr},
     session::SocksAddr as ClashSocksAddr,
 };
 
@@ -11,7 +11,6 @@ use quinn::{
 use register_count::Counter;
 use std::{
     collections::HashMap,
-    net::{IpAddr, SocketAddr},
     sync::{atomic::AtomicU32, Arc},
     time::Duration,
 };
@@ -39,7 +38,7 @@ impl TuicEndpoint {
         resolver: &ThreadSafeDNSResolver,
         rebind: bool,
     ) -> Result<Arc<TuicConnection>> {
-        let remote_addr = self.server.resolve(resolver).await?;
+        let remote_addr = self.server.socket_addr(resolver).await?;
         let connect_to = async {
             // if client and server don't match each other or forced to rebind,
             // then rebind local socket
@@ -47,7 +46,7 @@ impl TuicEndpoint {
                 debug!("rebinding endpoint UDP socket");
 
                 let socket = {
-                    let iface = get_outbound_interface();
+                    let iface = default_interface();
                     new_udp_socket(
                         None,
                         iface.map(|x| Interface::Name(x.name)),
@@ -67,11 +66,11 @@ impl TuicEndpoint {
             tracing::trace!(
                 "connecting to {} {} from {}",
                 remote_addr,
-                self.server.server_name(),
+                self.server.host(),
                 self.ep.local_addr().unwrap()
             );
 
-            let conn = self.ep.connect(remote_addr, self.server.server_name())?;
+            let conn = self.ep.connect(remote_addr, self.server.host())?;
             let (conn, zero_rtt_accepted) = if self.zero_rtt_handshake {
                 match conn.into_0rtt() {
                     Ok((conn, zero_rtt_accepted)) => (conn, Some(zero_rtt_accepted)),
@@ -201,36 +200,6 @@ impl TuicConnection {
     }
 }
 
-pub struct ServerAddr {
-    domain: String,
-    port: u16,
-    ip: Option<IpAddr>,
-}
-impl ServerAddr {
-    pub fn new(domain: String, port: u16, ip: Option<IpAddr>) -> Self {
-        Self { domain, port, ip }
-    }
-
-    pub fn server_name(&self) -> &str {
-        &self.domain
-    }
-
-    pub async fn resolve(
-        &self,
-        resolver: &ThreadSafeDNSResolver,
-    ) -> Result<SocketAddr> {
-        if let Some(ip) = self.ip {
-            Ok(SocketAddr::from((ip, self.port)))
-        } else {
-            let ip = resolver
-                .resolve(self.domain.as_str(), false)
-                .await?
-                .ok_or(anyhow!("Resolve failed: unknown hostname"))?;
-            Ok(SocketAddr::from((ip, self.port)))
-        }
-    }
-}
-
 #[derive(Debug, Clone, Copy)]
 pub enum UdpRelayMode {
     Native,
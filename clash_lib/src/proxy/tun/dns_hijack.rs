@@ -0,0 +1,137 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use hickory_proto::{op::Message, rr::rdata::opt::EdnsCode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, trace};
+
+use crate::{
+    app::dns::ThreadSafeDNSResolver,
+    common::{errors::new_io_error, io::PrefixedStream},
+    config::internal::config::DnsHijackTarget,
+};
+
+/// how long a hijacked TCP connection can take to send its first query before
+/// it's considered not DNS
+const FIRST_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn should_hijack(targets: &[DnsHijackTarget], dst: &SocketAddr) -> bool {
+    targets.iter().any(|x| x.matches(dst))
+}
+
+/// Answers the query with the Clash DNS server.
+pub async fn exchange(
+    resolver: &ThreadSafeDNSResolver,
+    msg: &Message,
+) -> anyhow::Result<Vec<u8>> {
+    trace!("hijack dns request: {:?}", msg);
+    let mut resp = resolver.exchange(msg).await?;
+    // hickory mutates id sometimes, https://github.com/hickory-dns/hickory-dns/pull/2590
+    resp.set_id(msg.id());
+
+    if let Some(edns) = msg.extensions() {
+        if edns.option(EdnsCode::Padding).is_none() {
+            if let Some(edns) = resp.extensions_mut() {
+                edns.options_mut().remove(EdnsCode::Padding);
+            }
+        }
+    }
+    trace!("hijack dns response: {:?}", resp);
+
+    Ok(resp.to_vec()?)
+}
+
+/// Serves the length-prefixed DNS queries on a hijacked TCP stream.
+/// If the first message doesn't parse as DNS, the stream is returned with the
+/// consumed bytes put back so it can be dispatched as usual.
+pub async fn serve_tcp<S>(
+    mut stream: S,
+    resolver: &ThreadSafeDNSResolver,
+) -> io::Result<Option<PrefixedStream<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut consumed = Vec::new();
+    let first = match tokio::time::timeout(
+        FIRST_QUERY_TIMEOUT,
+        read_first_message(&mut stream, &mut consumed),
+    )
+    .await
+    {
+        Ok(Ok(Some(len))) => Message::from_vec(&consumed[2..2 + len])
+            .ok()
+            .map(|msg| (msg, len)),
+        // closed before a full message was read
+        Ok(Ok(None)) => return Ok(None),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => None,
+    };
+
+    let Some((mut msg, len)) = first else {
+        debug!("hijacked TCP stream is not DNS, forwarding");
+        return Ok(Some(PrefixedStream::new(consumed, stream)));
+    };
+
+    // the client may have pipelined more queries after the first one
+    let mut stream = PrefixedStream::new(consumed.split_off(2 + len), stream);
+
+    loop {
+        let resp = exchange(resolver, &msg)
+            .await
+            .map_err(|e| new_io_error(format!("dns exchange: {}", e)))?;
+        stream.write_u16(resp.len() as u16).await?;
+        stream.write_all(&resp).await?;
+
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).await?;
+        msg = Message::from_vec(&buf).map_err(new_io_error)?;
+    }
+}
+
+/// Reads until `buf` holds the first length-prefixed message, keeping every
+/// byte read so far in it. Returns the message length, or None on EOF before
+/// a whole message arrived.
+async fn read_first_message<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> io::Result<Option<usize>>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        if buf.len() >= 2 {
+            let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+            if buf.len() >= len + 2 {
+                return Ok(Some(len));
+            }
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_first_message;
+
+    #[tokio::test]
+    async fn test_read_first_message() {
+        let mut data: &[u8] = &[0x00, 0x03, 1, 2, 3, 0x00];
+        let mut buf = vec![];
+        let len = read_first_message(&mut data, &mut buf).await.unwrap();
+        assert_eq!(len, Some(3));
+        // nothing read is dropped
+        assert_eq!(buf, vec![0x00, 0x03, 1, 2, 3, 0x00]);
+
+        let mut data: &[u8] = &[0x00, 0x05, 1, 2];
+        let mut buf = vec![];
+        let len = read_first_message(&mut data, &mut buf).await.unwrap();
+        assert_eq!(len, None);
+        assert_eq!(buf.len(), 4);
+    }
+}
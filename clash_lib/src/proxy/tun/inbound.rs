@@ -1,20 +1,29 @@
-use super::{datagram::TunDatagram, netstack};
-use std::{net::SocketAddr, sync::Arc};
+use super::{datagram::TunDatagram, dns_hijack, mss, netstack};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 
 use tracing::{debug, error, info, trace, warn};
 use tun::{Device, TunPacket};
 use url::Url;
 
 use crate::{
-    app::{dispatcher::Dispatcher, dns::ThreadSafeDNSResolver},
-    common::errors::{map_io_error, new_io_error},
-    config::internal::config::TunConfig,
-    proxy::{
-        datagram::UdpPacket, tun::routes::maybe_add_routes,
-        utils::get_outbound_interface,
+    app::{
+        dispatcher::Dispatcher, dns::ThreadSafeDNSResolver,
+        net_monitor::default_interface,
+    },
+    common::{
+        errors::{map_io_error, new_io_error},
+        io::AsTcpStream,
     },
+    config::internal::config::{DnsHijackTarget, TunConfig},
+    proxy::{datagram::UdpPacket, tun::routes::maybe_add_routes},
     session::{Network, Session, Type},
     Error, Runner,
 };
@@ -22,21 +31,65 @@ use crate::{
 use crate::{defer, proxy::tun::routes};
 
 const DEFAULT_SO_MARK: u32 = 3389;
+
+// a stream of the userspace stack, there is no socket under it
+impl AsTcpStream for netstack::TcpStream {}
 const DEFAULT_ROUTE_TABLE: u32 = 2468;
 
-async fn handle_inbound_stream(
-    stream: netstack::TcpStream,
+/// how long a SYN waits for the route of its connection, to tell the tunnel
+/// its MSS is clamped to
+const TUNNEL_LOOKUP_TIMEOUT: Duration = Duration::from_millis(200);
+/// the connections whose SYN-ACK is still to be clamped, dropped all at once
+/// past this many, e.g. when the stack never answers
+const MAX_PENDING_SYNS: usize = 1024;
+
+/// The MTU the MSS of a SYN from `src` to `dst` is clamped to, the smaller of
+/// `mtu` and that of the tunnel the connection is routed through, if any.
+async fn syn_mtu(
+    dispatcher: &Dispatcher,
+    src: SocketAddr,
+    dst: SocketAddr,
+    so_mark: u32,
+    mtu: u16,
+) -> u16 {
+    let sess = Session {
+        network: Network::Tcp,
+        typ: Type::Tun,
+        source: src,
+        destination: dst.into(),
+        so_mark: Some(so_mark),
+        ..Default::default()
+    };
+    match tokio::time::timeout(TUNNEL_LOOKUP_TIMEOUT, dispatcher.tunnel_mtu(sess))
+        .await
+    {
+        Ok(Some(tunnel)) => mtu.min(u16::try_from(tunnel.mtu).unwrap_or(u16::MAX)),
+        Ok(None) => mtu,
+        Err(_) => {
+            debug!(
+                "routing {} -> {} timed out, not clamping to a tunnel",
+                src, dst
+            );
+            mtu
+        }
+    }
+}
+
+async fn handle_inbound_stream<S>(
+    stream: S,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     so_mark: u32,
-) {
+) where
+    S: AsyncRead + AsyncWrite + AsTcpStream + Unpin + Send,
+{
     let sess = Session {
         network: Network::Tcp,
         typ: Type::Tun,
         source: local_addr,
         destination: remote_addr.into(),
-        iface: get_outbound_interface()
+        iface: default_interface()
             .map(|x| crate::proxy::utils::Interface::Name(x.name))
             .inspect(|x| {
                 debug!(
@@ -57,7 +110,7 @@ async fn handle_inbound_datagram(
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
     so_mark: u32,
-    dns_hijack: bool,
+    dns_hijack: Vec<DnsHijackTarget>,
 ) {
     // tun i/o
     let (ls, mut lr) = socket.split();
@@ -79,7 +132,7 @@ async fn handle_inbound_datagram(
     let sess = Session {
         network: Network::Udp,
         typ: Type::Tun,
-        iface: get_outbound_interface()
+        iface: default_interface()
             .map(|x| crate::proxy::utils::Interface::Name(x.name))
             .inspect(|x| {
                 debug!("selecting outbound interface: {:?} for tun UDP traffic", x);
@@ -118,57 +171,30 @@ async fn handle_inbound_datagram(
 
             trace!("tun -> dispatcher: {:?}", pkt);
 
-            if dns_hijack && pkt.dst_addr.port() == 53 {
+            if dns_hijack::should_hijack(&dns_hijack, &dst_addr) {
                 trace!("got dns packet: {:?}, returning from Clash DNS server", pkt);
 
                 match hickory_proto::op::Message::from_vec(&pkt.data) {
-                    Ok(msg) => {
-                        trace!("hijack dns request: {:?}", msg);
-                        let mut resp = match resolver_dns.exchange(&msg).await {
-                            Ok(resp) => resp,
-                            Err(e) => {
-                                warn!("failed to exchange dns message: {}", e);
-                                continue;
-                            }
-                        };
-                        // hickory mutates id sometimes, https://github.com/hickory-dns/hickory-dns/pull/2590
-                        resp.set_id(msg.id());
-
-                        if let Some(edns) = msg.extensions() {
-                            if edns
-                                .option(
-                                    hickory_proto::rr::rdata::opt::EdnsCode::Padding,
-                                )
-                                .is_none()
-                            {
-                                if let Some(edns) = resp.extensions_mut() {
-                                    edns.options_mut().remove(
-                                        hickory_proto::rr::rdata::opt::EdnsCode::Padding,
-                                    );
-                                }
+                    Ok(msg) => match dns_hijack::exchange(&resolver_dns, &msg).await
+                    {
+                        Ok(data) => {
+                            if let Err(e) = ls_dns.send_to(
+                                &data,
+                                &pkt.dst_addr.must_into_socket_addr(),
+                                &pkt.src_addr.must_into_socket_addr(),
+                            ) {
+                                warn!(
+                                    "failed to send udp packet to netstack: {}",
+                                    e
+                                );
                             }
+                            continue;
                         }
-                        trace!("hijack dns response: {:?}", resp);
-
-                        match resp.to_vec() {
-                            Ok(data) => {
-                                if let Err(e) = ls_dns.send_to(
-                                    &data,
-                                    &pkt.dst_addr.must_into_socket_addr(),
-                                    &pkt.src_addr.must_into_socket_addr(),
-                                ) {
-                                    warn!(
-                                        "failed to send udp packet to netstack: {}",
-                                        e
-                                    );
-                                }
-                                continue;
-                            }
-                            Err(e) => {
-                                warn!("failed to serialize dns response: {}", e);
-                            }
+                        Err(e) => {
+                            warn!("failed to exchange dns message: {}", e);
+                            continue;
                         }
-                    }
+                    },
                     Err(e) => {
                         warn!(
                             "failed to parse dns packet: {}, putting it back to \
@@ -235,10 +261,11 @@ pub fn get_runner(
     }
 
     let gw = cfg.gateway;
+    let mtu = cfg.mtu.unwrap_or(if cfg!(windows) { 65535 } else { 1500 });
     tun_cfg
         .address(gw.addr())
         .netmask(gw.netmask())
-        .mtu(cfg.mtu.unwrap_or(if cfg!(windows) { 65535 } else { 1500 }))
+        .mtu(mtu)
         .up();
 
     let tun = tun::create_as_async(&tun_cfg)
@@ -251,6 +278,7 @@ pub fn get_runner(
     cfg.route_table = cfg.route_table.or(Some(DEFAULT_ROUTE_TABLE));
     cfg.so_mark = cfg.so_mark.or(Some(DEFAULT_SO_MARK));
 
+    routes::maybe_add_inet6_address(&cfg, &tun_name)?;
     maybe_add_routes(&cfg, &tun_name)?;
 
     let (stack, mut tcp_listener, udp_socket) =
@@ -277,11 +305,26 @@ pub fn get_runner(
 
         let mut futs: Vec<Runner> = vec![];
 
+        let mss_mtu = u16::try_from(mtu).unwrap_or(u16::MAX);
+        // the connections whose SYN was clamped to a tunnel, for the SYN-ACK
+        // of the stack
+        let syn_mtus = Arc::new(Mutex::new(HashMap::new()));
+
         // dispatcher -> stack -> tun
+        let pending = syn_mtus.clone();
         futs.push(Box::pin(async move {
             while let Some(pkt) = stack_stream.next().await {
                 match pkt {
-                    Ok(pkt) => {
+                    Ok(mut pkt) => {
+                        let mtu = match mss::syn_addrs(&pkt) {
+                            Some((src, dst, true)) => pending
+                                .lock()
+                                .unwrap()
+                                .remove(&(dst, src))
+                                .unwrap_or(mss_mtu),
+                            _ => mss_mtu,
+                        };
+                        mss::clamp_mss(&mut pkt, mtu);
                         if let Err(e) = tun_sink.send(TunPacket::new(pkt)).await {
                             error!("failed to send pkt to tun: {}", e);
                             break;
@@ -298,13 +341,29 @@ pub fn get_runner(
         }));
 
         // tun -> stack -> dispatcher
+        let dsp = dispatcher.clone();
         futs.push(Box::pin(async move {
             while let Some(pkt) = tun_stream.next().await {
                 match pkt {
                     Ok(pkt) => {
-                        if let Err(e) =
-                            stack_sink.send(pkt.into_bytes().into()).await
-                        {
+                        let mut pkt: Vec<u8> = pkt.into_bytes().into();
+                        let mtu = match mss::syn_addrs(&pkt) {
+                            Some((src, dst, false)) => {
+                                let mtu =
+                                    syn_mtu(&dsp, src, dst, so_mark, mss_mtu).await;
+                                if mtu < mss_mtu {
+                                    let mut pending = syn_mtus.lock().unwrap();
+                                    if pending.len() >= MAX_PENDING_SYNS {
+                                        pending.clear();
+                                    }
+                                    pending.insert((src, dst), mtu);
+                                }
+                                mtu
+                            }
+                            _ => mss_mtu,
+                        };
+                        mss::clamp_mss(&mut pkt, mtu);
+                        if let Err(e) = stack_sink.send(pkt).await {
                             error!("failed to send pkt to stack: {}", e);
                             break;
                         }
@@ -320,12 +379,38 @@ pub fn get_runner(
         }));
 
         let dsp = dispatcher.clone();
+        let dns_hijack = cfg.dns_hijack.clone();
+        let resolver_dns = resolver.clone();
         futs.push(Box::pin(async move {
             while let Some((stream, local_addr, remote_addr)) =
                 tcp_listener.next().await
             {
                 debug!("new tun TCP connection: {} -> {}", local_addr, remote_addr);
 
+                if dns_hijack::should_hijack(&dns_hijack, &remote_addr) {
+                    let resolver = resolver_dns.clone();
+                    let dispatcher = dsp.clone();
+                    tokio::spawn(async move {
+                        match dns_hijack::serve_tcp(stream, &resolver).await {
+                            Ok(Some(stream)) => {
+                                handle_inbound_stream(
+                                    stream,
+                                    local_addr,
+                                    remote_addr,
+                                    dispatcher,
+                                    so_mark,
+                                )
+                                .await
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                warn!("failed to serve hijacked dns over tcp: {}", e)
+                            }
+                        }
+                    });
+                    continue;
+                }
+
                 tokio::spawn(handle_inbound_stream(
                     stream,
                     local_addr,
@@ -344,7 +429,7 @@ pub fn get_runner(
                 dispatcher,
                 resolver,
                 so_mark,
-                cfg.dns_hijack,
+                cfg.dns_hijack.clone(),
             )
             .await;
             Err(Error::Operation("tun stopped unexpectedly 3".to_string()))
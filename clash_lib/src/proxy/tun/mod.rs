@@ -1,13 +1,21 @@
+#[cfg(feature = "tun")]
 pub mod inbound;
+#[cfg(feature = "tun")]
 pub use netstack_lwip as netstack;
 mod datagram;
+#[cfg(feature = "tun")]
+mod dns_hijack;
+#[cfg(feature = "tun")]
+mod mss;
+#[cfg(feature = "tun")]
 pub use inbound::get_runner as get_tun_runner;
+#[cfg(feature = "tun")]
 mod routes;
 
-#[cfg(target_os = "linux")] // for tproxy
+// for tproxy and tunnel as well
 pub use datagram::TunDatagram;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tun"))]
 mod tests {
     use std::thread;
 
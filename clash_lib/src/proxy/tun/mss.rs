@@ -0,0 +1,217 @@
+//! TCP MSS clamping for the packets crossing the tun device, so neither end
+//! sends segments that don't fit in the tun MTU, or in the tunnel of the
+//! proxy the connection is routed to.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::proxy::utils::mtu::{IPV4_HEADER_LEN, IPV6_HEADER_LEN, TCP_HEADER_LEN};
+
+const PROTO_TCP: u8 = 6;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+/// Lowers the MSS option of a TCP SYN in the IP packet to what fits in `mtu`.
+/// Anything that isn't a SYN, or that can't be parsed, is left untouched.
+pub fn clamp_mss(pkt: &mut [u8], mtu: u16) {
+    let Some((tcp_offset, ip_header_len)) = syn_offsets(pkt) else {
+        return;
+    };
+    let tcp = &mut pkt[tcp_offset..];
+
+    let max_mss = (mtu as usize).saturating_sub(ip_header_len + TCP_HEADER_LEN);
+    let data_offset = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+
+    let mut i = TCP_HEADER_LEN;
+    while i < data_offset {
+        match tcp[i] {
+            TCP_OPT_END => return,
+            TCP_OPT_NOP => i += 1,
+            kind => {
+                let Some(&len) = tcp.get(i + 1) else {
+                    return;
+                };
+                let len = len as usize;
+                if len < 2 || i + len > data_offset {
+                    return;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if mss as usize > max_mss {
+                        let new_mss = max_mss as u16;
+                        tcp[i + 2..i + 4].copy_from_slice(&new_mss.to_be_bytes());
+                        let csum = u16::from_be_bytes([tcp[16], tcp[17]]);
+                        let csum = update_checksum(csum, mss, new_mss);
+                        tcp[16..18].copy_from_slice(&csum.to_be_bytes());
+                    }
+                    return;
+                }
+                i += len;
+            }
+        }
+    }
+}
+
+/// The source and destination of a TCP SYN in the IP packet, and whether it
+/// answers one, i.e. ACKs too. None for anything else.
+pub fn syn_addrs(pkt: &[u8]) -> Option<(SocketAddr, SocketAddr, bool)> {
+    let (tcp_offset, _) = syn_offsets(pkt)?;
+    let (src, dst): (IpAddr, IpAddr) = if pkt[0] >> 4 == 4 {
+        (
+            <[u8; 4]>::try_from(&pkt[12..16]).ok()?.into(),
+            <[u8; 4]>::try_from(&pkt[16..20]).ok()?.into(),
+        )
+    } else {
+        (
+            <[u8; 16]>::try_from(&pkt[8..24]).ok()?.into(),
+            <[u8; 16]>::try_from(&pkt[24..40]).ok()?.into(),
+        )
+    };
+    let tcp = &pkt[tcp_offset..];
+    Some((
+        (src, u16::from_be_bytes([tcp[0], tcp[1]])).into(),
+        (dst, u16::from_be_bytes([tcp[2], tcp[3]])).into(),
+        tcp[13] & TCP_FLAG_ACK != 0,
+    ))
+}
+
+/// where the TCP header of a SYN starts in the IP packet, and the length of
+/// the fixed IP header
+fn syn_offsets(pkt: &[u8]) -> Option<(usize, usize)> {
+    let first = *pkt.first()?;
+    let (tcp_offset, ip_header_len) = match first >> 4 {
+        4 => {
+            if pkt.len() < IPV4_HEADER_LEN || pkt[9] != PROTO_TCP {
+                return None;
+            }
+            // only the first fragment carries the TCP header
+            if u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff != 0 {
+                return None;
+            }
+            ((first & 0x0f) as usize * 4, IPV4_HEADER_LEN)
+        }
+        6 => {
+            // extension headers are not followed
+            if pkt.len() < IPV6_HEADER_LEN || pkt[6] != PROTO_TCP {
+                return None;
+            }
+            (IPV6_HEADER_LEN, IPV6_HEADER_LEN)
+        }
+        _ => return None,
+    };
+
+    let tcp = pkt.get(tcp_offset..)?;
+    if tcp.len() < TCP_HEADER_LEN || tcp[13] & TCP_FLAG_SYN == 0 {
+        return None;
+    }
+    Some((tcp_offset, ip_header_len))
+}
+
+/// incremental checksum update, RFC 1624
+fn update_checksum(csum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!csum as u32) + (!old as u32) + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clamp_mss, syn_addrs};
+
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum = 0u32;
+        for chunk in data.chunks(2) {
+            let word = if chunk.len() == 2 {
+                u16::from_be_bytes([chunk[0], chunk[1]])
+            } else {
+                u16::from_be_bytes([chunk[0], 0])
+            };
+            sum += word as u32;
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    /// TCP checksum of an IPv4 packet, 0 means valid
+    fn tcp_checksum_v4(pkt: &[u8]) -> u16 {
+        let tcp = &pkt[20..];
+        let mut pseudo = vec![];
+        pseudo.extend_from_slice(&pkt[12..20]);
+        pseudo.extend_from_slice(&[0, 6]);
+        pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        pseudo.extend_from_slice(tcp);
+        checksum(&pseudo)
+    }
+
+    fn syn_packet(flags: u8, mss: u16) -> Vec<u8> {
+        let mut pkt = vec![
+            0x45, 0, 0, 48, 0, 0, 0x40, 0, 64, 6, 0, 0, // ip
+            10, 0, 0, 1, // src
+            1, 1, 1, 1, // dst
+        ];
+        let mut tcp = vec![
+            0x30, 0x39, 0x01, 0xbb, // ports
+            0, 0, 0, 1, // seq
+            0, 0, 0, 0,    // ack
+            0x70, // data offset = 7 words
+            flags, 0xff, 0xff, // window
+            0, 0, 0, 0, // checksum, urgent
+            0x01, 0x01, // nop nop
+            2, 4, // mss
+        ];
+        tcp.extend_from_slice(&mss.to_be_bytes());
+        tcp.extend_from_slice(&[0x04, 0x02, 0x00, 0x00]); // sack permitted, end
+        pkt.extend_from_slice(&tcp);
+
+        let csum = tcp_checksum_v4(&pkt);
+        pkt[36..38].copy_from_slice(&csum.to_be_bytes());
+        assert_eq!(tcp_checksum_v4(&pkt), 0);
+        pkt
+    }
+
+    #[test]
+    fn test_clamp_syn() {
+        let mut pkt = syn_packet(0x02, 1460);
+        clamp_mss(&mut pkt, 1280);
+        assert_eq!(u16::from_be_bytes([pkt[44], pkt[45]]), 1240);
+        assert_eq!(tcp_checksum_v4(&pkt), 0);
+    }
+
+    #[test]
+    fn test_keep_small_mss() {
+        let mut pkt = syn_packet(0x12, 1200);
+        let orig = pkt.clone();
+        clamp_mss(&mut pkt, 1280);
+        assert_eq!(pkt, orig);
+    }
+
+    #[test]
+    fn test_ignore_non_syn() {
+        let mut pkt = syn_packet(0x10, 1460);
+        let orig = pkt.clone();
+        clamp_mss(&mut pkt, 1280);
+        assert_eq!(pkt, orig);
+    }
+
+    #[test]
+    fn test_syn_addrs() {
+        let syn = syn_packet(0x02, 1460);
+        assert_eq!(
+            syn_addrs(&syn),
+            Some((
+                "10.0.0.1:12345".parse().unwrap(),
+                "1.1.1.1:443".parse().unwrap(),
+                false
+            ))
+        );
+        let syn_ack = syn_packet(0x12, 1460);
+        assert!(syn_addrs(&syn_ack).is_some_and(|x| x.2));
+        assert_eq!(syn_addrs(&syn_packet(0x10, 1460)), None);
+    }
+}
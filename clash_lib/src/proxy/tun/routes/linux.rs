@@ -41,10 +41,31 @@ pub fn add_route(via: &OutboundInterface, dest: &IpNet) -> std::io::Result<()> {
     Ok(())
 }
 
+pub fn add_address(tun_name: &str, addr: &IpNet) -> std::io::Result<()> {
+    let cmd = std::process::Command::new("ip")
+        .arg("-6")
+        .arg("addr")
+        .arg("add")
+        .arg(addr.to_string())
+        .arg("dev")
+        .arg(tun_name)
+        .output()?;
+    warn!("executing: ip -6 addr add {} dev {}", addr, tun_name);
+    if !cmd.status.success() {
+        return Err(new_io_error(format!(
+            "add address failed: {}",
+            String::from_utf8_lossy(&cmd.stderr)
+        )));
+    }
+    Ok(())
+}
+
 /// three rules are added:
 /// # ip route add default dev wg0 table 2468
 /// # ip rule add not fwmark 1234 table 2468
 /// # ip rule add table main suppress_prefixlength 0
+/// and one more for each excluded prefix, taking precedence over the above:
+/// # ip rule add to 10.8.0.0/16 table main
 pub fn setup_policy_routing(
     tun_cfg: &TunConfig,
     via: &OutboundInterface,
@@ -117,6 +138,31 @@ pub fn setup_policy_routing(
         )));
     }
 
+    for exclude in &tun_cfg.route_exclude_address {
+        exclude_rule("add", exclude)?;
+    }
+
+    Ok(())
+}
+
+fn exclude_rule(action: &str, exclude: &IpNet) -> std::io::Result<()> {
+    let cmd = std::process::Command::new("ip")
+        .arg(if exclude.addr().is_ipv4() { "-4" } else { "-6" })
+        .arg("rule")
+        .arg(action)
+        .arg("to")
+        .arg(exclude.to_string())
+        .arg("table")
+        .arg("main")
+        .output()?;
+    warn!("executing: ip rule {} to {} table main", action, exclude);
+    if !cmd.status.success() {
+        return Err(new_io_error(format!(
+            "{} exclude rule failed: {}",
+            action,
+            String::from_utf8_lossy(&cmd.stderr)
+        )));
+    }
     Ok(())
 }
 
@@ -170,5 +216,9 @@ pub fn maybe_routes_clean_up(tun_cfg: &TunConfig) -> std::io::Result<()> {
             String::from_utf8_lossy(&cmd.stderr)
         )));
     }
+
+    for exclude in &tun_cfg.route_exclude_address {
+        exclude_rule("del", exclude)?;
+    }
     Ok(())
 }
@@ -27,6 +27,28 @@ pub fn add_route(via: &OutboundInterface, dest: &IpNet) -> std::io::Result<()> {
     }
 }
 
+pub fn add_address(tun_name: &str, addr: &IpNet) -> std::io::Result<()> {
+    let cmd = std::process::Command::new("ifconfig")
+        .arg(tun_name)
+        .arg("inet6")
+        .arg(addr.addr().to_string())
+        .arg("prefixlen")
+        .arg(addr.prefix_len().to_string())
+        .output()?;
+
+    warn!(
+        "executing: ifconfig {} inet6 {} prefixlen {}",
+        tun_name,
+        addr.addr(),
+        addr.prefix_len()
+    );
+    if !cmd.status.success() {
+        Err(new_io_error("add address failed"))
+    } else {
+        Ok(())
+    }
+}
+
 fn get_default_gateway() -> std::io::Result<Option<Ipv4Addr>> {
     let cmd = std::process::Command::new("route")
         .arg("-n")
@@ -1,31 +1,32 @@
 #[cfg(windows)]
 mod windows;
 #[cfg(windows)]
-use windows::add_route;
-#[cfg(windows)]
 pub use windows::maybe_routes_clean_up;
+#[cfg(windows)]
+use windows::{add_address, add_route};
 
 #[cfg(target_os = "macos")]
 mod macos;
 #[cfg(target_os = "macos")]
-use macos::add_route;
-#[cfg(target_os = "macos")]
 pub use macos::maybe_routes_clean_up;
+#[cfg(target_os = "macos")]
+use macos::{add_address, add_route};
 
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
-use linux::add_route;
-#[cfg(target_os = "linux")]
 pub use linux::maybe_routes_clean_up;
+#[cfg(target_os = "linux")]
+use linux::{add_address, add_route};
 
 #[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
 mod other;
 #[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
-use other::add_route;
-#[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
 pub use other::maybe_routes_clean_up;
+#[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
+use other::{add_address, add_route};
 
+use ipnet::IpNet;
 use tracing::warn;
 
 use crate::{
@@ -66,8 +67,6 @@ pub fn maybe_add_routes(cfg: &TunConfig, tun_name: &str) -> std::io::Result<()>
 
             #[cfg(not(target_os = "linux"))]
             {
-                use ipnet::IpNet;
-
                 use std::net::Ipv4Addr;
 
                 let default_routes = vec![
@@ -76,7 +75,8 @@ pub fn maybe_add_routes(cfg: &TunConfig, tun_name: &str) -> std::io::Result<()>
                     IpNet::new(std::net::IpAddr::V4(Ipv4Addr::new(128, 0, 0, 0)), 1)
                         .unwrap(),
                 ];
-                for r in default_routes {
+                for r in exclude_routes(&default_routes, &cfg.route_exclude_address)
+                {
                     add_route(&tun_iface, &r)?;
                 }
 
@@ -90,11 +90,89 @@ pub fn maybe_add_routes(cfg: &TunConfig, tun_name: &str) -> std::io::Result<()>
                 linux::setup_policy_routing(cfg, &tun_iface)?;
             }
         } else {
-            for r in &cfg.routes {
-                add_route(&tun_iface, r)?;
+            for r in exclude_routes(&cfg.routes, &cfg.route_exclude_address) {
+                add_route(&tun_iface, &r)?;
             }
         }
     }
 
     Ok(())
 }
+
+/// Adds the IPv6 address to the tun device, the IPv4 one is set when the
+/// device is created.
+pub fn maybe_add_inet6_address(
+    cfg: &TunConfig,
+    tun_name: &str,
+) -> std::io::Result<()> {
+    if let Some(addr) = &cfg.inet6_address {
+        add_address(tun_name, addr)?;
+    }
+    Ok(())
+}
+
+/// Removes `excludes` from `routes`, splitting a route into smaller prefixes
+/// when only part of it is excluded.
+fn exclude_routes(routes: &[IpNet], excludes: &[IpNet]) -> Vec<IpNet> {
+    let mut routes = routes.to_vec();
+    for exclude in excludes {
+        routes = routes
+            .into_iter()
+            .flat_map(|r| exclude_one(r, exclude))
+            .collect();
+    }
+    routes
+}
+
+fn exclude_one(route: IpNet, exclude: &IpNet) -> Vec<IpNet> {
+    if exclude.contains(&route) {
+        return vec![];
+    }
+    if !route.contains(exclude) {
+        return vec![route];
+    }
+    // the exclude is strictly inside, keep the half that doesn't overlap and
+    // carry on with the other one
+    route
+        .subnets(route.prefix_len() + 1)
+        .expect("prefix is shorter than the exclude")
+        .flat_map(|half| exclude_one(half, exclude))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ipnet::IpNet;
+
+    use super::exclude_routes;
+
+    fn nets(l: &[&str]) -> Vec<IpNet> {
+        l.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_exclude_routes() {
+        let routes = nets(&["10.0.0.0/8", "1.1.1.1/32"]);
+
+        assert_eq!(exclude_routes(&routes, &[]), routes);
+        assert_eq!(
+            exclude_routes(&routes, &nets(&["1.0.0.0/8"])),
+            nets(&["10.0.0.0/8"])
+        );
+        assert_eq!(
+            exclude_routes(&routes, &nets(&["10.128.0.0/9"])),
+            nets(&["10.0.0.0/9", "1.1.1.1/32"])
+        );
+        assert_eq!(
+            exclude_routes(&nets(&["10.0.0.0/8"]), &nets(&["10.8.0.0/14"])),
+            nets(&[
+                "10.0.0.0/13",
+                "10.12.0.0/14",
+                "10.16.0.0/12",
+                "10.32.0.0/11",
+                "10.64.0.0/10",
+                "10.128.0.0/9",
+            ])
+        );
+    }
+}
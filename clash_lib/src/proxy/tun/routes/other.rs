@@ -8,6 +8,11 @@ pub fn add_route(_: &OutboundInterface, _: &IpNet) -> std::io::Result<()> {
     Ok(())
 }
 
+pub fn add_address(_: &str, _: &IpNet) -> std::io::Result<()> {
+    warn!("add_address is not implemented on {}", std::env::consts::OS);
+    Ok(())
+}
+
 pub fn maybe_routes_clean_up(_: &TunConfig) -> std::io::Result<()> {
     warn!(
         "maybe_routes_clean_up is not implemented on {}",
@@ -51,6 +51,25 @@ pub fn add_route(via: &OutboundInterface, dest: &IpNet) -> io::Result<()> {
     }
 }
 
+pub fn add_address(tun_name: &str, addr: &IpNet) -> io::Result<()> {
+    let cmd = format!("netsh interface ipv6 add address \"{}\" {}", tun_name, addr);
+
+    info!("executing: {}", cmd);
+
+    let output = std::process::Command::new("cmd")
+        .args(["/C", &cmd])
+        .output()
+        .map_err(|e| new_io_error(e.to_string().as_str()))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let err = String::from_utf8_lossy(&output.stderr);
+        error!("failed to add address: {}", err);
+        Err(new_io_error(err.to_string().as_str()))
+    }
+}
+
 pub fn maybe_routes_clean_up(_: &TunConfig) -> std::io::Result<()> {
     Ok(())
 }
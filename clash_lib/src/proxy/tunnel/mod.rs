@@ -0,0 +1,138 @@
+use super::tun::TunDatagram;
+use crate::{
+    app::{dispatcher::Dispatcher, inbound::limiter::ListenerLimiter},
+    config::internal::config::Tunnel,
+    proxy::{datagram::UdpPacket, utils::apply_tcp_options, InboundListener},
+    session::{Network, Session, SocksAddr, Type},
+};
+use async_trait::async_trait;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{trace, warn};
+
+/// Forwards whatever comes in on a local address to a fixed target, like
+/// `socat` would, through the tunnel's proxy or by the rules.
+pub struct Listener {
+    tunnel: Tunnel,
+    dispatcher: Arc<Dispatcher>,
+    limiter: Arc<ListenerLimiter>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        warn!("tunnel inbound listener on {} stopped", self.tunnel.address);
+    }
+}
+
+impl Listener {
+    pub fn new(
+        tunnel: Tunnel,
+        dispatcher: Arc<Dispatcher>,
+        limiter: Arc<ListenerLimiter>,
+    ) -> Self {
+        Self {
+            tunnel,
+            dispatcher,
+            limiter,
+        }
+    }
+
+    fn session(&self, network: Network, source: SocketAddr) -> Session {
+        Session {
+            network,
+            typ: Type::Tunnel,
+            source,
+            destination: self.tunnel.target.clone(),
+            inbound_name: "tunnel".to_owned(),
+            special_proxy: self.tunnel.proxy.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl InboundListener for Listener {
+    fn handle_tcp(&self) -> bool {
+        self.tunnel.tcp
+    }
+
+    fn handle_udp(&self) -> bool {
+        self.tunnel.udp
+    }
+
+    async fn listen_tcp(&self) -> std::io::Result<()> {
+        let listener = TcpListener::bind(self.tunnel.address).await?;
+
+        loop {
+            let (socket, src_addr) = listener.accept().await?;
+
+            let Some(guard) = self.limiter.try_acquire(src_addr.ip()) else {
+                continue;
+            };
+
+            let socket = apply_tcp_options(socket)?;
+            let sess = self.session(Network::Tcp, src_addr);
+
+            trace!("tunnel new tcp conn {}", sess);
+
+            let dispatcher = self.dispatcher.clone();
+            tokio::spawn(async move {
+                dispatcher.dispatch_stream(sess, socket).await;
+                drop(guard);
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> std::io::Result<()> {
+        let socket = Arc::new(UdpSocket::bind(self.tunnel.address).await?);
+
+        // dispatcher <-> tunnel communications, the dispatcher keeps a
+        // session per source as with any other inbound
+        let (l_tx, mut l_rx) = tokio::sync::mpsc::channel(32);
+        let (d_tx, d_rx) = tokio::sync::mpsc::channel(32);
+
+        let udp_stream = TunDatagram::new(l_tx, d_rx);
+        let closer = self.dispatcher.dispatch_datagram(
+            self.session(Network::Udp, SocketAddr::from(([0, 0, 0, 0], 0))),
+            Box::new(udp_stream),
+        );
+
+        // dispatcher -> tunnel
+        let responder = socket.clone();
+        let fut1 = tokio::spawn(async move {
+            while let Some(pkt) = l_rx.recv().await {
+                trace!("tunnel <- dispatcher: {:?}", pkt);
+
+                let SocksAddr::Ip(dst) = pkt.dst_addr else {
+                    continue;
+                };
+                if let Err(e) = responder.send_to(&pkt.data[..], dst).await {
+                    warn!("failed to send udp packet to {}: {}", dst, e);
+                }
+            }
+        });
+
+        // tunnel -> dispatcher
+        let target = self.tunnel.target.clone();
+        let fut2 = tokio::spawn(async move {
+            let mut buf = vec![0_u8; 1024 * 64];
+            while let Ok((n, src)) = socket.recv_from(&mut buf).await {
+                let pkt = UdpPacket {
+                    data: buf[..n].to_vec(),
+                    src_addr: src.into(),
+                    dst_addr: target.clone(),
+                };
+                trace!("tunnel -> dispatcher: {:?}", pkt);
+                if let Err(e) = d_tx.send(pkt).await {
+                    warn!("failed to send udp packet to proxy: {}", e);
+                    break;
+                }
+            }
+
+            closer.send(0).ok();
+        });
+
+        let _ = futures::future::join(fut1, fut2).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! What a dial failed on, for the API and the logs to tell a name that
+//! didn't resolve from a certificate that was rejected.
+//!
+//! The dial path still returns [`io::Error`]s, a [`DialError`] goes in one as
+//! its inner error where the category isn't clear from the error already, so
+//! the kind and message are kept and nothing on the way has to change. TLS
+//! failures keep the rustls error inside and timeouts their kind, both are
+//! told without a tag.
+
+use std::{error::Error, fmt::Display, io};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DialError {
+    /// the name of the server or destination didn't resolve
+    Dns,
+    /// the TCP connect failed, refused, unreachable and the like
+    Connect,
+    Tls,
+    /// the proxy turned down the handshake or the request, e.g. a wrong
+    /// password
+    ProxyHandshake,
+    Timeout,
+    /// the proxy can't do what was asked, e.g. UDP
+    Unsupported,
+}
+
+impl Display for DialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DialError::Dns => "dns",
+            DialError::Connect => "connect",
+            DialError::Tls => "tls",
+            DialError::ProxyHandshake => "proxy-handshake",
+            DialError::Timeout => "timeout",
+            DialError::Unsupported => "unsupported",
+        };
+        f.write_str(s)
+    }
+}
+
+/// an error and what it's of, shows as the error alone
+#[derive(Debug)]
+struct Tagged {
+    category: DialError,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl Display for Tagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl Error for Tagged {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+impl DialError {
+    /// a new error of this, e.g. for a proxy refusing the request
+    pub fn error<T>(self, msg: T) -> io::Error
+    where
+        T: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let kind = match self {
+            DialError::Timeout => io::ErrorKind::TimedOut,
+            DialError::Unsupported => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(
+            kind,
+            Tagged {
+                category: self,
+                source: msg.into(),
+            },
+        )
+    }
+
+    /// `e` as of this, unless it's known what it's of already, which is then
+    /// closer to the cause, e.g. the timeout of a proxy handshake
+    pub fn tag(self, e: io::Error) -> io::Error {
+        if Self::of(&e).is_some() {
+            return e;
+        }
+        io::Error::new(
+            e.kind(),
+            Tagged {
+                category: self,
+                source: Box::new(e),
+            },
+        )
+    }
+
+    /// what `e` is of, if it's known
+    pub fn of(e: &io::Error) -> Option<DialError> {
+        if let Some(inner) = e.get_ref() {
+            if let Some(tagged) = inner.downcast_ref::<Tagged>() {
+                return Some(tagged.category);
+            }
+            if inner.is::<rustls::Error>() {
+                return Some(DialError::Tls);
+            }
+        }
+        match e.kind() {
+            io::ErrorKind::TimedOut => Some(DialError::Timeout),
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::AddrNotAvailable => Some(DialError::Connect),
+            io::ErrorKind::Unsupported => Some(DialError::Unsupported),
+            _ => None,
+        }
+    }
+
+    /// [`DialError::of`] the first io error in the sources of `e`, for the
+    /// errors of e.g. the http client of the url tests
+    pub fn find(e: &(dyn Error + 'static)) -> Option<DialError> {
+        let mut next = Some(e);
+        while let Some(e) = next {
+            if let Some(category) = e.downcast_ref::<io::Error>().and_then(Self::of)
+            {
+                return Some(category);
+            }
+            next = e.source();
+        }
+        None
+    }
+}
+
+#[cfg(feature = "quic-protocols")]
+impl DialError {
+    /// What a QUIC connection failed on, its handshake is the TLS one.
+    pub fn of_quic(e: &quinn::ConnectionError) -> DialError {
+        match e {
+            quinn::ConnectionError::TimedOut => DialError::Timeout,
+            // the CRYPTO_ERROR range, a TLS alert
+            quinn::ConnectionError::TransportError(e)
+                if (0x100..0x200).contains(&u64::from(e.code)) =>
+            {
+                DialError::Tls
+            }
+            quinn::ConnectionError::VersionMismatch => DialError::Unsupported,
+            _ => DialError::ProxyHandshake,
+        }
+    }
+
+    /// the error of a QUIC proxy's dial as an io error, of what it failed on
+    /// where that can be told and of `or` otherwise
+    pub fn from_anyhow(e: anyhow::Error, or: DialError) -> io::Error {
+        let category = if let Some(x) = e.downcast_ref::<io::Error>() {
+            Self::of(x)
+        } else if e.is::<tokio::time::error::Elapsed>() {
+            Some(DialError::Timeout)
+        } else {
+            e.downcast_ref::<quinn::ConnectionError>()
+                .map(Self::of_quic)
+        };
+        category.unwrap_or(or).error(format!("{:#}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        proxy::{
+            socks::{Handler, HandlerOptions},
+            transport::{self, TLSOptions},
+            utils::{new_tcp_stream, GLOBAL_DIRECT_CONNECTOR},
+            OutboundHandler,
+        },
+        session::Session,
+        test_support::{self, Socks5Server, TrojanServer},
+    };
+
+    use super::DialError;
+
+    #[test]
+    fn test_tag_keeps_kind_and_message() {
+        let e = DialError::Dns.tag(io::Error::other("no dns result"));
+        assert_eq!(DialError::of(&e), Some(DialError::Dns));
+        assert_eq!(e.to_string(), "no dns result");
+
+        // the first one wins
+        let e = DialError::ProxyHandshake.tag(e);
+        assert_eq!(DialError::of(&e), Some(DialError::Dns));
+
+        let e = DialError::ProxyHandshake.tag(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "handshake timed out",
+        ));
+        assert_eq!(DialError::of(&e), Some(DialError::Timeout));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        assert_eq!(DialError::of(&io::Error::other("boom")), None);
+    }
+
+    #[tokio::test]
+    async fn test_closed_port() {
+        let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = l.local_addr().unwrap();
+        drop(l);
+
+        let e = new_tcp_stream(
+            addr,
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .err()
+        .expect("nothing listens there");
+        assert_eq!(DialError::of(&e), Some(DialError::Connect));
+    }
+
+    #[tokio::test]
+    async fn test_bad_cert() {
+        let server = TrojanServer::new("password").start().await;
+
+        let s = new_tcp_stream(
+            server.addr,
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .unwrap();
+        // self-signed, and not for this name either
+        let e = transport::tls::wrap_stream(
+            Box::new(s),
+            TLSOptions {
+                skip_cert_verify: false,
+                sni: "example.com".to_owned(),
+                alpn: None,
+                pinned_cert_chain_sha256: vec![],
+                pinned_cert_only: false,
+                fingerprint: None,
+            },
+            None,
+        )
+        .await
+        .err()
+        .expect("the certificate should be rejected");
+        assert_eq!(DialError::of(&e), Some(DialError::Tls));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password() {
+        let server = Socks5Server::new().auth("user", "password").start().await;
+        let handler = Handler::new(HandlerOptions {
+            name: "socks".to_owned(),
+            server: server.addr.ip().to_string(),
+            port: server.addr.port(),
+            user: Some("user".to_owned()),
+            password: Some("wrong".to_owned()),
+            ..Default::default()
+        });
+
+        let e = tokio::time::timeout(
+            Duration::from_secs(5),
+            handler.connect_stream_with_connector(
+                &Session::default(),
+                test_support::resolver(),
+                GLOBAL_DIRECT_CONNECTOR.as_ref(),
+            ),
+        )
+        .await
+        .unwrap()
+        .err()
+        .expect("the password is wrong");
+        assert_eq!(DialError::of(&e), Some(DialError::ProxyHandshake));
+        assert!(e.to_string().contains("authentication failed"), "{}", e);
+        assert!(!server.requests()[0].accepted);
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize as ESerialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{
+    app::{
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
+        dns::ThreadSafeDNSResolver,
+        profile::ThreadSafeCacheFile,
+    },
+    config::def::UdpNat,
+    proxy::{
+        AnyOutboundHandler, ConnectorType, DialWithConnector, OutboundHandler,
+        OutboundType,
+    },
+    session::{Session, SocksAddr},
+};
+
+use super::{connect_timeout, mtu::TunnelMtu, RemoteConnector};
+
+/// A proxy with at most `max-concurrent-dials` connections being made to it
+/// at once, e.g. so the TLS handshakes of all the connections retried after
+/// a network outage don't overwhelm the server. The connections once made
+/// aren't limited.
+#[derive(Debug)]
+pub struct DialLimited {
+    inner: AnyOutboundHandler,
+    max: usize,
+    permits: Semaphore,
+    /// the dials waiting for their turn, shown by the proxies api
+    waiting: AtomicUsize,
+    /// the proxy's `dial-timeout`, how long a dial may wait for its turn as
+    /// well, `connect-timeout` if not set
+    dial_timeout: Option<Duration>,
+}
+
+/// counts a dial as waiting for as long as it's held, also when the dial is
+/// dropped while waiting
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl DialLimited {
+    pub fn new(
+        inner: AnyOutboundHandler,
+        max: usize,
+        dial_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            max,
+            permits: Semaphore::new(max),
+            waiting: AtomicUsize::new(0),
+            dial_timeout,
+        }
+    }
+
+    async fn acquire(&self) -> io::Result<SemaphorePermit<'_>> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Ok(permit);
+        }
+
+        let queue_timeout = self.dial_timeout.unwrap_or_else(connect_timeout);
+        let permit = {
+            let _waiting = Waiting::new(&self.waiting);
+            tokio::time::timeout(queue_timeout, self.permits.acquire()).await
+        };
+
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => unreachable!("the semaphore is never closed"),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("dial queue timeout for {}", self.inner.name()),
+            )),
+        }
+    }
+
+    async fn dial<T>(
+        &self,
+        f: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        let _permit = self.acquire().await?;
+        f.await
+    }
+}
+
+#[async_trait]
+impl DialWithConnector for DialLimited {
+    fn support_dialer(&self) -> Option<&str> {
+        self.inner.support_dialer()
+    }
+
+    async fn register_connector(&self, connector: Arc<dyn RemoteConnector>) {
+        self.inner.register_connector(connector).await
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for DialLimited {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.inner.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        self.dial(self.inner.connect_stream(sess, resolver)).await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.dial(self.inner.connect_datagram(sess, resolver)).await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        self.inner.support_connector().await
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        self.dial(
+            self.inner
+                .connect_stream_with_connector(sess, resolver, connector),
+        )
+        .await
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.dial(
+            self.inner
+                .connect_datagram_with_connector(sess, resolver, connector),
+        )
+        .await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send>> {
+        let mut m = self.inner.as_map().await;
+        m.insert("maxConcurrentDials".to_owned(), Box::new(self.max));
+        m.insert(
+            "dialsWaiting".to_owned(),
+            Box::new(self.waiting.load(Ordering::Relaxed)),
+        );
+        m
+    }
+
+    fn icon(&self) -> Option<String> {
+        self.inner.icon()
+    }
+
+    fn server(&self) -> Option<SocksAddr> {
+        self.inner.server()
+    }
+
+    fn set_cache_store(&self, cache_store: ThreadSafeCacheFile) {
+        self.inner.set_cache_store(cache_store)
+    }
+
+    async fn prefetch(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Option<io::Result<()>> {
+        self.inner.prefetch(resolver).await
+    }
+
+    fn udp_nat(&self) -> Option<UdpNat> {
+        self.inner.udp_nat()
+    }
+
+    fn tunnel_mtu(&self) -> Option<TunnelMtu> {
+        self.inner.tunnel_mtu()
+    }
+
+    fn pre_connect_refresh(&self) -> Option<Duration> {
+        self.inner.pre_connect_refresh()
+    }
+
+    async fn warm_up(&self, resolver: ThreadSafeDNSResolver) -> io::Result<()> {
+        self.inner.warm_up(resolver).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        app::{dispatcher::ChainedStreamWrapper, dns::MockClashResolver},
+        proxy::{mocks::MockDummyOutboundHandler, OutboundHandler},
+        session::Session,
+    };
+
+    use super::DialLimited;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dial_queue() {
+        let mut inner = MockDummyOutboundHandler::new();
+        inner.expect_name().return_const("slow".to_owned());
+        inner.expect_connect_stream().returning(|_, _| {
+            Ok(Box::new(ChainedStreamWrapper::new(
+                tokio_test::io::Builder::new().build(),
+            )))
+        });
+        let h = Arc::new(DialLimited::new(
+            Arc::new(inner),
+            1,
+            Some(Duration::from_secs(5)),
+        ));
+        let resolver = Arc::new(MockClashResolver::new());
+
+        // a dial taking its time holds the only permit
+        let permit = h.acquire().await.unwrap();
+
+        let waiter = {
+            let (h, resolver) = (h.clone(), resolver.clone());
+            tokio::spawn(async move {
+                h.connect_stream(&Session::default(), resolver).await
+            })
+        };
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(h.waiting.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        let started = tokio::time::Instant::now();
+        let e = waiter.await.unwrap().unwrap_err();
+        assert!(e.to_string().contains("dial queue timeout"), "{}", e);
+        // the dial-timeout of the proxy
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(h.waiting.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        // a dial given up on while waiting isn't counted anymore
+        let waiter = {
+            let (h, resolver) = (h.clone(), resolver.clone());
+            tokio::spawn(async move {
+                h.connect_stream(&Session::default(), resolver).await
+            })
+        };
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(h.waiting.load(std::sync::atomic::Ordering::Relaxed), 1);
+        waiter.abort();
+        let _ = waiter.await;
+        assert_eq!(h.waiting.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        drop(permit);
+        h.connect_stream(&Session::default(), resolver)
+            .await
+            .expect("dial failed");
+    }
+}
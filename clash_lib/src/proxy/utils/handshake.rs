@@ -0,0 +1,223 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// A stream whose reads and writes fail once `timeout` has passed, so a
+/// server dribbling out its side of a handshake can't hold the connection
+/// forever. The deadline is lifted by [`Handshake::done`], or by taking the
+/// stream back out with [`TimedStream::into_inner`].
+pub struct TimedStream<S> {
+    inner: S,
+    phase: &'static str,
+    timeout: Duration,
+    /// None once the handshake is done
+    deadline: Option<Pin<Box<Sleep>>>,
+    done: Arc<AtomicBool>,
+}
+
+/// Lifts the deadline of a [`TimedStream`] that has other streams put on top
+/// of it by the handshake, e.g. TLS, and can't be taken back out.
+pub struct Handshake(Arc<AtomicBool>);
+
+impl Handshake {
+    pub fn done(self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<S> TimedStream<S> {
+    /// `phase` names the handshake in the timeout error, e.g. `socks5
+    /// handshake`
+    pub fn new(
+        inner: S,
+        phase: &'static str,
+        timeout: Duration,
+    ) -> (Self, Handshake) {
+        let done = Arc::new(AtomicBool::new(false));
+        (
+            Self {
+                inner,
+                phase,
+                timeout,
+                deadline: Some(Box::pin(tokio::time::sleep(timeout))),
+                done: done.clone(),
+            },
+            Handshake(done),
+        )
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn check(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if self.deadline.is_some() && self.done.load(Ordering::Relaxed) {
+            self.deadline = None;
+        }
+        match self.deadline.as_mut() {
+            Some(deadline) if deadline.as_mut().poll(cx).is_ready() => {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} timed out after {:?}", self.phase, self.timeout),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.check(cx)?;
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check(cx)?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.check(cx)?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        app::dns::{MockClashResolver, ThreadSafeDNSResolver},
+        proxy::{
+            socks::{Handler, HandlerOptions},
+            utils::{Interface, RemoteConnector},
+            AnyOutboundDatagram, AnyStream, HandlerCommonOptions, OutboundHandler,
+        },
+        session::{Session, SocksAddr},
+    };
+
+    use super::TimedStream;
+
+    /// a server that takes the socks5 greeting, then says nothing
+    #[derive(Debug)]
+    struct StalledServer;
+
+    #[async_trait]
+    impl RemoteConnector for StalledServer {
+        async fn connect_stream(
+            &self,
+            _: ThreadSafeDNSResolver,
+            _: &str,
+            _: u16,
+            _: Option<&Interface>,
+            #[cfg(any(target_os = "linux", target_os = "android"))] _: Option<u32>,
+        ) -> io::Result<AnyStream> {
+            Ok(Box::new(
+                tokio_test::io::Builder::new()
+                    .write(&[0x05, 0x01, 0x00])
+                    .wait(Duration::from_secs(3600))
+                    .build(),
+            ))
+        }
+
+        async fn connect_datagram(
+            &self,
+            _: ThreadSafeDNSResolver,
+            _: Option<SocketAddr>,
+            _: SocksAddr,
+            _: Option<Interface>,
+            #[cfg(any(target_os = "linux", target_os = "android"))] _: Option<u32>,
+        ) -> io::Result<AnyOutboundDatagram> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_handshake() {
+        let handler = Handler::new(HandlerOptions {
+            name: "socks".to_owned(),
+            common_opts: HandlerCommonOptions {
+                handshake_timeout: Some(Duration::from_secs(5)),
+                ..Default::default()
+            },
+            server: "proxy.example".to_owned(),
+            port: 1080,
+            user: None,
+            password: None,
+            udp: false,
+            tls: false,
+            sni: "proxy.example".to_owned(),
+            skip_cert_verify: false,
+            pinned_cert_chain_sha256: vec![],
+            pinned_cert_only: false,
+            client_fingerprint: None,
+            udp_nat: None,
+        });
+
+        let started = tokio::time::Instant::now();
+        let e = handler
+            .connect_stream_with_connector(
+                &Session::default(),
+                Arc::new(MockClashResolver::new()),
+                &StalledServer,
+            )
+            .await
+            .err()
+            .expect("the handshake should time out");
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("socks5 handshake"), "{}", e);
+        assert!(started.elapsed() < Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_done_lifts_deadline() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let (mut s, handshake) =
+            TimedStream::new(server, "handshake", Duration::from_secs(10));
+        handshake.done();
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(s.into_inner());
+    }
+}
@@ -6,14 +6,29 @@ use std::{
 #[cfg(all(test, docker_test))]
 pub mod test_utils;
 
+mod dial_error;
+mod dial_limit;
+mod handshake;
+pub mod mtu;
 mod platform;
 
 pub mod provider_helper;
 mod proxy_connector;
+mod server_addr;
+#[cfg(any(feature = "shadowsocks", feature = "trojan"))]
+mod server_list;
 mod socket_helpers;
+mod uot;
 
+pub use dial_error::DialError;
+pub use dial_limit::DialLimited;
+pub use handshake::*;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 pub use proxy_connector::*;
+pub use server_addr::*;
+#[cfg(any(feature = "shadowsocks", feature = "trojan"))]
+pub use server_list::*;
+pub use uot::{uot_destination, UotDatagram};
 
 use serde::{Deserialize, Serialize};
 pub use socket_helpers::*;
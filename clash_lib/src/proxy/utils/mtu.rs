@@ -0,0 +1,194 @@
+//! How many bytes each proxy protocol adds around a UDP payload, to tell the
+//! largest payload that still fits in one packet of a link.
+//!
+//! TCP through the tun is terminated by the netstack, each outbound opening
+//! a connection of its own, so the MSS a client is told only has to fit the
+//! tun, and the [`TunnelMtu`] of the outbound its connection is routed to.
+
+use crate::{proxy::OutboundType, session::SocksAddr};
+
+/// what the links to the proxies are taken to be, ethernet
+pub const DEFAULT_LINK_MTU: usize = 1500;
+
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const UDP_HEADER_LEN: usize = 8;
+#[cfg_attr(not(feature = "tun"), allow(dead_code))]
+pub const TCP_HEADER_LEN: usize = 20;
+
+/// RSV and FRAG, then the address
+const SOCKS5_UDP_HEADER_LEN: usize = 3;
+/// the salt of the widest cipher and the AEAD tag
+const SHADOWSOCKS_UDP_HEADER_LEN: usize = 32 + 16;
+/// type, receiver index and counter, then the tag after the inner packet
+const WIREGUARD_HEADER_LEN: usize = 16 + 16;
+/// a QUIC short header with an 8 byte connection id and the longest packet
+/// number, the AEAD tag and the DATAGRAM frame header
+const QUIC_DATAGRAM_HEADER_LEN: usize = 1 + 8 + 4 + 16 + 3;
+/// version, type, association id, packet id, fragment total and id, size
+const TUIC_PACKET_HEADER_LEN: usize = 2 + 2 + 2 + 1 + 1 + 2;
+/// session id, packet id, fragment id and count, then the address as a
+/// length prefixed string
+const HYSTERIA2_UDP_HEADER_LEN: usize = 4 + 2 + 1 + 1 + 2;
+
+/// What a protocol puts around each UDP payload, besides the outer IP and UDP
+/// headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatagramOverhead {
+    /// the fixed part
+    pub header: usize,
+    /// whether the destination address is sent with each packet
+    pub addr: bool,
+    /// whether a payload too big for one packet is split up by the protocol
+    /// itself, instead of being left to IP fragmentation
+    pub fragments: bool,
+}
+
+/// None for the protocols carrying UDP over a stream, whose payloads are as
+/// large as UDP allows, and for the groups and REJECT.
+pub fn datagram_overhead(proto: &OutboundType) -> Option<DatagramOverhead> {
+    let (header, addr, fragments) = match proto {
+        OutboundType::Direct => (0, false, false),
+        OutboundType::Socks5 => (SOCKS5_UDP_HEADER_LEN, true, false),
+        OutboundType::Shadowsocks => (SHADOWSOCKS_UDP_HEADER_LEN, true, false),
+        // the inner IP and UDP headers are counted by the `mtu` of the tunnel
+        OutboundType::WireGuard => (WIREGUARD_HEADER_LEN, false, false),
+        OutboundType::Tuic => (
+            QUIC_DATAGRAM_HEADER_LEN + TUIC_PACKET_HEADER_LEN,
+            true,
+            true,
+        ),
+        OutboundType::Hysteria2 => (
+            QUIC_DATAGRAM_HEADER_LEN + HYSTERIA2_UDP_HEADER_LEN,
+            true,
+            true,
+        ),
+        _ => return None,
+    };
+    Some(DatagramOverhead {
+        header,
+        addr,
+        fragments,
+    })
+}
+
+/// the size of `addr` as a SOCKS5 address, which the protocols sending it
+/// with each packet all use or are close to
+pub fn socks_addr_len(addr: &SocksAddr) -> usize {
+    match addr {
+        SocksAddr::Ip(x) if x.is_ipv4() => 1 + 4 + 2,
+        SocksAddr::Ip(_) => 1 + 16 + 2,
+        SocksAddr::Domain(host, _) => 1 + 1 + host.len() + 2,
+    }
+}
+
+/// The largest payload to `dst` that fits in one packet of a link with
+/// `link_mtu`, the link being over IPv6 if `v6`. None if the protocol has no
+/// such limit.
+pub fn max_payload_size(
+    proto: &OutboundType,
+    link_mtu: usize,
+    v6: bool,
+    dst: &SocksAddr,
+) -> Option<usize> {
+    let overhead = datagram_overhead(proto)?;
+    let ip = if v6 { IPV6_HEADER_LEN } else { IPV4_HEADER_LEN };
+    let addr = if overhead.addr {
+        socks_addr_len(dst)
+    } else {
+        0
+    };
+    Some(link_mtu.saturating_sub(ip + UDP_HEADER_LEN + overhead.header + addr))
+}
+
+/// A tunnel carrying IP packets, e.g. wireguard, whose `mtu` counts the
+/// inner IP headers too. Its packets can't be fragmented on the way.
+#[cfg_attr(not(feature = "wireguard"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelMtu {
+    pub mtu: usize,
+    /// whether the tunnel has an IPv6 address
+    pub ipv6: bool,
+}
+
+impl TunnelMtu {
+    /// The largest UDP payload to `dst` the tunnel takes, the inner header
+    /// being the one of its family. A domain is taken to go over IPv6 if
+    /// the tunnel has an IPv6 address, as it may resolve to one.
+    pub fn max_udp_payload(&self, dst: &SocksAddr) -> usize {
+        let v6 = match dst {
+            SocksAddr::Ip(x) => x.is_ipv6(),
+            SocksAddr::Domain(..) => self.ipv6,
+        };
+        let ip = if v6 { IPV6_HEADER_LEN } else { IPV4_HEADER_LEN };
+        self.mtu.saturating_sub(ip + UDP_HEADER_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::{proxy::OutboundType, session::SocksAddr};
+
+    use super::{max_payload_size, TunnelMtu};
+
+    #[test]
+    fn test_max_payload_size() {
+        let v4 = SocksAddr::from("1.1.1.1:53".parse::<SocketAddr>().unwrap());
+        let v6 =
+            SocksAddr::from("[2606:4700::1111]:53".parse::<SocketAddr>().unwrap());
+        let domain = SocksAddr::Domain("example.com".to_owned(), 443);
+
+        assert_eq!(
+            max_payload_size(&OutboundType::Direct, 1500, false, &v4),
+            Some(1472)
+        );
+        assert_eq!(
+            max_payload_size(&OutboundType::Direct, 1500, true, &v6),
+            Some(1452)
+        );
+        // 1500 - 20 - 8 - 3 - 7
+        assert_eq!(
+            max_payload_size(&OutboundType::Socks5, 1500, false, &v4),
+            Some(1462)
+        );
+        // 1500 - 20 - 8 - 48 - 19
+        assert_eq!(
+            max_payload_size(&OutboundType::Shadowsocks, 1500, false, &v6),
+            Some(1405)
+        );
+        // 1500 - 20 - 8 - 42 - 15
+        assert_eq!(
+            max_payload_size(&OutboundType::Tuic, 1500, false, &domain),
+            Some(1415)
+        );
+        assert_eq!(
+            max_payload_size(&OutboundType::Trojan, 1500, false, &v4),
+            None
+        );
+        assert_eq!(
+            max_payload_size(&OutboundType::Direct, 20, false, &v4),
+            Some(0)
+        );
+
+        // the default tunnel mtu, the header of each packet's own family
+        let dual_stack = TunnelMtu {
+            mtu: 1420,
+            ipv6: true,
+        };
+        assert_eq!(dual_stack.max_udp_payload(&v4), 1392);
+        assert_eq!(dual_stack.max_udp_payload(&v6), 1372);
+        assert_eq!(dual_stack.max_udp_payload(&domain), 1372);
+        let v4_only = TunnelMtu {
+            mtu: 1420,
+            ipv6: false,
+        };
+        assert_eq!(v4_only.max_udp_payload(&domain), 1392);
+        // the largest packet inside the tunnel over a 1500 link
+        assert_eq!(
+            max_payload_size(&OutboundType::WireGuard, 1500, false, &v4),
+            Some(1440)
+        );
+    }
+}
@@ -7,20 +7,22 @@ use tracing::trace;
 use crate::{
     app::{
         dispatcher::{
-            ChainedDatagram, ChainedDatagramWrapper, ChainedStream,
-            ChainedStreamWrapper,
+            BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram,
+            ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::errors::new_io_error,
     proxy::{
         datagram::OutboundDatagramImpl, AnyOutboundDatagram, AnyOutboundHandler,
-        AnyStream,
+        AnyStream, ConnectorType, OutboundHandler,
     },
     session::{Network, Session, SocksAddr, Type},
 };
 
-use super::{new_tcp_stream, new_udp_socket, Interface};
+use super::{
+    new_tcp_stream, new_udp_socket, uot_destination, DialError, Interface,
+    UotDatagram,
+};
 
 /// allows a proxy to get a connection to a remote server
 #[async_trait]
@@ -46,6 +48,12 @@ pub trait RemoteConnector: Send + Sync + Debug {
             u32,
         >,
     ) -> std::io::Result<AnyOutboundDatagram>;
+
+    /// whether the server is dialed from here, so its name is resolved here
+    /// rather than by a proxy in between
+    fn dials_directly(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
@@ -66,6 +74,10 @@ fn global_direct_connector() -> Arc<dyn RemoteConnector> {
 
 #[async_trait]
 impl RemoteConnector for DirectConnector {
+    fn dials_directly(&self) -> bool {
+        true
+    }
+
     async fn connect_stream(
         &self,
         resolver: ThreadSafeDNSResolver,
@@ -77,8 +89,8 @@ impl RemoteConnector for DirectConnector {
         let dial_addr = resolver
             .resolve(address, false)
             .await
-            .map_err(|v| new_io_error(format!("can't resolve dns: {}", v)))?
-            .ok_or(new_io_error("no dns result"))?;
+            .map_err(|v| DialError::Dns.error(format!("can't resolve dns: {}", v)))?
+            .ok_or_else(|| DialError::Dns.error("no dns result"))?;
 
         new_tcp_stream(
             (dial_addr, port).into(),
@@ -115,6 +127,7 @@ impl RemoteConnector for DirectConnector {
 pub struct ProxyConnector {
     proxy: AnyOutboundHandler,
     connector: Box<dyn RemoteConnector>,
+    udp_over_tcp: bool,
 }
 
 impl ProxyConnector {
@@ -123,8 +136,81 @@ impl ProxyConnector {
         // TODO: make this Arc
         connector: Box<dyn RemoteConnector>,
     ) -> Self {
-        Self { proxy, connector }
+        Self {
+            proxy,
+            connector,
+            udp_over_tcp: false,
+        }
+    }
+
+    /// the datagrams through a proxy without UDP of its own over `connector`
+    /// go over a stream, see [`connect_datagram_via`]
+    pub fn udp_over_tcp(mut self, on: bool) -> Self {
+        self.udp_over_tcp = on;
+        self
+    }
+}
+
+/// Whether `proxy` relays UDP of its own when it's dialed over another
+/// proxy, or directly when `first`.
+pub async fn native_udp(proxy: &dyn OutboundHandler, first: bool) -> bool {
+    proxy.support_udp().await
+        && (first || matches!(proxy.support_connector().await, ConnectorType::All))
+}
+
+/// Whether `proxy` can be dialed over another proxy, or directly when
+/// `first`, so a stream can carry its UDP.
+pub async fn carries_stream(proxy: &dyn OutboundHandler, first: bool) -> bool {
+    first || !matches!(proxy.support_connector().await, ConnectorType::None)
+}
+
+/// The UDP of `proxy` dialed over `connector`. Without UDP of its own there,
+/// it goes over a stream with udp-over-tcp when `udp_over_tcp` is on, which
+/// the server of the proxy has to speak.
+pub async fn connect_datagram_via(
+    proxy: &AnyOutboundHandler,
+    sess: &Session,
+    resolver: ThreadSafeDNSResolver,
+    connector: &dyn RemoteConnector,
+    udp_over_tcp: bool,
+) -> std::io::Result<BoxedChainedDatagram> {
+    let first = connector.dials_directly();
+    // the ones that can't be given a connector dial on their own
+    let own =
+        first && matches!(proxy.support_connector().await, ConnectorType::None);
+    if !udp_over_tcp || native_udp(proxy.as_ref(), first).await {
+        return if own {
+            proxy.connect_datagram(sess, resolver).await
+        } else {
+            proxy
+                .connect_datagram_with_connector(sess, resolver, connector)
+                .await
+        };
     }
+
+    trace!(
+        "{} has no udp over {:?}, using udp-over-tcp",
+        proxy.name(),
+        connector
+    );
+    let stream_sess = Session {
+        network: Network::Tcp,
+        destination: uot_destination(),
+        ..sess.clone()
+    };
+    let s: BoxedChainedStream = if own {
+        proxy.connect_stream(&stream_sess, resolver).await?
+    } else {
+        proxy
+            .connect_stream_with_connector(&stream_sess, resolver, connector)
+            .await?
+    };
+    let names = s.chain().names().await;
+    let d = ChainedDatagramWrapper::new(
+        UotDatagram::new(Box::new(s), &sess.destination).await?,
+    );
+    d.chain().set(names).await;
+    Ok(Box::new(d))
 }
 
 impl Debug for ProxyConnector {
@@ -189,14 +275,14 @@ impl RemoteConnector for ProxyConnector {
             so_mark,
             ..Default::default()
         };
-        let s = self
-            .proxy
-            .connect_datagram_with_connector(
-                &sess,
-                resolver,
-                self.connector.as_ref(),
-            )
-            .await?;
+        let s = connect_datagram_via(
+            &self.proxy,
+            &sess,
+            resolver,
+            self.connector.as_ref(),
+            self.udp_over_tcp,
+        )
+        .await?;
 
         let stream = ChainedDatagramWrapper::new(s);
         stream.append_to_chain(self.proxy.name()).await;
@@ -0,0 +1,500 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+use tokio::{net::TcpStream, time::timeout};
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::ChainedDatagramWrapper, dns::ThreadSafeDNSResolver,
+        net_monitor::NetworkEpoch,
+    },
+    common::errors::new_io_error,
+    config::internal::proxy::ResolveStrategy,
+    proxy::{datagram::OutboundDatagramImpl, AnyOutboundDatagram, AnyStream},
+    session::SocksAddr,
+};
+
+use super::{
+    connect_timeout, new_tcp_stream_with_timeout, new_udp_socket_with_buffers,
+    DialError, Interface, RemoteConnector, SocketBuffers,
+};
+
+/// an address is looked up again after a day at the latest
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// how many of the addresses of a server are dialed before giving up
+const MAX_ATTEMPTS: usize = 4;
+
+/// how long a dial may take while there are other addresses left to try
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The server of a proxy, its addresses are kept for as long as the DNS
+/// answer allows instead of being looked up on every dial.
+pub struct ServerAddr {
+    host: String,
+    port: u16,
+    strategy: Option<ResolveStrategy>,
+    /// `connect-timeout` if None
+    dial_timeout: Option<Duration>,
+    /// the proxy's own, the global ones where not set
+    socket_buffers: SocketBuffers,
+    /// the addresses last looked up and until when they can be used
+    cached: Mutex<Option<(Vec<IpAddr>, Instant)>>,
+    /// the address last dialed, tried first next time
+    last_good: Mutex<Option<IpAddr>>,
+    /// another network may get other answers
+    epoch: NetworkEpoch,
+}
+
+impl ServerAddr {
+    pub fn new(host: &str, port: u16, strategy: Option<ResolveStrategy>) -> Self {
+        Self {
+            host: host.to_owned(),
+            port,
+            strategy,
+            dial_timeout: None,
+            socket_buffers: Default::default(),
+            cached: Mutex::new(None),
+            last_good: Mutex::new(None),
+            epoch: Default::default(),
+        }
+    }
+
+    /// the proxy's own `dial-timeout`
+    pub fn with_dial_timeout(mut self, dial_timeout: Option<Duration>) -> Self {
+        self.dial_timeout = dial_timeout;
+        self
+    }
+
+    /// the proxy's own socket buffer sizes
+    pub fn with_socket_buffers(mut self, socket_buffers: SocketBuffers) -> Self {
+        self.socket_buffers = socket_buffers;
+        self
+    }
+
+    pub fn socks_addr(&self) -> Option<SocksAddr> {
+        SocksAddr::try_from((self.host.clone(), self.port)).ok()
+    }
+
+    #[cfg_attr(
+        not(any(feature = "shadowsocks", feature = "tuic")),
+        allow(dead_code)
+    )]
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    #[cfg_attr(not(feature = "shadowsocks"), allow(dead_code))]
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// looks the server up into the cache, None if it's an IP
+    pub async fn prefetch(
+        &self,
+        resolver: &ThreadSafeDNSResolver,
+    ) -> Option<io::Result<()>> {
+        if self.host.parse::<IpAddr>().is_ok() {
+            return None;
+        }
+        Some(self.resolve(resolver, false).await.map(|_| ()))
+    }
+
+    /// The address a datagram protocol sends to, for those that don't dial
+    /// through [`Self::connect_stream`]: the last good one if it's still an
+    /// answer, otherwise any of them.
+    #[cfg_attr(
+        not(any(feature = "quic-protocols", feature = "wireguard")),
+        allow(dead_code)
+    )]
+    pub async fn socket_addr(
+        &self,
+        resolver: &ThreadSafeDNSResolver,
+    ) -> io::Result<SocketAddr> {
+        if let Ok(ip) = self.host.parse::<IpAddr>() {
+            return Ok((ip, self.port).into());
+        }
+        let ips = self.attempts(self.resolve(resolver, false).await?);
+        ips.first()
+            .map(|ip| (*ip, self.port).into())
+            .ok_or_else(|| {
+                DialError::Dns.error(format!("no address for {}", self.host))
+            })
+    }
+
+    /// v6 or not, in the order they are tried
+    fn families(&self, resolver: &ThreadSafeDNSResolver) -> &'static [bool] {
+        match (self.strategy, resolver.ipv6()) {
+            (Some(ResolveStrategy::OnlyV4), _) | (None, false) => &[false],
+            (Some(ResolveStrategy::OnlyV6), _) => &[true],
+            (Some(ResolveStrategy::PreferV6), true) => &[true, false],
+            (Some(ResolveStrategy::PreferV4), true) | (None, true) => &[false, true],
+            (_, false) => &[false],
+        }
+    }
+
+    /// the addresses of the first family that has any, shuffled so the
+    /// servers behind a round-robin name share the load
+    async fn resolve(
+        &self,
+        resolver: &ThreadSafeDNSResolver,
+        fresh: bool,
+    ) -> io::Result<Vec<IpAddr>> {
+        let stale = self.epoch.changed();
+        if !fresh && !stale {
+            if let Some((ips, until)) = self.cached.lock().unwrap().as_ref() {
+                if Instant::now() < *until {
+                    return Ok(ips.clone());
+                }
+            }
+        }
+
+        let mut last_err = None;
+        for v6 in self.families(resolver) {
+            match resolver.lookup_addrs(&self.host, *v6, fresh || stale).await {
+                Ok((mut ips, ttl)) if !ips.is_empty() => {
+                    ips.shuffle(&mut rand::thread_rng());
+                    let until = Instant::now() + ttl.min(MAX_TTL);
+                    *self.cached.lock().unwrap() = Some((ips.clone(), until));
+                    return Ok(ips);
+                }
+                Ok(_) => {}
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(DialError::Dns.error(format!(
+            "can't resolve {}: {}",
+            self.host,
+            last_err
+                .map(|e| e.to_string())
+                .unwrap_or("no address".to_owned())
+        )))
+    }
+
+    fn dial_timeout(&self) -> Duration {
+        self.dial_timeout.unwrap_or_else(connect_timeout)
+    }
+
+    /// `ips` in the order they are dialed, the last good one first
+    fn attempts(&self, mut ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        if let Some(good) = *self.last_good.lock().unwrap() {
+            if let Some(i) = ips.iter().position(|x| *x == good) {
+                ips[..=i].rotate_right(1);
+            }
+        }
+        ips.truncate(MAX_ATTEMPTS);
+        ips
+    }
+
+    /// Dials `ips` one after the other until one connects, any but the last
+    /// given [`ATTEMPT_TIMEOUT`] at most.
+    async fn dial_each<F, Fut>(
+        &self,
+        ips: &[IpAddr],
+        dial: F,
+    ) -> io::Result<AnyStream>
+    where
+        F: Fn(IpAddr) -> Fut,
+        Fut: Future<Output = io::Result<TcpStream>>,
+    {
+        let mut last_err = None;
+        for (i, ip) in ips.iter().enumerate() {
+            let rv = if i + 1 < ips.len() {
+                timeout(ATTEMPT_TIMEOUT.min(self.dial_timeout()), dial(*ip))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+                    })
+            } else {
+                dial(*ip).await
+            };
+            match rv {
+                Ok(s) => {
+                    *self.last_good.lock().unwrap() = Some(*ip);
+                    return Ok(Box::new(s));
+                }
+                Err(e) => {
+                    debug!("dialing {} at {} failed: {}", self.host, ip, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        let e = last_err.unwrap_or_else(|| new_io_error("no address to dial"));
+        if ips.len() < 2 {
+            return Err(e);
+        }
+        Err(io::Error::new(
+            e.kind(),
+            format!(
+                "tried {} addresses of {}, last error: {}",
+                ips.len(),
+                self.host,
+                e
+            ),
+        ))
+    }
+
+    /// Dials the server through `connector`. Only a direct dial resolves it,
+    /// with `resolver`, behind another proxy the name is left to the far end.
+    /// A direct dial takes the proxy's dial timeout and socket buffers.
+    /// A name with several addresses has them tried in turn, and if none
+    /// connects it's looked up again and the new ones are dialed, in case
+    /// the server moved to another address.
+    pub async fn connect_stream(
+        &self,
+        connector: &dyn RemoteConnector,
+        resolver: ThreadSafeDNSResolver,
+        iface: Option<&Interface>,
+        #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
+    ) -> io::Result<AnyStream> {
+        if !connector.dials_directly() {
+            return connector
+                .connect_stream(
+                    resolver,
+                    &self.host,
+                    self.port,
+                    iface,
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    so_mark,
+                )
+                .await;
+        }
+
+        let dial = |ip: IpAddr| {
+            new_tcp_stream_with_timeout(
+                (ip, self.port).into(),
+                self.dial_timeout(),
+                self.socket_buffers,
+                iface.cloned(),
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                so_mark,
+            )
+        };
+
+        if let Ok(ip) = self.host.parse::<IpAddr>() {
+            return dial(ip).await.map(|x| Box::new(x) as _);
+        }
+        self.dial_resolved(&resolver, dial).await
+    }
+
+    /// A socket to send datagrams to `destination`, e.g. the server or the
+    /// relay it gave, through `connector`. A direct one takes the proxy's
+    /// socket buffers.
+    pub async fn connect_datagram(
+        &self,
+        connector: &dyn RemoteConnector,
+        resolver: ThreadSafeDNSResolver,
+        destination: SocksAddr,
+        iface: Option<Interface>,
+        #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
+    ) -> io::Result<AnyOutboundDatagram> {
+        if !connector.dials_directly() {
+            return connector
+                .connect_datagram(
+                    resolver,
+                    None,
+                    destination,
+                    iface,
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    so_mark,
+                )
+                .await;
+        }
+
+        let socket = new_udp_socket_with_buffers(
+            None,
+            self.socket_buffers,
+            iface,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            so_mark,
+        )
+        .await?;
+        Ok(Box::new(ChainedDatagramWrapper::new(
+            OutboundDatagramImpl::new(socket, resolver),
+        )))
+    }
+
+    /// Dials the addresses of the name, and its new ones if none of them
+    /// connects.
+    async fn dial_resolved<F, Fut>(
+        &self,
+        resolver: &ThreadSafeDNSResolver,
+        dial: F,
+    ) -> io::Result<AnyStream>
+    where
+        F: Fn(IpAddr) -> Fut,
+        Fut: Future<Output = io::Result<TcpStream>>,
+    {
+        let tried = self.attempts(self.resolve(resolver, false).await?);
+        let err = match self.dial_each(&tried, &dial).await {
+            Ok(s) => return Ok(s),
+            Err(e) => e,
+        };
+        let fresh = self
+            .resolve(resolver, true)
+            .await?
+            .into_iter()
+            .filter(|x| !tried.contains(x))
+            .collect::<Vec<_>>();
+        if fresh.is_empty() {
+            return Err(err);
+        }
+        debug!(
+            "dialing {} failed: {}, trying its new addresses {:?}",
+            self.host, err, fresh
+        );
+        self.dial_each(&self.attempts(fresh), &dial).await
+    }
+}
+
+impl std::fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{
+        app::dns::{MockClashResolver, ThreadSafeDNSResolver},
+        config::internal::proxy::ResolveStrategy,
+    };
+
+    use super::ServerAddr;
+
+    /// the servers are documentation addresses, each stands for a port on
+    /// 127.0.0.1 given by its last byte, 0 for one refusing connections. No
+    /// other loopback address is used, they aren't there on every system.
+    fn dial_local(
+        ports: [u16; 4],
+    ) -> impl Fn(IpAddr) -> futures::future::BoxFuture<'static, std::io::Result<TcpStream>>
+    {
+        move |ip| {
+            let IpAddr::V4(ip) = ip else { unreachable!() };
+            match ports[ip.octets()[3] as usize] {
+                // not a real port, one that's free now may be taken by
+                // another test by the time it's dialed
+                0 => Box::pin(futures::future::ready(Err(
+                    std::io::ErrorKind::ConnectionRefused.into(),
+                ))),
+                port => Box::pin(TcpStream::connect(("127.0.0.1", port))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redial_after_server_moved() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dial = dial_local([0, port, 0, 0]);
+
+        // first the stale address, nothing listens on it
+        let answers = Arc::new(Mutex::new(vec![
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+            "192.0.2.2".parse().unwrap(),
+        ]));
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_ipv6().return_const(true);
+        resolver
+            .expect_lookup_addrs()
+            .withf(|host, v6, _| host == "proxy.example" && !*v6)
+            .times(2)
+            .returning(move |_, _, _| {
+                Ok((
+                    vec![answers.lock().unwrap().pop().unwrap()],
+                    Duration::from_secs(60),
+                ))
+            });
+        let resolver: ThreadSafeDNSResolver = Arc::new(resolver);
+
+        let server =
+            ServerAddr::new("proxy.example", 443, Some(ResolveStrategy::OnlyV4));
+        server.dial_resolved(&resolver, &dial).await.unwrap();
+        // the new address is kept, no more lookups
+        server.dial_resolved(&resolver, &dial).await.unwrap();
+        assert_eq!(
+            server.socket_addr(&resolver).await.unwrap(),
+            "192.0.2.1:443".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dial_alternate_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dial = dial_local([0, port, 0, 0]);
+
+        // only one of them listens
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_ipv6().return_const(false);
+        resolver
+            .expect_lookup_addrs()
+            .times(1)
+            .returning(move |_, _, _| {
+                Ok((
+                    ["192.0.2.2", "192.0.2.1", "192.0.2.3"]
+                        .map(|x| x.parse().unwrap())
+                        .to_vec(),
+                    Duration::from_secs(60),
+                ))
+            });
+        let resolver: ThreadSafeDNSResolver = Arc::new(resolver);
+
+        let server = ServerAddr::new("proxy.example", 443, None);
+        for _ in 0..2 {
+            server.dial_resolved(&resolver, &dial).await.unwrap();
+        }
+        assert_eq!(
+            *server.last_good.lock().unwrap(),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            server.attempts(server.cached.lock().unwrap().clone().unwrap().0)[0],
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+
+        // none of them does
+        drop(listener);
+        let dial = dial_local([0; 4]);
+        let resolver: ThreadSafeDNSResolver = Arc::new({
+            let mut resolver = MockClashResolver::new();
+            resolver.expect_ipv6().return_const(false);
+            resolver.expect_lookup_addrs().returning(move |_, _, _| {
+                Ok((
+                    ["192.0.2.1", "192.0.2.2", "192.0.2.3"]
+                        .map(|x| x.parse().unwrap())
+                        .to_vec(),
+                    Duration::from_secs(60),
+                ))
+            });
+            resolver
+        });
+        let e = server.dial_resolved(&resolver, &dial).await.err().unwrap();
+        assert!(
+            e.to_string()
+                .starts_with("tried 3 addresses of proxy.example, last error:"),
+            "{}",
+            e
+        );
+    }
+}
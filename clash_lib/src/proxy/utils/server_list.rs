@@ -0,0 +1,280 @@
+//! The servers of a proxy given a list of servers or ports, for providers
+//! that keep changing which of them are open. The one that connected last
+//! is dialed first, and the next ones when it fails. Which one that is goes
+//! in the cache, so it's not looked for again after a restart.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use tracing::{debug, info};
+
+use crate::{
+    app::{dns::ThreadSafeDNSResolver, profile::ThreadSafeCacheFile},
+    config::internal::proxy::ResolveStrategy,
+    proxy::AnyStream,
+    session::SocksAddr,
+};
+
+use super::{Interface, RemoteConnector, ServerAddr, SocketBuffers};
+
+/// the cache section of the servers in use, `server:port` by proxy name
+const CACHE_SECTION: &str = "servers";
+
+/// how many of the servers a dial tries, the next dial goes on from there
+const MAX_ATTEMPTS: usize = 4;
+
+/// one update of the cache section at a time, so none is lost
+static PERSIST: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+pub struct ServerList {
+    /// of the proxy, the cache goes by it
+    name: String,
+    servers: Vec<ServerAddr>,
+    /// the one dialed first
+    current: AtomicUsize,
+    restored: tokio::sync::OnceCell<()>,
+    /// where the server in use is kept, the cache of the running config
+    cache: RwLock<Option<ThreadSafeCacheFile>>,
+}
+
+impl ServerList {
+    /// `servers` are tried in that order, the first one to begin with
+    pub fn new(
+        name: &str,
+        servers: Vec<(String, u16)>,
+        strategy: Option<ResolveStrategy>,
+        dial_timeout: Option<Duration>,
+        socket_buffers: SocketBuffers,
+    ) -> Self {
+        assert!(!servers.is_empty(), "no server for {}", name);
+        Self {
+            name: name.to_owned(),
+            servers: servers
+                .into_iter()
+                .map(|(host, port)| {
+                    ServerAddr::new(&host, port, strategy)
+                        .with_dial_timeout(dial_timeout)
+                        .with_socket_buffers(socket_buffers)
+                })
+                .collect(),
+            current: AtomicUsize::new(0),
+            restored: Default::default(),
+            cache: Default::default(),
+        }
+    }
+
+    /// the cache the server in use is kept in, the new one on a reload
+    pub fn set_cache(&self, cache: ThreadSafeCacheFile) {
+        *self.cache.write().unwrap() = Some(cache);
+    }
+
+    fn cache(&self) -> Option<ThreadSafeCacheFile> {
+        self.cache.read().unwrap().clone()
+    }
+
+    /// the server dialed first
+    pub fn current(&self) -> &ServerAddr {
+        &self.servers[self.current.load(Ordering::Relaxed)]
+    }
+
+    pub fn socks_addr(&self) -> Option<SocksAddr> {
+        self.current().socks_addr()
+    }
+
+    /// the server dialed first is the one worth looking up ahead
+    pub async fn prefetch(
+        &self,
+        resolver: &ThreadSafeDNSResolver,
+    ) -> Option<io::Result<()>> {
+        self.restore().await;
+        self.current().prefetch(resolver).await
+    }
+
+    /// the server in use for the API, None when there's just the one
+    pub fn active(&self) -> Option<String> {
+        (self.servers.len() > 1).then(|| self.current().to_string())
+    }
+
+    /// takes the server that was in use from the cache, once
+    async fn restore(&self) {
+        if self.servers.len() < 2 {
+            return;
+        }
+        self.restored
+            .get_or_init(|| async {
+                let Some(cache) = self.cache() else {
+                    return;
+                };
+                let saved = cache
+                    .get::<HashMap<String, String>>(CACHE_SECTION)
+                    .await
+                    .and_then(|mut x| x.remove(&self.name));
+                if let Some(i) = saved.and_then(|saved| {
+                    self.servers.iter().position(|x| x.to_string() == saved)
+                }) {
+                    self.current.store(i, Ordering::Relaxed);
+                }
+            })
+            .await;
+    }
+
+    async fn switch(&self, i: usize) {
+        self.current.store(i, Ordering::Relaxed);
+        let Some(cache) = self.cache() else {
+            return;
+        };
+        let _g = PERSIST.lock().await;
+        let mut saved = cache
+            .get::<HashMap<String, String>>(CACHE_SECTION)
+            .await
+            .unwrap_or_default();
+        saved.insert(self.name.clone(), self.servers[i].to_string());
+        cache.set(CACHE_SECTION, &saved).await;
+    }
+
+    /// Dials the current server, then the ones after it until one connects,
+    /// which is the current one from then on. If none of those does, the
+    /// next dial starts after them.
+    pub async fn connect_stream(
+        &self,
+        connector: &dyn RemoteConnector,
+        resolver: ThreadSafeDNSResolver,
+        iface: Option<&Interface>,
+        #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
+    ) -> io::Result<AnyStream> {
+        self.restore().await;
+
+        let start = self.current.load(Ordering::Relaxed);
+        let attempts = self.servers.len().min(MAX_ATTEMPTS);
+        let mut last_err = None;
+        for i in (start..start + attempts).map(|x| x % self.servers.len()) {
+            let server = &self.servers[i];
+            match server
+                .connect_stream(
+                    connector,
+                    resolver.clone(),
+                    iface,
+                    #[cfg(any(target_os = "linux", target_os = "android"))]
+                    so_mark,
+                )
+                .await
+            {
+                Ok(s) => {
+                    if i != start {
+                        info!("{} switched to server {}", self.name, server);
+                        self.switch(i).await;
+                    }
+                    return Ok(s);
+                }
+                Err(e) => {
+                    if self.servers.len() > 1 {
+                        debug!("{}: dialing {} failed: {}", self.name, server, e);
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        let e = last_err.expect("at least one server");
+        if attempts < 2 {
+            return Err(e);
+        }
+        let _ = self.current.compare_exchange(
+            start,
+            (start + attempts) % self.servers.len(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        Err(io::Error::new(
+            e.kind(),
+            format!(
+                "tried {} servers of {}, last error: {}",
+                attempts, self.name, e
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::{
+        app::profile::ThreadSafeCacheFile, proxy::utils::GLOBAL_DIRECT_CONNECTOR,
+        test_support::resolver,
+    };
+
+    use super::ServerList;
+
+    #[tokio::test]
+    async fn test_rotate_and_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ThreadSafeCacheFile::new(
+            dir.path().join("cache.db").to_str().unwrap(),
+            true,
+        );
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let open = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = open.local_addr().unwrap().port();
+
+        let servers = vec![
+            ("127.0.0.1".to_owned(), closed_port),
+            ("127.0.0.1".to_owned(), open_port),
+        ];
+        let list = ServerList::new(
+            "multi",
+            servers.clone(),
+            None,
+            None,
+            Default::default(),
+        );
+        list.set_cache(cache.clone());
+        assert_eq!(list.active(), Some(format!("127.0.0.1:{}", closed_port)));
+        list.connect_stream(
+            GLOBAL_DIRECT_CONNECTOR.as_ref(),
+            resolver(),
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(list.active(), Some(format!("127.0.0.1:{}", open_port)));
+
+        // picked up by the same proxy after a restart
+        let list = ServerList::new("multi", servers, None, None, Default::default());
+        list.set_cache(cache);
+        list.restore().await;
+        assert_eq!(list.active(), Some(format!("127.0.0.1:{}", open_port)));
+
+        // a single server is never switched
+        let one = ServerList::new(
+            "one",
+            vec![("127.0.0.1".to_owned(), closed_port)],
+            None,
+            None,
+            Default::default(),
+        );
+        assert_eq!(one.active(), None);
+        assert!(one
+            .connect_stream(
+                GLOBAL_DIRECT_CONNECTOR.as_ref(),
+                resolver(),
+                None,
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                None,
+            )
+            .await
+            .is_err());
+    }
+}
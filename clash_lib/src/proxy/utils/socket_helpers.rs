@@ -1,19 +1,156 @@
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
 
 use socket2::TcpKeepalive;
 use tokio::{
-    net::{TcpSocket, TcpStream, UdpSocket},
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
     time::timeout,
 };
 
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
+
+use super::{platform::must_bind_socket_on_interface, DialError, Interface};
+
+/// Called with each outbound socket before it's bound or connected, false
+/// fails the dial. On Android this is where `VpnService.protect` goes, or
+/// the socket's traffic would come right back into the VPN.
+#[cfg(unix)]
+pub type SocketProtector =
+    std::sync::Arc<dyn Fn(std::os::fd::RawFd) -> bool + Send + Sync>;
+
+#[cfg(unix)]
+static SOCKET_PROTECTOR: std::sync::RwLock<Option<SocketProtector>> =
+    std::sync::RwLock::new(None);
+
+/// `connect-timeout`, in millis
+static CONNECT_TIMEOUT: AtomicU64 = AtomicU64::new(10_000);
+
+/// how long [`new_tcp_stream`] waits for the connection, from
+/// `connect-timeout`
+pub fn set_connect_timeout(timeout: Duration) {
+    CONNECT_TIMEOUT.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn connect_timeout() -> Duration {
+    Duration::from_millis(CONNECT_TIMEOUT.load(Ordering::Relaxed))
+}
+
+/// the smallest and largest buffer sizes that are set, what's asked beyond
+/// those is clamped to them
+const MIN_BUFFER_SIZE: u32 = 4 * 1024;
+const MAX_BUFFER_SIZE: u32 = 64 * 1024 * 1024;
+
+/// `socket-send-buffer-size` and `socket-recv-buffer-size`
+static SOCKET_BUFFERS: RwLock<SocketBuffers> = RwLock::new(SocketBuffers {
+    send: None,
+    recv: None,
+});
+
+/// The sizes of the kernel buffers of a socket in bytes, None for the OS
+/// default. Bigger ones let a single connection go faster over a long path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketBuffers {
+    pub send: Option<u32>,
+    pub recv: Option<u32>,
+}
+
+impl SocketBuffers {
+    /// 0 is the OS default, the other sizes are clamped to 4 KiB - 64 MiB
+    pub fn new(send: Option<u32>, recv: Option<u32>) -> Self {
+        let clamp = |name: &str, size: Option<u32>| match size {
+            Some(0) | None => size,
+            Some(size) => {
+                let clamped = size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE);
+                if clamped != size {
+                    warn!(
+                        "socket {} buffer size {} clamped to {}",
+                        name, size, clamped
+                    );
+                }
+                Some(clamped)
+            }
+        };
+        Self {
+            send: clamp("send", send),
+            recv: clamp("recv", recv),
+        }
+    }
+
+    /// the global ones, from the config
+    pub fn global() -> Self {
+        *SOCKET_BUFFERS.read().unwrap()
+    }
 
-use super::{platform::must_bind_socket_on_interface, Interface};
+    /// these, the global ones where they aren't set
+    pub fn or_global(self) -> Self {
+        let global = Self::global();
+        Self {
+            send: self.send.or(global.send),
+            recv: self.recv.or(global.recv),
+        }
+    }
+
+    pub fn apply(&self, socket: &socket2::Socket) -> io::Result<()> {
+        if let Some(size) = self.send.filter(|x| *x > 0) {
+            socket.set_send_buffer_size(size as usize)?;
+            debug!("socket send buffer size set to {}", size);
+        }
+        if let Some(size) = self.recv.filter(|x| *x > 0) {
+            socket.set_recv_buffer_size(size as usize)?;
+            debug!("socket recv buffer size set to {}", size);
+        }
+        Ok(())
+    }
+}
+
+/// the buffer sizes of the sockets created or accepted from now on, those
+/// of the proxies that set their own aside
+pub fn set_socket_buffers(buffers: SocketBuffers) {
+    *SOCKET_BUFFERS.write().unwrap() = buffers;
+}
+
+/// the protector said no, as the source of an `io::Error`
+#[derive(thiserror::Error, Debug)]
+#[error("socket protection failed")]
+pub struct SocketProtectError;
+
+/// Sets, or with None clears, the protector of the sockets created from
+/// now on.
+#[cfg(unix)]
+pub fn set_socket_protector(protector: Option<SocketProtector>) {
+    *SOCKET_PROTECTOR.write().unwrap() = protector;
+}
+
+#[cfg(unix)]
+pub fn protect_socket(socket: &impl std::os::fd::AsRawFd) -> io::Result<()> {
+    // not holding the lock while the callback runs
+    let protector = SOCKET_PROTECTOR.read().unwrap().clone();
+    match protector {
+        Some(protect) if !protect(socket.as_raw_fd()) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            SocketProtectError,
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn protect_socket<T>(_: &T) -> io::Result<()> {
+    Ok(())
+}
 
 pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     #[cfg(not(target_os = "windows"))]
     {
         let s = socket2::Socket::from(s.into_std()?);
+        SocketBuffers::global().apply(&s)?;
         s.set_tcp_keepalive(
             &TcpKeepalive::new()
                 .with_time(Duration::from_secs(10))
@@ -25,6 +162,7 @@ pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     #[cfg(target_os = "windows")]
     {
         let s = socket2::Socket::from(s.into_std()?);
+        SocketBuffers::global().apply(&s)?;
         s.set_tcp_keepalive(
             &TcpKeepalive::new()
                 .with_time(Duration::from_secs(10))
@@ -34,10 +172,70 @@ pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
     }
 }
 
+/// A listener on `addr`, taking IPv4 too if it's `[::]` where the system lets
+/// an IPv6 socket do so, or on `0.0.0.0` if there is no IPv6 at all.
+pub fn new_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let dual_stack = addr.is_ipv6() && addr.ip().is_unspecified();
+    let socket = match socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    ) {
+        Ok(socket) => socket,
+        Err(e) if dual_stack => {
+            debug!("no IPv6 for {}, listening on IPv4 only: {}", addr, e);
+            return new_tcp_listener((Ipv4Addr::UNSPECIFIED, addr.port()).into());
+        }
+        Err(e) => return Err(e),
+    };
+
+    if dual_stack {
+        if let Err(e) = socket.set_only_v6(false) {
+            warn!("{} only takes IPv6 clients: {}", addr, e);
+        }
+    }
+    // the accepted ones take these, and the window scale they allow
+    SocketBuffers::global().apply(&socket)?;
+    // what tokio's bind does
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// `addr` with an IPv4-mapped address, as a dual-stack listener sees the
+/// IPv4 clients, turned back into IPv4, so the rules on IPv4 CIDRs match
+pub fn unmapped(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
 pub async fn new_tcp_stream(
     endpoint: SocketAddr,
     iface: Option<Interface>,
     #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
+) -> io::Result<TcpStream> {
+    new_tcp_stream_with_timeout(
+        endpoint,
+        connect_timeout(),
+        SocketBuffers::default(),
+        iface,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        so_mark,
+    )
+    .await
+}
+
+/// [`new_tcp_stream`] with a timeout other than `connect-timeout`, e.g. the
+/// `dial-timeout` of a proxy, and its buffer sizes
+pub async fn new_tcp_stream_with_timeout(
+    endpoint: SocketAddr,
+    dial_timeout: Duration,
+    buffers: SocketBuffers,
+    iface: Option<Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
 ) -> io::Result<TcpStream> {
     let (socket, family) = match endpoint {
         SocketAddr::V4(_) => (
@@ -58,6 +256,8 @@ pub async fn new_tcp_stream(
         ),
     };
 
+    protect_socket(&socket)?;
+
     if let Some(iface) = iface {
         debug!("binding tcp socket to interface: {:?}", iface);
         must_bind_socket_on_interface(&socket, &iface, family)?;
@@ -68,15 +268,27 @@ pub async fn new_tcp_stream(
         socket.set_mark(so_mark)?;
     }
 
+    // before connecting, the window scale is agreed on then
+    buffers.or_global().apply(&socket)?;
     socket.set_keepalive(true)?;
     socket.set_nodelay(true)?;
     socket.set_nonblocking(true)?;
 
     timeout(
-        Duration::from_secs(10),
+        dial_timeout,
         TcpSocket::from_std_stream(socket.into()).connect(endpoint),
     )
-    .await?
+    .await
+    .map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "connecting to {} timed out after {:?}",
+                endpoint, dial_timeout
+            ),
+        )
+    })?
+    .map_err(|e| DialError::Connect.tag(e))
 }
 
 pub async fn new_udp_socket(
@@ -84,6 +296,54 @@ pub async fn new_udp_socket(
     iface: Option<Interface>,
     #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
 ) -> io::Result<UdpSocket> {
+    new_udp_socket_with_buffers(
+        src,
+        SocketBuffers::default(),
+        iface,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        so_mark,
+    )
+    .await
+}
+
+/// [`new_udp_socket`] with the buffer sizes of a proxy
+pub async fn new_udp_socket_with_buffers(
+    src: Option<SocketAddr>,
+    buffers: SocketBuffers,
+    iface: Option<Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
+) -> io::Result<UdpSocket> {
+    UdpSocket::from_std(std_udp_socket(
+        src,
+        buffers,
+        iface,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        so_mark,
+    )?)
+}
+
+/// [`new_udp_socket`] for the places without a tokio socket, e.g. quinn's
+/// abstract sockets. It's non-blocking all the same.
+pub fn new_std_udp_socket(
+    src: Option<SocketAddr>,
+    iface: Option<Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
+) -> io::Result<std::net::UdpSocket> {
+    std_udp_socket(
+        src,
+        SocketBuffers::default(),
+        iface,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        so_mark,
+    )
+}
+
+fn std_udp_socket(
+    src: Option<SocketAddr>,
+    buffers: SocketBuffers,
+    iface: Option<Interface>,
+    #[cfg(any(target_os = "linux", target_os = "android"))] so_mark: Option<u32>,
+) -> io::Result<std::net::UdpSocket> {
     let (socket, family) = match src {
         Some(src) => {
             if src.is_ipv4() {
@@ -112,6 +372,8 @@ pub async fn new_udp_socket(
         ),
     };
 
+    protect_socket(&socket)?;
+
     match (src, iface) {
         (Some(_), Some(iface)) => {
             debug!("both src and iface are set, iface will be used: {:?}", src);
@@ -143,8 +405,100 @@ pub async fn new_udp_socket(
         socket.set_mark(so_mark)?;
     }
 
+    buffers.or_global().apply(&socket)?;
     socket.set_broadcast(true)?;
     socket.set_nonblocking(true)?;
 
-    UdpSocket::from_std(socket.into())
+    Ok(socket.into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{net::IpAddr, sync::Arc};
+
+    use tokio::net::TcpStream;
+
+    use super::{
+        new_tcp_listener, new_udp_socket, set_socket_protector, unmapped,
+        SocketBuffers, SocketProtectError,
+    };
+
+    #[test]
+    fn test_buffer_sizes_clamped() {
+        let b = SocketBuffers::new(Some(1), Some(u32::MAX));
+        assert_eq!(b.send, Some(4 * 1024));
+        assert_eq!(b.recv, Some(64 * 1024 * 1024));
+        let b = SocketBuffers::new(Some(0), None);
+        assert_eq!(
+            b,
+            SocketBuffers {
+                send: Some(0),
+                recv: None
+            }
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_buffer_sizes_applied() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let s = super::new_tcp_stream_with_timeout(
+            listener.local_addr().unwrap(),
+            std::time::Duration::from_secs(5),
+            SocketBuffers::new(Some(64 * 1024), Some(96 * 1024)),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // linux doubles what's asked, for its own bookkeeping
+        let s = socket2::SockRef::from(&s);
+        assert_eq!(s.send_buffer_size().unwrap(), 2 * 64 * 1024);
+        assert_eq!(s.recv_buffer_size().unwrap(), 2 * 96 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_protector_fails_the_socket() {
+        // the other tests run on threads of their own, so only refuse here
+        let me = std::thread::current().id();
+        set_socket_protector(Some(Arc::new(move |_| {
+            std::thread::current().id() != me
+        })));
+
+        let e = new_udp_socket(
+            None,
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .unwrap_err();
+        set_socket_protector(None);
+
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(e.get_ref().unwrap().is::<SocketProtectError>());
+
+        assert!(new_udp_socket(
+            None,
+            None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            None,
+        )
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listener() {
+        let listener = new_tcp_listener("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for ip in ["127.0.0.1", "::1"] {
+            let ip = ip.parse::<IpAddr>().unwrap();
+            let _client = TcpStream::connect((ip, port)).await.unwrap();
+            let (_, src) = listener.accept().await.unwrap();
+            assert_eq!(unmapped(src).ip(), ip);
+        }
+    }
 }
@@ -47,10 +47,13 @@ pub async fn build_dns_resolver() -> anyhow::Result<Arc<dyn ClashResolver>> {
         config.profile.store_selected,
     );
 
-    let dns_resolver = Arc::new(
-        dns::EnhancedResolver::new(config.dns, cache_store.clone(), mmdb.clone())
-            .await,
-    );
+    let dns_resolver = dns::EnhancedResolver::new(
+        config.dns,
+        cache_store.clone(),
+        mmdb.clone(),
+        None,
+    )
+    .await;
 
     Ok(dns_resolver)
 }
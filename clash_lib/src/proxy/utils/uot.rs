@@ -0,0 +1,287 @@
+//! UDP over a stream the way sing-box's `udp-over-tcp` v2 carries it, for the
+//! hops of a relay that only take streams. The server at the other end of
+//! the hop has to speak it, as the sing-box and mihomo ones do.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::debug;
+
+use crate::{
+    proxy::{datagram::UdpPacket, AnyStream},
+    session::SocksAddr,
+};
+
+/// the stream carrying the packets is opened to this
+const MAGIC_ADDRESS: &str = "sp.v2.udp-over-tcp.arpa";
+
+/// what the stream of a hop is opened to, for it to carry UDP
+pub fn uot_destination() -> SocksAddr {
+    SocksAddr::Domain(MAGIC_ADDRESS.to_owned(), 0)
+}
+
+/// The packets over a stream opened to [`uot_destination`], each with its
+/// own address, so one stream serves all the destinations of a session.
+pub struct UotDatagram {
+    inner: Framed<AnyStream, UotCodec>,
+}
+
+impl UotDatagram {
+    /// sends the request, `destination` is the one of the session
+    pub async fn new(mut s: AnyStream, destination: &SocksAddr) -> io::Result<Self> {
+        if matches!(destination, SocksAddr::Domain(d, _) if d.len() > u8::MAX as usize)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "domain too long",
+            ));
+        }
+        let mut buf = BytesMut::new();
+        // not connected, the packets carry their addresses. the request has
+        // it the SOCKS way, unlike the packets
+        buf.put_u8(0);
+        destination.write_buf(&mut buf);
+        s.write_all(&buf).await?;
+        Ok(Self {
+            inner: Framed::new(s, UotCodec),
+        })
+    }
+}
+
+impl Sink<UdpPacket> for UotDatagram {
+    type Error = io::Error;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPacket) -> io::Result<()> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl Stream for UotDatagram {
+    type Item = UdpPacket;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        match ready!(self.inner.poll_next_unpin(cx)) {
+            Some(Ok(pkt)) => Poll::Ready(Some(pkt)),
+            Some(Err(e)) => {
+                debug!("failed to read udp-over-tcp packet: {}", e);
+                Poll::Ready(None)
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// the address, a u16 length and the payload
+struct UotCodec;
+
+impl Encoder<UdpPacket> for UotCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: UdpPacket, dst: &mut BytesMut) -> io::Result<()> {
+        if item.data.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp packet too large",
+            ));
+        }
+        write_addr(&item.dst_addr, dst)?;
+        dst.put_u16(item.data.len() as u16);
+        dst.put_slice(&item.data);
+        Ok(())
+    }
+}
+
+impl Decoder for UotCodec {
+    type Error = io::Error;
+    type Item = UdpPacket;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<UdpPacket>> {
+        let Some((addr, n)) = read_addr(src)? else {
+            return Ok(None);
+        };
+        if src.len() < n + 2 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([src[n], src[n + 1]]) as usize;
+        if src.len() < n + 2 + len {
+            src.reserve(n + 2 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(n + 2);
+        let data = src.split_to(len).to_vec();
+        Ok(Some(UdpPacket {
+            data,
+            src_addr: addr,
+            dst_addr: SocksAddr::any_ipv4(),
+        }))
+    }
+}
+
+/// sing-box's address family bytes, unlike SOCKS
+fn write_addr(addr: &SocksAddr, buf: &mut BytesMut) -> io::Result<()> {
+    match addr {
+        SocksAddr::Ip(SocketAddr::V4(a)) => {
+            buf.put_u8(0x00);
+            buf.put_slice(&a.ip().octets());
+            buf.put_u16(a.port());
+        }
+        SocksAddr::Ip(SocketAddr::V6(a)) => {
+            buf.put_u8(0x01);
+            buf.put_slice(&a.ip().octets());
+            buf.put_u16(a.port());
+        }
+        SocksAddr::Domain(d, port) => {
+            let len = u8::try_from(d.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "domain too long")
+            })?;
+            buf.put_u8(0x02);
+            buf.put_u8(len);
+            buf.put_slice(d.as_bytes());
+            buf.put_u16(*port);
+        }
+    }
+    Ok(())
+}
+
+/// the address at the start of `buf` and its size, None until it's all there
+fn read_addr(buf: &[u8]) -> io::Result<Option<(SocksAddr, usize)>> {
+    let Some(family) = buf.first() else {
+        return Ok(None);
+    };
+    let port = |at: usize| u16::from_be_bytes([buf[at], buf[at + 1]]);
+    match family {
+        0x00 if buf.len() >= 7 => {
+            let ip = Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]);
+            Ok(Some((SocksAddr::Ip((ip, port(5)).into()), 7)))
+        }
+        0x01 if buf.len() >= 19 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&buf[1..17]).unwrap());
+            Ok(Some((SocksAddr::Ip((ip, port(17)).into()), 19)))
+        }
+        0x02 if buf.len() >= 2 && buf.len() >= 4 + buf[1] as usize => {
+            let len = buf[1] as usize;
+            let domain =
+                String::from_utf8(buf[2..2 + len].to_vec()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid domain")
+                })?;
+            Ok(Some((SocksAddr::Domain(domain, port(2 + len)), 4 + len)))
+        }
+        0x00..=0x02 => Ok(None),
+        x => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown address family {}", x),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::{proxy::datagram::UdpPacket, session::SocksAddr};
+
+    use super::{UotCodec, UotDatagram};
+
+    fn packet(dst: SocksAddr, data: &[u8]) -> UdpPacket {
+        UdpPacket {
+            data: data.to_vec(),
+            src_addr: SocksAddr::any_ipv4(),
+            dst_addr: dst,
+        }
+    }
+
+    #[test]
+    fn test_codec() {
+        let addrs = [
+            SocksAddr::Ip("1.2.3.4:53".parse().unwrap()),
+            SocksAddr::Ip("[2001:db8::1]:443".parse().unwrap()),
+            SocksAddr::try_from(("example.com".to_owned(), 8080)).unwrap(),
+        ];
+        let mut buf = BytesMut::new();
+        for a in &addrs {
+            UotCodec
+                .encode(packet(a.clone(), b"hello"), &mut buf)
+                .unwrap();
+        }
+        // in pieces, as from a stream
+        let bytes = buf.split().freeze();
+        let mut decoded = vec![];
+        for chunk in bytes.chunks(3) {
+            buf.extend_from_slice(chunk);
+            while let Some(pkt) = UotCodec.decode(&mut buf).unwrap() {
+                decoded.push(pkt);
+            }
+        }
+        assert_eq!(decoded.len(), 3);
+        for (pkt, a) in decoded.iter().zip(&addrs) {
+            assert_eq!(&pkt.src_addr, a);
+            assert_eq!(pkt.data, b"hello");
+        }
+
+        let mut bad = BytesMut::from(&[0x05, 0, 0][..]);
+        assert!(UotCodec.decode(&mut bad).is_err());
+
+        // not cut short to what the length byte takes
+        let long = SocksAddr::Domain("a".repeat(256), 53);
+        assert!(UotCodec.encode(packet(long, b"hello"), &mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let dst = SocksAddr::Ip("1.2.3.4:53".parse().unwrap());
+        let mut d = UotDatagram::new(Box::new(client), &dst).await.unwrap();
+
+        // not connected, and the session's destination as in SOCKS
+        let mut req = [0; 8];
+        server.read_exact(&mut req).await.unwrap();
+        assert_eq!(req, [0, 1, 1, 2, 3, 4, 0, 53]);
+
+        d.send(packet(dst.clone(), b"ping")).await.unwrap();
+        let mut frame = [0; 13];
+        server.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame[..7], &[0, 1, 2, 3, 4, 0, 53]);
+        assert_eq!(&frame[7..9], &[0, 4]);
+        assert_eq!(&frame[9..], b"ping");
+
+        server.write_all(&frame).await.unwrap();
+        let pkt = d.next().await.unwrap();
+        assert_eq!(pkt.src_addr, dst);
+        assert_eq!(pkt.data, b"ping");
+    }
+}
@@ -14,7 +14,7 @@ use crate::{
         dns::ThreadSafeDNSResolver,
     },
     impl_default_connector,
-    session::Session,
+    session::{Session, SocksAddr},
 };
 
 use self::vmess_impl::OutboundDatagramVmess;
@@ -22,7 +22,9 @@ use self::vmess_impl::OutboundDatagramVmess;
 use super::{
     options::{GrpcOption, Http2Option, HttpOption, WsOption},
     transport::{self, Http2Config},
-    utils::{RemoteConnector, GLOBAL_DIRECT_CONNECTOR},
+    utils::{
+        DialError, RemoteConnector, ServerAddr, TimedStream, GLOBAL_DIRECT_CONNECTOR,
+    },
     AnyStream, ConnectorType, DialWithConnector, HandlerCommonOptions,
     OutboundHandler, OutboundType,
 };
@@ -50,6 +52,7 @@ pub struct HandlerOptions {
 
 pub struct Handler {
     opts: HandlerOptions,
+    server_addr: ServerAddr,
 
     connector: tokio::sync::Mutex<Option<Arc<dyn RemoteConnector>>>,
 }
@@ -67,6 +70,13 @@ impl_default_connector!(Handler);
 impl Handler {
     pub fn new(opts: HandlerOptions) -> Self {
         Self {
+            server_addr: ServerAddr::new(
+                &opts.server,
+                opts.port,
+                opts.common_opts.resolve_strategy,
+            )
+            .with_dial_timeout(opts.common_opts.dial_timeout)
+            .with_socket_buffers(opts.common_opts.socket_buffers),
             opts,
             connector: tokio::sync::Mutex::new(None),
         }
@@ -78,7 +88,12 @@ impl Handler {
         sess: &'a Session,
         udp: bool,
     ) -> io::Result<AnyStream> {
-        let mut stream = s;
+        let (s, handshake) = TimedStream::new(
+            s,
+            "vmess handshake",
+            self.opts.common_opts.handshake_timeout(),
+        );
+        let mut stream: AnyStream = Box::new(s);
 
         let underlying = match self.opts.transport {
             Some(VmessTransport::Ws(ref opt)) => {
@@ -101,7 +116,10 @@ impl Handler {
                     .await?;
                 }
 
-                ws_builder.proxy_stream(stream).await?
+                ws_builder
+                    .proxy_stream(stream)
+                    .await
+                    .map_err(|e| DialError::ProxyHandshake.tag(e))?
             }
             Some(VmessTransport::H2(ref opt)) => {
                 stream = match self.opts.tls.as_ref() {
@@ -121,7 +139,10 @@ impl Handler {
                     path: opt.path.to_owned().try_into().expect("invalid H2 path"),
                 };
 
-                h2_builder.proxy_stream(stream).await?
+                h2_builder
+                    .proxy_stream(stream)
+                    .await
+                    .map_err(|e| DialError::ProxyHandshake.tag(e))?
             }
             Some(VmessTransport::Grpc(ref opt)) => {
                 stream = match self.opts.tls.as_ref() {
@@ -139,13 +160,15 @@ impl Handler {
                         .try_into()
                         .expect("invalid gRPC service path"),
                 );
-                grpc_builder.proxy_stream(stream).await?
+                grpc_builder
+                    .proxy_stream(stream)
+                    .await
+                    .map_err(|e| DialError::ProxyHandshake.tag(e))?
             }
             Some(VmessTransport::Http(_)) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "HTTP transport is not supported",
-                ));
+                return Err(
+                    DialError::Unsupported.error("HTTP transport is not supported")
+                );
             }
             None => {
                 if let Some(tls_opt) = self.opts.tls.as_ref() {
@@ -168,7 +191,12 @@ impl Handler {
             dst: sess.destination.clone(),
         })?;
 
-        vmess_builder.proxy_stream(underlying).await
+        // the deadline stays until the server answers, a rejected request
+        // isn't a done handshake
+        vmess_builder
+            .proxy_stream(underlying, handshake)
+            .await
+            .map_err(|e| DialError::ProxyHandshake.tag(e))
     }
 }
 
@@ -183,6 +211,17 @@ impl OutboundHandler for Handler {
         OutboundType::Vmess
     }
 
+    fn server(&self) -> Option<SocksAddr> {
+        self.server_addr.socks_addr()
+    }
+
+    async fn prefetch(
+        &self,
+        resolver: ThreadSafeDNSResolver,
+    ) -> Option<io::Result<()>> {
+        self.server_addr.prefetch(&resolver).await
+    }
+
     /// whether the outbound handler support UDP
     async fn support_udp(&self) -> bool {
         self.opts.udp
@@ -242,11 +281,11 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = connector
+        let stream = self
+            .server_addr
             .connect_stream(
+                connector,
                 resolver,
-                self.opts.server.as_str(),
-                self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 sess.so_mark,
@@ -265,11 +304,11 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedDatagram> {
-        let stream = connector
+        let stream = self
+            .server_addr
             .connect_stream(
+                connector,
                 resolver,
-                self.opts.server.as_str(),
-                self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(any(target_os = "linux", target_os = "android"))]
                 sess.so_mark,
@@ -333,6 +372,9 @@ mod tests {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                pinned_cert_chain_sha256: vec![],
+                pinned_cert_only: false,
+                fingerprint: None,
             }),
             transport: Some(VmessTransport::Ws(WsOption {
                 path: "".to_owned(),
@@ -382,6 +424,9 @@ mod tests {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                pinned_cert_chain_sha256: vec![],
+                pinned_cert_only: false,
+                fingerprint: None,
             }),
             transport: Some(VmessTransport::Grpc(GrpcOption {
                 host: "example.org".to_owned(),
@@ -426,6 +471,9 @@ mod tests {
                 skip_cert_verify: true,
                 sni: "example.org".into(),
                 alpn: None,
+                pinned_cert_chain_sha256: vec![],
+                pinned_cert_only: false,
+                fingerprint: None,
             }),
             transport: Some(VmessTransport::H2(Http2Option {
                 host: vec!["example.org".into()],
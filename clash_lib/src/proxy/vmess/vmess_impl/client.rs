@@ -1,6 +1,10 @@
 use std::io;
 
-use crate::{common::utils, proxy::AnyStream, session::SocksAddr};
+use crate::{
+    common::utils,
+    proxy::{utils::Handshake, AnyStream},
+    session::SocksAddr,
+};
 
 use super::{
     stream::{self},
@@ -60,7 +64,12 @@ impl Builder {
         })
     }
 
-    pub async fn proxy_stream(&self, stream: AnyStream) -> io::Result<AnyStream> {
+    /// `handshake` is done once the response header of the server is read
+    pub async fn proxy_stream(
+        &self,
+        stream: AnyStream,
+        handshake: Handshake,
+    ) -> io::Result<AnyStream> {
         let idx = utils::rand_range(0..self.user.len());
         let stream = stream::VmessStream::new(
             stream,
@@ -69,6 +78,7 @@ impl Builder {
             &self.security,
             self.is_aead,
             self.is_udp,
+            handshake,
         )
         .await?;
 
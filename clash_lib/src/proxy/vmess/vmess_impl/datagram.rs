@@ -6,7 +6,10 @@ use tracing::{debug, error, instrument};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    common::errors::new_io_error,
+    common::{
+        errors::new_io_error,
+        pool::{PooledBuf, DATAGRAM_POOL},
+    },
     proxy::{datagram::UdpPacket, AnyStream},
     session::SocksAddr,
 };
@@ -18,7 +21,7 @@ pub struct OutboundDatagramVmess {
     written: Option<usize>,
     flushed: bool,
     pkt: Option<UdpPacket>,
-    buf: Vec<u8>,
+    buf: PooledBuf,
 }
 
 impl OutboundDatagramVmess {
@@ -29,7 +32,7 @@ impl OutboundDatagramVmess {
             written: None,
             flushed: true,
             pkt: None,
-            buf: vec![0u8; 65535],
+            buf: DATAGRAM_POOL.get(65535),
         }
     }
 }
@@ -14,7 +14,7 @@ use crate::{
         errors::map_io_error,
         utils,
     },
-    proxy::vmess::vmess_impl::MAX_CHUNK_SIZE,
+    proxy::{utils::Handshake, vmess::vmess_impl::MAX_CHUNK_SIZE},
     session::SocksAddr,
 };
 
@@ -53,6 +53,10 @@ pub struct VmessStream<S> {
 
     write_state: WriteState,
     write_buf: BytesMut,
+
+    /// the request isn't taken until the response header is read, None once
+    /// it is
+    handshake: Option<Handshake>,
 }
 
 impl<S> Debug for VmessStream<S> {
@@ -138,6 +142,7 @@ where
         security: &Security,
         is_aead: bool,
         is_udp: bool,
+        handshake: Handshake,
     ) -> std::io::Result<VmessStream<S>> {
         let mut rand_bytes = [0u8; 33];
         utils::rand_fill(&mut rand_bytes[..]);
@@ -222,6 +227,8 @@ where
 
             write_state: WriteState::BuildingData,
             write_buf: BytesMut::new(),
+
+            handshake: Some(handshake),
         };
 
         stream.send_handshake_request().await?;
@@ -321,6 +328,16 @@ where
     }
 }
 
+impl<S> VmessStream<S> {
+    /// the server took the request, the data follows
+    fn header_read(&mut self) {
+        if let Some(handshake) = self.handshake.take() {
+            handshake.done();
+        }
+        self.read_state = ReadState::StreamWaitingLength;
+    }
+}
+
 impl<S> AsyncRead for VmessStream<S>
 where
     S: AsyncRead + Unpin + Send,
@@ -361,7 +378,7 @@ where
                             )));
                         }
 
-                        this.read_state = ReadState::StreamWaitingLength;
+                        this.header_read();
                     } else {
                         ready!(this.poll_read_exact(cx, 18))?;
 
@@ -449,7 +466,7 @@ where
                         )));
                     }
 
-                    this.read_state = ReadState::StreamWaitingLength;
+                    this.header_read();
                 }
 
                 ReadState::StreamWaitingLength => {
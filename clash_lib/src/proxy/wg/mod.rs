@@ -14,15 +14,16 @@ use crate::{
     },
     common::errors::{map_io_error, new_io_error},
     impl_default_connector,
-    session::Session,
+    session::{Session, SocksAddr},
     Error,
 };
 
 use self::{keys::KeyBytes, wireguard::Config};
 
 use super::{
-    utils::RemoteConnector, ConnectorType, DialWithConnector, HandlerCommonOptions,
-    OutboundHandler, OutboundType,
+    utils::{mtu, DialError, RemoteConnector, ServerAddr},
+    ConnectorType, DialWithConnector, HandlerCommonOptions, OutboundHandler,
+    OutboundType,
 };
 
 use async_trait::async_trait;
@@ -40,6 +41,10 @@ mod ports;
 mod stack;
 mod wireguard;
 
+/// of the tunnel, 1500 less the most the IPv6, UDP and wireguard headers
+/// around it take
+const DEFAULT_MTU: u16 = 1420;
+
 pub struct HandlerOptions {
     pub name: String,
     pub common_opts: HandlerCommonOptions,
@@ -68,6 +73,7 @@ struct Inner {
 
 pub struct Handler {
     opts: HandlerOptions,
+    server: ServerAddr,
     inner: OnceCell<Inner>,
 
     connector: tokio::sync::Mutex<Option<Arc<dyn RemoteConnector>>>,
@@ -86,6 +92,11 @@ impl_default_connector!(Handler);
 impl Handler {
     pub fn new(opts: HandlerOptions) -> Self {
         Self {
+            server: ServerAddr::new(
+                &opts.server,
+                opts.port,
+                opts.common_opts.resolve_strategy,
+            ),
             opts,
             inner: OnceCell::new(),
 
@@ -93,6 +104,13 @@ impl Handler {
         }
     }
 
+    fn tunnel(&self) -> mtu::TunnelMtu {
+        mtu::TunnelMtu {
+            mtu: self.opts.mtu.unwrap_or(DEFAULT_MTU) as usize,
+            ipv6: self.opts.ipv6.is_some(),
+        }
+    }
+
     /// this is a one time initialization, however in theory sess.so_mark
     /// and sess.iface should be all the same
     /// ideally we move the so_mark and iface to a global context
@@ -105,14 +123,7 @@ impl Handler {
             .get_or_try_init(|| async {
                 let recv_pair = tokio::sync::mpsc::channel(1024);
                 let send_pair = tokio::sync::mpsc::channel(1024);
-                let server_ip = resolver
-                    .resolve(&self.opts.server, false)
-                    .await
-                    .map_err(map_io_error)?
-                    .ok_or(new_io_error(
-                        format!("invalid remote server: {}", self.opts.server)
-                            .as_str(),
-                    ))?;
+                let server_ip = self.server.socket_addr(&resolver).await?.ip();
                 let allowed_ips = self
                     .opts
                     .allowed_ips
@@ -175,8 +186,7 @@ impl Handler {
                     self.connector.lock().await.as_ref().cloned(),
                     sess,
                 )
-                .await
-                .map_err(map_io_error)?;
+                .await?;
 
                 let wg_handle = tokio::spawn(async move {
                     wg.start_polling().await;
@@ -189,7 +199,7 @@ impl Handler {
                     send_pair.0,
                     recv_pair.1,
                     packet_notifier.0,
-                    self.opts.mtu.unwrap_or(1420) as usize,
+                    self.opts.mtu.unwrap_or(DEFAULT_MTU) as usize,
                 );
 
                 let device_manager = Arc::new(device::DeviceManager::new(
@@ -230,6 +240,15 @@ impl Handler {
     }
 }
 
+/// the error of setting up the tunnel, an io error as it is so what the dial
+/// failed on is kept, e.g. the server not resolving
+fn tunnel_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => map_io_error(e),
+    }
+}
+
 #[async_trait]
 impl OutboundHandler for Handler {
     fn name(&self) -> &str {
@@ -240,10 +259,18 @@ impl OutboundHandler for Handler {
         OutboundType::WireGuard
     }
 
+    fn server(&self) -> Option<SocksAddr> {
+        SocksAddr::try_from((self.opts.server.clone(), self.opts.port)).ok()
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
 
+    fn tunnel_mtu(&self) -> Option<mtu::TunnelMtu> {
+        Some(self.tunnel())
+    }
+
     /// connect to remote target via TCP
     async fn connect_stream(
         &self,
@@ -253,7 +280,7 @@ impl OutboundHandler for Handler {
         let inner = self
             .initialize_inner(resolver.clone(), sess)
             .await
-            .map_err(map_io_error)?;
+            .map_err(tunnel_error)?;
 
         let ip = if self.opts.remote_dns_resolve
             && sess.destination.is_domain()
@@ -278,13 +305,13 @@ impl OutboundHandler for Handler {
                     (server.parse::<IpAddr>().unwrap(), 53).into(),
                 )
                 .await
-                .ok_or(new_io_error("invalid remote address"))?
+                .ok_or_else(|| DialError::Dns.error("invalid remote address"))?
         } else {
             resolver
                 .resolve(&sess.destination.host(), false)
-                .map_err(map_io_error)
+                .map_err(|e| DialError::Dns.tag(map_io_error(e)))
                 .await?
-                .ok_or(new_io_error("invalid remote address"))?
+                .ok_or_else(|| DialError::Dns.error("invalid remote address"))?
         };
 
         let remote = (ip, sess.destination.port()).into();
@@ -304,10 +331,11 @@ impl OutboundHandler for Handler {
         let inner = self
             .initialize_inner(resolver, sess)
             .await
-            .map_err(map_io_error)?;
+            .map_err(tunnel_error)?;
 
         let socket = inner.device_manager.new_udp_socket().await;
-        let chained = ChainedDatagramWrapper::new(socket);
+        let chained =
+            ChainedDatagramWrapper::new(socket).with_tunnel_mtu(self.tunnel());
         chained.append_to_chain(self.name()).await;
         Ok(Box::new(chained))
     }
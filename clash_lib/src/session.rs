@@ -5,14 +5,14 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
-use crate::proxy::utils::Interface;
+use crate::{config::def::RunMode, proxy::utils::Interface};
 use bytes::{Buf, BufMut};
 use serde::Serialize;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 use erased_serde::Serialize as ESerialize;
 
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Serialize)]
 pub enum SocksAddr {
     Ip(SocketAddr),
     Domain(String, u16),
@@ -80,6 +80,7 @@ impl SocksAddr {
         }
     }
 
+    #[cfg_attr(not(feature = "vmess"), allow(dead_code))]
     pub fn write_to_buf_vmess<B: BufMut>(&self, buf: &mut B) {
         match self {
             Self::Ip(SocketAddr::V4(addr)) => {
@@ -361,20 +362,29 @@ impl TryFrom<SocksAddr> for SocketAddr {
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Network {
     Tcp,
     Udp,
 }
 
+/// serialized with the names dashboards expect from mihomo
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize)]
 pub enum Type {
+    #[serde(rename = "HTTP")]
     Http,
+    #[serde(rename = "HTTPS")]
     HttpConnect,
+    Socks4,
     Socks5,
+    #[serde(rename = "Tun")]
     Tun,
     #[cfg(target_os = "linux")]
+    #[serde(rename = "TProxy")]
     Tproxy,
+    Tunnel,
 
+    #[serde(rename = "Inner")]
     Ignore,
 }
 
@@ -405,15 +415,45 @@ pub struct Session {
     pub iface: Option<Interface>,
     /// The ASN of the destination IP address. Only for display.
     pub asn: Option<String>,
+    /// The country code of the destination IP address, `PRIVATE` for the
+    /// private ranges. Only for display.
+    pub destination_geoip: Option<String>,
+    /// The listener the connection came in on, keyed like in the config,
+    /// e.g. `socks`. Empty for tun and the internal connections.
+    pub inbound_name: String,
+    /// The user the client authenticated as on the inbound, e.g. with SOCKS5
+    /// or HTTP basic auth. None where the inbound takes no credentials.
+    pub inbound_user: Option<String>,
+    /// The mode it was dispatched in, set by the dispatcher.
+    pub mode: RunMode,
+    /// The proxy it goes through whatever the mode and the rules say, e.g.
+    /// the one of a tunnel.
+    pub special_proxy: Option<String>,
+    /// Whether a UDP session only ever talks to `destination`, as with
+    /// symmetric NAT, set by the dispatcher. The direct outbound connects
+    /// its socket to it then.
+    pub fixed_destination: bool,
+    /// What the connection carries, for the `PROTOCOL` rules, e.g. `tls`
+    /// or `ssh`. Assumed from the destination port by `port-hints`, nothing
+    /// is sniffed.
+    pub protocol: Option<String>,
+    /// Why a UDP session went through `udp-fallback` instead of what the
+    /// rules said, e.g. `ss-hk has no udp`.
+    pub udp_fallback: Option<String>,
 }
 
 impl Session {
+    /// The connection metadata in the shape of mihomo's `/connections`,
+    /// fields we don't track are present but empty.
     pub fn as_map(&self) -> HashMap<String, Box<dyn ESerialize + Send + Sync>> {
         let mut rv = HashMap::new();
         rv.insert("network".to_string(), Box::new(self.network) as _);
         rv.insert("type".to_string(), Box::new(self.typ) as _);
         rv.insert("sourceIP".to_string(), Box::new(self.source.ip()) as _);
-        rv.insert("sourcePort".to_string(), Box::new(self.source.port()) as _);
+        rv.insert(
+            "sourcePort".to_string(),
+            Box::new(self.source.port().to_string()) as _,
+        );
         rv.insert("destinationIP".to_string(), {
             let ip = self.resolved_ip.or(self.destination.ip());
             let asn = self.asn.clone();
@@ -427,10 +467,72 @@ impl Session {
         });
         rv.insert(
             "destinationPort".to_string(),
-            Box::new(self.destination.port()) as _,
+            Box::new(self.destination.port().to_string()) as _,
+        );
+        rv.insert(
+            "host".to_string(),
+            Box::new(match &self.destination {
+                SocksAddr::Domain(host, _) => host.clone(),
+                SocksAddr::Ip(_) => "".to_string(),
+            }) as _,
+        );
+        rv.insert(
+            "remoteDestination".to_string(),
+            Box::new(self.resolved_ip.map(|x| x.to_string()).unwrap_or_default())
+                as _,
         );
-        rv.insert("host".to_string(), Box::new(self.destination.host()) as _);
         rv.insert("asn".to_string(), Box::new(self.asn.clone()) as _);
+        // a list in mihomo, an IP in several of its geoip sets
+        rv.insert(
+            "destinationGeoIP".to_string(),
+            Box::new(self.destination_geoip.iter().cloned().collect::<Vec<_>>())
+                as _,
+        );
+        rv.insert(
+            "destinationIPASN".to_string(),
+            Box::new(self.asn.clone().unwrap_or_default()) as _,
+        );
+        rv.insert(
+            "inboundName".to_string(),
+            Box::new(self.inbound_name.clone()) as _,
+        );
+        rv.insert(
+            "inboundUser".to_string(),
+            Box::new(self.inbound_user.clone().unwrap_or_default()) as _,
+        );
+        rv.insert("mode".to_string(), Box::new(self.mode) as _);
+        rv.insert(
+            "specialProxy".to_string(),
+            Box::new(self.special_proxy.clone().unwrap_or_default()) as _,
+        );
+        // tagged with where it came from, an assumed one can be wrong
+        rv.insert(
+            "protocol".to_string(),
+            Box::new(self.protocol.clone().unwrap_or_default()) as _,
+        );
+        rv.insert(
+            "protocolSource".to_string(),
+            Box::new(if self.protocol.is_some() {
+                "assumed"
+            } else {
+                ""
+            }) as _,
+        );
+        rv.insert(
+            "udpFallback".to_string(),
+            Box::new(self.udp_fallback.clone().unwrap_or_default()) as _,
+        );
+        for key in [
+            "inboundIP",
+            "inboundPort",
+            "sniffHost",
+            "dnsMode",
+            "process",
+            "processPath",
+            "specialRules",
+        ] {
+            rv.insert(key.to_string(), Box::new("") as _);
+        }
         rv
     }
 }
@@ -446,6 +548,14 @@ impl Default for Session {
             so_mark: None,
             iface: None,
             asn: None,
+            destination_geoip: None,
+            inbound_name: String::new(),
+            inbound_user: None,
+            mode: RunMode::default(),
+            special_proxy: None,
+            fixed_destination: false,
+            protocol: None,
+            udp_fallback: None,
         }
     }
 }
@@ -454,8 +564,14 @@ impl Display for Session {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "[{}] {} -> {}",
-            self.network, self.source, self.destination,
+            "[{}] {}{} -> {}",
+            self.network,
+            self.source,
+            self.inbound_user
+                .as_ref()
+                .map(|x| format!("({})", x))
+                .unwrap_or_default(),
+            self.destination,
         )
     }
 }
@@ -469,6 +585,14 @@ impl Debug for Session {
             .field("packet_mark", &self.so_mark)
             .field("iface", &self.iface)
             .field("asn", &self.asn)
+            .field("destination_geoip", &self.destination_geoip)
+            .field("inbound_name", &self.inbound_name)
+            .field("inbound_user", &self.inbound_user)
+            .field("mode", &self.mode)
+            .field("special_proxy", &self.special_proxy)
+            .field("fixed_destination", &self.fixed_destination)
+            .field("protocol", &self.protocol)
+            .field("udp_fallback", &self.udp_fallback)
             .finish()
     }
 }
@@ -484,6 +608,14 @@ impl Clone for Session {
             so_mark: self.so_mark,
             iface: self.iface.as_ref().cloned(),
             asn: self.asn.clone(),
+            destination_geoip: self.destination_geoip.clone(),
+            inbound_name: self.inbound_name.clone(),
+            inbound_user: self.inbound_user.clone(),
+            mode: self.mode,
+            special_proxy: self.special_proxy.clone(),
+            fixed_destination: self.fixed_destination,
+            protocol: self.protocol.clone(),
+            udp_fallback: self.udp_fallback.clone(),
         }
     }
 }
@@ -499,3 +631,43 @@ fn invalid_atyp() -> io::Error {
 fn insuff_bytes() -> io::Error {
     io::Error::new(io::ErrorKind::Other, "insufficient bytes")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Network, Session, SocksAddr, Type};
+
+    #[test]
+    fn test_session_metadata_shape() {
+        let sess = Session {
+            network: Network::Tcp,
+            typ: Type::HttpConnect,
+            source: "127.0.0.1:52124".parse().unwrap(),
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            resolved_ip: Some("93.184.216.34".parse().unwrap()),
+            inbound_name: "mixed".to_owned(),
+            ..Default::default()
+        };
+
+        let v = serde_json::to_value(sess.as_map()).unwrap();
+        assert_eq!(v["network"], "tcp");
+        assert_eq!(v["type"], "HTTPS");
+        assert_eq!(v["sourceIP"], "127.0.0.1");
+        assert_eq!(v["sourcePort"], "52124");
+        assert_eq!(v["destinationIP"], "93.184.216.34");
+        assert_eq!(v["destinationPort"], "443");
+        assert_eq!(v["host"], "example.com");
+        assert_eq!(v["inboundName"], "mixed");
+        assert_eq!(v["sniffHost"], "");
+        assert_eq!(v["mode"], "rule");
+        assert_eq!(v["protocol"], "");
+        assert_eq!(v["protocolSource"], "");
+
+        let sess = Session {
+            protocol: Some("tls".to_owned()),
+            ..Default::default()
+        };
+        let v = serde_json::to_value(sess.as_map()).unwrap();
+        assert_eq!(v["protocol"], "tls");
+        assert_eq!(v["protocolSource"], "assumed");
+    }
+}
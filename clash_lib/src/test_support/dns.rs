@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+
+use hickory_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{
+        rdata::{A, AAAA},
+        RData, Record, RecordType,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use super::MockServer;
+
+type Handler = dyn Fn(&Message) -> Option<Message> + Send + Sync;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsQuery {
+    /// without the trailing dot
+    pub name: String,
+    pub query_type: RecordType,
+    pub tcp: bool,
+}
+
+/// A DNS server on UDP and TCP of the same port. It answers from its table,
+/// the names it doesn't have with NXDOMAIN, unless a handler is given which
+/// then makes all the answers.
+#[derive(Default)]
+pub struct DnsServer {
+    answers: HashMap<String, Vec<IpAddr>>,
+    any: Vec<IpAddr>,
+    handler: Option<Arc<Handler>>,
+}
+
+impl DnsServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `name` resolves to `ip` too, A or AAAA depending on what it is
+    pub fn answer(mut self, name: &str, ip: IpAddr) -> Self {
+        self.answers
+            .entry(name.trim_end_matches('.').to_lowercase())
+            .or_default()
+            .push(ip);
+        self
+    }
+
+    /// the names not in the table resolve to `ip` too
+    pub fn answer_any(mut self, ip: IpAddr) -> Self {
+        self.any.push(ip);
+        self
+    }
+
+    /// makes the answers to the queries, None for no answer at all, e.g. to
+    /// have the query time out
+    pub fn handler<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Message) -> Option<Message> + Send + Sync + 'static,
+    {
+        self.handler = Some(Arc::new(f));
+        self
+    }
+
+    pub async fn start(self) -> MockServer<DnsQuery> {
+        let (udp, tcp) = bind().await;
+        let addr = udp.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let this = Arc::new(self);
+
+        let udp_task = {
+            let this = this.clone();
+            let recorded = requests.clone();
+            async move {
+                let mut buf = vec![0; 65535];
+                while let Ok((n, from)) = udp.recv_from(&mut buf).await {
+                    let Some(answer) = this.exchange(&buf[..n], false, &recorded)
+                    else {
+                        continue;
+                    };
+                    let _ = udp.send_to(&answer, from).await;
+                }
+            }
+        };
+        let tcp_task = {
+            let recorded = requests.clone();
+            async move {
+                while let Ok((s, _)) = tcp.accept().await {
+                    let this = this.clone();
+                    let recorded = recorded.clone();
+                    tokio::spawn(async move {
+                        let _ = this.serve_tcp(s, recorded).await;
+                    });
+                }
+            }
+        };
+        let handle = tokio::spawn(async move {
+            tokio::join!(udp_task, tcp_task);
+        });
+        MockServer::new(addr, handle, requests)
+    }
+
+    async fn serve_tcp(
+        &self,
+        mut s: TcpStream,
+        recorded: Arc<Mutex<Vec<DnsQuery>>>,
+    ) -> io::Result<()> {
+        loop {
+            let mut buf = vec![0; s.read_u16().await? as usize];
+            s.read_exact(&mut buf).await?;
+            if let Some(answer) = self.exchange(&buf, true, &recorded) {
+                s.write_u16(answer.len() as u16).await?;
+                s.write_all(&answer).await?;
+            }
+        }
+    }
+
+    fn exchange(
+        &self,
+        buf: &[u8],
+        tcp: bool,
+        recorded: &Mutex<Vec<DnsQuery>>,
+    ) -> Option<Vec<u8>> {
+        let query = Message::from_vec(buf).ok()?;
+        if let Some(q) = query.query() {
+            recorded.lock().unwrap().push(DnsQuery {
+                name: q.name().to_utf8().trim_end_matches('.').to_owned(),
+                query_type: q.query_type(),
+                tcp,
+            });
+        }
+        let answer = match &self.handler {
+            Some(handler) => handler(&query)?,
+            None => self.lookup(&query),
+        };
+        answer.to_vec().ok()
+    }
+
+    fn lookup(&self, query: &Message) -> Message {
+        let mut answer = Message::new();
+        answer.set_id(query.id());
+        answer.set_message_type(MessageType::Response);
+        answer.set_recursion_desired(query.recursion_desired());
+        answer.set_recursion_available(true);
+        let Some(q) = query.query().cloned() else {
+            answer.set_response_code(ResponseCode::FormErr);
+            return answer;
+        };
+
+        let name = q.name().to_utf8().trim_end_matches('.').to_lowercase();
+        let ips = match self.answers.get(&name) {
+            Some(ips) => ips,
+            None if !self.any.is_empty() => &self.any,
+            None => {
+                answer.set_response_code(ResponseCode::NXDomain);
+                answer.add_query(q);
+                return answer;
+            }
+        };
+        for ip in ips {
+            let rdata = match (ip, q.query_type()) {
+                (IpAddr::V4(ip), RecordType::A) => RData::A(A::from(*ip)),
+                (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA(AAAA::from(*ip)),
+                _ => continue,
+            };
+            answer.add_answer(Record::from_rdata(q.name().clone(), 60, rdata));
+        }
+        answer.add_query(q);
+        answer
+    }
+}
+
+/// a UDP socket and a TCP listener of the same random port
+async fn bind() -> (UdpSocket, TcpListener) {
+    loop {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = udp.local_addr().unwrap();
+        if let Ok(tcp) = TcpListener::bind(addr).await {
+            return (udp, tcp);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::session::SocksAddr;
+
+use super::{serve, MockServer, ProxyRequest};
+
+/// an HTTP proxy doing CONNECT only, with or without basic auth
+#[derive(Default)]
+pub struct HttpConnectServer {
+    auth: Option<(String, String)>,
+    relay: bool,
+}
+
+impl HttpConnectServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// wants this user and password in `Proxy-Authorization`
+    pub fn auth(mut self, user: &str, password: &str) -> Self {
+        self.auth = Some((user.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// connects to the targets, instead of echoing
+    pub fn relay(mut self) -> Self {
+        self.relay = true;
+        self
+    }
+
+    pub async fn start(self) -> MockServer<ProxyRequest> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        let this = Arc::new(self);
+        let handle = tokio::spawn(async move {
+            while let Ok((s, _)) = listener.accept().await {
+                let this = this.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let _ = this.handle(s, recorded).await;
+                });
+            }
+        });
+        MockServer::new(addr, handle, requests)
+    }
+
+    async fn handle(
+        &self,
+        s: TcpStream,
+        recorded: Arc<Mutex<Vec<ProxyRequest>>>,
+    ) -> io::Result<()> {
+        let mut s = BufReader::new(s);
+        let mut line = String::new();
+        s.read_line(&mut line).await?;
+        let mut parts = line.split_whitespace();
+        if parts.next() != Some("CONNECT") {
+            s.write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")
+                .await?;
+            return Ok(());
+        }
+        let target = parts.next().and_then(|x| x.rsplit_once(':')).and_then(
+            |(host, port)| {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                SocksAddr::try_from((host.to_owned(), port.parse().ok()?)).ok()
+            },
+        );
+        let Some(target) = target else {
+            s.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+            return Ok(());
+        };
+
+        let mut credentials = None;
+        loop {
+            line.clear();
+            if s.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("proxy-authorization") {
+                    credentials = value
+                        .trim()
+                        .strip_prefix("Basic ")
+                        .and_then(|x| STANDARD.decode(x).ok())
+                        .and_then(|x| String::from_utf8(x).ok())
+                        .and_then(|x| {
+                            x.split_once(':')
+                                .map(|(u, p)| (u.to_owned(), p.to_owned()))
+                        });
+                }
+            }
+        }
+
+        let accepted = match &self.auth {
+            Some(auth) => credentials.as_ref() == Some(auth),
+            None => true,
+        };
+        recorded.lock().unwrap().push(ProxyRequest {
+            target: target.clone(),
+            user: credentials.map(|(u, _)| u),
+            accepted,
+            udp: false,
+        });
+        if !accepted {
+            s.write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                  Proxy-Authenticate: Basic\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await?;
+            return Ok(());
+        }
+        s.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+
+        serve(s, &target, self.relay).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! In-process servers for the tests of the handlers and the resolver, so they
+//! talk to something on a real socket without docker. Each binds a random
+//! port on 127.0.0.1, serves until it's dropped, and records what it was
+//! asked for the test to check.
+
+// not every test takes every part of them
+#![allow(dead_code)]
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    task::JoinHandle,
+};
+use tracing::debug;
+
+use crate::{
+    app::dns::{MockClashResolver, ThreadSafeDNSResolver},
+    session::SocksAddr,
+};
+
+mod dns;
+mod http_connect;
+mod socks5;
+mod trojan;
+
+pub use dns::{DnsQuery, DnsServer};
+pub use http_connect::HttpConnectServer;
+pub use socks5::Socks5Server;
+pub use trojan::{tls_acceptor, TrojanServer};
+
+pub struct MockServer<R> {
+    pub addr: SocketAddr,
+    pub handle: JoinHandle<()>,
+    requests: Arc<Mutex<Vec<R>>>,
+}
+
+impl<R: Clone> MockServer<R> {
+    fn new(
+        addr: SocketAddr,
+        handle: JoinHandle<()>,
+        requests: Arc<Mutex<Vec<R>>>,
+    ) -> Self {
+        Self {
+            addr,
+            handle,
+            requests,
+        }
+    }
+
+    /// what it was asked so far, in order
+    pub fn requests(&self) -> Vec<R> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl<R> Drop for MockServer<R> {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// a request to one of the proxies
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyRequest {
+    pub target: SocksAddr,
+    /// the user it authenticated as, if it did
+    pub user: Option<String>,
+    /// false when the credentials were wrong, nothing is served then
+    pub accepted: bool,
+    /// a trojan UDP request, the packets are echoed like a stream
+    pub udp: bool,
+}
+
+/// a resolver for servers given as IPs, which the handlers resolve still
+pub fn resolver() -> ThreadSafeDNSResolver {
+    let mut resolver = MockClashResolver::new();
+    resolver
+        .expect_resolve()
+        .returning(|host, _| Ok(host.parse().ok()));
+    Arc::new(resolver)
+}
+
+/// what the proxies do once the request is accepted: echo what comes, or
+/// relay it to the target for real
+async fn serve<S>(mut s: S, target: &SocksAddr, relay: bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !relay {
+        let (mut r, mut w) = tokio::io::split(s);
+        let _ = tokio::io::copy(&mut r, &mut w).await;
+        return;
+    }
+    match TcpStream::connect((target.host(), target.port())).await {
+        Ok(mut remote) => {
+            let _ = tokio::io::copy_bidirectional(&mut s, &mut remote).await;
+        }
+        Err(e) => debug!("mock proxy can't reach {}: {}", target, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::{
+        op::{Message, Query, ResponseCode},
+        rr,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{DnsServer, HttpConnectServer};
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = s.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+
+        let server = HttpConnectServer::new()
+            .auth("user", "password")
+            .relay()
+            .start()
+            .await;
+
+        let mut s = TcpStream::connect(server.addr).await.unwrap();
+        s.write_all(
+            format!(
+                "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic \
+                 dXNlcjpwYXNzd29yZA==\r\n\r\n",
+                echo_addr
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        let mut buf = [0; 39];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HTTP/1.1 200 Connection established\r\n\r\n");
+        s.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut s = TcpStream::connect(server.addr).await.unwrap();
+        s.write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        s.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 407"), "{}", res);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].user.as_deref(), Some("user"));
+        assert!(requests[0].accepted);
+        assert_eq!(requests[1].target.to_string(), "example.com:443");
+        assert!(!requests[1].accepted);
+    }
+
+    #[tokio::test]
+    async fn test_dns_over_tcp() {
+        let server = DnsServer::new()
+            .answer("example.com", "1.2.3.4".parse().unwrap())
+            .start()
+            .await;
+
+        let mut s = TcpStream::connect(server.addr).await.unwrap();
+        for (name, code) in [
+            ("example.com.", ResponseCode::NoError),
+            ("missing.example.", ResponseCode::NXDomain),
+        ] {
+            let mut m = Message::new();
+            m.set_id(7);
+            m.add_query(Query::query(
+                rr::Name::from_utf8(name).unwrap(),
+                rr::RecordType::A,
+            ));
+            let m = m.to_vec().unwrap();
+            s.write_u16(m.len() as u16).await.unwrap();
+            s.write_all(&m).await.unwrap();
+
+            let mut buf = vec![0; s.read_u16().await.unwrap() as usize];
+            s.read_exact(&mut buf).await.unwrap();
+            let answer = Message::from_vec(&buf).unwrap();
+            assert_eq!(answer.id(), 7);
+            assert_eq!(answer.response_code(), code);
+        }
+
+        let queries = server.requests();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].name, "example.com");
+        assert!(queries[0].tcp);
+    }
+}
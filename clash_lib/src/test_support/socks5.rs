@@ -0,0 +1,147 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use crate::session::SocksAddr;
+
+use super::{serve, MockServer, ProxyRequest};
+
+/// A SOCKS5 server doing CONNECT, with or without a password, and UDP
+/// ASSOCIATE, whose relay echoes the datagrams to where they came from.
+#[derive(Default)]
+pub struct Socks5Server {
+    auth: Option<(String, String)>,
+    relay: bool,
+}
+
+impl Socks5Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// wants this user and password
+    pub fn auth(mut self, user: &str, password: &str) -> Self {
+        self.auth = Some((user.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// connects to the targets, instead of echoing
+    pub fn relay(mut self) -> Self {
+        self.relay = true;
+        self
+    }
+
+    pub async fn start(self) -> MockServer<ProxyRequest> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        let this = Arc::new(self);
+        let handle = tokio::spawn(async move {
+            while let Ok((s, _)) = listener.accept().await {
+                let this = this.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let _ = this.handle(s, recorded).await;
+                });
+            }
+        });
+        MockServer::new(addr, handle, requests)
+    }
+
+    async fn handle(
+        &self,
+        mut s: TcpStream,
+        recorded: Arc<Mutex<Vec<ProxyRequest>>>,
+    ) -> io::Result<()> {
+        if s.read_u8().await? != 5 {
+            return Ok(());
+        }
+        let mut methods = vec![0; s.read_u8().await? as usize];
+        s.read_exact(&mut methods).await?;
+
+        let wanted = if self.auth.is_some() { 0x02 } else { 0x00 };
+        if !methods.contains(&wanted) {
+            s.write_all(&[0x05, 0xff]).await?;
+            return Ok(());
+        }
+        s.write_all(&[0x05, wanted]).await?;
+
+        let mut user = None;
+        let mut accepted = true;
+        if let Some((want_user, want_password)) = &self.auth {
+            s.read_u8().await?;
+            let mut u = vec![0; s.read_u8().await? as usize];
+            s.read_exact(&mut u).await?;
+            let mut p = vec![0; s.read_u8().await? as usize];
+            s.read_exact(&mut p).await?;
+            let u = String::from_utf8_lossy(&u).into_owned();
+            accepted = u == *want_user && p == want_password.as_bytes();
+            user = Some(u);
+            if !accepted {
+                s.write_all(&[0x01, 0x01]).await?;
+                recorded.lock().unwrap().push(ProxyRequest {
+                    target: SocksAddr::any_ipv4(),
+                    user,
+                    accepted,
+                    udp: false,
+                });
+                return Ok(());
+            }
+            s.write_all(&[0x01, 0x00]).await?;
+        }
+
+        let mut head = [0; 3];
+        s.read_exact(&mut head).await?;
+        let target = SocksAddr::read_from(&mut s).await?;
+        recorded.lock().unwrap().push(ProxyRequest {
+            target: target.clone(),
+            user,
+            accepted,
+            udp: head[1] == 0x03,
+        });
+        if head[1] == 0x03 {
+            return udp_associate(s).await;
+        }
+        if head[1] != 0x01 {
+            // command not supported
+            s.write_all(&[0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            return Ok(());
+        }
+        let mut reply = vec![0x05, 0x00, 0x00];
+        SocksAddr::from(s.local_addr()?).write_buf(&mut reply);
+        s.write_all(&reply).await?;
+
+        serve(s, &target, self.relay).await;
+        Ok(())
+    }
+}
+
+/// echoes until the associate's connection closes
+async fn udp_associate(mut s: TcpStream) -> io::Result<()> {
+    let udp = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut reply = vec![0x05, 0x00, 0x00];
+    SocksAddr::from(udp.local_addr()?).write_buf(&mut reply);
+    s.write_all(&reply).await?;
+
+    let mut rest = vec![];
+    tokio::select! {
+        r = echo(&udp) => r,
+        _ = s.read_to_end(&mut rest) => Ok(()),
+    }
+}
+
+async fn echo(udp: &UdpSocket) -> io::Result<()> {
+    let mut buf = vec![0; 65535];
+    loop {
+        let (n, src) = udp.recv_from(&mut buf).await?;
+        udp.send_to(&buf[..n], src).await?;
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha224};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{common::utils, session::SocksAddr};
+
+use super::{serve, MockServer, ProxyRequest};
+
+/// the certificate the TLS servers have, self-signed for example.org
+pub fn tls_acceptor() -> TlsAcceptor {
+    let certs = rustls_pemfile::certs(
+        &mut include_str!("../../../clash/tests/data/config/example.org.pem")
+            .as_bytes(),
+    )
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+    let key = rustls_pemfile::private_key(
+        &mut include_str!("../../../clash/tests/data/config/example.org-key.pem")
+            .as_bytes(),
+    )
+    .unwrap()
+    .unwrap();
+    TlsAcceptor::from(Arc::new(
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap(),
+    ))
+}
+
+/// A trojan server echoing what comes after the request, UDP included, as
+/// the packets go back in the same framing. A wrong password closes the
+/// connection, there's no web server to fall back to.
+pub struct TrojanServer {
+    password: String,
+    relay: bool,
+}
+
+impl TrojanServer {
+    pub fn new(password: &str) -> Self {
+        Self {
+            password: password.to_owned(),
+            relay: false,
+        }
+    }
+
+    /// connects to the targets and sends the packets to theirs, instead of
+    /// echoing
+    pub fn relay(mut self) -> Self {
+        self.relay = true;
+        self
+    }
+
+    pub async fn start(self) -> MockServer<ProxyRequest> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        let acceptor = tls_acceptor();
+        let hash = utils::encode_hex(&Sha224::digest(self.password.as_bytes())[..]);
+        let relay = self.relay;
+        let handle = tokio::spawn(async move {
+            while let Ok((s, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let hash = hash.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let _ = handle(s, acceptor, &hash, relay, recorded).await;
+                });
+            }
+        });
+        MockServer::new(addr, handle, requests)
+    }
+}
+
+async fn handle(
+    s: TcpStream,
+    acceptor: TlsAcceptor,
+    hash: &str,
+    relay: bool,
+    recorded: Arc<Mutex<Vec<ProxyRequest>>>,
+) -> io::Result<()> {
+    let mut s = acceptor.accept(s).await?;
+
+    let mut password = [0; 56];
+    s.read_exact(&mut password).await?;
+    crlf(&mut s).await?;
+    let cmd = s.read_u8().await?;
+    let target = SocksAddr::read_from(&mut s).await?;
+    crlf(&mut s).await?;
+
+    let accepted = password == hash.as_bytes();
+    recorded.lock().unwrap().push(ProxyRequest {
+        target: target.clone(),
+        user: None,
+        accepted,
+        udp: cmd == 0x03,
+    });
+    if !accepted {
+        return Ok(());
+    }
+    if relay && cmd == 0x03 {
+        return relay_udp(s).await;
+    }
+    serve(s, &target, relay).await;
+    Ok(())
+}
+
+/// the packets to their targets for real, and what comes back to the stream
+async fn relay_udp<S>(s: S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let udp = UdpSocket::bind("127.0.0.1:0").await?;
+    let (r, w) = tokio::io::split(s);
+    tokio::select! {
+        r = udp_out(r, &udp) => r,
+        r = udp_back(w, &udp) => r,
+    }
+}
+
+async fn udp_out<R: AsyncRead + Unpin>(mut r: R, udp: &UdpSocket) -> io::Result<()> {
+    loop {
+        let target = SocksAddr::read_from(&mut r).await?;
+        let mut data = vec![0; r.read_u16().await? as usize];
+        crlf(&mut r).await?;
+        r.read_exact(&mut data).await?;
+        udp.send_to(&data, (target.host(), target.port())).await?;
+    }
+}
+
+async fn udp_back<W: AsyncWrite + Unpin>(
+    mut w: W,
+    udp: &UdpSocket,
+) -> io::Result<()> {
+    let mut buf = vec![0; 65535];
+    loop {
+        let (n, src) = udp.recv_from(&mut buf).await?;
+        let mut frame = vec![];
+        SocksAddr::from(src).write_buf(&mut frame);
+        frame.extend_from_slice(&(n as u16).to_be_bytes());
+        frame.extend_from_slice(b"\r\n");
+        frame.extend_from_slice(&buf[..n]);
+        w.write_all(&frame).await?;
+    }
+}
+
+async fn crlf<S: AsyncRead + Unpin>(s: &mut S) -> io::Result<()> {
+    let mut buf = [0; 2];
+    s.read_exact(&mut buf).await?;
+    if &buf != b"\r\n" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no CRLF"));
+    }
+    Ok(())
+}